@@ -0,0 +1,81 @@
+//! Compare parsing messages fully and as [`MessageLite`]s.
+//!
+//! Usage: `cargo run --release --example lite_parse --features lite -- [count]`
+
+use std::error::Error;
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+use euphoxide::api::{
+    Message, MessageId, MessageLite, SessionId, SessionView, Snowflake, Time, UserId,
+};
+
+const RUNS: usize = 5;
+
+fn message(i: u64) -> Message {
+    Message {
+        id: MessageId(Snowflake(i)),
+        parent: (!i.is_multiple_of(3)).then_some(MessageId(Snowflake(i / 2))),
+        previous_edit_id: None,
+        time: Time(1_700_000_000 + i as i64),
+        sender: SessionView {
+            id: UserId(format!("agent:{i:016x}")),
+            name: format!("user{}", i % 100),
+            server_id: "heim.1".to_string(),
+            server_era: "abcdefghij".to_string(),
+            session_id: SessionId(format!("{i:016x}-{:08x}", i * 31)),
+            is_staff: false,
+            is_manager: i.is_multiple_of(50),
+            client_address: Some(format!("10.0.{}.{}", i % 256, i / 256 % 256)),
+            real_client_address: None,
+        },
+        content: format!("message number {i}, saying hello to everyone in the room"),
+        encryption_key_id: None,
+        edited: None,
+        deleted: None,
+        truncated: false,
+    }
+}
+
+/// The fastest of multiple runs.
+fn bench<F: FnMut()>(mut f: F) -> Duration {
+    (0..RUNS)
+        .map(|_| {
+            let start = Instant::now();
+            f();
+            start.elapsed()
+        })
+        .min()
+        .unwrap()
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let count = match std::env::args().nth(1) {
+        Some(count) => count.parse()?,
+        None => 100_000,
+    };
+
+    let lines = (0..count)
+        .map(|i| serde_json::to_string(&message(i)))
+        .collect::<Result<Vec<_>, _>>()?;
+    let bytes = lines.iter().map(|l| l.len()).sum::<usize>();
+    println!("Corpus: {count} messages, {bytes} bytes");
+
+    let full = bench(|| {
+        for line in &lines {
+            black_box(serde_json::from_str::<Message>(line).unwrap());
+        }
+    });
+    let lite = bench(|| {
+        for line in &lines {
+            black_box(serde_json::from_str::<MessageLite>(line).unwrap());
+        }
+    });
+
+    println!("Full: {full:?}");
+    println!(
+        "Lite: {lite:?} ({:.2}x)",
+        full.as_secs_f64() / lite.as_secs_f64()
+    );
+    Ok(())
+}
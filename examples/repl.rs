@@ -0,0 +1,20 @@
+//! Connect to a room and interact with it by typing into the terminal.
+//!
+//! Usage: `cargo run --example repl --features devtools -- <room> [domain]`
+
+use std::error::Error;
+
+use euphoxide::devtools::repl::{self, ReplConfig};
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn Error>> {
+    let mut args = std::env::args().skip(1);
+    let room = args.next().ok_or("missing room argument")?;
+    let mut config = ReplConfig::default();
+    if let Some(domain) = args.next() {
+        config = config.with_domain(domain);
+    }
+
+    repl::run_repl(config, &room).await?;
+    Ok(())
+}
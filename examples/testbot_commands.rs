@@ -7,7 +7,9 @@ use async_trait::async_trait;
 use clap::Parser;
 use euphoxide::api::Message;
 use euphoxide::bot::botrulez::{FullHelp, HasDescriptions, HasStartTime, Ping, ShortHelp, Uptime};
-use euphoxide::bot::command::{Clap, ClapCommand, Context, General, Global, Hidden, Specific};
+use euphoxide::bot::command::{
+    Clap, ClapCommand, Context, General, Global, Hidden, Info, Specific,
+};
 use euphoxide::bot::commands::Commands;
 use euphoxide::bot::instance::{Event, ServerConfig};
 use euphoxide::bot::instances::Instances;
@@ -79,8 +81,8 @@ struct Bot {
 }
 
 impl HasDescriptions for Bot {
-    fn descriptions(&self, ctx: &Context) -> Vec<String> {
-        self.commands.descriptions(ctx)
+    fn infos(&self, ctx: &Context) -> Vec<Info> {
+        self.commands.infos(ctx)
     }
 }
 
@@ -92,11 +94,6 @@ impl HasStartTime for Bot {
 
 #[tokio::main]
 async fn main() {
-    // https://github.com/snapview/tokio-tungstenite/issues/353#issuecomment-2455247837
-    rustls::crypto::aws_lc_rs::default_provider()
-        .install_default()
-        .unwrap();
-
     let (tx, mut rx) = mpsc::unbounded_channel();
     let mut instances = Instances::new(ServerConfig::default());
 
@@ -117,27 +114,45 @@ async fn main() {
     };
 
     for room in ["test", "test2", "testing"] {
-        let tx_clone = tx.clone();
         let instance = instances
             .server_config()
             .clone()
             .room(room)
-            .username(Some("TestBot"))
-            .build(move |e| {
-                let _ = tx_clone.send(e);
-            });
+            .with_username("TestBot")
+            .build_with_sender(tx.clone());
         instances.add(instance);
     }
 
-    while let Some(event) = rx.recv().await {
+    loop {
+        let event = tokio::select! {
+            biased;
+            () = cmds.retries_due() => {
+                if let Err(err) = cmds.handle_retries(&mut bot).await {
+                    error!("{err}");
+                }
+                if bot.stop {
+                    break;
+                }
+                continue;
+            }
+            event = rx.recv() => match event {
+                Some(event) => event,
+                None => break,
+            },
+        };
+
         instances.purge();
         if instances.is_empty() {
             break;
         }
 
-        if let Event::Packet(config, packet, snapshot) = event {
+        if let Event::Packet(identity, packet, snapshot) = event {
+            let instance = match instances.get(&identity.name) {
+                Some(instance) => instance,
+                None => continue,
+            };
             let result = cmds
-                .handle_packet(&config, &packet, &snapshot, &mut bot)
+                .handle_packet(instance.config(), &packet, &snapshot, &mut bot)
                 .await;
             if let Err(err) = result {
                 error!("{err}");
@@ -104,10 +104,11 @@ async fn on_packet(packet: ParsedPacket, snapshot: ConnSnapshot) -> Result<(), (
             }
 
             if let Some(reply) = reply {
-                // If you are not interested in the result, you can just
-                // throw away the future returned by the send function.
+                // If you are not interested in the result, you can use
+                // send_logged instead of send. It doesn't block and logs a
+                // warning if the command fails for any reason.
                 println!("Sending reply...");
-                snapshot.conn_tx.send_only(Send {
+                snapshot.conn_tx.send_logged(Send {
                     content: reply,
                     parent: Some(event.0.id),
                 });
@@ -122,22 +123,15 @@ async fn on_packet(packet: ParsedPacket, snapshot: ConnSnapshot) -> Result<(), (
 
 #[tokio::main]
 async fn main() {
-    // https://github.com/snapview/tokio-tungstenite/issues/353#issuecomment-2455247837
-    rustls::crypto::aws_lc_rs::default_provider()
-        .install_default()
-        .unwrap();
-
     let (tx, mut rx) = mpsc::unbounded_channel();
 
     let _instance = ServerConfig::default()
         .room("test")
-        .username(Some("TestBot"))
-        .build(move |e| {
-            let _ = tx.send(e);
-        });
+        .with_username("TestBot")
+        .build_with_sender(tx);
 
     while let Some(event) = rx.recv().await {
-        if let Event::Packet(_config, packet, snapshot) = event {
+        if let Event::Packet(_identity, packet, snapshot) = event {
             if on_packet(packet, snapshot).await.is_err() {
                 break;
             }
@@ -105,10 +105,11 @@ async fn on_packet(packet: ParsedPacket, snapshot: ConnSnapshot) -> Result<(), (
             }
 
             if let Some(reply) = reply {
-                // If you are not interested in the result, you can just
-                // throw away the future returned by the send function.
+                // If you are not interested in the result, you can use
+                // send_logged instead of send. It doesn't block and logs a
+                // warning if the command fails for any reason.
                 println!("Sending reply...");
-                snapshot.conn_tx.send_only(Send {
+                snapshot.conn_tx.send_logged(Send {
                     content: reply,
                     parent: Some(event.0.id),
                 });
@@ -123,24 +124,16 @@ async fn on_packet(packet: ParsedPacket, snapshot: ConnSnapshot) -> Result<(), (
 
 #[tokio::main]
 async fn main() {
-    // https://github.com/snapview/tokio-tungstenite/issues/353#issuecomment-2455247837
-    rustls::crypto::aws_lc_rs::default_provider()
-        .install_default()
-        .unwrap();
-
     let (tx, mut rx) = mpsc::unbounded_channel();
     let mut instances = Instances::new(ServerConfig::default());
 
     for room in ["test", "test2", "testing"] {
-        let tx_clone = tx.clone();
         let instance = instances
             .server_config()
             .clone()
             .room(room)
-            .username(Some("TestBot"))
-            .build(move |e| {
-                let _ = tx_clone.send(e);
-            });
+            .with_username("TestBot")
+            .build_with_sender(tx.clone());
         instances.add(instance);
     }
 
@@ -150,7 +143,7 @@ async fn main() {
             break;
         }
 
-        if let Event::Packet(_config, packet, snapshot) = event {
+        if let Event::Packet(_identity, packet, snapshot) = event {
             if on_packet(packet, snapshot).await.is_err() {
                 break;
             }
@@ -108,10 +108,11 @@ async fn on_packet(packet: ParsedPacket, conn_tx: &ConnTx, state: &State) -> Res
             }
 
             if let Some(reply) = reply {
-                // If you are not interested in the result, you can just
-                // throw away the future returned by the send function.
+                // If you are not interested in the result, you can use
+                // send_logged instead of send. It doesn't block and logs a
+                // warning if the command fails for any reason.
                 println!("Sending reply...");
-                conn_tx.send_only(Send {
+                conn_tx.send_logged(Send {
                     content: reply,
                     parent: Some(event.0.id),
                 });
@@ -126,11 +127,6 @@ async fn on_packet(packet: ParsedPacket, conn_tx: &ConnTx, state: &State) -> Res
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
-    // https://github.com/snapview/tokio-tungstenite/issues/353#issuecomment-2455247837
-    rustls::crypto::aws_lc_rs::default_provider()
-        .install_default()
-        .unwrap();
-
     let (mut conn, _) = Conn::connect(DOMAIN, ROOM, false, None, TIMEOUT).await?;
 
     while let Ok(packet) = conn.recv().await {
@@ -0,0 +1,90 @@
+//! Similar to the `testbot_instances` example, but reading the rooms to join
+//! from a file and reloading it whenever it changes.
+//!
+//! The file contains one room per line. Run the example with its path as the
+//! only argument, then edit the file while the bot is running. A real bot
+//! might reload its config when it receives a `SIGHUP` instead.
+
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use euphoxide::api::packet::ParsedPacket;
+use euphoxide::api::{Data, Send};
+use euphoxide::bot::instance::{ConnSnapshot, Event, InstanceConfig, ServerConfig};
+use euphoxide::bot::instances::Instances;
+use tokio::sync::mpsc;
+
+const NICK: &str = "TestBot";
+const STAGGER: Duration = Duration::from_secs(1);
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+fn load_configs(server_config: &ServerConfig, path: &Path) -> Vec<InstanceConfig> {
+    let rooms = match std::fs::read_to_string(path) {
+        Ok(rooms) => rooms,
+        Err(err) => {
+            println!("Failed to read {}: {err}", path.display());
+            return vec![];
+        }
+    };
+
+    rooms
+        .lines()
+        .map(|line| line.trim())
+        .filter(|line| !line.is_empty())
+        .map(|room| server_config.clone().room(room).with_username(NICK))
+        .collect()
+}
+
+fn on_packet(packet: ParsedPacket, snapshot: ConnSnapshot) {
+    let Ok(Data::SendEvent(event)) = packet.content else {
+        return;
+    };
+
+    let content = event.0.content.trim();
+    if content == "!ping" || content == format!("!ping @{NICK}") {
+        snapshot.conn_tx.send_logged(Send {
+            content: "Pong!".to_string(),
+            parent: Some(event.0.id),
+        });
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    let Some(path) = std::env::args_os().nth(1).map(PathBuf::from) else {
+        println!("Usage: testbot_reload <rooms file>");
+        return;
+    };
+
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    let mut instances = Instances::new(ServerConfig::default());
+    let mut last_modified = None;
+    let mut poll = tokio::time::interval(POLL_INTERVAL);
+
+    loop {
+        tokio::select! {
+            _ = poll.tick() => {
+                let modified = modified(&path);
+                if modified == last_modified {
+                    continue;
+                }
+                last_modified = modified;
+
+                let desired = load_configs(instances.server_config(), &path);
+                let make = |config: InstanceConfig| config.build_with_sender(tx.clone());
+                let report = instances.reconcile(desired, STAGGER, make).await;
+                println!("Reloaded {}: {report}", path.display());
+            }
+            Some(event) = rx.recv() => {
+                instances.purge();
+                if let Event::Packet(_identity, packet, snapshot) = event {
+                    on_packet(packet, snapshot);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,437 @@
+//! Multi-step account management flows.
+//!
+//! Some account commands, like [`RegisterAccount`], make the server disconnect
+//! the session. Only the next connection is logged in. The flows in this
+//! module send the commands, reconnect and check the resulting account state,
+//! reporting their [`Progress`] along the way.
+//!
+//! Reconnecting is done by a user-provided function. When connecting using
+//! [`Conn::connect`], it must pass on the cookies returned by the previous
+//! connection, since they identify the logged in agent.
+
+use std::future::Future;
+use std::time::Duration;
+use std::{error, fmt, result};
+
+use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+use tokio_tungstenite::tungstenite::protocol::CloseFrame;
+
+use crate::api::packet::Command;
+use crate::api::{AccountId, Data, PersonalAccountView, RegisterAccount, ResendVerificationEmail};
+use crate::conn::{self, Conn, Joined, State};
+
+/// A step of an account management flow.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Progress {
+    /// The [`RegisterAccount`] command was sent.
+    Registering,
+    /// The server accepted the [`RegisterAccount`] command.
+    Registered(Option<AccountId>),
+    /// The server closed the old connection.
+    Disconnected,
+    /// A new connection was opened and has joined the room.
+    Reconnected,
+    /// The new connection is logged in to the account.
+    LoggedIn(PersonalAccountView),
+    /// A new verification email was sent.
+    VerificationEmailSent,
+    /// Checking whether the account's email address has been verified.
+    CheckingVerification,
+    /// The account's email address has been verified.
+    Verified,
+}
+
+#[derive(Debug)]
+pub enum Error {
+    /// Sending a command or receiving its reply failed.
+    Command(conn::Error),
+    /// The server rejected a command, with the given reason.
+    Rejected(String),
+    /// Opening a new connection failed.
+    Reconnect(conn::Error),
+    /// The new connection is not logged in to the expected account.
+    NotLoggedIn,
+    /// The flow did not finish in time.
+    TimedOut,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Command(err) => write!(f, "command failed: {err}"),
+            Self::Rejected(reason) => write!(f, "command rejected: {reason}"),
+            Self::Reconnect(err) => write!(f, "failed to reconnect: {err}"),
+            Self::NotLoggedIn => write!(f, "not logged in after reconnecting"),
+            Self::TimedOut => write!(f, "timed out"),
+        }
+    }
+}
+
+impl error::Error for Error {}
+
+pub type Result<T> = result::Result<T, Error>;
+
+/// Send a command and wait for its reply while receiving packets.
+///
+/// Errors returned by the server are turned into [`Error::Rejected`].
+async fn send<C>(conn: &mut Conn, cmd: C) -> Result<C::Reply>
+where
+    C: Command + Into<Data>,
+    C::Reply: TryFrom<Data>,
+{
+    let reply = conn.tx().send(cmd);
+    tokio::pin!(reply);
+    loop {
+        // The reply must be checked first since receiving the next packet
+        // might close the connection, e.g. after a successful registration.
+        tokio::select! {
+            biased;
+            reply = &mut reply => return reply.map_err(|err| match err {
+                conn::Error::Euph { message, .. } => Error::Rejected(message),
+                err => Error::Command(err),
+            }),
+            packet = conn.recv() => {
+                packet.map_err(Error::Command)?;
+            }
+        }
+    }
+}
+
+/// Receive packets until the connection has joined its room.
+async fn wait_until_joined(conn: &mut Conn) -> conn::Result<Joined> {
+    loop {
+        if let State::Joined(joined) = conn.state() {
+            return Ok(joined.clone());
+        }
+        conn.recv().await?;
+    }
+}
+
+/// Open a new connection and wait until it has joined its room.
+async fn reconnect<F, Fut>(reconnect: &mut F) -> Result<(Conn, Joined)>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = conn::Result<Conn>>,
+{
+    let mut conn = reconnect().await.map_err(Error::Reconnect)?;
+    let joined = wait_until_joined(&mut conn)
+        .await
+        .map_err(Error::Reconnect)?;
+    Ok((conn, joined))
+}
+
+/// Register a new account and log in to it.
+///
+/// After the server has accepted the registration, this function waits for it
+/// to close the connection and then calls `reconnect` to open a new one. The
+/// new connection is returned once it has joined its room and is logged in to
+/// the new account.
+///
+/// If the server rejects the registration, e.g. because the identifier is
+/// already taken, [`Error::Rejected`] is returned and `reconnect` is not
+/// called.
+pub async fn register_account<F, Fut, P>(
+    mut conn: Conn,
+    namespace: &str,
+    id: &str,
+    password: &str,
+    mut reconnect_fn: F,
+    mut on_progress: P,
+) -> Result<Conn>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = conn::Result<Conn>>,
+    P: FnMut(Progress),
+{
+    let cmd = RegisterAccount {
+        namespace: namespace.to_string(),
+        id: id.to_string(),
+        password: password.to_string(),
+    };
+    on_progress(Progress::Registering);
+    let reply = send(&mut conn, cmd).await?;
+    if !reply.success {
+        let reason = reply.reason.unwrap_or_else(|| "unknown reason".to_string());
+        return Err(Error::Rejected(reason));
+    }
+    on_progress(Progress::Registered(reply.account_id));
+
+    // The connection closes itself after a successful registration. In case it
+    // stays open anyways, don't wait for it longer than the usual timeout.
+    let timeout = conn.timeout();
+    let _ = tokio::time::timeout(timeout, async { while conn.recv().await.is_ok() {} }).await;
+    let close = CloseFrame {
+        code: CloseCode::Normal,
+        reason: "registered".into(),
+    };
+    let _ = conn.close(timeout, close).await;
+    on_progress(Progress::Disconnected);
+
+    let (conn, joined) = reconnect(&mut reconnect_fn).await?;
+    on_progress(Progress::Reconnected);
+
+    match joined.account {
+        Some(account) if reply.account_id.is_none_or(|id| id == account.id) => {
+            on_progress(Progress::LoggedIn(account));
+            Ok(conn)
+        }
+        _ => Err(Error::NotLoggedIn),
+    }
+}
+
+/// Ask the server to send a new verification email for the account the
+/// connection is logged in to.
+pub async fn resend_verification<P>(conn: &mut Conn, mut on_progress: P) -> Result<()>
+where
+    P: FnMut(Progress),
+{
+    send(conn, ResendVerificationEmail {}).await?;
+    on_progress(Progress::VerificationEmailSent);
+    Ok(())
+}
+
+/// Wait until the email address of the account the connection is logged in to
+/// has been verified.
+///
+/// The server only tells new connections whether the email address has been
+/// verified (see [`Joined::account_email_verified`]). If `conn` doesn't already
+/// know that it is verified, this function uses `reconnect` every
+/// `poll_interval` to open a new connection and check again. The first
+/// connection that knows that the email address is verified is returned.
+///
+/// Returns [`Error::TimedOut`] if the email address was not verified within
+/// `timeout`.
+pub async fn wait_for_email_verification<F, Fut, P>(
+    mut conn: Conn,
+    mut reconnect_fn: F,
+    poll_interval: Duration,
+    timeout: Duration,
+    mut on_progress: P,
+) -> Result<Conn>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = conn::Result<Conn>>,
+    P: FnMut(Progress),
+{
+    let poll = async move {
+        on_progress(Progress::CheckingVerification);
+        let mut joined = wait_until_joined(&mut conn).await.map_err(Error::Command)?;
+        loop {
+            if joined.account.is_none() {
+                return Err(Error::NotLoggedIn);
+            }
+            if joined.account_email_verified == Some(true) {
+                on_progress(Progress::Verified);
+                return Ok(conn);
+            }
+
+            tokio::time::sleep(poll_interval).await;
+            on_progress(Progress::CheckingVerification);
+            (conn, joined) = reconnect(&mut reconnect_fn).await?;
+        }
+    };
+
+    tokio::time::timeout(timeout, poll)
+        .await
+        .unwrap_or(Err(Error::TimedOut))
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    use crate::conn::test::{connect, hello, Server};
+    use crate::conn::{self, Conn, State};
+
+    use super::{
+        register_account, resend_verification, wait_for_email_verification, Error, Progress,
+    };
+
+    const TIMEOUT: Duration = Duration::from_secs(10);
+
+    /// Reply to the next command the client sends.
+    async fn reply(server: &mut Server, r#type: &str, data: serde_json::Value) {
+        let cmd = server.recv().await.unwrap();
+        server
+            .send(serde_json::json!({ "id": cmd["id"], "type": r#type, "data": data }))
+            .await;
+    }
+
+    /// Connect to a server that joins the room and then does nothing.
+    ///
+    /// The hello-event says whether the account's email address has been
+    /// verified, or that the session is not logged in if `verified` is `None`.
+    async fn reconnect(verified: Option<bool>) -> conn::Result<Conn> {
+        let (conn, mut server) = connect(TIMEOUT).await;
+        tokio::spawn(async move {
+            server.join(hello(false, verified)).await;
+            while server.recv().await.is_some() {}
+        });
+        Ok(conn)
+    }
+
+    fn account(conn: &Conn) -> Option<String> {
+        match conn.state() {
+            State::Joined(joined) => joined.account.as_ref().map(|a| a.name.clone()),
+            State::Joining(_) => None,
+        }
+    }
+
+    #[tokio::test]
+    async fn register() {
+        let (conn, mut server) = connect(TIMEOUT).await;
+        let server = tokio::spawn(async move {
+            let data = serde_json::json!({ "success": true, "account_id": "0000000000000" });
+            reply(&mut server, "register-account-reply", data).await;
+            while server.recv().await.is_some() {}
+        });
+
+        let mut progress = vec![];
+        let conn = register_account(
+            conn,
+            "email",
+            "testbot@example.com",
+            "hunter2",
+            || reconnect(Some(false)),
+            |p| progress.push(p),
+        )
+        .await
+        .unwrap();
+        server.await.unwrap();
+
+        assert_eq!(account(&conn).as_deref(), Some("TestBot"));
+        assert_eq!(progress.len(), 5);
+        assert_eq!(progress[0], Progress::Registering);
+        assert!(matches!(progress[1], Progress::Registered(Some(_))));
+        assert_eq!(progress[2], Progress::Disconnected);
+        assert_eq!(progress[3], Progress::Reconnected);
+        assert!(matches!(&progress[4], Progress::LoggedIn(a) if a.name == "TestBot"));
+    }
+
+    #[tokio::test]
+    async fn register_identifier_taken() {
+        let (conn, mut server) = connect(TIMEOUT).await;
+        tokio::spawn(async move {
+            let data = serde_json::json!({ "success": false, "reason": "already in use" });
+            reply(&mut server, "register-account-reply", data).await;
+            while server.recv().await.is_some() {}
+        });
+
+        let mut progress = vec![];
+        let result = register_account(
+            conn,
+            "email",
+            "testbot@example.com",
+            "hunter2",
+            || async { panic!("must not reconnect") },
+            |p| progress.push(p),
+        )
+        .await;
+
+        assert!(matches!(result, Err(Error::Rejected(r)) if r == "already in use"));
+        assert_eq!(progress, [Progress::Registering]);
+    }
+
+    #[tokio::test]
+    async fn register_without_login() {
+        let (conn, mut server) = connect(TIMEOUT).await;
+        tokio::spawn(async move {
+            let data = serde_json::json!({ "success": true });
+            reply(&mut server, "register-account-reply", data).await;
+            while server.recv().await.is_some() {}
+        });
+
+        let result = register_account(
+            conn,
+            "email",
+            "testbot@example.com",
+            "hunter2",
+            || reconnect(None),
+            |_| {},
+        )
+        .await;
+        assert!(matches!(result, Err(Error::NotLoggedIn)));
+    }
+
+    #[tokio::test]
+    async fn resend() {
+        let (mut conn, mut server) = connect(TIMEOUT).await;
+        tokio::spawn(async move {
+            reply(
+                &mut server,
+                "resend-verification-email-reply",
+                serde_json::json!({}),
+            )
+            .await;
+            let cmd = server.recv().await.unwrap();
+            server
+                .send(serde_json::json!({
+                    "id": cmd["id"],
+                    "type": "resend-verification-email-reply",
+                    "error": "no unverified email addresses",
+                }))
+                .await;
+            while server.recv().await.is_some() {}
+        });
+
+        let mut progress = vec![];
+        resend_verification(&mut conn, |p| progress.push(p))
+            .await
+            .unwrap();
+        assert_eq!(progress, [Progress::VerificationEmailSent]);
+
+        let result = resend_verification(&mut conn, |_| {}).await;
+        assert!(matches!(result, Err(Error::Rejected(r)) if r == "no unverified email addresses"));
+    }
+
+    #[tokio::test]
+    async fn email_verification() {
+        let (conn, mut server) = connect(TIMEOUT).await;
+        tokio::spawn(async move {
+            server.join(hello(false, Some(false))).await;
+            while server.recv().await.is_some() {}
+        });
+
+        // Verified on the third reconnect
+        let reconnects = Arc::new(Mutex::new(0));
+        let reconnect_fn = || {
+            let mut reconnects = reconnects.lock().unwrap();
+            *reconnects += 1;
+            reconnect(Some(*reconnects >= 3))
+        };
+
+        let mut progress = vec![];
+        let conn = wait_for_email_verification(
+            conn,
+            reconnect_fn,
+            Duration::from_millis(10),
+            TIMEOUT,
+            |p| progress.push(p),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(account(&conn).as_deref(), Some("TestBot"));
+        assert_eq!(*reconnects.lock().unwrap(), 3);
+        let checks = progress
+            .iter()
+            .filter(|p| **p == Progress::CheckingVerification)
+            .count();
+        assert_eq!(checks, 4);
+        assert_eq!(progress.last(), Some(&Progress::Verified));
+    }
+
+    #[tokio::test]
+    async fn email_verification_timeout() {
+        let result = wait_for_email_verification(
+            reconnect(Some(false)).await.unwrap(),
+            || reconnect(Some(false)),
+            Duration::from_millis(10),
+            Duration::from_millis(100),
+            |_| {},
+        )
+        .await;
+        assert!(matches!(result, Err(Error::TimedOut)));
+    }
+}
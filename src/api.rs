@@ -4,6 +4,10 @@
 
 mod account_cmds;
 mod events;
+mod host_cmds;
+#[cfg(feature = "lite")]
+mod lite;
+mod manager_cmds;
 pub mod packet;
 mod room_cmds;
 mod session_cmds;
@@ -11,6 +15,10 @@ mod types;
 
 pub use account_cmds::*;
 pub use events::*;
+pub use host_cmds::*;
+#[cfg(feature = "lite")]
+pub use lite::*;
+pub use manager_cmds::*;
 pub use packet::Data;
 pub use room_cmds::*;
 pub use session_cmds::*;
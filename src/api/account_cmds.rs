@@ -12,6 +12,7 @@ use super::AccountId;
 ///
 /// The email address may need to be verified before the change is fully applied.
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(test, derive(proptest_derive::Arbitrary))]
 pub struct ChangeEmail {
     /// The new primary email address for the account.
     pub email: String,
@@ -21,6 +22,7 @@ pub struct ChangeEmail {
 
 /// Indicate that the primary email address has been changed.
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(test, derive(proptest_derive::Arbitrary))]
 pub struct ChangeEmailReply {
     /// True if authentication succeeded and the email was changed.
     pub success: bool,
@@ -33,6 +35,7 @@ pub struct ChangeEmailReply {
 
 /// Change the name associated with the signed in account.
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(test, derive(proptest_derive::Arbitrary))]
 pub struct ChangeName {
     /// The name to associate with the account.
     pub name: String,
@@ -40,6 +43,7 @@ pub struct ChangeName {
 
 /// Indicate a successful name change.
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(test, derive(proptest_derive::Arbitrary))]
 pub struct ChangeNameReply {
     /// The new name associated with the account.
     pub name: String,
@@ -47,6 +51,7 @@ pub struct ChangeNameReply {
 
 /// Change the password of the signed in account.
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(test, derive(proptest_derive::Arbitrary))]
 pub struct ChangePassword {
     /// The current (and soon-to-be former) password.
     pub old_password: String,
@@ -56,6 +61,7 @@ pub struct ChangePassword {
 
 /// Return the outcome of changing the password.
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(test, derive(proptest_derive::Arbitrary))]
 pub struct ChangePasswordReply {}
 
 /// Attempt to log an anonymous session into an account.
@@ -66,6 +72,7 @@ pub struct ChangePasswordReply {}
 /// [`DisconnectEvent`](super::DisconnectEvent) shortly after. The next
 /// connection the client makes will be a logged in session.
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(test, derive(proptest_derive::Arbitrary))]
 pub struct Login {
     /// The namespace of a personal identifier.
     pub namespace: String,
@@ -81,6 +88,7 @@ pub struct Login {
 /// [`DisconnectEvent`](super::DisconnectEvent) shortly after. The next
 /// connection the client makes will be a logged in session.
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(test, derive(proptest_derive::Arbitrary))]
 pub struct LoginReply {
     /// True if the session is now logged in.
     pub success: bool,
@@ -99,10 +107,12 @@ pub struct LoginReply {
 /// [`DisconnectEvent`](super::DisconnectEvent) shortly after. The next
 /// connection the client makes will be a logged out session.
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(test, derive(proptest_derive::Arbitrary))]
 pub struct Logout {}
 
 /// Confirm a logout.
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(test, derive(proptest_derive::Arbitrary))]
 pub struct LogoutReply {}
 
 /// Create a new account and logs into it.
@@ -114,6 +124,7 @@ pub struct LogoutReply {}
 /// connection the client makes will be a logged in session using the new
 /// account.
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(test, derive(proptest_derive::Arbitrary))]
 pub struct RegisterAccount {
     /// The namespace of a personal identifier.
     pub namespace: String,
@@ -130,6 +141,7 @@ pub struct RegisterAccount {
 /// connection the client makes will be a logged in session, using the newly
 /// created account.
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(test, derive(proptest_derive::Arbitrary))]
 pub struct RegisterAccountReply {
     /// True if the session is now logged in.
     pub success: bool,
@@ -146,10 +158,12 @@ pub struct RegisterAccountReply {
 /// An error will be returned if the account has no unverified email addresses
 /// associated with it.
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(test, derive(proptest_derive::Arbitrary))]
 pub struct ResendVerificationEmail {}
 
 /// Indicate that a verification email has been sent.
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(test, derive(proptest_derive::Arbitrary))]
 pub struct ResendVerificationEmailReply {}
 
 /// Generate a password reset request.
@@ -157,6 +171,7 @@ pub struct ResendVerificationEmailReply {}
 /// An email will be sent to the owner of the given personal identifier, with
 /// instructions and a confirmation code for resetting the password.
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(test, derive(proptest_derive::Arbitrary))]
 pub struct ResetPassword {
     pub namespace: String,
     pub id: String,
@@ -164,4 +179,5 @@ pub struct ResetPassword {
 
 /// Confirm that the password reset is in progress.
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(test, derive(proptest_derive::Arbitrary))]
 pub struct ResetPasswordReply {}
@@ -1,5 +1,8 @@
 //! Asynchronous events.
 
+use std::fmt;
+use std::sync::Arc;
+
 use serde::{Deserialize, Serialize};
 
 use super::{
@@ -9,6 +12,7 @@ use super::{
 
 /// Indicates that access to a room is denied.
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(test, derive(proptest_derive::Arbitrary))]
 pub struct BounceEvent {
     /// The reason why access was denied.
     pub reason: Option<String>,
@@ -26,16 +30,54 @@ pub struct BounceEvent {
 /// If the disconnect reason is `authentication changed`, the client should
 /// immediately reconnect.
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(test, derive(proptest_derive::Arbitrary))]
 pub struct DisconnectEvent {
     /// The reason for disconnection.
     pub reason: String,
 }
 
+impl DisconnectEvent {
+    /// The reason for disconnection, parsed into a [`DisconnectReason`].
+    pub fn parsed_reason(&self) -> DisconnectReason {
+        DisconnectReason::parse(&self.reason)
+    }
+}
+
+/// The reason given in a [`DisconnectEvent`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DisconnectReason {
+    /// The authentication of the session changed, for example because the
+    /// agent logged in or out in a different session. The client should
+    /// immediately reconnect.
+    AuthenticationChanged,
+    /// Any reason not modeled by the other variants.
+    Other(String),
+}
+
+impl DisconnectReason {
+    pub fn parse(reason: &str) -> Self {
+        match reason {
+            "authentication changed" => Self::AuthenticationChanged,
+            other => Self::Other(other.to_string()),
+        }
+    }
+}
+
+impl fmt::Display for DisconnectReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::AuthenticationChanged => write!(f, "authentication changed"),
+            Self::Other(reason) => write!(f, "{reason}"),
+        }
+    }
+}
+
 /// Sent by the server to the client when a session is started.
 ///
 /// It includes information about the client's authentication and associated
 /// identity.
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(test, derive(proptest_derive::Arbitrary))]
 pub struct HelloEvent {
     /// The id of the agent or account logged into this session.
     pub id: UserId,
@@ -45,10 +87,13 @@ pub struct HelloEvent {
     pub session: SessionView,
     /// If true, then the account has an explicit access grant to the current
     /// room.
+    #[serde(default)]
     pub account_has_access: Option<bool>,
     /// Whether the account's email address has been verified.
+    #[serde(default)]
     pub account_email_verified: Option<bool>,
     /// If true, the session is connected to a private room.
+    #[serde(default)]
     pub room_is_private: bool,
     /// The version of the code being run and served by the server.
     pub version: String,
@@ -56,11 +101,13 @@ pub struct HelloEvent {
 
 /// Indicates a session just joined the room.
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(test, derive(proptest_derive::Arbitrary))]
 pub struct JoinEvent(pub SessionView);
 
 /// Sent to all sessions of an agent when that agent is logged in (except for
 /// the session that issued the login command).
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(test, derive(proptest_derive::Arbitrary))]
 pub struct LoginEvent {
     pub account_id: AccountId,
 }
@@ -68,6 +115,7 @@ pub struct LoginEvent {
 /// Sent to all sessions of an agent when that agent is logged out (except for
 /// the session that issued the logout command).
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(test, derive(proptest_derive::Arbitrary))]
 pub struct LogoutEvent {}
 
 /// Indicates some server-side event that impacts the presence of sessions in a
@@ -76,6 +124,7 @@ pub struct LogoutEvent {}
 /// If the network event type is `partition`, then this should be treated as a
 /// [`PartEvent`] for all sessions connected to the same server id/era combo.
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(test, derive(proptest_derive::Arbitrary))]
 pub struct NetworkEvent {
     /// The type of network event; for now, always `partition`.
     pub r#type: String,
@@ -87,6 +136,7 @@ pub struct NetworkEvent {
 
 /// Announces a nick change by another session in the room.
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(test, derive(proptest_derive::Arbitrary))]
 pub struct NickEvent {
     /// The id of the session this name applies to.
     pub session_id: SessionId,
@@ -105,6 +155,7 @@ pub struct NickEvent {
 ///
 /// The event packet includes a snapshot of the message post-edit.
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(test, derive(proptest_derive::Arbitrary))]
 pub struct EditMessageEvent {
     /// The id of the edit.
     pub edit_id: Snowflake,
@@ -115,6 +166,7 @@ pub struct EditMessageEvent {
 
 /// Indicates a session just disconnected from the room.
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(test, derive(proptest_derive::Arbitrary))]
 pub struct PartEvent(pub SessionView);
 
 /// Represents a server-to-client ping.
@@ -122,6 +174,7 @@ pub struct PartEvent(pub SessionView);
 /// The client should send back a ping-reply with the same value for the time
 /// field as soon as possible (or risk disconnection).
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(test, derive(proptest_derive::Arbitrary))]
 pub struct PingEvent {
     /// A unix timestamp according to the server's clock.
     pub time: Time,
@@ -132,6 +185,7 @@ pub struct PingEvent {
 
 /// Informs the client that another user wants to chat with them privately.
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(test, derive(proptest_derive::Arbitrary))]
 pub struct PmInitiateEvent {
     /// The id of the user inviting the client to chat privately.
     pub from: UserId,
@@ -144,13 +198,18 @@ pub struct PmInitiateEvent {
 }
 
 /// Indicates a message received by the room from another session.
+///
+/// The message is behind an [`Arc`] so it can be handed to multiple consumers,
+/// e.g. every command of a bot, without being cloned.
 #[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct SendEvent(pub Message);
+#[cfg_attr(test, derive(proptest_derive::Arbitrary))]
+pub struct SendEvent(pub Arc<Message>);
 
 /// Indicates that a session has successfully joined a room.
 ///
 /// It also offers a snapshot of the room’s state and recent history.
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(test, derive(proptest_derive::Arbitrary))]
 pub struct SnapshotEvent {
     /// The id of the agent or account logged into this session.
     pub identity: UserId,
@@ -171,3 +230,26 @@ pub struct SnapshotEvent {
     /// If given, this room is for private chat with the given user.
     pub pm_with_user_id: Option<String>,
 }
+
+#[cfg(test)]
+mod test {
+    use super::{DisconnectEvent, DisconnectReason};
+
+    #[test]
+    fn disconnect_reason() {
+        let event = DisconnectEvent {
+            reason: "authentication changed".to_string(),
+        };
+        assert_eq!(
+            event.parsed_reason(),
+            DisconnectReason::AuthenticationChanged
+        );
+
+        let event = DisconnectEvent {
+            reason: "shutting down".to_string(),
+        };
+        let reason = event.parsed_reason();
+        assert_eq!(reason, DisconnectReason::Other("shutting down".to_string()));
+        assert_eq!(reason.to_string(), "shutting down");
+    }
+}
@@ -0,0 +1,234 @@
+//! Room host commands.
+//!
+//! These commands are available to the client once a session successfully joins
+//! a room as a host.
+
+use serde::{Deserialize, Serialize};
+
+use super::{Message, MessageId, Snowflake, UserId};
+
+/// Who or what a [`Ban`] or [`Unban`] applies to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BanTarget {
+    /// An agent or account.
+    Id(UserId),
+    /// An IP address.
+    Ip(String),
+}
+
+/// Ban an agent, account or IP address from the room.
+///
+/// Usually, exactly one of [`Self::id`] and [`Self::ip`] is set. See
+/// [`Self::new`] for a way to ensure this.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(test, derive(proptest_derive::Arbitrary))]
+pub struct Ban {
+    /// The id of the agent or account to ban.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<UserId>,
+    /// The IP address to ban.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ip: Option<String>,
+    /// The duration of the ban in seconds, or forever if not set.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub seconds: Option<u64>,
+}
+
+impl Ban {
+    pub fn new(target: BanTarget, seconds: Option<u64>) -> Self {
+        let (id, ip) = split_target(target);
+        Self { id, ip, seconds }
+    }
+
+    /// The target of the ban, if exactly one of [`Self::id`] and [`Self::ip`]
+    /// is set.
+    pub fn target(&self) -> Option<BanTarget> {
+        join_target(&self.id, &self.ip)
+    }
+}
+
+/// Confirms the [`Ban`] command by repeating it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(test, derive(proptest_derive::Arbitrary))]
+pub struct BanReply {
+    /// The id of the banned agent or account.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<UserId>,
+    /// The banned IP address.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ip: Option<String>,
+    /// The duration of the ban in seconds, or forever if not set.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub seconds: Option<u64>,
+}
+
+/// Modify the content or display of a message, or delete it.
+///
+/// See also
+/// [`Permissions::can_edit_messages`](crate::conn::Permissions::can_edit_messages).
+///
+/// A message deleted by this command is still stored in the database and may be
+/// undeleted by this command again. Messages that have expired from the
+/// database or that have been purged are no longer available.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(test, derive(proptest_derive::Arbitrary))]
+pub struct EditMessage {
+    /// The id of the message to edit.
+    pub id: MessageId,
+    /// The id of the message's most recent edit, or null if it's never been
+    /// edited.
+    ///
+    /// If this doesn't match the message's current edit id, the edit fails.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub previous_edit_id: Option<Snowflake>,
+    /// The new parent of the message, if it should change.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub parent: Option<MessageId>,
+    /// The new content of the message, if it should change.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub content: Option<String>,
+    /// Whether the message should be deleted.
+    #[serde(default)]
+    pub delete: bool,
+    /// Whether an [`EditMessageEvent`](super::EditMessageEvent) should be
+    /// broadcast to the room.
+    #[serde(default)]
+    pub announce: bool,
+}
+
+/// The message as it looks after the [`EditMessage`].
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(test, derive(proptest_derive::Arbitrary))]
+pub struct EditMessageReply(pub Message);
+
+/// Lift a [`Ban`].
+///
+/// Usually, exactly one of [`Self::id`] and [`Self::ip`] is set. See
+/// [`Self::new`] for a way to ensure this.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(test, derive(proptest_derive::Arbitrary))]
+pub struct Unban {
+    /// The id of the agent or account to unban.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<UserId>,
+    /// The IP address to unban.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ip: Option<String>,
+}
+
+impl Unban {
+    pub fn new(target: BanTarget) -> Self {
+        let (id, ip) = split_target(target);
+        Self { id, ip }
+    }
+
+    /// The target of the unban, if exactly one of [`Self::id`] and
+    /// [`Self::ip`] is set.
+    pub fn target(&self) -> Option<BanTarget> {
+        join_target(&self.id, &self.ip)
+    }
+}
+
+/// Confirms the [`Unban`] command by repeating it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(test, derive(proptest_derive::Arbitrary))]
+pub struct UnbanReply {
+    /// The id of the unbanned agent or account.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<UserId>,
+    /// The unbanned IP address.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ip: Option<String>,
+}
+
+fn split_target(target: BanTarget) -> (Option<UserId>, Option<String>) {
+    match target {
+        BanTarget::Id(id) => (Some(id), None),
+        BanTarget::Ip(ip) => (None, Some(ip)),
+    }
+}
+
+fn join_target(id: &Option<UserId>, ip: &Option<String>) -> Option<BanTarget> {
+    match (id, ip) {
+        (Some(id), None) => Some(BanTarget::Id(id.clone())),
+        (None, Some(ip)) => Some(BanTarget::Ip(ip.clone())),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json::json;
+
+    use crate::api::{MessageId, Snowflake, UserId};
+
+    use super::{Ban, BanReply, BanTarget, EditMessage, Unban, UnbanReply};
+
+    #[test]
+    fn ban_serialization() {
+        let id = UserId("agent:spammer".to_string());
+        let ban = Ban::new(BanTarget::Id(id.clone()), Some(3600));
+        assert_eq!(
+            serde_json::to_value(&ban).unwrap(),
+            json!({ "id": "agent:spammer", "seconds": 3600 })
+        );
+        assert_eq!(ban.target(), Some(BanTarget::Id(id.clone())));
+
+        let ban = Ban::new(BanTarget::Ip("10.0.0.1".to_string()), None);
+        assert_eq!(
+            serde_json::to_value(&ban).unwrap(),
+            json!({ "ip": "10.0.0.1" })
+        );
+
+        let reply = json!({ "id": "agent:spammer", "seconds": 3600 });
+        let reply = serde_json::from_value::<BanReply>(reply).unwrap();
+        assert_eq!(reply.id, Some(id.clone()));
+        assert_eq!(reply.seconds, Some(3600));
+
+        let unban = Unban::new(BanTarget::Id(id.clone()));
+        assert_eq!(
+            serde_json::to_value(&unban).unwrap(),
+            json!({ "id": "agent:spammer" })
+        );
+        let reply = serde_json::from_value::<UnbanReply>(json!({ "ip": "10.0.0.1" })).unwrap();
+        assert_eq!(reply.ip.as_deref(), Some("10.0.0.1"));
+
+        let both = Unban {
+            id: Some(id),
+            ip: Some("10.0.0.1".to_string()),
+        };
+        assert_eq!(both.target(), None);
+    }
+
+    #[test]
+    fn edit_message_skips_unset_fields() {
+        let edit = EditMessage {
+            id: MessageId(Snowflake(1)),
+            previous_edit_id: None,
+            parent: None,
+            content: None,
+            delete: true,
+            announce: false,
+        };
+        assert_eq!(
+            serde_json::to_value(&edit).unwrap(),
+            json!({ "id": "0000000000001", "delete": true, "announce": false })
+        );
+
+        let edit = EditMessage {
+            previous_edit_id: Some(Snowflake(2)),
+            content: Some("edited".to_string()),
+            ..edit
+        };
+        assert_eq!(
+            serde_json::to_value(&edit).unwrap(),
+            json!({
+                "id": "0000000000001",
+                "previous_edit_id": "0000000000002",
+                "content": "edited",
+                "delete": true,
+                "announce": false,
+            })
+        );
+    }
+}
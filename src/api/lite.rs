@@ -0,0 +1,397 @@
+//! Lightweight variants of heavy types for processing lots of data offline.
+
+use std::borrow::Cow;
+use std::fmt;
+
+use serde::de::{self, DeserializeOwned, MapAccess, Visitor};
+use serde::ser::{self, SerializeMap};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde_json::value::RawValue;
+
+use super::{LogReply, Message, MessageId, SnapshotEvent, Time};
+
+/// An object key, borrowed from the input if possible.
+struct Key<'a>(Cow<'a, str>);
+
+impl<'de> Deserialize<'de> for Key<'de> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct KeyVisitor;
+
+        impl<'de> Visitor<'de> for KeyVisitor {
+            type Value = Key<'de>;
+
+            fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                f.write_str("a string")
+            }
+
+            fn visit_borrowed_str<E: de::Error>(self, v: &'de str) -> Result<Key<'de>, E> {
+                Ok(Key(Cow::Borrowed(v)))
+            }
+
+            fn visit_str<E: de::Error>(self, v: &str) -> Result<Key<'de>, E> {
+                Ok(Key(Cow::Owned(v.to_string())))
+            }
+        }
+
+        deserializer.deserialize_str(KeyVisitor)
+    }
+}
+
+/// The fields of a JSON object that weren't parsed, in their original order.
+///
+/// The values are borrowed from the input, so copying them into a single
+/// [`RawValue`] is the only allocation needed for all of them.
+struct RawFields<'a>(Vec<(Key<'a>, &'a RawValue)>);
+
+impl<'de> RawFields<'de> {
+    /// Visit all fields of an object, keeping those that `parse` doesn't
+    /// consume.
+    ///
+    /// `parse` must either consume the field's value and return `true`, or
+    /// leave it alone and return `false`.
+    fn visit<A, F>(map: &mut A, mut parse: F) -> Result<Self, A::Error>
+    where
+        A: MapAccess<'de>,
+        F: FnMut(&str, &mut A) -> Result<bool, A::Error>,
+    {
+        let mut fields = vec![];
+        while let Some(key) = map.next_key::<Key<'de>>()? {
+            if !parse(&key.0, map)? {
+                fields.push((key, map.next_value()?));
+            }
+        }
+        Ok(Self(fields))
+    }
+
+    fn get(&self, key: &str) -> Option<&'de RawValue> {
+        self.0.iter().find(|(k, _)| k.0 == key).map(|(_, v)| *v)
+    }
+
+    fn from_raw(raw: &'de RawValue) -> serde_json::Result<Self> {
+        serde_json::from_str(raw.get())
+    }
+
+    fn to_raw(&self) -> serde_json::Result<Box<RawValue>> {
+        serde_json::value::to_raw_value(self)
+    }
+
+    fn serialize_entries<M: SerializeMap>(&self, map: &mut M) -> Result<(), M::Error> {
+        for (key, value) in &self.0 {
+            map.serialize_entry(&key.0, value)?;
+        }
+        Ok(())
+    }
+}
+
+impl Serialize for RawFields<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(Some(self.0.len()))?;
+        self.serialize_entries(&mut map)?;
+        map.end()
+    }
+}
+
+impl<'de> Deserialize<'de> for RawFields<'de> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct RawFieldsVisitor;
+
+        impl<'de> Visitor<'de> for RawFieldsVisitor {
+            type Value = RawFields<'de>;
+
+            fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                f.write_str("an object")
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<RawFields<'de>, A::Error> {
+                RawFields::visit(&mut map, |_, _| Ok(false))
+            }
+        }
+
+        deserializer.deserialize_map(RawFieldsVisitor)
+    }
+}
+
+/// Parse a lightweight type's JSON representation as the full type.
+fn complete<T: Serialize, U: DeserializeOwned>(lite: &T) -> serde_json::Result<U> {
+    serde_json::from_str(&serde_json::to_string(lite)?)
+}
+
+/// A [`Message`] whose sender and less common fields aren't parsed.
+///
+/// Parsing a [`Message`] fully also parses its sender's
+/// [`SessionView`](super::SessionView), which dominates the runtime when
+/// processing lots of messages offline. This type keeps every field that isn't
+/// parsed as raw JSON instead. Nothing is lost, so it serializes to the same
+/// JSON it was parsed from and can be completed via [`TryFrom`] on demand.
+///
+/// The raw fields are borrowed from the input while parsing, so this type can
+/// only be deserialized via [`serde_json::from_str`] or
+/// [`serde_json::from_slice`], not from a reader or a [`serde_json::Value`].
+/// Whether it is actually faster depends on how much of the data can be
+/// skipped. The `lite_parse` example compares both on a generated corpus.
+#[derive(Debug, Clone)]
+pub struct MessageLite {
+    /// The id of the message (unique within a room).
+    pub id: MessageId,
+    /// The unix timestamp of when the message was posted.
+    pub time: Time,
+    /// The content of the message (client-defined).
+    pub content: String,
+    sender_name: String,
+    rest: Box<RawValue>,
+}
+
+impl MessageLite {
+    /// The name of the message's sender at the time the message was sent.
+    pub fn sender_name(&self) -> &str {
+        &self.sender_name
+    }
+
+    /// All fields except [`Self::id`], [`Self::time`] and [`Self::content`] as
+    /// a raw JSON object, including the sender.
+    pub fn rest(&self) -> &RawValue {
+        &self.rest
+    }
+}
+
+impl Serialize for MessageLite {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let rest = RawFields::from_raw(&self.rest).map_err(ser::Error::custom)?;
+        let mut map = serializer.serialize_map(Some(rest.0.len() + 3))?;
+        map.serialize_entry("id", &self.id)?;
+        map.serialize_entry("time", &self.time)?;
+        map.serialize_entry("content", &self.content)?;
+        rest.serialize_entries(&mut map)?;
+        map.end()
+    }
+}
+
+impl<'de> Deserialize<'de> for MessageLite {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct MessageLiteVisitor;
+
+        impl<'de> Visitor<'de> for MessageLiteVisitor {
+            type Value = MessageLite;
+
+            fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                f.write_str("a message")
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<MessageLite, A::Error> {
+                let mut id = None;
+                let mut time = None;
+                let mut content = None;
+                let rest = RawFields::visit(&mut map, |key, map| {
+                    match key {
+                        "id" => id = Some(map.next_value()?),
+                        "time" => time = Some(map.next_value()?),
+                        "content" => content = Some(map.next_value()?),
+                        _ => return Ok(false),
+                    }
+                    Ok(true)
+                })?;
+
+                #[derive(Deserialize)]
+                struct Sender {
+                    name: String,
+                }
+
+                let sender = rest
+                    .get("sender")
+                    .ok_or_else(|| de::Error::missing_field("sender"))?;
+                let sender = serde_json::from_str::<Sender>(sender.get())
+                    .map_err(|err| de::Error::custom(format!("invalid sender: {err}")))?;
+
+                Ok(MessageLite {
+                    id: id.ok_or_else(|| de::Error::missing_field("id"))?,
+                    time: time.ok_or_else(|| de::Error::missing_field("time"))?,
+                    content: content.ok_or_else(|| de::Error::missing_field("content"))?,
+                    sender_name: sender.name,
+                    rest: rest.to_raw().map_err(de::Error::custom)?,
+                })
+            }
+        }
+
+        deserializer.deserialize_map(MessageLiteVisitor)
+    }
+}
+
+impl TryFrom<MessageLite> for Message {
+    type Error = serde_json::Error;
+
+    fn try_from(lite: MessageLite) -> Result<Self, Self::Error> {
+        complete(&lite)
+    }
+}
+
+/// A [`LogReply`] containing [`MessageLite`]s.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LogReplyLite {
+    /// List of messages returned.
+    pub log: Vec<MessageLite>,
+    /// Messages prior to this snowflake were returned.
+    pub before: Option<MessageId>,
+}
+
+impl TryFrom<LogReplyLite> for LogReply {
+    type Error = serde_json::Error;
+
+    fn try_from(lite: LogReplyLite) -> Result<Self, Self::Error> {
+        Ok(Self {
+            log: (lite.log.into_iter())
+                .map(Message::try_from)
+                .collect::<Result<_, _>>()?,
+            before: lite.before,
+        })
+    }
+}
+
+/// A [`SnapshotEvent`] containing [`MessageLite`]s and whose other fields
+/// aren't parsed.
+#[derive(Debug, Clone)]
+pub struct SnapshotEventLite {
+    /// The most recent messages posted to the room (currently up to 100).
+    pub log: Vec<MessageLite>,
+    rest: Box<RawValue>,
+}
+
+impl SnapshotEventLite {
+    /// All fields except [`Self::log`] as a raw JSON object.
+    pub fn rest(&self) -> &RawValue {
+        &self.rest
+    }
+}
+
+impl Serialize for SnapshotEventLite {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let rest = RawFields::from_raw(&self.rest).map_err(ser::Error::custom)?;
+        let mut map = serializer.serialize_map(Some(rest.0.len() + 1))?;
+        map.serialize_entry("log", &self.log)?;
+        rest.serialize_entries(&mut map)?;
+        map.end()
+    }
+}
+
+impl<'de> Deserialize<'de> for SnapshotEventLite {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct SnapshotEventLiteVisitor;
+
+        impl<'de> Visitor<'de> for SnapshotEventLiteVisitor {
+            type Value = SnapshotEventLite;
+
+            fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                f.write_str("a snapshot-event")
+            }
+
+            fn visit_map<A: MapAccess<'de>>(
+                self,
+                mut map: A,
+            ) -> Result<SnapshotEventLite, A::Error> {
+                let mut log = None;
+                let rest = RawFields::visit(&mut map, |key, map| {
+                    if key != "log" {
+                        return Ok(false);
+                    }
+                    log = Some(map.next_value()?);
+                    Ok(true)
+                })?;
+
+                Ok(SnapshotEventLite {
+                    log: log.ok_or_else(|| de::Error::missing_field("log"))?,
+                    rest: rest.to_raw().map_err(de::Error::custom)?,
+                })
+            }
+        }
+
+        deserializer.deserialize_map(SnapshotEventLiteVisitor)
+    }
+}
+
+impl TryFrom<SnapshotEventLite> for SnapshotEvent {
+    type Error = serde_json::Error;
+
+    fn try_from(lite: SnapshotEventLite) -> Result<Self, Self::Error> {
+        complete(&lite)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::env;
+
+    use proptest::prelude::*;
+    use serde_json::{json, Value};
+
+    use crate::api::{LogReply, Message, SnapshotEvent};
+
+    use super::{LogReplyLite, MessageLite, SnapshotEventLite};
+
+    fn config() -> ProptestConfig {
+        match env::var_os("PROPTEST_CASES") {
+            Some(_) => ProptestConfig::default(),
+            None => ProptestConfig::with_cases(64),
+        }
+    }
+
+    proptest! {
+        #![proptest_config(config())]
+
+        #[test]
+        fn message_round_trip(msg in any::<Message>()) {
+            let json = serde_json::to_string(&msg).unwrap();
+            let lite = serde_json::from_str::<MessageLite>(&json).unwrap();
+            prop_assert_eq!(lite.id, msg.id);
+            prop_assert_eq!(&lite.content, &msg.content);
+            prop_assert_eq!(lite.sender_name(), &msg.sender.name);
+
+            let expected = serde_json::to_value(&msg).unwrap();
+            prop_assert_eq!(serde_json::to_value(&lite).unwrap(), expected.clone());
+            let full = Message::try_from(lite).unwrap();
+            prop_assert_eq!(serde_json::to_value(&full).unwrap(), expected);
+        }
+
+        #[test]
+        fn log_reply_round_trip(reply in any::<LogReply>()) {
+            let expected = serde_json::to_value(&reply).unwrap();
+            let json = serde_json::to_string(&reply).unwrap();
+            let lite = serde_json::from_str::<LogReplyLite>(&json).unwrap();
+            prop_assert_eq!(serde_json::to_value(&lite).unwrap(), expected.clone());
+            let full = LogReply::try_from(lite).unwrap();
+            prop_assert_eq!(serde_json::to_value(&full).unwrap(), expected);
+        }
+
+        #[test]
+        fn snapshot_event_round_trip(snapshot in any::<SnapshotEvent>()) {
+            let expected = serde_json::to_value(&snapshot).unwrap();
+            let json = serde_json::to_string(&snapshot).unwrap();
+            let lite = serde_json::from_str::<SnapshotEventLite>(&json).unwrap();
+            prop_assert_eq!(lite.log.len(), snapshot.log.len());
+            prop_assert_eq!(serde_json::to_value(&lite).unwrap(), expected.clone());
+            let full = SnapshotEvent::try_from(lite).unwrap();
+            prop_assert_eq!(serde_json::to_value(&full).unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn unknown_fields_are_kept() {
+        let msg = json!({
+            "id": "0000000000001",
+            "time": 1,
+            "sender": { "name": "sender", "future": [1, 2, { "x": null }] },
+            "content": "hello",
+            "future": { "nested": true },
+        });
+        let lite = serde_json::from_str::<MessageLite>(&msg.to_string()).unwrap();
+        assert_eq!(lite.sender_name(), "sender");
+        assert_eq!(serde_json::to_value(&lite).unwrap(), msg);
+        let rest = serde_json::from_str::<Value>(lite.rest().get()).unwrap();
+        assert_eq!(rest["future"], json!({ "nested": true }));
+
+        // Completing fails since the sender is missing required fields
+        assert!(Message::try_from(lite).is_err());
+
+        let missing = json!({ "id": "0000000000001", "time": 1, "content": "" });
+        let err = serde_json::from_str::<MessageLite>(&missing.to_string()).unwrap_err();
+        assert!(err.to_string().contains("missing field `sender`"));
+    }
+}
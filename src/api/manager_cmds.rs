@@ -0,0 +1,272 @@
+//! Room manager commands.
+//!
+//! These commands are available to the client once a session successfully joins
+//! a room as a manager.
+
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+use super::packet::ErrorReason;
+use super::AccountId;
+
+/// Who or what a [`GrantAccess`] or [`RevokeAccess`] applies to.
+///
+/// The [`Debug`](fmt::Debug) impl hides passcodes.
+#[derive(Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AccessTarget {
+    /// An account.
+    Account(AccountId),
+    /// Anyone who knows the passcode.
+    Passcode(String),
+}
+
+impl fmt::Debug for AccessTarget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Account(id) => f.debug_tuple("Account").field(id).finish(),
+            Self::Passcode(_) => write!(f, "Passcode(<hidden>)"),
+        }
+    }
+}
+
+/// Grant an account or passcode access to a private room.
+///
+/// Usually, exactly one of [`Self::account_id`] and [`Self::passcode`] is set.
+/// See [`Self::new`] for a way to ensure this.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(test, derive(proptest_derive::Arbitrary))]
+pub struct GrantAccess {
+    /// The id of the account to grant access to.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub account_id: Option<AccountId>,
+    /// The passcode that grants access.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub passcode: Option<String>,
+}
+
+impl GrantAccess {
+    pub fn new(target: AccessTarget) -> Self {
+        let (account_id, passcode) = split_access_target(target);
+        Self {
+            account_id,
+            passcode,
+        }
+    }
+
+    /// The target of the grant, if exactly one of [`Self::account_id`] and
+    /// [`Self::passcode`] is set.
+    pub fn target(&self) -> Option<AccessTarget> {
+        join_access_target(&self.account_id, &self.passcode)
+    }
+}
+
+/// Confirms the [`GrantAccess`] command.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(test, derive(proptest_derive::Arbitrary))]
+pub struct GrantAccessReply {}
+
+/// Revoke access previously granted via [`GrantAccess`].
+///
+/// Usually, exactly one of [`Self::account_id`] and [`Self::passcode`] is set.
+/// See [`Self::new`] for a way to ensure this.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(test, derive(proptest_derive::Arbitrary))]
+pub struct RevokeAccess {
+    /// The id of the account to revoke access from.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub account_id: Option<AccountId>,
+    /// The passcode that should no longer grant access.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub passcode: Option<String>,
+}
+
+impl RevokeAccess {
+    pub fn new(target: AccessTarget) -> Self {
+        let (account_id, passcode) = split_access_target(target);
+        Self {
+            account_id,
+            passcode,
+        }
+    }
+
+    /// The target of the revocation, if exactly one of [`Self::account_id`]
+    /// and [`Self::passcode`] is set.
+    pub fn target(&self) -> Option<AccessTarget> {
+        join_access_target(&self.account_id, &self.passcode)
+    }
+}
+
+/// Confirms the [`RevokeAccess`] command.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(test, derive(proptest_derive::Arbitrary))]
+pub struct RevokeAccessReply {}
+
+/// The reason given by the server when rejecting a [`GrantAccess`] or
+/// [`RevokeAccess`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AccessErrorReason {
+    /// The target already has access.
+    AlreadyGranted,
+    /// The target doesn't have access that could be revoked.
+    NotGranted,
+    /// Any reason not modeled by the other variants.
+    Other(String),
+}
+
+impl AccessErrorReason {
+    pub fn parse(reason: &str) -> Self {
+        Self::from_reason(&ErrorReason::parse(reason), reason)
+    }
+
+    /// View the kind of an error reply to a [`GrantAccess`] or
+    /// [`RevokeAccess`] as an access error.
+    ///
+    /// The raw `message` is used for [`Self::Other`].
+    pub fn from_reason(reason: &ErrorReason, message: &str) -> Self {
+        match reason {
+            ErrorReason::Conflict => Self::AlreadyGranted,
+            ErrorReason::NotFound => Self::NotGranted,
+            _ => Self::Other(message.to_string()),
+        }
+    }
+}
+
+impl fmt::Display for AccessErrorReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::AlreadyGranted => write!(f, "already granted"),
+            Self::NotGranted => write!(f, "not granted"),
+            Self::Other(reason) => write!(f, "{reason}"),
+        }
+    }
+}
+
+/// Grant an account manager status in the room.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(test, derive(proptest_derive::Arbitrary))]
+pub struct GrantManager {
+    /// The id of the account to make a manager.
+    pub account_id: AccountId,
+}
+
+/// Confirms the [`GrantManager`] command.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(test, derive(proptest_derive::Arbitrary))]
+pub struct GrantManagerReply {}
+
+/// Revoke an account's manager status in the room.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(test, derive(proptest_derive::Arbitrary))]
+pub struct RevokeManager {
+    /// The id of the account that should no longer be a manager.
+    pub account_id: AccountId,
+}
+
+/// Confirms the [`RevokeManager`] command.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(test, derive(proptest_derive::Arbitrary))]
+pub struct RevokeManagerReply {}
+
+fn split_access_target(target: AccessTarget) -> (Option<AccountId>, Option<String>) {
+    match target {
+        AccessTarget::Account(id) => (Some(id), None),
+        AccessTarget::Passcode(passcode) => (None, Some(passcode)),
+    }
+}
+
+fn join_access_target(
+    account_id: &Option<AccountId>,
+    passcode: &Option<String>,
+) -> Option<AccessTarget> {
+    match (account_id, passcode) {
+        (Some(id), None) => Some(AccessTarget::Account(*id)),
+        (None, Some(passcode)) => Some(AccessTarget::Passcode(passcode.clone())),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json::json;
+
+    use crate::api::{AccountId, Data, Snowflake};
+
+    use super::{
+        AccessErrorReason, AccessTarget, GrantAccess, GrantManager, RevokeAccess, RevokeManager,
+    };
+
+    /// Serialize a command like it is sent to the server.
+    fn packet<C: Into<Data>>(cmd: C) -> serde_json::Value {
+        let data = cmd.into();
+        json!({ "type": data.packet_type(), "data": data.into_value().unwrap() })
+    }
+
+    #[test]
+    fn commands_match_api_docs() {
+        let account_id = AccountId(Snowflake(36));
+        let passcode = "hunter2".to_string();
+
+        assert_eq!(
+            packet(GrantAccess::new(AccessTarget::Account(account_id))),
+            json!({ "type": "grant-access", "data": { "account_id": "0000000000010" } })
+        );
+        assert_eq!(
+            packet(GrantAccess::new(AccessTarget::Passcode(passcode.clone()))),
+            json!({ "type": "grant-access", "data": { "passcode": "hunter2" } })
+        );
+        assert_eq!(
+            packet(RevokeAccess::new(AccessTarget::Account(account_id))),
+            json!({ "type": "revoke-access", "data": { "account_id": "0000000000010" } })
+        );
+        assert_eq!(
+            packet(RevokeAccess::new(AccessTarget::Passcode(passcode))),
+            json!({ "type": "revoke-access", "data": { "passcode": "hunter2" } })
+        );
+        assert_eq!(
+            packet(GrantManager { account_id }),
+            json!({ "type": "grant-manager", "data": { "account_id": "0000000000010" } })
+        );
+        assert_eq!(
+            packet(RevokeManager { account_id }),
+            json!({ "type": "revoke-manager", "data": { "account_id": "0000000000010" } })
+        );
+    }
+
+    #[test]
+    fn access_serialization() {
+        let account = AccessTarget::Account(AccountId(Snowflake(1)));
+        let grant = GrantAccess::new(account.clone());
+        assert_eq!(
+            serde_json::to_value(&grant).unwrap(),
+            json!({ "account_id": "0000000000001" })
+        );
+        assert_eq!(grant.target(), Some(account));
+
+        let passcode = AccessTarget::Passcode("hunter2".to_string());
+        let revoke = RevokeAccess::new(passcode.clone());
+        assert_eq!(
+            serde_json::to_value(&revoke).unwrap(),
+            json!({ "passcode": "hunter2" })
+        );
+        assert_eq!(revoke.target(), Some(passcode));
+    }
+
+    #[test]
+    fn access_error_reason() {
+        let parse = AccessErrorReason::parse;
+        assert_eq!(
+            parse("access already granted"),
+            AccessErrorReason::AlreadyGranted
+        );
+        assert_eq!(parse("Already Granted"), AccessErrorReason::AlreadyGranted);
+        assert_eq!(parse("capability not found"), AccessErrorReason::NotGranted);
+        assert_eq!(parse("access not granted"), AccessErrorReason::NotGranted);
+        assert_eq!(
+            parse("access denied"),
+            AccessErrorReason::Other("access denied".to_string())
+        );
+        assert_eq!(parse("no such capability").to_string(), "not granted");
+    }
+}
@@ -1,4 +1,7 @@
-use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::time::Duration;
+
+use serde::{ser, Deserialize, Serialize};
 use serde_json::Value;
 
 use super::PacketType;
@@ -22,6 +25,11 @@ pub trait Command {
 
 macro_rules! packets {
     ( $( $name:ident, )*) => {
+        // The generated macro needs its own metavariables, so we need to smuggle
+        // a `$` into the expansion.
+        packets!(@with_dollar ($) $( $name, )*);
+    };
+    ( @with_dollar ($d:tt) $( $name:ident, )*) => {
         #[derive(Debug, Clone)]
         #[non_exhaustive]
         pub enum Data {
@@ -29,7 +37,51 @@ macro_rules! packets {
             Unimplemented,
         }
 
+        /// Invoke a macro with the names of all packet types modeled by
+        /// [`Data`].
+        ///
+        /// The macro is called once with a comma-separated list of the names,
+        /// including a trailing comma. Every name corresponds to a variant of
+        /// [`Data`], a variant of [`PacketType`](crate::api::PacketType) and a
+        /// struct in [`api`](crate::api).
+        ///
+        /// Since [`Data`] is `#[non_exhaustive]`, matching on it always requires
+        /// a wildcard arm. By generating code using this macro instead, new
+        /// packet types can't be missed silently when updating euphoxide.
+        ///
+        /// # Example
+        ///
+        /// ```
+        /// use euphoxide::api::Data;
+        ///
+        /// macro_rules! describe {
+        ///     ( $( $name:ident, )* ) => {
+        ///         fn describe(data: &Data) -> &'static str {
+        ///             match data {
+        ///                 $( Data::$name(_) => stringify!($name), )*
+        ///                 _ => "unimplemented",
+        ///             }
+        ///         }
+        ///     };
+        /// }
+        ///
+        /// euphoxide::for_each_packet_type!(describe);
+        ///
+        /// let data = Data::from(euphoxide::api::Who {});
+        /// assert_eq!(describe(&data), "Who");
+        /// ```
+        #[macro_export]
+        macro_rules! for_each_packet_type {
+            ( $d callback:ident ) => {
+                $d callback! { $( $name, )* }
+            };
+        }
+
         impl Data {
+            /// The packet types modeled by this enum, in the same order as
+            /// passed to [`for_each_packet_type!`].
+            pub const MODELED_TYPES: &'static [PacketType] = &[ $( PacketType::$name, )* ];
+
             pub fn from_value(ptype: PacketType, value: Value) -> serde_json::Result<Self> {
                 Ok(match ptype {
                     $( PacketType::$name => Self::$name(serde_json::from_value(value)?), )*
@@ -37,10 +89,16 @@ macro_rules! packets {
                 })
             }
 
+            /// Serialize the packet's data.
+            ///
+            /// Fails for [`Data::Unimplemented`], which can't be serialized
+            /// since it doesn't contain any data.
             pub fn into_value(self) -> serde_json::Result<Value> {
                 Ok(match self{
                     $( Self::$name(p) => serde_json::to_value(p)?, )*
-                    Self::Unimplemented => panic!("using unimplemented data"),
+                    Self::Unimplemented => {
+                        return Err(ser::Error::custom("using unimplemented data"))
+                    }
                 })
             }
 
@@ -80,6 +138,17 @@ macro_rules! commands {
                 type Reply = super::$rpl;
             }
         )*
+
+        impl PacketType {
+            /// The type of the reply the server sends in response to a command
+            /// of this type, or [`None`] if this is not a command.
+            pub fn reply_type(self) -> Option<Self> {
+                match self {
+                    $( Self::$cmd => Some(Self::$rpl), )*
+                    _ => None,
+                }
+            }
+        }
     };
 }
 
@@ -134,6 +203,21 @@ packets! {
     ResendVerificationEmailReply,
     ResetPassword,
     ResetPasswordReply,
+    // Room host commands
+    Ban,
+    BanReply,
+    EditMessage,
+    EditMessageReply,
+    GrantAccess,
+    GrantAccessReply,
+    GrantManager,
+    GrantManagerReply,
+    RevokeAccess,
+    RevokeAccessReply,
+    RevokeManager,
+    RevokeManagerReply,
+    Unban,
+    UnbanReply,
 }
 
 commands! {
@@ -156,6 +240,31 @@ commands! {
     RegisterAccount => RegisterAccountReply,
     ResendVerificationEmail => ResendVerificationEmailReply,
     ResetPassword => ResetPasswordReply,
+    // Room host commands
+    Ban => BanReply,
+    EditMessage => EditMessageReply,
+    GrantAccess => GrantAccessReply,
+    GrantManager => GrantManagerReply,
+    RevokeAccess => RevokeAccessReply,
+    RevokeManager => RevokeManagerReply,
+    Unban => UnbanReply,
+}
+
+/// Where a packet was received, see [`ParsedPacket::seq`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct PacketSeq {
+    /// Identifies the connection the packet was received on.
+    ///
+    /// Every [`Conn`](crate::conn::Conn) created in this process has a
+    /// different generation. Later connections have higher generations.
+    pub generation: u64,
+    /// Position of the packet among all packets received on the connection,
+    /// starting at zero.
+    ///
+    /// Packets the connection skipped (see
+    /// [`MalformedPolicy::Skip`](crate::conn::MalformedPolicy::Skip)) still
+    /// use up a sequence number, so they show up as gaps.
+    pub seq: u64,
 }
 
 #[derive(Debug, Clone)]
@@ -164,18 +273,37 @@ pub struct ParsedPacket {
     pub r#type: PacketType,
     pub content: Result<Data, String>,
     pub throttled: Option<String>,
+    /// Set by the [`Conn`](crate::conn::Conn) that received the packet.
+    ///
+    /// Not part of the packet sent over the wire.
+    pub seq: Option<PacketSeq>,
 }
 
 impl ParsedPacket {
     pub fn from_packet(packet: Packet) -> serde_json::Result<Self> {
+        match Self::from_packet_lenient(packet) {
+            (_, Some(err)) => Err(err),
+            (packet, None) => Ok(packet),
+        }
+    }
+
+    /// Like [`Self::from_packet`], but falls back to [`Data::Unimplemented`]
+    /// if the packet's data can't be deserialized.
+    ///
+    /// Also returns the error that caused the fallback, if any.
+    pub fn from_packet_lenient(packet: Packet) -> (Self, Option<serde_json::Error>) {
         let id = packet.id;
         let r#type = packet.r#type;
 
+        let mut data_error = None;
         let content = if let Some(error) = packet.error {
             Err(error)
         } else {
             let data = packet.data.unwrap_or_default();
-            Ok(Data::from_value(r#type, data)?)
+            Ok(Data::from_value(r#type, data).unwrap_or_else(|err| {
+                data_error = Some(err);
+                Data::Unimplemented
+            }))
         };
 
         let throttled = if packet.throttled {
@@ -187,12 +315,24 @@ impl ParsedPacket {
             None
         };
 
-        Ok(Self {
+        let packet = Self {
             id,
             r#type,
             content,
             throttled,
-        })
+            seq: None,
+        };
+        (packet, data_error)
+    }
+
+    /// If the packet is an error reply, the kind of error.
+    pub fn error_reason(&self) -> Option<ErrorReason> {
+        self.content.as_ref().err().map(|e| ErrorReason::parse(e))
+    }
+
+    /// If the packet was throttled, why.
+    pub fn throttle_reason(&self) -> Option<ThrottleReason> {
+        self.throttled.as_deref().map(ThrottleReason::parse)
     }
 
     pub fn into_packet(self) -> serde_json::Result<Packet> {
@@ -221,3 +361,425 @@ impl ParsedPacket {
         })
     }
 }
+
+/// The kind of error in an error reply, see [`ParsedPacket::content`].
+///
+/// The server only sends a human-readable message, so this is a best guess
+/// based on the messages known to be sent by heim. This is the only place
+/// where error messages are classified. Command-specific reasons like
+/// [`SendErrorReason`](crate::api::SendErrorReason) and
+/// [`AccessErrorReason`](crate::api::AccessErrorReason) are views over it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ErrorReason {
+    /// The session isn't allowed to do this, e.g. because it isn't logged in
+    /// or lacks the required privileges.
+    AccessDenied,
+    /// The room, message, account or whatever else the command referred to
+    /// doesn't exist.
+    ///
+    /// This includes the parent of a [`Send`](crate::api::Send) and access that
+    /// could not be revoked because it was never granted.
+    NotFound,
+    /// The command would have no effect because its target already is in the
+    /// requested state, e.g. access that is already granted.
+    Conflict,
+    /// A message would be nested too deeply within its thread.
+    ThreadTooDeep,
+    /// The command or its arguments are invalid.
+    BadRequest,
+    /// The session is sending too much.
+    RateLimited,
+    /// Something went wrong on the server's side.
+    Internal,
+    /// Any message not recognized as one of the other variants.
+    Unknown(String),
+}
+
+impl ErrorReason {
+    pub fn parse(message: &str) -> Self {
+        let lower = message.to_lowercase();
+        let any = |needles: &[&str]| needles.iter().any(|n| lower.contains(n));
+        if any(&["rate limit", "too many", "throttl", "slow down"]) {
+            Self::RateLimited
+        } else if any(&["too deep", "depth"]) {
+            Self::ThreadTooDeep
+        } else if any(&["already"]) {
+            Self::Conflict
+        } else if any(&[
+            "access denied",
+            "not allowed",
+            "unauthorized",
+            "forbidden",
+            "permission",
+            "not logged in",
+            "must be logged in",
+        ]) {
+            Self::AccessDenied
+        } else if any(&[
+            "not found",
+            "no such",
+            "not granted",
+            "does not have",
+            "parent",
+        ]) {
+            Self::NotFound
+        } else if any(&["internal"]) {
+            Self::Internal
+        } else if any(&[
+            "invalid",
+            "bad request",
+            "malformed",
+            "unknown command",
+            "unrecognized",
+            "too long",
+        ]) {
+            Self::BadRequest
+        } else {
+            Self::Unknown(message.to_string())
+        }
+    }
+}
+
+impl fmt::Display for ErrorReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::AccessDenied => write!(f, "access denied"),
+            Self::NotFound => write!(f, "not found"),
+            Self::Conflict => write!(f, "conflict"),
+            Self::ThreadTooDeep => write!(f, "thread too deep"),
+            Self::BadRequest => write!(f, "bad request"),
+            Self::RateLimited => write!(f, "rate limited"),
+            Self::Internal => write!(f, "internal error"),
+            Self::Unknown(message) => write!(f, "{message}"),
+        }
+    }
+}
+
+/// Why the server throttled a packet, see [`ParsedPacket::throttled`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ThrottleReason {
+    /// Generic flood protection.
+    ///
+    /// Reasons not modeled by the other variants end up here as well, since
+    /// being throttled always means the client is sending too much.
+    Flood,
+    /// The room is in slow mode and only allows one message per interval.
+    ///
+    /// The interval is only known if the reason mentions it.
+    SlowMode(Option<Duration>),
+}
+
+impl ThrottleReason {
+    pub fn parse(reason: &str) -> Self {
+        let lower = reason.to_lowercase();
+        let slow_mode = ["slow mode", "slow-mode", "slow_mode", "slowmode"]
+            .into_iter()
+            .any(|s| lower.contains(s));
+        if slow_mode {
+            Self::SlowMode(parse_interval(&lower))
+        } else {
+            Self::Flood
+        }
+    }
+}
+
+/// Find the first number followed by a time unit (or no unit at all, meaning
+/// seconds) in a lowercase string.
+fn parse_interval(reason: &str) -> Option<Duration> {
+    let mut rest = reason;
+    while let Some(start) = rest.find(|c: char| c.is_ascii_digit()) {
+        rest = &rest[start..];
+        let end = rest
+            .find(|c: char| !c.is_ascii_digit() && c != '.')
+            .unwrap_or(rest.len());
+        let (number, after) = rest.split_at(end);
+        rest = after;
+
+        let unit = after.trim_start();
+        let unit = &unit[..unit
+            .find(|c: char| !c.is_alphabetic())
+            .unwrap_or(unit.len())];
+        let secs_per_unit = match unit {
+            "ms" | "msec" | "millisecond" | "milliseconds" => 0.001,
+            "" | "s" | "sec" | "secs" | "second" | "seconds" => 1.0,
+            "m" | "min" | "mins" | "minute" | "minutes" => 60.0,
+            "h" | "hour" | "hours" => 3600.0,
+            _ => continue,
+        };
+        if let Ok(number) = number.parse::<f64>() {
+            return Duration::try_from_secs_f64(number * secs_per_unit).ok();
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod test {
+    use std::env;
+    use std::time::Duration;
+
+    use proptest::prelude::*;
+    use serde_json::Value;
+
+    use super::{Data, ErrorReason, Packet, PacketType, ParsedPacket, ThrottleReason};
+
+    /// Parse a packet the same way [`Conn`](crate::conn::Conn) does.
+    fn parse(text: &str) -> serde_json::Result<ParsedPacket> {
+        ParsedPacket::from_packet(serde_json::from_str::<Packet>(text)?)
+    }
+
+    /// Keep the number of cases low enough to run as part of the normal test
+    /// suite. Set the `PROPTEST_CASES` environment variable to search more
+    /// thoroughly.
+    fn config() -> ProptestConfig {
+        match env::var_os("PROPTEST_CASES") {
+            Some(_) => ProptestConfig::default(),
+            None => ProptestConfig::with_cases(64),
+        }
+    }
+
+    fn arb_json() -> impl Strategy<Value = Value> {
+        let leaf = prop_oneof![
+            Just(Value::Null),
+            any::<bool>().prop_map(Value::from),
+            any::<i64>().prop_map(Value::from),
+            any::<u64>().prop_map(Value::from),
+            any::<f64>().prop_map(Value::from),
+            any::<String>().prop_map(Value::from),
+            // Strings that look like snowflakes and other ids
+            "[0-9a-z+-]{0,14}".prop_map(Value::from),
+        ];
+        leaf.prop_recursive(8, 64, 8, |inner| {
+            prop_oneof![
+                prop::collection::vec(inner.clone(), 0..8).prop_map(Value::from),
+                prop::collection::btree_map("[a-z_]{1,12}", inner, 0..8)
+                    .prop_map(|map| Value::Object(map.into_iter().collect())),
+            ]
+        })
+    }
+
+    /// JSON objects resembling packets, so the parser gets past the outer
+    /// layer more often than with completely arbitrary JSON.
+    fn arb_packet_json() -> impl Strategy<Value = Value> {
+        (
+            prop::option::of(arb_json()),
+            prop::sample::select(Data::MODELED_TYPES),
+            prop::option::of(arb_json()),
+            prop::option::of(arb_json()),
+            prop::option::of(arb_json()),
+        )
+            .prop_map(|(id, r#type, data, error, throttled)| {
+                let mut packet = serde_json::json!({ "type": r#type });
+                let object = packet.as_object_mut().unwrap();
+                for (key, value) in [("id", id), ("data", data), ("error", error)] {
+                    if let Some(value) = value {
+                        object.insert(key.to_string(), value);
+                    }
+                }
+                if let Some(throttled) = throttled {
+                    object.insert("throttled".to_string(), Value::Bool(true));
+                    object.insert("throttled_reason".to_string(), throttled);
+                }
+                packet
+            })
+    }
+
+    macro_rules! arb_data {
+        ( $( $name:ident, )* ) => {
+            /// Any modeled [`Data`].
+            fn arb_data() -> impl Strategy<Value = Data> {
+                prop::strategy::Union::new(vec![
+                    $( any::<crate::api::$name>().prop_map(Data::from).boxed(), )*
+                ])
+            }
+        };
+    }
+
+    for_each_packet_type!(arb_data);
+
+    fn arb_parsed_packet() -> impl Strategy<Value = ParsedPacket> {
+        (
+            prop::option::of(any::<String>()),
+            prop_oneof![arb_data().prop_map(Ok), any::<String>().prop_map(Err),],
+            prop::sample::select(Data::MODELED_TYPES),
+            prop::option::of(any::<String>()),
+        )
+            .prop_map(|(id, content, error_type, throttled)| ParsedPacket {
+                id,
+                r#type: match &content {
+                    Ok(data) => data.packet_type(),
+                    Err(_) => error_type,
+                },
+                content,
+                throttled,
+                seq: None,
+            })
+    }
+
+    macro_rules! packet_types {
+        ( $( $name:ident, )* ) => {
+            vec![ $( PacketType::$name, )* ]
+        };
+    }
+
+    #[test]
+    fn modeled_types() {
+        let types: Vec<PacketType> = for_each_packet_type!(packet_types);
+        assert_eq!(types, Data::MODELED_TYPES);
+    }
+
+    #[test]
+    fn unimplemented_data_does_not_serialize() {
+        assert!(Data::Unimplemented.into_value().is_err());
+
+        // Known, but unmodeled packet types are parsed as unimplemented data
+        let packet = parse(r#"{"type": "staff-invade-reply", "data": {}}"#).unwrap();
+        assert!(matches!(packet.content, Ok(Data::Unimplemented)));
+        assert!(packet.into_packet().is_err());
+    }
+
+    #[test]
+    fn deeply_nested_values() {
+        let depth = 1_000_000;
+
+        let array = format!("{}{}", "[".repeat(depth), "]".repeat(depth));
+        assert!(parse(&array).is_err());
+
+        let object = format!("{}null{}", r#"{"a":"#.repeat(depth), "}".repeat(depth));
+        assert!(parse(&object).is_err());
+
+        let data = format!(
+            r#"{{"type": "send-event", "data": {}{}}}"#,
+            "[".repeat(depth),
+            "]".repeat(depth),
+        );
+        assert!(parse(&data).is_err());
+
+        // serde_json's recursion limit of 128 levels includes the packet itself.
+        // Below it, the data is rejected for not being a message.
+        let nested = |depth: usize| {
+            let data = format!(
+                r#"{{"type": "send-event", "data": {}{}}}"#,
+                "[".repeat(depth),
+                "]".repeat(depth),
+            );
+            parse(&data).unwrap_err().to_string()
+        };
+        assert!(nested(127).contains("recursion limit exceeded"));
+        assert!(nested(126).contains("invalid type"));
+    }
+
+    #[test]
+    fn huge_values() {
+        let len = 1_000_000;
+
+        let content = "a".repeat(len);
+        let packet = serde_json::json!({
+            "type": "send",
+            "data": { "content": content },
+        });
+        let packet = parse(&packet.to_string()).unwrap();
+        match packet.content {
+            Ok(Data::Send(send)) => assert_eq!(send.content.len(), len),
+            other => panic!("unexpected content: {other:?}"),
+        }
+
+        let packet = serde_json::json!({
+            "type": "log-reply",
+            "data": { "log": vec![0; len], "before": null },
+        });
+        assert!(parse(&packet.to_string()).is_err());
+    }
+
+    #[test]
+    fn error_reasons() {
+        let reason = ErrorReason::parse;
+        assert_eq!(reason("access denied"), ErrorReason::AccessDenied);
+        assert_eq!(reason("Not Logged In"), ErrorReason::AccessDenied);
+        assert_eq!(reason("room not found"), ErrorReason::NotFound);
+        assert_eq!(reason("message not found"), ErrorReason::NotFound);
+        assert_eq!(reason("no such account"), ErrorReason::NotFound);
+        assert_eq!(reason("invalid command"), ErrorReason::BadRequest);
+        assert_eq!(reason("name too long"), ErrorReason::BadRequest);
+        assert_eq!(reason("rate limited"), ErrorReason::RateLimited);
+        assert_eq!(reason("too many requests"), ErrorReason::RateLimited);
+        assert_eq!(reason("internal server error"), ErrorReason::Internal);
+        assert_eq!(reason("invalid parent"), ErrorReason::NotFound);
+        assert_eq!(reason("access not granted"), ErrorReason::NotFound);
+        assert_eq!(reason("already granted"), ErrorReason::Conflict);
+        assert_eq!(reason("thread too deep"), ErrorReason::ThreadTooDeep);
+        assert_eq!(reason("nope"), ErrorReason::Unknown("nope".to_string()));
+        assert_eq!(reason("room not found").to_string(), "not found");
+        assert_eq!(reason("nope").to_string(), "nope");
+
+        let packet = parse(r#"{"id":"1","type":"nick-reply","error":"access denied"}"#).unwrap();
+        assert_eq!(packet.error_reason(), Some(ErrorReason::AccessDenied));
+        let packet = parse(r#"{"type":"ping-event","data":{"time":0,"next":0}}"#).unwrap();
+        assert_eq!(packet.error_reason(), None);
+    }
+
+    #[test]
+    fn throttle_reasons() {
+        let slow_mode = |secs| ThrottleReason::SlowMode(Some(Duration::from_secs_f64(secs)));
+        let table = [
+            ("slow down", ThrottleReason::Flood),
+            ("flood protection", ThrottleReason::Flood),
+            ("no reason given", ThrottleReason::Flood),
+            ("", ThrottleReason::Flood),
+            ("wait 5 seconds", ThrottleReason::Flood),
+            ("slow mode", ThrottleReason::SlowMode(None)),
+            ("Room is in Slow-Mode", ThrottleReason::SlowMode(None)),
+            ("slowmode: 10", slow_mode(10.0)),
+            ("slow mode: wait 5s", slow_mode(5.0)),
+            ("slow mode (2.5 seconds)", slow_mode(2.5)),
+            ("slow mode: 1 message every 2 min", slow_mode(120.0)),
+            ("slow_mode 750ms", slow_mode(0.75)),
+            ("slow mode: 1 hour", slow_mode(3600.0)),
+            ("slow mode: 3 messages", ThrottleReason::SlowMode(None)),
+        ];
+        for (reason, expected) in table {
+            assert_eq!(ThrottleReason::parse(reason), expected, "{reason:?}");
+        }
+    }
+
+    proptest! {
+        #![proptest_config(config())]
+
+        #[test]
+        fn arbitrary_text_does_not_panic(text in any::<String>()) {
+            let _ = parse(&text);
+        }
+
+        #[test]
+        fn arbitrary_json_does_not_panic(json in arb_json()) {
+            let _ = parse(&json.to_string());
+        }
+
+        #[test]
+        fn arbitrary_packets_do_not_panic(json in arb_packet_json()) {
+            if let Ok(packet) = parse(&json.to_string()) {
+                // Re-serializing a received packet must not panic either
+                let _ = packet.into_packet();
+            }
+        }
+
+        #[test]
+        fn packets_round_trip(packet in arb_parsed_packet()) {
+            let id = packet.id.clone();
+            let r#type = packet.r#type;
+            let throttled = packet.throttled.clone();
+            let content = packet
+                .content
+                .clone()
+                .map(|data| data.into_value().unwrap());
+
+            let text = serde_json::to_string(&packet.into_packet().unwrap()).unwrap();
+            let parsed = parse(&text).unwrap();
+
+            prop_assert_eq!(parsed.id, id);
+            prop_assert_eq!(parsed.r#type, r#type);
+            prop_assert_eq!(parsed.throttled, throttled);
+            prop_assert_eq!(parsed.content.map(|data| data.into_value().unwrap()), content);
+        }
+    }
+}
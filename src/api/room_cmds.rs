@@ -3,12 +3,16 @@
 //! These commands are available to the client once a session successfully joins
 //! a room.
 
+use std::fmt;
+
 use serde::{Deserialize, Serialize};
 
+use super::packet::ErrorReason;
 use super::{Message, MessageId, PmId, SessionId, SessionView, UserId};
 
 /// Retrieve the full content of a single message in the room.
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(test, derive(proptest_derive::Arbitrary))]
 pub struct GetMessage {
     /// The id of the message to retrieve.
     pub id: MessageId,
@@ -16,6 +20,7 @@ pub struct GetMessage {
 
 /// The message retrieved by [`GetMessage`].
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(test, derive(proptest_derive::Arbitrary))]
 pub struct GetMessageReply(pub Message);
 
 /// Request messages from the room's message log.
@@ -24,6 +29,7 @@ pub struct GetMessageReply(pub Message);
 /// [`SnapshotEvent`](super::SnapshotEvent) (for example, when scrolling back
 /// further in history).
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(test, derive(proptest_derive::Arbitrary))]
 pub struct Log {
     /// Maximum number of messages to return (up to 1000).
     pub n: usize,
@@ -33,6 +39,7 @@ pub struct Log {
 
 /// List of messages from the room's message log.
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(test, derive(proptest_derive::Arbitrary))]
 pub struct LogReply {
     /// List of messages returned.
     pub log: Vec<Message>,
@@ -40,11 +47,74 @@ pub struct LogReply {
     pub before: Option<MessageId>,
 }
 
+/// How complete a [`LogReply`] is, see [`LogReply::completeness`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogCompleteness {
+    /// The reply contains at least as many messages as requested.
+    Complete,
+    /// The reply contains no messages, so there are no messages before the
+    /// requested id (or in the room at all).
+    StartOfLog,
+    /// The reply contains fewer messages than requested, but not none.
+    ///
+    /// Either the reply reached the start of the log, or the server truncated
+    /// it. Which one can only be found out by requesting the messages before
+    /// the oldest message in the reply: If there are none, the reply reached
+    /// the start of the log.
+    Short,
+    /// The reply doesn't answer the request it is judged against.
+    ///
+    /// Either its `before` differs from the requested one, or it contains
+    /// messages that aren't before the requested id.
+    Mismatched,
+}
+
+impl LogReply {
+    /// Judge how complete the reply to a [`Log`] command is.
+    ///
+    /// The server doesn't say whether it truncated a reply, so this only
+    /// compares the reply with the request, in this order:
+    ///
+    /// 1. A reply whose `before` differs from the request's or that contains a
+    ///    message not before the request's `before` is
+    ///    [`Mismatched`](LogCompleteness::Mismatched).
+    /// 2. A reply with at least `n` messages is
+    ///    [`Complete`](LogCompleteness::Complete).
+    /// 3. An empty reply is [`StartOfLog`](LogCompleteness::StartOfLog).
+    /// 4. Any other reply is [`Short`](LogCompleteness::Short).
+    pub fn completeness(&self, request: &Log) -> LogCompleteness {
+        let outside = |msg: &Message| request.before.is_some_and(|before| msg.id >= before);
+        if self.before != request.before || self.log.iter().any(outside) {
+            LogCompleteness::Mismatched
+        } else if self.log.len() >= request.n {
+            LogCompleteness::Complete
+        } else if self.log.is_empty() {
+            LogCompleteness::StartOfLog
+        } else {
+            LogCompleteness::Short
+        }
+    }
+
+    /// Whether messages may be missing from the reply to a [`Log`] command.
+    ///
+    /// This is the case for [`Short`](LogCompleteness::Short) and
+    /// [`Mismatched`](LogCompleteness::Mismatched) replies, see
+    /// [`Self::completeness`]. Since short replies are also sent at the start
+    /// of the log, this errs on the side of caution.
+    pub fn is_possibly_truncated(&self, request: &Log) -> bool {
+        matches!(
+            self.completeness(request),
+            LogCompleteness::Short | LogCompleteness::Mismatched
+        )
+    }
+}
+
 /// Set the name you present to the room.
 ///
 /// This name applies to all messages sent during this session, until the nick
 /// command is called again.
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(test, derive(proptest_derive::Arbitrary))]
 pub struct Nick {
     /// The requested name (maximum length 36 bytes).
     pub name: String,
@@ -55,6 +125,7 @@ pub struct Nick {
 /// Returns the session's former and new names (the server may modify the
 /// requested nick).
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(test, derive(proptest_derive::Arbitrary))]
 pub struct NickReply {
     /// The id of the session this name applies to.
     pub session_id: SessionId,
@@ -69,6 +140,7 @@ pub struct NickReply {
 /// Constructs a virtual room for private messaging between the client and the
 /// given [`UserId`].
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(test, derive(proptest_derive::Arbitrary))]
 pub struct PmInitiate {
     /// The id of the user to invite to chat privately.
     pub user_id: UserId,
@@ -76,6 +148,7 @@ pub struct PmInitiate {
 
 /// Provides the PMID for the requested private messaging room.
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(test, derive(proptest_derive::Arbitrary))]
 pub struct PmInitiateReply {
     /// The private chat can be accessed at `/room/pm:<pm_id>`.
     pub pm_id: PmId,
@@ -95,6 +168,7 @@ pub struct PmInitiateReply {
 /// [`SendEvent`](super::SendEvent), but will receive the same information in
 /// the [`SendReply`].
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(test, derive(proptest_derive::Arbitrary))]
 pub struct Send {
     /// The content of the message (client-defined).
     pub content: String,
@@ -106,15 +180,199 @@ pub struct Send {
 ///
 /// this includes the message id, which was populated by the server.
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(test, derive(proptest_derive::Arbitrary))]
 pub struct SendReply(pub Message);
 
+/// The reason given by the server when rejecting a [`Send`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SendErrorReason {
+    /// The message would be nested too deeply within its thread.
+    ThreadTooDeep,
+    /// The parent message does not exist.
+    InvalidParent,
+    /// Any reason not modeled by the other variants.
+    Other(String),
+}
+
+impl SendErrorReason {
+    pub fn parse(reason: &str) -> Self {
+        Self::from_reason(&ErrorReason::parse(reason), reason)
+    }
+
+    /// View the kind of an error reply to a [`Send`] as a send error.
+    ///
+    /// The only thing a send can refer to is its parent, so anything not found
+    /// is the parent. The raw `message` is used for [`Self::Other`].
+    pub fn from_reason(reason: &ErrorReason, message: &str) -> Self {
+        match reason {
+            ErrorReason::ThreadTooDeep => Self::ThreadTooDeep,
+            ErrorReason::NotFound => Self::InvalidParent,
+            _ => Self::Other(message.to_string()),
+        }
+    }
+}
+
+impl fmt::Display for SendErrorReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::ThreadTooDeep => write!(f, "thread too deep"),
+            Self::InvalidParent => write!(f, "invalid parent"),
+            Self::Other(reason) => write!(f, "{reason}"),
+        }
+    }
+}
+
 /// Request a list of sessions currently joined in the room.
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(test, derive(proptest_derive::Arbitrary))]
 pub struct Who {}
 
 /// Lists the sessions currently joined in the room.
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(test, derive(proptest_derive::Arbitrary))]
 pub struct WhoReply {
     /// A list of session views.
     pub listing: Vec<SessionView>,
 }
+
+/// Rooms with fewer sessions than this are never judged by
+/// [`WhoReply::is_implausibly_small`], since their size fluctuates too much.
+const WHO_PLAUSIBILITY_MIN_SESSIONS: usize = 20;
+
+impl WhoReply {
+    /// Whether the listing is implausibly small compared to the number of
+    /// sessions that were previously known to be in the room.
+    ///
+    /// The server may truncate the listings of very large rooms. A listing is
+    /// judged implausible if
+    ///
+    /// - it is empty even though `previous` is at least 2, or
+    /// - `previous` is at least 20 and the listing contains fewer than half as
+    ///   many sessions.
+    ///
+    /// Sessions rarely leave in such numbers between two listings, so an
+    /// implausible listing should be flagged instead of trusted.
+    pub fn is_implausibly_small(&self, previous: usize) -> bool {
+        let len = self.listing.len();
+        if len == 0 {
+            previous >= 2
+        } else {
+            previous >= WHO_PLAUSIBILITY_MIN_SESSIONS && len * 2 < previous
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::api::{Message, MessageId, SessionId, SessionView, Snowflake, Time, UserId};
+    use crate::test_util::{MessageBuilder, SessionViewBuilder};
+
+    use super::{Log, LogCompleteness, LogReply, SendErrorReason, WhoReply};
+
+    fn session(id: u64) -> SessionView {
+        SessionViewBuilder::new(format!("session {id}"))
+            .with_id(UserId(format!("agent:{id}")))
+            .with_session_id(SessionId(format!("{id}")))
+            .build()
+    }
+
+    fn message(id: u64) -> Message {
+        MessageBuilder::new(format!("message {id}"))
+            .with_id(MessageId(Snowflake(id)))
+            .with_time(Time(id as i64))
+            .with_sender(session(0))
+            .build()
+    }
+
+    fn id(id: u64) -> Option<MessageId> {
+        Some(MessageId(Snowflake(id)))
+    }
+
+    fn completeness(n: usize, before: Option<MessageId>, reply: LogReply) -> LogCompleteness {
+        reply.completeness(&Log { n, before })
+    }
+
+    fn reply(ids: impl IntoIterator<Item = u64>, before: Option<MessageId>) -> LogReply {
+        LogReply {
+            log: ids.into_iter().map(message).collect(),
+            before,
+        }
+    }
+
+    #[test]
+    fn log_completeness() {
+        use LogCompleteness::*;
+
+        assert_eq!(completeness(3, None, reply(1..=3, None)), Complete);
+        assert_eq!(completeness(3, id(10), reply(7..=9, id(10))), Complete);
+        assert_eq!(completeness(0, None, reply([], None)), Complete);
+        // More than requested isn't a problem
+        assert_eq!(completeness(2, id(10), reply(7..=9, id(10))), Complete);
+
+        assert_eq!(completeness(3, None, reply([], None)), StartOfLog);
+        assert_eq!(completeness(3, id(1), reply([], id(1))), StartOfLog);
+
+        // Either the start of the log or truncated
+        assert_eq!(completeness(3, None, reply(1..=2, None)), Short);
+        assert_eq!(completeness(1000, id(10), reply(1..=9, id(10))), Short);
+
+        // Replies to a different command
+        assert_eq!(completeness(3, id(10), reply(7..=9, None)), Mismatched);
+        assert_eq!(completeness(3, None, reply(7..=9, id(10))), Mismatched);
+        assert_eq!(completeness(3, id(10), reply([], id(5))), Mismatched);
+        // Messages that aren't before the requested id
+        assert_eq!(completeness(3, id(10), reply(8..=10, id(10))), Mismatched);
+
+        let request = Log {
+            n: 3,
+            before: id(10),
+        };
+        assert!(!reply(7..=9, id(10)).is_possibly_truncated(&request));
+        assert!(!reply([], id(10)).is_possibly_truncated(&request));
+        assert!(reply(8..=9, id(10)).is_possibly_truncated(&request));
+        assert!(reply(7..=9, None).is_possibly_truncated(&request));
+    }
+
+    #[test]
+    fn who_plausibility() {
+        let who = |len: u64| WhoReply {
+            listing: (0..len).map(session).collect(),
+        };
+
+        // Empty listings are implausible unless we were alone
+        assert!(!who(0).is_implausibly_small(0));
+        assert!(!who(0).is_implausibly_small(1));
+        assert!(who(0).is_implausibly_small(2));
+
+        // Small rooms fluctuate too much to judge
+        assert!(!who(1).is_implausibly_small(19));
+        assert!(!who(5).is_implausibly_small(10));
+
+        // Large rooms must not shrink to less than half
+        assert!(!who(10).is_implausibly_small(20));
+        assert!(who(9).is_implausibly_small(20));
+        assert!(who(100).is_implausibly_small(1000));
+        assert!(!who(500).is_implausibly_small(1000));
+        assert!(!who(2000).is_implausibly_small(1000));
+    }
+
+    #[test]
+    fn send_error_reason() {
+        let parse = SendErrorReason::parse;
+        assert_eq!(parse("thread too deep"), SendErrorReason::ThreadTooDeep);
+        assert_eq!(
+            parse("maximum thread depth exceeded"),
+            SendErrorReason::ThreadTooDeep
+        );
+        assert_eq!(parse("Thread Too Deep"), SendErrorReason::ThreadTooDeep);
+        assert_eq!(parse("invalid parent"), SendErrorReason::InvalidParent);
+        assert_eq!(parse("bad parent"), SendErrorReason::InvalidParent);
+        assert_eq!(parse("message not found"), SendErrorReason::InvalidParent);
+        assert_eq!(
+            parse("rate limited"),
+            SendErrorReason::Other("rate limited".to_string())
+        );
+        assert_eq!(parse("bad parent").to_string(), "invalid parent");
+        assert_eq!(parse("rate limited").to_string(), "rate limited");
+    }
+}
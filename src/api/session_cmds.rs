@@ -12,6 +12,7 @@ use super::{AuthOption, Time};
 /// This should be sent in response to a [`BounceEvent`](super::BounceEvent) at
 /// the beginning of a session.
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(test, derive(proptest_derive::Arbitrary))]
 pub struct Auth {
     /// The method of authentication.
     pub r#type: AuthOption,
@@ -21,6 +22,7 @@ pub struct Auth {
 
 /// Reports whether the [`Auth`] command succeeded.
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(test, derive(proptest_derive::Arbitrary))]
 pub struct AuthReply {
     /// True if authentication succeeded.
     pub success: bool,
@@ -33,6 +35,7 @@ pub struct AuthReply {
 /// The server will send back a [`PingReply`] with the same timestamp as soon as
 /// possible.
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(test, derive(proptest_derive::Arbitrary))]
 pub struct Ping {
     /// An arbitrary value, intended to be a unix timestamp.
     pub time: Time,
@@ -40,6 +43,7 @@ pub struct Ping {
 
 /// Response to a [`Ping`] command or [`PingEvent`](super::PingEvent).
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(test, derive(proptest_derive::Arbitrary))]
 pub struct PingReply {
     /// The timestamp of the ping being replied to.
     pub time: Option<Time>,
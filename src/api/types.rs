@@ -14,8 +14,11 @@ use jiff::Timestamp;
 use serde::{de, ser, Deserialize, Serialize};
 use serde_json::Value;
 
+use crate::content::MessageContent;
+
 /// Describes an account and its preferred name.
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(test, derive(proptest_derive::Arbitrary))]
 pub struct AccountView {
     /// The id of the account.
     pub id: AccountId,
@@ -25,6 +28,7 @@ pub struct AccountView {
 
 /// Mode of authentication.
 #[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[cfg_attr(test, derive(proptest_derive::Arbitrary))]
 #[serde(rename_all = "kebab-case")]
 pub enum AuthOption {
     /// Authentication with a passcode, where a key is derived from the passcode
@@ -37,6 +41,7 @@ pub enum AuthOption {
 /// It corresponds to a chat message, or a post, or any broadcasted event in a
 /// room that should appear in the log.
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(test, derive(proptest_derive::Arbitrary))]
 pub struct Message {
     /// The id of the message (unique within a room).
     pub id: MessageId,
@@ -69,10 +74,21 @@ pub struct Message {
     pub truncated: bool,
 }
 
+impl Message {
+    /// The message's content split along the conventions of the euphoria
+    /// client.
+    ///
+    /// See [`MessageContent`] for more details.
+    pub fn parsed_content(&self) -> MessageContent<'_> {
+        MessageContent::parse(&self.content)
+    }
+}
+
 /// The type of a packet.
 ///
 /// Not all of these types have their corresponding data modeled as a struct.
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(test, derive(proptest_derive::Arbitrary))]
 #[serde(rename_all = "kebab-case")]
 pub enum PacketType {
     // Asynchronous events
@@ -176,33 +192,33 @@ pub enum PacketType {
     ResetPasswordReply,
 
     // Room host commands
-    /// Not implemented.
+    /// See [`Ban`](super::Ban).
     Ban,
-    /// Not implemented.
+    /// See [`BanReply`](super::BanReply).
     BanReply,
-    /// Not implemented.
+    /// See [`EditMessage`](super::EditMessage).
     EditMessage,
-    /// Not implemented.
+    /// See [`EditMessageReply`](super::EditMessageReply).
     EditMessageReply,
-    /// Not implemented.
+    /// See [`GrantAccess`](super::GrantAccess).
     GrantAccess,
-    /// Not implemented.
+    /// See [`GrantAccessReply`](super::GrantAccessReply).
     GrantAccessReply,
-    /// Not implemented.
+    /// See [`GrantManager`](super::GrantManager).
     GrantManager,
-    /// Not implemented.
+    /// See [`GrantManagerReply`](super::GrantManagerReply).
     GrantManagerReply,
-    /// Not implemented.
+    /// See [`RevokeAccess`](super::RevokeAccess).
     RevokeAccess,
-    /// Not implemented.
+    /// See [`RevokeAccessReply`](super::RevokeAccessReply).
     RevokeAccessReply,
-    /// Not implemented.
+    /// See [`RevokeManager`](super::RevokeManager).
     RevokeManager,
-    /// Not implemented.
+    /// See [`RevokeManagerReply`](super::RevokeManagerReply).
     RevokeManagerReply,
-    /// Not implemented.
+    /// See [`Unban`](super::Unban).
     Unban,
-    /// Not implemented.
+    /// See [`UnbanReply`](super::UnbanReply).
     UnbanReply,
 
     // Staff commands
@@ -250,7 +266,8 @@ impl fmt::Display for PacketType {
 }
 
 /// Describes an account to its owner.
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(test, derive(proptest_derive::Arbitrary))]
 pub struct PersonalAccountView {
     /// The id of the account.
     pub id: AccountId,
@@ -261,7 +278,8 @@ pub struct PersonalAccountView {
 }
 
 /// Describes a session and its identity.
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(test, derive(proptest_derive::Arbitrary))]
 pub struct SessionView {
     /// The id of an agent or account (or bot).
     pub id: UserId,
@@ -291,6 +309,7 @@ pub struct SessionView {
 ///
 /// It is the base-36 encoding of an unsigned, 64-bit integer.
 #[derive(Debug, Clone, Copy, Hash, PartialEq, Eq, PartialOrd, Ord)]
+#[cfg_attr(test, derive(proptest_derive::Arbitrary))]
 pub struct Snowflake(pub u64);
 
 impl Snowflake {
@@ -308,6 +327,58 @@ impl Snowflake {
     /// range, and since message ids mostly consist of a timestamp, this
     /// approach should last until at least 2075.
     pub const MAX: Self = Snowflake(i64::MAX as u64);
+
+    /// Milliseconds between the unix epoch and [`Self::epoch`].
+    const EPOCH_MILLIS: i64 = 1_417_305_600_000;
+    const TIMESTAMP_SHIFT: u32 = 22;
+    const WORKER_SHIFT: u32 = 12;
+    const WORKER_MASK: u64 = 0x3ff;
+    const SEQ_MASK: u64 = 0xfff;
+
+    /// The time heim's snowflakes are relative to, 2014-11-30 00:00:00 UTC.
+    pub fn epoch() -> Timestamp {
+        Timestamp::from_millisecond(Self::EPOCH_MILLIS).expect("epoch is within jiff's range")
+    }
+
+    /// The smallest snowflake created at a certain time.
+    ///
+    /// Heim's snowflakes consist of the milliseconds since [`Self::epoch`]
+    /// followed by a 10-bit worker id and a 12-bit sequence number. This
+    /// function sets both of these to zero, so the result can be used as a
+    /// lower bound for all snowflakes created at or after that time.
+    ///
+    /// Times before [`Self::epoch`] result in `Snowflake(0)`. Times too late to
+    /// be represented without exceeding [`Self::MAX`] result in
+    /// [`Self::MAX`].
+    pub fn from_timestamp(timestamp: Timestamp) -> Self {
+        let millis = timestamp.as_millisecond() - Self::EPOCH_MILLIS;
+        if millis <= 0 {
+            return Self(0);
+        }
+        let millis = millis as u64;
+        if millis > Self::MAX.0 >> Self::TIMESTAMP_SHIFT {
+            return Self::MAX;
+        }
+        Self(millis << Self::TIMESTAMP_SHIFT)
+    }
+
+    /// The time the snowflake was created at, with millisecond precision.
+    pub fn timestamp(&self) -> Timestamp {
+        let millis = (self.0 >> Self::TIMESTAMP_SHIFT) as i64;
+        Timestamp::from_millisecond(Self::EPOCH_MILLIS + millis)
+            .expect("snowflake timestamps are within jiff's range")
+    }
+
+    /// The id of the worker that created the snowflake.
+    pub fn worker(&self) -> u16 {
+        ((self.0 >> Self::WORKER_SHIFT) & Self::WORKER_MASK) as u16
+    }
+
+    /// The snowflake's sequence number, which distinguishes snowflakes created
+    /// by the same worker within the same millisecond.
+    pub fn seq(&self) -> u16 {
+        (self.0 & Self::SEQ_MASK) as u16
+    }
 }
 
 impl fmt::Display for Snowflake {
@@ -403,6 +474,7 @@ impl<'de> Deserialize<'de> for Snowflake {
 /// Time is specified as a signed 64-bit integer, giving the number of seconds
 /// since the Unix Epoch.
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(test, derive(proptest_derive::Arbitrary))]
 pub struct Time(pub i64);
 
 impl Time {
@@ -427,6 +499,7 @@ impl Time {
 /// It is possible for this value to have no prefix and colon, and there is no
 /// fixed format for the unique value.
 #[derive(Debug, Clone, Hash, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[cfg_attr(test, derive(proptest_derive::Arbitrary))]
 pub struct UserId(pub String);
 
 impl fmt::Display for UserId {
@@ -461,6 +534,7 @@ impl UserId {
 /// This type is a wrapper around [`Snowflake`] meant for type safety. It is not
 /// specified in the euphoria API itself.
 #[derive(Debug, Clone, Copy, Hash, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[cfg_attr(test, derive(proptest_derive::Arbitrary))]
 pub struct AccountId(pub Snowflake);
 
 /// Identifies a message.
@@ -468,6 +542,7 @@ pub struct AccountId(pub Snowflake);
 /// This type is a wrapper around [`Snowflake`] meant for type safety. It is not
 /// specified in the euphoria API itself.
 #[derive(Debug, Clone, Copy, Hash, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[cfg_attr(test, derive(proptest_derive::Arbitrary))]
 pub struct MessageId(pub Snowflake);
 
 /// Identifies a private room.
@@ -475,6 +550,7 @@ pub struct MessageId(pub Snowflake);
 /// This type is a wrapper around [`Snowflake`] meant for type safety. It is not
 /// specified in the euphoria API itself.
 #[derive(Debug, Clone, Copy, Hash, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[cfg_attr(test, derive(proptest_derive::Arbitrary))]
 pub struct PmId(pub Snowflake);
 
 /// Identifies a session.
@@ -482,6 +558,102 @@ pub struct PmId(pub Snowflake);
 /// This type is a wrapper around [`String`] meant for type safety. It is not
 /// specified in the euphoria API itself.
 #[derive(Debug, Clone, Hash, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[cfg_attr(test, derive(proptest_derive::Arbitrary))]
 pub struct SessionId(pub String);
 
 // TODO Find out if an edit id is a MessageId or if it deserves a wrapper
+
+#[cfg(test)]
+mod test {
+    use jiff::civil::date;
+    use jiff::tz::TimeZone;
+    use jiff::{Timestamp, ToSpan};
+
+    use super::Snowflake;
+
+    #[test]
+    fn snowflake_fields() {
+        let time = Snowflake::epoch() + 24.hours();
+        let millis = 24 * 60 * 60 * 1000;
+        let snowflake = Snowflake(millis << 22 | 5 << 12 | 7);
+        assert_eq!(snowflake.timestamp(), time);
+        assert_eq!(snowflake.worker(), 5);
+        assert_eq!(snowflake.seq(), 7);
+        assert_eq!(Snowflake::from_timestamp(time), Snowflake(millis << 22));
+
+        let snowflake = Snowflake(u64::MAX);
+        assert_eq!(snowflake.worker(), 0x3ff);
+        assert_eq!(snowflake.seq(), 0xfff);
+    }
+
+    #[test]
+    fn snowflake_epoch() {
+        assert_eq!(Snowflake::epoch().to_string(), "2014-11-30T00:00:00Z");
+        assert_eq!(Snowflake(0).timestamp(), Snowflake::epoch());
+        assert_eq!(Snowflake::from_timestamp(Snowflake::epoch()), Snowflake(0));
+
+        // The timestamp survives the round trip through the wire format
+        let time = "2016-02-29T23:59:59.999Z".parse::<Timestamp>().unwrap();
+        let snowflake = Snowflake::from_timestamp(time).to_string();
+        assert_eq!(snowflake.parse::<Snowflake>().unwrap().timestamp(), time);
+    }
+
+    #[test]
+    fn snowflake_epoch_matches_heim() {
+        // Heim defines its epoch as `time.Date(2014, 12, 0, 0, 0, 0, 0, time.UTC)`
+        // in backend/snowflake.go. Go normalizes day 0 to the last day of the
+        // previous month, so the epoch is derived here the same way instead of
+        // going through the constant under test.
+        let epoch = date(2014, 12, 1)
+            .yesterday()
+            .unwrap()
+            .to_zoned(TimeZone::UTC)
+            .unwrap()
+            .timestamp();
+        assert_eq!(Snowflake::epoch(), epoch);
+
+        // 32 days after the epoch, worker 3, sequence number 42, encoded by
+        // hand. No id of a real message with a known time could be verified
+        // offline, so this is the closest substitute.
+        let snowflake = "00366l1zn8eui".parse::<Snowflake>().unwrap();
+        let time = "2015-01-01T00:00:00Z".parse::<Timestamp>().unwrap();
+        assert_eq!(snowflake.timestamp(), time);
+        assert_eq!((snowflake.worker(), snowflake.seq()), (3, 42));
+    }
+
+    #[test]
+    fn snowflake_range_bounds() {
+        let time = "2020-05-17T12:34:56.789Z".parse::<Timestamp>().unwrap();
+        let bound = Snowflake::from_timestamp(time);
+        assert_eq!(bound.timestamp(), time);
+        assert_eq!((bound.worker(), bound.seq()), (0, 0));
+
+        // Every snowflake created at that time is at least the bound
+        let later = Snowflake(bound.0 | 0x3fffff);
+        assert!(later >= bound && later.timestamp() == time);
+        let earlier = Snowflake(bound.0 - 1);
+        assert!(earlier < bound && earlier.timestamp() < time);
+
+        // Sub-millisecond precision is discarded
+        assert_eq!(Snowflake::from_timestamp(time + 500.microseconds()), bound);
+    }
+
+    #[test]
+    fn snowflake_clamping() {
+        let before = Snowflake::epoch() - 1.second();
+        assert_eq!(Snowflake::from_timestamp(before), Snowflake(0));
+        assert_eq!(
+            Snowflake::from_timestamp(Timestamp::UNIX_EPOCH),
+            Snowflake(0)
+        );
+
+        let last = Snowflake::MAX.timestamp();
+        assert_eq!(last.to_string(), "2084-08-05T15:47:35.551Z");
+        assert_eq!(
+            Snowflake::from_timestamp(last),
+            Snowflake(Snowflake::MAX.0 & !0x3fffff)
+        );
+        assert!(Snowflake::from_timestamp(last + 1.millisecond()) == Snowflake::MAX);
+        assert_eq!(Snowflake::from_timestamp(Timestamp::MAX), Snowflake::MAX);
+    }
+}
@@ -3,5 +3,16 @@
 pub mod botrulez;
 pub mod command;
 pub mod commands;
+pub mod fleet;
+pub mod grants;
+pub mod handoff;
 pub mod instance;
 pub mod instances;
+pub mod persona;
+pub mod pm;
+pub mod sequence;
+pub mod settings;
+pub mod spam;
+pub mod supervisor;
+#[cfg(feature = "webhook")]
+pub mod webhook;
@@ -2,16 +2,35 @@ use async_trait::async_trait;
 use clap::Parser;
 
 use crate::api::Message;
-use crate::bot::command::{ClapCommand, Command, Context};
+use crate::bot::command::{ClapCommand, Command, Context, Info};
 use crate::conn;
+use crate::text::{Chaining, MessagePlan};
 
+/// Show the help lines of all commands, or detailed help for a single command.
+///
+/// When given an argument, its first word is interpreted as the name of a
+/// command (with or without prefix, e.g. `remind` or `!remind`). The reply then
+/// contains the command's trigger, description and long help (if any) instead
+/// of the full command list.
+///
+/// Long replies are split into multiple messages according to
+/// [`Self::plan`].
 pub struct FullHelp {
     pub before: String,
     pub after: String,
+    /// Whether and how to split the reply into multiple messages.
+    pub plan: MessagePlan,
+    /// How to send the reply if it is split into multiple messages.
+    pub chaining: Chaining,
+    /// Whether to group the help lines by their [`Info::category`].
+    ///
+    /// Commands without a category are listed first, followed by each
+    /// category in the order it first appears, introduced by its name.
+    pub group_by_category: bool,
 }
 
 pub trait HasDescriptions {
-    fn descriptions(&self, ctx: &Context) -> Vec<String>;
+    fn infos(&self, ctx: &Context) -> Vec<Info>;
 }
 
 impl FullHelp {
@@ -19,10 +38,34 @@ impl FullHelp {
         Self {
             before: before.to_string(),
             after: after.to_string(),
+            plan: MessagePlan::default(),
+            chaining: Chaining::default(),
+            group_by_category: false,
         }
     }
 
-    fn formulate_reply<B: HasDescriptions>(&self, ctx: &Context, bot: &B) -> String {
+    pub fn with_plan(mut self, plan: MessagePlan) -> Self {
+        self.plan = plan;
+        self
+    }
+
+    pub fn with_chaining(mut self, chaining: Chaining) -> Self {
+        self.chaining = chaining;
+        self
+    }
+
+    pub fn with_group_by_category(mut self, active: bool) -> Self {
+        self.group_by_category = active;
+        self
+    }
+
+    async fn send_reply(&self, ctx: &Context, msg: &Message, reply: &str) -> conn::Result<()> {
+        ctx.send_plan(Some(msg.id), &self.plan, reply, self.chaining)
+            .await?;
+        Ok(())
+    }
+
+    fn formulate_reply(&self, infos: &[Info]) -> String {
         let mut result = String::new();
 
         if !self.before.is_empty() {
@@ -30,9 +73,29 @@ impl FullHelp {
             result.push('\n');
         }
 
-        for description in bot.descriptions(ctx) {
-            result.push_str(&description);
-            result.push('\n');
+        let mut categories: Vec<Option<&str>> = vec![None];
+        if self.group_by_category {
+            for category in infos.iter().filter_map(|i| i.category.as_deref()) {
+                if !categories.contains(&Some(category)) {
+                    categories.push(Some(category));
+                }
+            }
+        }
+
+        for category in categories {
+            let infos = infos
+                .iter()
+                .filter(|i| !self.group_by_category || i.category.as_deref() == category)
+                .filter_map(|i| i.line())
+                .collect::<Vec<_>>();
+            if let (Some(category), false) = (category, infos.is_empty()) {
+                result.push_str(category);
+                result.push_str(":\n");
+            }
+            for line in infos {
+                result.push_str(&line);
+                result.push('\n');
+            }
         }
 
         if !self.after.is_empty() {
@@ -42,6 +105,54 @@ impl FullHelp {
 
         result
     }
+
+    fn formulate_topic_reply(&self, infos: &[Info], topic: &str) -> String {
+        let topic = topic.trim_start_matches(|c: char| c.is_ascii_punctuation());
+
+        // Only commands that would show up in the full help are considered.
+        let visible = infos
+            .iter()
+            .filter(|i| i.description.is_some())
+            .filter_map(|i| Some((i.name()?, i)))
+            .collect::<Vec<_>>();
+
+        let entries = visible
+            .iter()
+            .filter(|(name, _)| *name == topic)
+            .filter_map(|(_, info)| {
+                let mut entry = info.line()?;
+                if let Some(long_help) = &info.long_help {
+                    entry.push_str("\n\n");
+                    entry.push_str(long_help);
+                }
+                Some(entry)
+            })
+            .collect::<Vec<_>>();
+
+        if !entries.is_empty() {
+            return entries.join("\n\n");
+        }
+
+        let mut reply = format!("No such command: {topic}");
+        let close = visible
+            .iter()
+            .filter(|(name, _)| name.starts_with(topic) || topic.starts_with(name))
+            .filter_map(|(_, info)| info.trigger.as_deref())
+            .collect::<Vec<_>>();
+        if !close.is_empty() {
+            reply.push_str("\nDid you mean: ");
+            reply.push_str(&close.join(", "));
+        }
+        reply
+    }
+
+    fn formulate_reply_for<B: HasDescriptions>(&self, ctx: &Context, bot: &B, arg: &str) -> String {
+        let infos = bot.infos(ctx);
+        match arg.split_whitespace().next() {
+            Some(topic) => self.formulate_topic_reply(&infos, topic),
+            None => self.formulate_reply(&infos),
+        }
+    }
 }
 
 #[async_trait]
@@ -57,19 +168,18 @@ where
         ctx: &Context,
         bot: &mut B,
     ) -> Result<bool, E> {
-        if arg.trim().is_empty() {
-            let reply = self.formulate_reply(ctx, bot);
-            ctx.reply(msg.id, reply).await?;
-            Ok(true)
-        } else {
-            Ok(false)
-        }
+        let reply = self.formulate_reply_for(ctx, bot, arg);
+        self.send_reply(ctx, msg, &reply).await?;
+        Ok(true)
     }
 }
 
 /// Show full bot help.
 #[derive(Parser)]
-pub struct Args {}
+pub struct Args {
+    /// Show detailed help for this command.
+    pub command: Option<String>,
+}
 
 #[async_trait]
 impl<B, E> ClapCommand<B, E> for FullHelp
@@ -81,13 +191,110 @@ where
 
     async fn execute(
         &self,
-        _args: Self::Args,
+        args: Self::Args,
         msg: &Message,
         ctx: &Context,
         bot: &mut B,
     ) -> Result<bool, E> {
-        let reply = self.formulate_reply(ctx, bot);
-        ctx.reply(msg.id, reply).await?;
+        let arg = args.command.unwrap_or_default();
+        let reply = self.formulate_reply_for(ctx, bot, &arg);
+        self.send_reply(ctx, msg, &reply).await?;
         Ok(true)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use crate::bot::command::Info;
+
+    use super::FullHelp;
+
+    fn infos() -> Vec<Info> {
+        vec![
+            Info::new()
+                .with_description("Set a reminder.")
+                .with_long_help("Reminds you of things.")
+                .with_prepended_trigger("!remind @TestBot"),
+            Info::new()
+                .with_description("List your reminders.")
+                .with_prepended_trigger("!reminders @TestBot"),
+            Info::new()
+                .with_description("Trigger a short reply.")
+                .with_prepended_trigger("!ping @TestBot"),
+            // Hidden commands don't have a description
+            Info::new().with_prepended_trigger("!ping"),
+        ]
+    }
+
+    #[test]
+    fn full_help() {
+        let help = FullHelp::new("before", "after");
+        assert_eq!(
+            help.formulate_reply(&infos()),
+            "before\n\
+             !remind @TestBot - Set a reminder.\n\
+             !reminders @TestBot - List your reminders.\n\
+             !ping @TestBot - Trigger a short reply.\n\
+             after\n"
+        );
+    }
+
+    #[test]
+    fn grouped_by_category() {
+        let mut infos = infos();
+        infos[0].category = Some("Reminders".to_string());
+        infos[1].category = Some("Reminders".to_string());
+        infos.push(
+            Info::new()
+                .with_description("Roll some dice.")
+                .with_category("Games")
+                .with_prepended_trigger("!roll"),
+        );
+
+        let help = FullHelp::new("before", "").with_group_by_category(true);
+        assert_eq!(
+            help.formulate_reply(&infos),
+            "before\n\
+             !ping @TestBot - Trigger a short reply.\n\
+             Reminders:\n\
+             !remind @TestBot - Set a reminder.\n\
+             !reminders @TestBot - List your reminders.\n\
+             Games:\n\
+             !roll - Roll some dice.\n"
+        );
+
+        // Without grouping, categories are ignored
+        let help = FullHelp::new("", "");
+        assert_eq!(help.formulate_reply(&infos).lines().count(), 4);
+    }
+
+    #[test]
+    fn topic_lookup() {
+        let help = FullHelp::new("", "");
+        let expected = "!ping @TestBot - Trigger a short reply.";
+        assert_eq!(help.formulate_topic_reply(&infos(), "ping"), expected);
+        assert_eq!(help.formulate_topic_reply(&infos(), "!ping"), expected);
+    }
+
+    #[test]
+    fn topic_long_help() {
+        let help = FullHelp::new("", "");
+        assert_eq!(
+            help.formulate_topic_reply(&infos(), "remind"),
+            "!remind @TestBot - Set a reminder.\n\nReminds you of things."
+        );
+    }
+
+    #[test]
+    fn unknown_topic() {
+        let help = FullHelp::new("", "");
+        assert_eq!(
+            help.formulate_topic_reply(&infos(), "rem"),
+            "No such command: rem\nDid you mean: !remind @TestBot, !reminders @TestBot"
+        );
+        assert_eq!(
+            help.formulate_topic_reply(&infos(), "kill"),
+            "No such command: kill"
+        );
+    }
+}
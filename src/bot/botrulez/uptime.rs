@@ -59,7 +59,7 @@ pub trait HasStartTime {
 impl Uptime {
     fn formulate_reply<B: HasStartTime>(&self, ctx: &Context, bot: &B, connected: bool) -> String {
         let start = bot.start_time();
-        let now = Timestamp::now();
+        let now = ctx.config.server.clock.now();
 
         let mut reply = format!(
             "/me has been up since {} ({})",
@@ -131,3 +131,44 @@ where
         Ok(true)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use jiff::{Timestamp, ToSpan};
+
+    use crate::bot::command::test::context;
+    use crate::clock::MockClock;
+
+    use super::{HasStartTime, Uptime};
+
+    struct Bot(Timestamp);
+
+    impl HasStartTime for Bot {
+        fn start_time(&self) -> Timestamp {
+            self.0
+        }
+    }
+
+    #[test]
+    fn uptime() {
+        let start = Timestamp::from_second(1_700_000_000).unwrap();
+        let clock = MockClock::at(start);
+        let mut ctx = context();
+        ctx.config.server.clock = clock.shared();
+        ctx.joined.since = start + 1.hour();
+        clock.advance(Duration::from_secs(2 * 24 * 60 * 60 + 90 * 60 + 5));
+
+        let bot = Bot(start);
+        assert_eq!(
+            Uptime.formulate_reply(&ctx, &bot, false),
+            "/me has been up since 2023-11-14 22:13:20 UTC (2d 1h 30m 5s ago)"
+        );
+        assert_eq!(
+            Uptime.formulate_reply(&ctx, &bot, true),
+            "/me has been up since 2023-11-14 22:13:20 UTC (2d 1h 30m 5s ago), \
+             connected since 2023-11-14 23:13:20 UTC (2d 30m 5s ago)"
+        );
+    }
+}
@@ -1,57 +1,547 @@
 mod bang;
+mod cancellation;
+mod category;
 mod clap;
+mod conversation;
+mod debug_state;
+mod dedup;
+mod described;
 mod hidden;
+mod keyword;
+mod output;
 mod prefixed;
+mod reply_to;
+mod restricted;
+mod retry;
+mod room_size;
+mod self_test;
 
 use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
 
 use async_trait::async_trait;
 
 use crate::api::{self, Message, MessageId};
-use crate::conn::{self, ConnTx, Joined};
+use crate::conn::{self, ConnTx, FreshnessRequirements, Joined, StaleStateError};
+use crate::text::{Chaining, MessagePlan};
 
 pub use self::bang::*;
+pub use self::cancellation::*;
+pub use self::category::*;
 pub use self::clap::*;
+pub use self::conversation::*;
+pub use self::debug_state::*;
+pub use self::dedup::*;
+pub use self::described::*;
 pub use self::hidden::*;
+pub use self::keyword::*;
+pub use self::output::*;
 pub use self::prefixed::*;
+pub use self::reply_to::*;
+pub use self::restricted::*;
+pub use self::retry::*;
+pub use self::room_size::*;
+pub use self::self_test::*;
 
 use super::instance::InstanceConfig;
+use super::persona::{PersonaError, Personas};
 
+/// How many ancestors [`Context::reply_or_root`] fetches at most.
+const MAX_THREAD_WALK: usize = 1000;
+
+/// Where [`Context::reply_or_root`] should send its message.
+#[derive(Debug, PartialEq, Eq)]
+enum ReplyTarget {
+    Parent,
+    Ancestor(MessageId),
+    Root,
+}
+
+/// Decide where to reply given the ids of the parent and its ancestors, parent
+/// first.
+///
+/// `complete` says whether the last id belongs to a top-level message.
+fn reply_target(ancestors: &[MessageId], complete: bool, max_depth: usize) -> ReplyTarget {
+    // Top-level messages have a depth of zero, so the reply's depth is the
+    // number of its ancestors.
+    let depth = ancestors.len();
+    if !complete || max_depth == 0 {
+        ReplyTarget::Root
+    } else if depth <= max_depth {
+        ReplyTarget::Parent
+    } else {
+        ReplyTarget::Ancestor(ancestors[depth - max_depth])
+    }
+}
+
+#[derive(Clone)]
 pub struct Context {
     pub config: InstanceConfig,
     pub conn_tx: ConnTx,
     pub joined: Joined,
+    /// The command prefix used in this room, if it differs from the commands'
+    /// own prefixes.
+    ///
+    /// Bang commands like [`General`] use this prefix instead of their own one
+    /// if it is set. See [`PrefixResolver`](super::commands::PrefixResolver)
+    /// for more details.
+    pub prefix: Option<String>,
+    /// Cancelled when the command should stop, at the latest once the
+    /// connection it was received on is closed.
+    ///
+    /// Long-running commands should regularly check it, for example via
+    /// [`Self::checkpoint`] or [`Self::sleep`]. Commands can also be cancelled
+    /// manually using
+    /// [`Commands::cancel_running`](super::commands::Commands::cancel_running).
+    pub cancellation: CancellationToken,
+    /// Whether commands should not be executed right now because other
+    /// instances of the bot are in the room.
+    ///
+    /// [`Commands`](super::commands::Commands) doesn't execute any commands
+    /// while this is set. See
+    /// [`DuplicatePolicy::Defer`](super::instance::DuplicatePolicy::Defer) for
+    /// more details.
+    pub deferred: bool,
+    /// Where [`Self::ask`] waits for answers.
+    pub conversations: Conversations,
+    /// How often the message was handled before, i.e. `0` the first time and
+    /// `1` after it was retried once.
+    ///
+    /// See [`Self::retry_after`] for more details.
+    pub attempt: u32,
+    /// Where [`Self::retry_after`] records its request.
+    pub retry: RetryRequest,
+    /// Applied to the content of messages sent via this context, except for
+    /// [`Self::send_raw`].
+    ///
+    /// [`Commands`](super::commands::Commands) sets this to the transform
+    /// active in the room. Commands may replace it in a clone of the context
+    /// to change the output of a single invocation. See [`OutputTransforms`]
+    /// for more details.
+    pub output: Option<Arc<dyn OutputTransform>>,
+    /// Used by [`Self::send_as`].
+    ///
+    /// [`Commands`](super::commands::Commands) shares it between all commands
+    /// of an instance, so sends by different commands are serialized.
+    pub personas: Personas,
 }
 
 impl Context {
+    /// Return [`conn::Error::Cancelled`] if the command should stop.
+    ///
+    /// See [`Self::cancellation`] for more details.
+    #[allow(clippy::result_large_err)]
+    pub fn checkpoint(&self) -> conn::Result<()> {
+        if self.cancellation.is_cancelled() {
+            return Err(conn::Error::Cancelled);
+        }
+        Ok(())
+    }
+
+    /// Sleep for a duration unless the command is cancelled first.
+    ///
+    /// Returns [`conn::Error::Cancelled`] if the command was cancelled before
+    /// or during the sleep.
+    pub async fn sleep(&self, duration: Duration) -> conn::Result<()> {
+        self.checkpoint()?;
+        tokio::select! {
+            _ = tokio::time::sleep(duration) => Ok(()),
+            _ = self.cancellation.cancelled() => Err(conn::Error::Cancelled),
+        }
+    }
+
+    /// Split content into messages according to a [`MessagePlan`] and send
+    /// them in order.
+    ///
+    /// [`Self::output`] is applied before splitting, so content that becomes
+    /// longer when transformed still results in messages within the plan's
+    /// limits. The messages are sent as replies to `parent`, or as top-level
+    /// messages if it is `None`. Each message is only sent after the server
+    /// replied to the previous one. Returns the sent messages, which are none
+    /// if the content is blank.
+    pub async fn send_plan<S: ToString>(
+        &self,
+        parent: Option<MessageId>,
+        plan: &MessagePlan,
+        content: S,
+        chaining: Chaining,
+    ) -> conn::Result<Vec<Message>> {
+        let plan = plan.plan(&self.transform(content));
+        let mut parent = parent;
+        let mut sent = vec![];
+        for content in plan.into_messages() {
+            self.checkpoint()?;
+            let msg = self.send_raw(parent, content).await?;
+            if chaining == Chaining::Nested {
+                parent = Some(msg.id);
+            }
+            sent.push(msg);
+        }
+        Ok(sent)
+    }
+
+    /// Check whether the bot's view of the room is fresh enough to act on.
+    ///
+    /// Commands performing destructive actions should call this first and
+    /// refuse to act if it fails, for example by replying with the error. See
+    /// [`Joined::ensure_fresh`] for more details.
+    pub fn ensure_fresh(
+        &self,
+        requirements: FreshnessRequirements<'_>,
+    ) -> Result<(), StaleStateError> {
+        let now = self.config.server.clock.now();
+        self.joined.ensure_fresh(requirements, now)
+    }
+
+    /// Whether the sender of a message sent more than `max_rate` messages per
+    /// second within the last `window`, including the message itself.
+    ///
+    /// Commands can use this to avoid amplifying spam. Always returns `false`
+    /// unless message times are tracked, see
+    /// [`ServerConfig::message_times`](super::instance::ServerConfig::message_times).
+    pub fn sender_is_flooding(&self, msg: &Message, max_rate: f32, window: Duration) -> bool {
+        let now = self.config.server.clock.now();
+        let rate = self
+            .joined
+            .recent_message_rate(&msg.sender.session_id, window, now);
+        rate > max_rate
+    }
+
+    /// The command prefix used in this room, if any.
+    ///
+    /// See [`Self::prefix`] for more details.
+    pub fn prefix_for_room(&self) -> Option<&str> {
+        self.prefix.as_deref()
+    }
+
+    /// Whether the room is private, i.e. requires authentication to join.
+    pub fn is_private_room(&self) -> bool {
+        self.joined.room_is_private
+    }
+
+    /// Apply [`Self::output`] to the content of a message.
+    fn transform<S: ToString>(&self, content: S) -> String {
+        let content = content.to_string();
+        match &self.output {
+            Some(output) => output.transform(&content).into_owned(),
+            None => content,
+        }
+    }
+
     pub fn send<S: ToString>(&self, content: S) -> impl Future<Output = conn::Result<Message>> {
-        let cmd = api::Send {
-            content: content.to_string(),
-            parent: None,
-        };
-        let reply = self.conn_tx.send(cmd);
-        async move { reply.await.map(|r| r.0) }
+        self.send_raw(None, self.transform(content))
     }
 
     pub fn reply<S: ToString>(
         &self,
         parent: MessageId,
         content: S,
+    ) -> impl Future<Output = conn::Result<Message>> {
+        self.send_raw(Some(parent), self.transform(content))
+    }
+
+    /// Send a message under a different nick.
+    ///
+    /// The message is sent as a reply to `parent`, or as a top-level message if
+    /// it is `None`. See [`Personas`] for more details.
+    pub async fn send_as<S: ToString>(
+        &self,
+        persona: &str,
+        parent: Option<MessageId>,
+        content: S,
+    ) -> Result<Message, PersonaError> {
+        let content = self.transform(content);
+        (self.personas)
+            .send_as(&self.conn_tx, persona, parent, content)
+            .await
+    }
+
+    /// Send a message without applying [`Self::output`].
+    ///
+    /// The message is sent as a reply to `parent`, or as a top-level message if
+    /// it is `None`.
+    pub fn send_raw<S: ToString>(
+        &self,
+        parent: Option<MessageId>,
+        content: S,
     ) -> impl Future<Output = conn::Result<Message>> {
         let cmd = api::Send {
             content: content.to_string(),
-            parent: Some(parent),
+            parent,
         };
         let reply = self.conn_tx.send(cmd);
         async move { reply.await.map(|r| r.0) }
     }
+
+    /// Reply to a message unless the reply would be nested too deeply.
+    ///
+    /// Top-level messages have a depth of zero, replies to them a depth of one,
+    /// and so on. The depth is determined by fetching the parent's ancestors
+    /// one by one. If the reply's depth would exceed `max_depth`, it is sent as
+    /// a reply to the parent's ancestor at depth `max_depth - 1` instead.
+    ///
+    /// If `max_depth` is zero or the thread is too deep to determine its depth,
+    /// the message is sent as a top-level message starting with a quote of the
+    /// parent's first line.
+    ///
+    /// Whether the server rejected a message because its thread was too deep
+    /// can be checked using [`conn::Error::send_error_reason`].
+    pub async fn reply_or_root<S: ToString>(
+        &self,
+        parent: MessageId,
+        content: S,
+        max_depth: usize,
+    ) -> conn::Result<Message> {
+        let mut ancestors = vec![];
+        let mut quote = String::new();
+        let mut next = Some(parent);
+        while let Some(id) = next {
+            if ancestors.len() >= MAX_THREAD_WALK {
+                break;
+            }
+            self.checkpoint()?;
+            let msg = self.conn_tx.send(api::GetMessage { id }).await?.0;
+            if ancestors.is_empty() {
+                quote = msg.content.lines().next().unwrap_or_default().to_string();
+            }
+            ancestors.push(id);
+            next = msg.parent;
+        }
+
+        self.checkpoint()?;
+        let content = content.to_string();
+        match reply_target(&ancestors, next.is_none(), max_depth) {
+            ReplyTarget::Parent => self.reply(parent, content).await,
+            ReplyTarget::Ancestor(id) => self.reply(id, content).await,
+            ReplyTarget::Root => self.send(format!("> {quote}\n{content}")).await,
+        }
+    }
+
+    /// Ask the sender of a message a question and wait for their answer.
+    ///
+    /// The question is sent as a reply to `msg`. The answer is the first
+    /// direct reply to the question by the sender of `msg`. Returns `None` if
+    /// no answer arrived within `timeout`, or [`conn::Error::Cancelled`] if the
+    /// command was cancelled while waiting.
+    ///
+    /// Answers are delivered by [`Commands::handle_packet`](super::commands::Commands::handle_packet).
+    /// If packets are handled one after the other, waiting for an answer
+    /// directly in [`Command::execute`] would prevent the answer from ever
+    /// being handled. Instead, clone the context and ask in a separate task.
+    pub async fn ask<S: ToString>(
+        &self,
+        msg: &Message,
+        question: S,
+        timeout: Duration,
+    ) -> conn::Result<Option<Arc<Message>>> {
+        self.checkpoint()?;
+        let question = self.reply(msg.id, question).await?;
+        let mut waiter = self
+            .conversations
+            .wait_for(question.id, msg.sender.id.clone());
+
+        tokio::select! {
+            answer = tokio::time::timeout(timeout, &mut waiter.rx) => match answer {
+                Ok(Ok(answer)) => Ok(Some(answer)),
+                Ok(Err(_)) | Err(_) => Ok(None),
+            },
+            _ = self.cancellation.cancelled() => Err(conn::Error::Cancelled),
+        }
+    }
+
+    /// Handle the message again after a delay instead of now.
+    ///
+    /// This is useful if a resource the command needs is busy, or to batch
+    /// multiple quick requests into a single response. Once the command
+    /// returns, [`Commands`](super::commands::Commands) stops executing further
+    /// commands for the message, regardless of
+    /// [`fallthrough`](super::commands::Commands::fallthrough). After the
+    /// delay, all commands are executed for the message again, starting with
+    /// the first one, and with [`Self::attempt`] increased by one. The message
+    /// only reaches commands after the retrying one once no command requests a
+    /// retry any more.
+    ///
+    /// Messages are retried at most
+    /// [`Commands::max_retries`](super::commands::Commands::max_retries) times,
+    /// so commands should check [`Self::attempt`] and give up gracefully.
+    /// Requests beyond that limit are ignored, as are requests while too many
+    /// other messages are waiting to be retried.
+    ///
+    /// Retries only happen if the bot's event loop also drives
+    /// [`Commands::retries_due`](super::commands::Commands::retries_due) and
+    /// [`Commands::handle_retries`](super::commands::Commands::handle_retries).
+    /// A warning is logged for messages queued before that.
+    pub fn retry_after(&self, delay: Duration) {
+        self.retry.request(delay);
+    }
+
+    /// Like [`Self::send`], but without waiting for the server's reply.
+    #[allow(clippy::result_large_err)]
+    pub fn send_only<S: ToString>(&self, content: S) -> conn::Result<()> {
+        self.conn_tx.send_only(api::Send {
+            content: self.transform(content),
+            parent: None,
+        })
+    }
+
+    /// Like [`Self::reply`], but without waiting for the server's reply.
+    #[allow(clippy::result_large_err)]
+    pub fn reply_only<S: ToString>(&self, parent: MessageId, content: S) -> conn::Result<()> {
+        self.conn_tx.send_only(api::Send {
+            content: self.transform(content),
+            parent: Some(parent),
+        })
+    }
+
+    /// Like [`Self::send`], but logging failures instead of returning them.
+    ///
+    /// Since the caller can't react to a message being too long, content
+    /// longer than the server allows is split into multiple messages according
+    /// to [`MessagePlan::default`] after [`Self::output`] was applied, like
+    /// [`Self::send_plan`] would. See [`ConnTx::send_logged`] for more details.
+    pub fn send_logged<S: ToString>(&self, content: S) {
+        self.logged(None, content);
+    }
+
+    /// Like [`Self::reply`], but logging failures instead of returning them.
+    ///
+    /// Long content is split like in [`Self::send_logged`]. See
+    /// [`ConnTx::send_logged`] for more details.
+    pub fn reply_logged<S: ToString>(&self, parent: MessageId, content: S) {
+        self.logged(Some(parent), content);
+    }
+
+    fn logged<S: ToString>(&self, parent: Option<MessageId>, content: S) {
+        let content = self.transform(content);
+        let plan = MessagePlan::default();
+        let contents = if content.chars().count() > plan.max_len {
+            plan.plan(&content).into_messages()
+        } else {
+            vec![content]
+        };
+        // The connection sends the messages in order
+        for content in contents {
+            self.conn_tx.send_logged(api::Send { content, parent });
+        }
+    }
+}
+
+/// Information about a command, used to generate help output.
+///
+/// Commands without a description are not listed in the bot's help.
+///
+/// Wrapper commands build their info from their inner command's info using
+/// [`Self::merge`]. This means that the innermost command usually provides the
+/// description, while wrappers like [`General`] or [`Prefixed`] prepend their
+/// part of the trigger and wrappers like [`Described`] override the
+/// description of everything they wrap.
+#[derive(Debug, Clone, Default)]
+pub struct Info {
+    /// How to invoke the command, e.g. `!ping @TestBot`.
+    pub trigger: Option<String>,
+    /// A short, single-line description of what the command does.
+    pub description: Option<String>,
+    /// A more detailed explanation of the command, shown when asking for help
+    /// on this specific command.
+    pub long_help: Option<String>,
+    /// The group of commands this command belongs to, e.g. `Reminders`.
+    ///
+    /// See [`CommandExt::category`].
+    pub category: Option<String>,
+    /// Example invocations of the command.
+    pub examples: Vec<String>,
+}
+
+impl Info {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_trigger<S: ToString>(mut self, trigger: S) -> Self {
+        self.trigger = Some(trigger.to_string());
+        self
+    }
+
+    pub fn with_description<S: ToString>(mut self, description: S) -> Self {
+        self.description = Some(description.to_string());
+        self
+    }
+
+    pub fn with_long_help<S: ToString>(mut self, long_help: S) -> Self {
+        self.long_help = Some(long_help.to_string());
+        self
+    }
+
+    pub fn with_category<S: ToString>(mut self, category: S) -> Self {
+        self.category = Some(category.to_string());
+        self
+    }
+
+    pub fn with_example<S: ToString>(mut self, example: S) -> Self {
+        self.examples.push(example.to_string());
+        self
+    }
+
+    /// Prepend a part to the trigger, separated by a space if the trigger is
+    /// not empty.
+    pub fn with_prepended_trigger<S: ToString>(self, trigger: S) -> Self {
+        self.merge(Self::new().with_trigger(trigger))
+    }
+
+    /// Combine the info of an inner command with that of a wrapper around it.
+    ///
+    /// - The outer trigger is prepended to the inner trigger, separated by a
+    ///   space if both are not empty.
+    /// - The outer description, long help and category replace the inner ones
+    ///   if they are present.
+    /// - The outer examples replace the inner ones if there are any.
+    pub fn merge(self, outer: Self) -> Self {
+        let trigger = match (outer.trigger, self.trigger) {
+            (Some(outer), Some(inner)) if outer.is_empty() => Some(inner),
+            (Some(outer), Some(inner)) if !inner.is_empty() => Some(format!("{outer} {inner}")),
+            (Some(outer), _) => Some(outer),
+            (None, inner) => inner,
+        };
+        Self {
+            trigger,
+            description: outer.description.or(self.description),
+            long_help: outer.long_help.or(self.long_help),
+            category: outer.category.or(self.category),
+            examples: if outer.examples.is_empty() {
+                self.examples
+            } else {
+                outer.examples
+            },
+        }
+    }
+
+    /// The command's name, i.e. the first word of its trigger without any
+    /// leading punctuation like a `!`.
+    pub fn name(&self) -> Option<&str> {
+        let trigger = self.trigger.as_deref()?;
+        let word = trigger.split_whitespace().next()?;
+        let name = word.trim_start_matches(|c: char| c.is_ascii_punctuation());
+        Some(name).filter(|n| !n.is_empty())
+    }
+
+    /// A single line describing the command, as shown in the bot's help.
+    ///
+    /// Returns `None` if the command has no description.
+    pub fn line(&self) -> Option<String> {
+        let description = self.description.as_ref()?;
+        Some(match &self.trigger {
+            Some(trigger) => format!("{trigger} - {description}"),
+            None => description.clone(),
+        })
+    }
 }
 
 #[allow(unused_variables)]
 #[async_trait]
 pub trait Command<B, E> {
-    fn description(&self, ctx: &Context) -> Option<String> {
-        None
+    fn info(&self, ctx: &Context) -> Info {
+        Info::default()
     }
 
     async fn execute(
@@ -62,3 +552,261 @@ pub trait Command<B, E> {
         bot: &mut B,
     ) -> Result<bool, E>;
 }
+
+#[cfg(test)]
+pub(crate) mod test {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use async_trait::async_trait;
+    use clap::Parser;
+
+    use crate::api::{Message, MessageId, Snowflake};
+    use crate::bot::instance::{InstanceConfig, ServerConfig};
+    use crate::bot::persona::Personas;
+    use crate::conn;
+    use crate::test_util::JoinedBuilder;
+    use crate::text::{Chaining, MessagePlan};
+
+    use super::{
+        reply_target, AsciiFallback, CancellationToken, Clap, ClapCommand, Command, Context,
+        Conversations, Described, General, Global, Hidden, Info, Prefixed, ReplyTarget,
+        RetryRequest, Specific,
+    };
+
+    pub(crate) fn context() -> Context {
+        Context {
+            config: InstanceConfig::new(ServerConfig::default(), "test"),
+            conn_tx: conn::test::closed_tx(),
+            joined: JoinedBuilder::new().build(),
+            prefix: None,
+            cancellation: CancellationToken::new(),
+            deferred: false,
+            conversations: Conversations::new(),
+            attempt: 0,
+            retry: RetryRequest::new(),
+            output: None,
+            personas: Personas::new("TestBot"),
+        }
+    }
+
+    /// Roll some dice.
+    #[derive(Parser)]
+    struct Args {
+        /// How many dice to roll.
+        amount: Option<u64>,
+    }
+
+    struct Roll;
+
+    #[async_trait]
+    impl ClapCommand<(), conn::Error> for Roll {
+        type Args = Args;
+
+        async fn execute(
+            &self,
+            _args: Args,
+            _msg: &Message,
+            _ctx: &Context,
+            _bot: &mut (),
+        ) -> Result<bool, conn::Error> {
+            Ok(true)
+        }
+    }
+
+    struct Ping;
+
+    #[async_trait]
+    impl Command<(), conn::Error> for Ping {
+        fn info(&self, _ctx: &Context) -> Info {
+            Info::new().with_description("Trigger a short reply.")
+        }
+
+        async fn execute(
+            &self,
+            _arg: &str,
+            _msg: &Message,
+            _ctx: &Context,
+            _bot: &mut (),
+        ) -> Result<bool, conn::Error> {
+            Ok(true)
+        }
+    }
+
+    type Cmd = Box<dyn Command<(), conn::Error> + Send + Sync>;
+
+    #[test]
+    fn merge() {
+        let inner = Info::new()
+            .with_trigger("<args>")
+            .with_description("inner")
+            .with_long_help("inner help");
+
+        let merged = inner.clone().merge(Info::new());
+        assert_eq!(merged.trigger.as_deref(), Some("<args>"));
+        assert_eq!(merged.description.as_deref(), Some("inner"));
+        assert_eq!(merged.long_help.as_deref(), Some("inner help"));
+
+        let merged = inner.merge(Info::new().with_trigger("!cmd").with_description("outer"));
+        assert_eq!(merged.trigger.as_deref(), Some("!cmd <args>"));
+        assert_eq!(merged.description.as_deref(), Some("outer"));
+        assert_eq!(merged.long_help.as_deref(), Some("inner help"));
+    }
+
+    /// Help lines of realistic wrapper stacks. If this test breaks, a wrapper
+    /// changed the help output of existing bots.
+    #[test]
+    fn wrapper_stacks() {
+        let cases: Vec<(Cmd, Option<&str>)> = vec![
+            (Box::new(Ping), Some("Trigger a short reply.")),
+            (Box::new(Clap(Roll)), Some("Roll some dice")),
+            (
+                Box::new(General::new("roll", Clap(Roll))),
+                Some("!roll - Roll some dice"),
+            ),
+            (
+                Box::new(Specific::new("roll", Clap(Roll))),
+                Some("!roll @TestBot - Roll some dice"),
+            ),
+            (
+                Box::new(Global::new("roll", Clap(Roll)).with_prefix("/")),
+                Some("/roll - Roll some dice"),
+            ),
+            (
+                Box::new(General::new(
+                    "roll",
+                    Described::new(Clap(Roll)).with_description("Dice!"),
+                )),
+                Some("!roll - Dice!"),
+            ),
+            (
+                Box::new(
+                    Described::new(General::new("roll", Clap(Roll))).with_description("Dice!"),
+                ),
+                Some("!roll - Dice!"),
+            ),
+            (
+                Box::new(Described::new(General::new("roll", Clap(Roll)))),
+                Some("!roll - Roll some dice"),
+            ),
+            (
+                Box::new(Described::new(Hidden(Ping)).with_description("Not so hidden.")),
+                Some("Not so hidden."),
+            ),
+            (Box::new(Hidden(General::new("ping", Ping))), None),
+            (Box::new(General::new("ping", Hidden(Ping))), None),
+            (
+                Box::new(Prefixed::new("!dice", General::new("roll", Clap(Roll)))),
+                Some("!dice !roll - Roll some dice"),
+            ),
+            (
+                Box::new(General::new(
+                    "ping",
+                    Described::new(Ping).with_long_help("Pong!"),
+                )),
+                Some("!ping - Trigger a short reply."),
+            ),
+        ];
+
+        let ctx = context();
+        for (i, (cmd, expected)) in cases.into_iter().enumerate() {
+            assert_eq!(cmd.info(&ctx).line().as_deref(), expected, "case {i}");
+        }
+    }
+
+    #[test]
+    fn described_keeps_clap_long_help() {
+        let ctx = context();
+        let cmd = General::new("roll", Described::new(Clap(Roll)).with_description("Dice!"));
+        let info = Command::<(), conn::Error>::info(&cmd, &ctx);
+        assert_eq!(info.name(), Some("roll"));
+        assert!(info.long_help.unwrap().contains("How many dice to roll"));
+    }
+
+    #[test]
+    fn reply_targets() {
+        // Message 0 is top-level and message n is a reply to message n - 1
+        let ancestors = |parent: u64| {
+            (0..=parent)
+                .rev()
+                .map(|i| MessageId(Snowflake(i)))
+                .collect::<Vec<_>>()
+        };
+        let id = |i| ReplyTarget::Ancestor(MessageId(Snowflake(i)));
+
+        // Replies to a top-level message have a depth of one
+        assert_eq!(reply_target(&ancestors(0), true, 1), ReplyTarget::Parent);
+        assert_eq!(reply_target(&ancestors(0), true, 5), ReplyTarget::Parent);
+
+        assert_eq!(reply_target(&ancestors(4), true, 5), ReplyTarget::Parent);
+        assert_eq!(reply_target(&ancestors(5), true, 5), id(4));
+        assert_eq!(reply_target(&ancestors(9), true, 5), id(4));
+        assert_eq!(reply_target(&ancestors(9), true, 1), id(0));
+        assert_eq!(reply_target(&ancestors(100), true, 50), id(49));
+
+        // Top level if the depth is unknown or no replies are allowed
+        assert_eq!(reply_target(&ancestors(3), false, 5), ReplyTarget::Root);
+        assert_eq!(reply_target(&ancestors(0), true, 0), ReplyTarget::Root);
+        assert_eq!(reply_target(&ancestors(9), true, 0), ReplyTarget::Root);
+    }
+
+    #[tokio::test]
+    async fn send_plan_transforms_before_splitting() {
+        let (mut conn, mut server) = conn::test::connect(Duration::from_secs(10)).await;
+        let mut ctx = context();
+        ctx.conn_tx = conn.tx().clone();
+        // Each emoji becomes ten characters long
+        ctx.output = Some(Arc::new(AsciiFallback::new()));
+        tokio::spawn(async move { while conn.recv().await.is_ok() {} });
+
+        let sender = ctx.joined.session.clone();
+        tokio::spawn(async move {
+            server.join(conn::test::hello(false, None)).await;
+            let mut sent = 0;
+            while let Some(cmd) = server.recv().await {
+                let content = cmd["data"]["content"].as_str().unwrap().to_string();
+                let msg = serde_json::json!({
+                    "id": Snowflake(sent).to_string(),
+                    "time": 0,
+                    "sender": sender,
+                    "content": content,
+                });
+                server
+                    .send(serde_json::json!({ "id": cmd["id"], "type": "send-reply", "data": msg }))
+                    .await;
+                sent += 1;
+            }
+        });
+
+        // Untransformed, the content would fit into a single message
+        let plan = MessagePlan::new().with_max_len(12);
+        let sent = ctx
+            .send_plan(None, &plan, "👍\n👍\n👍", Chaining::Siblings)
+            .await
+            .unwrap();
+        let contents = sent.iter().map(|m| &m.content[..]).collect::<Vec<_>>();
+        assert_eq!(contents, [":thumbsup:", ":thumbsup:", ":thumbsup:"]);
+    }
+
+    #[tokio::test]
+    async fn send_logged_splits_long_content() {
+        let (mut conn, mut server) = conn::test::connect(Duration::from_secs(10)).await;
+        let mut ctx = context();
+        ctx.conn_tx = conn.tx().clone();
+        tokio::spawn(async move { while conn.recv().await.is_ok() {} });
+
+        let parent = MessageId(Snowflake(1));
+        ctx.reply_logged(parent, "a".repeat(5000));
+        ctx.reply_logged(parent, "short");
+
+        server.join(conn::test::hello(false, None)).await;
+        let mut sent = vec![];
+        while sent.len() < 3 {
+            let cmd = server.recv().await.unwrap();
+            assert_eq!(cmd["type"], "send");
+            assert_eq!(cmd["data"]["parent"], parent.0.to_string());
+            sent.push(cmd["data"]["content"].as_str().unwrap().len());
+        }
+        assert_eq!(sent, [4096, 904, 5]);
+    }
+}
@@ -3,18 +3,27 @@ use async_trait::async_trait;
 use crate::api::Message;
 use crate::nick;
 
-use super::{Command, Context};
+use super::{Command, Context, Info};
 
-// TODO Don't ignore leading whitespace?
-// I'm not entirely happy with how commands handle whitespace, and on euphoria,
-// prefixing commands with whitespace is traditionally used to not trigger them.
-
-/// Parse leading whitespace followed by an prefix-initiated command.
+/// Parse a prefix-initiated command.
+///
+/// If `skip_whitespace` is set, leading whitespace before the prefix is
+/// ignored. Otherwise, the text must start with the prefix. On euphoria,
+/// prefixing commands with whitespace is traditionally used to not trigger
+/// them.
 ///
 /// Returns the command name and the remaining text with one leading whitespace
 /// removed. The remaining text may be the empty string.
-pub fn parse_prefix_initiated<'a>(text: &'a str, prefix: &str) -> Option<(&'a str, &'a str)> {
-    let text = text.trim_start();
+pub fn parse_prefix_initiated<'a>(
+    text: &'a str,
+    prefix: &str,
+    skip_whitespace: bool,
+) -> Option<(&'a str, &'a str)> {
+    let text = if skip_whitespace {
+        text.trim_start()
+    } else {
+        text
+    };
     let text = text.strip_prefix(prefix)?;
     let (name, rest) = text.split_once(char::is_whitespace).unwrap_or((text, ""));
     if name.is_empty() {
@@ -23,9 +32,23 @@ pub fn parse_prefix_initiated<'a>(text: &'a str, prefix: &str) -> Option<(&'a st
     Some((name, rest))
 }
 
+/// Compare a parsed command name to the expected one.
+///
+/// If `case_insensitive` is set, the names are compared after converting them
+/// to lowercase.
+fn name_matches(name: &str, expected: &str, case_insensitive: bool) -> bool {
+    if case_insensitive {
+        name.to_lowercase() == expected.to_lowercase()
+    } else {
+        name == expected
+    }
+}
+
 pub struct Global<C> {
     prefix: String,
     name: String,
+    respect_leading_whitespace: bool,
+    case_insensitive: bool,
     inner: C,
 }
 
@@ -34,14 +57,38 @@ impl<C> Global<C> {
         Self {
             prefix: "!".to_string(),
             name: name.to_string(),
+            respect_leading_whitespace: true,
+            case_insensitive: false,
             inner,
         }
     }
 
-    pub fn prefix<S: ToString>(mut self, prefix: S) -> Self {
+    /// The prefix to use unless the room has its own prefix (default: `!`).
+    ///
+    /// See [`Context::prefix`] for more details.
+    pub fn with_prefix<S: ToString>(mut self, prefix: S) -> Self {
         self.prefix = prefix.to_string();
         self
     }
+
+    #[deprecated(note = "use `with_prefix` instead")]
+    pub fn prefix<S: ToString>(self, prefix: S) -> Self {
+        self.with_prefix(prefix)
+    }
+
+    /// Whether messages starting with whitespace should be ignored (default:
+    /// `true`).
+    pub fn with_respect_leading_whitespace(mut self, respect: bool) -> Self {
+        self.respect_leading_whitespace = respect;
+        self
+    }
+
+    /// Whether the command name should be matched case-insensitively (default:
+    /// `false`).
+    pub fn with_case_insensitive(mut self, case_insensitive: bool) -> Self {
+        self.case_insensitive = case_insensitive;
+        self
+    }
 }
 
 #[async_trait]
@@ -50,9 +97,11 @@ where
     B: Send,
     C: Command<B, E> + Send + Sync,
 {
-    fn description(&self, ctx: &Context) -> Option<String> {
-        let inner = self.inner.description(ctx)?;
-        Some(format!("{}{} - {inner}", self.prefix, self.name))
+    fn info(&self, ctx: &Context) -> Info {
+        let prefix = ctx.prefix_for_room().unwrap_or(&self.prefix);
+        self.inner
+            .info(ctx)
+            .merge(Info::new().with_trigger(format!("{prefix}{}", self.name)))
     }
 
     async fn execute(
@@ -63,12 +112,14 @@ where
         bot: &mut B,
     ) -> Result<bool, E> {
         // TODO Replace with let-else
-        let (name, rest) = match parse_prefix_initiated(arg, &self.prefix) {
+        let prefix = ctx.prefix_for_room().unwrap_or(&self.prefix);
+        let skip_whitespace = !self.respect_leading_whitespace;
+        let (name, rest) = match parse_prefix_initiated(arg, prefix, skip_whitespace) {
             Some(parsed) => parsed,
             None => return Ok(false),
         };
 
-        if name != self.name {
+        if !name_matches(name, &self.name, self.case_insensitive) {
             return Ok(false);
         }
 
@@ -79,6 +130,8 @@ where
 pub struct General<C> {
     prefix: String,
     name: String,
+    respect_leading_whitespace: bool,
+    case_insensitive: bool,
     inner: C,
 }
 
@@ -87,14 +140,38 @@ impl<C> General<C> {
         Self {
             prefix: "!".to_string(),
             name: name.to_string(),
+            respect_leading_whitespace: true,
+            case_insensitive: false,
             inner,
         }
     }
 
-    pub fn prefix<S: ToString>(mut self, prefix: S) -> Self {
+    /// The prefix to use unless the room has its own prefix (default: `!`).
+    ///
+    /// See [`Context::prefix`] for more details.
+    pub fn with_prefix<S: ToString>(mut self, prefix: S) -> Self {
         self.prefix = prefix.to_string();
         self
     }
+
+    #[deprecated(note = "use `with_prefix` instead")]
+    pub fn prefix<S: ToString>(self, prefix: S) -> Self {
+        self.with_prefix(prefix)
+    }
+
+    /// Whether messages starting with whitespace should be ignored (default:
+    /// `true`).
+    pub fn with_respect_leading_whitespace(mut self, respect: bool) -> Self {
+        self.respect_leading_whitespace = respect;
+        self
+    }
+
+    /// Whether the command name should be matched case-insensitively (default:
+    /// `false`).
+    pub fn with_case_insensitive(mut self, case_insensitive: bool) -> Self {
+        self.case_insensitive = case_insensitive;
+        self
+    }
 }
 
 #[async_trait]
@@ -103,9 +180,11 @@ where
     B: Send,
     C: Command<B, E> + Send + Sync,
 {
-    fn description(&self, ctx: &Context) -> Option<String> {
-        let inner = self.inner.description(ctx)?;
-        Some(format!("{}{} - {inner}", self.prefix, self.name))
+    fn info(&self, ctx: &Context) -> Info {
+        let prefix = ctx.prefix_for_room().unwrap_or(&self.prefix);
+        self.inner
+            .info(ctx)
+            .merge(Info::new().with_trigger(format!("{prefix}{}", self.name)))
     }
 
     async fn execute(
@@ -116,16 +195,18 @@ where
         bot: &mut B,
     ) -> Result<bool, E> {
         // TODO Replace with let-else
-        let (name, rest) = match parse_prefix_initiated(arg, &self.prefix) {
+        let prefix = ctx.prefix_for_room().unwrap_or(&self.prefix);
+        let skip_whitespace = !self.respect_leading_whitespace;
+        let (name, rest) = match parse_prefix_initiated(arg, prefix, skip_whitespace) {
             Some(parsed) => parsed,
             None => return Ok(false),
         };
 
-        if name != self.name {
+        if !name_matches(name, &self.name, self.case_insensitive) {
             return Ok(false);
         }
 
-        if parse_prefix_initiated(rest, "@").is_some() {
+        if parse_prefix_initiated(rest, "@", true).is_some() {
             // The command looks like a specific command. If we treated it like
             // a general command match, we would interpret other bots' specific
             // commands as general commands.
@@ -139,6 +220,8 @@ where
 pub struct Specific<C> {
     prefix: String,
     name: String,
+    respect_leading_whitespace: bool,
+    case_insensitive: bool,
     inner: C,
 }
 
@@ -147,14 +230,38 @@ impl<C> Specific<C> {
         Self {
             prefix: "!".to_string(),
             name: name.to_string(),
+            respect_leading_whitespace: true,
+            case_insensitive: false,
             inner,
         }
     }
 
-    pub fn prefix<S: ToString>(mut self, prefix: S) -> Self {
+    /// The prefix to use unless the room has its own prefix (default: `!`).
+    ///
+    /// See [`Context::prefix`] for more details.
+    pub fn with_prefix<S: ToString>(mut self, prefix: S) -> Self {
         self.prefix = prefix.to_string();
         self
     }
+
+    #[deprecated(note = "use `with_prefix` instead")]
+    pub fn prefix<S: ToString>(self, prefix: S) -> Self {
+        self.with_prefix(prefix)
+    }
+
+    /// Whether messages starting with whitespace should be ignored (default:
+    /// `true`).
+    pub fn with_respect_leading_whitespace(mut self, respect: bool) -> Self {
+        self.respect_leading_whitespace = respect;
+        self
+    }
+
+    /// Whether the command name should be matched case-insensitively (default:
+    /// `false`).
+    pub fn with_case_insensitive(mut self, case_insensitive: bool) -> Self {
+        self.case_insensitive = case_insensitive;
+        self
+    }
 }
 
 #[async_trait]
@@ -163,10 +270,12 @@ where
     B: Send,
     C: Command<B, E> + Send + Sync,
 {
-    fn description(&self, ctx: &Context) -> Option<String> {
-        let inner = self.inner.description(ctx)?;
+    fn info(&self, ctx: &Context) -> Info {
+        let prefix = ctx.prefix_for_room().unwrap_or(&self.prefix);
         let nick = nick::mention(&ctx.joined.session.name);
-        Some(format!("{}{} @{nick} - {inner}", self.prefix, self.name))
+        self.inner
+            .info(ctx)
+            .merge(Info::new().with_trigger(format!("{prefix}{} @{nick}", self.name)))
     }
 
     async fn execute(
@@ -177,17 +286,19 @@ where
         bot: &mut B,
     ) -> Result<bool, E> {
         // TODO Replace with let-else
-        let (name, rest) = match parse_prefix_initiated(arg, &self.prefix) {
+        let prefix = ctx.prefix_for_room().unwrap_or(&self.prefix);
+        let skip_whitespace = !self.respect_leading_whitespace;
+        let (name, rest) = match parse_prefix_initiated(arg, prefix, skip_whitespace) {
             Some(parsed) => parsed,
             None => return Ok(false),
         };
 
-        if name != self.name {
+        if !name_matches(name, &self.name, self.case_insensitive) {
             return Ok(false);
         }
 
         // TODO Replace with let-else
-        let (nick, rest) = match parse_prefix_initiated(rest, "@") {
+        let (nick, rest) = match parse_prefix_initiated(rest, "@", true) {
             Some(parsed) => parsed,
             None => return Ok(false),
         };
@@ -202,34 +313,60 @@ where
 
 #[cfg(test)]
 mod test {
-    use super::parse_prefix_initiated;
+    use super::{name_matches, parse_prefix_initiated};
 
     #[test]
     fn test_parse_prefixed() {
-        assert_eq!(parse_prefix_initiated("!foo", "!"), Some(("foo", "")));
-        assert_eq!(parse_prefix_initiated("    !foo", "!"), Some(("foo", "")));
-        assert_eq!(
-            parse_prefix_initiated("!foo    ", "!"),
-            Some(("foo", "   "))
-        );
-        assert_eq!(
-            parse_prefix_initiated("    !foo    ", "!"),
-            Some(("foo", "   "))
-        );
-        assert_eq!(
-            parse_prefix_initiated("!foo @bar", "!"),
-            Some(("foo", "@bar"))
-        );
-        assert_eq!(
-            parse_prefix_initiated("!foo    @bar", "!"),
-            Some(("foo", "   @bar"))
-        );
-        assert_eq!(
-            parse_prefix_initiated("!foo @bar   ", "!"),
-            Some(("foo", "@bar   "))
-        );
-        assert_eq!(parse_prefix_initiated("! foo @bar", "!"), None);
-        assert_eq!(parse_prefix_initiated("!", "!"), None);
-        assert_eq!(parse_prefix_initiated("?foo", "!"), None);
+        for skip_whitespace in [false, true] {
+            let parse = |text| parse_prefix_initiated(text, "!", skip_whitespace);
+            assert_eq!(parse("!foo"), Some(("foo", "")));
+            assert_eq!(parse("!foo    "), Some(("foo", "   ")));
+            assert_eq!(parse("!foo @bar"), Some(("foo", "@bar")));
+            assert_eq!(parse("!foo    @bar"), Some(("foo", "   @bar")));
+            assert_eq!(parse("!foo @bar   "), Some(("foo", "@bar   ")));
+            assert_eq!(parse("! foo @bar"), None);
+            assert_eq!(parse("!"), None);
+            assert_eq!(parse("?foo"), None);
+        }
+    }
+
+    #[test]
+    fn test_parse_prefixed_leading_whitespace() {
+        let parse = |text, skip_whitespace| parse_prefix_initiated(text, "!", skip_whitespace);
+        assert_eq!(parse("    !foo", true), Some(("foo", "")));
+        assert_eq!(parse("    !foo    ", true), Some(("foo", "   ")));
+        assert_eq!(parse("\n!foo", true), Some(("foo", "")));
+        assert_eq!(parse("    !foo", false), None);
+        assert_eq!(parse("    !foo    ", false), None);
+        assert_eq!(parse("\n!foo", false), None);
+    }
+
+    #[test]
+    fn test_bang_matching() {
+        // All combinations of respecting leading whitespace and case
+        // insensitivity, the way the bang commands use them
+        let matches = |text, respect_whitespace: bool, case_insensitive| {
+            parse_prefix_initiated(text, "!", !respect_whitespace)
+                .is_some_and(|(name, _)| name_matches(name, "ping", case_insensitive))
+        };
+
+        for case_insensitive in [false, true] {
+            assert!(matches("!ping", true, case_insensitive));
+            assert!(matches("!ping", false, case_insensitive));
+            assert!(!matches(" !ping", true, case_insensitive));
+            assert!(matches(" !ping", false, case_insensitive));
+            assert!(!matches("!pong", false, case_insensitive));
+        }
+
+        for respect_whitespace in [false, true] {
+            assert!(!matches("!Ping", respect_whitespace, false));
+            assert!(!matches("!PING", respect_whitespace, false));
+            assert!(matches("!Ping", respect_whitespace, true));
+            assert!(matches("!PING", respect_whitespace, true));
+        }
+
+        assert!(matches(" !PiNg", false, true));
+        assert!(!matches(" !PiNg", true, true));
+        assert!(!matches(" !PiNg", false, false));
     }
 }
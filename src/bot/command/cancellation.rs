@@ -0,0 +1,152 @@
+use std::sync::{Arc, Mutex, Weak};
+
+use tokio::sync::watch;
+
+use crate::conn::ConnTx;
+
+#[derive(Debug)]
+struct Inner {
+    cancelled: watch::Sender<bool>,
+    children: Mutex<Vec<Weak<Self>>>,
+}
+
+impl Inner {
+    fn new() -> Self {
+        Self {
+            cancelled: watch::Sender::new(false),
+            children: Mutex::new(vec![]),
+        }
+    }
+
+    fn cancel(&self) {
+        if self.cancelled.send_replace(true) {
+            return;
+        }
+        let children = std::mem::take(&mut *self.children.lock().unwrap());
+        for child in children.iter().filter_map(Weak::upgrade) {
+            child.cancel();
+        }
+    }
+}
+
+/// Signals long-running work that it should stop.
+///
+/// Clones of a token share their state, so cancelling one cancels all of them.
+/// Tokens can have children, which are cancelled together with their parent
+/// but can also be cancelled on their own.
+///
+/// A token can additionally be tied to a connection using [`Self::with_conn`].
+/// It then counts as cancelled once the connection is closed. The
+/// [`Context::cancellation`](super::Context::cancellation) of a command is
+/// tied to the connection the command was received on, so commands stop when
+/// their instance disconnects or is stopped.
+#[derive(Debug, Clone)]
+pub struct CancellationToken {
+    inner: Arc<Inner>,
+    conn_tx: Option<ConnTx>,
+}
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self {
+            inner: Arc::new(Inner::new()),
+            conn_tx: None,
+        }
+    }
+
+    /// Tie the token to a connection.
+    ///
+    /// The token counts as cancelled once the connection is closed.
+    pub fn with_conn(mut self, conn_tx: ConnTx) -> Self {
+        self.conn_tx = Some(conn_tx);
+        self
+    }
+
+    /// Create a token that is cancelled when this token is cancelled.
+    ///
+    /// Cancelling the child does not cancel its parent. The child is tied to
+    /// the same connection as its parent, if any.
+    pub fn child_token(&self) -> Self {
+        let child = Arc::new(Inner::new());
+        {
+            let mut children = self.inner.children.lock().unwrap();
+            children.retain(|c| c.strong_count() > 0);
+            children.push(Arc::downgrade(&child));
+        }
+        // The parent may have been cancelled before the child was registered
+        if *self.inner.cancelled.borrow() {
+            child.cancel();
+        }
+        Self {
+            inner: child,
+            conn_tx: self.conn_tx.clone(),
+        }
+    }
+
+    /// Cancel this token and all its children.
+    pub fn cancel(&self) {
+        self.inner.cancel();
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        *self.inner.cancelled.borrow() || self.conn_tx.as_ref().is_some_and(|tx| tx.is_closed())
+    }
+
+    /// Wait until the token is cancelled.
+    pub async fn cancelled(&self) {
+        let mut rx = self.inner.cancelled.subscribe();
+        // The sender lives as long as self, so this can't fail
+        let cancelled = rx.wait_for(|c| *c);
+        match &self.conn_tx {
+            Some(conn_tx) => tokio::select! {
+                _ = cancelled => {}
+                _ = conn_tx.closed() => {}
+            },
+            None => {
+                let _ = cancelled.await;
+            }
+        }
+    }
+}
+
+impl Default for CancellationToken {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use crate::conn;
+
+    use super::CancellationToken;
+
+    #[tokio::test]
+    async fn children() {
+        let parent = CancellationToken::new();
+        let child = parent.child_token();
+        let grandchild = child.child_token();
+
+        grandchild.cancel();
+        assert!(grandchild.is_cancelled());
+        assert!(!child.is_cancelled());
+
+        parent.cancel();
+        assert!(child.is_cancelled());
+        child.cancelled().await;
+        assert!(parent.child_token().is_cancelled());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn conn_closed() {
+        let token = CancellationToken::new().with_conn(conn::test::closed_tx());
+        assert!(token.is_cancelled());
+        token.cancelled().await;
+
+        let token = CancellationToken::new();
+        let waiting = tokio::time::timeout(Duration::from_secs(1), token.cancelled());
+        assert!(waiting.await.is_err());
+    }
+}
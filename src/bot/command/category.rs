@@ -0,0 +1,81 @@
+use std::time::Duration;
+
+use async_trait::async_trait;
+
+use crate::api::Message;
+
+use super::{Command, Context, Dedup, Info, RoomSizeGate};
+
+/// Put a command into a category, see [`CommandExt::category`].
+pub struct Categorized<C> {
+    category: String,
+    inner: C,
+}
+
+impl<C> Categorized<C> {
+    pub fn new<S: ToString>(category: S, inner: C) -> Self {
+        Self {
+            category: category.to_string(),
+            inner,
+        }
+    }
+}
+
+#[async_trait]
+impl<B, E, C> Command<B, E> for Categorized<C>
+where
+    B: Send,
+    C: Command<B, E> + Send + Sync,
+{
+    fn info(&self, ctx: &Context) -> Info {
+        let outer = Info::new().with_category(&self.category);
+        self.inner.info(ctx).merge(outer)
+    }
+
+    async fn execute(
+        &self,
+        arg: &str,
+        msg: &Message,
+        ctx: &Context,
+        bot: &mut B,
+    ) -> Result<bool, E> {
+        self.inner.execute(arg, msg, ctx, bot).await
+    }
+}
+
+/// Convenience methods for wrapping commands.
+pub trait CommandExt: Sized {
+    /// Put the command into a category.
+    ///
+    /// Categories are used to group commands in the
+    /// [`FullHelp`](crate::bot::botrulez::FullHelp) and in
+    /// [`Commands::export_info`](crate::bot::commands::Commands::export_info).
+    /// Wrapping an already categorized command overrides its category.
+    fn category<S: ToString>(self, category: S) -> Categorized<Self> {
+        Categorized::new(category, self)
+    }
+
+    /// Execute the command only once for identical messages sent within the
+    /// window.
+    ///
+    /// See [`Dedup`] for when two messages are identical.
+    fn dedup(self, window: Duration) -> Dedup<Self> {
+        Dedup::new(window, self)
+    }
+
+    /// Only run the command in rooms with at most this many sessions.
+    ///
+    /// See [`RoomSizeGate`] for more options.
+    fn max_room_size(self, max: usize) -> RoomSizeGate<Self> {
+        RoomSizeGate::new(self).with_max(max)
+    }
+
+    /// Only run the command in rooms with at least this many sessions.
+    ///
+    /// See [`RoomSizeGate`] for more options.
+    fn min_room_size(self, min: usize) -> RoomSizeGate<Self> {
+        RoomSizeGate::new(self).with_min(min)
+    }
+}
+
+impl<C> CommandExt for C {}
@@ -4,7 +4,7 @@ use clap::{CommandFactory, Parser};
 use crate::api::Message;
 use crate::conn;
 
-use super::{Command, Context};
+use super::{Command, Context, Info};
 
 #[async_trait]
 pub trait ClapCommand<B, E> {
@@ -96,6 +96,19 @@ fn parse_quoted_args(text: &str) -> Result<Vec<String>, &'static str> {
     Ok(args)
 }
 
+/// Render a command's long help without the usage line.
+///
+/// The usage line would contain the name of the binary, which doesn't make much
+/// sense in the context of a chat bot.
+fn render_long_help(command: clap::Command) -> String {
+    command
+        .help_template("{about-with-newline}\n{all-args}")
+        .render_long_help()
+        .to_string()
+        .trim_end()
+        .to_string()
+}
+
 pub struct Clap<C>(pub C);
 
 #[async_trait]
@@ -106,8 +119,14 @@ where
     C: ClapCommand<B, E> + Send + Sync,
     C::Args: Parser + Send,
 {
-    fn description(&self, _ctx: &Context) -> Option<String> {
-        C::Args::command().get_about().map(|s| format!("{s}"))
+    fn info(&self, _ctx: &Context) -> Info {
+        let command = C::Args::command();
+        Info {
+            trigger: None,
+            description: command.get_about().map(|s| format!("{s}")),
+            long_help: Some(render_long_help(command)),
+            ..Info::default()
+        }
     }
 
     async fn execute(
@@ -143,7 +162,17 @@ where
 
 #[cfg(test)]
 mod test {
-    use super::parse_quoted_args;
+    use clap::{CommandFactory, Parser};
+
+    use super::{parse_quoted_args, render_long_help};
+
+    /// Do the thing.
+    #[derive(Parser)]
+    struct Args {
+        /// How often to do the thing.
+        #[arg(long, short)]
+        amount: Option<u64>,
+    }
 
     fn assert_quoted(raw: &str, parsed: &[&str]) {
         let parsed = parsed.iter().map(|s| s.to_string()).collect();
@@ -179,4 +208,13 @@ mod test {
         assert!(parse_quoted_args("foo \\").is_err());
         assert!(parse_quoted_args("foo 'bar\\").is_err());
     }
+
+    #[test]
+    fn test_render_long_help() {
+        let help = render_long_help(Args::command());
+        assert!(help.starts_with("Do the thing"));
+        assert!(help.contains("-a, --amount <AMOUNT>"));
+        assert!(help.contains("How often to do the thing"));
+        assert!(!help.contains("Usage:"));
+    }
 }
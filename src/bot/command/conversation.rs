@@ -0,0 +1,142 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::sync::oneshot;
+use tokio::time::Instant;
+
+use crate::api::{Message, MessageId, UserId};
+
+/// How many of the bot's own messages are remembered per registry.
+const OWN_MESSAGES: usize = 100;
+
+type Waiters = HashMap<(MessageId, UserId), oneshot::Sender<Arc<Message>>>;
+
+#[derive(Debug, Default)]
+struct Registry {
+    waiters: Waiters,
+    /// The bot's most recent messages, oldest first.
+    own: VecDeque<Arc<Message>>,
+    /// Messages of the bot whose replies [`ReplyTo`](super::ReplyTo) commands
+    /// react to, and when they expire.
+    prompts: HashMap<MessageId, (Arc<Message>, Instant)>,
+}
+
+/// Messages that commands are waiting for as answers to their questions.
+///
+/// Commands ask questions via [`Context::ask`](super::Context::ask). The answer
+/// is the first direct reply to the question from the user it was asked. Since
+/// waiters are keyed by question and user, multiple conversations can happen
+/// at the same time without interfering with each other.
+///
+/// The registry also remembers the bot's own recent messages and the prompts
+/// registered via [`Self::add_prompt`], which [`ReplyTo`](super::ReplyTo)
+/// commands use to recognize replies to the bot.
+///
+/// [`Commands`](crate::bot::commands::Commands) owns a registry and delivers
+/// incoming messages to it before executing any commands. It also remembers
+/// every message the bot sends. Clones share the same registry.
+#[derive(Debug, Clone, Default)]
+pub struct Conversations(Arc<Mutex<Registry>>);
+
+impl Conversations {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// How many answers are currently being waited for.
+    pub fn pending(&self) -> usize {
+        self.0.lock().unwrap().waiters.len()
+    }
+
+    /// Deliver a message to the conversation waiting for it, if any.
+    ///
+    /// Returns `true` if the message was an answer to a question.
+    pub fn deliver(&self, msg: &Arc<Message>) -> bool {
+        let parent = match msg.parent {
+            Some(parent) => parent,
+            None => return false,
+        };
+        let key = (parent, msg.sender.id.clone());
+        match self.0.lock().unwrap().waiters.remove(&key) {
+            Some(tx) => tx.send(msg.clone()).is_ok(),
+            None => false,
+        }
+    }
+
+    /// Remember a message sent by the bot.
+    ///
+    /// Only the most recent messages are remembered.
+    pub fn remember_own(&self, msg: Arc<Message>) {
+        let mut guard = self.0.lock().unwrap();
+        guard.own.push_back(msg);
+        while guard.own.len() > OWN_MESSAGES {
+            guard.own.pop_front();
+        }
+    }
+
+    /// A recent message sent by the bot, if it is remembered.
+    pub fn own_message(&self, id: MessageId) -> Option<Arc<Message>> {
+        let guard = self.0.lock().unwrap();
+        guard.own.iter().rev().find(|m| m.id == id).cloned()
+    }
+
+    /// Register a message of the bot as a prompt for `ttl`.
+    ///
+    /// Replies to the prompt are handled by [`ReplyTo::prompts`](super::ReplyTo::prompts)
+    /// commands until the prompt expires or is removed.
+    pub fn add_prompt(&self, msg: Arc<Message>, ttl: Duration) {
+        let expires = Instant::now() + ttl;
+        let mut guard = self.0.lock().unwrap();
+        guard.prompts.retain(|_, (_, e)| *e > Instant::now());
+        guard.prompts.insert(msg.id, (msg, expires));
+    }
+
+    /// Remove a prompt, e.g. once it was answered.
+    ///
+    /// Returns `true` if the prompt existed and had not expired yet.
+    pub fn remove_prompt(&self, id: MessageId) -> bool {
+        let removed = self.0.lock().unwrap().prompts.remove(&id);
+        removed.is_some_and(|(_, expires)| expires > Instant::now())
+    }
+
+    /// A prompt registered via [`Self::add_prompt`], unless it expired.
+    pub fn prompt(&self, id: MessageId) -> Option<Arc<Message>> {
+        let guard = self.0.lock().unwrap();
+        match guard.prompts.get(&id) {
+            Some((msg, expires)) if *expires > Instant::now() => Some(msg.clone()),
+            _ => None,
+        }
+    }
+
+    /// Wait for the answer of a user to a question.
+    pub(crate) fn wait_for(&self, question: MessageId, user: UserId) -> Waiter {
+        let (tx, rx) = oneshot::channel();
+        let key = (question, user);
+        self.0.lock().unwrap().waiters.insert(key.clone(), tx);
+        Waiter {
+            conversations: self.clone(),
+            key,
+            rx,
+        }
+    }
+}
+
+/// Removes its waiter from the [`Conversations`] when dropped, e.g. because
+/// the question timed out or was cancelled.
+pub(crate) struct Waiter {
+    conversations: Conversations,
+    key: (MessageId, UserId),
+    pub(crate) rx: oneshot::Receiver<Arc<Message>>,
+}
+
+impl Drop for Waiter {
+    fn drop(&mut self) {
+        self.conversations
+            .0
+            .lock()
+            .unwrap()
+            .waiters
+            .remove(&self.key);
+    }
+}
@@ -0,0 +1,247 @@
+use async_trait::async_trait;
+use jiff::Timestamp;
+
+use crate::api::Message;
+use crate::bot::botrulez::format_relative_time;
+use crate::bot::instance::{Instance, InstanceConfig};
+use crate::conn::{self, DebugInfo, Joined};
+use crate::text::{Chaining, MessagePlan};
+
+use super::{Command, Context, Info};
+
+pub trait HasInstance {
+    /// The instance a command is executed in, if the bot knows it.
+    fn instance(&self, config: &InstanceConfig) -> Option<&Instance>;
+}
+
+/// Reply with the bot's internal view of the room and its connection.
+///
+/// The report reveals internal state, so only operators should be able to use
+/// this command. Restrict it by wrapping it in a [`Restricted`](super::Restricted),
+/// usually via [`Restricted::allow_users`](super::Restricted::allow_users).
+///
+/// The report includes information from the [`Context`], from
+/// [`ConnTx::debug_info`](crate::conn::ConnTx::debug_info) and, if the bot
+/// knows the [`Instance`] the command is executed in, from the instance.
+pub struct DebugState {
+    sample_size: usize,
+    plan: MessagePlan,
+}
+
+impl Default for DebugState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl DebugState {
+    pub fn new() -> Self {
+        Self {
+            sample_size: 5,
+            plan: MessagePlan::new(),
+        }
+    }
+
+    /// How many session names from the listing to include (default: 5).
+    pub fn with_sample_size(mut self, sample_size: usize) -> Self {
+        self.sample_size = sample_size;
+        self
+    }
+
+    /// How to split the report into multiple messages if it is too long.
+    pub fn with_plan(mut self, plan: MessagePlan) -> Self {
+        self.plan = plan;
+        self
+    }
+
+    fn report(
+        &self,
+        joined: &Joined,
+        info: &DebugInfo,
+        instance: Option<&Instance>,
+        now: Timestamp,
+    ) -> String {
+        let mut lines = vec![];
+
+        let session = &joined.session;
+        lines.push(format!(
+            "session: {} ({}, {})",
+            session.name, session.id.0, session.session_id.0
+        ));
+
+        let account = match (&joined.account, joined.account_email_verified) {
+            (None, _) => "none".to_string(),
+            (Some(account), Some(true)) => format!("{} (verified)", account.name),
+            (Some(account), Some(false)) => format!("{} (unverified)", account.name),
+            (Some(account), None) => account.name.clone(),
+        };
+        lines.push(format!("account: {account}"));
+
+        let mut names = joined
+            .listing
+            .values()
+            .map(|s| s.name())
+            .collect::<Vec<_>>();
+        names.sort_unstable();
+        let mut sample = names
+            .iter()
+            .take(self.sample_size)
+            .copied()
+            .collect::<Vec<_>>()
+            .join(", ");
+        if names.len() > self.sample_size {
+            sample.push_str(&format!(", +{} more", names.len() - self.sample_size));
+        }
+        lines.push(format!("listing: {} sessions ({sample})", names.len()));
+
+        let last_who = match joined.last_who {
+            Some(time) => format_relative_time(time - now),
+            None => "never".to_string(),
+        };
+        lines.push(format!("last who: {last_who}"));
+
+        match instance {
+            Some(instance) => {
+                let reconnects = instance.connections().saturating_sub(1);
+                lines.push(format!("reconnects: {reconnects}"));
+                lines.push(format!("scheduled: {}", instance.scheduled().len()));
+            }
+            None => {
+                lines.push("reconnects: unknown".to_string());
+                lines.push("scheduled: unknown".to_string());
+            }
+        }
+
+        let throttled = match &info.throttled {
+            Some(reason) => format!("yes ({reason})"),
+            None => "no".to_string(),
+        };
+        lines.push(format!(
+            "throttled: {throttled}, {} replies so far",
+            info.throttled_replies
+        ));
+        lines.push(format!("pending replies: {}", info.pending_replies));
+        lines.push(format!("malformed packets: {}", info.malformed_packets));
+        if info.disconnect_pending {
+            lines.push("disconnect pending".to_string());
+        }
+
+        lines.join("\n")
+    }
+}
+
+#[async_trait]
+impl<B, E> Command<B, E> for DebugState
+where
+    B: HasInstance + Send,
+    E: From<conn::Error>,
+{
+    fn info(&self, _ctx: &Context) -> Info {
+        Info::new().with_description("Show the bot's internal state.")
+    }
+
+    async fn execute(
+        &self,
+        _arg: &str,
+        msg: &Message,
+        ctx: &Context,
+        bot: &mut B,
+    ) -> Result<bool, E> {
+        let info = ctx.conn_tx.debug_info().await?;
+        let now = ctx.config.server.clock.now();
+        let report = self.report(&ctx.joined, &info, bot.instance(&ctx.config), now);
+        ctx.send_plan(Some(msg.id), &self.plan, report, Chaining::Siblings)
+            .await?;
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use jiff::Timestamp;
+
+    use crate::api::{Message, UserId};
+    use crate::bot::command::test::context;
+    use crate::bot::command::{Command, Restricted};
+    use crate::bot::instance::{Instance, InstanceConfig};
+    use crate::conn::{self, ConnectTimings, DebugInfo, DiagnosticCounts, SessionInfo};
+    use crate::test_util::{JoinedBuilder, MessageBuilder, SessionViewBuilder};
+
+    use super::{DebugState, HasInstance};
+
+    struct Bot;
+
+    impl HasInstance for Bot {
+        fn instance(&self, _config: &InstanceConfig) -> Option<&Instance> {
+            None
+        }
+    }
+
+    fn message(sender: &str) -> Message {
+        let sender = SessionViewBuilder::new("someone")
+            .with_id(UserId(sender.to_string()))
+            .build();
+        MessageBuilder::new("!debug").with_sender(sender).build()
+    }
+
+    fn command() -> DebugState {
+        DebugState::new().with_sample_size(2)
+    }
+
+    #[tokio::test]
+    async fn operators_only() {
+        let ctx = context();
+        let operator = UserId("agent:operator".to_string());
+        let command = Restricted::allow_users(vec![operator], command());
+        let result: Result<bool, conn::Error> = command
+            .execute("", &message("agent:someone"), &ctx, &mut Bot)
+            .await;
+        assert!(matches!(result, Ok(false)));
+
+        // Operators get past the check, but the connection is closed
+        let result: Result<bool, conn::Error> = command
+            .execute("", &message("agent:operator"), &ctx, &mut Bot)
+            .await;
+        assert!(matches!(result, Err(conn::Error::ConnectionClosed)));
+    }
+
+    #[test]
+    fn report() {
+        let joined = ["carol", "alice", "bob"]
+            .into_iter()
+            .fold(JoinedBuilder::new(), |joined, name| {
+                joined.with_listing_entry(SessionInfo::Full(SessionViewBuilder::new(name).build()))
+            })
+            .build();
+        let info = DebugInfo {
+            pending_replies: 2,
+            malformed_packets: 0,
+            throttled_replies: 3,
+            throttled: Some("slow down".to_string()),
+            slow_mode_interval: None,
+            pending_sends: 0,
+            disconnect_pending: false,
+            generation: 0,
+            received_packets: 0,
+            parsed_packets: 0,
+            diagnostics: DiagnosticCounts::default(),
+            connect_timings: ConnectTimings::default(),
+        };
+
+        assert_eq!(
+            command().report(&joined, &info, None, Timestamp::now()),
+            [
+                "session: TestBot (bot:TestBot, me)",
+                "account: none",
+                "listing: 3 sessions (alice, bob, +1 more)",
+                "last who: never",
+                "reconnects: unknown",
+                "scheduled: unknown",
+                "throttled: yes (slow down), 3 replies so far",
+                "pending replies: 2",
+                "malformed packets: 0",
+            ]
+            .join("\n")
+        );
+    }
+}
@@ -0,0 +1,241 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::VecDeque;
+use std::hash::{Hash, Hasher};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use tokio::time::Instant;
+
+use crate::api::{Message, MessageId, UserId};
+
+use super::{Command, Context, Info};
+
+#[derive(Debug, PartialEq, Eq)]
+struct Key {
+    sender: UserId,
+    parent: Option<MessageId>,
+    edited: bool,
+    content_hash: u64,
+}
+
+impl Key {
+    fn of(msg: &Message) -> Self {
+        // Whitespace differences shouldn't make two messages distinct
+        let mut hasher = DefaultHasher::new();
+        for word in msg.content.split_whitespace() {
+            word.hash(&mut hasher);
+        }
+
+        Self {
+            sender: msg.sender.id.clone(),
+            parent: msg.parent,
+            edited: msg.edited.is_some(),
+            content_hash: hasher.finish(),
+        }
+    }
+}
+
+/// Execute the inner command only once for identical messages sent in quick
+/// succession.
+///
+/// Two messages are identical if they were sent by the same user, have the
+/// same content (ignoring whitespace differences) and the same parent. An
+/// edited message is never identical to an unedited one.
+///
+/// Only messages the inner command handled (i.e. returned `true` for) are
+/// remembered. Duplicates of such messages are silently marked as handled
+/// without executing the inner command again.
+pub struct Dedup<C> {
+    window: Duration,
+    capacity: usize,
+    seen: Mutex<VecDeque<(Instant, Key)>>,
+    inner: C,
+}
+
+impl<C> Dedup<C> {
+    pub fn new(window: Duration, inner: C) -> Self {
+        Self {
+            window,
+            capacity: 256,
+            seen: Mutex::new(VecDeque::new()),
+            inner,
+        }
+    }
+
+    /// The maximum amount of messages to remember at once.
+    ///
+    /// If more messages are handled within the window, the oldest ones are
+    /// forgotten early.
+    pub fn with_capacity(mut self, capacity: usize) -> Self {
+        self.capacity = capacity;
+        self
+    }
+
+    fn is_duplicate(&self, key: &Key) -> bool {
+        let now = Instant::now();
+        let mut seen = self.seen.lock().unwrap();
+        while let Some((time, _)) = seen.front() {
+            if now.duration_since(*time) < self.window {
+                break;
+            }
+            seen.pop_front();
+        }
+        seen.iter().any(|(_, k)| k == key)
+    }
+
+    fn remember(&self, key: Key) {
+        let mut seen = self.seen.lock().unwrap();
+        while seen.len() >= self.capacity.max(1) {
+            seen.pop_front();
+        }
+        seen.push_back((Instant::now(), key));
+    }
+}
+
+#[async_trait]
+impl<B, E, C> Command<B, E> for Dedup<C>
+where
+    B: Send,
+    C: Command<B, E> + Send + Sync,
+{
+    fn info(&self, ctx: &Context) -> Info {
+        self.inner.info(ctx)
+    }
+
+    async fn execute(
+        &self,
+        arg: &str,
+        msg: &Message,
+        ctx: &Context,
+        bot: &mut B,
+    ) -> Result<bool, E> {
+        let key = Key::of(msg);
+        if self.is_duplicate(&key) {
+            return Ok(true);
+        }
+
+        let handled = self.inner.execute(arg, msg, ctx, bot).await?;
+        if handled {
+            self.remember(key);
+        }
+        Ok(handled)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use async_trait::async_trait;
+
+    use crate::api::{Message, MessageId, Snowflake, Time, UserId};
+    use crate::bot::command::test::context;
+    use crate::bot::command::{Command, CommandExt, Context, Info};
+    use crate::conn;
+    use crate::test_util::{MessageBuilder, SessionViewBuilder};
+
+    use super::Dedup;
+
+    fn message(sender: &str, content: &str, parent: Option<u64>) -> Message {
+        let sender = SessionViewBuilder::new(sender)
+            .with_id(UserId(sender.to_string()))
+            .build();
+        MessageBuilder::new(content)
+            .with_id(MessageId(Snowflake(0)))
+            .with_parent_opt(parent.map(|p| MessageId(Snowflake(p))))
+            .with_sender(sender)
+            .build()
+    }
+
+    /// Counts how often it was executed and handles all messages except those
+    /// containing "skip".
+    struct Count;
+
+    #[async_trait]
+    impl Command<usize, conn::Error> for Count {
+        fn info(&self, _ctx: &Context) -> Info {
+            Info::new()
+        }
+
+        async fn execute(
+            &self,
+            _arg: &str,
+            msg: &Message,
+            _ctx: &Context,
+            bot: &mut usize,
+        ) -> Result<bool, conn::Error> {
+            *bot += 1;
+            Ok(!msg.content.contains("skip"))
+        }
+    }
+
+    /// Whether the inner command was executed for the message.
+    async fn handle(dedup: &Dedup<Count>, msg: &Message) -> bool {
+        let mut executions = 0;
+        let handled = dedup.execute("", msg, &context(), &mut executions).await;
+        if executions == 0 {
+            // Duplicates are marked as handled
+            assert!(handled.unwrap());
+        }
+        executions > 0
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn suppresses_duplicates() {
+        let dedup = Count.dedup(Duration::from_secs(5));
+        assert!(handle(&dedup, &message("agent:a", "!roll", None)).await);
+        assert!(!handle(&dedup, &message("agent:a", "!roll", None)).await);
+        assert!(!handle(&dedup, &message("agent:a", "  !roll ", None)).await);
+
+        // Different senders and contents are distinct
+        assert!(handle(&dedup, &message("agent:b", "!roll", None)).await);
+        assert!(handle(&dedup, &message("agent:a", "!roll 2", None)).await);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn window_expires() {
+        let dedup = Count.dedup(Duration::from_secs(5));
+        assert!(handle(&dedup, &message("agent:a", "!roll", None)).await);
+        tokio::time::advance(Duration::from_secs(4)).await;
+        assert!(!handle(&dedup, &message("agent:a", "!roll", None)).await);
+        tokio::time::advance(Duration::from_secs(1)).await;
+        assert!(handle(&dedup, &message("agent:a", "!roll", None)).await);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn parents_and_edits_are_distinct() {
+        let dedup = Count.dedup(Duration::from_secs(5));
+        assert!(handle(&dedup, &message("agent:a", "!roll", None)).await);
+        assert!(handle(&dedup, &message("agent:a", "!roll", Some(1))).await);
+        assert!(handle(&dedup, &message("agent:a", "!roll", Some(2))).await);
+        assert!(!handle(&dedup, &message("agent:a", "!roll", Some(2))).await);
+
+        let mut edited = message("agent:a", "!roll", None);
+        edited.edited = Some(Time(1));
+        assert!(handle(&dedup, &edited).await);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn bounded() {
+        let dedup = Count.dedup(Duration::from_secs(5)).with_capacity(2);
+        assert!(handle(&dedup, &message("agent:a", "one", None)).await);
+        assert!(handle(&dedup, &message("agent:a", "two", None)).await);
+        assert!(handle(&dedup, &message("agent:a", "three", None)).await);
+        assert_eq!(dedup.seen.lock().unwrap().len(), 2);
+        assert!(handle(&dedup, &message("agent:a", "one", None)).await);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn unhandled_messages_are_not_remembered() {
+        let dedup = Count.dedup(Duration::from_secs(5));
+        let msg = message("agent:a", "!skip", None);
+        let ctx = context();
+        let mut executions = 0;
+        for _ in 0..2 {
+            let handled = dedup.execute("", &msg, &ctx, &mut executions);
+            assert!(!handled.await.unwrap());
+        }
+        assert_eq!(executions, 2);
+    }
+}
@@ -0,0 +1,62 @@
+use async_trait::async_trait;
+
+use crate::api::Message;
+
+use super::{Command, Context, Info};
+
+/// Override the description, long help and examples of a command.
+///
+/// Everything not explicitly set is taken from the inner command, so wrapping
+/// a [`Clap`](super::Clap) command without setting a long help keeps the help
+/// text generated by clap.
+pub struct Described<C> {
+    info: Info,
+    inner: C,
+}
+
+impl<C> Described<C> {
+    pub fn new(inner: C) -> Self {
+        Self {
+            info: Info::new(),
+            inner,
+        }
+    }
+
+    pub fn with_description<S: ToString>(mut self, description: S) -> Self {
+        self.info = self.info.with_description(description);
+        self
+    }
+
+    pub fn with_long_help<S: ToString>(mut self, long_help: S) -> Self {
+        self.info = self.info.with_long_help(long_help);
+        self
+    }
+
+    /// Add an example invocation. Once an example is added, the examples of
+    /// the inner command are no longer used.
+    pub fn with_example<S: ToString>(mut self, example: S) -> Self {
+        self.info = self.info.with_example(example);
+        self
+    }
+}
+
+#[async_trait]
+impl<B, E, C> Command<B, E> for Described<C>
+where
+    B: Send,
+    C: Command<B, E> + Send + Sync,
+{
+    fn info(&self, ctx: &Context) -> Info {
+        self.inner.info(ctx).merge(self.info.clone())
+    }
+
+    async fn execute(
+        &self,
+        arg: &str,
+        msg: &Message,
+        ctx: &Context,
+        bot: &mut B,
+    ) -> Result<bool, E> {
+        self.inner.execute(arg, msg, ctx, bot).await
+    }
+}
@@ -2,7 +2,7 @@ use async_trait::async_trait;
 
 use crate::api::Message;
 
-use super::{Command, Context};
+use super::{Command, Context, Info};
 
 pub struct Hidden<C>(pub C);
 
@@ -12,9 +12,9 @@ where
     B: Send,
     C: Command<B, E> + Send + Sync,
 {
-    fn description(&self, _ctx: &Context) -> Option<String> {
+    fn info(&self, _ctx: &Context) -> Info {
         // Default implementation, repeated here for emphasis.
-        None
+        Info::default()
     }
 
     async fn execute(
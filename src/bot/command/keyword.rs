@@ -0,0 +1,321 @@
+use std::collections::HashMap;
+use std::ops::Range;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use caseless::Caseless;
+use tokio::time::Instant;
+use unicode_normalization::char::is_combining_mark;
+use unicode_normalization::UnicodeNormalization;
+
+use crate::api::Message;
+use crate::Emoji;
+
+use super::{Command, Context, Info};
+
+/// Case fold a word similar to [`nick::normalize`](crate::nick::normalize).
+fn normalize(word: &str) -> String {
+    word.nfkc().default_case_fold().collect()
+}
+
+fn is_url(token: &str) -> bool {
+    token.contains("://") || token.starts_with("www.")
+}
+
+/// Byte ranges of the text that should never be matched.
+fn excluded_ranges(text: &str, emoji: Option<&Emoji>) -> Vec<Range<usize>> {
+    let mut ranges = vec![];
+
+    // Code spans. An unpaired backtick doesn't start a span.
+    let mut ticks = text.match_indices('`').map(|(i, _)| i);
+    while let (Some(start), Some(end)) = (ticks.next(), ticks.next()) {
+        ranges.push(start..end + 1);
+    }
+
+    // URL-looking tokens
+    let mut start = 0;
+    for token in text.split_whitespace() {
+        let offset = start + text[start..].find(token).unwrap_or(0);
+        start = offset + token.len();
+        if is_url(token) {
+            ranges.push(offset..start);
+        }
+    }
+
+    if let Some(emoji) = emoji {
+        for (range, _) in emoji.find(text) {
+            ranges.push(*range.start()..*range.end() + 1);
+        }
+    }
+
+    ranges
+}
+
+/// Split text into normalized words along with their byte ranges.
+///
+/// A word is a maximal run of alphanumeric characters, combining marks and
+/// underscores.
+fn words(text: &str, excluded: &[Range<usize>]) -> Vec<(Range<usize>, String)> {
+    let mut words = vec![];
+    let mut start = None;
+    for (i, c) in text.char_indices().chain([(text.len(), ' ')]) {
+        let is_word = (c.is_alphanumeric() || is_combining_mark(c) || c == '_')
+            && !excluded.iter().any(|r| r.contains(&i));
+        match (start, is_word) {
+            (None, true) => start = Some(i),
+            (Some(s), false) => {
+                words.push((s..i, normalize(&text[s..i])));
+                start = None;
+            }
+            _ => {}
+        }
+    }
+    words
+}
+
+/// A keyword found in a text by a [`Trigger`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Match {
+    /// Index of the keyword in the order it was added to the [`Trigger`].
+    pub keyword: usize,
+    /// Byte range of the keyword in the text.
+    pub range: Range<usize>,
+}
+
+/// Find keywords and phrases in messages, e.g. to respond whenever somebody
+/// mentions tea.
+///
+/// Keywords only match whole words, so `tea` matches `Tea!` but not `team`.
+/// Phrases consisting of multiple words match if the words appear in order,
+/// separated only by whitespace or punctuation. Words are compared after
+/// converting to NFKC and case folding.
+///
+/// Content inside code spans (delimited by backticks) and URL-looking tokens is
+/// ignored.
+#[derive(Default)]
+pub struct Trigger {
+    keywords: Vec<Vec<String>>,
+    emoji: Option<Emoji>,
+}
+
+impl Trigger {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a keyword or phrase.
+    ///
+    /// Keywords without any alphanumeric characters never match.
+    pub fn keyword<S: AsRef<str>>(mut self, keyword: S) -> Self {
+        let words = words(keyword.as_ref(), &[]);
+        self.keywords
+            .push(words.into_iter().map(|(_, w)| w).collect());
+        self
+    }
+
+    /// Ignore colon-delimited emoji like `:tea:` known to the given emoji table.
+    pub fn with_skip_emoji(mut self, emoji: Emoji) -> Self {
+        self.emoji = Some(emoji);
+        self
+    }
+
+    /// Find all occurrences of all keywords in a text.
+    ///
+    /// Matches are sorted by their position in the text.
+    pub fn matches(&self, text: &str) -> Vec<Match> {
+        let excluded = excluded_ranges(text, self.emoji.as_ref());
+        let words = words(text, &excluded);
+
+        let mut matches = vec![];
+        for (i, (start, _)) in words.iter().enumerate() {
+            for (keyword, keyword_words) in self.keywords.iter().enumerate() {
+                if keyword_words.is_empty() || words.len() - i < keyword_words.len() {
+                    continue;
+                }
+                let candidate = &words[i..i + keyword_words.len()];
+                if candidate.iter().map(|(_, w)| w).eq(keyword_words) {
+                    let end = candidate.last().unwrap().0.end;
+                    matches.push(Match {
+                        keyword,
+                        range: start.start..end,
+                    });
+                }
+            }
+        }
+        matches
+    }
+
+    pub fn is_match(&self, text: &str) -> bool {
+        !self.matches(text).is_empty()
+    }
+}
+
+/// Execute the inner command whenever a message contains one of the
+/// [`Trigger`]'s keywords.
+///
+/// Unlike most other wrappers, this doesn't consume any part of the argument.
+/// The inner command receives the argument unchanged.
+///
+/// With a cooldown, the inner command is only executed once per room within
+/// the cooldown. Messages containing keywords during the cooldown are not
+/// handled.
+pub struct Keyword<C> {
+    trigger: Trigger,
+    cooldown: Option<Duration>,
+    last_triggered: Mutex<HashMap<String, Instant>>,
+    inner: C,
+}
+
+impl<C> Keyword<C> {
+    pub fn new(trigger: Trigger, inner: C) -> Self {
+        Self {
+            trigger,
+            cooldown: None,
+            last_triggered: Mutex::new(HashMap::new()),
+            inner,
+        }
+    }
+
+    pub fn with_cooldown(mut self, cooldown: Duration) -> Self {
+        self.cooldown = Some(cooldown);
+        self
+    }
+
+    fn cooling_down(&self, room: &str) -> bool {
+        let cooldown = match self.cooldown {
+            Some(cooldown) => cooldown,
+            None => return false,
+        };
+        let last_triggered = self.last_triggered.lock().unwrap();
+        last_triggered
+            .get(room)
+            .is_some_and(|time| time.elapsed() < cooldown)
+    }
+
+    fn triggered(&self, room: &str) {
+        if self.cooldown.is_some() {
+            let mut last_triggered = self.last_triggered.lock().unwrap();
+            last_triggered.insert(room.to_string(), Instant::now());
+        }
+    }
+}
+
+#[async_trait]
+impl<B, E, C> Command<B, E> for Keyword<C>
+where
+    B: Send,
+    C: Command<B, E> + Send + Sync,
+{
+    fn info(&self, ctx: &Context) -> Info {
+        self.inner.info(ctx)
+    }
+
+    async fn execute(
+        &self,
+        arg: &str,
+        msg: &Message,
+        ctx: &Context,
+        bot: &mut B,
+    ) -> Result<bool, E> {
+        if !self.trigger.is_match(arg) || self.cooling_down(&ctx.config.room) {
+            return Ok(false);
+        }
+
+        let handled = self.inner.execute(arg, msg, ctx, bot).await?;
+        if handled {
+            self.triggered(&ctx.config.room);
+        }
+        Ok(handled)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::Emoji;
+
+    use super::{Match, Trigger};
+
+    fn ranges<'a>(trigger: &Trigger, text: &'a str) -> Vec<&'a str> {
+        trigger
+            .matches(text)
+            .into_iter()
+            .map(|m| &text[m.range])
+            .collect()
+    }
+
+    #[test]
+    fn word_boundaries() {
+        let trigger = Trigger::new().keyword("tea");
+        assert_eq!(ranges(&trigger, "tea"), vec!["tea"]);
+        assert_eq!(ranges(&trigger, "Some TEA, please!"), vec!["TEA"]);
+        assert_eq!(ranges(&trigger, "(tea)tea"), vec!["tea", "tea"]);
+        assert!(!trigger.is_match("team"));
+        assert!(!trigger.is_match("steam"));
+        assert!(!trigger.is_match("teatime"));
+        assert!(!trigger.is_match("tea_time"));
+    }
+
+    #[test]
+    fn phrases() {
+        let trigger = Trigger::new().keyword("green tea").keyword("tea");
+        assert_eq!(
+            trigger.matches("I like green  tea."),
+            vec![
+                Match {
+                    keyword: 0,
+                    range: 7..17
+                },
+                Match {
+                    keyword: 1,
+                    range: 14..17
+                },
+            ]
+        );
+        assert!(!Trigger::new().keyword("green tea").is_match("tea green"));
+        assert!(!Trigger::new().keyword("...").is_match("..."));
+    }
+
+    #[test]
+    fn unicode() {
+        let trigger = Trigger::new().keyword("straße").keyword("café");
+        assert_eq!(ranges(&trigger, "STRASSE"), vec!["STRASSE"]);
+        assert_eq!(ranges(&trigger, "Ein Café!"), vec!["Café"]);
+        // Decomposed é
+        assert_eq!(ranges(&trigger, "cafe\u{301}"), vec!["cafe\u{301}"]);
+        assert!(!trigger.is_match("cafés"));
+        // Full-width letters are compatibility-equivalent to ascii letters
+        assert!(Trigger::new().keyword("tea").is_match("ｔｅａ"));
+    }
+
+    #[test]
+    fn code_spans() {
+        let trigger = Trigger::new().keyword("tea");
+        assert!(!trigger.is_match("`tea`"));
+        assert!(!trigger.is_match("run `make tea` now"));
+        assert!(trigger.is_match("`code` tea `code`"));
+        // Unpaired backticks don't start a code span
+        assert!(trigger.is_match("`code` `tea"));
+    }
+
+    #[test]
+    fn urls() {
+        let trigger = Trigger::new().keyword("tea");
+        assert!(!trigger.is_match("https://example.com/tea"));
+        assert!(!trigger.is_match("see www.tea.com"));
+        assert!(trigger.is_match("tea: https://example.com/"));
+        assert!(trigger.is_match("https://example.com/ tea"));
+    }
+
+    #[test]
+    fn emoji() {
+        let trigger = Trigger::new().keyword("tea");
+        assert!(trigger.is_match(":tea:"));
+
+        let trigger = trigger.with_skip_emoji(Emoji::global().clone());
+        assert!(!trigger.is_match(":tea:"));
+        assert!(trigger.is_match(":tea: tea"));
+        // Not an emoji
+        assert!(trigger.is_match(":nottea:tea"));
+    }
+}
@@ -0,0 +1,476 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::ops::RangeInclusive;
+use std::sync::{Arc, Mutex};
+
+use async_trait::async_trait;
+
+use crate::api::Message;
+use crate::conn;
+use crate::text::{self, MENTION_ESCAPE, NICK_ESCAPE};
+use crate::{Demojifier, Emoji};
+
+use super::{Command, Context, Info};
+
+/// Changes the content of messages before a command sends them.
+///
+/// Transforms are applied by [`Context::send`], [`Context::reply`] and the
+/// other sending functions of [`Context`], except for [`Context::send_raw`].
+/// Which transform is active in which room is decided by
+/// [`OutputTransforms`].
+pub trait OutputTransform: Send + Sync {
+    fn transform<'a>(&self, content: &'a str) -> Cow<'a, str>;
+}
+
+impl<F> OutputTransform for F
+where
+    F: Fn(&str) -> String + Send + Sync,
+{
+    fn transform<'a>(&self, content: &'a str) -> Cow<'a, str> {
+        Cow::Owned(self(content))
+    }
+}
+
+/// What [`AsciiFallback`] does with unicode emoji.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum EmojiFallback {
+    /// Replace emoji by their colon-delimited names, e.g. `:thumbsup:`.
+    #[default]
+    Shortcodes,
+    /// Remove emoji entirely.
+    Remove,
+    /// Leave emoji as they are.
+    Keep,
+}
+
+/// Character ranges that [`AsciiFallback`] strips by default.
+pub const DECORATIVE_CHARS: &[RangeInclusive<char>] = &[
+    // Zero width joiner, used to glue emoji together
+    '\u{200d}'..='\u{200d}',
+    // Box drawing
+    '\u{2500}'..='\u{257f}',
+    // Block elements
+    '\u{2580}'..='\u{259f}',
+    // Geometric shapes
+    '\u{25a0}'..='\u{25ff}',
+    // Braille patterns, popular for ASCII art
+    '\u{2800}'..='\u{28ff}',
+    // Variation selectors
+    '\u{fe00}'..='\u{fe0f}',
+];
+
+/// Make messages easier to read for screen readers and plain-text clients.
+///
+/// Unicode emoji are replaced by their names (see [`EmojiFallback`]) and
+/// decorative characters like box drawing characters are removed (see
+/// [`DECORATIVE_CHARS`]). Emoji are handled first, so stripping e.g. zero
+/// width joiners doesn't break up emoji that could otherwise be named.
+///
+/// Escaped mentions (see [`text::escape_mentions`]) and nicks (see
+/// [`Filler::fill_nick`](text::Filler::fill_nick)) are never stripped, even if
+/// [`MENTION_ESCAPE`] or [`NICK_ESCAPE`] is in one of the stripped ranges.
+#[derive(Debug, Clone)]
+pub struct AsciiFallback {
+    demojifier: Arc<Demojifier>,
+    emoji: EmojiFallback,
+    strip: Vec<RangeInclusive<char>>,
+}
+
+impl AsciiFallback {
+    pub fn new() -> Self {
+        Self {
+            demojifier: Arc::new(Demojifier::new(Emoji::global())),
+            emoji: EmojiFallback::default(),
+            strip: DECORATIVE_CHARS.to_vec(),
+        }
+    }
+
+    pub fn with_emoji(mut self, emoji: EmojiFallback) -> Self {
+        self.emoji = emoji;
+        self
+    }
+
+    /// Additionally strip all characters in a range.
+    pub fn with_strip(mut self, range: RangeInclusive<char>) -> Self {
+        self.strip.push(range);
+        self
+    }
+
+    /// Strip exactly the characters in these ranges instead of
+    /// [`DECORATIVE_CHARS`].
+    pub fn with_strip_only<I>(mut self, ranges: I) -> Self
+    where
+        I: IntoIterator<Item = RangeInclusive<char>>,
+    {
+        self.strip = ranges.into_iter().collect();
+        self
+    }
+
+    fn is_stripped(&self, c: char) -> bool {
+        self.strip.iter().any(|r| r.contains(&c))
+    }
+
+    fn handle_emoji<'a>(&self, content: &'a str) -> Cow<'a, str> {
+        match self.emoji {
+            EmojiFallback::Shortcodes => self.demojifier.demojify(content),
+            EmojiFallback::Remove => {
+                let emoji = self.demojifier.find(content);
+                if emoji.is_empty() {
+                    return Cow::Borrowed(content);
+                }
+                let mut result = String::new();
+                let mut after_last_emoji = 0;
+                for (range, _) in emoji {
+                    result.push_str(&content[after_last_emoji..range.start]);
+                    after_last_emoji = range.end;
+                }
+                result.push_str(&content[after_last_emoji..]);
+                Cow::Owned(result)
+            }
+            EmojiFallback::Keep => Cow::Borrowed(content),
+        }
+    }
+}
+
+impl Default for AsciiFallback {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl OutputTransform for AsciiFallback {
+    fn transform<'a>(&self, content: &'a str) -> Cow<'a, str> {
+        let content = self.handle_emoji(content);
+        if !content.chars().any(|c| self.is_stripped(c)) {
+            return content;
+        }
+        let mut result = String::with_capacity(content.len());
+        let mut prev = None;
+        for c in content.chars() {
+            let is_escape = prev == Some('@') && (c == MENTION_ESCAPE || c == NICK_ESCAPE);
+            if is_escape || !self.is_stripped(c) {
+                result.push(c);
+            }
+            prev = Some(c);
+        }
+        Cow::Owned(result)
+    }
+}
+
+/// Prevent messages from pinging anyone.
+///
+/// Mentions are escaped using [`text::escape_mentions`]. Since escaping is
+/// idempotent, content that was already escaped, e.g. by a
+/// [`Template`](crate::text::Template), isn't escaped twice.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NoMentions;
+
+impl OutputTransform for NoMentions {
+    fn transform<'a>(&self, content: &'a str) -> Cow<'a, str> {
+        text::escape_mentions(content)
+    }
+}
+
+#[derive(Default)]
+struct Transforms {
+    global: Option<Arc<dyn OutputTransform>>,
+    /// Overrides of the global transform. `None` disables transforming
+    /// messages in the room.
+    rooms: HashMap<String, Option<Arc<dyn OutputTransform>>>,
+}
+
+/// Decides which [`OutputTransform`] is active in which room.
+///
+/// By default, no transform is active anywhere. A transform can be set
+/// globally and overridden per room, either when setting up the bot or at
+/// runtime, e.g. via [`OutputMode`].
+///
+/// [`Commands`](crate::bot::commands::Commands) owns a registry and resolves
+/// the transform for each [`Context`] it creates. Clones share the same
+/// transforms.
+#[derive(Clone, Default)]
+pub struct OutputTransforms(Arc<Mutex<Transforms>>);
+
+impl OutputTransforms {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the transform used in rooms without an override.
+    pub fn set_global(&self, transform: Option<Arc<dyn OutputTransform>>) {
+        self.0.lock().unwrap().global = transform;
+    }
+
+    /// Override the global transform in a room.
+    ///
+    /// If `transform` is `None`, messages in the room are not transformed,
+    /// regardless of the global transform.
+    pub fn set_room<S: ToString>(&self, room: S, transform: Option<Arc<dyn OutputTransform>>) {
+        let mut guard = self.0.lock().unwrap();
+        guard.rooms.insert(room.to_string(), transform);
+    }
+
+    /// Remove the override of a room so it uses the global transform again.
+    pub fn reset_room(&self, room: &str) {
+        self.0.lock().unwrap().rooms.remove(room);
+    }
+
+    /// The transform active in a room, if any.
+    pub fn resolve(&self, room: &str) -> Option<Arc<dyn OutputTransform>> {
+        let guard = self.0.lock().unwrap();
+        match guard.rooms.get(room) {
+            Some(transform) => transform.clone(),
+            None => guard.global.clone(),
+        }
+    }
+}
+
+/// Switch the [`OutputTransform`] of the current room at runtime.
+///
+/// The argument must be `on` to activate the command's transform in the room,
+/// `off` to disable transforming messages in the room, or `default` to use the
+/// global transform again.
+///
+/// Only operators should be able to use this command. Restrict it by wrapping
+/// it in a [`Restricted`](super::Restricted), usually via
+/// [`Restricted::allow_users`](super::Restricted::allow_users).
+pub struct OutputMode {
+    transforms: OutputTransforms,
+    transform: Arc<dyn OutputTransform>,
+}
+
+impl OutputMode {
+    /// Switch between `transform` and no transform.
+    ///
+    /// Pass a clone of
+    /// [`Commands::output_transforms`](crate::bot::commands::Commands::output_transforms)
+    /// as `transforms`.
+    pub fn new(transforms: OutputTransforms, transform: Arc<dyn OutputTransform>) -> Self {
+        Self {
+            transforms,
+            transform,
+        }
+    }
+
+    /// Switch between [`AsciiFallback`] and no transform.
+    pub fn ascii_fallback(transforms: OutputTransforms) -> Self {
+        Self::new(transforms, Arc::new(AsciiFallback::new()))
+    }
+}
+
+#[async_trait]
+impl<B, E> Command<B, E> for OutputMode
+where
+    B: Send,
+    E: From<conn::Error>,
+{
+    fn info(&self, _ctx: &Context) -> Info {
+        Info::new()
+            .with_description("Switch the output mode of this room.")
+            .with_example("on")
+            .with_example("off")
+            .with_example("default")
+    }
+
+    async fn execute(
+        &self,
+        arg: &str,
+        msg: &Message,
+        ctx: &Context,
+        _bot: &mut B,
+    ) -> Result<bool, E> {
+        let room = &ctx.config.room;
+        let reply = match arg.trim() {
+            "on" => {
+                self.transforms.set_room(room, Some(self.transform.clone()));
+                "Output mode switched on."
+            }
+            "off" => {
+                self.transforms.set_room(room, None);
+                "Output mode switched off."
+            }
+            "default" => {
+                self.transforms.reset_room(room);
+                "Output mode reset to the default."
+            }
+            _ => "Usage: on, off or default",
+        };
+        // The reply shouldn't be affected by the mode it announces
+        ctx.send_raw(Some(msg.id), reply).await?;
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use crate::api::{Message, UserId};
+    use crate::bot::command::test::context;
+    use crate::bot::command::{Command, Restricted};
+    use crate::conn::test::{connect, hello};
+    use crate::conn::{self, State};
+    use crate::test_util::{MessageBuilder, SessionViewBuilder};
+
+    use crate::text::Template;
+
+    use super::{
+        AsciiFallback, EmojiFallback, NoMentions, OutputMode, OutputTransform, OutputTransforms,
+    };
+
+    fn message(sender: &str) -> Message {
+        let sender = SessionViewBuilder::new("someone")
+            .with_id(UserId(sender.to_string()))
+            .build();
+        MessageBuilder::new("!output on")
+            .with_sender(sender)
+            .build()
+    }
+
+    #[test]
+    fn ascii_fallback() {
+        let fallback = AsciiFallback::new();
+        assert_eq!(fallback.transform("plain"), "plain");
+        assert_eq!(fallback.transform("nice 👍"), "nice :thumbsup:");
+        assert_eq!(fallback.transform("╔═══╗ box ╚═══╝"), " box ");
+
+        let fallback = fallback.with_emoji(EmojiFallback::Remove);
+        assert_eq!(fallback.transform("nice 👍 ✔️"), "nice  ");
+
+        let fallback = AsciiFallback::new()
+            .with_emoji(EmojiFallback::Keep)
+            .with_strip_only(['a'..='c']);
+        assert_eq!(fallback.transform("abcd 👍 ═"), "d 👍 ═");
+    }
+
+    #[test]
+    fn templates_and_transforms() {
+        const TEMPLATE: Template = Template::new("@{nick} 👍\u{200b} {text}");
+        let content = TEMPLATE
+            .fill()
+            .fill_nick("nick", "bob")
+            .fill_text("text", "hi @alice")
+            .render()
+            .unwrap();
+        let escaped = "@\u{200a}bob 👍\u{200b} hi @\u{200b}alice";
+        assert_eq!(content, escaped);
+
+        // Escaping again doesn't change anything
+        assert_eq!(NoMentions.transform(&content), escaped);
+
+        // Stripping spaces keeps the escapes but not the rest
+        let fallback = AsciiFallback::new().with_strip('\u{2000}'..='\u{200b}');
+        assert_eq!(
+            fallback.transform(&content),
+            "@\u{200a}bob :thumbsup: hi @\u{200b}alice"
+        );
+        let both = NoMentions
+            .transform(&fallback.transform(&content))
+            .into_owned();
+        assert_eq!(both, fallback.transform(&NoMentions.transform(&content)));
+
+        // Unescaped content is escaped by the transform
+        assert_eq!(NoMentions.transform("@bob"), "@\u{200b}bob");
+    }
+
+    #[test]
+    fn resolve() {
+        let transforms = OutputTransforms::new();
+        let upper: Arc<dyn OutputTransform> = Arc::new(|s: &str| s.to_uppercase());
+        let lower: Arc<dyn OutputTransform> = Arc::new(|s: &str| s.to_lowercase());
+        let apply = |room| {
+            let transform = transforms.resolve(room)?;
+            Some(transform.transform("Hi").into_owned())
+        };
+
+        assert_eq!(apply("a"), None);
+        transforms.set_global(Some(upper));
+        transforms.set_room("b", Some(lower));
+        transforms.set_room("c", None);
+        assert_eq!(apply("a").as_deref(), Some("HI"));
+        assert_eq!(apply("b").as_deref(), Some("hi"));
+        assert_eq!(apply("c"), None);
+
+        transforms.clone().reset_room("b");
+        assert_eq!(apply("b").as_deref(), Some("HI"));
+    }
+
+    #[tokio::test]
+    async fn applied_except_when_raw() {
+        let (mut conn, mut server) = connect(Duration::from_secs(10)).await;
+        server.join(hello(false, None)).await;
+        while let State::Joining(_) = conn.state() {
+            conn.recv().await.unwrap();
+        }
+
+        let mut ctx = context();
+        ctx.conn_tx = conn.tx().clone();
+        ctx.output = Some(Arc::new(AsciiFallback::new()));
+        let msg = message("agent:someone");
+
+        // Commands are queued right away, so the replies needn't be awaited
+        drop(ctx.send("send 👍"));
+        drop(ctx.reply(msg.id, "reply 👍"));
+        ctx.send_only("send only 👍").unwrap();
+        ctx.reply_only(msg.id, "reply only 👍").unwrap();
+        drop(ctx.send_raw(None, "raw 👍"));
+        drop(ctx.send_raw(Some(msg.id), "raw reply 👍"));
+        tokio::spawn(async move { while conn.recv().await.is_ok() {} });
+
+        let mut contents = vec![];
+        for _ in 0..6 {
+            let packet = server.recv().await.unwrap();
+            contents.push(packet["data"]["content"].as_str().unwrap().to_string());
+        }
+        assert_eq!(
+            contents,
+            [
+                "send :thumbsup:",
+                "reply :thumbsup:",
+                "send only :thumbsup:",
+                "reply only :thumbsup:",
+                "raw 👍",
+                "raw reply 👍",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn output_mode() {
+        let transforms = OutputTransforms::new();
+        let operator = UserId("agent:operator".to_string());
+        let command = Restricted::allow_users(
+            vec![operator],
+            OutputMode::ascii_fallback(transforms.clone()),
+        );
+        let ctx = context();
+
+        let execute = |arg: &'static str, msg: Message| {
+            let ctx = ctx.clone();
+            let command = &command;
+            async move {
+                let result: Result<bool, conn::Error> =
+                    command.execute(arg, &msg, &ctx, &mut ()).await;
+                result
+            }
+        };
+
+        let result = execute("on", message("agent:someone")).await;
+        assert!(matches!(result, Ok(false)));
+        assert!(transforms.resolve("test").is_none());
+
+        // The mode is switched before replying, which fails since the
+        // connection is closed
+        let result = execute("on", message("agent:operator")).await;
+        assert!(matches!(result, Err(conn::Error::ConnectionClosed)));
+        let transform = transforms.resolve("test").unwrap();
+        assert_eq!(transform.transform("👍"), ":thumbsup:");
+
+        transforms.set_global(Some(transform));
+        let _ = execute("off", message("agent:operator")).await;
+        assert!(transforms.resolve("test").is_none());
+        let _ = execute("default", message("agent:operator")).await;
+        assert!(transforms.resolve("test").is_some());
+    }
+}
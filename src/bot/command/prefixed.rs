@@ -2,7 +2,7 @@ use async_trait::async_trait;
 
 use crate::api::Message;
 
-use super::{Command, Context};
+use super::{Command, Context, Info};
 
 pub struct Prefixed<C> {
     prefix: String,
@@ -24,9 +24,10 @@ where
     B: Send,
     C: Command<B, E> + Send + Sync,
 {
-    fn description(&self, ctx: &Context) -> Option<String> {
-        let inner = self.inner.description(ctx)?;
-        Some(format!("{} - {inner}", self.prefix))
+    fn info(&self, ctx: &Context) -> Info {
+        self.inner
+            .info(ctx)
+            .merge(Info::new().with_trigger(&self.prefix))
     }
 
     async fn execute(
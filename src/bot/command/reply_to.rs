@@ -0,0 +1,302 @@
+use std::sync::Arc;
+
+use async_trait::async_trait;
+
+use crate::api::{self, Message, MessageId};
+use crate::conn;
+
+use super::{Command, Context};
+
+/// A command invoked by replying to a message of the bot, see [`ReplyTo`].
+#[async_trait]
+pub trait ReplyCommand<B, E> {
+    /// Handle `msg`, a reply to the bot's message `prompt`.
+    ///
+    /// Like [`Command::execute`], returns whether the message was handled.
+    async fn execute(
+        &self,
+        prompt: &Message,
+        msg: &Message,
+        ctx: &Context,
+        bot: &mut B,
+    ) -> Result<bool, E>;
+}
+
+type Predicate = Box<dyn Fn(&Message) -> bool + Send + Sync>;
+
+enum Prompts {
+    /// Any recent message of the bot matching the predicate, see
+    /// [`Conversations::own_message`](super::Conversations::own_message).
+    Own(Option<Predicate>),
+    /// Only prompts registered via
+    /// [`Conversations::add_prompt`](super::Conversations::add_prompt).
+    Registered,
+}
+
+/// Execute a command for replies to the bot's messages, regardless of their
+/// content.
+///
+/// This is useful for commands that are invoked by replying to the bot instead
+/// of using a prefix, for example answering a confirmation prompt with "yes".
+/// All other messages are propagated, i.e. the command returns `false` for
+/// them.
+///
+/// Which messages of the bot count as prompts depends on the constructor. The
+/// bot's messages are looked up in [`Context::conversations`], the same
+/// registry that [`Context::ask`] uses. Answers to questions asked via
+/// [`Context::ask`] are delivered there first, so they only reach this command
+/// if [`Commands::propagate_answers`](crate::bot::commands::Commands::propagate_answers)
+/// is set.
+///
+/// By default, only direct replies to a prompt trigger the command. With a
+/// [`Self::with_max_depth`] greater than one, replies further down the thread do as
+/// well, which requires fetching the message's ancestors from the server one
+/// by one.
+///
+/// The command has no [`Info`](super::Info) and thus doesn't show up in help
+/// messages.
+pub struct ReplyTo<C> {
+    inner: C,
+    prompts: Prompts,
+    max_depth: usize,
+}
+
+impl<C> ReplyTo<C> {
+    /// React to replies to any recent message of the bot.
+    pub fn new(inner: C) -> Self {
+        Self {
+            inner,
+            prompts: Prompts::Own(None),
+            max_depth: 1,
+        }
+    }
+
+    /// React to replies to recent messages of the bot matching a predicate.
+    pub fn matching<F>(inner: C, predicate: F) -> Self
+    where
+        F: Fn(&Message) -> bool + Send + Sync + 'static,
+    {
+        Self {
+            inner,
+            prompts: Prompts::Own(Some(Box::new(predicate))),
+            max_depth: 1,
+        }
+    }
+
+    /// React only to replies to prompts registered via
+    /// [`Conversations::add_prompt`](super::Conversations::add_prompt).
+    pub fn prompts(inner: C) -> Self {
+        Self {
+            inner,
+            prompts: Prompts::Registered,
+            max_depth: 1,
+        }
+    }
+
+    /// How far below a prompt a reply may be (default: 1).
+    ///
+    /// A depth of one only allows direct replies to the prompt, a depth of two
+    /// also allows replies to those replies, and so on. A depth of zero
+    /// disables the command.
+    pub fn with_max_depth(mut self, max_depth: usize) -> Self {
+        self.max_depth = max_depth;
+        self
+    }
+
+    fn prompt(&self, ctx: &Context, id: MessageId) -> Option<Arc<Message>> {
+        match &self.prompts {
+            Prompts::Own(predicate) => {
+                let msg = ctx.conversations.own_message(id)?;
+                match predicate {
+                    Some(predicate) if !predicate(&msg) => None,
+                    _ => Some(msg),
+                }
+            }
+            Prompts::Registered => ctx.conversations.prompt(id),
+        }
+    }
+
+    /// Find the prompt `msg` is a reply to, if any.
+    async fn find_prompt(
+        &self,
+        msg: &Message,
+        ctx: &Context,
+    ) -> conn::Result<Option<Arc<Message>>> {
+        let mut next = msg.parent;
+        for depth in 1..=self.max_depth {
+            let id = match next {
+                Some(id) => id,
+                None => break,
+            };
+            if let Some(prompt) = self.prompt(ctx, id) {
+                return Ok(Some(prompt));
+            }
+            if depth < self.max_depth {
+                ctx.checkpoint()?;
+                next = ctx.conn_tx.send(api::GetMessage { id }).await?.0.parent;
+            }
+        }
+        Ok(None)
+    }
+}
+
+#[async_trait]
+impl<B, E, C> Command<B, E> for ReplyTo<C>
+where
+    B: Send,
+    E: From<conn::Error>,
+    C: ReplyCommand<B, E> + Send + Sync,
+{
+    async fn execute(
+        &self,
+        _arg: &str,
+        msg: &Message,
+        ctx: &Context,
+        bot: &mut B,
+    ) -> Result<bool, E> {
+        match self.find_prompt(msg, ctx).await? {
+            Some(prompt) => self.inner.execute(&prompt, msg, ctx, bot).await,
+            None => Ok(false),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use async_trait::async_trait;
+
+    use crate::api::{Message, MessageId, Snowflake};
+    use crate::bot::command::test::context;
+    use crate::bot::command::{Command, Context};
+    use crate::conn::test::{connect, hello};
+    use crate::conn::{self, State};
+    use crate::test_util::MessageBuilder;
+
+    use super::{ReplyCommand, ReplyTo};
+
+    /// Records the prompts it was invoked for.
+    struct Record;
+
+    #[async_trait]
+    impl ReplyCommand<Vec<String>, conn::Error> for Record {
+        async fn execute(
+            &self,
+            prompt: &Message,
+            msg: &Message,
+            _ctx: &Context,
+            bot: &mut Vec<String>,
+        ) -> Result<bool, conn::Error> {
+            bot.push(format!("{} <- {}", prompt.content, msg.content));
+            Ok(true)
+        }
+    }
+
+    fn msg(id: u64, parent: Option<u64>, content: &str) -> Message {
+        MessageBuilder::new(content)
+            .with_id(MessageId(Snowflake(id)))
+            .with_parent_opt(parent.map(|p| MessageId(Snowflake(p))))
+            .build()
+    }
+
+    async fn run(cmd: &ReplyTo<Record>, msg: &Message, ctx: &Context) -> Vec<String> {
+        let mut bot = vec![];
+        let handled = cmd.execute("", msg, ctx, &mut bot).await.unwrap();
+        assert_eq!(handled, !bot.is_empty());
+        bot
+    }
+
+    #[tokio::test]
+    async fn own_messages() {
+        let ctx = context();
+        ctx.conversations
+            .remember_own(Arc::new(msg(1, None, "Are you sure?")));
+        ctx.conversations.remember_own(Arc::new(msg(2, None, "Hi")));
+
+        let cmd = ReplyTo::new(Record);
+        assert_eq!(
+            run(&cmd, &msg(10, Some(1), "yes"), &ctx).await,
+            ["Are you sure? <- yes"]
+        );
+        assert_eq!(run(&cmd, &msg(11, Some(2), "hi"), &ctx).await, ["Hi <- hi"]);
+        // Unrelated parents and top-level messages propagate
+        assert!(run(&cmd, &msg(12, Some(5), "yes"), &ctx).await.is_empty());
+        assert!(run(&cmd, &msg(13, None, "yes"), &ctx).await.is_empty());
+
+        let cmd = ReplyTo::matching(Record, |m| m.content.ends_with('?'));
+        assert_eq!(run(&cmd, &msg(14, Some(1), "no"), &ctx).await.len(), 1);
+        assert!(run(&cmd, &msg(15, Some(2), "no"), &ctx).await.is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn registered_prompts() {
+        let ctx = context();
+        let prompt = Arc::new(msg(1, None, "Really?"));
+        ctx.conversations.remember_own(prompt.clone());
+        ctx.conversations
+            .remember_own(Arc::new(msg(2, None, "Not a prompt")));
+        ctx.conversations
+            .add_prompt(prompt.clone(), Duration::from_secs(60));
+
+        let cmd = ReplyTo::prompts(Record);
+        assert_eq!(run(&cmd, &msg(10, Some(1), "yes"), &ctx).await.len(), 1);
+        assert!(run(&cmd, &msg(11, Some(2), "yes"), &ctx).await.is_empty());
+
+        // Prompts expire
+        tokio::time::advance(Duration::from_secs(61)).await;
+        assert!(run(&cmd, &msg(12, Some(1), "yes"), &ctx).await.is_empty());
+        assert!(!ctx.conversations.remove_prompt(prompt.id));
+
+        ctx.conversations
+            .add_prompt(prompt.clone(), Duration::from_secs(60));
+        assert!(ctx.conversations.remove_prompt(prompt.id));
+        assert!(run(&cmd, &msg(13, Some(1), "yes"), &ctx).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn thread_descendants() {
+        let (mut conn, mut server) = connect(Duration::from_secs(10)).await;
+        server.join(hello(false, None)).await;
+        while let State::Joining(_) = conn.state() {
+            conn.recv().await.unwrap();
+        }
+        let mut ctx = context();
+        ctx.conn_tx = conn.tx().clone();
+        tokio::spawn(async move { while conn.recv().await.is_ok() {} });
+
+        // Message 3 replies to 2, which replies to the prompt 1
+        tokio::spawn(async move {
+            while let Some(packet) = server.recv().await {
+                assert_eq!(packet["type"], "get-message");
+                assert_eq!(packet["data"]["id"], "0000000000002");
+                let reply = serde_json::json!({
+                    "id": packet["id"],
+                    "type": "get-message-reply",
+                    "data": {
+                        "id": "0000000000002",
+                        "parent": "0000000000001",
+                        "time": 0,
+                        "sender": hello(false, None).session,
+                        "content": "what?",
+                    },
+                });
+                server.send(reply).await;
+            }
+        });
+
+        ctx.conversations
+            .remember_own(Arc::new(msg(1, None, "Sure?")));
+        let grandchild = msg(3, Some(2), "yes");
+
+        let cmd = ReplyTo::new(Record);
+        assert!(run(&cmd, &grandchild, &ctx).await.is_empty());
+
+        let cmd = ReplyTo::new(Record).with_max_depth(2);
+        assert_eq!(run(&cmd, &grandchild, &ctx).await, ["Sure? <- yes"]);
+
+        let cmd = ReplyTo::new(Record).with_max_depth(0);
+        assert!(run(&cmd, &msg(4, Some(1), "yes"), &ctx).await.is_empty());
+    }
+}
@@ -0,0 +1,213 @@
+use std::collections::HashSet;
+
+use async_trait::async_trait;
+
+use crate::api::{Message, SessionView, UserId};
+use crate::conn;
+
+use super::{Command, Context, Info};
+
+enum Audience {
+    Managers,
+    Staff,
+    Users(HashSet<UserId>),
+}
+
+/// Only run the inner command if its sender has certain permissions.
+///
+/// Room managers (hosts) and euphoria staff are identified by different flags
+/// on the sender's [`SessionView`]. A manager isn't necessarily staff and vice
+/// versa, so [`Self::managers_only`] and [`Self::staff_only`] check only their
+/// respective flag.
+///
+/// If the sender isn't allowed to use the command, it is skipped, or, if a
+/// refusal is configured, answered with the refusal.
+pub struct Restricted<C> {
+    audience: Audience,
+    refusal: Option<String>,
+    inner: C,
+}
+
+impl<C> Restricted<C> {
+    fn new(audience: Audience, inner: C) -> Self {
+        Self {
+            audience,
+            refusal: None,
+            inner,
+        }
+    }
+
+    /// Only allow senders that are managers of the room.
+    pub fn managers_only(inner: C) -> Self {
+        Self::new(Audience::Managers, inner)
+    }
+
+    /// Only allow senders that are euphoria staff.
+    pub fn staff_only(inner: C) -> Self {
+        Self::new(Audience::Staff, inner)
+    }
+
+    /// Only allow senders with one of these user ids.
+    pub fn allow_users(users: Vec<UserId>, inner: C) -> Self {
+        Self::new(Audience::Users(users.into_iter().collect()), inner)
+    }
+
+    /// Reply with this message instead of silently skipping the command when
+    /// the sender isn't allowed to use it.
+    pub fn with_refusal<S: ToString>(self, refusal: S) -> Self {
+        self.with_refusal_opt(Some(refusal))
+    }
+
+    pub fn with_refusal_opt<S: ToString>(mut self, refusal: Option<S>) -> Self {
+        self.refusal = refusal.map(|s| s.to_string());
+        self
+    }
+
+    pub fn allows(&self, sender: &SessionView) -> bool {
+        match &self.audience {
+            Audience::Managers => sender.is_manager,
+            Audience::Staff => sender.is_staff,
+            Audience::Users(users) => users.contains(&sender.id),
+        }
+    }
+
+    fn restriction(&self) -> &'static str {
+        match self.audience {
+            Audience::Managers => "managers only",
+            Audience::Staff => "staff only",
+            Audience::Users(_) => "only for certain users",
+        }
+    }
+}
+
+#[async_trait]
+impl<B, E, C> Command<B, E> for Restricted<C>
+where
+    B: Send,
+    E: From<conn::Error>,
+    C: Command<B, E> + Send + Sync,
+{
+    fn info(&self, ctx: &Context) -> Info {
+        let mut info = self.inner.info(ctx);
+        if let Some(description) = &mut info.description {
+            description.push_str(&format!(" ({})", self.restriction()));
+        }
+        info
+    }
+
+    async fn execute(
+        &self,
+        arg: &str,
+        msg: &Message,
+        ctx: &Context,
+        bot: &mut B,
+    ) -> Result<bool, E> {
+        if self.allows(&msg.sender) {
+            return self.inner.execute(arg, msg, ctx, bot).await;
+        }
+
+        if let Some(refusal) = &self.refusal {
+            ctx.reply(msg.id, refusal).await?;
+            Ok(true)
+        } else {
+            Ok(false)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use async_trait::async_trait;
+
+    use crate::api::{Message, SessionView, UserId};
+    use crate::bot::command::test::context;
+    use crate::bot::command::{Command, Context, Described, Info};
+    use crate::conn;
+    use crate::test_util::{MessageBuilder, SessionViewBuilder};
+
+    use super::Restricted;
+
+    fn sender(id: &str, is_staff: bool, is_manager: bool) -> SessionView {
+        SessionViewBuilder::new("someone")
+            .with_id(UserId(id.to_string()))
+            .with_staff(is_staff)
+            .with_manager(is_manager)
+            .build()
+    }
+
+    fn message(sender: SessionView) -> Message {
+        MessageBuilder::new("!admin").with_sender(sender).build()
+    }
+
+    struct Admin;
+
+    #[async_trait]
+    impl Command<(), conn::Error> for Admin {
+        fn info(&self, _ctx: &Context) -> Info {
+            Info::new()
+        }
+
+        async fn execute(
+            &self,
+            _arg: &str,
+            _msg: &Message,
+            _ctx: &Context,
+            _bot: &mut (),
+        ) -> Result<bool, conn::Error> {
+            Ok(true)
+        }
+    }
+
+    #[test]
+    fn allows() {
+        let managers = Restricted::managers_only(());
+        assert!(managers.allows(&sender("agent:a", false, true)));
+        assert!(!managers.allows(&sender("agent:a", true, false)));
+
+        let staff = Restricted::staff_only(());
+        assert!(staff.allows(&sender("agent:a", true, false)));
+        assert!(!staff.allows(&sender("agent:a", false, true)));
+
+        let users = Restricted::allow_users(vec![UserId("account:op".to_string())], ());
+        assert!(users.allows(&sender("account:op", false, false)));
+        assert!(!users.allows(&sender("agent:a", true, true)));
+    }
+
+    #[test]
+    fn info() {
+        let ctx = context();
+        let cmd =
+            Restricted::managers_only(Described::new(Admin).with_description("Do admin things."));
+        let info = Command::<(), conn::Error>::info(&cmd, &ctx);
+        assert_eq!(
+            info.description.as_deref(),
+            Some("Do admin things. (managers only)")
+        );
+
+        // Commands without description stay without description
+        let cmd = Restricted::staff_only(Admin);
+        let info = Command::<(), conn::Error>::info(&cmd, &ctx);
+        assert_eq!(info.description, None);
+    }
+
+    #[tokio::test]
+    async fn execute() {
+        let ctx = context();
+        let cmd = Restricted::managers_only(Admin);
+        let result = cmd
+            .execute("", &message(sender("agent:a", false, false)), &ctx, &mut ())
+            .await;
+        assert!(matches!(result, Ok(false)));
+        let result = cmd
+            .execute("", &message(sender("agent:a", false, true)), &ctx, &mut ())
+            .await;
+        assert!(matches!(result, Ok(true)));
+
+        // The refusal is sent, but the connection is closed
+        let cmd = Restricted::managers_only(Admin).with_refusal("Permission denied.");
+        let result = cmd
+            .execute("", &message(sender("agent:a", false, false)), &ctx, &mut ())
+            .await;
+        assert!(matches!(result, Err(conn::Error::ConnectionClosed)));
+    }
+}
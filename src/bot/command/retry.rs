@@ -0,0 +1,27 @@
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Where [`Context::retry_after`](super::Context::retry_after) records that the
+/// current message should be handled again later.
+///
+/// Clones share the same request.
+#[derive(Debug, Clone, Default)]
+pub struct RetryRequest(Arc<Mutex<Option<Duration>>>);
+
+impl RetryRequest {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Request that the message be handled again after `delay`.
+    ///
+    /// If this is called multiple times, the last delay wins.
+    pub fn request(&self, delay: Duration) {
+        *self.0.lock().unwrap() = Some(delay);
+    }
+
+    /// The requested delay, if any.
+    pub fn requested(&self) -> Option<Duration> {
+        *self.0.lock().unwrap()
+    }
+}
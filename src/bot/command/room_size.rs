@@ -0,0 +1,203 @@
+use async_trait::async_trait;
+
+use crate::api::Message;
+use crate::conn::{self, Joined};
+
+use super::{Command, Context, Info};
+
+/// Only run the inner command if the room's size is within certain limits.
+///
+/// The room's size includes the bot's own session. If the room is too small or
+/// too large, the command is skipped, or, if a refusal is configured, answered
+/// with the refusal.
+pub struct RoomSizeGate<C> {
+    min: Option<usize>,
+    max: Option<usize>,
+    humans_only: bool,
+    refusal: Option<String>,
+    inner: C,
+}
+
+impl<C> RoomSizeGate<C> {
+    pub fn new(inner: C) -> Self {
+        Self {
+            min: None,
+            max: None,
+            humans_only: false,
+            refusal: None,
+            inner,
+        }
+    }
+
+    /// Only run the command in rooms with at least this many sessions.
+    pub fn with_min(mut self, min: usize) -> Self {
+        self.min = Some(min);
+        self
+    }
+
+    /// Only run the command in rooms with at most this many sessions.
+    pub fn with_max(mut self, max: usize) -> Self {
+        self.max = Some(max);
+        self
+    }
+
+    /// Whether only sessions that don't belong to bots should be counted.
+    ///
+    /// See [`Joined::count_humans`] for more details.
+    pub fn with_humans_only(mut self, humans_only: bool) -> Self {
+        self.humans_only = humans_only;
+        self
+    }
+
+    /// Reply with this message instead of silently skipping the command when
+    /// the room's size is outside the limits.
+    pub fn with_refusal<S: ToString>(self, refusal: S) -> Self {
+        self.with_refusal_opt(Some(refusal))
+    }
+
+    pub fn with_refusal_opt<S: ToString>(mut self, refusal: Option<S>) -> Self {
+        self.refusal = refusal.map(|s| s.to_string());
+        self
+    }
+
+    fn count(&self, joined: &Joined) -> usize {
+        if self.humans_only {
+            joined.count_humans()
+        } else {
+            joined.count_sessions()
+        }
+    }
+
+    fn allows(&self, joined: &Joined) -> bool {
+        let count = self.count(joined);
+        self.min.is_none_or(|min| count >= min) && self.max.is_none_or(|max| count <= max)
+    }
+
+    fn restriction(&self) -> Option<String> {
+        let people = if self.humans_only { "humans" } else { "people" };
+        match (self.min, self.max) {
+            (None, None) => None,
+            (Some(min), None) => Some(format!("at least {min} {people}")),
+            (None, Some(max)) => Some(format!("at most {max} {people}")),
+            (Some(min), Some(max)) => Some(format!("{min} to {max} {people}")),
+        }
+    }
+}
+
+#[async_trait]
+impl<B, E, C> Command<B, E> for RoomSizeGate<C>
+where
+    B: Send,
+    E: From<conn::Error>,
+    C: Command<B, E> + Send + Sync,
+{
+    fn info(&self, ctx: &Context) -> Info {
+        let mut info = self.inner.info(ctx);
+        if let (Some(description), Some(restriction)) = (&mut info.description, self.restriction())
+        {
+            description.push_str(&format!(" (only in rooms with {restriction})"));
+        }
+        info
+    }
+
+    async fn execute(
+        &self,
+        arg: &str,
+        msg: &Message,
+        ctx: &Context,
+        bot: &mut B,
+    ) -> Result<bool, E> {
+        if self.allows(&ctx.joined) {
+            return self.inner.execute(arg, msg, ctx, bot).await;
+        }
+
+        if let Some(refusal) = &self.refusal {
+            ctx.reply(msg.id, refusal).await?;
+            Ok(true)
+        } else {
+            Ok(false)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::api::{SessionView, UserId};
+    use crate::bot::command::CommandExt;
+    use crate::conn::{Joined, SessionInfo};
+    use crate::test_util::{JoinedBuilder, SessionViewBuilder};
+
+    use super::RoomSizeGate;
+
+    fn session(id: &str, session_id: &str) -> SessionView {
+        SessionViewBuilder::new(session_id)
+            .with_id(UserId(id.to_string()))
+            .build()
+    }
+
+    /// A room containing our own bot session and the given amount of other
+    /// human and bot sessions.
+    fn joined(humans: usize, bots: usize) -> Joined {
+        let mut joined = JoinedBuilder::new().with_session(session("bot:me", "me"));
+        for i in 0..humans {
+            let s = session(&format!("agent:{i}"), &format!("human{i}"));
+            joined = joined.with_listing_entry(SessionInfo::Full(s));
+        }
+        for i in 0..bots {
+            let s = session(&format!("bot:{i}"), &format!("bot{i}"));
+            joined = joined.with_listing_entry(SessionInfo::Full(s));
+        }
+        joined.build()
+    }
+
+    #[test]
+    fn counting() {
+        let joined = joined(3, 2);
+        assert_eq!(joined.count_sessions(), 6);
+        assert_eq!(joined.count_humans(), 3);
+        assert_eq!(joined.count_bots(), 3);
+    }
+
+    #[test]
+    fn max_room_size() {
+        let gate = ().max_room_size(5);
+        assert!(gate.allows(&joined(0, 0)));
+        assert!(gate.allows(&joined(2, 2)));
+        assert!(!gate.allows(&joined(3, 2)));
+        assert!(!gate.allows(&joined(0, 100)));
+    }
+
+    #[test]
+    fn min_room_size() {
+        let gate = ().min_room_size(3);
+        assert!(!gate.allows(&joined(0, 0)));
+        assert!(!gate.allows(&joined(1, 0)));
+        assert!(gate.allows(&joined(1, 1)));
+        assert!(gate.allows(&joined(100, 0)));
+    }
+
+    #[test]
+    fn humans_only() {
+        let gate = RoomSizeGate::new(()).with_max(5).with_humans_only(true);
+        assert!(gate.allows(&joined(5, 0)));
+        assert!(gate.allows(&joined(5, 100)));
+        assert!(!gate.allows(&joined(6, 0)));
+
+        let gate = RoomSizeGate::new(()).with_min(2).with_humans_only(true);
+        assert!(!gate.allows(&joined(1, 10)));
+        assert!(gate.allows(&joined(2, 0)));
+    }
+
+    #[test]
+    fn restriction() {
+        let gate = RoomSizeGate::new(());
+        assert_eq!(gate.restriction(), None);
+        let gate = RoomSizeGate::new(()).with_max(20);
+        assert_eq!(gate.restriction().as_deref(), Some("at most 20 people"));
+        let gate = RoomSizeGate::new(())
+            .with_min(2)
+            .with_max(20)
+            .with_humans_only(true);
+        assert_eq!(gate.restriction().as_deref(), Some("2 to 20 humans"));
+    }
+}
@@ -0,0 +1,497 @@
+use std::collections::HashSet;
+use std::fmt;
+use std::future::Future;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use tokio::time::Instant;
+
+use crate::api::{self, GetMessage, Message, MessageId, SessionId, Who};
+use crate::conn::{self, ConnTx};
+use crate::text::{Chaining, MessagePlan};
+
+use super::{Command, Context, Info};
+
+/// A step of [`run_self_test`], in the order they are performed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SelfTestStep {
+    /// Send a message and check the server's [`SendReply`](api::SendReply).
+    Send,
+    /// Reply to the sent message.
+    Reply,
+    /// Fetch the sent message via [`GetMessage`] and compare its content.
+    GetMessage,
+    /// Check that the bot's own session is listed by [`Who`].
+    Who,
+}
+
+impl fmt::Display for SelfTestStep {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Send => write!(f, "send"),
+            Self::Reply => write!(f, "reply"),
+            Self::GetMessage => write!(f, "get-message"),
+            Self::Who => write!(f, "who"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StepOutcome {
+    Passed,
+    Failed(String),
+    /// The step depends on a previous step that failed.
+    Skipped,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StepReport {
+    pub step: SelfTestStep,
+    pub outcome: StepOutcome,
+    /// How long the step took, or `None` if it was skipped.
+    pub latency: Option<Duration>,
+}
+
+/// The result of [`run_self_test`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SelfTestReport {
+    pub steps: Vec<StepReport>,
+}
+
+impl SelfTestReport {
+    pub fn passed(&self) -> bool {
+        self.steps.iter().all(|s| s.outcome == StepOutcome::Passed)
+    }
+
+    /// Perform a step and record its outcome and latency.
+    ///
+    /// Returns the step's result if it passed.
+    async fn step<T, F>(&mut self, step: SelfTestStep, f: F) -> Option<T>
+    where
+        F: Future<Output = Result<T, String>>,
+    {
+        let start = Instant::now();
+        let result = f.await;
+        let latency = Some(start.elapsed());
+        let (outcome, result) = match result {
+            Ok(result) => (StepOutcome::Passed, Some(result)),
+            Err(reason) => (StepOutcome::Failed(reason), None),
+        };
+        self.steps.push(StepReport {
+            step,
+            outcome,
+            latency,
+        });
+        result
+    }
+
+    fn skip(&mut self, step: SelfTestStep) {
+        self.steps.push(StepReport {
+            step,
+            outcome: StepOutcome::Skipped,
+            latency: None,
+        });
+    }
+}
+
+impl fmt::Display for SelfTestReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let passed = self
+            .steps
+            .iter()
+            .filter(|s| s.outcome == StepOutcome::Passed)
+            .count();
+        let verdict = if self.passed() { "passed" } else { "failed" };
+        write!(
+            f,
+            "self-test {verdict} ({passed}/{} steps)",
+            self.steps.len()
+        )?;
+
+        for report in &self.steps {
+            let step = report.step;
+            let ms = report.latency.unwrap_or_default().as_millis();
+            match &report.outcome {
+                StepOutcome::Passed => write!(f, "\n{step}: ok ({ms}ms)")?,
+                StepOutcome::Failed(reason) => {
+                    write!(f, "\n{step}: FAILED after {ms}ms: {reason}")?
+                }
+                StepOutcome::Skipped => write!(f, "\n{step}: skipped")?,
+            }
+        }
+        Ok(())
+    }
+}
+
+fn check(ok: bool, reason: impl FnOnce() -> String) -> Result<(), String> {
+    if ok {
+        Ok(())
+    } else {
+        Err(reason())
+    }
+}
+
+/// Exercise the connection like a bot would, sending messages to the room.
+///
+/// The messages are sent as replies to `parent`, or as top-level messages if
+/// it is `None`. `own` is the session id of the connection's session, which is
+/// expected to show up in the [`Who`] listing.
+///
+/// Every [`SelfTestStep`] is performed in order. A failing step doesn't abort
+/// the test. Only the steps depending on the message sent in the first step
+/// are skipped if it could not be sent.
+pub async fn run_self_test(
+    conn_tx: &ConnTx,
+    own: &SessionId,
+    parent: Option<MessageId>,
+) -> SelfTestReport {
+    let mut report = SelfTestReport::default();
+    let content = "self-test: sending a message".to_string();
+
+    let sent = report
+        .step(SelfTestStep::Send, async {
+            let cmd = api::Send {
+                content: content.clone(),
+                parent,
+            };
+            let msg = conn_tx.send(cmd).await.map_err(|e| e.to_string())?.0;
+            check(msg.content == content, || {
+                format!("server changed content to {:?}", msg.content)
+            })?;
+            check(msg.parent == parent, || "server changed parent".to_string())?;
+            Ok(msg)
+        })
+        .await;
+
+    match &sent {
+        Some(sent) => {
+            report
+                .step(SelfTestStep::Reply, async {
+                    let cmd = api::Send {
+                        content: "self-test: replying to it".to_string(),
+                        parent: Some(sent.id),
+                    };
+                    let msg = conn_tx.send(cmd).await.map_err(|e| e.to_string())?.0;
+                    check(msg.parent == Some(sent.id), || {
+                        "reply has wrong parent".to_string()
+                    })
+                })
+                .await;
+
+            report
+                .step(SelfTestStep::GetMessage, async {
+                    let cmd = GetMessage { id: sent.id };
+                    let msg = conn_tx.send(cmd).await.map_err(|e| e.to_string())?.0;
+                    check(msg.id == sent.id, || "got wrong message".to_string())?;
+                    check(msg.content == sent.content, || {
+                        format!("content differs: {:?}", msg.content)
+                    })
+                })
+                .await;
+        }
+        None => {
+            report.skip(SelfTestStep::Reply);
+            report.skip(SelfTestStep::GetMessage);
+        }
+    }
+
+    report
+        .step(SelfTestStep::Who, async {
+            let listing = conn_tx
+                .send(Who {})
+                .await
+                .map_err(|e| e.to_string())?
+                .listing;
+            check(listing.iter().any(|s| s.session_id == *own), || {
+                format!("own session missing from {} sessions", listing.len())
+            })
+        })
+        .await;
+
+    report
+}
+
+/// Run [`run_self_test`] and reply with the report.
+///
+/// The command only works in the configured test rooms. Invocations in any
+/// other room are ignored, i.e. the command returns `false` without replying.
+/// The test's messages are sent as replies to the invoking message.
+///
+/// Since the test spams the room, only operators should be able to use this
+/// command. Restrict it by wrapping it in a [`Restricted`](super::Restricted),
+/// usually via [`Restricted::allow_users`](super::Restricted::allow_users).
+pub struct SelfTest {
+    rooms: HashSet<String>,
+    plan: MessagePlan,
+}
+
+impl SelfTest {
+    pub fn new<R, S>(rooms: R) -> Self
+    where
+        R: IntoIterator<Item = S>,
+        S: ToString,
+    {
+        Self {
+            rooms: rooms.into_iter().map(|r| r.to_string()).collect(),
+            plan: MessagePlan::new(),
+        }
+    }
+
+    /// How to split the report into multiple messages if it is too long.
+    pub fn with_plan(mut self, plan: MessagePlan) -> Self {
+        self.plan = plan;
+        self
+    }
+
+    pub fn is_test_room(&self, room: &str) -> bool {
+        self.rooms.contains(room)
+    }
+}
+
+#[async_trait]
+impl<B, E> Command<B, E> for SelfTest
+where
+    B: Send,
+    E: From<conn::Error>,
+{
+    fn info(&self, _ctx: &Context) -> Info {
+        Info::new().with_description("Test sending and fetching messages.")
+    }
+
+    async fn execute(
+        &self,
+        _arg: &str,
+        msg: &Message,
+        ctx: &Context,
+        _bot: &mut B,
+    ) -> Result<bool, E> {
+        if !self.is_test_room(&ctx.config.room) {
+            return Ok(false);
+        }
+
+        let own = &ctx.joined.session.session_id;
+        let report = run_self_test(&ctx.conn_tx, own, Some(msg.id)).await;
+        ctx.send_plan(Some(msg.id), &self.plan, report, Chaining::Siblings)
+            .await?;
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use crate::api::{Message, MessageId, SessionId, UserId};
+    use crate::bot::command::test::context;
+    use crate::bot::command::{Command, Restricted};
+    use crate::conn::test::{connect, hello, Server};
+    use crate::conn::{self, State};
+    use crate::test_util::{MessageBuilder, SessionViewBuilder};
+
+    use super::{run_self_test, SelfTest, SelfTestReport, SelfTestStep, StepOutcome, StepReport};
+
+    #[derive(Clone, Copy, PartialEq, Eq)]
+    enum Failure {
+        None,
+        Send,
+        Content,
+        Who,
+    }
+
+    fn message(id: &str, parent: Option<&str>, content: &str) -> Message {
+        let parse = |id: &str| MessageId(id.parse().unwrap());
+        let sender = SessionViewBuilder::new("TestBot")
+            .with_id(UserId("agent:abc".to_string()))
+            .build();
+        MessageBuilder::new(content)
+            .with_id(parse(id))
+            .with_parent_opt(parent.map(parse))
+            .with_sender(sender)
+            .build()
+    }
+
+    /// Answer the commands of a self-test, failing in the specified way.
+    async fn serve(mut server: Server, failure: Failure) {
+        let mut log = vec![];
+        while let Some(packet) = server.recv().await {
+            let data = &packet["data"];
+            let mut reply = match packet["type"].as_str().unwrap() {
+                "send" if failure == Failure::Send => {
+                    serde_json::json!({ "type": "send-reply", "error": "room is read-only" })
+                }
+                "send" => {
+                    let id = format!("{:013}", 10 + log.len());
+                    let parent = data["parent"].as_str();
+                    let msg = message(&id, parent, data["content"].as_str().unwrap());
+                    log.push(msg.clone());
+                    serde_json::json!({ "type": "send-reply", "data": msg })
+                }
+                "get-message" => {
+                    let msg = log.iter().find(|m| serde_json::json!(m.id) == data["id"]);
+                    let mut msg = msg.unwrap().clone();
+                    if failure == Failure::Content {
+                        msg.content = "something else".to_string();
+                    }
+                    serde_json::json!({ "type": "get-message-reply", "data": msg })
+                }
+                "who" => {
+                    let mut listing = vec![hello(false, None).session];
+                    if failure == Failure::Who {
+                        listing.clear();
+                    }
+                    serde_json::json!({ "type": "who-reply", "data": { "listing": listing } })
+                }
+                _ => continue,
+            };
+            reply["id"] = packet["id"].clone();
+            server.send(reply).await;
+        }
+    }
+
+    async fn self_test(failure: Failure) -> SelfTestReport {
+        let (mut conn, mut server) = connect(Duration::from_secs(10)).await;
+        server.join(hello(false, None)).await;
+        while let State::Joining(_) = conn.state() {
+            conn.recv().await.unwrap();
+        }
+        let tx = conn.tx().clone();
+        tokio::spawn(async move { while conn.recv().await.is_ok() {} });
+        tokio::spawn(serve(server, failure));
+
+        let own = SessionId("session".to_string());
+        run_self_test(&tx, &own, None).await
+    }
+
+    fn outcomes(report: &SelfTestReport) -> Vec<(SelfTestStep, StepOutcome)> {
+        report
+            .steps
+            .iter()
+            .map(|s| (s.step, s.outcome.clone()))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn operators_and_test_rooms_only() {
+        let ctx = context();
+        let mut msg = message("0000000000001", None, "!selftest");
+        let operator = UserId("agent:operator".to_string());
+
+        let execute = |command: Restricted<SelfTest>, msg: Message| {
+            let ctx = ctx.clone();
+            async move {
+                let result: Result<bool, conn::Error> =
+                    command.execute("", &msg, &ctx, &mut ()).await;
+                result
+            }
+        };
+
+        let restricted =
+            |rooms| Restricted::allow_users(vec![operator.clone()], SelfTest::new(rooms));
+        assert!(matches!(
+            execute(restricted(["test"]), msg.clone()).await,
+            Ok(false)
+        ));
+
+        msg.sender.id = operator.clone();
+        assert!(matches!(
+            execute(restricted(["other"]), msg.clone()).await,
+            Ok(false)
+        ));
+
+        // Operators get past the checks in test rooms, but the connection is
+        // closed, so replying with the report fails
+        let command = restricted(["test"]);
+        let result = execute(command, msg).await;
+        assert!(matches!(result, Err(conn::Error::ConnectionClosed)));
+    }
+
+    #[tokio::test]
+    async fn passing() {
+        let report = self_test(Failure::None).await;
+        assert!(report.passed());
+        assert_eq!(
+            outcomes(&report),
+            [
+                (SelfTestStep::Send, StepOutcome::Passed),
+                (SelfTestStep::Reply, StepOutcome::Passed),
+                (SelfTestStep::GetMessage, StepOutcome::Passed),
+                (SelfTestStep::Who, StepOutcome::Passed),
+            ]
+        );
+        assert!(report.steps.iter().all(|s| s.latency.is_some()));
+    }
+
+    #[tokio::test]
+    async fn injected_failures() {
+        let report = self_test(Failure::Send).await;
+        assert!(!report.passed());
+        assert_eq!(
+            outcomes(&report),
+            [
+                (
+                    SelfTestStep::Send,
+                    StepOutcome::Failed("room is read-only".to_string())
+                ),
+                (SelfTestStep::Reply, StepOutcome::Skipped),
+                (SelfTestStep::GetMessage, StepOutcome::Skipped),
+                (SelfTestStep::Who, StepOutcome::Passed),
+            ]
+        );
+
+        let report = self_test(Failure::Content).await;
+        assert_eq!(
+            outcomes(&report)[2],
+            (
+                SelfTestStep::GetMessage,
+                StepOutcome::Failed("content differs: \"something else\"".to_string())
+            )
+        );
+        assert_eq!(outcomes(&report)[3].1, StepOutcome::Passed);
+
+        let report = self_test(Failure::Who).await;
+        assert_eq!(
+            outcomes(&report)[3],
+            (
+                SelfTestStep::Who,
+                StepOutcome::Failed("own session missing from 0 sessions".to_string())
+            )
+        );
+    }
+
+    #[test]
+    fn format() {
+        let step = |step, outcome, ms: Option<u64>| StepReport {
+            step,
+            outcome,
+            latency: ms.map(Duration::from_millis),
+        };
+        let report = SelfTestReport {
+            steps: vec![
+                step(
+                    SelfTestStep::Send,
+                    StepOutcome::Failed("timed out".to_string()),
+                    Some(30000),
+                ),
+                step(SelfTestStep::Reply, StepOutcome::Skipped, None),
+                step(SelfTestStep::GetMessage, StepOutcome::Skipped, None),
+                step(SelfTestStep::Who, StepOutcome::Passed, Some(12)),
+            ],
+        };
+        assert_eq!(
+            report.to_string(),
+            "self-test failed (1/4 steps)\n\
+             send: FAILED after 30000ms: timed out\n\
+             reply: skipped\n\
+             get-message: skipped\n\
+             who: ok (12ms)"
+        );
+
+        let report = SelfTestReport {
+            steps: vec![step(SelfTestStep::Who, StepOutcome::Passed, Some(3))],
+        };
+        assert_eq!(
+            report.to_string(),
+            "self-test passed (1/1 steps)\nwho: ok (3ms)"
+        );
+    }
+}
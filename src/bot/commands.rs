@@ -1,13 +1,103 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use log::warn;
+use tokio::time::Instant;
+
 use crate::api::packet::ParsedPacket;
-use crate::api::{Data, SendEvent};
+use crate::api::{Data, Message, SendEvent, SendReply};
 use crate::conn;
 
-use super::command::{Command, Context};
+use super::command::{
+    CancellationToken, Command, Context, Conversations, Info, OutputTransforms, RetryRequest,
+};
 use super::instance::{ConnSnapshot, InstanceConfig};
+use super::persona::Personas;
+
+type ResolveFn = dyn Fn(&str) -> Option<String> + Send + Sync;
+
+/// Decides which command prefix to use in which room.
+///
+/// Rooms often have their own conventions about command prefixes, e.g. `?`
+/// instead of `!` for quieter bots. The prefix resolved for a room is available
+/// to commands via [`Context::prefix`] and overrides the prefix of bang commands
+/// like [`General`](super::command::General), both when matching messages and
+/// when rendering help.
+pub struct PrefixResolver(Box<ResolveFn>);
+
+impl PrefixResolver {
+    /// Resolve prefixes using a function from room name to prefix.
+    pub fn new<F>(resolve: F) -> Self
+    where
+        F: Fn(&str) -> Option<String> + Send + Sync + 'static,
+    {
+        Self(Box::new(resolve))
+    }
+
+    /// Resolve prefixes using a map from room name to prefix.
+    ///
+    /// Commands in rooms not contained in the map use their own prefixes.
+    pub fn from_rooms<I, R, P>(rooms: I) -> Self
+    where
+        I: IntoIterator<Item = (R, P)>,
+        R: ToString,
+        P: ToString,
+    {
+        let rooms = rooms
+            .into_iter()
+            .map(|(r, p)| (r.to_string(), p.to_string()))
+            .collect::<HashMap<_, _>>();
+        Self::new(move |room| rooms.get(room).cloned())
+    }
+
+    /// The prefix to use in a room, if it differs from the commands' own
+    /// prefixes.
+    pub fn resolve(&self, room: &str) -> Option<String> {
+        (self.0)(room)
+    }
+}
+
+/// Documentation of a single command, see [`Commands::export_info`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct CommandDoc {
+    /// See [`Info::name`].
+    pub name: Option<String>,
+    /// The trigger as shown in the help in the context's room, i.e. with the
+    /// room's prefix and the bot's current nick, e.g. `!ping @TestBot`.
+    pub trigger: Option<String>,
+    pub description: String,
+    pub long_help: Option<String>,
+    pub category: Option<String>,
+    pub examples: Vec<String>,
+}
+
+/// A message waiting to be handled again, see [`Context::retry_after`].
+struct Retry {
+    due: Instant,
+    attempt: u32,
+    config: InstanceConfig,
+    msg: Arc<Message>,
+    snapshot: ConnSnapshot,
+}
 
 pub struct Commands<B, E> {
     commands: Vec<Box<dyn Command<B, E> + Send + Sync>>,
     fallthrough: bool,
+    prefix_resolver: Option<PrefixResolver>,
+    cancellation: Mutex<CancellationToken>,
+    conversations: Conversations,
+    propagate_answers: bool,
+    retries: Mutex<Vec<Retry>>,
+    /// Whether anything ever waited for or handled retries.
+    retries_driven: AtomicBool,
+    max_retries: u32,
+    max_queued_retries: usize,
+    output_transforms: OutputTransforms,
+    personas: Mutex<HashMap<String, Personas>>,
+    serialize_personas: bool,
 }
 
 impl<B, E> Commands<B, E> {
@@ -15,6 +105,17 @@ impl<B, E> Commands<B, E> {
         Self {
             commands: vec![],
             fallthrough: false,
+            prefix_resolver: None,
+            cancellation: Mutex::new(CancellationToken::new()),
+            conversations: Conversations::new(),
+            propagate_answers: false,
+            retries: Mutex::new(vec![]),
+            retries_driven: AtomicBool::new(false),
+            max_retries: 3,
+            max_queued_retries: 100,
+            output_transforms: OutputTransforms::new(),
+            personas: Mutex::new(HashMap::new()),
+            serialize_personas: true,
         }
     }
 
@@ -35,6 +136,104 @@ impl<B, E> Commands<B, E> {
         self.fallthrough = active;
     }
 
+    /// How the command prefix for each room is resolved, if at all.
+    ///
+    /// See [`PrefixResolver`] for more details.
+    pub fn prefix_resolver(&self) -> Option<&PrefixResolver> {
+        self.prefix_resolver.as_ref()
+    }
+
+    /// Set how the command prefix for each room is resolved.
+    ///
+    /// See [`PrefixResolver`] for more details.
+    pub fn set_prefix_resolver(&mut self, resolver: Option<PrefixResolver>) {
+        self.prefix_resolver = resolver;
+    }
+
+    /// The answers commands are currently waiting for.
+    ///
+    /// See [`Context::ask`] for more details.
+    pub fn conversations(&self) -> &Conversations {
+        &self.conversations
+    }
+
+    /// Which output transform is active in which room.
+    ///
+    /// Clones share the same transforms, so they can be changed at runtime,
+    /// e.g. via [`OutputMode`](super::command::OutputMode). See
+    /// [`OutputTransforms`] for more details.
+    pub fn output_transforms(&self) -> &OutputTransforms {
+        &self.output_transforms
+    }
+
+    /// Whether messages that answer a question asked via [`Context::ask`] are
+    /// also passed on to the commands.
+    ///
+    /// If disabled, [`Self::handle_packet`] returns `true` for answers without
+    /// executing any commands.
+    pub fn propagate_answers(&self) -> bool {
+        self.propagate_answers
+    }
+
+    /// Set whether answers are passed on to the commands.
+    ///
+    /// See [`Self::propagate_answers`] for more details.
+    pub fn set_propagate_answers(&mut self, active: bool) {
+        self.propagate_answers = active;
+    }
+
+    /// How often a single message may be retried via [`Context::retry_after`].
+    pub fn max_retries(&self) -> u32 {
+        self.max_retries
+    }
+
+    /// Set how often a single message may be retried (default: 3).
+    pub fn set_max_retries(&mut self, max_retries: u32) {
+        self.max_retries = max_retries;
+    }
+
+    /// How many messages may wait to be retried at the same time.
+    pub fn max_queued_retries(&self) -> usize {
+        self.max_queued_retries
+    }
+
+    /// Set how many messages may wait to be retried at the same time (default:
+    /// 100).
+    pub fn set_max_queued_retries(&mut self, max_queued_retries: usize) {
+        self.max_queued_retries = max_queued_retries;
+    }
+
+    /// Whether [`Context::send_as`] serializes sends by different personas.
+    ///
+    /// See [`Personas::with_serialize`] for more details.
+    pub fn serialize_personas(&self) -> bool {
+        self.serialize_personas
+    }
+
+    /// Set whether sends by different personas are serialized (default:
+    /// `true`).
+    ///
+    /// Only affects instances whose commands haven't run yet.
+    pub fn set_serialize_personas(&mut self, active: bool) {
+        self.serialize_personas = active;
+    }
+
+    /// How many messages are currently waiting to be retried.
+    pub fn queued_retries(&self) -> usize {
+        self.retries.lock().unwrap().len()
+    }
+
+    /// Cancel all commands that are currently being executed.
+    ///
+    /// Commands notice this via their [`Context::cancellation`]. Commands
+    /// executed afterwards are not affected. Commands are also cancelled
+    /// automatically once the connection they were received on is closed,
+    /// e.g. because their instance disconnected or was stopped.
+    pub fn cancel_running(&self) {
+        let old = std::mem::take(&mut *self.cancellation.lock().unwrap());
+        old.cancel();
+    }
+
     pub fn add<C>(&mut self, command: C)
     where
         C: Command<B, E> + Send + Sync + 'static,
@@ -42,15 +241,51 @@ impl<B, E> Commands<B, E> {
         self.commands.push(Box::new(command));
     }
 
+    pub fn infos(&self, ctx: &Context) -> Vec<Info> {
+        self.commands.iter().map(|c| c.info(ctx)).collect()
+    }
+
+    /// Structured documentation of all commands that have a description, e.g.
+    /// for generating a web page about the bot.
+    ///
+    /// Like the help lines, the docs are resolved in the context's room. Their
+    /// triggers contain the prefix used in that room and the bot's nick in
+    /// that room. Commands without a description are not documented, just
+    /// like they don't show up in the help.
+    pub fn export_info(&self, ctx: &Context) -> Vec<CommandDoc> {
+        self.infos(ctx)
+            .into_iter()
+            .filter_map(|info| {
+                Some(CommandDoc {
+                    name: info.name().map(|n| n.to_string()),
+                    description: info.description?,
+                    trigger: info.trigger,
+                    long_help: info.long_help,
+                    category: info.category,
+                    examples: info.examples,
+                })
+            })
+            .collect()
+    }
+
+    /// The help lines of all commands that have a description.
+    ///
+    /// See [`Info::line`] for more details.
     pub fn descriptions(&self, ctx: &Context) -> Vec<String> {
-        self.commands
-            .iter()
-            .filter_map(|c| c.description(ctx))
-            .collect::<Vec<_>>()
+        self.infos(ctx).iter().filter_map(|i| i.line()).collect()
     }
 
     /// Returns `true` if one or more commands returned `true`, `false`
     /// otherwise.
+    ///
+    /// Messages answering a question asked via [`Context::ask`] are delivered
+    /// to the question first, see [`Self::propagate_answers`]. No commands are
+    /// executed while commands are [deferred](Context::deferred). If a command
+    /// requests the message to be retried via [`Context::retry_after`], no
+    /// further commands are executed and `true` is returned.
+    ///
+    /// The bot's own messages contained in [`SendReply`]s are remembered for
+    /// [`ReplyTo`](super::command::ReplyTo) commands.
     pub async fn handle_packet(
         &self,
         config: &InstanceConfig,
@@ -60,23 +295,97 @@ impl<B, E> Commands<B, E> {
     ) -> Result<bool, E> {
         let msg = match &packet.content {
             Ok(Data::SendEvent(SendEvent(msg))) => msg,
+            Ok(Data::SendReply(SendReply(msg))) => {
+                self.conversations.remember_own(Arc::new(msg.clone()));
+                return Ok(false);
+            }
             _ => return Ok(false),
         };
 
-        let joined = match &snapshot.state {
-            conn::State::Joining(_) => return Ok(false),
-            conn::State::Joined(joined) => joined.clone(),
+        if self.conversations.deliver(msg) && !self.propagate_answers {
+            return Ok(true);
+        }
+
+        self.handle_message(config, msg, snapshot, 0, bot).await
+    }
+
+    /// Wait until the next message is due to be retried.
+    ///
+    /// Waits forever if no messages are waiting to be retried. Intended to be
+    /// used in a [`tokio::select!`] next to receiving events, followed by
+    /// [`Self::handle_retries`]. Messages queued while waiting are not noticed,
+    /// so the future should be recreated after handling each event.
+    ///
+    /// Since this must be polled before the first message is queued for a
+    /// retry, the `select!` should be `biased` with this future first.
+    /// Otherwise, a warning is logged for every message queued before.
+    pub async fn retries_due(&self) {
+        self.retries_driven.store(true, Ordering::Relaxed);
+        let next = self.retries.lock().unwrap().iter().map(|r| r.due).min();
+        match next {
+            Some(due) => tokio::time::sleep_until(due).await,
+            None => std::future::pending().await,
+        }
+    }
+
+    /// Handle all messages that are due to be retried.
+    ///
+    /// Messages received on a connection that has since been closed are
+    /// discarded instead. Returns `true` if one or more commands returned
+    /// `true` for any of the messages, `false` otherwise.
+    pub async fn handle_retries(&self, bot: &mut B) -> Result<bool, E> {
+        self.retries_driven.store(true, Ordering::Relaxed);
+        let now = Instant::now();
+        let due = {
+            let mut retries = self.retries.lock().unwrap();
+            let (mut due, pending) = retries.drain(..).partition::<Vec<_>, _>(|r| r.due <= now);
+            *retries = pending;
+            due.sort_by_key(|r| r.due);
+            due
         };
 
-        let ctx = Context {
-            config: config.clone(),
-            conn_tx: snapshot.conn_tx.clone(),
-            joined,
+        let mut handled = false;
+        for retry in due {
+            if retry.snapshot.conn_tx.is_closed() {
+                continue;
+            }
+            let Retry {
+                attempt,
+                config,
+                msg,
+                snapshot,
+                ..
+            } = retry;
+            handled |= self
+                .handle_message(&config, &msg, &snapshot, attempt, bot)
+                .await?;
+        }
+        Ok(handled)
+    }
+
+    async fn handle_message(
+        &self,
+        config: &InstanceConfig,
+        msg: &Arc<Message>,
+        snapshot: &ConnSnapshot,
+        attempt: u32,
+        bot: &mut B,
+    ) -> Result<bool, E> {
+        let ctx = match self.context(config, snapshot, attempt) {
+            Some(ctx) => ctx,
+            None => return Ok(false),
         };
+        if ctx.deferred {
+            return Ok(false);
+        }
 
         let mut handled = false;
         for command in &self.commands {
             handled = handled || command.execute(&msg.content, msg, &ctx, bot).await?;
+            if let Some(delay) = ctx.retry.requested() {
+                self.queue_retry(delay, config, msg, snapshot, attempt);
+                return Ok(true);
+            }
             if !self.fallthrough && handled {
                 break;
             }
@@ -84,6 +393,92 @@ impl<B, E> Commands<B, E> {
 
         Ok(handled)
     }
+
+    fn queue_retry(
+        &self,
+        delay: Duration,
+        config: &InstanceConfig,
+        msg: &Arc<Message>,
+        snapshot: &ConnSnapshot,
+        attempt: u32,
+    ) {
+        if attempt >= self.max_retries {
+            warn!(
+                "Not retrying message {} again after {attempt} retries",
+                msg.id.0
+            );
+            return;
+        }
+        if !self.retries_driven.load(Ordering::Relaxed) {
+            warn!(
+                "Message {} is queued for a retry, but nothing calls \
+                 Commands::retries_due and Commands::handle_retries yet",
+                msg.id.0
+            );
+        }
+        let mut retries = self.retries.lock().unwrap();
+        if retries.len() >= self.max_queued_retries {
+            warn!("Not retrying message {}, too many retries queued", msg.id.0);
+            return;
+        }
+        retries.push(Retry {
+            due: Instant::now() + delay,
+            attempt: attempt + 1,
+            config: config.clone(),
+            msg: msg.clone(),
+            snapshot: snapshot.clone(),
+        });
+    }
+
+    fn context(
+        &self,
+        config: &InstanceConfig,
+        snapshot: &ConnSnapshot,
+        attempt: u32,
+    ) -> Option<Context> {
+        let joined = match &*snapshot.state {
+            conn::State::Joining(_) => return None,
+            conn::State::Joined(joined) => joined.clone(),
+        };
+
+        let prefix = self
+            .prefix_resolver
+            .as_ref()
+            .and_then(|r| r.resolve(&config.room));
+
+        let deferred = config.defers_commands(&joined);
+        let cancellation = self
+            .cancellation
+            .lock()
+            .unwrap()
+            .child_token()
+            .with_conn(snapshot.conn_tx.clone());
+
+        let personas = self
+            .personas
+            .lock()
+            .unwrap()
+            .entry(config.name.clone())
+            .or_insert_with(|| {
+                let resting = config.username.as_ref().unwrap_or(&joined.session.name);
+                Personas::new(resting).with_serialize(self.serialize_personas)
+            })
+            .clone();
+
+        Some(Context {
+            config: config.clone(),
+            conn_tx: snapshot.conn_tx.clone(),
+            joined,
+            prefix,
+            cancellation,
+            deferred,
+            conversations: self.conversations.clone(),
+            attempt,
+            retry: RetryRequest::new(),
+            output: self.output_transforms.resolve(&config.room),
+            personas,
+        })
+    }
 }
 
 impl<B, E> Default for Commands<B, E> {
@@ -91,3 +486,689 @@ impl<B, E> Default for Commands<B, E> {
         Self::new()
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::future::Future;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use async_trait::async_trait;
+
+    use crate::api::packet::ParsedPacket;
+    use crate::api::{Message, MessageId, PacketType, SendEvent, SessionId};
+    use crate::bot::command::test::context;
+    use crate::bot::command::{Command, Context, General, Info, Specific};
+    use crate::bot::instance::{ConnSnapshot, DuplicatePolicy, InstanceConfig, ServerConfig};
+    use crate::conn::test::{connect, hello, Server};
+    use crate::conn::{self, Conn, ConnectTimings, SessionInfo, State};
+    use crate::test_util::{MessageBuilder, SessionViewBuilder};
+
+    use super::{Commands, PrefixResolver};
+
+    /// Remembers the names of the rooms it was executed in.
+    struct Record;
+
+    #[async_trait]
+    impl Command<Vec<String>, conn::Error> for Record {
+        fn info(&self, _ctx: &Context) -> Info {
+            Info::new().with_description("Remember the room.")
+        }
+
+        async fn execute(
+            &self,
+            _arg: &str,
+            _msg: &Message,
+            ctx: &Context,
+            bot: &mut Vec<String>,
+        ) -> Result<bool, conn::Error> {
+            bot.push(ctx.config.room.clone());
+            Ok(true)
+        }
+    }
+
+    /// Remembers the address of every message it was executed with.
+    struct Address;
+
+    #[async_trait]
+    impl Command<Vec<usize>, conn::Error> for Address {
+        async fn execute(
+            &self,
+            _arg: &str,
+            msg: &Message,
+            _ctx: &Context,
+            bot: &mut Vec<usize>,
+        ) -> Result<bool, conn::Error> {
+            bot.push(msg as *const Message as usize);
+            Ok(false)
+        }
+    }
+
+    /// Asks for the message to be retried after 30 seconds until the given
+    /// attempt.
+    struct Busy(u32);
+
+    #[async_trait]
+    impl Command<Vec<String>, conn::Error> for Busy {
+        async fn execute(
+            &self,
+            _arg: &str,
+            _msg: &Message,
+            ctx: &Context,
+            bot: &mut Vec<String>,
+        ) -> Result<bool, conn::Error> {
+            bot.push(format!("busy {}", ctx.attempt));
+            if ctx.attempt < self.0 {
+                ctx.retry_after(Duration::from_secs(30));
+            }
+            Ok(false)
+        }
+    }
+
+    /// Counts down from three, one message per second.
+    struct Countdown;
+
+    #[async_trait]
+    impl Command<Vec<String>, conn::Error> for Countdown {
+        async fn execute(
+            &self,
+            _arg: &str,
+            _msg: &Message,
+            ctx: &Context,
+            _bot: &mut Vec<String>,
+        ) -> Result<bool, conn::Error> {
+            for i in (1..=3).rev() {
+                ctx.send_only(i)?;
+                ctx.sleep(Duration::from_secs(1)).await?;
+            }
+            Ok(true)
+        }
+    }
+
+    fn commands() -> Commands<Vec<String>, conn::Error> {
+        let mut commands = Commands::new();
+        commands.add(General::new("record", Record));
+        commands.add(Specific::new("record", Record).with_prefix("/"));
+        commands.set_prefix_resolver(Some(PrefixResolver::from_rooms([("quiet", "?")])));
+        commands
+    }
+
+    fn config(room: &str) -> InstanceConfig {
+        InstanceConfig::new(ServerConfig::default(), room)
+    }
+
+    fn snapshot() -> ConnSnapshot {
+        let ctx = context();
+        ConnSnapshot {
+            conn_tx: ctx.conn_tx,
+            state: Arc::new(State::Joined(ctx.joined)),
+            connect_timings: ConnectTimings::default(),
+        }
+    }
+
+    async fn drive(conn: &mut Conn) {
+        loop {
+            conn.recv().await.unwrap();
+        }
+    }
+
+    /// Start a countdown and drive the connection until the server received
+    /// the countdown's first message.
+    ///
+    /// Must be called with tokio's time paused. Connecting and joining happen
+    /// in real time though, since a paused clock would auto-advance to the
+    /// connection's next ping while waiting for the server. The clock is paused
+    /// again once the countdown is running.
+    async fn start_countdown(
+        commands: &Commands<Vec<String>, conn::Error>,
+    ) -> (
+        impl Future<Output = Result<bool, conn::Error>> + '_,
+        Conn,
+        Server,
+    ) {
+        tokio::time::resume();
+        let (mut conn, mut server) = connect(Duration::from_secs(10)).await;
+        server.join(hello(false, None)).await;
+        while let State::Joining(_) = conn.state() {
+            conn.recv().await.unwrap();
+        }
+        let snapshot = ConnSnapshot {
+            conn_tx: conn.tx().clone(),
+            state: conn.shared_state(),
+            connect_timings: ConnectTimings::default(),
+        };
+
+        let command = async move {
+            let packet = packet("!countdown");
+            commands
+                .handle_packet(&config("test"), &packet, &snapshot, &mut vec![])
+                .await
+        };
+        let mut command = Box::pin(command);
+        tokio::select! {
+            _ = &mut command => panic!("countdown finished early"),
+            _ = drive(&mut conn) => unreachable!(),
+            packet = server.recv() => assert_eq!(packet.unwrap()["data"]["content"], "3"),
+        }
+        tokio::time::pause();
+        (command, conn, server)
+    }
+
+    fn message(id: &str, parent: Option<&str>, sender: &str, content: &str) -> Message {
+        let parse = |id: &str| MessageId(id.parse().unwrap());
+        MessageBuilder::new(content)
+            .with_id(parse(id))
+            .with_parent_opt(parent.map(parse))
+            .with_sender(SessionViewBuilder::new(sender).build())
+            .build()
+    }
+
+    fn send_event(msg: Message) -> ParsedPacket {
+        ParsedPacket {
+            id: None,
+            r#type: PacketType::SendEvent,
+            content: Ok(SendEvent(Arc::new(msg)).into()),
+            throttled: None,
+            seq: None,
+        }
+    }
+
+    fn packet(content: &str) -> ParsedPacket {
+        send_event(message("0000000000001", None, "someone", content))
+    }
+
+    /// Connect to a server that replies to every sent message, numbering the
+    /// messages starting with 10.
+    async fn chat_server() -> ConnSnapshot {
+        let (mut conn, mut server) = connect(Duration::from_secs(10)).await;
+        server.join(hello(false, None)).await;
+        while let State::Joining(_) = conn.state() {
+            conn.recv().await.unwrap();
+        }
+        let snapshot = ConnSnapshot {
+            conn_tx: conn.tx().clone(),
+            state: conn.shared_state(),
+            connect_timings: ConnectTimings::default(),
+        };
+
+        tokio::spawn(async move { while conn.recv().await.is_ok() {} });
+        tokio::spawn(async move {
+            let mut next = 10;
+            while let Some(packet) = server.recv().await {
+                if packet["type"] != "send" {
+                    continue;
+                }
+                let id = format!("{next:013}");
+                next += 1;
+                let data = &packet["data"];
+                let parent = data["parent"].as_str();
+                let content = data["content"].as_str().unwrap();
+                let msg = message(&id, parent, "TestBot", content);
+                server
+                    .send(serde_json::json!({
+                        "id": packet["id"],
+                        "type": "send-reply",
+                        "data": msg,
+                    }))
+                    .await;
+            }
+        });
+
+        snapshot
+    }
+
+    #[test]
+    fn resolve() {
+        let resolver = PrefixResolver::from_rooms([("quiet", "?"), ("slash", "/")]);
+        assert_eq!(resolver.resolve("quiet").as_deref(), Some("?"));
+        assert_eq!(resolver.resolve("slash").as_deref(), Some("/"));
+        assert_eq!(resolver.resolve("test"), None);
+
+        let resolver = PrefixResolver::new(|room| room.strip_prefix("q").map(|_| "?".to_string()));
+        assert_eq!(resolver.resolve("quiet").as_deref(), Some("?"));
+        assert_eq!(resolver.resolve("test"), None);
+    }
+
+    #[tokio::test]
+    async fn per_room_prefixes() {
+        let commands = commands();
+        let snapshot = snapshot();
+        let cases = [
+            ("test", "!record", true),
+            ("test", "?record", false),
+            ("test", "/record @TestBot", true),
+            ("test", "?record @TestBot", false),
+            ("quiet", "?record", true),
+            ("quiet", "!record", false),
+            ("quiet", "?record @TestBot", true),
+            ("quiet", "/record @TestBot", false),
+        ];
+
+        for (room, content, expected) in cases {
+            let mut rooms = vec![];
+            let handled = commands
+                .handle_packet(&config(room), &packet(content), &snapshot, &mut rooms)
+                .await
+                .unwrap();
+            assert_eq!(handled, expected, "{content:?} in &{room}");
+            assert_eq!(rooms.len(), usize::from(expected));
+        }
+    }
+
+    #[tokio::test]
+    async fn dispatch_shares_messages() {
+        let mut commands = Commands::new();
+        for _ in 0..3 {
+            commands.add(Address);
+        }
+        let config = config("test");
+        let snapshot = snapshot();
+
+        let mut addresses = vec![];
+        for i in 0..10_000 {
+            let msg = Arc::new(message("0000000000001", None, "someone", &format!("{i}")));
+            let packet = ParsedPacket {
+                id: None,
+                r#type: PacketType::SendEvent,
+                content: Ok(SendEvent(msg.clone()).into()),
+                throttled: None,
+                seq: None,
+            };
+            addresses.clear();
+            commands
+                .handle_packet(&config, &packet, &snapshot, &mut addresses)
+                .await
+                .unwrap();
+
+            // Every command sees the message from the packet, not a copy
+            let address = Arc::as_ptr(&msg) as usize;
+            assert_eq!(addresses, [address; 3]);
+            // and no references to it are kept around
+            drop(packet);
+            assert_eq!(Arc::strong_count(&msg), 1);
+        }
+    }
+
+    /// Commands whose first command is [`Busy`], followed by [`Record`].
+    fn busy_commands(until: u32) -> Commands<Vec<String>, conn::Error> {
+        let mut commands = Commands::new();
+        commands.add(Busy(until));
+        commands.add(General::new("record", Record));
+        commands
+    }
+
+    /// A snapshot whose connection stays open as long as the [`Conn`] exists.
+    async fn open_snapshot() -> (ConnSnapshot, Conn) {
+        let (conn, _) = connect(Duration::from_secs(10)).await;
+        let snapshot = ConnSnapshot {
+            conn_tx: conn.tx().clone(),
+            state: snapshot().state,
+            connect_timings: ConnectTimings::default(),
+        };
+        (snapshot, conn)
+    }
+
+    /// Pins the exported docs of a representative set of commands. If this
+    /// test breaks, the docs generated by existing bots changed.
+    #[cfg(feature = "serde")]
+    #[test]
+    fn export_info() {
+        use crate::bot::command::{Clap, ClapCommand, CommandExt, Described, Hidden};
+
+        /// Repeat a message.
+        #[derive(clap::Parser)]
+        struct EchoArgs {
+            /// The message to repeat.
+            message: Vec<String>,
+        }
+
+        struct Echo;
+
+        #[async_trait]
+        impl ClapCommand<Vec<String>, conn::Error> for Echo {
+            type Args = EchoArgs;
+
+            async fn execute(
+                &self,
+                args: EchoArgs,
+                _msg: &Message,
+                _ctx: &Context,
+                bot: &mut Vec<String>,
+            ) -> Result<bool, conn::Error> {
+                bot.push(args.message.join(" "));
+                Ok(true)
+            }
+        }
+
+        let mut commands: Commands<Vec<String>, conn::Error> = Commands::new();
+        commands.add(Specific::new("echo", Clap(Echo)).category("Fun"));
+        commands.add(General::new("say", Clap(Echo)).category("Fun"));
+        commands.add(
+            Described::new(General::new("record", Record))
+                .with_description("Write down the room.")
+                .with_example("!record")
+                .category("Admin"),
+        );
+        commands.add(Hidden(General::new("secret", Record)));
+
+        let mut ctx = context();
+        ctx.prefix = Some("?".to_string());
+        let echo_help = "Repeat a message\n\n\
+                         Arguments:\n  [MESSAGE]...\n          The message to repeat\n\n\
+                         Options:\n  -h, --help\n          Print help";
+        assert_eq!(
+            serde_json::to_value(commands.export_info(&ctx)).unwrap(),
+            serde_json::json!([
+                {
+                    "name": "echo",
+                    "trigger": "?echo @TestBot",
+                    "description": "Repeat a message",
+                    "long_help": echo_help,
+                    "category": "Fun",
+                    "examples": [],
+                },
+                {
+                    "name": "say",
+                    "trigger": "?say",
+                    "description": "Repeat a message",
+                    "long_help": echo_help,
+                    "category": "Fun",
+                    "examples": [],
+                },
+                {
+                    "name": "record",
+                    "trigger": "?record",
+                    "description": "Write down the room.",
+                    "long_help": null,
+                    "category": "Admin",
+                    "examples": ["!record"],
+                },
+            ])
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn retry_redelivery() {
+        let commands = busy_commands(2);
+        let (snapshot, _conn) = open_snapshot().await;
+        let mut bot = vec![];
+
+        let handled = commands
+            .handle_packet(&config("test"), &packet("!record"), &snapshot, &mut bot)
+            .await
+            .unwrap();
+        assert!(handled);
+        // Later commands don't see the message while it is being retried
+        assert_eq!(bot, ["busy 0"]);
+        assert_eq!(commands.queued_retries(), 1);
+
+        // Retries only happen once they are due
+        assert!(!commands.handle_retries(&mut bot).await.unwrap());
+        assert_eq!(bot, ["busy 0"]);
+
+        let start = tokio::time::Instant::now();
+        commands.retries_due().await;
+        assert_eq!(start.elapsed(), Duration::from_secs(30));
+        assert!(commands.handle_retries(&mut bot).await.unwrap());
+        assert_eq!(bot, ["busy 0", "busy 1"]);
+
+        commands.retries_due().await;
+        assert!(commands.handle_retries(&mut bot).await.unwrap());
+        assert_eq!(bot, ["busy 0", "busy 1", "busy 2", "test"]);
+        assert_eq!(commands.queued_retries(), 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn retry_cap() {
+        let mut commands = busy_commands(u32::MAX);
+        commands.set_max_retries(2);
+        let (snapshot, _conn) = open_snapshot().await;
+        let mut bot = vec![];
+
+        commands
+            .handle_packet(&config("test"), &packet("!record"), &snapshot, &mut bot)
+            .await
+            .unwrap();
+        while commands.queued_retries() > 0 {
+            commands.retries_due().await;
+            commands.handle_retries(&mut bot).await.unwrap();
+        }
+        // The message never reaches later commands
+        assert_eq!(bot, ["busy 0", "busy 1", "busy 2"]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn retry_queue_bound() {
+        let mut commands = busy_commands(1);
+        commands.set_max_queued_retries(2);
+        let (snapshot, conn) = open_snapshot().await;
+        let mut bot = vec![];
+
+        for _ in 0..3 {
+            commands
+                .handle_packet(&config("test"), &packet("!record"), &snapshot, &mut bot)
+                .await
+                .unwrap();
+        }
+        assert_eq!(commands.queued_retries(), 2);
+
+        // Retries of closed connections are discarded
+        drop(conn);
+        commands.retries_due().await;
+        assert!(!commands.handle_retries(&mut bot).await.unwrap());
+        assert_eq!(bot, ["busy 0", "busy 0", "busy 0"]);
+        assert_eq!(commands.queued_retries(), 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn cancel_running() {
+        let mut commands = Commands::new();
+        commands.add(General::new("countdown", Countdown));
+
+        let (command, mut conn, mut server) = start_countdown(&commands).await;
+        commands.cancel_running();
+        let result = tokio::time::timeout(Duration::from_millis(500), command).await;
+        assert!(matches!(result, Ok(Err(conn::Error::Cancelled))));
+
+        // No further messages are sent
+        let received = tokio::time::timeout(Duration::from_millis(1500), async {
+            tokio::select! {
+                _ = drive(&mut conn) => unreachable!(),
+                packet = server.recv() => packet,
+            }
+        });
+        assert!(received.await.is_err());
+
+        // Later commands are not affected
+        let (command, _conn, _server) = start_countdown(&commands).await;
+        let result = tokio::time::timeout(Duration::from_millis(500), command).await;
+        assert!(result.is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn cancel_on_disconnect() {
+        let mut commands = Commands::new();
+        commands.add(General::new("countdown", Countdown));
+
+        let (command, conn, mut server) = start_countdown(&commands).await;
+        drop(conn);
+        let result = tokio::time::timeout(Duration::from_millis(500), command).await;
+        assert!(matches!(result, Ok(Err(conn::Error::Cancelled))));
+        assert!(server.recv().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn ask_and_answer() {
+        let mut commands = commands();
+        let config = config("test");
+        let snapshot = chat_server().await;
+        let ctx = commands.context(&config, &snapshot, 0).unwrap();
+        let question = message("0000000000001", None, "someone", "!rename");
+
+        let ask = ctx.ask(&question, "Which room?", Duration::from_secs(10));
+        let answer = async {
+            while commands.conversations().pending() == 0 {
+                tokio::task::yield_now().await;
+            }
+            // The question is the first message sent to the server
+            let answer = |sender, content| {
+                send_event(message(
+                    "0000000000002",
+                    Some("0000000000010"),
+                    sender,
+                    content,
+                ))
+            };
+
+            // Someone else can't answer, and their message is handled normally
+            let mut rooms = vec![];
+            let handled = commands
+                .handle_packet(&config, &answer("other", "!record"), &snapshot, &mut rooms)
+                .await
+                .unwrap();
+            assert!(handled);
+            assert_eq!(rooms.len(), 1);
+
+            // Neither can replies to other messages
+            let msg = message("0000000000003", Some("0000000000001"), "someone", "test");
+            let handled = commands
+                .handle_packet(&config, &send_event(msg), &snapshot, &mut rooms)
+                .await
+                .unwrap();
+            assert!(!handled);
+
+            // The answer is not passed on to the commands
+            let handled = commands
+                .handle_packet(
+                    &config,
+                    &answer("someone", "!record"),
+                    &snapshot,
+                    &mut rooms,
+                )
+                .await
+                .unwrap();
+            assert!(handled);
+            assert_eq!(rooms.len(), 1);
+        };
+        let (asked, ()) = tokio::join!(ask, answer);
+        let asked = asked.unwrap().unwrap();
+        assert_eq!(asked.content, "!record");
+        assert_eq!(asked.sender.name, "someone");
+        assert_eq!(commands.conversations().pending(), 0);
+
+        // Unless configured otherwise
+        commands.set_propagate_answers(true);
+        let ctx = commands.context(&config, &snapshot, 0).unwrap();
+        let ask = ctx.ask(&question, "Which room?", Duration::from_secs(10));
+        let answer = async {
+            while commands.conversations().pending() == 0 {
+                tokio::task::yield_now().await;
+            }
+            let msg = message("0000000000004", Some("0000000000011"), "someone", "!record");
+            let mut rooms = vec![];
+            commands
+                .handle_packet(&config, &send_event(msg), &snapshot, &mut rooms)
+                .await
+                .unwrap();
+            assert_eq!(rooms.len(), 1);
+        };
+        let (asked, ()) = tokio::join!(ask, answer);
+        assert!(asked.unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn ask_cleanup() {
+        let commands = commands();
+        let config = config("test");
+        let snapshot = chat_server().await;
+        let ctx = commands.context(&config, &snapshot, 0).unwrap();
+        let question = message("0000000000001", None, "someone", "!rename");
+
+        let asked = ctx.ask(&question, "Which room?", Duration::from_millis(50));
+        assert!(asked.await.unwrap().is_none());
+        assert_eq!(commands.conversations().pending(), 0);
+
+        let ask = ctx.ask(&question, "Which room?", Duration::from_secs(10));
+        let cancel = async {
+            while commands.conversations().pending() == 0 {
+                tokio::task::yield_now().await;
+            }
+            commands.cancel_running();
+        };
+        let (asked, ()) = tokio::join!(ask, cancel);
+        assert!(matches!(asked, Err(conn::Error::Cancelled)));
+        assert_eq!(commands.conversations().pending(), 0);
+
+        // A late answer is handled like any other message
+        let msg = message("0000000000002", Some("0000000000011"), "someone", "!record");
+        let mut rooms = vec![];
+        commands
+            .handle_packet(&config, &send_event(msg), &snapshot, &mut rooms)
+            .await
+            .unwrap();
+        assert_eq!(rooms.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn defer_to_other_instances() {
+        let commands = commands();
+        let mut joined = context().joined;
+        let mut other = joined.session.clone();
+        other.session_id = SessionId("old".to_string());
+        Arc::make_mut(&mut joined.listing)
+            .insert(other.session_id.clone(), SessionInfo::Full(other));
+        let snapshot = ConnSnapshot {
+            conn_tx: context().conn_tx,
+            state: Arc::new(State::Joined(joined)),
+            connect_timings: ConnectTimings::default(),
+        };
+
+        for (policy, expected) in [
+            (DuplicatePolicy::Ignore, true),
+            (DuplicatePolicy::WarnOnly, true),
+            (DuplicatePolicy::Defer, false),
+        ] {
+            let config = config("test").with_duplicate_policy(policy);
+            let ctx = commands.context(&config, &snapshot, 0).unwrap();
+            assert_eq!(ctx.deferred, !expected);
+            let handled = commands
+                .handle_packet(&config, &packet("!record"), &snapshot, &mut vec![])
+                .await
+                .unwrap();
+            assert_eq!(handled, expected, "{policy:?}");
+        }
+
+        let config = config("test")
+            .with_duplicate_policy(DuplicatePolicy::Defer)
+            .with_duplicate_defer_timeout(Duration::ZERO);
+        let handled = commands
+            .handle_packet(&config, &packet("!record"), &snapshot, &mut vec![])
+            .await
+            .unwrap();
+        assert!(handled);
+    }
+
+    #[test]
+    fn per_room_help() {
+        let commands = commands();
+        let snapshot = snapshot();
+
+        let ctx = commands.context(&config("test"), &snapshot, 0).unwrap();
+        assert_eq!(
+            commands.descriptions(&ctx),
+            [
+                "!record - Remember the room.",
+                "/record @TestBot - Remember the room.",
+            ]
+        );
+
+        let ctx = commands.context(&config("quiet"), &snapshot, 0).unwrap();
+        assert_eq!(
+            commands.descriptions(&ctx),
+            [
+                "?record - Remember the room.",
+                "?record @TestBot - Remember the room.",
+            ]
+        );
+    }
+}
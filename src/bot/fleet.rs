@@ -0,0 +1,230 @@
+//! Utilities operating on all connections of a bot.
+//!
+//! Code written against [`ConnectionSet`] works regardless of how the bot
+//! manages its connections, e.g. via [`Instances`] or its own collection of
+//! [`Conn`](crate::conn::Conn)s.
+
+use async_trait::async_trait;
+
+use crate::api::{self, Message};
+use crate::conn::{self, ConnTx, State};
+
+use super::instances::Instances;
+
+/// A connection of a [`ConnectionSet`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConnectionInfo {
+    /// Identifies the connection within its set.
+    pub name: String,
+    pub room: String,
+}
+
+/// A collection of named connections to rooms.
+#[async_trait]
+pub trait ConnectionSet: Send + Sync {
+    /// All connections, sorted by name.
+    fn connections(&self) -> Vec<ConnectionInfo>;
+
+    /// A way to send commands via a connection.
+    ///
+    /// Returns `None` if the connection is unknown or not currently connected.
+    async fn conn_tx(&self, name: &str) -> Option<ConnTx>;
+
+    /// Stop a connection.
+    ///
+    /// Returns `false` if the connection is unknown.
+    fn stop(&self, name: &str) -> bool;
+
+    /// The current state of a connection, if it is connected.
+    async fn state(&self, name: &str) -> Option<State> {
+        self.conn_tx(name).await?.state().await.ok()
+    }
+}
+
+#[async_trait]
+impl ConnectionSet for Instances {
+    fn connections(&self) -> Vec<ConnectionInfo> {
+        let mut connections = self
+            .instances()
+            .map(|i| ConnectionInfo {
+                name: i.config().name.clone(),
+                room: i.config().room.clone(),
+            })
+            .collect::<Vec<_>>();
+        connections.sort_by(|a, b| a.name.cmp(&b.name));
+        connections
+    }
+
+    async fn conn_tx(&self, name: &str) -> Option<ConnTx> {
+        self.get(name)?.conn_tx().await
+    }
+
+    fn stop(&self, name: &str) -> bool {
+        match self.get(name) {
+            Some(instance) => {
+                instance.stop();
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// Send a top-level message via every connection of a set.
+///
+/// The message is sent via all connections at the same time. Returns the
+/// result for every connection, sorted by name. Connections that aren't
+/// currently connected fail with [`conn::Error::ConnectionClosed`].
+pub async fn broadcast<S>(set: &S, content: &str) -> Vec<(String, conn::Result<Message>)>
+where
+    S: ConnectionSet + ?Sized,
+{
+    let sends = set.connections().into_iter().map(|c| async move {
+        let result = match set.conn_tx(&c.name).await {
+            Some(conn_tx) => {
+                let cmd = api::Send {
+                    content: content.to_string(),
+                    parent: None,
+                };
+                conn_tx.send(cmd).await.map(|r| r.0)
+            }
+            None => Err(conn::Error::ConnectionClosed),
+        };
+        (c.name, result)
+    });
+    futures_util::future::join_all(sends).await
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeMap;
+    use std::time::Duration;
+
+    use async_trait::async_trait;
+
+    use crate::bot::instance::ServerConfig;
+    use crate::bot::instances::Instances;
+    use crate::conn::test::{connect, hello, Server};
+    use crate::conn::{self, ConnTx, State};
+
+    use super::{broadcast, ConnectionInfo, ConnectionSet};
+
+    /// Connections that are either connected or not.
+    struct Mock(BTreeMap<String, Option<ConnTx>>);
+
+    #[async_trait]
+    impl ConnectionSet for Mock {
+        fn connections(&self) -> Vec<ConnectionInfo> {
+            self.0
+                .keys()
+                .map(|name| ConnectionInfo {
+                    name: name.clone(),
+                    room: format!("room-{name}"),
+                })
+                .collect()
+        }
+
+        async fn conn_tx(&self, name: &str) -> Option<ConnTx> {
+            self.0.get(name)?.clone()
+        }
+
+        fn stop(&self, name: &str) -> bool {
+            self.0.contains_key(name)
+        }
+    }
+
+    /// Reply to every send command with the sent message.
+    async fn serve(mut server: Server) {
+        while let Some(packet) = server.recv().await {
+            if packet["type"] != "send" {
+                continue;
+            }
+            let reply = serde_json::json!({
+                "id": packet["id"],
+                "type": "send-reply",
+                "data": {
+                    "id": "0000000000001",
+                    "time": 0,
+                    "sender": hello(false, None).session,
+                    "content": packet["data"]["content"],
+                },
+            });
+            server.send(reply).await;
+        }
+    }
+
+    async fn connected() -> ConnTx {
+        let (mut conn, mut server) = connect(Duration::from_secs(10)).await;
+        server.join(hello(false, None)).await;
+        while let State::Joining(_) = conn.state() {
+            conn.recv().await.unwrap();
+        }
+        let conn_tx = conn.tx().clone();
+        tokio::spawn(async move { while conn.recv().await.is_ok() {} });
+        tokio::spawn(serve(server));
+        conn_tx
+    }
+
+    #[tokio::test]
+    async fn broadcast_to_mock() {
+        let mut set = BTreeMap::new();
+        set.insert("b".to_string(), Some(connected().await));
+        set.insert("a".to_string(), Some(connected().await));
+        set.insert("c".to_string(), None);
+        let set = Mock(set);
+
+        let results = broadcast(&set, "hello").await;
+        let names = results.iter().map(|(n, _)| n.as_str()).collect::<Vec<_>>();
+        assert_eq!(names, ["a", "b", "c"]);
+        assert_eq!(results[0].1.as_ref().unwrap().content, "hello");
+        assert_eq!(results[1].1.as_ref().unwrap().content, "hello");
+        assert!(matches!(results[2].1, Err(conn::Error::ConnectionClosed)));
+
+        assert!(set.state("a").await.unwrap().joined().is_some());
+        assert!(set.state("c").await.is_none());
+    }
+
+    #[tokio::test]
+    async fn broadcast_to_instances() {
+        // Nothing listens here, so the instances never connect
+        let server = ServerConfig::default()
+            .with_domain("127.0.0.1:1")
+            .with_reconnect_delay(Duration::from_secs(60));
+        let mut instances = Instances::new(server.clone());
+        for (name, room) in [("b", "room2"), ("a", "room1")] {
+            instances.add(server.clone().room(room).with_name(name).build(|_| {}));
+        }
+
+        assert_eq!(
+            instances.connections(),
+            [
+                ConnectionInfo {
+                    name: "a".to_string(),
+                    room: "room1".to_string(),
+                },
+                ConnectionInfo {
+                    name: "b".to_string(),
+                    room: "room2".to_string(),
+                },
+            ]
+        );
+
+        let results = broadcast(&instances, "hello").await;
+        assert_eq!(results.len(), 2);
+        for (_, result) in results {
+            assert!(matches!(result, Err(conn::Error::ConnectionClosed)));
+        }
+
+        assert!(instances.stop("a"));
+        assert!(!instances.stop("unknown"));
+        let a = instances.get("a").unwrap();
+        tokio::time::timeout(Duration::from_secs(10), async {
+            while !a.stopped() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+        assert!(!instances.get("b").unwrap().stopped());
+    }
+}
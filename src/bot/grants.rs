@@ -0,0 +1,403 @@
+//! Keeping track of who was granted access to a private room.
+
+use std::sync::{Arc, Mutex};
+
+use jiff::Timestamp;
+
+use crate::api::{
+    AccessErrorReason, AccessTarget, AccountId, GrantAccess, RevokeAccess, SessionView, UserId,
+};
+use crate::clock::{Clock, SystemClock};
+use crate::conn::{self, ConnTx};
+
+/// Who caused a grant, usually the sender of the command message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Operator {
+    pub id: UserId,
+    /// The operator's nick at the time of the grant.
+    pub name: String,
+}
+
+impl From<&SessionView> for Operator {
+    fn from(session: &SessionView) -> Self {
+        Self {
+            id: session.id.clone(),
+            name: session.name.clone(),
+        }
+    }
+}
+
+/// How an entry ended up in a [`GrantLedger`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum GrantOrigin {
+    /// The server confirmed the grant.
+    Granted,
+    /// The server said the target already had access when we tried to grant
+    /// it, so when and by whom access was originally granted is unknown.
+    Reconciled,
+}
+
+/// A target that was granted access, see [`GrantLedger`].
+///
+/// The [`Debug`](std::fmt::Debug) impl hides passcodes, but serializing an
+/// entry includes its passcode in plaintext.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct GrantEntry {
+    pub target: AccessTarget,
+    /// When the grant was recorded.
+    pub time: Timestamp,
+    /// Who caused the grant, if known.
+    pub operator: Option<Operator>,
+    pub origin: GrantOrigin,
+}
+
+/// The result of a successful [`GrantLedger::grant`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GrantOutcome {
+    Granted,
+    /// The server said the target already had access.
+    AlreadyGranted,
+}
+
+/// The result of a successful [`GrantLedger::revoke`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RevokeOutcome {
+    Revoked,
+    /// The server said the target had no access to revoke.
+    NotGranted,
+}
+
+/// A record of the accounts and passcodes that were granted access to a room.
+///
+/// Access granted via [`Self::grant`] and revoked via [`Self::revoke`] is
+/// recorded once the server confirms it. Grants made some other way can be
+/// recorded manually via [`Self::record_granted`] and [`Self::record_revoked`].
+///
+/// If the server disagrees with the ledger, the server wins: Granting access
+/// to a target that already has it updates the ledger instead of failing, and
+/// so does revoking access from a target that doesn't have it.
+///
+/// Clones share their entries. Entries are timestamped using the ledger's
+/// [`Clock`], see [`Self::with_clock`].
+///
+/// # Serialization
+///
+/// With the `serde` feature, the ledger can be serialized as a list of
+/// [`GrantEntry`]s for persistence. Passcodes are included in plaintext, since
+/// the ledger couldn't revoke them otherwise. Treat the serialized ledger like
+/// the passcodes themselves, or serialize only [`Self::accounts`] if the
+/// passcodes needn't be persisted. Deserialized ledgers use the
+/// [`SystemClock`].
+#[derive(Debug, Clone)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(from = "Vec<GrantEntry>", into = "Vec<GrantEntry>")
+)]
+pub struct GrantLedger {
+    entries: Arc<Mutex<Vec<GrantEntry>>>,
+    clock: Arc<dyn Clock>,
+}
+
+impl Default for GrantLedger {
+    fn default() -> Self {
+        Self::from(vec![])
+    }
+}
+
+impl GrantLedger {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The clock used to timestamp new entries (default: [`SystemClock`]).
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// All entries, oldest first.
+    pub fn entries(&self) -> Vec<GrantEntry> {
+        self.entries.lock().unwrap().clone()
+    }
+
+    pub fn get(&self, target: &AccessTarget) -> Option<GrantEntry> {
+        let entries = self.entries.lock().unwrap();
+        entries.iter().find(|e| e.target == *target).cloned()
+    }
+
+    pub fn has_account(&self, id: AccountId) -> bool {
+        self.get(&AccessTarget::Account(id)).is_some()
+    }
+
+    pub fn has_passcode(&self, passcode: &str) -> bool {
+        self.get(&AccessTarget::Passcode(passcode.to_string()))
+            .is_some()
+    }
+
+    /// All entries granting access to accounts, oldest first.
+    pub fn accounts(&self) -> Vec<GrantEntry> {
+        self.filter(|t| matches!(t, AccessTarget::Account(_)))
+    }
+
+    /// All entries granting access via passcodes, oldest first.
+    pub fn passcodes(&self) -> Vec<GrantEntry> {
+        self.filter(|t| matches!(t, AccessTarget::Passcode(_)))
+    }
+
+    fn filter(&self, f: impl Fn(&AccessTarget) -> bool) -> Vec<GrantEntry> {
+        let entries = self.entries.lock().unwrap();
+        entries.iter().filter(|e| f(&e.target)).cloned().collect()
+    }
+
+    /// Record that a target was granted access.
+    ///
+    /// A [`GrantOrigin::Granted`] entry replaces any existing entry for the
+    /// same target. A [`GrantOrigin::Reconciled`] entry is only added if there
+    /// is none yet, since the existing one knows more about the grant.
+    pub fn record_granted(
+        &self,
+        target: AccessTarget,
+        operator: Option<Operator>,
+        origin: GrantOrigin,
+    ) {
+        let mut entries = self.entries.lock().unwrap();
+        let existing = entries.iter().position(|e| e.target == target);
+        if existing.is_some() && origin == GrantOrigin::Reconciled {
+            return;
+        }
+        if let Some(i) = existing {
+            entries.remove(i);
+        }
+        entries.push(GrantEntry {
+            target,
+            time: self.clock.now(),
+            operator,
+            origin,
+        });
+    }
+
+    /// Record that a target's access was revoked, returning its entry.
+    pub fn record_revoked(&self, target: &AccessTarget) -> Option<GrantEntry> {
+        let mut entries = self.entries.lock().unwrap();
+        let i = entries.iter().position(|e| e.target == *target)?;
+        Some(entries.remove(i))
+    }
+
+    /// Grant a target access and record it once the server confirms.
+    pub async fn grant(
+        &self,
+        conn_tx: &ConnTx,
+        target: AccessTarget,
+        operator: Option<Operator>,
+    ) -> conn::Result<GrantOutcome> {
+        let result = conn_tx.send(GrantAccess::new(target.clone())).await;
+        match result {
+            Ok(_) => {
+                self.record_granted(target, operator, GrantOrigin::Granted);
+                Ok(GrantOutcome::Granted)
+            }
+            Err(err) if err.access_error_reason() == Some(AccessErrorReason::AlreadyGranted) => {
+                self.record_granted(target, None, GrantOrigin::Reconciled);
+                Ok(GrantOutcome::AlreadyGranted)
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Revoke a target's access and record it once the server confirms.
+    pub async fn revoke(
+        &self,
+        conn_tx: &ConnTx,
+        target: AccessTarget,
+    ) -> conn::Result<RevokeOutcome> {
+        let result = conn_tx.send(RevokeAccess::new(target.clone())).await;
+        match result {
+            Ok(_) => {
+                self.record_revoked(&target);
+                Ok(RevokeOutcome::Revoked)
+            }
+            Err(err) if err.access_error_reason() == Some(AccessErrorReason::NotGranted) => {
+                self.record_revoked(&target);
+                Ok(RevokeOutcome::NotGranted)
+            }
+            Err(err) => Err(err),
+        }
+    }
+}
+
+impl From<Vec<GrantEntry>> for GrantLedger {
+    fn from(entries: Vec<GrantEntry>) -> Self {
+        Self {
+            entries: Arc::new(Mutex::new(entries)),
+            clock: SystemClock::shared(),
+        }
+    }
+}
+
+impl From<GrantLedger> for Vec<GrantEntry> {
+    fn from(ledger: GrantLedger) -> Self {
+        ledger.entries()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+    use std::time::Duration;
+
+    use serde_json::json;
+
+    use crate::api::{AccessTarget, AccountId, Snowflake, UserId};
+    use crate::clock::MockClock;
+    use crate::conn::test::{connect, hello};
+    use crate::conn::{ConnTx, State};
+
+    use super::{GrantLedger, GrantOrigin, GrantOutcome, Operator, RevokeOutcome};
+
+    /// Connect to a server that answers access commands, returning the given
+    /// error for a command and target or succeeding otherwise.
+    async fn serve(errors: HashMap<(&'static str, &'static str), &'static str>) -> ConnTx {
+        let (mut conn, mut server) = connect(Duration::from_secs(10)).await;
+        server.join(hello(false, None)).await;
+        while let State::Joining(_) = conn.state() {
+            conn.recv().await.unwrap();
+        }
+        let conn_tx = conn.tx().clone();
+        tokio::spawn(async move { while conn.recv().await.is_ok() {} });
+        tokio::spawn(async move {
+            while let Some(cmd) = server.recv().await {
+                let r#type = cmd["type"].as_str().unwrap();
+                let target = match (&cmd["data"]["account_id"], &cmd["data"]["passcode"]) {
+                    (id, _) if id.is_string() => id.as_str().unwrap(),
+                    (_, passcode) => passcode.as_str().unwrap(),
+                };
+                let reply_type = format!("{type}-reply");
+                let reply = match errors
+                    .iter()
+                    .find(|((t, n), _)| *t == r#type && *n == target)
+                {
+                    Some((_, error)) => {
+                        json!({ "id": cmd["id"], "type": reply_type, "error": error })
+                    }
+                    None => json!({ "id": cmd["id"], "type": reply_type, "data": {} }),
+                };
+                server.send(reply).await;
+            }
+        });
+        conn_tx
+    }
+
+    fn account(id: u64) -> AccessTarget {
+        AccessTarget::Account(AccountId(Snowflake(id)))
+    }
+
+    fn operator() -> Operator {
+        Operator {
+            id: UserId("account:op".to_string()),
+            name: "op".to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn grant_and_revoke() {
+        let errors = HashMap::from([
+            (("grant-access", "0000000000002"), "access already granted"),
+            (("revoke-access", "0000000000003"), "capability not found"),
+            (("grant-access", "0000000000004"), "access denied"),
+        ]);
+        let conn_tx = serve(errors).await;
+        let ledger = GrantLedger::new();
+
+        let outcome = ledger.grant(&conn_tx, account(1), Some(operator())).await;
+        assert_eq!(outcome.unwrap(), GrantOutcome::Granted);
+        let passcode = AccessTarget::Passcode("hunter2".to_string());
+        let outcome = ledger.grant(&conn_tx, passcode.clone(), None).await;
+        assert_eq!(outcome.unwrap(), GrantOutcome::Granted);
+
+        // The server knows better
+        let outcome = ledger.grant(&conn_tx, account(2), Some(operator())).await;
+        assert_eq!(outcome.unwrap(), GrantOutcome::AlreadyGranted);
+        let outcome = ledger.revoke(&conn_tx, account(3)).await;
+        assert_eq!(outcome.unwrap(), RevokeOutcome::NotGranted);
+
+        // Other errors leave the ledger alone
+        ledger.grant(&conn_tx, account(4), None).await.unwrap_err();
+
+        assert!(ledger.has_account(AccountId(Snowflake(1))));
+        assert!(ledger.has_passcode("hunter2"));
+        let entry = ledger.get(&account(1)).unwrap();
+        assert_eq!(entry.operator, Some(operator()));
+        assert_eq!(entry.origin, GrantOrigin::Granted);
+        let entry = ledger.get(&account(2)).unwrap();
+        assert_eq!(entry.operator, None);
+        assert_eq!(entry.origin, GrantOrigin::Reconciled);
+        assert!(ledger.get(&account(3)).is_none());
+        assert!(ledger.get(&account(4)).is_none());
+
+        let accounts = ledger.accounts();
+        let accounts = accounts.iter().map(|e| &e.target).collect::<Vec<_>>();
+        assert_eq!(accounts, [&account(1), &account(2)]);
+        assert_eq!(ledger.passcodes().len(), 1);
+
+        // Reconciling doesn't overwrite what we know
+        let outcome = ledger.grant(&conn_tx, account(2), None).await;
+        assert_eq!(outcome.unwrap(), GrantOutcome::AlreadyGranted);
+        ledger.record_granted(account(1), None, GrantOrigin::Reconciled);
+        assert_eq!(ledger.get(&account(1)).unwrap().operator, Some(operator()));
+
+        let outcome = ledger.revoke(&conn_tx, account(1)).await;
+        assert_eq!(outcome.unwrap(), RevokeOutcome::Revoked);
+        let outcome = ledger.revoke(&conn_tx, passcode).await;
+        assert_eq!(outcome.unwrap(), RevokeOutcome::Revoked);
+        let targets = ledger.entries().into_iter().map(|e| e.target);
+        assert_eq!(targets.collect::<Vec<_>>(), [account(2)]);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn export() {
+        let ledger = GrantLedger::new();
+        ledger.record_granted(account(1), Some(operator()), GrantOrigin::Granted);
+        ledger.record_granted(
+            AccessTarget::Passcode("hunter2".to_string()),
+            None,
+            GrantOrigin::Reconciled,
+        );
+
+        let json = serde_json::to_value(&ledger).unwrap();
+        assert_eq!(json[0]["target"], json!({ "account": "0000000000001" }));
+        assert_eq!(json[0]["operator"]["name"], "op");
+        assert_eq!(json[1]["target"], json!({ "passcode": "hunter2" }));
+        assert_eq!(json[1]["origin"], "Reconciled");
+
+        let restored = serde_json::from_value::<GrantLedger>(json).unwrap();
+        assert_eq!(restored.entries(), ledger.entries());
+    }
+
+    #[test]
+    fn entries_use_clock_and_hide_passcodes() {
+        let time = "2024-01-01T12:00:00Z".parse().unwrap();
+        let clock = MockClock::at(time);
+        let ledger = GrantLedger::new().with_clock(clock.shared());
+
+        let passcode = AccessTarget::Passcode("hunter2".to_string());
+        ledger.record_granted(passcode, Some(operator()), GrantOrigin::Granted);
+        clock.advance(Duration::from_secs(60));
+        ledger.record_granted(account(1), None, GrantOrigin::Reconciled);
+
+        let times = ledger.entries().into_iter().map(|e| e.time.to_string());
+        assert_eq!(
+            times.collect::<Vec<_>>(),
+            ["2024-01-01T12:00:00Z", "2024-01-01T12:01:00Z"]
+        );
+
+        let debug = format!("{ledger:?}");
+        assert!(!debug.contains("hunter2"));
+        assert!(debug.contains("Passcode(<hidden>)"));
+        assert!(debug.contains(&format!("{:?}", account(1))));
+    }
+}
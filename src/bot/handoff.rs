@@ -0,0 +1,823 @@
+//! Handing a bot's nick over from one connection to another.
+//!
+//! When deploying a new version of a bot, the new process should take over the
+//! old one's presence without the room seeing two bots with the same nick. A
+//! [`Handoff`] sequences this in four [`HandoffStep`]s:
+//!
+//! 1. **Request:** The old party is asked to hand over its nick and must agree.
+//! 2. **Park:** The old party renames itself to a parking nick.
+//! 3. **Claim:** The new party renames itself to the real nick.
+//! 4. **Release:** The old party disconnects.
+//!
+//! Every step must finish within the handoff's timeout. If the park or claim
+//! step fails, the handoff is rolled back by renaming the old party back to the
+//! real nick.
+//!
+//! The parties are anything implementing [`HandoffParty`]. If both connections
+//! live in the same process, [`Instance`] can be used directly. Otherwise, one
+//! side must be represented by a party that forwards each step to the other
+//! process.
+//!
+//! # In-room protocol
+//!
+//! For the common case of a new process taking over from an old one in the same
+//! room, the steps can be forwarded as messages in the room itself. The old
+//! process adds a [`HandoffCommand`] to its commands, and the new process
+//! represents the old one as a [`RoomParty`]:
+//!
+//! - `!handoff request <nick>` asks the bot currently named `<nick>` to hand
+//!   over its nick. Only this message is addressed by nick, the following ones
+//!   are only accepted from the session that sent the request.
+//! - `!handoff rename <nick>` renames the old bot.
+//! - `!handoff release` makes the old bot leave the room.
+//!
+//! The old bot answers each message with a reply of `!handoff ok`,
+//! `!handoff refused <reason>` or `!handoff failed <reason>`. Renaming the old
+//! bot via the protocol also changes its [`Instance::username`], so the old bot
+//! keeps its parking nick if it reconnects before being released.
+
+use std::collections::HashSet;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::{error, fmt};
+
+use async_trait::async_trait;
+use tokio::time;
+
+use crate::api::{self, Message, Nick, SessionId, UserId};
+use crate::conn::{self, ConnTx};
+
+use super::command::{Command, Context, Conversations, HasInstance, Info};
+use super::instance::Instance;
+
+/// The prefix of all messages of the [in-room protocol](self#in-room-protocol).
+pub const PROTOCOL_PREFIX: &str = "!handoff";
+
+/// One side of a [`Handoff`].
+#[async_trait]
+pub trait HandoffParty: Send + Sync {
+    /// Ask the party to hand over its nick.
+    ///
+    /// Only called on the old party. By default, the party always agrees.
+    async fn request(&self) -> Result<(), HandoffFailure> {
+        Ok(())
+    }
+
+    /// Change the party's nick.
+    async fn rename(&self, nick: &str) -> Result<(), HandoffFailure>;
+
+    /// Disconnect the party from the room.
+    ///
+    /// Only called on the old party, once the new party holds the nick.
+    async fn release(&self) -> Result<(), HandoffFailure>;
+}
+
+#[async_trait]
+impl HandoffParty for Instance {
+    async fn rename(&self, nick: &str) -> Result<(), HandoffFailure> {
+        let conn_tx = self
+            .conn_tx()
+            .await
+            .ok_or(HandoffFailure::Conn(conn::Error::ConnectionClosed))?;
+        let name = nick.to_string();
+        conn_tx.send(Nick { name }).await?;
+        // Otherwise, the nick refresh or a reconnect would reclaim the old nick
+        self.set_username(Some(nick));
+        Ok(())
+    }
+
+    async fn release(&self) -> Result<(), HandoffFailure> {
+        self.leave(None);
+        Ok(())
+    }
+}
+
+/// A step of a [`Handoff`], in the order they are performed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HandoffStep {
+    /// The old party is asked to hand over its nick.
+    Request,
+    /// The old party renames itself to the parking nick.
+    Park,
+    /// The new party renames itself to the real nick.
+    Claim,
+    /// The old party disconnects.
+    Release,
+}
+
+impl fmt::Display for HandoffStep {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Request => write!(f, "request"),
+            Self::Park => write!(f, "park"),
+            Self::Claim => write!(f, "claim"),
+            Self::Release => write!(f, "release"),
+        }
+    }
+}
+
+/// Why a single step of a [`Handoff`] failed.
+#[derive(Debug)]
+pub enum HandoffFailure {
+    /// The old party refused to hand over its nick.
+    Refused(String),
+    /// The step did not finish within the handoff's timeout.
+    TimedOut,
+    /// A command failed.
+    Conn(conn::Error),
+    /// The party forwarded the step to another process, which failed to
+    /// perform it or gave an answer that couldn't be understood.
+    Remote(String),
+}
+
+impl fmt::Display for HandoffFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Refused(reason) => write!(f, "refused: {reason}"),
+            Self::TimedOut => write!(f, "timed out"),
+            Self::Conn(error) => write!(f, "{error}"),
+            Self::Remote(reason) => write!(f, "failed remotely: {reason}"),
+        }
+    }
+}
+
+impl error::Error for HandoffFailure {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            Self::Conn(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl From<conn::Error> for HandoffFailure {
+    fn from(error: conn::Error) -> Self {
+        Self::Conn(error)
+    }
+}
+
+/// Progress of a [`Handoff`], reported as it happens.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HandoffEvent {
+    /// A step is about to be performed.
+    Started(HandoffStep),
+    /// A step was performed successfully.
+    Finished(HandoffStep),
+    /// A step failed. The reason is part of the returned [`HandoffError`].
+    Failed(HandoffStep),
+    /// The old party was renamed back to the real nick after a failure.
+    RolledBack,
+    /// Renaming the old party back to the real nick failed.
+    RollbackFailed,
+}
+
+/// Why a [`Handoff`] failed.
+#[derive(Debug)]
+pub struct HandoffError {
+    /// The step that failed.
+    pub step: HandoffStep,
+    /// Why the step failed.
+    pub failure: HandoffFailure,
+    /// Why rolling back failed, if it did.
+    pub rollback: Option<HandoffFailure>,
+}
+
+impl HandoffError {
+    /// Whether the new party holds the nick despite the error.
+    ///
+    /// This is the case if only releasing the old party failed.
+    pub fn handed_over(&self) -> bool {
+        self.step == HandoffStep::Release
+    }
+}
+
+impl fmt::Display for HandoffError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "handoff failed at {} step: {}", self.step, self.failure)?;
+        match &self.rollback {
+            Some(failure) => write!(f, " (failed to roll back: {failure})"),
+            None => Ok(()),
+        }
+    }
+}
+
+impl error::Error for HandoffError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        Some(&self.failure)
+    }
+}
+
+/// Hands a nick over from an old party to a new party.
+///
+/// See the [module documentation](self) for the steps involved.
+#[derive(Debug, Clone)]
+pub struct Handoff {
+    nick: String,
+    parking_nick: String,
+    timeout: Duration,
+}
+
+impl Handoff {
+    /// Hand over `nick`, parking the old party as `"<nick> (leaving)"`.
+    pub fn new<S: ToString>(nick: S) -> Self {
+        let nick = nick.to_string();
+        Self {
+            parking_nick: format!("{nick} (leaving)"),
+            nick,
+            timeout: Duration::from_secs(10),
+        }
+    }
+
+    /// The nick the old party uses while the new party claims the real nick.
+    pub fn with_parking_nick<S: ToString>(mut self, parking_nick: S) -> Self {
+        self.parking_nick = parking_nick.to_string();
+        self
+    }
+
+    /// How long each step may take (default: 10 seconds).
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Perform the handoff, reporting progress to `on_event`.
+    ///
+    /// If the park or claim step fails, the old party is renamed back to the
+    /// real nick before returning. The new party's nick is left as is. If the
+    /// claim step timed out, the server may still have renamed the new party,
+    /// so both parties may end up with the real nick.
+    ///
+    /// If only the release step fails, the new party already holds the nick and
+    /// nothing is rolled back. See [`HandoffError::handed_over`].
+    pub async fn run<O, N, F>(&self, old: &O, new: &N, mut on_event: F) -> Result<(), HandoffError>
+    where
+        O: HandoffParty + ?Sized,
+        N: HandoffParty + ?Sized,
+        F: FnMut(HandoffEvent),
+    {
+        for step in [
+            HandoffStep::Request,
+            HandoffStep::Park,
+            HandoffStep::Claim,
+            HandoffStep::Release,
+        ] {
+            on_event(HandoffEvent::Started(step));
+            let result = match step {
+                HandoffStep::Request => self.limit(old.request()).await,
+                HandoffStep::Park => self.limit(old.rename(&self.parking_nick)).await,
+                HandoffStep::Claim => self.limit(new.rename(&self.nick)).await,
+                HandoffStep::Release => self.limit(old.release()).await,
+            };
+            let failure = match result {
+                Ok(()) => {
+                    on_event(HandoffEvent::Finished(step));
+                    continue;
+                }
+                Err(failure) => failure,
+            };
+
+            on_event(HandoffEvent::Failed(step));
+            let rollback = match step {
+                HandoffStep::Park | HandoffStep::Claim => {
+                    match self.limit(old.rename(&self.nick)).await {
+                        Ok(()) => {
+                            on_event(HandoffEvent::RolledBack);
+                            None
+                        }
+                        Err(failure) => {
+                            on_event(HandoffEvent::RollbackFailed);
+                            Some(failure)
+                        }
+                    }
+                }
+                HandoffStep::Request | HandoffStep::Release => None,
+            };
+            return Err(HandoffError {
+                step,
+                failure,
+                rollback,
+            });
+        }
+        Ok(())
+    }
+
+    async fn limit<F>(&self, step: F) -> Result<(), HandoffFailure>
+    where
+        F: std::future::Future<Output = Result<(), HandoffFailure>>,
+    {
+        match time::timeout(self.timeout, step).await {
+            Ok(result) => result,
+            Err(_) => Err(HandoffFailure::TimedOut),
+        }
+    }
+}
+
+/// Hand `nick` over from `old` to `new`, giving each step `timeout`.
+///
+/// Shorthand for [`Handoff::run`] without progress reports.
+pub async fn initiate<O, N>(
+    old: &O,
+    new: &N,
+    nick: &str,
+    timeout: Duration,
+) -> Result<(), HandoffError>
+where
+    O: HandoffParty + ?Sized,
+    N: HandoffParty + ?Sized,
+{
+    Handoff::new(nick)
+        .with_timeout(timeout)
+        .run(old, new, |_| {})
+        .await
+}
+
+/// The old side of the [in-room protocol](self#in-room-protocol).
+///
+/// Performs the steps requested by a [`RoomParty`] on the [`Instance`] the
+/// command is executed in, as returned by [`HasInstance::instance`]. Must be
+/// added to the commands as is, i.e. not wrapped in a
+/// [`General`](super::command::General), since it parses the message content
+/// itself. It has no [`Info`] and is thus not listed in any help.
+///
+/// Only messages sent by the bot's own user or one of the trusted users are
+/// accepted. Everything else is ignored, i.e. the command returns `false`.
+pub struct HandoffCommand {
+    trusted: HashSet<UserId>,
+    /// The session that requested the current handoff, if any.
+    active: Mutex<Option<SessionId>>,
+}
+
+impl HandoffCommand {
+    /// Accept requests from the bot's own user and from `trusted`.
+    ///
+    /// The bot's own user is only sufficient if the new process shares its
+    /// account or cookies with the old one.
+    pub fn new<I: IntoIterator<Item = UserId>>(trusted: I) -> Self {
+        Self {
+            trusted: trusted.into_iter().collect(),
+            active: Mutex::new(None),
+        }
+    }
+
+    fn is_trusted(&self, msg: &Message, ctx: &Context) -> bool {
+        msg.sender.id == ctx.joined.session.id || self.trusted.contains(&msg.sender.id)
+    }
+
+    fn is_active(&self, msg: &Message) -> bool {
+        self.active.lock().unwrap().as_ref() == Some(&msg.sender.session_id)
+    }
+}
+
+impl fmt::Debug for HandoffCommand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("HandoffCommand")
+            .field("trusted", &self.trusted.len())
+            .field("active", &self.active.lock().unwrap())
+            .finish()
+    }
+}
+
+/// Format the answer to a step of the in-room protocol.
+fn answer(result: &Result<(), HandoffFailure>) -> String {
+    match result {
+        Ok(()) => format!("{PROTOCOL_PREFIX} ok"),
+        Err(HandoffFailure::Refused(reason)) => format!("{PROTOCOL_PREFIX} refused {reason}"),
+        Err(failure) => format!("{PROTOCOL_PREFIX} failed {failure}"),
+    }
+}
+
+/// Parse the answer to a step of the in-room protocol.
+#[allow(clippy::result_large_err)]
+fn parse_answer(content: &str) -> Result<(), HandoffFailure> {
+    let rest = match content.strip_prefix(PROTOCOL_PREFIX) {
+        Some(rest) => rest.trim(),
+        None => {
+            return Err(HandoffFailure::Remote(format!(
+                "unexpected answer: {content}"
+            )))
+        }
+    };
+    let (answer, reason) = rest.split_once(' ').unwrap_or((rest, ""));
+    match answer {
+        "ok" => Ok(()),
+        "refused" => Err(HandoffFailure::Refused(reason.to_string())),
+        "failed" => Err(HandoffFailure::Remote(reason.to_string())),
+        _ => Err(HandoffFailure::Remote(format!(
+            "unexpected answer: {content}"
+        ))),
+    }
+}
+
+#[async_trait]
+impl<B, E> Command<B, E> for HandoffCommand
+where
+    B: HasInstance + Send,
+    E: From<conn::Error>,
+{
+    fn info(&self, _ctx: &Context) -> Info {
+        Info::default()
+    }
+
+    async fn execute(
+        &self,
+        _arg: &str,
+        msg: &Message,
+        ctx: &Context,
+        bot: &mut B,
+    ) -> Result<bool, E> {
+        let rest = match msg.content.strip_prefix(PROTOCOL_PREFIX) {
+            Some(rest) if rest.starts_with(' ') => rest.trim(),
+            _ => return Ok(false),
+        };
+        if !self.is_trusted(msg, ctx) {
+            return Ok(false);
+        }
+        let (step, arg) = rest.split_once(' ').unwrap_or((rest, ""));
+        let instance = bot.instance(&ctx.config);
+
+        let result = match (step, instance) {
+            ("request", _) if arg != ctx.joined.session.name => return Ok(false),
+            ("request", None) => Err(HandoffFailure::Refused("instance unknown".to_string())),
+            ("request", Some(instance)) => {
+                let result = instance.request().await;
+                if result.is_ok() {
+                    *self.active.lock().unwrap() = Some(msg.sender.session_id.clone());
+                }
+                result
+            }
+            ("rename" | "release", _) if !self.is_active(msg) => return Ok(false),
+            ("rename", Some(instance)) => instance.rename(arg).await,
+            ("release", Some(instance)) => {
+                // Once released, the answer could no longer be sent
+                ctx.send_raw(Some(msg.id), answer(&Ok(()))).await?;
+                *self.active.lock().unwrap() = None;
+                if let Err(failure) = instance.release().await {
+                    log::warn!("Failed to release handoff: {failure}");
+                }
+                return Ok(true);
+            }
+            ("rename" | "release", None) => {
+                Err(HandoffFailure::Remote("instance unknown".to_string()))
+            }
+            _ => return Ok(false),
+        };
+
+        ctx.send_raw(Some(msg.id), answer(&result)).await?;
+        Ok(true)
+    }
+}
+
+/// The old side of a [`Handoff`], represented via the
+/// [in-room protocol](self#in-room-protocol).
+///
+/// Sends each step as a message in the room and waits for the old bot's
+/// [`HandoffCommand`] to answer it. The answers are received via
+/// [`Conversations`], so the new bot's
+/// [`Commands`](super::commands::Commands) must be handling the room's
+/// messages during the handoff.
+#[derive(Debug, Clone)]
+pub struct RoomParty {
+    conn_tx: ConnTx,
+    conversations: Conversations,
+    nick: String,
+    user: UserId,
+}
+
+impl RoomParty {
+    /// Represent the bot named `nick` whose messages are sent by `user`.
+    ///
+    /// The conversations are usually those of the new bot's commands, see
+    /// [`Commands::conversations`](super::commands::Commands::conversations).
+    pub fn new<S: ToString>(
+        conn_tx: ConnTx,
+        conversations: Conversations,
+        nick: S,
+        user: UserId,
+    ) -> Self {
+        Self {
+            conn_tx,
+            conversations,
+            nick: nick.to_string(),
+            user,
+        }
+    }
+
+    async fn step(&self, content: String) -> Result<(), HandoffFailure> {
+        let cmd = api::Send {
+            content,
+            parent: None,
+        };
+        let question = self.conn_tx.send(cmd).await?.0;
+        let mut waiter = self.conversations.wait_for(question.id, self.user.clone());
+        match (&mut waiter.rx).await {
+            Ok(answer) => parse_answer(&answer.content),
+            Err(_) => Err(conn::Error::ConnectionClosed.into()),
+        }
+    }
+}
+
+#[async_trait]
+impl HandoffParty for RoomParty {
+    async fn request(&self) -> Result<(), HandoffFailure> {
+        let content = format!("{PROTOCOL_PREFIX} request {}", self.nick);
+        self.step(content).await
+    }
+
+    async fn rename(&self, nick: &str) -> Result<(), HandoffFailure> {
+        self.step(format!("{PROTOCOL_PREFIX} rename {nick}")).await
+    }
+
+    async fn release(&self) -> Result<(), HandoffFailure> {
+        self.step(format!("{PROTOCOL_PREFIX} release")).await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    use async_trait::async_trait;
+
+    use crate::conn;
+
+    use super::{
+        answer, initiate, parse_answer, Handoff, HandoffError, HandoffEvent, HandoffFailure,
+        HandoffParty, HandoffStep,
+    };
+
+    type Log = Arc<Mutex<Vec<String>>>;
+
+    enum Behavior {
+        Succeed,
+        Fail,
+        Hang,
+    }
+
+    /// A party that logs every call as `name action` and behaves according to
+    /// a script, falling back to succeeding.
+    struct Mock {
+        name: &'static str,
+        log: Log,
+        script: Mutex<Vec<(&'static str, Behavior)>>,
+    }
+
+    impl Mock {
+        fn new(name: &'static str, log: &Log) -> Self {
+            Self {
+                name,
+                log: log.clone(),
+                script: Mutex::new(vec![]),
+            }
+        }
+
+        fn on(self, action: &'static str, behavior: Behavior) -> Self {
+            self.script.lock().unwrap().push((action, behavior));
+            self
+        }
+
+        async fn act(&self, action: String) -> Result<(), HandoffFailure> {
+            self.log
+                .lock()
+                .unwrap()
+                .push(format!("{} {action}", self.name));
+            let behavior = {
+                let mut script = self.script.lock().unwrap();
+                match script.iter().position(|(a, _)| *a == action) {
+                    Some(i) => script.remove(i).1,
+                    None => Behavior::Succeed,
+                }
+            };
+            match behavior {
+                Behavior::Succeed => Ok(()),
+                Behavior::Fail if action == "request" => {
+                    Err(HandoffFailure::Refused("busy".to_string()))
+                }
+                Behavior::Fail => Err(HandoffFailure::Conn(conn::Error::ConnectionClosed)),
+                Behavior::Hang => std::future::pending().await,
+            }
+        }
+    }
+
+    #[async_trait]
+    impl HandoffParty for Mock {
+        async fn request(&self) -> Result<(), HandoffFailure> {
+            self.act("request".to_string()).await
+        }
+
+        async fn rename(&self, nick: &str) -> Result<(), HandoffFailure> {
+            self.act(format!("nick {nick}")).await
+        }
+
+        async fn release(&self) -> Result<(), HandoffFailure> {
+            self.act("release".to_string()).await
+        }
+    }
+
+    async fn run(old: &Mock, new: &Mock) -> (Result<(), HandoffError>, Vec<HandoffEvent>) {
+        let mut events = vec![];
+        let result = Handoff::new("Bot")
+            .with_parking_nick("Bot-old")
+            .with_timeout(Duration::from_secs(5))
+            .run(old, new, |e| events.push(e))
+            .await;
+        (result, events)
+    }
+
+    fn take(log: &Log) -> Vec<String> {
+        std::mem::take(&mut *log.lock().unwrap())
+    }
+
+    #[tokio::test]
+    async fn successful_handoff() {
+        let log = Log::default();
+        let (old, new) = (Mock::new("old", &log), Mock::new("new", &log));
+
+        let (result, events) = run(&old, &new).await;
+        result.unwrap();
+        assert_eq!(
+            take(&log),
+            [
+                "old request",
+                "old nick Bot-old",
+                "new nick Bot",
+                "old release"
+            ]
+        );
+        use HandoffEvent::*;
+        use HandoffStep::*;
+        assert_eq!(
+            events,
+            [
+                Started(Request),
+                Finished(Request),
+                Started(Park),
+                Finished(Park),
+                Started(Claim),
+                Finished(Claim),
+                Started(Release),
+                Finished(Release),
+            ]
+        );
+
+        initiate(&old, &new, "Bot", Duration::from_secs(5))
+            .await
+            .unwrap();
+        assert_eq!(
+            take(&log),
+            [
+                "old request",
+                "old nick Bot (leaving)",
+                "new nick Bot",
+                "old release"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn refused_request_changes_nothing() {
+        let log = Log::default();
+        let old = Mock::new("old", &log).on("request", Behavior::Fail);
+        let new = Mock::new("new", &log);
+
+        let (result, events) = run(&old, &new).await;
+        let err = result.unwrap_err();
+        assert_eq!(err.step, HandoffStep::Request);
+        assert!(err.rollback.is_none());
+        assert!(!err.handed_over());
+        assert_eq!(
+            err.to_string(),
+            "handoff failed at request step: refused: busy"
+        );
+        assert_eq!(take(&log), ["old request"]);
+        assert_eq!(
+            events.last(),
+            Some(&HandoffEvent::Failed(HandoffStep::Request))
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn timeouts_roll_back() {
+        let log = Log::default();
+
+        // The old party never confirms parking
+        let old = Mock::new("old", &log).on("nick Bot-old", Behavior::Hang);
+        let new = Mock::new("new", &log);
+        let (result, events) = run(&old, &new).await;
+        let err = result.unwrap_err();
+        assert_eq!(err.step, HandoffStep::Park);
+        assert!(matches!(err.failure, HandoffFailure::TimedOut));
+        assert!(err.rollback.is_none());
+        assert_eq!(
+            take(&log),
+            ["old request", "old nick Bot-old", "old nick Bot"]
+        );
+        assert_eq!(
+            events[events.len() - 2..],
+            [
+                HandoffEvent::Failed(HandoffStep::Park),
+                HandoffEvent::RolledBack
+            ]
+        );
+
+        // The new party never confirms claiming, and the old one is gone too
+        let old = Mock::new("old", &log).on("nick Bot", Behavior::Hang);
+        let new = Mock::new("new", &log).on("nick Bot", Behavior::Hang);
+        let (result, events) = run(&old, &new).await;
+        let err = result.unwrap_err();
+        assert_eq!(err.step, HandoffStep::Claim);
+        assert!(matches!(err.rollback, Some(HandoffFailure::TimedOut)));
+        assert_eq!(
+            err.to_string(),
+            "handoff failed at claim step: timed out (failed to roll back: timed out)"
+        );
+        assert_eq!(
+            take(&log),
+            [
+                "old request",
+                "old nick Bot-old",
+                "new nick Bot",
+                "old nick Bot"
+            ]
+        );
+        assert_eq!(events.last(), Some(&HandoffEvent::RollbackFailed));
+    }
+
+    #[tokio::test]
+    async fn failed_claim_rolls_back() {
+        let log = Log::default();
+        let old = Mock::new("old", &log);
+        let new = Mock::new("new", &log).on("nick Bot", Behavior::Fail);
+
+        let (result, _) = run(&old, &new).await;
+        let err = result.unwrap_err();
+        assert_eq!(err.step, HandoffStep::Claim);
+        assert!(matches!(
+            err.failure,
+            HandoffFailure::Conn(conn::Error::ConnectionClosed)
+        ));
+        assert!(err.rollback.is_none());
+        assert_eq!(
+            take(&log),
+            [
+                "old request",
+                "old nick Bot-old",
+                "new nick Bot",
+                "old nick Bot"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn failed_release_keeps_new_nick() {
+        let log = Log::default();
+        let old = Mock::new("old", &log).on("release", Behavior::Fail);
+        let new = Mock::new("new", &log);
+
+        let (result, events) = run(&old, &new).await;
+        let err = result.unwrap_err();
+        assert_eq!(err.step, HandoffStep::Release);
+        assert!(err.handed_over());
+        assert_eq!(
+            take(&log),
+            [
+                "old request",
+                "old nick Bot-old",
+                "new nick Bot",
+                "old release"
+            ]
+        );
+        assert_eq!(
+            events.last(),
+            Some(&HandoffEvent::Failed(HandoffStep::Release))
+        );
+    }
+
+    #[test]
+    fn protocol_answers_round_trip() {
+        assert_eq!(answer(&Ok(())), "!handoff ok");
+        parse_answer("!handoff ok").unwrap();
+
+        let refused = Err(HandoffFailure::Refused("busy right now".to_string()));
+        assert_eq!(answer(&refused), "!handoff refused busy right now");
+        assert!(matches!(
+            parse_answer("!handoff refused busy right now"),
+            Err(HandoffFailure::Refused(reason)) if reason == "busy right now"
+        ));
+
+        let failed = Err(HandoffFailure::Conn(conn::Error::ConnectionClosed));
+        let content = answer(&failed);
+        assert!(content.starts_with("!handoff failed "));
+        assert!(matches!(
+            parse_answer(&content),
+            Err(HandoffFailure::Remote(reason)) if reason == conn::Error::ConnectionClosed.to_string()
+        ));
+
+        for content in ["!handoff maybe", "ok"] {
+            assert!(matches!(
+                parse_answer(content),
+                Err(HandoffFailure::Remote(reason)) if reason.starts_with("unexpected answer")
+            ));
+        }
+    }
+}
@@ -2,21 +2,67 @@
 //!
 //! See [`Instance`] for more details.
 
+mod backoff;
+mod data_stream;
+mod duplicates;
+mod gap;
+mod governor;
+mod missed;
+mod nick_refresh;
+mod outbox;
+mod pipeline;
+mod population;
+mod schedule;
+
+use std::collections::VecDeque;
 use std::convert::Infallible;
-use std::fmt;
+use std::future::{self, Future};
+use std::io;
 use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex};
 use std::time::Duration;
+use std::{error, fmt};
 
 use cookie::{Cookie, CookieJar};
+use jiff::Timestamp;
 use tokio::select;
-use tokio::sync::{mpsc, oneshot};
+use tokio::sync::{mpsc, oneshot, watch, Notify};
+use tokio::time::MissedTickBehavior;
 use tokio_tungstenite::tungstenite;
 use tokio_tungstenite::tungstenite::http::{HeaderValue, StatusCode};
+use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+use tokio_tungstenite::tungstenite::protocol::CloseFrame;
+
+use crate::api::packet::{PacketSeq, ParsedPacket};
+use crate::api::{
+    self, Auth, AuthOption, Data, DisconnectReason, HelloEvent, Nick, PmId, PmInitiateEvent,
+};
+use crate::clock::{Clock, SystemClock};
+use crate::conn::{
+    self, Conn, ConnPhase, ConnStatus, ConnTx, ConnectTimings, MalformedPolicy, MessageTimesConfig,
+    ParentCheck, PermissionCheck, SendRate, SlowMode, State,
+};
+
+use super::sequence::{self, SequenceReport, SequenceStep};
+
+pub use self::data_stream::DataStream;
+pub use self::duplicates::{other_instances, DuplicatePolicy};
+pub use self::gap::GapReport;
+pub use self::governor::{ConnectGovernor, ConnectLimits, ConnectPermit};
+pub use self::missed::MissedMessages;
+pub use self::nick_refresh::NickRefreshMode;
+pub use self::outbox::{FileOutbox, Outbox, PendingSend};
+pub use self::pipeline::{PipelineReport, PipelineStage};
+pub use self::population::PopulationSample;
+pub use self::schedule::{LateSchedules, ScheduleHandle, Scheduled};
 
-use crate::api::packet::ParsedPacket;
-use crate::api::{Auth, AuthOption, Data, Nick};
-use crate::conn::{self, Conn, ConnTx, State};
+use self::backoff::Backoff;
+use self::gap::GapTracker;
+use self::missed::MISSED_MESSAGES_LOG_LEN;
+use self::nick_refresh::NickRefresher;
+use self::population::PopulationHistory;
+use self::schedule::Schedules;
 
 macro_rules! ilog {
     ( $conf:expr, $target:expr, $($arg:tt)+ ) => {
@@ -48,6 +94,22 @@ macro_rules! iwarn {
 
 /// Settings that are usually shared between all instances connecting to a
 /// specific server.
+///
+/// # Example
+///
+/// ```
+/// use std::time::Duration;
+///
+/// use euphoxide::bot::instance::ServerConfig;
+/// use euphoxide::conn::SlowMode;
+///
+/// let config = ServerConfig::default()
+///     .with_timeout(Duration::from_secs(10))
+///     .with_slow_mode(SlowMode::new())
+///     .with_message_log_opt(None);
+/// assert_eq!(config.timeout, Duration::from_secs(10));
+/// assert!(config.slow_mode.is_some());
+/// ```
 #[derive(Clone)]
 pub struct ServerConfig {
     /// How long to wait for the server until an operation is considered timed
@@ -59,35 +121,191 @@ pub struct ServerConfig {
     pub timeout: Duration,
     /// How long to wait until reconnecting after an unsuccessful attempt to
     /// connect.
+    ///
+    /// The delay doubles with every consecutive unsuccessful attempt, up to
+    /// [`Self::reconnect_delay_max`], and is reset once the instance has joined
+    /// its room.
     pub reconnect_delay: Duration,
+    /// The longest delay between two unsuccessful attempts to connect (default:
+    /// 5 minutes).
+    pub reconnect_delay_max: Duration,
+    /// Which fraction of the reconnect delay may be randomly cut off, between
+    /// `0.0` and `1.0` (default: `0.2`).
+    ///
+    /// This spreads out the attempts of instances that lost their connection
+    /// at the same time, for example because the server restarted.
+    pub reconnect_jitter: f64,
     /// Domain name, to be used with [`Conn::connect`].
     pub domain: String,
+    /// Whether to connect via TLS (default: `true`).
+    ///
+    /// If disabled, instances connect via [`Conn::connect_insecure`] instead.
+    /// Euphoria servers only accept TLS connections, so this is mostly useful
+    /// for testing against local servers.
+    pub tls: bool,
     /// Cookies to use when connecting. They are updated with the server's reply
     /// after successful connection attempts.
     pub cookies: Arc<Mutex<CookieJar>>,
+    /// What to do when receiving a packet that can't be deserialized.
+    ///
+    /// See [`Conn::set_on_malformed`] for more details.
+    pub on_malformed: MalformedPolicy,
+    /// How to comply with the slow mode of rooms, if at all.
+    ///
+    /// See [`Conn::set_slow_mode`] for more details.
+    pub slow_mode: Option<SlowMode>,
+    /// The budget for commands sent by instances, if any.
+    ///
+    /// See [`Conn::set_send_rate`] for more details.
+    pub send_rate: Option<SendRate>,
+    /// Whether to check that replies are sent to messages of the same room.
+    ///
+    /// See [`ParentCheck`] for more details.
+    pub parent_check: ParentCheck,
+    /// Whether to check outgoing commands against the session's permissions.
+    ///
+    /// See [`PermissionCheck`] for more details.
+    pub permission_check: PermissionCheck,
+    /// Whether and how to track when other sessions sent messages.
+    ///
+    /// See [`Conn::set_message_times`] for more details.
+    pub message_times: Option<MessageTimesConfig>,
+    /// How many of the room's most recent messages to track, if any.
+    ///
+    /// See [`Conn::set_message_log`] for more details.
+    pub message_log: Option<usize>,
+    /// Limits on connection attempts shared by all instances using this
+    /// config, if any.
+    ///
+    /// See [`ConnectGovernor`] for more details.
+    pub connect_governor: Option<ConnectGovernor>,
+    /// Where instances and their connections get the current time from.
+    ///
+    /// See [`Conn::set_clock`] for more details.
+    pub clock: Arc<dyn Clock>,
 }
 
 impl ServerConfig {
-    pub fn timeout(mut self, timeout: Duration) -> Self {
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
         self.timeout = timeout;
         self
     }
 
-    pub fn reconnect_delay(mut self, reconnect_delay: Duration) -> Self {
+    #[deprecated(note = "use `with_timeout` instead")]
+    pub fn timeout(self, timeout: Duration) -> Self {
+        self.with_timeout(timeout)
+    }
+
+    pub fn with_reconnect_delay(mut self, reconnect_delay: Duration) -> Self {
         self.reconnect_delay = reconnect_delay;
         self
     }
 
-    pub fn domain<S: ToString>(mut self, domain: S) -> Self {
+    #[deprecated(note = "use `with_reconnect_delay` instead")]
+    pub fn reconnect_delay(self, reconnect_delay: Duration) -> Self {
+        self.with_reconnect_delay(reconnect_delay)
+    }
+
+    pub fn with_reconnect_delay_max(mut self, reconnect_delay_max: Duration) -> Self {
+        self.reconnect_delay_max = reconnect_delay_max;
+        self
+    }
+
+    pub fn with_reconnect_jitter(mut self, reconnect_jitter: f64) -> Self {
+        self.reconnect_jitter = reconnect_jitter;
+        self
+    }
+
+    pub fn with_domain<S: ToString>(mut self, domain: S) -> Self {
         self.domain = domain.to_string();
         self
     }
 
-    pub fn cookies(mut self, cookies: Arc<Mutex<CookieJar>>) -> Self {
+    #[deprecated(note = "use `with_domain` instead")]
+    pub fn domain<S: ToString>(self, domain: S) -> Self {
+        self.with_domain(domain)
+    }
+
+    pub fn with_tls(mut self, tls: bool) -> Self {
+        self.tls = tls;
+        self
+    }
+
+    pub fn with_cookies(mut self, cookies: Arc<Mutex<CookieJar>>) -> Self {
         self.cookies = cookies;
         self
     }
 
+    #[deprecated(note = "use `with_cookies` instead")]
+    pub fn cookies(self, cookies: Arc<Mutex<CookieJar>>) -> Self {
+        self.with_cookies(cookies)
+    }
+
+    pub fn with_on_malformed(mut self, on_malformed: MalformedPolicy) -> Self {
+        self.on_malformed = on_malformed;
+        self
+    }
+
+    pub fn with_slow_mode(self, slow_mode: SlowMode) -> Self {
+        self.with_slow_mode_opt(Some(slow_mode))
+    }
+
+    pub fn with_slow_mode_opt(mut self, slow_mode: Option<SlowMode>) -> Self {
+        self.slow_mode = slow_mode;
+        self
+    }
+
+    pub fn with_send_rate(self, send_rate: SendRate) -> Self {
+        self.with_send_rate_opt(Some(send_rate))
+    }
+
+    pub fn with_send_rate_opt(mut self, send_rate: Option<SendRate>) -> Self {
+        self.send_rate = send_rate;
+        self
+    }
+
+    pub fn with_parent_check(mut self, parent_check: ParentCheck) -> Self {
+        self.parent_check = parent_check;
+        self
+    }
+
+    pub fn with_permission_check(mut self, permission_check: PermissionCheck) -> Self {
+        self.permission_check = permission_check;
+        self
+    }
+
+    pub fn with_message_times(self, message_times: MessageTimesConfig) -> Self {
+        self.with_message_times_opt(Some(message_times))
+    }
+
+    pub fn with_message_times_opt(mut self, message_times: Option<MessageTimesConfig>) -> Self {
+        self.message_times = message_times;
+        self
+    }
+
+    pub fn with_message_log(self, message_log: usize) -> Self {
+        self.with_message_log_opt(Some(message_log))
+    }
+
+    pub fn with_message_log_opt(mut self, message_log: Option<usize>) -> Self {
+        self.message_log = message_log;
+        self
+    }
+
+    pub fn with_connect_governor(self, connect_governor: ConnectGovernor) -> Self {
+        self.with_connect_governor_opt(Some(connect_governor))
+    }
+
+    pub fn with_connect_governor_opt(mut self, connect_governor: Option<ConnectGovernor>) -> Self {
+        self.connect_governor = connect_governor;
+        self
+    }
+
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
     pub fn room<S: ToString>(self, room: S) -> InstanceConfig {
         InstanceConfig::new(self, room)
     }
@@ -98,8 +316,20 @@ impl Default for ServerConfig {
         Self {
             timeout: Duration::from_secs(30),
             reconnect_delay: Duration::from_secs(30),
+            reconnect_delay_max: Duration::from_secs(5 * 60),
+            reconnect_jitter: 0.2,
             domain: "euphoria.leet.nu".to_string(),
+            tls: true,
             cookies: Arc::new(Mutex::new(CookieJar::new())),
+            on_malformed: MalformedPolicy::default(),
+            slow_mode: None,
+            send_rate: None,
+            parent_check: ParentCheck::default(),
+            permission_check: PermissionCheck::default(),
+            message_times: None,
+            message_log: None,
+            connect_governor: None,
+            clock: SystemClock::shared(),
         }
     }
 }
@@ -117,14 +347,80 @@ impl fmt::Debug for ServerConfig {
         f.debug_struct("ServerConfig")
             .field("timeout", &self.timeout)
             .field("reconnect_delay", &self.reconnect_delay)
+            .field("reconnect_delay_max", &self.reconnect_delay_max)
+            .field("reconnect_jitter", &self.reconnect_jitter)
             .field("domain", &self.domain)
+            .field("tls", &self.tls)
             .field("cookies", &Hidden)
+            .field("on_malformed", &self.on_malformed)
+            .field("slow_mode", &self.slow_mode)
+            .field("send_rate", &self.send_rate)
+            .field("parent_check", &self.parent_check)
+            .field("permission_check", &self.permission_check)
+            .field("message_times", &self.message_times)
+            .field("message_log", &self.message_log)
+            .field("connect_governor", &self.connect_governor)
+            .field("clock", &self.clock)
             .finish()
     }
 }
 
+/// A problem with an [`InstanceConfig`] that would prevent the instance from
+/// working as intended.
+///
+/// See [`InstanceConfig::validate`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConfigError {
+    /// [`ServerConfig::domain`] is empty.
+    EmptyDomain,
+    /// [`ServerConfig::timeout`] is zero.
+    ZeroTimeout,
+    /// [`ServerConfig::reconnect_jitter`] is not between `0.0` and `1.0`.
+    InvalidReconnectJitter,
+    /// [`InstanceConfig::room`] is empty.
+    EmptyRoom,
+    /// [`InstanceConfig::username`] is set, but empty.
+    EmptyUsername,
+    /// [`InstanceConfig::force_username`] is set, but there is no
+    /// [`InstanceConfig::username`].
+    ForceUsernameWithoutUsername,
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::EmptyDomain => write!(f, "server domain must not be empty"),
+            Self::ZeroTimeout => write!(f, "server timeout must not be zero"),
+            Self::InvalidReconnectJitter => {
+                write!(f, "reconnect jitter must be between 0.0 and 1.0")
+            }
+            Self::EmptyRoom => write!(f, "room must not be empty"),
+            Self::EmptyUsername => write!(f, "username must not be empty if set"),
+            Self::ForceUsernameWithoutUsername => {
+                write!(f, "force_username requires a username to be set")
+            }
+        }
+    }
+}
+
+impl error::Error for ConfigError {}
+
 /// Settings that are usually specific to a single instance.
-#[derive(Debug, Clone)]
+///
+/// # Example
+///
+/// ```
+/// use euphoxide::bot::instance::ServerConfig;
+///
+/// let config = ServerConfig::default()
+///     .room("test")
+///     .with_username("TestBot")
+///     .with_password_opt(None::<String>)
+///     .with_human(false);
+/// assert_eq!(config.username.as_deref(), Some("TestBot"));
+/// assert_eq!(config.password, None);
+/// ```
+#[derive(Clone)]
 pub struct InstanceConfig {
     pub server: ServerConfig,
     /// Unique name of this instance.
@@ -140,6 +436,129 @@ pub struct InstanceConfig {
     pub force_username: bool,
     /// Password to use if room requires authentication.
     pub password: Option<String>,
+    /// What to do with scheduled messages that became due while the instance
+    /// was not connected to its room.
+    pub late_schedules: LateSchedules,
+    /// How often to sample the room's population, if at all.
+    ///
+    /// The samples can be retrieved via [`Instance::population_samples`] and
+    /// are also emitted as [`Event::PopulationSample`]s.
+    pub population_sampling: Option<Duration>,
+    /// Additionally sample the room's population whenever the number of
+    /// sessions changed by at least this much since the last sample.
+    ///
+    /// Only has an effect if [`Self::population_sampling`] is set.
+    pub population_sampling_delta: Option<usize>,
+    /// Whether the instance should stop once nobody receives its events
+    /// anymore.
+    ///
+    /// Only has an effect for instances created via [`Instance::with_sender`].
+    pub stop_when_unobserved: bool,
+    /// Whether to wait for the server to confirm the nick set via
+    /// [`Self::username`] before emitting the snapshot-event.
+    ///
+    /// If enabled, the [`Event::Packet`] containing the
+    /// [`SnapshotEvent`](api::SnapshotEvent) is delayed until the
+    /// [`NickReply`](api::NickReply) arrives, so its [`ConnSnapshot`] already
+    /// contains the final nick. Packets received in the meantime are emitted
+    /// afterwards. If setting the nick fails or times out, the snapshot-event is
+    /// emitted anyways.
+    pub join_after_nick: bool,
+    /// Where to store messages sent via [`Instance::send_durable`] until the
+    /// server has replied to them.
+    pub outbox: Option<Arc<dyn Outbox>>,
+    /// Called with the tag and the message whenever a message is submitted via
+    /// [`Instance::send_durable`].
+    ///
+    /// Durable messages are sent at least once, but may be sent multiple times
+    /// if the process stops after sending a message but before receiving the
+    /// server's reply. This hook can be used to include the message's
+    /// [`PendingSend::tag`] in its content so duplicates can be detected.
+    pub tag_durable: Option<fn(&str, &mut api::Send)>,
+    /// How often to check whether the nick set via [`Self::username`] should
+    /// be sent again, if at all.
+    ///
+    /// The server may silently drop the nicks of sessions that have been idle
+    /// for a long time. See [`Self::nick_refresh_mode`] for when the nick is
+    /// sent again.
+    pub nick_refresh_interval: Option<Duration>,
+    /// Only has an effect if [`Self::nick_refresh_interval`] is set.
+    pub nick_refresh_mode: NickRefreshMode,
+    /// The nick is never sent again within this duration of the session's nick
+    /// changing or the nick being sent again.
+    ///
+    /// Only has an effect if [`Self::nick_refresh_interval`] is set.
+    pub nick_refresh_suppression: Duration,
+    /// What to do when other instances of the bot are in the room.
+    ///
+    /// See [`Event::Joined`] for how other instances are detected.
+    pub duplicate_policy: DuplicatePolicy,
+    /// How long to defer commands at most after joining a room.
+    ///
+    /// Only has an effect if [`Self::duplicate_policy`] is
+    /// [`DuplicatePolicy::Defer`].
+    pub duplicate_defer_timeout: Duration,
+    /// Whether to emit an [`Event::GapReport`] after reconnecting.
+    ///
+    /// The report is computed by comparing the last state before the
+    /// disconnect with the new [`SnapshotEvent`](api::SnapshotEvent). It is
+    /// emitted as a single event instead of individual join-, part- and
+    /// send-events so consumers relying on events happening live aren't
+    /// confused.
+    pub gap_reports: bool,
+    /// How often to check for messages the server didn't deliver, if at all.
+    ///
+    /// Each check fetches the 100 most recent messages of the room via
+    /// [`Log`](api::Log) and compares them with the messages the connection
+    /// received, as described in [`MissedMessages`]. If more messages are sent
+    /// between two checks, the older ones aren't compared. Messages that were never
+    /// received are emitted as an [`Event::MissedMessages`]. Checks can also be
+    /// triggered manually via [`Instance::check_missed_messages`].
+    pub missed_message_checks: Option<Duration>,
+}
+
+impl fmt::Debug for InstanceConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("InstanceConfig")
+            .field("server", &self.server)
+            .field("name", &self.name)
+            .field("room", &self.room)
+            .field("human", &self.human)
+            .field("username", &self.username)
+            .field("force_username", &self.force_username)
+            .field("password", &self.password.as_ref().map(|_| Hidden))
+            .field("late_schedules", &self.late_schedules)
+            .field("population_sampling", &self.population_sampling)
+            .field("population_sampling_delta", &self.population_sampling_delta)
+            .field("stop_when_unobserved", &self.stop_when_unobserved)
+            .field("join_after_nick", &self.join_after_nick)
+            .field("outbox", &self.outbox)
+            .field("tag_durable", &self.tag_durable)
+            .field("nick_refresh_interval", &self.nick_refresh_interval)
+            .field("nick_refresh_mode", &self.nick_refresh_mode)
+            .field("nick_refresh_suppression", &self.nick_refresh_suppression)
+            .field("duplicate_policy", &self.duplicate_policy)
+            .field("duplicate_defer_timeout", &self.duplicate_defer_timeout)
+            .field("gap_reports", &self.gap_reports)
+            .field("missed_message_checks", &self.missed_message_checks)
+            .finish()
+    }
+}
+
+/// Identifies the instance an [`Event`] came from.
+///
+/// Unlike the full [`InstanceConfig`], this is cheap to clone and contains no
+/// secrets. The full config can be retrieved via [`Instance::config`], e.g.
+/// using [`Instances::get`](super::instances::Instances::get) with the
+/// identity's name.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct InstanceIdentity {
+    /// See [`InstanceConfig::name`].
+    pub name: Arc<str>,
+    /// See [`InstanceConfig::room`].
+    pub room: Arc<str>,
+    /// See [`InstanceConfig::human`].
+    pub human: bool,
 }
 
 impl InstanceConfig {
@@ -152,34 +571,238 @@ impl InstanceConfig {
             username: None,
             force_username: false,
             password: None,
+            late_schedules: LateSchedules::default(),
+            population_sampling: None,
+            population_sampling_delta: None,
+            stop_when_unobserved: true,
+            join_after_nick: false,
+            outbox: None,
+            tag_durable: None,
+            nick_refresh_interval: None,
+            nick_refresh_mode: NickRefreshMode::default(),
+            nick_refresh_suppression: Duration::from_secs(5 * 60),
+            duplicate_policy: DuplicatePolicy::default(),
+            duplicate_defer_timeout: Duration::from_secs(60),
+            gap_reports: false,
+            missed_message_checks: None,
         }
     }
 
-    pub fn name<S: ToString>(mut self, name: S) -> Self {
+    pub fn with_name<S: ToString>(mut self, name: S) -> Self {
         self.name = name.to_string();
         self
     }
 
-    pub fn human(mut self, human: bool) -> Self {
+    #[deprecated(note = "use `with_name` instead")]
+    pub fn name<S: ToString>(self, name: S) -> Self {
+        self.with_name(name)
+    }
+
+    pub fn with_human(mut self, human: bool) -> Self {
         self.human = human;
         self
     }
 
-    pub fn username<S: ToString>(mut self, username: Option<S>) -> Self {
+    #[deprecated(note = "use `with_human` instead")]
+    pub fn human(self, human: bool) -> Self {
+        self.with_human(human)
+    }
+
+    pub fn with_username<S: ToString>(self, username: S) -> Self {
+        self.with_username_opt(Some(username))
+    }
+
+    pub fn with_username_opt<S: ToString>(mut self, username: Option<S>) -> Self {
         self.username = username.map(|s| s.to_string());
         self
     }
 
-    pub fn force_username(mut self, force_username: bool) -> Self {
+    #[deprecated(note = "use `with_username_opt` instead")]
+    pub fn username<S: ToString>(self, username: Option<S>) -> Self {
+        self.with_username_opt(username)
+    }
+
+    pub fn with_force_username(mut self, force_username: bool) -> Self {
         self.force_username = force_username;
         self
     }
 
-    pub fn password<S: ToString>(mut self, password: Option<S>) -> Self {
+    #[deprecated(note = "use `with_force_username` instead")]
+    pub fn force_username(self, force_username: bool) -> Self {
+        self.with_force_username(force_username)
+    }
+
+    pub fn with_password<S: ToString>(self, password: S) -> Self {
+        self.with_password_opt(Some(password))
+    }
+
+    pub fn with_password_opt<S: ToString>(mut self, password: Option<S>) -> Self {
         self.password = password.map(|s| s.to_string());
         self
     }
 
+    #[deprecated(note = "use `with_password_opt` instead")]
+    pub fn password<S: ToString>(self, password: Option<S>) -> Self {
+        self.with_password_opt(password)
+    }
+
+    pub fn with_late_schedules(mut self, late_schedules: LateSchedules) -> Self {
+        self.late_schedules = late_schedules;
+        self
+    }
+
+    pub fn with_population_sampling(self, population_sampling: Duration) -> Self {
+        self.with_population_sampling_opt(Some(population_sampling))
+    }
+
+    pub fn with_population_sampling_opt(mut self, population_sampling: Option<Duration>) -> Self {
+        self.population_sampling = population_sampling;
+        self
+    }
+
+    pub fn with_population_sampling_delta(self, population_sampling_delta: usize) -> Self {
+        self.with_population_sampling_delta_opt(Some(population_sampling_delta))
+    }
+
+    pub fn with_population_sampling_delta_opt(
+        mut self,
+        population_sampling_delta: Option<usize>,
+    ) -> Self {
+        self.population_sampling_delta = population_sampling_delta;
+        self
+    }
+
+    pub fn with_stop_when_unobserved(mut self, stop_when_unobserved: bool) -> Self {
+        self.stop_when_unobserved = stop_when_unobserved;
+        self
+    }
+
+    pub fn with_join_after_nick(mut self, join_after_nick: bool) -> Self {
+        self.join_after_nick = join_after_nick;
+        self
+    }
+
+    pub fn with_outbox(self, outbox: Arc<dyn Outbox>) -> Self {
+        self.with_outbox_opt(Some(outbox))
+    }
+
+    pub fn with_outbox_opt(mut self, outbox: Option<Arc<dyn Outbox>>) -> Self {
+        self.outbox = outbox;
+        self
+    }
+
+    pub fn with_tag_durable(self, tag_durable: fn(&str, &mut api::Send)) -> Self {
+        self.with_tag_durable_opt(Some(tag_durable))
+    }
+
+    pub fn with_tag_durable_opt(mut self, tag_durable: Option<fn(&str, &mut api::Send)>) -> Self {
+        self.tag_durable = tag_durable;
+        self
+    }
+
+    pub fn with_nick_refresh_interval(self, nick_refresh_interval: Duration) -> Self {
+        self.with_nick_refresh_interval_opt(Some(nick_refresh_interval))
+    }
+
+    pub fn with_nick_refresh_interval_opt(
+        mut self,
+        nick_refresh_interval: Option<Duration>,
+    ) -> Self {
+        self.nick_refresh_interval = nick_refresh_interval;
+        self
+    }
+
+    pub fn with_nick_refresh_mode(mut self, nick_refresh_mode: NickRefreshMode) -> Self {
+        self.nick_refresh_mode = nick_refresh_mode;
+        self
+    }
+
+    pub fn with_nick_refresh_suppression(mut self, nick_refresh_suppression: Duration) -> Self {
+        self.nick_refresh_suppression = nick_refresh_suppression;
+        self
+    }
+
+    pub fn with_duplicate_policy(mut self, duplicate_policy: DuplicatePolicy) -> Self {
+        self.duplicate_policy = duplicate_policy;
+        self
+    }
+
+    pub fn with_duplicate_defer_timeout(mut self, duplicate_defer_timeout: Duration) -> Self {
+        self.duplicate_defer_timeout = duplicate_defer_timeout;
+        self
+    }
+
+    pub fn with_gap_reports(mut self, gap_reports: bool) -> Self {
+        self.gap_reports = gap_reports;
+        self
+    }
+
+    pub fn with_missed_message_checks(self, missed_message_checks: Duration) -> Self {
+        self.with_missed_message_checks_opt(Some(missed_message_checks))
+    }
+
+    pub fn with_missed_message_checks_opt(
+        mut self,
+        missed_message_checks: Option<Duration>,
+    ) -> Self {
+        self.missed_message_checks = missed_message_checks;
+        self
+    }
+
+    /// A config for the private chat room with this id, derived from this
+    /// config.
+    ///
+    /// The private chat room is `pm:<pm_id>`, which is also used as the name.
+    /// All other settings are kept, except for the room password. Since the
+    /// [`ServerConfig`] is cloned, the cookie jar is shared with this config.
+    pub fn pm(&self, pm_id: PmId) -> Self {
+        let room = format!("pm:{}", pm_id.0);
+        Self {
+            name: room.clone(),
+            room,
+            password: None,
+            ..self.clone()
+        }
+    }
+
+    /// Whether commands should currently be deferred because other instances
+    /// of the bot are in the room.
+    ///
+    /// See [`DuplicatePolicy::Defer`] for more details.
+    pub fn defers_commands(&self, joined: &conn::Joined) -> bool {
+        duplicates::defers_commands(
+            self.duplicate_policy,
+            self.duplicate_defer_timeout,
+            joined,
+            self.username.as_deref(),
+            self.server.clock.now(),
+        )
+    }
+
+    /// Check the config for contradictory or missing settings.
+    ///
+    /// Returns the first problem found.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.server.domain.is_empty() {
+            return Err(ConfigError::EmptyDomain);
+        }
+        if self.server.timeout.is_zero() {
+            return Err(ConfigError::ZeroTimeout);
+        }
+        if !(0.0..=1.0).contains(&self.server.reconnect_jitter) {
+            return Err(ConfigError::InvalidReconnectJitter);
+        }
+        if self.room.is_empty() {
+            return Err(ConfigError::EmptyRoom);
+        }
+        match &self.username {
+            Some(username) if username.is_empty() => return Err(ConfigError::EmptyUsername),
+            None if self.force_username => return Err(ConfigError::ForceUsernameWithoutUsername),
+            _ => {}
+        }
+        Ok(())
+    }
+
     /// Create a new instance using this config.
     ///
     /// See [`Instance::new`] for more details.
@@ -189,20 +812,56 @@ impl InstanceConfig {
     {
         Instance::new(self, on_event)
     }
+
+    /// The identity events of an instance with this config carry.
+    pub fn identity(&self) -> InstanceIdentity {
+        InstanceIdentity {
+            name: self.name.as_str().into(),
+            room: self.room.as_str().into(),
+            human: self.human,
+        }
+    }
+
+    /// Create a new instance using this config that sends its events to a
+    /// channel.
+    ///
+    /// See [`Instance::with_sender`] for more details.
+    pub fn build_with_sender(self, event_tx: mpsc::UnboundedSender<Event>) -> Instance {
+        Instance::with_sender(self, event_tx)
+    }
+
+    /// Create a new instance using this config if the config is valid.
+    ///
+    /// See [`Instance::try_new`] for more details.
+    pub fn try_build<F>(self, on_event: F) -> Result<Instance, ConfigError>
+    where
+        F: Fn(Event) + Send + Sync + 'static,
+    {
+        Instance::try_new(self, on_event)
+    }
 }
 
 /// Snapshot of a [`Conn`]'s state immediately after receiving a packet.
 #[derive(Debug, Clone)]
 pub struct ConnSnapshot {
     pub conn_tx: ConnTx,
-    pub state: State,
+    /// The connection's state, shared between snapshots.
+    ///
+    /// Taking a snapshot doesn't clone the state. See [`Conn::shared_state`]
+    /// for more details.
+    pub state: Arc<State>,
+    /// How long establishing the connection took so far.
+    ///
+    /// See [`Conn::connect_timings`] for more details.
+    pub connect_timings: ConnectTimings,
 }
 
 impl ConnSnapshot {
     fn from_conn(conn: &Conn) -> Self {
         Self {
             conn_tx: conn.tx().clone(),
-            state: conn.state().clone(),
+            state: conn.shared_state(),
+            connect_timings: conn.connect_timings(),
         }
     }
 }
@@ -215,7 +874,7 @@ impl ConnSnapshot {
 /// Events are emitted by a single instance following this schema, written in
 /// pseudo-regex syntax:
 /// ```text
-/// (Connecting (Connected Packet*)? Disconnected)* Stopped
+/// (Connecting (Connected (DisconnectImminent? Packet | Joined GapReport? | PopulationSample | MissedMessages)*)? Disconnected)* Stopped
 /// ```
 ///
 /// In particular, this means that every [`Self::Connecting`] is always followed
@@ -223,61 +882,246 @@ impl ConnSnapshot {
 /// the last event and is always sent exactly once per instance.
 #[derive(Debug)]
 pub enum Event {
-    Connecting(InstanceConfig),
-    Connected(InstanceConfig, ConnSnapshot),
-    Packet(InstanceConfig, ParsedPacket, ConnSnapshot),
-    Disconnected(InstanceConfig),
-    Stopped(InstanceConfig),
+    Connecting(InstanceIdentity),
+    Connected(InstanceIdentity, ConnSnapshot),
+    /// The server announced that it is about to close the connection.
+    ///
+    /// This event is emitted immediately before the [`Self::Packet`] containing
+    /// the [`DisconnectEvent`](crate::api::DisconnectEvent). Since the event
+    /// handler is called synchronously, the connection is still open while it
+    /// runs. However, the server may close the connection at any time, so any
+    /// commands sent from the handler are sent on a best-effort basis.
+    DisconnectImminent(InstanceIdentity, DisconnectReason),
+    Packet(InstanceIdentity, ParsedPacket, ConnSnapshot),
+    /// The instance joined its room.
+    ///
+    /// This event is emitted immediately after the [`Self::Packet`] containing
+    /// the [`SnapshotEvent`](crate::api::SnapshotEvent). It contains the other
+    /// instances of the bot that were in the room at that time, as determined
+    /// by [`other_instances`]. What the instance does about them depends on
+    /// [`InstanceConfig::duplicate_policy`].
+    ///
+    /// Other instances are usually left over from a previous deployment that
+    /// hasn't shut down yet, or from the same bot running twice by accident.
+    Joined(InstanceIdentity, ConnSnapshot, Vec<api::SessionView>),
+    /// The room's population was sampled.
+    ///
+    /// Only emitted if [`InstanceConfig::population_sampling`] is set.
+    PopulationSample(InstanceIdentity, PopulationSample),
+    /// What happened in the room while the instance was reconnecting.
+    ///
+    /// Only emitted if [`InstanceConfig::gap_reports`] is set. It immediately
+    /// follows the [`Self::Joined`] of every connection except the first.
+    GapReport(InstanceIdentity, GapReport),
+    /// Messages the server sent to the room but never delivered to the current
+    /// connection, oldest first.
+    ///
+    /// See [`InstanceConfig::missed_message_checks`] for more details.
+    MissedMessages(InstanceIdentity, Vec<api::Message>),
+    Disconnected(InstanceIdentity),
+    Stopped(InstanceIdentity),
 }
 
 impl Event {
-    pub fn config(&self) -> &InstanceConfig {
+    /// The instance the event came from.
+    pub fn identity(&self) -> &InstanceIdentity {
         match self {
-            Self::Connecting(config) => config,
-            Self::Connected(config, _) => config,
-            Self::Packet(config, _, _) => config,
-            Self::Disconnected(config) => config,
-            Self::Stopped(config) => config,
+            Self::Connecting(identity) => identity,
+            Self::Connected(identity, _) => identity,
+            Self::DisconnectImminent(identity, _) => identity,
+            Self::Packet(identity, _, _) => identity,
+            Self::Joined(identity, _, _) => identity,
+            Self::PopulationSample(identity, _) => identity,
+            Self::GapReport(identity, _) => identity,
+            Self::MissedMessages(identity, _) => identity,
+            Self::Disconnected(identity) => identity,
+            Self::Stopped(identity) => identity,
         }
     }
-}
 
-enum Request {
-    GetConnTx(oneshot::Sender<ConnTx>),
-    Stop,
-}
+    /// The invitation to chat privately, if the event is a packet containing
+    /// one.
+    ///
+    /// See [`Instances::accept_pm`](super::instances::Instances::accept_pm) for
+    /// accepting the invitation.
+    pub fn pm_invitation(&self) -> Option<&PmInitiateEvent> {
+        match self {
+            Self::Packet(_, packet, _) => match &packet.content {
+                Ok(Data::PmInitiateEvent(event)) => Some(event),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
 
-/// An error that occurred inside an [`Instance`] while it was running.
-enum RunError {
-    StoppedManually,
-    InstanceDropped,
-    CouldNotConnect(conn::Error),
-    Conn(conn::Error),
+    fn packet_seq(&self) -> Option<PacketSeq> {
+        match self {
+            Self::Packet(_, packet, _) => packet.seq,
+            _ => None,
+        }
+    }
 }
 
-/// A single instance of a bot in a single room.
-///
-/// The instance automatically connects to its room once it is created, and it
-/// reconnects when it loses connection. If the room requires authentication and
-/// a password is given, the instance automatically authenticates. If a nick is
-/// given, the instance sets its nick upon joining the room.
-///
-/// An instance has a unique name used for logging and identifying the instance.
-/// The room name can be used as the instance name if there is never more than
-/// one instance per room.
-///
-/// An instance can be created using [`Instance::new`] or using
-/// [`InstanceConfig::build`].
+/// The server-side backend a connection of an [`Instance`] was attached to.
 ///
-/// An instance can be stopped using [`Instance::stop`] or by dropping it. In
-/// either case, the last event the instance sends will be an
-/// [`Event::Stopped`]. If it is not stopped using one of these two ways, it
-/// will continue to run and reconnect indefinitely.
+/// This is purely informational and can be useful when diagnosing server-side
+/// issues. See [`Instance::placement_history`] for more details.
 #[derive(Debug, Clone)]
-pub struct Instance {
-    config: InstanceConfig,
-    request_tx: mpsc::UnboundedSender<Request>,
-    // In theory, request_tx should be sufficient as canary, but I'm not sure
+pub struct Placement {
+    /// When the server greeted the connection with its hello-event.
+    pub connected: Timestamp,
+    /// The id of the server the connection was attached to.
+    pub server_id: String,
+    /// The era of the server the connection was attached to.
+    pub server_era: String,
+    /// When the connection was closed, if it has been closed already.
+    pub disconnected: Option<Timestamp>,
+    /// Why the connection was closed, if it has been closed already.
+    pub disconnect_cause: Option<String>,
+}
+
+/// How many [`Placement`]s an [`Instance`] remembers.
+const PLACEMENT_HISTORY_LEN: usize = 16;
+
+#[derive(Debug, Default)]
+struct PlacementHistory {
+    placements: VecDeque<Placement>,
+    connections: usize,
+}
+
+impl PlacementHistory {
+    fn on_hello(&mut self, hello: &HelloEvent, now: Timestamp) {
+        self.connections += 1;
+        if self.placements.len() >= PLACEMENT_HISTORY_LEN {
+            self.placements.pop_front();
+        }
+        self.placements.push_back(Placement {
+            connected: now,
+            server_id: hello.session.server_id.clone(),
+            server_era: hello.session.server_era.clone(),
+            disconnected: None,
+            disconnect_cause: None,
+        });
+    }
+
+    /// Record the cause of the current connection being closed.
+    ///
+    /// The first cause recorded for a connection wins, so the reason given in a
+    /// disconnect-event is not overwritten by the error that follows it.
+    fn on_disconnect_cause(&mut self, cause: String) {
+        if let Some(placement) = self.placements.back_mut() {
+            if placement.disconnected.is_none() && placement.disconnect_cause.is_none() {
+                placement.disconnect_cause = Some(cause);
+            }
+        }
+    }
+
+    fn on_disconnected(&mut self, cause: String, now: Timestamp) {
+        self.on_disconnect_cause(cause);
+        if let Some(placement) = self.placements.back_mut() {
+            if placement.disconnected.is_none() {
+                placement.disconnected = Some(now);
+            }
+        }
+    }
+}
+
+/// State shared between an [`Instance`] and its task.
+#[derive(Debug)]
+struct Shared {
+    placements: Mutex<PlacementHistory>,
+    schedules: Arc<Schedules>,
+    population: Mutex<PopulationHistory>,
+    outbox_changed: Notify,
+    pipeline: Mutex<PipelineReport>,
+    status: Arc<ConnStatus>,
+    /// See [`Instance::username`].
+    username: Mutex<Option<String>>,
+}
+
+impl Shared {
+    fn new(config: &InstanceConfig) -> Self {
+        Self {
+            placements: Mutex::new(PlacementHistory::default()),
+            schedules: Arc::new(Schedules::default()),
+            population: Mutex::new(PopulationHistory::default()),
+            outbox_changed: Notify::new(),
+            pipeline: Mutex::new(PipelineReport::default()),
+            status: Arc::new(ConnStatus::new()),
+            username: Mutex::new(config.username.clone()),
+        }
+    }
+
+    fn username(&self) -> Option<String> {
+        self.username.lock().unwrap().clone()
+    }
+}
+
+enum Request {
+    GetConnTx(oneshot::Sender<ConnTx>),
+    Stop,
+    StopGracefully(Duration),
+    Leave(Option<api::Send>),
+    CheckMissedMessages,
+}
+
+/// An error that occurred inside an [`Instance`] while it was running.
+enum RunError {
+    StoppedManually,
+    /// A [`Request::StopGracefully`] arrived while connected. The connection
+    /// has not been closed yet.
+    Stopping(Duration),
+    /// A [`Request::Leave`] arrived while connected. The goodbye has not been
+    /// sent yet.
+    Leaving(Option<api::Send>),
+    Left,
+    InstanceDropped,
+    CouldNotConnect(conn::Error),
+    Conn(conn::Error),
+}
+
+impl fmt::Display for RunError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::StoppedManually | Self::Stopping(_) => write!(f, "instance stopped manually"),
+            Self::Leaving(_) => write!(f, "instance leaving room"),
+            Self::Left => write!(f, "instance left room"),
+            Self::InstanceDropped => write!(f, "instance dropped"),
+            Self::CouldNotConnect(err) => write!(f, "failed to connect: {err}"),
+            Self::Conn(err) => write!(f, "{err}"),
+        }
+    }
+}
+
+/// A single instance of a bot in a single room.
+///
+/// The instance automatically connects to its room once it is created, and it
+/// reconnects when it loses connection. If the room requires authentication and
+/// a password is given, the instance automatically authenticates. If a nick is
+/// given, the instance sets its nick upon joining the room.
+///
+/// An instance has a unique name used for logging and identifying the instance.
+/// The room name can be used as the instance name if there is never more than
+/// one instance per room.
+///
+/// An instance can be created using [`Instance::new`] or using
+/// [`InstanceConfig::build`]. Alternatively, an instance sending its events to
+/// a channel can be created using [`Instance::with_sender`] or
+/// [`InstanceConfig::build_with_sender`].
+///
+/// An instance can be stopped using [`Instance::stop`],
+/// [`Instance::stop_gracefully`], [`Instance::leave`] or by dropping it. In
+/// any case, the last event the instance sends will be an [`Event::Stopped`].
+/// If it is not stopped using one of these ways, it will continue to run and
+/// reconnect indefinitely. Instances created with a
+/// channel also stop once the channel's receiver is dropped, unless
+/// [`InstanceConfig::stop_when_unobserved`] is disabled.
+#[derive(Debug, Clone)]
+pub struct Instance {
+    config: InstanceConfig,
+    shared: Arc<Shared>,
+    request_tx: mpsc::UnboundedSender<Request>,
+    // In theory, request_tx should be sufficient as canary, but I'm not sure
     // exactly how to check it during the reconnect timeout.
     _canary_tx: mpsc::UnboundedSender<Infallible>,
 }
@@ -304,8 +1148,75 @@ impl Instance {
     /// an [`Event`]. It must not block for long. See [`Event`] for more details
     /// on the events and the order in which they are emitted.
     ///
-    /// [`InstanceConfig::build`] can be used in place of this function.
+    /// [`InstanceConfig::build`] can be used in place of this function. The
+    /// config is not validated, see [`Self::try_new`] for that.
     pub fn new<F>(config: InstanceConfig, on_event: F) -> Self
+    where
+        F: Fn(Event) + Send + Sync + 'static,
+    {
+        let shared = Arc::new(Shared::new(&config));
+        let on_event = {
+            let shared = shared.clone();
+            move |event: Event| {
+                let seq = event.packet_seq();
+                on_event(event);
+                shared.pipeline.lock().unwrap().on_emitted(seq);
+            }
+        };
+        Self::spawn(config, shared, on_event, Arc::new(Notify::new()))
+    }
+
+    /// Create a new instance that sends its events to a channel.
+    ///
+    /// If the channel's receiver is dropped, the instance's events can no
+    /// longer be observed. The instance then logs a warning and, if
+    /// [`InstanceConfig::stop_when_unobserved`] is set, stops. Otherwise, it
+    /// keeps running and its events are discarded.
+    ///
+    /// [`InstanceConfig::build_with_sender`] can be used in place of this
+    /// function. The config is not validated.
+    pub fn with_sender(config: InstanceConfig, event_tx: mpsc::UnboundedSender<Event>) -> Self {
+        let unobserved = Arc::new(Notify::new());
+        let shared = Arc::new(Shared::new(&config));
+        let on_event =
+            Self::send_events(config.clone(), event_tx, unobserved.clone(), shared.clone());
+        Self::spawn(config, shared, on_event, unobserved)
+    }
+
+    fn send_events(
+        config: InstanceConfig,
+        event_tx: mpsc::UnboundedSender<Event>,
+        unobserved: Arc<Notify>,
+        shared: Arc<Shared>,
+    ) -> impl Fn(Event) + Send + Sync + 'static {
+        let warned = AtomicBool::new(false);
+        move |event| {
+            let seq = event.packet_seq();
+            if event_tx.send(event).is_ok() {
+                shared.pipeline.lock().unwrap().on_emitted(seq);
+                return;
+            }
+            if warned.swap(true, Ordering::Relaxed) {
+                return;
+            }
+            if config.stop_when_unobserved {
+                iwarn!(config, "Event receiver was dropped, stopping instance");
+                unobserved.notify_one();
+            } else {
+                iwarn!(
+                    config,
+                    "Event receiver was dropped, events will be discarded"
+                );
+            }
+        }
+    }
+
+    fn spawn<F>(
+        config: InstanceConfig,
+        shared: Arc<Shared>,
+        on_event: F,
+        unobserved: Arc<Notify>,
+    ) -> Self
     where
         F: Fn(Event) + Send + Sync + 'static,
     {
@@ -316,22 +1227,58 @@ impl Instance {
 
         tokio::spawn(Self::run::<F>(
             config.clone(),
+            shared.clone(),
             on_event,
             request_rx,
             canary_rx,
+            unobserved,
         ));
 
         Self {
             config,
+            shared,
             request_tx,
             _canary_tx: canary_tx,
         }
     }
 
+    /// Like [`Self::new`], but validates the config first.
+    ///
+    /// No instance is created if the config is invalid. See
+    /// [`InstanceConfig::validate`] for more details.
+    ///
+    /// [`InstanceConfig::try_build`] can be used in place of this function.
+    pub fn try_new<F>(config: InstanceConfig, on_event: F) -> Result<Self, ConfigError>
+    where
+        F: Fn(Event) + Send + Sync + 'static,
+    {
+        config.validate()?;
+        Ok(Self::new(config, on_event))
+    }
+
     pub fn config(&self) -> &InstanceConfig {
         &self.config
     }
 
+    /// The nick the instance sets when joining its room and refreshes.
+    ///
+    /// This starts out as [`InstanceConfig::username`] and can be changed via
+    /// [`Self::set_username`]. The config returned by [`Self::config`] is not
+    /// affected by such changes.
+    pub fn username(&self) -> Option<String> {
+        self.shared.username()
+    }
+
+    /// Change the nick the instance sets when joining its room and refreshes.
+    ///
+    /// This doesn't change the nick of the current session, only which nick
+    /// future sessions and [nick refreshes](InstanceConfig::nick_refresh_interval)
+    /// use. To change both, send a [`Nick`] command as well, as
+    /// [`HandoffParty::rename`](super::handoff::HandoffParty::rename) does.
+    pub fn set_username<S: ToString>(&self, username: Option<S>) {
+        *self.shared.username.lock().unwrap() = username.map(|s| s.to_string());
+    }
+
     /// Retrieve the instance's current connection.
     ///
     /// Returns `None` if the instance is currently not connected, or has
@@ -342,6 +1289,151 @@ impl Instance {
         rx.await.ok()
     }
 
+    /// Connection quality signals that can be read from any thread.
+    ///
+    /// The status is shared by all connections of the instance. See
+    /// [`ConnStatus`] for more details.
+    pub fn status(&self) -> &Arc<ConnStatus> {
+        &self.shared.status
+    }
+
+    /// Run a sequence of commands via the instance's current connection.
+    ///
+    /// Returns `None` if the instance is currently not connected. See
+    /// [`run_sequence`](super::sequence::run_sequence) for more details.
+    pub async fn run_sequence(&self, steps: Vec<SequenceStep>) -> Option<SequenceReport> {
+        let conn_tx = self.conn_tx().await?;
+        Some(sequence::run_sequence(&conn_tx, steps).await)
+    }
+
+    /// The server backends the instance's most recent connections were attached
+    /// to, oldest first.
+    ///
+    /// Only connections that received a hello-event are included. The history
+    /// is limited to the last few connections.
+    pub fn placement_history(&self) -> Vec<Placement> {
+        self.shared
+            .placements
+            .lock()
+            .unwrap()
+            .placements
+            .iter()
+            .cloned()
+            .collect()
+    }
+
+    /// How often the instance has connected to its room so far.
+    ///
+    /// Like with [`Self::placement_history`], only connections that received a
+    /// hello-event are counted.
+    pub fn connections(&self) -> usize {
+        self.shared.placements.lock().unwrap().connections
+    }
+
+    /// How far the packets of the instance's current connection got on their
+    /// way to the event handler.
+    ///
+    /// Useful for finding out where packets went missing. For the last stage to
+    /// be counted, the consumer must call [`Self::ack_delivered`].
+    pub fn pipeline_report(&self) -> PipelineReport {
+        *self.shared.pipeline.lock().unwrap()
+    }
+
+    /// Acknowledge that a packet emitted as [`Event::Packet`] was handled.
+    ///
+    /// See [`PipelineStage::Delivered`]. Packets from previous connections are
+    /// ignored.
+    pub fn ack_delivered(&self, packet: &ParsedPacket) {
+        self.shared
+            .pipeline
+            .lock()
+            .unwrap()
+            .on_delivered(packet.seq);
+    }
+
+    /// The most recent samples of the room's population, oldest first.
+    ///
+    /// Always empty unless [`InstanceConfig::population_sampling`] is set. The
+    /// history is limited to the last few samples.
+    pub fn population_samples(&self) -> Vec<PopulationSample> {
+        self.shared
+            .population
+            .lock()
+            .unwrap()
+            .0
+            .iter()
+            .cloned()
+            .collect()
+    }
+
+    /// Schedule a message to be sent at a specific time.
+    ///
+    /// The message is sent by the instance itself, so it is not affected by
+    /// reconnects. If it becomes due while the instance is not in its room,
+    /// [`InstanceConfig::late_schedules`] decides whether it is sent after the
+    /// instance has joined the room again.
+    ///
+    /// Messages that are still pending when the instance stops are never sent.
+    pub fn schedule(&self, at: Timestamp, send: api::Send) -> ScheduleHandle {
+        let id = self.shared.schedules.add(at, send);
+        ScheduleHandle {
+            id,
+            schedules: self.shared.schedules.clone(),
+        }
+    }
+
+    /// All messages scheduled via [`Self::schedule`] that have not yet been
+    /// sent, dropped or cancelled, ordered by when they are due.
+    pub fn scheduled(&self) -> Vec<Scheduled> {
+        self.shared.schedules.pending()
+    }
+
+    /// Cancel a scheduled message by its id.
+    ///
+    /// See also [`ScheduleHandle::cancel`].
+    pub fn cancel_scheduled(&self, id: u64) -> bool {
+        self.shared.schedules.cancel(id)
+    }
+
+    /// Send a message that is not lost if the instance or the whole process
+    /// stops before it could be sent.
+    ///
+    /// The message is pushed to [`InstanceConfig::outbox`] and sent by the
+    /// instance itself once it has joined its room. It is only removed from the
+    /// outbox once the server has replied to it. If the server rejects the
+    /// message, a warning is logged and the message is removed as well.
+    ///
+    /// This means that messages are sent at least once, but may be sent
+    /// multiple times, see [`InstanceConfig::tag_durable`].
+    ///
+    /// Returns the message's [`PendingSend::tag`]. Fails with
+    /// [`io::ErrorKind::Unsupported`] if no outbox is configured.
+    pub fn send_durable(&self, send: api::Send) -> io::Result<String> {
+        let outbox = match &self.config.outbox {
+            Some(outbox) => outbox,
+            None => return Err(io::Error::new(io::ErrorKind::Unsupported, "no outbox")),
+        };
+
+        let mut pending = PendingSend::new(send);
+        if let Some(tag_durable) = self.config.tag_durable {
+            tag_durable(&pending.tag, &mut pending.send);
+        }
+        let tag = pending.tag.clone();
+        outbox.push(pending)?;
+
+        self.shared.outbox_changed.notify_one();
+        Ok(tag)
+    }
+
+    /// Check for messages the server didn't deliver to the current connection.
+    ///
+    /// Missed messages are emitted as an [`Event::MissedMessages`]. Does
+    /// nothing if the instance is currently not connected. See
+    /// [`InstanceConfig::missed_message_checks`] for more details.
+    pub fn check_missed_messages(&self) {
+        let _ = self.request_tx.send(Request::CheckMissedMessages);
+    }
+
     /// Stop the instance.
     ///
     /// For more info on stopping instances, see [`Instance`].
@@ -349,6 +1441,59 @@ impl Instance {
         let _ = self.request_tx.send(Request::Stop);
     }
 
+    /// Stop the instance after the commands it has sent are done.
+    ///
+    /// Unlike [`Self::stop`], this doesn't drop the connection right away. If
+    /// the instance is connected, it stops emitting events, waits up to
+    /// `timeout` for queued commands to be sent and for pending replies to
+    /// arrive, and then closes the connection with a normal close frame (see
+    /// [`Conn::close`]). Afterwards, the instance emits [`Event::Disconnected`]
+    /// and [`Event::Stopped`] like it would when stopped. Commands sent via its
+    /// [`ConnTx`] after the request arrives fail with
+    /// [`conn::Error::ConnectionClosed`].
+    ///
+    /// The instance keeps running until it has stopped, even if it is dropped
+    /// in the meantime.
+    pub fn stop_gracefully(&self, timeout: Duration) {
+        if self
+            .request_tx
+            .send(Request::StopGracefully(timeout))
+            .is_err()
+        {
+            return;
+        }
+        self.keep_alive_until_stopped();
+    }
+
+    /// Leave the room gracefully and stop the instance.
+    ///
+    /// If a `goodbye` is given and the instance is connected, the goodbye
+    /// message is sent first and its reply awaited. The connection is then
+    /// closed with a normal close frame instead of being dropped. Afterwards,
+    /// the instance emits [`Event::Disconnected`] and [`Event::Stopped`] like
+    /// it would when stopped, and it doesn't reconnect.
+    ///
+    /// The instance keeps running until it has left, even if it is dropped in
+    /// the meantime.
+    pub fn leave(&self, goodbye: Option<api::Send>) {
+        if self.request_tx.send(Request::Leave(goodbye)).is_err() {
+            return;
+        }
+        self.keep_alive_until_stopped();
+    }
+
+    /// Keep the instance from stopping when it is dropped until it has stopped
+    /// on its own.
+    fn keep_alive_until_stopped(&self) {
+        // The instance stops once the canary is dropped
+        let request_tx = self.request_tx.clone();
+        let canary_tx = self._canary_tx.clone();
+        tokio::spawn(async move {
+            request_tx.closed().await;
+            drop(canary_tx);
+        });
+    }
+
     /// Whether this instance is stopped.
     ///
     /// For more info on stopping instances, see [`Instance`].
@@ -358,64 +1503,20 @@ impl Instance {
 
     async fn run<F: Fn(Event)>(
         config: InstanceConfig,
+        shared: Arc<Shared>,
         on_event: F,
         request_rx: mpsc::UnboundedReceiver<Request>,
         mut canary_rx: mpsc::UnboundedReceiver<Infallible>,
+        unobserved: Arc<Notify>,
     ) {
+        let on_event = GapTracker::wrap(&config, on_event);
+        let task = Task::new(&config, &shared, &on_event);
         select! {
-            _ = Self::stay_connected(&config, &on_event, request_rx) => (),
+            _ = task.stay_connected(request_rx) => (),
             _ = canary_rx.recv() => { idebug!(config, "Instance dropped"); },
+            _ = unobserved.notified() => { idebug!(config, "Instance unobserved"); },
         }
-        on_event(Event::Stopped(config))
-    }
-
-    async fn stay_connected<F: Fn(Event)>(
-        config: &InstanceConfig,
-        on_event: &F,
-        mut request_rx: mpsc::UnboundedReceiver<Request>,
-    ) {
-        loop {
-            idebug!(config, "Connecting...");
-
-            on_event(Event::Connecting(config.clone()));
-            let result = Self::run_once::<F>(config, on_event, &mut request_rx).await;
-            on_event(Event::Disconnected(config.clone()));
-
-            let connected = match result {
-                Ok(()) => {
-                    idebug!(config, "Connection closed normally");
-                    true
-                }
-                Err(RunError::StoppedManually) => {
-                    idebug!(config, "Instance stopped manually");
-                    break;
-                }
-                Err(RunError::InstanceDropped) => {
-                    idebug!(config, "Instance dropped");
-                    break;
-                }
-                Err(RunError::CouldNotConnect(conn::Error::Tungstenite(
-                    tungstenite::Error::Http(response),
-                ))) if response.status() == StatusCode::NOT_FOUND => {
-                    iwarn!(config, "Failed to connect: room does not exist");
-                    break;
-                }
-                Err(RunError::CouldNotConnect(err)) => {
-                    iwarn!(config, "Failed to connect: {err}");
-                    false
-                }
-                Err(RunError::Conn(err)) => {
-                    iwarn!(config, "An error occurred: {err}");
-                    true
-                }
-            };
-
-            if !connected {
-                let s = config.server.reconnect_delay.as_secs();
-                idebug!(config, "Waiting {s} seconds before reconnecting");
-                tokio::time::sleep(config.server.reconnect_delay).await;
-            }
-        }
+        on_event(Event::Stopped(task.identity))
     }
 
     fn get_cookies(config: &InstanceConfig) -> HeaderValue {
@@ -442,93 +1543,1926 @@ impl Instance {
         }
     }
 
-    async fn run_once<F: Fn(Event)>(
-        config: &InstanceConfig,
-        on_event: &F,
-        request_rx: &mut mpsc::UnboundedReceiver<Request>,
-    ) -> Result<(), RunError> {
-        let (mut conn, cookies) = Conn::connect(
-            &config.server.domain,
-            &config.room,
-            config.human,
-            Some(Self::get_cookies(config)),
-            config.server.timeout,
-        )
-        .await
-        .map_err(RunError::CouldNotConnect)?;
+    fn take_snapshot(conn: &Conn, state_tx: &watch::Sender<Arc<State>>) -> ConnSnapshot {
+        let snapshot = ConnSnapshot::from_conn(conn);
 
-        Self::set_cookies(config, cookies);
-        on_event(Event::Connected(
-            config.clone(),
-            ConnSnapshot::from_conn(&conn),
-        ));
+        // Only notify others if the state actually changed
+        state_tx.send_if_modified(|state| {
+            let changed = !Arc::ptr_eq(state, &snapshot.state);
+            *state = snapshot.state.clone();
+            changed
+        });
 
-        let conn_tx = conn.tx().clone();
-        select! {
-            r = Self::receive::<F>(config, &mut conn, on_event) => r,
-            r = Self::handle_requests(request_rx, &conn_tx) => Err(r),
-        }
+        snapshot
     }
 
-    async fn receive<F: Fn(Event)>(
-        config: &InstanceConfig,
-        conn: &mut Conn,
-        on_event: &F,
-    ) -> Result<(), RunError> {
+    /// Wait for a future, e.g. the [`ServerConfig::connect_governor`], while
+    /// still handling requests.
+    async fn while_handling_requests<T>(
+        future: impl Future<Output = T>,
+        request_rx: &mut mpsc::UnboundedReceiver<Request>,
+    ) -> Result<T, RunError> {
+        tokio::pin!(future);
         loop {
-            let packet = conn.recv().await.map_err(RunError::Conn)?;
-            let snapshot = ConnSnapshot::from_conn(conn);
-
-            match &packet.content {
-                Ok(Data::SnapshotEvent(snapshot)) => {
-                    if let Some(username) = &config.username {
-                        if config.force_username || snapshot.nick.is_none() {
-                            idebug!(config, "Setting nick to username {username}");
-                            let name = username.to_string();
-                            conn.tx().send_only(Nick { name });
-                        } else if let Some(nick) = &snapshot.nick {
-                            idebug!(config, "Not setting nick, already set to {nick}");
-                        }
-                    }
-                }
-                Ok(Data::BounceEvent(_)) => {
-                    if let Some(password) = &config.password {
-                        idebug!(config, "Authenticating with password");
-                        let cmd = Auth {
-                            r#type: AuthOption::Passcode,
-                            passcode: Some(password.to_string()),
-                        };
-                        conn.tx().send_only(cmd);
-                    } else {
-                        iwarn!(config, "Auth required but no password configured");
-                    }
-                }
-                Ok(Data::DisconnectEvent(ev)) => {
-                    if ev.reason == "authentication changed" {
-                        iinfo!(config, "Disconnected because {}", ev.reason);
-                    } else {
-                        iwarn!(config, "Disconnected because {}", ev.reason);
+            select! {
+                result = &mut future => break Ok(result),
+                request = request_rx.recv() => match request {
+                    // Dropping the sender makes conn_tx return None
+                    Some(Request::GetConnTx(_)) => {}
+                    // Not connected, so there is nothing to check
+                    Some(Request::CheckMissedMessages) => {}
+                    Some(Request::Stop | Request::StopGracefully(_)) => {
+                        break Err(RunError::StoppedManually)
                     }
-                }
-                _ => {}
+                    Some(Request::Leave(_)) => break Err(RunError::Left),
+                    None => break Err(RunError::InstanceDropped),
+                },
             }
-
-            on_event(Event::Packet(config.clone(), packet, snapshot));
         }
     }
 
     async fn handle_requests(
         request_rx: &mut mpsc::UnboundedReceiver<Request>,
         conn_tx: &ConnTx,
+        check_missed: &Notify,
     ) -> RunError {
         while let Some(request) = request_rx.recv().await {
             match request {
                 Request::GetConnTx(tx) => {
                     let _ = tx.send(conn_tx.clone());
                 }
+                Request::CheckMissedMessages => check_missed.notify_one(),
                 Request::Stop => return RunError::StoppedManually,
+                Request::StopGracefully(timeout) => return RunError::Stopping(timeout),
+                Request::Leave(goodbye) => return RunError::Leaving(goodbye),
             }
         }
         RunError::InstanceDropped
     }
 }
+
+/// The task running an [`Instance`].
+///
+/// Bundles what the functions making up the task need, so they don't have to
+/// pass it around individually.
+struct Task<'a, F> {
+    config: &'a InstanceConfig,
+    identity: InstanceIdentity,
+    shared: &'a Shared,
+    on_event: &'a F,
+}
+
+impl<'a, F: Fn(Event)> Task<'a, F> {
+    fn new(config: &'a InstanceConfig, shared: &'a Shared, on_event: &'a F) -> Self {
+        Self {
+            config,
+            identity: config.identity(),
+            shared,
+            on_event,
+        }
+    }
+
+    /// The same task, but emitting its events via `on_event`.
+    fn with_on_event<'b, G: Fn(Event)>(&'b self, on_event: &'b G) -> Task<'b, G> {
+        Task {
+            config: self.config,
+            identity: self.identity.clone(),
+            shared: self.shared,
+            on_event,
+        }
+    }
+
+    fn emit(&self, event: Event) {
+        (self.on_event)(event);
+    }
+
+    async fn stay_connected(&self, mut request_rx: mpsc::UnboundedReceiver<Request>) {
+        let mut backoff = Backoff::new();
+        let joined = AtomicBool::new(false);
+        let tracking = |event: Event| {
+            if let Event::Joined(..) = event {
+                joined.store(true, Ordering::Relaxed);
+            }
+            self.emit(event);
+        };
+        let tracked = self.with_on_event(&tracking);
+
+        loop {
+            idebug!(self.config, "Connecting...");
+
+            self.emit(Event::Connecting(self.identity.clone()));
+            self.shared.status.set_phase(ConnPhase::Connecting);
+            let result = tracked.run_once(&mut request_rx).await;
+            self.shared.status.set_phase(ConnPhase::Disconnected);
+            if joined.swap(false, Ordering::Relaxed) {
+                backoff.on_joined();
+            }
+            self.emit(Event::Disconnected(self.identity.clone()));
+
+            let cause = match &result {
+                Ok(()) => "connection closed normally".to_string(),
+                Err(err) => err.to_string(),
+            };
+            self.shared.status.set_last_error(cause.clone());
+            let now = self.config.server.clock.now();
+            self.shared
+                .placements
+                .lock()
+                .unwrap()
+                .on_disconnected(cause, now);
+
+            let connected = match result {
+                Ok(()) => {
+                    idebug!(self.config, "Connection closed normally");
+                    true
+                }
+                Err(RunError::StoppedManually | RunError::Stopping(_)) => {
+                    idebug!(self.config, "Instance stopped manually");
+                    break;
+                }
+                Err(RunError::Leaving(_) | RunError::Left) => {
+                    idebug!(self.config, "Instance left room");
+                    break;
+                }
+                Err(RunError::InstanceDropped) => {
+                    idebug!(self.config, "Instance dropped");
+                    break;
+                }
+                Err(RunError::CouldNotConnect(conn::Error::Tungstenite(
+                    tungstenite::Error::Http(response),
+                ))) if response.status() == StatusCode::NOT_FOUND => {
+                    iwarn!(self.config, "Failed to connect: room does not exist");
+                    break;
+                }
+                Err(RunError::CouldNotConnect(err)) => {
+                    iwarn!(self.config, "Failed to connect: {err}");
+                    false
+                }
+                Err(RunError::Conn(err)) => {
+                    iwarn!(self.config, "An error occurred: {err}");
+                    true
+                }
+            };
+
+            if !connected {
+                let delay = backoff.on_failure(
+                    self.config.server.reconnect_delay,
+                    self.config.server.reconnect_delay_max,
+                    self.config.server.reconnect_jitter,
+                );
+                let s = delay.as_secs_f64();
+                idebug!(self.config, "Waiting {s:.1} seconds before reconnecting");
+                let delay = tokio::time::sleep(delay);
+                if Instance::while_handling_requests(delay, &mut request_rx)
+                    .await
+                    .is_err()
+                {
+                    idebug!(self.config, "Instance stopped while waiting to reconnect");
+                    break;
+                }
+            }
+        }
+    }
+
+    async fn run_once(
+        &self,
+        request_rx: &mut mpsc::UnboundedReceiver<Request>,
+    ) -> Result<(), RunError> {
+        let permit = match &self.config.server.connect_governor {
+            Some(governor) => {
+                let acquire = governor.acquire();
+                Some(Instance::while_handling_requests(acquire, request_rx).await?)
+            }
+            None => None,
+        };
+        let (domain, room) = (&self.config.server.domain, &self.config.room);
+        let cookies = Some(Instance::get_cookies(self.config));
+        let timeout = self.config.server.timeout;
+        let connected = if self.config.server.tls {
+            Conn::connect(domain, room, self.config.human, cookies, timeout).await
+        } else {
+            Conn::connect_insecure(domain, room, self.config.human, cookies, timeout).await
+        };
+        let (mut conn, cookies) = connected.map_err(RunError::CouldNotConnect)?;
+        drop(permit);
+
+        Instance::set_cookies(self.config, cookies);
+        conn.set_on_malformed(self.config.server.on_malformed);
+        conn.set_slow_mode(self.config.server.slow_mode);
+        conn.set_send_rate(self.config.server.send_rate);
+        conn.set_parent_check(self.config.server.parent_check);
+        conn.set_permission_check(self.config.server.permission_check);
+        conn.set_message_times(self.config.server.message_times);
+        conn.set_message_log(self.config.server.message_log);
+        conn.set_clock(self.config.server.clock.clone());
+        conn.set_status(self.shared.status.clone());
+        self.shared
+            .pipeline
+            .lock()
+            .unwrap()
+            .on_connected(conn.generation());
+        idebug!(self.config, "Connected ({})", conn.connect_timings());
+        self.emit(Event::Connected(
+            self.identity.clone(),
+            ConnSnapshot::from_conn(&conn),
+        ));
+
+        let conn_tx = conn.tx().clone();
+        let (state_tx, state_rx) = watch::channel(conn.shared_state());
+        let missed = Mutex::new(MissedMessages::default());
+        let check_missed = Notify::new();
+        let observing = |event: Event| {
+            if let Event::Packet(_, packet, _) = &event {
+                missed.lock().unwrap().observe(packet);
+            }
+            self.emit(event);
+        };
+        let observed = self.with_on_event(&observing);
+        let result = select! {
+            r = observed.receive(&mut conn, &state_tx) => r,
+            r = Instance::handle_requests(request_rx, &conn_tx, &check_missed) => Err(r),
+            r = self.check_missed(&missed, &check_missed, &conn_tx) => match r {},
+            r = self.send_scheduled(&conn_tx, state_rx.clone()) => match r {},
+            r = self.send_outbox(&conn_tx, state_rx.clone()) => match r {},
+            r = self.refresh_nick(&conn_tx, state_rx.clone()) => match r {},
+            r = self.sample_population(state_rx) => match r {},
+        };
+
+        match result {
+            Err(RunError::Leaving(goodbye)) => {
+                // Even if leaving fails halfway, the instance must not reconnect
+                if let Err(err) = self.leave_room(conn, &state_tx, goodbye).await {
+                    iwarn!(self.config, "An error occurred while leaving: {err}");
+                }
+                Err(RunError::Left)
+            }
+            Err(RunError::Stopping(timeout)) => {
+                idebug!(self.config, "Waiting for pending commands before closing");
+                let close = CloseFrame {
+                    code: CloseCode::Normal,
+                    reason: "stopping".into(),
+                };
+                if let Err(err) = conn.close(timeout, close).await {
+                    iwarn!(self.config, "An error occurred while stopping: {err}");
+                }
+                Err(RunError::StoppedManually)
+            }
+            result => result,
+        }
+    }
+
+    /// Send the goodbye, if any, and close the connection once the server has
+    /// replied to it.
+    ///
+    /// Packets received while waiting for the reply are still emitted.
+    async fn leave_room(
+        &self,
+        mut conn: Conn,
+        state_tx: &watch::Sender<Arc<State>>,
+        goodbye: Option<api::Send>,
+    ) -> conn::Result<()> {
+        if let Some(goodbye) = goodbye {
+            idebug!(self.config, "Saying goodbye");
+            let reply = conn.tx().send(goodbye);
+            tokio::pin!(reply);
+            loop {
+                select! {
+                    biased;
+                    r = &mut reply => {
+                        if let Err(err) = r {
+                            iwarn!(self.config, "Failed to say goodbye: {err}");
+                        }
+                        break;
+                    }
+                    r = conn.recv() => {
+                        let packet = r?;
+                        self.shared.pipeline.lock().unwrap().on_parsed(packet.seq);
+                        let snapshot = Instance::take_snapshot(&conn, state_tx);
+                        self.emit_packet(packet, snapshot);
+                    }
+                }
+            }
+        }
+
+        let close = CloseFrame {
+            code: CloseCode::Normal,
+            reason: "leaving".into(),
+        };
+        conn.drain(Duration::ZERO, close).await
+    }
+
+    async fn receive(
+        &self,
+        conn: &mut Conn,
+        state_tx: &watch::Sender<Arc<State>>,
+    ) -> Result<(), RunError> {
+        loop {
+            let packet = conn.recv().await.map_err(RunError::Conn)?;
+            self.shared.pipeline.lock().unwrap().on_parsed(packet.seq);
+            let snapshot = Instance::take_snapshot(conn, state_tx);
+
+            match self.on_packet(conn.tx(), &packet) {
+                Some(nick) if self.config.join_after_nick => {
+                    self.emit_after_nick(conn, state_tx, nick, packet).await?;
+                }
+                Some(nick) => {
+                    let _ = conn.tx().send_only(nick);
+                    self.emit_packet(packet, snapshot);
+                }
+                None => self.emit_packet(packet, snapshot),
+            }
+        }
+    }
+
+    /// Emit a packet, followed by [`Event::Joined`] if it is the
+    /// snapshot-event.
+    fn emit_packet(&self, packet: ParsedPacket, snapshot: ConnSnapshot) {
+        let others = match (&packet.content, snapshot.state.joined()) {
+            (Ok(Data::SnapshotEvent(_)), Some(joined)) => {
+                other_instances(joined, self.shared.username().as_deref())
+            }
+            _ => {
+                self.emit(Event::Packet(self.identity.clone(), packet, snapshot));
+                return;
+            }
+        };
+
+        self.emit(Event::Packet(
+            self.identity.clone(),
+            packet,
+            snapshot.clone(),
+        ));
+        if !others.is_empty() {
+            let names = others
+                .iter()
+                .map(|s| format!("{:?} ({})", s.name, s.session_id.0))
+                .collect::<Vec<_>>()
+                .join(", ");
+            match self.config.duplicate_policy {
+                DuplicatePolicy::Ignore => {
+                    idebug!(self.config, "Other instances in the room: {names}");
+                }
+                DuplicatePolicy::WarnOnly => {
+                    iwarn!(self.config, "Other instances in the room: {names}");
+                }
+                DuplicatePolicy::Defer => {
+                    iwarn!(
+                        self.config,
+                        "Other instances in the room, deferring commands: {names}"
+                    );
+                }
+            }
+        }
+        idebug!(self.config, "Joined ({})", snapshot.connect_timings);
+        self.emit(Event::Joined(self.identity.clone(), snapshot, others));
+    }
+
+    /// React to a packet before it is emitted as an event.
+    ///
+    /// Returns the nick to set if the packet is the snapshot-event and a nick
+    /// should be set.
+    fn on_packet(&self, conn_tx: &ConnTx, packet: &ParsedPacket) -> Option<Nick> {
+        match &packet.content {
+            Ok(Data::HelloEvent(hello)) => {
+                let session = &hello.session;
+                idebug!(
+                    self.config,
+                    "Attached to server {} (era {})",
+                    session.server_id,
+                    session.server_era
+                );
+                let now = self.config.server.clock.now();
+                self.shared.placements.lock().unwrap().on_hello(hello, now);
+            }
+            Ok(Data::SnapshotEvent(snapshot)) => {
+                if let Some(username) = self.shared.username() {
+                    if self.config.force_username || snapshot.nick.is_none() {
+                        idebug!(self.config, "Setting nick to username {username}");
+                        return Some(Nick { name: username });
+                    } else if let Some(nick) = &snapshot.nick {
+                        idebug!(self.config, "Not setting nick, already set to {nick}");
+                    }
+                }
+            }
+            Ok(Data::BounceEvent(_)) => {
+                if let Some(password) = &self.config.password {
+                    idebug!(self.config, "Authenticating with password");
+                    let cmd = Auth {
+                        r#type: AuthOption::Passcode,
+                        passcode: Some(password.to_string()),
+                    };
+                    let _ = conn_tx.send_only(cmd);
+                } else {
+                    iwarn!(self.config, "Auth required but no password configured");
+                }
+            }
+            Ok(Data::DisconnectEvent(ev)) => {
+                let reason = ev.parsed_reason();
+                let cause = format!("disconnected because {reason}");
+                self.shared
+                    .placements
+                    .lock()
+                    .unwrap()
+                    .on_disconnect_cause(cause);
+                if reason == DisconnectReason::AuthenticationChanged {
+                    iinfo!(self.config, "Disconnected because {reason}");
+                } else {
+                    iwarn!(self.config, "Disconnected because {reason}");
+                }
+                self.emit(Event::DisconnectImminent(self.identity.clone(), reason));
+            }
+            _ => {}
+        }
+        None
+    }
+
+    /// Set the nick and emit the packet once the server has replied.
+    ///
+    /// See [`InstanceConfig::join_after_nick`] for more details.
+    async fn emit_after_nick(
+        &self,
+        conn: &mut Conn,
+        state_tx: &watch::Sender<Arc<State>>,
+        nick: Nick,
+        packet: ParsedPacket,
+    ) -> Result<(), RunError> {
+        // The reply is matched to the command by its packet id.
+        let reply = conn.tx().send(nick);
+        tokio::pin!(reply);
+
+        let mut held = vec![];
+        let result = loop {
+            select! {
+                biased;
+                r = &mut reply => break r.map_err(|err| err.to_string()),
+                r = conn.recv() => match r {
+                    Ok(packet) => {
+                        self.shared.pipeline.lock().unwrap().on_parsed(packet.seq);
+                        held.push((packet, Instance::take_snapshot(conn, state_tx)));
+                    }
+                    Err(err) => break Err(err.to_string()),
+                },
+            }
+        };
+
+        match result {
+            Ok(reply) => {
+                idebug!(self.config, "Nick set to {}", reply.to);
+            }
+            Err(err) => {
+                iwarn!(self.config, "Failed to set nick: {err}");
+            }
+        }
+
+        // The snapshot now contains the final nick, if setting it succeeded.
+        let snapshot = Instance::take_snapshot(conn, state_tx);
+        self.emit_packet(packet, snapshot);
+
+        for (packet, snapshot) in held {
+            if let Some(nick) = self.on_packet(conn.tx(), &packet) {
+                let _ = conn.tx().send_only(nick);
+            }
+            self.emit_packet(packet, snapshot);
+        }
+
+        Ok(())
+    }
+
+    async fn send_scheduled(
+        &self,
+        conn_tx: &ConnTx,
+        mut state_rx: watch::Receiver<Arc<State>>,
+    ) -> Infallible {
+        // The sender lives as long as this future is polled
+        let _ = state_rx.wait_for(|state| state.joined().is_some()).await;
+
+        let clock = &*self.config.server.clock;
+        let late = self.shared.schedules.take_due(clock.now());
+        if !late.is_empty() {
+            match self.config.late_schedules {
+                LateSchedules::Send => {
+                    idebug!(
+                        self.config,
+                        "Sending {} late scheduled messages",
+                        late.len()
+                    );
+                    for scheduled in late {
+                        let _ = conn_tx.send_only(scheduled.send);
+                    }
+                }
+                LateSchedules::Drop => {
+                    iinfo!(
+                        self.config,
+                        "Dropping {} late scheduled messages",
+                        late.len()
+                    );
+                }
+            }
+        }
+
+        loop {
+            self.shared.schedules.wait(clock).await;
+            for scheduled in self.shared.schedules.take_due(clock.now()) {
+                idebug!(self.config, "Sending scheduled message {}", scheduled.id);
+                let _ = conn_tx.send_only(scheduled.send);
+            }
+        }
+    }
+
+    async fn send_outbox(
+        &self,
+        conn_tx: &ConnTx,
+        mut state_rx: watch::Receiver<Arc<State>>,
+    ) -> Infallible {
+        let outbox = match &self.config.outbox {
+            Some(outbox) => outbox,
+            None => return future::pending().await,
+        };
+
+        // The sender lives as long as this future is polled
+        let _ = state_rx.wait_for(|state| state.joined().is_some()).await;
+
+        loop {
+            let pending = match outbox.drain() {
+                Ok(pending) => pending,
+                Err(err) => {
+                    iwarn!(self.config, "Failed to read outbox: {err}");
+                    vec![]
+                }
+            };
+
+            for pending in pending {
+                idebug!(self.config, "Sending durable message {}", pending.tag);
+                match conn_tx.send(pending.send).await {
+                    Ok(_) => {}
+                    Err(err @ conn::Error::Euph { .. }) => {
+                        iwarn!(
+                            self.config,
+                            "Durable message {} rejected: {err}",
+                            pending.tag
+                        );
+                    }
+                    // The message stays in the outbox and is sent again after
+                    // the next time the instance joins the room.
+                    Err(err) => {
+                        idebug!(
+                            self.config,
+                            "Durable message {} not sent: {err}",
+                            pending.tag
+                        );
+                        return future::pending().await;
+                    }
+                }
+                if let Err(err) = outbox.ack(&pending.tag) {
+                    iwarn!(
+                        self.config,
+                        "Failed to remove {} from outbox: {err}",
+                        pending.tag
+                    );
+                }
+            }
+
+            self.shared.outbox_changed.notified().await;
+        }
+    }
+
+    async fn sample_population(&self, mut state_rx: watch::Receiver<Arc<State>>) -> Infallible {
+        let interval = match self.config.population_sampling {
+            Some(interval) => interval,
+            None => return future::pending().await,
+        };
+
+        let mut interval = tokio::time::interval(interval);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+        // Whether a regular sample is due but couldn't be taken yet
+        let mut due = false;
+        loop {
+            select! {
+                _ = interval.tick() => due = true,
+                r = state_rx.changed() => {
+                    if r.is_err() {
+                        // The sender lives as long as this future is polled
+                        return future::pending().await;
+                    }
+                }
+            }
+
+            let sample = match state_rx.borrow_and_update().joined() {
+                Some(joined) => PopulationSample::at(joined, self.config.server.clock.now()),
+                None => continue,
+            };
+
+            let mut population = self.shared.population.lock().unwrap();
+            let changed_enough = match (self.config.population_sampling_delta, population.last()) {
+                (Some(delta), Some(last)) => sample.total.abs_diff(last.total) >= delta,
+                _ => false,
+            };
+            if !due && !changed_enough {
+                continue;
+            }
+            due = false;
+            population.push(sample.clone());
+            drop(population);
+
+            self.emit(Event::PopulationSample(self.identity.clone(), sample));
+        }
+    }
+
+    async fn check_missed(
+        &self,
+        missed: &Mutex<MissedMessages>,
+        requested: &Notify,
+        conn_tx: &ConnTx,
+    ) -> Infallible {
+        let mut interval = self.config.missed_message_checks.map(|period| {
+            let start = tokio::time::Instant::now() + period;
+            let mut interval = tokio::time::interval_at(start, period);
+            interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            interval
+        });
+
+        loop {
+            let tick = async {
+                match &mut interval {
+                    Some(interval) => interval.tick().await,
+                    None => future::pending().await,
+                }
+            };
+            select! {
+                _ = tick => {}
+                _ = requested.notified() => {}
+            }
+
+            let cmd = api::Log {
+                n: MISSED_MESSAGES_LOG_LEN,
+                before: None,
+            };
+            let log = match conn_tx.send(cmd).await {
+                Ok(reply) => reply.log,
+                Err(err) => {
+                    idebug!(self.config, "Failed to check for missed messages: {err}");
+                    continue;
+                }
+            };
+            let messages = missed.lock().unwrap().check(&log);
+            if !messages.is_empty() {
+                iwarn!(self.config, "Missed {} messages", messages.len());
+                self.emit(Event::MissedMessages(self.identity.clone(), messages));
+            }
+        }
+    }
+
+    async fn refresh_nick(
+        &self,
+        conn_tx: &ConnTx,
+        mut state_rx: watch::Receiver<Arc<State>>,
+    ) -> Infallible {
+        let period = match self.config.nick_refresh_interval {
+            Some(period) => period,
+            None => return future::pending().await,
+        };
+
+        let mut refresher = NickRefresher::new(
+            self.shared.username().unwrap_or_default(),
+            self.config.nick_refresh_mode,
+            self.config.nick_refresh_suppression,
+            tokio::time::Instant::now(),
+        );
+        let mut interval = tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+        loop {
+            let tick = select! {
+                _ = interval.tick() => true,
+                r = state_rx.changed() => {
+                    if r.is_err() {
+                        // The sender lives as long as this future is polled
+                        return future::pending().await;
+                    }
+                    false
+                }
+            };
+
+            let name = match state_rx.borrow_and_update().joined() {
+                Some(joined) => joined.session.name.clone(),
+                None => continue,
+            };
+            let username = match self.shared.username() {
+                Some(username) => username,
+                None => continue,
+            };
+            let now = tokio::time::Instant::now();
+            refresher.set_username(username);
+            refresher.observe(&name, now);
+            if tick && refresher.should_refresh(&name, now) {
+                idebug!(self.config, "Refreshing nick {:?}", refresher.username());
+                let nick = Nick {
+                    name: refresher.username().to_string(),
+                };
+                let _ = conn_tx.send_only(nick);
+                refresher.refreshed(now);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::future::{self, Future};
+    use std::io;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    use cookie::Cookie;
+    use jiff::{Timestamp, ToSpan};
+    use tokio::net::TcpListener;
+    use tokio::sync::{mpsc, watch, Notify};
+    use tokio::time::Instant;
+    use tokio_stream::StreamExt;
+    use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+    use tokio_tungstenite::tungstenite::Message;
+
+    use crate::api::packet::PacketSeq;
+    use crate::api::{self, HelloEvent, PacketType, SessionId, SessionView, UserId};
+    use crate::bot::instances::Instances;
+    use crate::clock::{Clock, MockClock};
+    use crate::conn::{self, Joined, Joining, SessionInfo, State};
+    use crate::test_util::{JoinedBuilder, SessionViewBuilder};
+
+    use super::population::POPULATION_HISTORY_LEN;
+    use super::{
+        outbox, ConfigError, ConnectGovernor, ConnectLimits, DuplicatePolicy, Event, FileOutbox,
+        Instance, InstanceConfig, LateSchedules, MissedMessages, NickRefreshMode, Outbox,
+        PipelineReport, PipelineStage, PlacementHistory, PopulationHistory, PopulationSample,
+        ServerConfig, Shared, Task, PLACEMENT_HISTORY_LEN,
+    };
+
+    fn session(id: &str, server_id: &str, server_era: &str) -> SessionView {
+        SessionViewBuilder::new("TestBot")
+            .with_id(UserId(id.to_string()))
+            .with_session_id(SessionId(id.to_string()))
+            .with_server(server_id, server_era)
+            .build()
+    }
+
+    fn hello(server_id: &str, server_era: &str) -> HelloEvent {
+        HelloEvent {
+            id: UserId("bot:test".to_string()),
+            account: None,
+            session: session("bot:test", server_id, server_era),
+            account_has_access: None,
+            account_email_verified: None,
+            room_is_private: false,
+            version: "version".to_string(),
+        }
+    }
+
+    #[test]
+    fn server_config_debug_hides_cookies() {
+        let config = ServerConfig::default();
+        config
+            .cookies
+            .lock()
+            .unwrap()
+            .add(Cookie::new("session", "s3cret"));
+        let debug = format!("{config:?}");
+        assert!(!debug.contains("s3cret"));
+        assert!(debug.contains("cookies: <hidden>"));
+    }
+
+    #[test]
+    fn instance_config_debug_hides_secrets() {
+        let config = ServerConfig::default()
+            .room("room")
+            .with_username("Bot")
+            .with_password("hunter2");
+        config
+            .server
+            .cookies
+            .lock()
+            .unwrap()
+            .add(Cookie::new("session", "s3cret"));
+        let debug = format!("{config:?}");
+        assert!(!debug.contains("hunter2"));
+        assert!(!debug.contains("s3cret"));
+        assert!(debug.contains("password: Some(<hidden>)"));
+        assert!(debug.contains("cookies: <hidden>"));
+        assert!(debug.contains("username: Some(\"Bot\")"));
+
+        let debug = format!("{:?}", config.with_password_opt(None::<String>));
+        assert!(debug.contains("password: None"));
+    }
+
+    #[test]
+    fn placement_history() {
+        let mut history = PlacementHistory::default();
+
+        let now = Timestamp::now();
+        history.on_hello(&hello("heim.1", "era1"), now);
+        history.on_disconnect_cause("disconnected because reasons".to_string());
+        history.on_disconnected("connection closed".to_string(), now);
+
+        // Connection attempts without hello-event are not recorded
+        history.on_disconnected("failed to connect".to_string(), now);
+
+        history.on_hello(&hello("heim.2", "era2"), now);
+        history.on_disconnected("connection closed".to_string(), now);
+
+        let placements = history.placements.iter().collect::<Vec<_>>();
+        assert_eq!(placements.len(), 2);
+        assert_eq!(history.connections, 2);
+
+        assert_eq!(placements[0].server_id, "heim.1");
+        assert_eq!(placements[0].server_era, "era1");
+        assert!(placements[0].disconnected.is_some());
+        assert_eq!(
+            placements[0].disconnect_cause.as_deref(),
+            Some("disconnected because reasons")
+        );
+
+        assert_eq!(placements[1].server_id, "heim.2");
+        assert_eq!(placements[1].server_era, "era2");
+        assert!(placements[1].disconnected.is_some());
+        assert_eq!(
+            placements[1].disconnect_cause.as_deref(),
+            Some("connection closed")
+        );
+    }
+
+    #[test]
+    fn placement_history_is_bounded() {
+        let mut history = PlacementHistory::default();
+        let now = Timestamp::now();
+        for i in 0..PLACEMENT_HISTORY_LEN + 5 {
+            history.on_hello(&hello(&format!("heim.{i}"), "era"), now);
+            history.on_disconnected("connection closed".to_string(), now);
+        }
+        assert_eq!(history.placements.len(), PLACEMENT_HISTORY_LEN);
+        assert_eq!(history.placements[0].server_id, "heim.5");
+        assert_eq!(history.connections, PLACEMENT_HISTORY_LEN + 5);
+    }
+
+    /// Run the scheduler of a freshly joined connection for 200 ms and return
+    /// the contents of all messages it sent.
+    ///
+    /// The clock advances along with tokio's time, which should be paused.
+    async fn send_scheduled(
+        late_schedules: LateSchedules,
+        clock: &MockClock,
+        shared: &Shared,
+    ) -> Vec<String> {
+        let config =
+            InstanceConfig::new(ServerConfig::default().with_clock(clock.shared()), "test")
+                .with_late_schedules(late_schedules);
+        let (mut conn, mut server) = conn::test::connect(Duration::from_secs(10)).await;
+        let conn_tx = conn.tx().clone();
+        let state = State::Joined(joined(0, 0));
+        let (_state_tx, state_rx) = watch::channel(Arc::new(state));
+
+        let task = Task::new(&config, shared, &|_| {});
+        let tick = async {
+            let tick = Duration::from_millis(10);
+            loop {
+                tokio::time::sleep(tick).await;
+                clock.advance(tick);
+            }
+        };
+        let run = async {
+            tokio::select! {
+                _ = conn.recv() => {}
+                () = tick => {}
+                r = task.send_scheduled(&conn_tx, state_rx) => match r {},
+            }
+        };
+        let _ = tokio::time::timeout(Duration::from_millis(200), run).await;
+        drop(conn);
+
+        let mut sent = vec![];
+        while let Some(Ok(Message::Text(text))) = server.0.next().await {
+            let packet: serde_json::Value = serde_json::from_str(&text).unwrap();
+            sent.push(packet["data"]["content"].as_str().unwrap().to_string());
+        }
+        sent
+    }
+
+    /// Run the nick refresh of a freshly joined connection for 200 ms and
+    /// return all nicks it sent.
+    async fn refresh_nick(config: &InstanceConfig, name: &str) -> Vec<String> {
+        let (mut conn, mut server) = conn::test::connect(Duration::from_secs(10)).await;
+        let conn_tx = conn.tx().clone();
+        let mut joined = joined(0, 0);
+        joined.session.name = name.to_string();
+        let (_state_tx, state_rx) = watch::channel(Arc::new(State::Joined(joined)));
+
+        let shared = Shared::new(config);
+        let task = Task::new(config, &shared, &|_| {});
+        let run = async {
+            tokio::select! {
+                _ = conn.recv() => {}
+                r = task.refresh_nick(&conn_tx, state_rx) => match r {},
+            }
+        };
+        let _ = tokio::time::timeout(Duration::from_millis(200), run).await;
+        drop(conn);
+
+        let mut sent = vec![];
+        while let Some(packet) = server.recv().await {
+            assert_eq!(packet["type"], "nick");
+            sent.push(packet["data"]["name"].as_str().unwrap().to_string());
+        }
+        sent
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn nick_refresh() {
+        let config = InstanceConfig::new(ServerConfig::default(), "test")
+            .with_username("TestBot")
+            .with_nick_refresh_interval(Duration::from_millis(30))
+            .with_nick_refresh_suppression(Duration::ZERO);
+
+        let sent = refresh_nick(&config, "").await;
+        assert_eq!(sent.len(), 6);
+        assert!(sent.iter().all(|n| n == "TestBot"));
+        assert!(refresh_nick(&config, "TestBot").await.is_empty());
+        assert!(!refresh_nick(&config, "Manual").await.is_empty());
+
+        let if_empty = config
+            .clone()
+            .with_nick_refresh_mode(NickRefreshMode::IfEmpty);
+        assert!(!refresh_nick(&if_empty, "").await.is_empty());
+        assert!(refresh_nick(&if_empty, "Manual").await.is_empty());
+
+        let always = config
+            .clone()
+            .with_nick_refresh_mode(NickRefreshMode::Always);
+        assert!(!refresh_nick(&always, "TestBot").await.is_empty());
+
+        let suppressed = config
+            .clone()
+            .with_nick_refresh_suppression(Duration::from_secs(60 * 60));
+        assert!(refresh_nick(&suppressed, "").await.is_empty());
+
+        let no_username = config.with_username_opt(None::<&str>);
+        assert!(refresh_nick(&no_username, "").await.is_empty());
+    }
+
+    fn send(content: &str) -> api::Send {
+        api::Send {
+            content: content.to_string(),
+            parent: None,
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn scheduled_messages_spanning_disconnect() {
+        for (late_schedules, expected) in [
+            (LateSchedules::Send, vec!["late", "due"]),
+            (LateSchedules::Drop, vec!["due"]),
+        ] {
+            let clock = MockClock::new();
+            let config = InstanceConfig::new(ServerConfig::default(), "test");
+            let shared = Shared::new(&config);
+            let schedules = &shared.schedules;
+            let now = clock.now();
+            // Became due while the instance was not connected
+            schedules.add(now - 1.minute(), send("late"));
+            schedules.add(now + 50.milliseconds(), send("due"));
+            let cancelled = schedules.add(now + 50.milliseconds(), send("cancelled"));
+            schedules.add(now + 1.hour(), send("later"));
+            schedules.cancel(cancelled);
+
+            let sent = send_scheduled(late_schedules, &clock, &shared).await;
+            assert_eq!(sent, expected);
+
+            let pending = schedules.pending();
+            assert_eq!(pending.len(), 1);
+            assert_eq!(pending[0].send.content, "later");
+        }
+    }
+
+    #[tokio::test]
+    async fn scheduled_messages_follow_the_clock() {
+        let clock = MockClock::new();
+        let config =
+            InstanceConfig::new(ServerConfig::default().with_clock(clock.shared()), "test");
+        let shared = Shared::new(&config);
+        shared.schedules.add(clock.now() + 1.hour(), send("later"));
+
+        let (mut conn, mut server) = conn::test::connect(Duration::from_secs(10)).await;
+        let conn_tx = conn.tx().clone();
+        let (_state_tx, state_rx) = watch::channel(Arc::new(State::Joined(joined(0, 0))));
+        let task = Task::new(&config, &shared, &|_| {});
+        let run = async {
+            tokio::select! {
+                _ = conn.recv() => {}
+                r = task.send_scheduled(&conn_tx, state_rx) => match r {},
+            }
+        };
+        let advance = async {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            assert_eq!(shared.schedules.pending().len(), 1);
+            clock.advance(Duration::from_secs(60 * 60));
+            server.recv().await.unwrap()
+        };
+        let packet = tokio::time::timeout(Duration::from_secs(10), async {
+            tokio::select! {
+                () = run => panic!("connection closed"),
+                packet = advance => packet,
+            }
+        })
+        .await
+        .unwrap();
+
+        assert_eq!(packet["type"], "send");
+        assert_eq!(packet["data"]["content"], "later");
+        assert!(shared.schedules.pending().is_empty());
+    }
+
+    #[test]
+    fn config_validation() {
+        let valid = || InstanceConfig::new(ServerConfig::default(), "test");
+        assert_eq!(valid().validate(), Ok(()));
+        assert_eq!(valid().with_username("TestBot").validate(), Ok(()));
+        assert_eq!(
+            valid()
+                .with_username("TestBot")
+                .with_force_username(true)
+                .validate(),
+            Ok(())
+        );
+
+        let cases = [
+            (
+                InstanceConfig::new(ServerConfig::default().with_domain(""), "test"),
+                ConfigError::EmptyDomain,
+            ),
+            (
+                InstanceConfig::new(ServerConfig::default().with_timeout(Duration::ZERO), "test"),
+                ConfigError::ZeroTimeout,
+            ),
+            (
+                InstanceConfig::new(ServerConfig::default().with_reconnect_jitter(1.5), "test"),
+                ConfigError::InvalidReconnectJitter,
+            ),
+            (
+                InstanceConfig::new(
+                    ServerConfig::default().with_reconnect_jitter(f64::NAN),
+                    "test",
+                ),
+                ConfigError::InvalidReconnectJitter,
+            ),
+            (
+                InstanceConfig::new(ServerConfig::default(), ""),
+                ConfigError::EmptyRoom,
+            ),
+            (valid().with_username(""), ConfigError::EmptyUsername),
+            (
+                valid().with_force_username(true),
+                ConfigError::ForceUsernameWithoutUsername,
+            ),
+        ];
+        for (config, error) in cases {
+            assert_eq!(config.validate(), Err(error));
+        }
+    }
+
+    #[tokio::test]
+    async fn invalid_config_creates_no_instance() {
+        let config = InstanceConfig::new(ServerConfig::default(), "");
+        let result = config.try_build(|_| panic!("instance was created"));
+        assert_eq!(result.unwrap_err(), ConfigError::EmptyRoom);
+    }
+
+    /// A server that refuses all connections.
+    fn unreachable_server() -> ServerConfig {
+        ServerConfig::default()
+            .with_domain("127.0.0.1:1")
+            .with_reconnect_delay(Duration::from_millis(10))
+    }
+
+    #[tokio::test]
+    async fn unobserved_instances_stop() {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let mut instances = Instances::new(unreachable_server());
+        for room in ["a", "b", "c"] {
+            let config = instances.server_config().clone().room(room);
+            instances.add(config.build_with_sender(tx.clone()));
+        }
+        drop(rx);
+
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while !instances.is_empty() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+                instances.purge();
+            }
+        })
+        .await
+        .expect("instances did not stop");
+    }
+
+    #[tokio::test]
+    async fn unobserved_instances_keep_running_if_configured() {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let instance = unreachable_server()
+            .room("test")
+            .with_stop_when_unobserved(false)
+            .build_with_sender(tx);
+        drop(rx);
+
+        // Enough time for multiple reconnect attempts
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(!instance.stopped());
+    }
+
+    #[tokio::test]
+    async fn stop_while_waiting_for_governor() {
+        let limits = ConnectLimits::new().with_max_concurrent_connects(1);
+        let governor = ConnectGovernor::new(limits);
+        let permit = governor.acquire().await;
+
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let instance = unreachable_server()
+            .with_connect_governor(governor)
+            .room("test")
+            .build_with_sender(tx);
+        assert!(matches!(rx.recv().await, Some(Event::Connecting(_))));
+        assert!(instance.conn_tx().await.is_none());
+
+        instance.stop();
+        let events = tokio::time::timeout(Duration::from_secs(5), async {
+            let mut events = vec![];
+            while let Some(event) = rx.recv().await {
+                events.push(event);
+            }
+            events
+        })
+        .await
+        .expect("instance did not stop");
+        assert!(matches!(
+            events[..],
+            [Event::Disconnected(_), Event::Stopped(_)]
+        ));
+        drop(permit);
+    }
+
+    #[tokio::test]
+    async fn leave_while_waiting_for_governor() {
+        let limits = ConnectLimits::new().with_max_concurrent_connects(1);
+        let governor = ConnectGovernor::new(limits);
+        let permit = governor.acquire().await;
+
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let server = unreachable_server().with_connect_governor(governor);
+        let mut instances = Instances::new(server.clone());
+        instances.add(server.room("test").build_with_sender(tx));
+        assert!(matches!(rx.recv().await, Some(Event::Connecting(_))));
+
+        // Dropping the instance must not cancel leaving
+        drop(instances.leave("test", None));
+        assert!(instances.is_empty());
+        let events = tokio::time::timeout(Duration::from_secs(5), async {
+            let mut events = vec![];
+            while let Some(event) = rx.recv().await {
+                events.push(event);
+            }
+            events
+        })
+        .await
+        .expect("instance did not stop");
+        assert!(matches!(
+            events[..],
+            [Event::Disconnected(_), Event::Stopped(_)]
+        ));
+        drop(permit);
+    }
+
+    #[tokio::test]
+    async fn reconnect_backoff() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        // Refuse three connections, let the instance join once, then refuse
+        // connections again.
+        tokio::spawn(async move {
+            for i in 0.. {
+                let (stream, _) = listener.accept().await.unwrap();
+                if i != 3 {
+                    continue;
+                }
+                let stream = tokio_tungstenite::accept_async(stream).await.unwrap();
+                conn::test::Server(stream)
+                    .join(conn::test::hello(false, None))
+                    .await;
+            }
+        });
+
+        let server = ServerConfig::default()
+            .with_domain(addr)
+            .with_tls(false)
+            .with_reconnect_delay(Duration::from_secs(1))
+            .with_reconnect_delay_max(Duration::from_secs(3))
+            .with_reconnect_jitter(0.0);
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let instance = server.room("test").build_with_sender(tx);
+
+        // Time is only paused while the instance waits to reconnect. While it
+        // is connecting, the clock would otherwise skip ahead to the timeout.
+        let mut delays = vec![];
+        let mut joined = false;
+        let mut disconnected = None;
+        while delays.len() < 6 {
+            match rx.recv().await.unwrap() {
+                Event::Joined(..) => joined = true,
+                Event::Disconnected(_) if !joined => {
+                    disconnected = Some(Instant::now());
+                    tokio::time::pause();
+                }
+                Event::Disconnected(_) => {
+                    joined = false;
+                    delays.push(0);
+                }
+                Event::Connecting(_) => {
+                    if let Some(since) = disconnected.take() {
+                        delays.push(since.elapsed().as_secs_f64().round() as u64);
+                        tokio::time::resume();
+                    }
+                }
+                _ => {}
+            }
+        }
+        drop(instance);
+
+        // The delay doubles up to the maximum, isn't applied after the instance
+        // was connected and then starts over.
+        assert_eq!(delays, [1, 2, 3, 0, 1, 2]);
+    }
+
+    #[tokio::test]
+    async fn stop_gracefully_waits_for_sends() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let stream = tokio_tungstenite::accept_async(stream).await.unwrap();
+            let mut server = conn::test::Server(stream);
+            server.join(conn::test::hello(false, None)).await;
+
+            let mut received = vec![];
+            while let Some(Ok(msg)) = server.0.next().await {
+                if let Message::Text(text) = &msg {
+                    let packet: serde_json::Value = serde_json::from_str(text).unwrap();
+                    // Take a while to reply, like a busy server would
+                    tokio::time::sleep(Duration::from_millis(50)).await;
+                    server
+                        .send(serde_json::json!({
+                            "id": packet["id"],
+                            "type": "send-reply",
+                            "data": {
+                                "id": "0000000000001",
+                                "time": 0,
+                                "sender": session("bot:test", "heim.1", "era"),
+                                "content": packet["data"]["content"],
+                            },
+                        }))
+                        .await;
+                }
+                received.push(msg);
+            }
+            received
+        });
+
+        let config = ServerConfig::default()
+            .with_domain(addr)
+            .with_tls(false)
+            .room("test");
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let instance = config.build_with_sender(tx);
+        while !matches!(rx.recv().await, Some(Event::Joined(..))) {}
+
+        let conn_tx = instance.conn_tx().await.unwrap();
+        let reply = conn_tx.send(api::Send {
+            content: "/me dies".to_string(),
+            parent: None,
+        });
+        instance.stop_gracefully(Duration::from_secs(10));
+        drop(instance);
+
+        // New commands are rejected while the pending one is still sent
+        conn_tx.closed().await;
+        let late = conn_tx.send(api::Send {
+            content: "/me lives".to_string(),
+            parent: None,
+        });
+        assert!(matches!(late.await, Err(conn::Error::ConnectionClosed)));
+        assert_eq!(reply.await.unwrap().0.content, "/me dies");
+
+        let mut events = vec![];
+        while let Some(event) = rx.recv().await {
+            events.push(event);
+        }
+        assert!(matches!(
+            events[..],
+            [Event::Disconnected(_), Event::Stopped(_)]
+        ));
+
+        // The close frame arrives after the last send
+        let received = server.await.unwrap();
+        assert_eq!(received.len(), 2);
+        assert!(matches!(&received[0], Message::Text(t) if t.contains("/me dies")));
+        match &received[1] {
+            Message::Close(Some(frame)) => {
+                assert_eq!(frame.code, CloseCode::Normal);
+                assert_eq!(frame.reason, "stopping");
+            }
+            msg => panic!("expected close frame, got {msg:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn leave_room_says_goodbye_before_closing() {
+        let config = InstanceConfig::new(ServerConfig::default(), "test");
+        let (conn, mut server) = conn::test::connect(Duration::from_secs(10)).await;
+
+        // The server must close the tcp connection after the closing handshake,
+        // so it is moved into and dropped at the end of this block.
+        let server = async move {
+            let mut received = vec![];
+            while let Some(Ok(msg)) = server.0.next().await {
+                let packet = match &msg {
+                    Message::Text(text) => serde_json::from_str(text).unwrap(),
+                    _ => serde_json::Value::Null,
+                };
+                if packet["type"] == "send" {
+                    server
+                        .send(serde_json::json!({
+                            "type": "ping-event",
+                            "data": { "time": 0, "next": 0 },
+                        }))
+                        .await;
+                    server
+                        .send(serde_json::json!({
+                            "id": packet["id"],
+                            "type": "send-reply",
+                            "data": {
+                                "id": "0000000000001",
+                                "time": 0,
+                                "sender": session("bot:test", "heim.1", "era"),
+                                "content": packet["data"]["content"],
+                            },
+                        }))
+                        .await;
+                }
+                received.push(msg);
+            }
+            received
+        };
+
+        let events = Mutex::new(vec![]);
+        let on_event = |event| {
+            if let Event::Packet(_, packet, _) = event {
+                events.lock().unwrap().push(packet.r#type);
+            }
+        };
+        let shared = Shared::new(&config);
+        let (state_tx, _) = watch::channel(conn.shared_state());
+        let goodbye = api::Send {
+            content: "bye!".to_string(),
+            parent: None,
+        };
+        let task = Task::new(&config, &shared, &on_event);
+        let leave = task.leave_room(conn, &state_tx, Some(goodbye));
+
+        let (left, received) = tokio::join!(leave, server);
+        left.unwrap();
+
+        // Packets received while waiting for the reply are still emitted
+        assert_eq!(
+            events.into_inner().unwrap(),
+            [PacketType::PingEvent, PacketType::SendReply]
+        );
+        // The ping-event was answered before closing
+        assert_eq!(received.len(), 3);
+        assert!(matches!(&received[0], Message::Text(t) if t.contains("bye!")));
+        assert!(matches!(&received[1], Message::Text(t) if t.contains("ping-reply")));
+        match &received[2] {
+            Message::Close(Some(frame)) => {
+                assert_eq!(frame.code, CloseCode::Normal);
+                assert_eq!(frame.reason, "leaving");
+            }
+            msg => panic!("expected close frame, got {msg:?}"),
+        }
+    }
+
+    /// A room with our own bot session and the given amount of other sessions.
+    fn joined(humans: usize, bots: usize) -> Joined {
+        let humans = (0..humans).map(|i| session(&format!("agent:{i}"), "heim.1", "era"));
+        let bots = (0..bots).map(|i| session(&format!("bot:{i}"), "heim.1", "era"));
+        humans
+            .chain(bots)
+            .fold(
+                JoinedBuilder::new().with_session(session("bot:test", "heim.1", "era")),
+                |joined, s| joined.with_listing_entry(SessionInfo::Full(s)),
+            )
+            .build()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn population_sampling() {
+        let config = InstanceConfig::new(ServerConfig::default(), "test")
+            .with_population_sampling(Duration::from_secs(60))
+            .with_population_sampling_delta(3);
+        let shared = Shared::new(&config);
+        let events = Mutex::new(vec![]);
+        let on_event = |event| {
+            if let Event::PopulationSample(_, sample) = event {
+                events.lock().unwrap().push(sample.total);
+            }
+        };
+
+        let joining = State::Joining(Joining {
+            since: Timestamp::now(),
+            hello: None,
+            snapshot: None,
+            bounce: None,
+        });
+        let (state_tx, state_rx) = watch::channel(Arc::new(joining));
+
+        let script = async {
+            let second = Duration::from_secs(1);
+            // The first regular sample is delayed until the room is joined
+            tokio::time::sleep(second).await;
+            state_tx.send_replace(Arc::new(State::Joined(joined(2, 0))));
+            // Small changes don't cause a sample
+            tokio::time::sleep(second).await;
+            state_tx.send_replace(Arc::new(State::Joined(joined(3, 0))));
+            // But large ones do
+            tokio::time::sleep(second).await;
+            state_tx.send_replace(Arc::new(State::Joined(joined(4, 1))));
+            // The next regular sample is at 60 seconds
+            tokio::time::sleep(100 * second).await;
+        };
+
+        let task = Task::new(&config, &shared, &on_event);
+        tokio::select! {
+            _ = script => {}
+            r = task.sample_population(state_rx) => match r {},
+        }
+
+        assert_eq!(*events.lock().unwrap(), [3, 6, 6]);
+        let samples = shared
+            .population
+            .lock()
+            .unwrap()
+            .0
+            .iter()
+            .cloned()
+            .collect::<Vec<_>>();
+        assert_eq!(samples.len(), 3);
+        assert_eq!((samples[0].humans, samples[0].bots), (2, 1));
+        assert_eq!((samples[2].humans, samples[2].bots), (4, 2));
+    }
+
+    #[test]
+    fn population_history_is_bounded() {
+        let mut history = PopulationHistory::default();
+        for i in 0..POPULATION_HISTORY_LEN + 5 {
+            history.push(PopulationSample {
+                time: Timestamp::now(),
+                total: i,
+                humans: i,
+                bots: 0,
+            });
+        }
+        assert_eq!(history.0.len(), POPULATION_HISTORY_LEN);
+        assert_eq!(history.0[0].total, 5);
+    }
+
+    fn message(id: u64) -> serde_json::Value {
+        serde_json::json!({
+            "id": api::Snowflake(id),
+            "time": id,
+            "sender": session("agent:other", "heim.1", "era"),
+            "content": format!("message {id}"),
+        })
+    }
+
+    #[tokio::test]
+    async fn missed_messages() {
+        let config = InstanceConfig::new(ServerConfig::default(), "test");
+        let (mut conn, mut server) = conn::test::connect(Duration::from_secs(10)).await;
+
+        tokio::spawn(async move {
+            server.join(conn::test::hello(false, None)).await;
+            for id in [1, 2, 4] {
+                let event = serde_json::json!({ "type": "send-event", "data": message(id) });
+                server.send(event).await;
+            }
+            while let Some(cmd) = server.recv().await {
+                assert_eq!(cmd["type"], "log");
+                let log = (1..=4).map(message).collect::<Vec<_>>();
+                server
+                    .send(serde_json::json!({
+                        "id": cmd["id"],
+                        "type": "log-reply",
+                        "data": { "log": log },
+                    }))
+                    .await;
+            }
+        });
+
+        let missed = Mutex::new(MissedMessages::default());
+        let received = Notify::new();
+        let on_event = |event| {
+            if let Event::Packet(_, packet, _) = &event {
+                missed.lock().unwrap().observe(packet);
+                if let Ok(api::Data::SendEvent(event)) = &packet.content {
+                    if event.0.id.0 .0 == 4 {
+                        received.notify_one();
+                    }
+                }
+            }
+        };
+        let reported = Mutex::new(vec![]);
+        let reported_notify = Notify::new();
+        let on_missed = |event| {
+            if let Event::MissedMessages(_, messages) = event {
+                reported.lock().unwrap().push(messages);
+                reported_notify.notify_one();
+            }
+        };
+
+        let shared = Shared::new(&config);
+        let (state_tx, _) = watch::channel(conn.shared_state());
+        let check_missed = Notify::new();
+        let task = Task::new(&config, &shared, &on_event);
+        let missed_task = task.with_on_event(&on_missed);
+        let conn_tx = conn.tx().clone();
+        let script = async {
+            received.notified().await;
+            check_missed.notify_one();
+            reported_notify.notified().await;
+        };
+        tokio::select! {
+            _ = script => {}
+            r = task.receive(&mut conn, &state_tx) => {
+                panic!("receive ended: {}", r.unwrap_err());
+            }
+            r = missed_task.check_missed(&missed, &check_missed, &conn_tx) => match r {},
+        }
+
+        let reported = reported.into_inner().unwrap();
+        assert_eq!(reported.len(), 1);
+        let ids = reported[0].iter().map(|m| m.id.0 .0).collect::<Vec<_>>();
+        assert_eq!(ids, [3]);
+        assert_eq!(reported[0][0].content, "message 3");
+    }
+
+    /// Join a room whose server either accepts or rejects the nick and return
+    /// the type and own nick of all emitted packets.
+    async fn receive_join(join_after_nick: bool, accept_nick: bool) -> Vec<(PacketType, String)> {
+        let config = InstanceConfig::new(ServerConfig::default(), "test")
+            .with_username("TestBot")
+            .with_join_after_nick(join_after_nick);
+        let (mut conn, mut server) = conn::test::connect(Duration::from_secs(10)).await;
+
+        tokio::spawn(async move {
+            server.join(conn::test::hello(false, None)).await;
+            let nick = server.recv().await.unwrap();
+            assert_eq!(nick["type"], "nick");
+            server
+                .send(serde_json::json!({
+                    "type": "join-event",
+                    "data": session("agent:other", "heim.1", "era"),
+                }))
+                .await;
+            let reply = if accept_nick {
+                serde_json::json!({
+                    "id": nick["id"],
+                    "type": "nick-reply",
+                    "data": {
+                        "session_id": "session",
+                        "id": "agent:abc",
+                        "from": "",
+                        "to": "TestBot",
+                    },
+                })
+            } else {
+                serde_json::json!({
+                    "id": nick["id"],
+                    "type": "nick-reply",
+                    "error": "invalid nick",
+                })
+            };
+            server.send(reply).await;
+        });
+
+        let events = Mutex::new(vec![]);
+        let on_event = |event| {
+            if let Event::Packet(_, packet, snapshot) = event {
+                let nick = match snapshot.state.joined() {
+                    Some(joined) => joined.session.name.clone(),
+                    None => String::new(),
+                };
+                events.lock().unwrap().push((packet.r#type, nick));
+            }
+        };
+        let shared = Shared::new(&config);
+        let (state_tx, _) = watch::channel(conn.shared_state());
+        let result = Task::new(&config, &shared, &on_event)
+            .receive(&mut conn, &state_tx)
+            .await;
+        assert!(result.is_err());
+
+        events.into_inner().unwrap()
+    }
+
+    #[tokio::test]
+    async fn join_after_nick() {
+        let packets = |nicks: [&str; 4]| {
+            [
+                PacketType::HelloEvent,
+                PacketType::SnapshotEvent,
+                PacketType::JoinEvent,
+                PacketType::NickReply,
+            ]
+            .into_iter()
+            .zip(nicks.map(|n| n.to_string()))
+            .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            receive_join(false, true).await,
+            packets(["", "", "", "TestBot"])
+        );
+        assert_eq!(
+            receive_join(true, true).await,
+            packets(["", "TestBot", "", "TestBot"])
+        );
+        assert_eq!(receive_join(true, false).await, packets(["", "", "", ""]));
+    }
+
+    /// Join a room with other instances of the bot in it, returning the emitted
+    /// events.
+    async fn receive_duplicates(join_after_nick: bool) -> Vec<String> {
+        let config = InstanceConfig::new(ServerConfig::default(), "test")
+            .with_username("TestBot")
+            .with_join_after_nick(join_after_nick)
+            .with_duplicate_policy(DuplicatePolicy::WarnOnly);
+        let (mut conn, mut server) = conn::test::connect(Duration::from_secs(10)).await;
+
+        tokio::spawn(async move {
+            let hello = conn::test::hello(false, None);
+            server
+                .send(serde_json::json!({ "type": "hello-event", "data": hello }))
+                .await;
+            let mut human = session("agent:other", "heim.1", "era");
+            human.session_id = SessionId("human".to_string());
+            server
+                .send(serde_json::json!({
+                    "type": "snapshot-event",
+                    "data": {
+                        "identity": "agent:abc",
+                        "session_id": "session",
+                        "version": "version",
+                        "listing": [
+                            session("agent:abc", "heim.1", "era"),
+                            session("bot:other", "heim.1", "era"),
+                            human,
+                        ],
+                        "log": [],
+                    },
+                }))
+                .await;
+        });
+
+        let events = Mutex::new(vec![]);
+        let on_event = |event| match event {
+            Event::Packet(_, packet, _) => {
+                events.lock().unwrap().push(format!("{:?}", packet.r#type));
+            }
+            Event::Joined(_, _, others) => {
+                let ids = others
+                    .into_iter()
+                    .map(|s| s.session_id.0)
+                    .collect::<Vec<_>>();
+                events.lock().unwrap().push(format!("Joined {ids:?}"));
+            }
+            _ => {}
+        };
+        let shared = Shared::new(&config);
+        let (state_tx, _) = watch::channel(conn.shared_state());
+        let result = Task::new(&config, &shared, &on_event)
+            .receive(&mut conn, &state_tx)
+            .await;
+        assert!(result.is_err());
+
+        events.into_inner().unwrap()
+    }
+
+    #[tokio::test]
+    async fn joined_with_duplicates() {
+        let expected = [
+            "HelloEvent",
+            "SnapshotEvent",
+            r#"Joined ["agent:abc", "bot:other"]"#,
+        ];
+        assert_eq!(receive_duplicates(false).await, expected);
+        assert_eq!(receive_duplicates(true).await, expected);
+    }
+
+    /// Receive a short session, sending the events to a channel.
+    async fn receive_pipeline(event_tx: mpsc::UnboundedSender<Event>) -> PipelineReport {
+        let config =
+            InstanceConfig::new(ServerConfig::default(), "test").with_stop_when_unobserved(false);
+        let (mut conn, mut server) = conn::test::connect(Duration::from_secs(10)).await;
+        let shared = Arc::new(Shared::new(&config));
+        shared
+            .pipeline
+            .lock()
+            .unwrap()
+            .on_connected(conn.generation());
+
+        tokio::spawn(async move {
+            server.join(conn::test::hello(false, None)).await;
+            server
+                .send(serde_json::json!({
+                    "type": "join-event",
+                    "data": session("agent:other", "heim.1", "era"),
+                }))
+                .await;
+        });
+
+        let on_event = Instance::send_events(
+            config.clone(),
+            event_tx,
+            Arc::new(Notify::new()),
+            shared.clone(),
+        );
+        let (state_tx, _) = watch::channel(conn.shared_state());
+        let result = Task::new(&config, &shared, &on_event)
+            .receive(&mut conn, &state_tx)
+            .await;
+        assert!(result.is_err());
+
+        let report = *shared.pipeline.lock().unwrap();
+        report
+    }
+
+    #[tokio::test]
+    async fn pipeline_report() {
+        // A consumer that stops handling packets after the second one
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let mut report = receive_pipeline(tx).await;
+        let mut seqs = vec![];
+        while let Some(event) = rx.recv().await {
+            if let Event::Packet(_, packet, _) = event {
+                let seq = packet.seq.unwrap();
+                assert_eq!(Some(seq.generation), report.generation);
+                if seqs.len() < 2 {
+                    report.on_delivered(packet.seq);
+                }
+                seqs.push(seq.seq);
+            }
+        }
+        assert_eq!(seqs, [0, 1, 2]);
+        assert_eq!(
+            (
+                report.received,
+                report.parsed,
+                report.emitted,
+                report.delivered
+            ),
+            (3, 3, 3, 2)
+        );
+        assert_eq!(report.first_gap(), Some(PipelineStage::Delivered));
+
+        // Packets from other connections are not counted
+        report.on_delivered(Some(PacketSeq {
+            generation: report.generation.unwrap() + 1,
+            seq: 2,
+        }));
+        assert_eq!(report.delivered, 2);
+
+        // A consumer that is gone
+        let (tx, rx) = mpsc::unbounded_channel();
+        drop(rx);
+        let report = receive_pipeline(tx).await;
+        assert_eq!(
+            (
+                report.received,
+                report.parsed,
+                report.emitted,
+                report.delivered
+            ),
+            (3, 3, 0, 0)
+        );
+        assert_eq!(report.first_gap(), Some(PipelineStage::Emitted));
+        assert_eq!(
+            report.to_string(),
+            "3 received, 3 parsed, 0 emitted, 0 delivered"
+        );
+    }
+
+    /// Run the outbox of a freshly joined connection until `server` returns.
+    async fn send_outbox<S, F>(config: &InstanceConfig, server: S)
+    where
+        S: FnOnce(conn::test::Server) -> F,
+        F: Future<Output = ()>,
+    {
+        let (mut conn, server_conn) = conn::test::connect(Duration::from_secs(10)).await;
+        let conn_tx = conn.tx().clone();
+        let state = State::Joined(joined(0, 0));
+        let (_state_tx, state_rx) = watch::channel(Arc::new(state));
+        let shared = Shared::new(config);
+
+        let receive = async {
+            while conn.recv().await.is_ok() {}
+            future::pending::<()>().await
+        };
+        let task = Task::new(config, &shared, &|_| {});
+        tokio::select! {
+            _ = receive => {}
+            r = task.send_outbox(&conn_tx, state_rx) => match r {},
+            _ = server(server_conn) => {}
+        }
+    }
+
+    /// Reply to the next send command and return its content.
+    async fn reply_to_send(server: &mut conn::test::Server) -> String {
+        let cmd = server.recv().await.unwrap();
+        assert_eq!(cmd["type"], "send");
+        let reply = serde_json::json!({
+            "id": "0000000000001",
+            "time": 0,
+            "sender": session("bot:test", "heim.1", "era"),
+            "content": cmd["data"]["content"],
+        });
+        server
+            .send(serde_json::json!({ "id": cmd["id"], "type": "send-reply", "data": reply }))
+            .await;
+        cmd["data"]["content"].as_str().unwrap().to_string()
+    }
+
+    #[tokio::test]
+    async fn outbox_survives_crash() {
+        let path = outbox::test::path("outbox_survives_crash");
+        let outbox = Arc::new(FileOutbox::open(&path).unwrap());
+        outbox.push(outbox::test::pending("a")).unwrap();
+        outbox.push(outbox::test::pending("b")).unwrap();
+        let tag = outbox.drain().unwrap()[1].tag.clone();
+
+        let config = InstanceConfig::new(ServerConfig::default(), "test")
+            .with_outbox(outbox.clone() as Arc<dyn Outbox>);
+        send_outbox(&config, {
+            let outbox = outbox.clone();
+            |mut server| async move {
+                assert_eq!(reply_to_send(&mut server).await, "a");
+                let cmd = server.recv().await.unwrap();
+                assert_eq!(cmd["data"]["content"], "b");
+                // Messages are only acknowledged once the server replies
+                assert_eq!(outbox::test::contents(&*outbox), ["b"]);
+                // Crash before replying to the second message
+            }
+        })
+        .await;
+        drop(config);
+        drop(outbox);
+
+        // Rebuild everything from the file
+        let outbox = Arc::new(FileOutbox::open(&path).unwrap());
+        assert_eq!(outbox.drain().unwrap()[0].tag, tag);
+        let config = InstanceConfig::new(ServerConfig::default(), "test")
+            .with_outbox(outbox.clone() as Arc<dyn Outbox>);
+        send_outbox(&config, |mut server| async move {
+            assert_eq!(reply_to_send(&mut server).await, "b");
+            // Give the outbox some time to process the reply
+            let _ = tokio::time::timeout(Duration::from_millis(100), server.recv()).await;
+        })
+        .await;
+        assert!(outbox::test::contents(&*outbox).is_empty());
+        assert!(outbox::test::contents(&FileOutbox::open(&path).unwrap()).is_empty());
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn send_durable() {
+        let server = ServerConfig::default().with_domain("localhost:0");
+        let instance = InstanceConfig::new(server.clone(), "test").build(|_| {});
+        let err = instance.send_durable(send("a")).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::Unsupported);
+
+        let path = outbox::test::path("send_durable");
+        let outbox = Arc::new(FileOutbox::open(&path).unwrap());
+        let instance = InstanceConfig::new(server, "test")
+            .with_outbox(outbox.clone() as Arc<dyn Outbox>)
+            .with_tag_durable(|tag, send| send.content.push_str(&format!(" [{tag}]")))
+            .build(|_| {});
+        let tag = instance.send_durable(send("a")).unwrap();
+        assert_eq!(outbox::test::contents(&*outbox), [format!("a [{tag}]")]);
+
+        std::fs::remove_file(path).unwrap();
+    }
+}
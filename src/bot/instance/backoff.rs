@@ -0,0 +1,136 @@
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+/// A random number in the range `0.0..1.0`.
+///
+/// This is not suitable for anything but spreading out reconnect attempts.
+fn random_fraction() -> f64 {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u64(COUNTER.fetch_add(1, Ordering::Relaxed));
+    (hasher.finish() >> 11) as f64 / (1_u64 << 53) as f64
+}
+
+/// Delays between unsuccessful attempts to connect.
+///
+/// See [`ServerConfig::reconnect_delay`](super::ServerConfig::reconnect_delay)
+/// for more details.
+#[derive(Debug, Default)]
+pub(super) struct Backoff {
+    /// Unsuccessful attempts since the instance last joined its room.
+    failures: u32,
+}
+
+impl Backoff {
+    pub(super) fn new() -> Self {
+        Self::default()
+    }
+
+    /// The delay before the next attempt, without jitter.
+    fn base_delay(&self, initial: Duration, max: Duration) -> Duration {
+        let factor = 1_u32.checked_shl(self.failures).unwrap_or(u32::MAX);
+        initial.saturating_mul(factor).min(max)
+    }
+
+    /// Register an unsuccessful attempt and return how long to wait before
+    /// the next one.
+    ///
+    /// Up to the fraction `jitter` of the delay is subtracted at random. A
+    /// jitter outside `0.0..=1.0` is clamped, and a NaN jitter is ignored.
+    pub(super) fn on_failure(&mut self, initial: Duration, max: Duration, jitter: f64) -> Duration {
+        self.on_failure_with(initial, max, jitter, random_fraction())
+    }
+
+    fn on_failure_with(
+        &mut self,
+        initial: Duration,
+        max: Duration,
+        jitter: f64,
+        random: f64,
+    ) -> Duration {
+        let delay = self.base_delay(initial, max);
+        self.failures = self.failures.saturating_add(1);
+        let jitter = if jitter.is_nan() {
+            0.0
+        } else {
+            jitter.clamp(0.0, 1.0)
+        };
+        delay.mul_f64(1.0 - jitter * random)
+    }
+
+    /// Register that the instance joined its room.
+    pub(super) fn on_joined(&mut self) {
+        self.failures = 0;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::{random_fraction, Backoff};
+
+    const INITIAL: Duration = Duration::from_secs(1);
+    const MAX: Duration = Duration::from_secs(10);
+
+    fn secs(delay: Duration) -> f64 {
+        delay.as_secs_f64()
+    }
+
+    #[test]
+    fn doubles_until_max() {
+        let mut backoff = Backoff::new();
+        let delays = (0..6)
+            .map(|_| secs(backoff.on_failure(INITIAL, MAX, 0.0)))
+            .collect::<Vec<_>>();
+        assert_eq!(delays, [1.0, 2.0, 4.0, 8.0, 10.0, 10.0]);
+
+        // Many failures don't overflow
+        for _ in 0..100 {
+            backoff.on_failure(INITIAL, MAX, 0.0);
+        }
+        assert_eq!(backoff.on_failure(INITIAL, MAX, 0.0), MAX);
+
+        // An initial delay above the maximum is capped as well
+        let mut backoff = Backoff::new();
+        assert_eq!(backoff.on_failure(MAX * 2, MAX, 0.0), MAX);
+    }
+
+    #[test]
+    fn resets_on_join() {
+        let mut backoff = Backoff::new();
+        backoff.on_failure(INITIAL, MAX, 0.0);
+        backoff.on_failure(INITIAL, MAX, 0.0);
+        assert_eq!(backoff.on_failure(INITIAL, MAX, 0.0), INITIAL * 4);
+
+        backoff.on_joined();
+        assert_eq!(backoff.on_failure(INITIAL, MAX, 0.0), INITIAL);
+        assert_eq!(backoff.on_failure(INITIAL, MAX, 0.0), INITIAL * 2);
+    }
+
+    #[test]
+    fn jitter() {
+        let mut backoff = Backoff::new();
+        assert_eq!(secs(backoff.on_failure_with(INITIAL, MAX, 0.5, 0.0)), 1.0);
+        assert_eq!(secs(backoff.on_failure_with(INITIAL, MAX, 0.5, 0.5)), 1.5);
+        assert_eq!(secs(backoff.on_failure_with(INITIAL, MAX, 0.5, 1.0)), 2.0);
+        assert_eq!(secs(backoff.on_failure_with(INITIAL, MAX, 2.0, 1.0)), 0.0);
+        assert_eq!(
+            secs(backoff.on_failure_with(INITIAL, MAX, f64::NAN, 1.0)),
+            10.0
+        );
+
+        for _ in 0..1000 {
+            let random = random_fraction();
+            assert!((0.0..1.0).contains(&random), "{random}");
+        }
+        let mut backoff = Backoff::new();
+        for _ in 0..1000 {
+            backoff.on_joined();
+            let delay = backoff.on_failure(INITIAL, MAX, 0.25);
+            assert!(delay > INITIAL * 3 / 4 && delay <= INITIAL, "{delay:?}");
+        }
+    }
+}
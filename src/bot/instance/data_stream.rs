@@ -0,0 +1,184 @@
+use std::marker::PhantomData;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use tokio::sync::mpsc;
+use tokio_stream::Stream;
+
+use crate::api::Data;
+
+use super::{ConnSnapshot, Event, InstanceIdentity};
+
+/// Only the packets of a specific type from a channel of [`Event`]s.
+///
+/// Created from the receiving end of a channel passed to
+/// [`Instance::with_sender`](super::Instance::with_sender). Each
+/// [`Event::Packet`] whose content can be converted to `T` is yielded along
+/// with the identity of the instance it came from and the [`ConnSnapshot`] taken
+/// when it was received. The snapshot's [`ConnSnapshot::conn_tx`] can be used
+/// to reply.
+///
+/// All other events are discarded, including packets containing an error and
+/// events like [`Event::Connected`] or [`Event::Stopped`]. To react to those
+/// as well, receive the events directly instead.
+///
+/// This type can be used either via [`Self::recv`] or as a [`Stream`].
+pub struct DataStream<T> {
+    rx: mpsc::UnboundedReceiver<Event>,
+    _type: PhantomData<fn() -> T>,
+}
+
+impl<T: TryFrom<Data>> DataStream<T> {
+    pub fn new(rx: mpsc::UnboundedReceiver<Event>) -> Self {
+        Self {
+            rx,
+            _type: PhantomData,
+        }
+    }
+
+    /// The underlying channel receiver.
+    pub fn into_inner(self) -> mpsc::UnboundedReceiver<Event> {
+        self.rx
+    }
+
+    fn convert(event: Event) -> Option<(InstanceIdentity, T, ConnSnapshot)> {
+        match event {
+            Event::Packet(identity, packet, snapshot) => {
+                let data = T::try_from(packet.content.ok()?).ok()?;
+                Some((identity, data, snapshot))
+            }
+            _ => None,
+        }
+    }
+
+    /// Receive the next packet of type `T`.
+    ///
+    /// Returns `None` once the channel is closed.
+    pub async fn recv(&mut self) -> Option<(InstanceIdentity, T, ConnSnapshot)> {
+        loop {
+            if let Some(item) = Self::convert(self.rx.recv().await?) {
+                return Some(item);
+            }
+        }
+    }
+}
+
+impl<T: TryFrom<Data>> Stream for DataStream<T> {
+    type Item = (InstanceIdentity, T, ConnSnapshot);
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        loop {
+            match self.rx.poll_recv(cx) {
+                Poll::Ready(Some(event)) => {
+                    if let Some(item) = Self::convert(event) {
+                        return Poll::Ready(Some(item));
+                    }
+                }
+                Poll::Ready(None) => return Poll::Ready(None),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use tokio::sync::mpsc;
+    use tokio_stream::StreamExt;
+
+    use crate::api::packet::ParsedPacket;
+    use crate::api::{Data, DisconnectReason, NickEvent, PacketType, SendEvent, SessionId, UserId};
+    use crate::bot::command::test::context;
+    use crate::bot::instance::{
+        ConnSnapshot, Event, InstanceConfig, InstanceIdentity, ServerConfig,
+    };
+    use crate::conn::{self, ConnectTimings, State};
+
+    use super::DataStream;
+
+    fn identity(name: &str) -> InstanceIdentity {
+        InstanceConfig::new(ServerConfig::default(), "test")
+            .with_name(name)
+            .identity()
+    }
+
+    fn snapshot() -> ConnSnapshot {
+        ConnSnapshot {
+            conn_tx: conn::test::closed_tx(),
+            state: Arc::new(State::Joined(context().joined)),
+            connect_timings: ConnectTimings::default(),
+        }
+    }
+
+    fn nick_event(to: &str) -> NickEvent {
+        NickEvent {
+            session_id: SessionId("session".to_string()),
+            id: UserId("agent:abc".to_string()),
+            from: "".to_string(),
+            to: to.to_string(),
+        }
+    }
+
+    fn packet(name: &str, content: Result<Data, String>) -> Event {
+        let packet = ParsedPacket {
+            id: None,
+            r#type: PacketType::NickEvent,
+            content,
+            throttled: None,
+            seq: None,
+        };
+        Event::Packet(identity(name), packet, snapshot())
+    }
+
+    fn events() -> mpsc::UnboundedReceiver<Event> {
+        let (tx, rx) = mpsc::unbounded_channel();
+        tx.send(Event::Connecting(identity("a"))).unwrap();
+        tx.send(packet("a", Ok(Data::NickEvent(nick_event("one")))))
+            .unwrap();
+        tx.send(Event::DisconnectImminent(
+            identity("a"),
+            DisconnectReason::AuthenticationChanged,
+        ))
+        .unwrap();
+        tx.send(packet("a", Err("error".to_string()))).unwrap();
+        tx.send(packet("b", Ok(Data::NickEvent(nick_event("two")))))
+            .unwrap();
+        tx.send(Event::Stopped(identity("a"))).unwrap();
+        rx
+    }
+
+    #[tokio::test]
+    async fn hits() {
+        let mut stream = DataStream::<NickEvent>::new(events());
+        let (identity, nick, snapshot) = stream.recv().await.unwrap();
+        assert_eq!(&*identity.name, "a");
+        assert_eq!(nick.to, "one");
+        assert!(snapshot.state.joined().is_some());
+
+        let (identity, nick, _) = stream.recv().await.unwrap();
+        assert_eq!(&*identity.name, "b");
+        assert_eq!(nick.to, "two");
+
+        // All senders were dropped
+        assert!(stream.recv().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn misses() {
+        let mut stream = DataStream::<SendEvent>::new(events());
+        assert!(stream.recv().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn as_stream() {
+        let stream = DataStream::<NickEvent>::new(events());
+        let nicks = stream.map(|(_, nick, _)| nick.to).collect::<Vec<_>>();
+        let nicks = tokio::time::timeout(Duration::from_secs(1), nicks)
+            .await
+            .unwrap();
+        assert_eq!(nicks, ["one", "two"]);
+    }
+}
@@ -0,0 +1,148 @@
+use std::time::Duration;
+
+use jiff::Timestamp;
+
+use crate::api::{SessionType, SessionView};
+use crate::conn::{Joined, SessionInfo};
+use crate::nick;
+
+/// What an instance does when other instances of the same bot are in its room,
+/// see [`Event::Joined`](super::Event::Joined).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DuplicatePolicy {
+    /// Do nothing.
+    #[default]
+    Ignore,
+    /// Log a warning when joining the room.
+    WarnOnly,
+    /// Log a warning when joining the room and don't execute commands while
+    /// the other instances are still in the room.
+    ///
+    /// Commands are executed again once all other instances have left or once
+    /// [`InstanceConfig::duplicate_defer_timeout`](super::InstanceConfig::duplicate_defer_timeout)
+    /// has passed since joining, whichever happens first. See
+    /// [`Context::deferred`](crate::bot::command::Context::deferred) for more
+    /// details.
+    Defer,
+}
+
+/// Find other instances of the bot in the room.
+///
+/// A session counts as another instance if it has the same [`UserId`](crate::api::UserId)
+/// as our own session, i.e. the same agent or account is connected elsewhere,
+/// or if it is a bot whose normalized nick equals the normalized `username`.
+///
+/// Only sessions with a [`SessionInfo::Full`] entry in the listing are
+/// considered. The sessions are ordered by session id.
+pub fn other_instances(joined: &Joined, username: Option<&str>) -> Vec<SessionView> {
+    let own = &joined.session;
+    let username = username.map(nick::normalize);
+    let mut others = joined
+        .listing
+        .values()
+        .filter_map(|info| match info {
+            SessionInfo::Full(session) => Some(session),
+            SessionInfo::Partial(_) => None,
+        })
+        .filter(|session| session.session_id != own.session_id)
+        .filter(|session| {
+            session.id == own.id
+                || (session.id.session_type() == Some(SessionType::Bot)
+                    && username.as_ref() == Some(&nick::normalize(&session.name)))
+        })
+        .cloned()
+        .collect::<Vec<_>>();
+    others.sort_unstable_by(|a, b| a.session_id.0.cmp(&b.session_id.0));
+    others
+}
+
+/// Whether commands should currently be deferred according to the policy.
+pub(super) fn defers_commands(
+    policy: DuplicatePolicy,
+    timeout: Duration,
+    joined: &Joined,
+    username: Option<&str>,
+    now: Timestamp,
+) -> bool {
+    if policy != DuplicatePolicy::Defer {
+        return false;
+    }
+    let joined_for = Duration::try_from(now.duration_since(joined.since)).unwrap_or_default();
+    joined_for < timeout && !other_instances(joined, username).is_empty()
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use jiff::{Timestamp, ToSpan};
+
+    use crate::api::{SessionId, SessionView, UserId};
+    use crate::conn::{Joined, SessionInfo};
+    use crate::test_util::{JoinedBuilder, SessionViewBuilder};
+
+    use super::{defers_commands, other_instances, DuplicatePolicy};
+
+    const TIMEOUT: Duration = Duration::from_secs(60);
+
+    fn session(id: &str, name: &str, session_id: &str) -> SessionView {
+        SessionViewBuilder::new(name)
+            .with_id(UserId(id.to_string()))
+            .with_session_id(SessionId(session_id.to_string()))
+            .build()
+    }
+
+    /// Our own session is `bot:TestBot` with the session id `me`.
+    fn joined(listing: &[SessionView]) -> Joined {
+        listing
+            .iter()
+            .fold(JoinedBuilder::new(), |joined, s| {
+                joined.with_listing_entry(SessionInfo::Full(s.clone()))
+            })
+            .build()
+    }
+
+    fn session_ids(sessions: &[SessionView]) -> Vec<&str> {
+        sessions.iter().map(|s| s.session_id.0.as_str()).collect()
+    }
+
+    #[test]
+    fn detection() {
+        let joined = joined(&[
+            session("bot:TestBot", "TestBot", "me"),
+            session("bot:TestBot", "", "old"),
+            session("bot:other", "test bot", "renamed"),
+            session("bot:other", "TestBots", "similar"),
+            session("agent:other", "TestBot", "human"),
+            session("account:other", "Someone", "account"),
+        ]);
+        let others = other_instances(&joined, Some("TestBot"));
+        assert_eq!(session_ids(&others), ["old", "renamed"]);
+        let others = other_instances(&joined, None);
+        assert_eq!(session_ids(&others), ["old"]);
+    }
+
+    #[test]
+    fn policies() {
+        let now = Timestamp::now();
+        let mut duplicate = joined(&[session("bot:other", "TestBot", "old")]);
+        duplicate.since = now;
+        let mut alone = joined(&[session("agent:other", "TestBot", "human")]);
+        alone.since = now;
+
+        let defers = |policy, joined: &Joined, now| {
+            defers_commands(policy, TIMEOUT, joined, Some("TestBot"), now)
+        };
+        for policy in [DuplicatePolicy::Ignore, DuplicatePolicy::WarnOnly] {
+            assert!(!defers(policy, &duplicate, now));
+        }
+        assert!(defers(DuplicatePolicy::Defer, &duplicate, now));
+        assert!(!defers(DuplicatePolicy::Defer, &alone, now));
+
+        // Once the timeout has passed, commands are executed anyways
+        let later = now + 59.seconds();
+        assert!(defers(DuplicatePolicy::Defer, &duplicate, later));
+        let later = now + 60.seconds();
+        assert!(!defers(DuplicatePolicy::Defer, &duplicate, later));
+    }
+}
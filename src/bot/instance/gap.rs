@@ -0,0 +1,304 @@
+use std::sync::{Arc, Mutex};
+
+use crate::api::{Data, Message, MessageId, SessionView, SnapshotEvent};
+use crate::conn::{Joined, SessionInfo, State};
+
+use super::{Event, InstanceConfig};
+
+/// What happened in a room while an instance was reconnecting.
+///
+/// See [`InstanceConfig::gap_reports`](super::InstanceConfig::gap_reports) for
+/// more details.
+#[derive(Debug, Clone, Default)]
+pub struct GapReport {
+    /// Messages sent while the instance was disconnected, oldest first.
+    ///
+    /// Only messages still contained in the log of the new
+    /// [`SnapshotEvent`] are included. For longer gaps, the remaining messages
+    /// must be requested via [`Log`](crate::api::Log) commands.
+    pub messages: Vec<Message>,
+    /// Sessions that are in the room now but weren't before the disconnect.
+    pub joined: Vec<SessionView>,
+    /// Sessions that were in the room before the disconnect but aren't now.
+    pub parted: Vec<SessionInfo>,
+}
+
+impl GapReport {
+    /// Compare the state before a disconnect with the snapshot received after
+    /// reconnecting.
+    ///
+    /// Messages are new if their id is greater than `last_seen`. If no message
+    /// was seen before the disconnect, messages sent after joining the room
+    /// the last time are considered new.
+    pub fn diff(before: &Joined, last_seen: Option<MessageId>, after: &SnapshotEvent) -> Self {
+        let messages = after
+            .log
+            .iter()
+            .filter(|msg| match last_seen {
+                Some(id) => msg.id > id,
+                // Message times only have a resolution of one second
+                None => msg.time.0 >= before.since.as_second(),
+            })
+            .cloned()
+            .collect();
+
+        let joined = after
+            .listing
+            .iter()
+            .filter(|s| !before.listing.contains_key(&s.session_id))
+            .cloned()
+            .collect();
+
+        let mut parted = before
+            .listing
+            .values()
+            .filter(|s| {
+                !after
+                    .listing
+                    .iter()
+                    .any(|a| a.session_id == *s.session_id())
+            })
+            .cloned()
+            .collect::<Vec<_>>();
+        parted.sort_by(|a, b| a.session_id().cmp(b.session_id()));
+
+        Self {
+            messages,
+            joined,
+            parted,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.messages.is_empty() && self.joined.is_empty() && self.parted.is_empty()
+    }
+}
+
+/// Watches the events of an instance to produce [`GapReport`]s.
+#[derive(Debug, Default)]
+pub(super) struct GapTracker {
+    /// The state of the current connection, if it has joined its room.
+    current: Option<Arc<State>>,
+    /// The newest message seen on any connection.
+    last_seen: Option<MessageId>,
+    /// The state of the last connection that had joined its room, kept until
+    /// the next connection joins.
+    previous: Option<Arc<State>>,
+    /// The report to emit after the next [`Event::Joined`].
+    pending: Option<GapReport>,
+}
+
+impl GapTracker {
+    fn see(&mut self, id: MessageId) {
+        self.last_seen = self.last_seen.max(Some(id));
+    }
+
+    /// Observe an event, returning a report to emit right after it.
+    pub(super) fn observe(&mut self, event: &Event) -> Option<GapReport> {
+        match event {
+            Event::Packet(_, packet, snapshot) => {
+                match &packet.content {
+                    Ok(Data::SendEvent(event)) => self.see(event.0.id),
+                    Ok(Data::SendReply(reply)) => self.see(reply.0.id),
+                    Ok(Data::SnapshotEvent(event)) => {
+                        let previous = self.previous.take();
+                        if let Some(before) = previous.as_ref().and_then(|s| s.joined()) {
+                            self.pending = Some(GapReport::diff(before, self.last_seen, event));
+                        }
+                        for msg in &event.log {
+                            self.see(msg.id);
+                        }
+                    }
+                    _ => {}
+                }
+                if snapshot.state.joined().is_some() {
+                    self.current = Some(snapshot.state.clone());
+                }
+                None
+            }
+            Event::Joined(..) => self.pending.take(),
+            Event::Disconnected(_) => {
+                // If reconnecting fails, the gap continues and the state from
+                // before it stays relevant.
+                if let Some(current) = self.current.take() {
+                    self.previous = Some(current);
+                }
+                None
+            }
+            _ => None,
+        }
+    }
+
+    /// Emit an [`Event::GapReport`] after reconnecting, if enabled.
+    pub(super) fn wrap<F: Fn(Event)>(config: &InstanceConfig, on_event: F) -> impl Fn(Event) {
+        let enabled = config.gap_reports;
+        let tracker = Mutex::new(Self::default());
+        move |event| {
+            if !enabled {
+                return on_event(event);
+            }
+            let report = tracker.lock().unwrap().observe(&event);
+            let report = report.map(|r| (event.identity().clone(), r));
+            on_event(event);
+            if let Some((identity, report)) = report {
+                on_event(Event::GapReport(identity, report));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use crate::api::packet::ParsedPacket;
+    use crate::api::{
+        Data, Message, MessageId, NickEvent, SendEvent, SessionId, SessionView, SnapshotEvent,
+        Snowflake, Time, UserId,
+    };
+    use crate::bot::command::test::context;
+    use crate::bot::instance::{ConnSnapshot, Event, InstanceConfig, ServerConfig};
+    use crate::conn::{ConnectTimings, Joined, SessionInfo, State};
+    use crate::test_util::{JoinedBuilder, MessageBuilder, SessionViewBuilder};
+
+    use super::{GapReport, GapTracker};
+
+    fn session(name: &str) -> SessionView {
+        SessionViewBuilder::new(name).build()
+    }
+
+    fn message(id: u64) -> Message {
+        MessageBuilder::new(format!("message {id}"))
+            .with_id(MessageId(Snowflake(id)))
+            .with_time(Time::now())
+            .build()
+    }
+
+    fn snapshot(listing: &[&str], log: &[u64]) -> SnapshotEvent {
+        SnapshotEvent {
+            identity: UserId("bot:me".to_string()),
+            session_id: SessionId("me".to_string()),
+            version: "version".to_string(),
+            listing: listing.iter().map(|n| session(n)).collect(),
+            log: log.iter().map(|id| message(*id)).collect(),
+            nick: None,
+            pm_with_nick: None,
+            pm_with_user_id: None,
+        }
+    }
+
+    fn joined(listing: &[&str]) -> Joined {
+        listing
+            .iter()
+            .fold(JoinedBuilder::new(), |joined, name| {
+                joined.with_listing_entry(SessionInfo::Full(session(name)))
+            })
+            .build()
+    }
+
+    fn packet(data: Data, state: &Arc<State>) -> Event {
+        let packet = ParsedPacket {
+            id: None,
+            r#type: data.packet_type(),
+            content: Ok(data),
+            throttled: None,
+            seq: None,
+        };
+        Event::Packet(config().identity(), packet, conn_snapshot(state))
+    }
+
+    fn conn_snapshot(state: &Arc<State>) -> ConnSnapshot {
+        ConnSnapshot {
+            conn_tx: context().conn_tx,
+            state: state.clone(),
+            connect_timings: ConnectTimings::default(),
+        }
+    }
+
+    fn config() -> InstanceConfig {
+        InstanceConfig::new(ServerConfig::default(), "test").with_gap_reports(true)
+    }
+
+    fn ids(report: &GapReport) -> (Vec<u64>, Vec<String>, Vec<String>) {
+        (
+            report.messages.iter().map(|m| m.id.0 .0).collect(),
+            report.joined.iter().map(|s| s.name.clone()).collect(),
+            report
+                .parted
+                .iter()
+                .map(|s| s.session_id().0.clone())
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn diff() {
+        let mut before = joined(&["a", "b"]);
+        let partial = NickEvent {
+            session_id: SessionId("p".to_string()),
+            id: UserId("agent:p".to_string()),
+            from: String::new(),
+            to: "p".to_string(),
+        };
+        Arc::make_mut(&mut before.listing)
+            .insert(partial.session_id.clone(), SessionInfo::Partial(partial));
+
+        let after = snapshot(&["b", "c"], &[1, 2, 3, 4]);
+        let report = GapReport::diff(&before, Some(MessageId(Snowflake(2))), &after);
+        assert_eq!(
+            ids(&report),
+            (
+                vec![3, 4],
+                vec!["c".to_string()],
+                vec!["a".to_string(), "p".to_string()]
+            )
+        );
+
+        // Without a last seen message, everything since joining is new
+        let report = GapReport::diff(&before, None, &after);
+        assert_eq!(report.messages.len(), 4);
+
+        let report = GapReport::diff(&joined(&["a"]), None, &snapshot(&["a"], &[]));
+        assert!(report.is_empty());
+    }
+
+    #[test]
+    fn tracker() {
+        let mut tracker = GapTracker::default();
+        let first = Arc::new(State::Joined(joined(&["a", "b"])));
+        let second = Arc::new(State::Joined(joined(&["b", "c"])));
+
+        // The first connection produces no report
+        let events = [
+            Event::Connecting(config().identity()),
+            packet(snapshot(&["a", "b"], &[1, 2]).into(), &first),
+            Event::Joined(config().identity(), conn_snapshot(&first), vec![]),
+            packet(SendEvent(Arc::new(message(3))).into(), &first),
+            Event::Disconnected(config().identity()),
+            // A failed reconnect doesn't end the gap
+            Event::Connecting(config().identity()),
+            Event::Disconnected(config().identity()),
+            Event::Connecting(config().identity()),
+        ];
+        for event in &events {
+            assert!(tracker.observe(event).is_none());
+        }
+
+        let event = packet(snapshot(&["b", "c"], &[2, 3, 4, 5]).into(), &second);
+        assert!(tracker.observe(&event).is_none());
+        let event = Event::Joined(config().identity(), conn_snapshot(&second), vec![]);
+        let report = tracker.observe(&event).unwrap();
+        assert_eq!(
+            ids(&report),
+            (vec![4, 5], vec!["c".to_string()], vec!["a".to_string()])
+        );
+
+        // Messages from the new snapshot count as seen
+        tracker.observe(&Event::Disconnected(config().identity()));
+        let event = packet(snapshot(&["b", "c"], &[4, 5, 6]).into(), &second);
+        tracker.observe(&event);
+        let event = Event::Joined(config().identity(), conn_snapshot(&second), vec![]);
+        let report = tracker.observe(&event).unwrap();
+        assert_eq!(ids(&report), (vec![6], vec![], vec![]));
+    }
+}
@@ -0,0 +1,246 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio::time::Instant;
+
+const WINDOW: Duration = Duration::from_secs(60);
+
+/// Limits enforced by a [`ConnectGovernor`].
+///
+/// # Example
+///
+/// ```
+/// use euphoxide::bot::instance::ConnectLimits;
+///
+/// let limits = ConnectLimits::new()
+///     .with_max_concurrent_connects(4)
+///     .with_max_connects_per_minute_opt(None);
+/// assert_eq!(limits.max_concurrent_connects, Some(4));
+/// ```
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ConnectLimits {
+    /// How many connection attempts may be in progress at the same time.
+    pub max_concurrent_connects: Option<usize>,
+    /// How many connection attempts may be started within any period of one
+    /// minute.
+    pub max_connects_per_minute: Option<usize>,
+}
+
+impl ConnectLimits {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_max_concurrent_connects(self, max_concurrent_connects: usize) -> Self {
+        self.with_max_concurrent_connects_opt(Some(max_concurrent_connects))
+    }
+
+    pub fn with_max_concurrent_connects_opt(
+        mut self,
+        max_concurrent_connects: Option<usize>,
+    ) -> Self {
+        self.max_concurrent_connects = max_concurrent_connects;
+        self
+    }
+
+    pub fn with_max_connects_per_minute(self, max_connects_per_minute: usize) -> Self {
+        self.with_max_connects_per_minute_opt(Some(max_connects_per_minute))
+    }
+
+    pub fn with_max_connects_per_minute_opt(
+        mut self,
+        max_connects_per_minute: Option<usize>,
+    ) -> Self {
+        self.max_connects_per_minute = max_connects_per_minute;
+        self
+    }
+}
+
+#[derive(Debug)]
+struct Inner {
+    limits: ConnectLimits,
+    concurrent: Option<Arc<Semaphore>>,
+    recent: Mutex<VecDeque<Instant>>,
+}
+
+/// Limits the connection attempts of all instances sharing it.
+///
+/// When a server goes down, all instances connected to it start reconnecting.
+/// Even with a [`ServerConfig::reconnect_delay`](super::ServerConfig::reconnect_delay),
+/// the combined attempts of many instances in one process can add up. Before
+/// each attempt, an instance with a
+/// [`ServerConfig::connect_governor`](super::ServerConfig::connect_governor)
+/// waits until the governor's [`ConnectLimits`] allow another attempt.
+///
+/// Clones of a governor share their limits. Since the [`ServerConfig`](super::ServerConfig)
+/// is cloned into every [`InstanceConfig`](super::InstanceConfig), setting the
+/// governor on the server config of an [`Instances`](crate::bot::instances::Instances)
+/// limits all its instances together.
+#[derive(Debug, Clone)]
+pub struct ConnectGovernor(Arc<Inner>);
+
+/// Permission to attempt a connection, see [`ConnectGovernor::acquire`].
+///
+/// The attempt counts towards [`ConnectLimits::max_concurrent_connects`]
+/// until the permit is dropped.
+#[derive(Debug)]
+pub struct ConnectPermit {
+    _permit: Option<OwnedSemaphorePermit>,
+}
+
+impl ConnectGovernor {
+    /// Create a governor enforcing some limits.
+    ///
+    /// Limits of zero are treated like limits of one.
+    pub fn new(limits: ConnectLimits) -> Self {
+        let concurrent = limits
+            .max_concurrent_connects
+            .map(|n| Arc::new(Semaphore::new(n.max(1))));
+        Self(Arc::new(Inner {
+            limits,
+            concurrent,
+            recent: Mutex::new(VecDeque::new()),
+        }))
+    }
+
+    pub fn limits(&self) -> ConnectLimits {
+        self.0.limits
+    }
+
+    /// Wait until another connection attempt is allowed.
+    ///
+    /// The attempt counts as started once this function returns.
+    pub async fn acquire(&self) -> ConnectPermit {
+        let permit = match &self.0.concurrent {
+            // The semaphore is never closed
+            Some(semaphore) => Some(semaphore.clone().acquire_owned().await.unwrap()),
+            None => None,
+        };
+
+        if let Some(max) = self.0.limits.max_connects_per_minute {
+            while let Some(until) = self.try_start(max.max(1)) {
+                tokio::time::sleep_until(until).await;
+            }
+        }
+
+        ConnectPermit { _permit: permit }
+    }
+
+    /// Record an attempt if allowed, otherwise return when to try again.
+    fn try_start(&self, max: usize) -> Option<Instant> {
+        let now = Instant::now();
+        let mut recent = self.0.recent.lock().unwrap();
+        while recent.front().is_some_and(|t| *t + WINDOW <= now) {
+            recent.pop_front();
+        }
+
+        if recent.len() < max {
+            recent.push_back(now);
+            return None;
+        }
+        recent.front().map(|t| *t + WINDOW)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    use tokio::time::Instant;
+
+    use super::{ConnectGovernor, ConnectLimits, WINDOW};
+
+    /// Simulate instances failing to connect during an outage, returning the
+    /// start time of every attempt and the highest number of concurrent
+    /// attempts.
+    async fn outage(governor: ConnectGovernor, instances: usize) -> (Vec<Instant>, usize) {
+        let attempts = Arc::new(Mutex::new(vec![]));
+        let current = Arc::new(AtomicUsize::new(0));
+        let highest = Arc::new(AtomicUsize::new(0));
+
+        let tasks = (0..instances)
+            .map(|_| {
+                let governor = governor.clone();
+                let attempts = attempts.clone();
+                let current = current.clone();
+                let highest = highest.clone();
+                tokio::spawn(async move {
+                    for _ in 0..5 {
+                        let permit = governor.acquire().await;
+                        attempts.lock().unwrap().push(Instant::now());
+                        let n = current.fetch_add(1, Ordering::SeqCst) + 1;
+                        highest.fetch_max(n, Ordering::SeqCst);
+
+                        // The attempt times out, then the reconnect delay
+                        tokio::time::sleep(Duration::from_secs(3)).await;
+                        current.fetch_sub(1, Ordering::SeqCst);
+                        drop(permit);
+                        tokio::time::sleep(Duration::from_secs(1)).await;
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        for task in tasks {
+            task.await.unwrap();
+        }
+
+        let mut attempts = attempts.lock().unwrap().clone();
+        attempts.sort_unstable();
+        (attempts, highest.load(Ordering::SeqCst))
+    }
+
+    fn max_per_window(attempts: &[Instant]) -> usize {
+        attempts
+            .iter()
+            .map(|start| {
+                attempts
+                    .iter()
+                    .filter(|t| **t >= *start && **t < *start + WINDOW)
+                    .count()
+            })
+            .max()
+            .unwrap_or(0)
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn unlimited() {
+        let governor = ConnectGovernor::new(ConnectLimits::new());
+        let (attempts, highest) = outage(governor, 50).await;
+        assert_eq!(attempts.len(), 250);
+        assert_eq!(highest, 50);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn concurrent() {
+        let limits = ConnectLimits::new().with_max_concurrent_connects(4);
+        let (attempts, highest) = outage(ConnectGovernor::new(limits), 50).await;
+        assert_eq!(attempts.len(), 250);
+        assert_eq!(highest, 4);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn per_minute() {
+        let limits = ConnectLimits::new().with_max_connects_per_minute(20);
+        let start = Instant::now();
+        let (attempts, _) = outage(ConnectGovernor::new(limits), 50).await;
+        assert_eq!(attempts.len(), 250);
+        assert_eq!(max_per_window(&attempts), 20);
+        // The limit is used up, not undercut
+        assert!(Instant::now() - start < 13 * WINDOW);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn both() {
+        let limits = ConnectLimits::new()
+            .with_max_concurrent_connects(3)
+            .with_max_connects_per_minute(30);
+        let (attempts, highest) = outage(ConnectGovernor::new(limits), 50).await;
+        assert_eq!(attempts.len(), 250);
+        assert!(max_per_window(&attempts) <= 30);
+        assert_eq!(highest, 3);
+    }
+}
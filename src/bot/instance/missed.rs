@@ -0,0 +1,225 @@
+use std::collections::BTreeSet;
+
+use crate::api::packet::ParsedPacket;
+use crate::api::{Data, Message, MessageId, SnapshotEvent};
+
+/// How many live messages a [`MissedMessages`] remembers by default.
+const MISSED_MESSAGES_WINDOW: usize = 1000;
+
+/// How many of the room's most recent messages an instance fetches per check.
+pub(super) const MISSED_MESSAGES_LOG_LEN: usize = 100;
+
+/// Detects messages the server never delivered live.
+///
+/// Since euphoria doesn't number its send-events, a message the server failed
+/// to deliver to a connection is only noticed by comparing the messages that
+/// were delivered with the room's log. This type remembers which messages were
+/// delivered live via [`Self::observe`] and reports the difference to a log
+/// fetched via [`Log`](crate::api::Log) in [`Self::check`].
+///
+/// Only messages sent after the connection's
+/// [`SnapshotEvent`](crate::api::SnapshotEvent) are considered, so messages
+/// sent before joining are never reported. The ids of at most a fixed number of
+/// live messages are remembered. Older messages are no longer considered
+/// either.
+///
+/// The log may contain messages whose send-event is still on its way. A message
+/// newer than all messages delivered live is therefore only reported if it
+/// still hasn't been delivered by the next check.
+///
+/// A single detector should be used per connection. See
+/// [`InstanceConfig::missed_message_checks`](super::InstanceConfig::missed_message_checks)
+/// for having an instance check periodically.
+#[derive(Debug, Clone)]
+pub struct MissedMessages {
+    window: usize,
+    /// Whether a snapshot was observed. Nothing is reported before that.
+    joined: bool,
+    /// Messages up to and including this one are never reported.
+    floor: Option<MessageId>,
+    /// Messages above the floor that were delivered live or reported.
+    seen: BTreeSet<MessageId>,
+    /// Messages newer than all seen messages that were missing in the last
+    /// check.
+    suspects: BTreeSet<MessageId>,
+}
+
+impl MissedMessages {
+    /// Create a detector remembering at most `window` live messages.
+    pub fn new(window: usize) -> Self {
+        Self {
+            window: window.max(1),
+            joined: false,
+            floor: None,
+            seen: BTreeSet::new(),
+            suspects: BTreeSet::new(),
+        }
+    }
+
+    /// Start over with the log of a new snapshot.
+    ///
+    /// All messages in the snapshot count as delivered, and older messages are
+    /// never reported.
+    pub fn observe_snapshot(&mut self, snapshot: &SnapshotEvent) {
+        self.joined = true;
+        self.floor = snapshot.log.iter().map(|msg| msg.id).max();
+        self.seen.clear();
+        self.suspects.clear();
+    }
+
+    /// Remember that a message was delivered live.
+    pub fn observe_message(&mut self, id: MessageId) {
+        if Some(id) <= self.floor {
+            return;
+        }
+        self.seen.insert(id);
+        self.suspects.remove(&id);
+        while self.seen.len() > self.window {
+            self.floor = self.seen.pop_first();
+        }
+    }
+
+    /// Observe a packet received by the connection.
+    ///
+    /// Snapshot-events, send-events and send-replies are taken into account.
+    pub fn observe(&mut self, packet: &ParsedPacket) {
+        match &packet.content {
+            Ok(Data::SnapshotEvent(event)) => self.observe_snapshot(event),
+            Ok(Data::SendEvent(event)) => self.observe_message(event.0.id),
+            Ok(Data::SendReply(reply)) => self.observe_message(reply.0.id),
+            _ => {}
+        }
+    }
+
+    /// Compare a freshly fetched part of the log with the messages delivered
+    /// live and return the missed messages, oldest first.
+    ///
+    /// Reported messages count as delivered afterwards, so they are only
+    /// reported once.
+    pub fn check(&mut self, log: &[Message]) -> Vec<Message> {
+        if !self.joined {
+            return vec![];
+        }
+
+        let newest = self.seen.last().copied();
+        let mut suspects = BTreeSet::new();
+        let mut missed = log
+            .iter()
+            .filter(|msg| Some(msg.id) > self.floor && !self.seen.contains(&msg.id))
+            .filter(|msg| {
+                if Some(msg.id) <= newest || self.suspects.contains(&msg.id) {
+                    true
+                } else {
+                    suspects.insert(msg.id);
+                    false
+                }
+            })
+            .cloned()
+            .collect::<Vec<_>>();
+        missed.sort_by_key(|msg| msg.id);
+        missed.dedup_by_key(|msg| msg.id);
+
+        self.suspects = suspects;
+        for msg in &missed {
+            self.observe_message(msg.id);
+        }
+        missed
+    }
+}
+
+impl Default for MissedMessages {
+    fn default() -> Self {
+        Self::new(MISSED_MESSAGES_WINDOW)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::api::{Message, MessageId, SessionId, SnapshotEvent, Snowflake, Time, UserId};
+    use crate::test_util::MessageBuilder;
+
+    use super::MissedMessages;
+
+    fn message(id: u64) -> Message {
+        MessageBuilder::new(format!("message {id}"))
+            .with_id(MessageId(Snowflake(id)))
+            .with_time(Time(id as i64))
+            .build()
+    }
+
+    fn snapshot(log: &[u64]) -> SnapshotEvent {
+        SnapshotEvent {
+            identity: UserId("bot:me".to_string()),
+            session_id: SessionId("me".to_string()),
+            version: "version".to_string(),
+            listing: vec![],
+            log: log.iter().map(|id| message(*id)).collect(),
+            nick: None,
+            pm_with_nick: None,
+            pm_with_user_id: None,
+        }
+    }
+
+    fn log(ids: impl IntoIterator<Item = u64>) -> Vec<Message> {
+        ids.into_iter().map(message).collect()
+    }
+
+    fn ids(missed: &[Message]) -> Vec<u64> {
+        missed.iter().map(|m| m.id.0 .0).collect()
+    }
+
+    fn live(detector: &mut MissedMessages, ids: &[u64]) {
+        for id in ids {
+            detector.observe_message(MessageId(Snowflake(*id)));
+        }
+    }
+
+    #[test]
+    fn reports_exactly_the_difference() {
+        let mut detector = MissedMessages::default();
+        assert!(detector.check(&log(1..=5)).is_empty(), "not joined yet");
+
+        detector.observe_snapshot(&snapshot(&[1, 2, 3]));
+        live(&mut detector, &[4, 5, 7, 10, 11]);
+
+        // Messages from before joining are never reported
+        let missed = detector.check(&log(1..=11));
+        assert_eq!(ids(&missed), [6, 8, 9]);
+        assert_eq!(missed[0].content, "message 6");
+
+        // Reported messages are only reported once
+        assert!(detector.check(&log(1..=11)).is_empty());
+    }
+
+    #[test]
+    fn newest_messages_get_another_chance() {
+        let mut detector = MissedMessages::default();
+        detector.observe_snapshot(&snapshot(&[]));
+        live(&mut detector, &[1, 2]);
+
+        // 3 and 4 may still be on their way
+        assert!(detector.check(&log(1..=4)).is_empty());
+
+        // 3 arrived in the meantime, 4 didn't
+        live(&mut detector, &[3]);
+        assert_eq!(ids(&detector.check(&log(1..=5))), [4]);
+
+        // 5 is newer than anything seen, but was already suspect
+        assert_eq!(ids(&detector.check(&log(1..=5))), [5]);
+    }
+
+    #[test]
+    fn window_is_bounded() {
+        let mut detector = MissedMessages::new(3);
+        detector.observe_snapshot(&snapshot(&[1]));
+        live(&mut detector, &[2, 4, 6, 7, 8]);
+
+        // Only 6, 7 and 8 are remembered, so 3 is out of the window
+        assert_eq!(ids(&detector.check(&log(1..=8))), [5]);
+
+        // A new snapshot starts over
+        detector.observe_snapshot(&snapshot(&[7, 8, 9]));
+        live(&mut detector, &[11]);
+        assert_eq!(ids(&detector.check(&log(5..=11))), [10]);
+    }
+}
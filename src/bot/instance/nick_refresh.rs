@@ -0,0 +1,149 @@
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+/// When an instance re-sends its nick, see
+/// [`InstanceConfig::nick_refresh_interval`](super::InstanceConfig::nick_refresh_interval).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum NickRefreshMode {
+    /// Only if the session's nick differs from the configured
+    /// [`username`](super::InstanceConfig::username).
+    ///
+    /// This also restores the nick if it was changed manually.
+    #[default]
+    IfDifferent,
+    /// Only if the session has no nick.
+    ///
+    /// Nicks changed manually to something else are kept.
+    IfEmpty,
+    /// Always, even if the nick seems to be set correctly.
+    ///
+    /// The server may drop a nick without telling the session about it, so
+    /// this is the only mode that reliably keeps the nick alive. Like
+    /// [`Self::IfDifferent`], it overrides nicks changed manually.
+    Always,
+}
+
+/// Decides when to re-send the nick.
+#[derive(Debug)]
+pub(super) struct NickRefresher {
+    username: String,
+    mode: NickRefreshMode,
+    suppression: Duration,
+    last_name: Option<String>,
+    last_set: Instant,
+}
+
+impl NickRefresher {
+    pub(super) fn new(
+        username: String,
+        mode: NickRefreshMode,
+        suppression: Duration,
+        now: Instant,
+    ) -> Self {
+        Self {
+            username,
+            mode,
+            suppression,
+            last_name: None,
+            last_set: now,
+        }
+    }
+
+    pub(super) fn username(&self) -> &str {
+        &self.username
+    }
+
+    /// Refresh a different nick from now on.
+    pub(super) fn set_username(&mut self, username: String) {
+        self.username = username;
+    }
+
+    /// Keep track of the session's current nick.
+    ///
+    /// A change to a non-empty nick counts as a successful nick set.
+    pub(super) fn observe(&mut self, name: &str, now: Instant) {
+        if name.is_empty() {
+            self.last_name = None;
+            return;
+        }
+        if self.last_name.as_deref() == Some(name) {
+            return;
+        }
+        self.last_name = Some(name.to_string());
+        self.last_set = now;
+    }
+
+    pub(super) fn should_refresh(&self, name: &str, now: Instant) -> bool {
+        if now < self.last_set + self.suppression {
+            return false;
+        }
+        match self.mode {
+            NickRefreshMode::IfDifferent => name != self.username,
+            NickRefreshMode::IfEmpty => name.is_empty(),
+            NickRefreshMode::Always => true,
+        }
+    }
+
+    pub(super) fn refreshed(&mut self, now: Instant) {
+        self.last_set = now;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use tokio::time::Instant;
+
+    use super::{NickRefreshMode, NickRefresher};
+
+    const MINUTE: Duration = Duration::from_secs(60);
+
+    fn refresher(mode: NickRefreshMode) -> NickRefresher {
+        NickRefresher::new("TestBot".to_string(), mode, 5 * MINUTE, Instant::now())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn suppression_window() {
+        let mut refresher = refresher(NickRefreshMode::Always);
+        assert!(!refresher.should_refresh("TestBot", Instant::now()));
+
+        tokio::time::sleep(4 * MINUTE).await;
+        refresher.observe("TestBot", Instant::now());
+        tokio::time::sleep(4 * MINUTE).await;
+        assert!(!refresher.should_refresh("TestBot", Instant::now()));
+        tokio::time::sleep(MINUTE).await;
+        assert!(refresher.should_refresh("TestBot", Instant::now()));
+
+        refresher.refreshed(Instant::now());
+        assert!(!refresher.should_refresh("TestBot", Instant::now()));
+
+        // Observing the same nick again is not a new nick set
+        tokio::time::sleep(5 * MINUTE).await;
+        refresher.observe("TestBot", Instant::now());
+        assert!(refresher.should_refresh("TestBot", Instant::now()));
+
+        // Losing the nick is not a nick set either, but getting it back is
+        refresher.observe("", Instant::now());
+        assert!(refresher.should_refresh("", Instant::now()));
+        refresher.observe("TestBot", Instant::now());
+        assert!(!refresher.should_refresh("TestBot", Instant::now()));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn modes() {
+        let cases = [
+            (NickRefreshMode::IfDifferent, [false, true, true]),
+            (NickRefreshMode::IfEmpty, [false, true, false]),
+            (NickRefreshMode::Always, [true, true, true]),
+        ];
+        for (mode, expected) in cases {
+            let refresher = refresher(mode);
+            tokio::time::sleep(5 * MINUTE).await;
+            let now = Instant::now();
+            let actual = ["TestBot", "", "Manual"].map(|n| refresher.should_refresh(n, now));
+            assert_eq!(actual, expected, "{mode:?}");
+        }
+    }
+}
@@ -0,0 +1,205 @@
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+use jiff::Timestamp;
+use serde::{Deserialize, Serialize};
+
+use crate::api;
+
+/// A message submitted via
+/// [`Instance::send_durable`](super::Instance::send_durable) whose
+/// [`SendReply`](api::SendReply) has not been received yet.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PendingSend {
+    /// Unique tag generated by the client when the message was submitted.
+    ///
+    /// It stays the same when the message is sent again after a restart, so it
+    /// can be used to detect duplicates, see
+    /// [`InstanceConfig::tag_durable`](super::InstanceConfig::tag_durable).
+    pub tag: String,
+    pub send: api::Send,
+}
+
+impl PendingSend {
+    pub(super) fn new(send: api::Send) -> Self {
+        static COUNTER: AtomicU64 = AtomicU64::new(0);
+        let nanos = Timestamp::now().as_nanosecond();
+        let count = COUNTER.fetch_add(1, Ordering::Relaxed);
+        let tag = format!("{nanos:x}-{:x}-{count:x}", std::process::id());
+        Self { tag, send }
+    }
+}
+
+/// Storage for messages sent via
+/// [`Instance::send_durable`](super::Instance::send_durable) that should
+/// survive process restarts.
+///
+/// Messages are pushed when they are submitted and acknowledged once the server
+/// has replied to them. An instance sends all messages returned by
+/// [`Self::drain`] after joining its room.
+pub trait Outbox: fmt::Debug + Send + Sync {
+    /// Add a message to the end of the outbox.
+    fn push(&self, pending: PendingSend) -> io::Result<()>;
+
+    /// All messages that have not been acknowledged yet, oldest first.
+    ///
+    /// The messages stay in the outbox until they are acknowledged.
+    fn drain(&self) -> io::Result<Vec<PendingSend>>;
+
+    /// Remove the message with the given tag from the outbox.
+    fn ack(&self, tag: &str) -> io::Result<()>;
+}
+
+/// An [`Outbox`] stored in a file, one JSON-encoded [`PendingSend`] per line.
+///
+/// The file is appended to when messages are pushed and rewritten when they
+/// are acknowledged.
+#[derive(Debug)]
+pub struct FileOutbox {
+    path: PathBuf,
+    pending: Mutex<Vec<PendingSend>>,
+}
+
+impl FileOutbox {
+    /// Open an outbox file, creating it if it doesn't exist.
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .read(true)
+            .open(&path)?;
+
+        let mut pending = vec![];
+        for line in BufReader::new(file).lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            pending.push(serde_json::from_str(&line)?);
+        }
+
+        Ok(Self {
+            path,
+            pending: Mutex::new(pending),
+        })
+    }
+
+    fn write_line(file: &mut File, pending: &PendingSend) -> io::Result<()> {
+        let mut line = serde_json::to_string(pending)?;
+        line.push('\n');
+        file.write_all(line.as_bytes())
+    }
+}
+
+impl Outbox for FileOutbox {
+    fn push(&self, pending: PendingSend) -> io::Result<()> {
+        let mut guard = self.pending.lock().unwrap();
+        let mut file = OpenOptions::new().append(true).open(&self.path)?;
+        Self::write_line(&mut file, &pending)?;
+        file.sync_data()?;
+        guard.push(pending);
+        Ok(())
+    }
+
+    fn drain(&self) -> io::Result<Vec<PendingSend>> {
+        Ok(self.pending.lock().unwrap().clone())
+    }
+
+    fn ack(&self, tag: &str) -> io::Result<()> {
+        let mut guard = self.pending.lock().unwrap();
+        let remaining = guard
+            .iter()
+            .filter(|p| p.tag != tag)
+            .cloned()
+            .collect::<Vec<_>>();
+        if remaining.len() == guard.len() {
+            return Ok(());
+        }
+
+        // Write to a temporary file first so a crash can't leave the outbox
+        // half-written.
+        let mut tmp_path = self.path.clone().into_os_string();
+        tmp_path.push(".tmp");
+        let mut file = File::create(&tmp_path)?;
+        for pending in &remaining {
+            Self::write_line(&mut file, pending)?;
+        }
+        file.sync_data()?;
+        fs::rename(&tmp_path, &self.path)?;
+
+        *guard = remaining;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+pub(super) mod test {
+    use std::path::PathBuf;
+    use std::{fs, process};
+
+    use crate::api;
+
+    use super::{FileOutbox, Outbox, PendingSend};
+
+    /// A fresh path in the temp directory, unique per test.
+    pub(in super::super) fn path(name: &str) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("euphoxide-outbox-{}-{name}.jsonl", process::id()));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    pub(in super::super) fn pending(content: &str) -> PendingSend {
+        PendingSend::new(api::Send {
+            content: content.to_string(),
+            parent: None,
+        })
+    }
+
+    pub(in super::super) fn contents(outbox: &dyn Outbox) -> Vec<String> {
+        outbox
+            .drain()
+            .unwrap()
+            .into_iter()
+            .map(|p| p.send.content)
+            .collect()
+    }
+
+    #[test]
+    fn tags_are_unique() {
+        assert_ne!(pending("a").tag, pending("a").tag);
+    }
+
+    #[test]
+    fn survives_reopening() {
+        let path = path("survives_reopening");
+        let outbox = FileOutbox::open(&path).unwrap();
+        assert!(contents(&outbox).is_empty());
+
+        let a = pending("a");
+        outbox.push(a.clone()).unwrap();
+        outbox.push(pending("b")).unwrap();
+        outbox.push(pending("c")).unwrap();
+        outbox.ack(&a.tag).unwrap();
+        outbox.ack("unknown").unwrap();
+        assert_eq!(contents(&outbox), ["b", "c"]);
+
+        // Simulate a crash by dropping the outbox without further cleanup
+        drop(outbox);
+        let outbox = FileOutbox::open(&path).unwrap();
+        assert_eq!(contents(&outbox), ["b", "c"]);
+
+        for pending in outbox.drain().unwrap() {
+            outbox.ack(&pending.tag).unwrap();
+        }
+        let outbox = FileOutbox::open(&path).unwrap();
+        assert!(contents(&outbox).is_empty());
+
+        fs::remove_file(path).unwrap();
+    }
+}
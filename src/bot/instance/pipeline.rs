@@ -0,0 +1,114 @@
+use std::fmt;
+
+use crate::api::packet::PacketSeq;
+
+/// A stage packets pass through on their way from the server to the event
+/// handler, see [`PipelineReport`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PipelineStage {
+    /// The [`Conn`](crate::conn::Conn) received the packet from the server.
+    Received,
+    /// The [`Conn`](crate::conn::Conn) returned the packet from
+    /// [`Conn::recv`](crate::conn::Conn::recv).
+    Parsed,
+    /// The instance emitted the packet as [`Event::Packet`](super::Event::Packet).
+    ///
+    /// For instances created via [`Instance::new`](super::Instance::new), this
+    /// happens once the event handler has returned. For instances created via
+    /// [`Instance::with_sender`](super::Instance::with_sender), it happens once
+    /// the event was sent to the channel.
+    Emitted,
+    /// The consumer acknowledged the packet via
+    /// [`Instance::ack_delivered`](super::Instance::ack_delivered).
+    Delivered,
+}
+
+/// How many packets of the instance's current connection reached each
+/// [`PipelineStage`].
+///
+/// Each stage should eventually have the same count as the stage before it. If
+/// packets go missing, the first stage with a lower count is where they were
+/// lost, see [`Self::first_gap`]. Since packets pass through the stages one
+/// after the other, small gaps are normal while packets are in flight.
+///
+/// The counts are reset whenever the instance connects, and only packets
+/// received on the connection with the current
+/// [`generation`](PacketSeq::generation) are counted.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PipelineReport {
+    /// The [`Conn::generation`](crate::conn::Conn::generation) of the current
+    /// connection, if the instance has connected yet.
+    pub generation: Option<u64>,
+    pub received: u64,
+    pub parsed: u64,
+    pub emitted: u64,
+    pub delivered: u64,
+}
+
+impl PipelineReport {
+    pub fn count(&self, stage: PipelineStage) -> u64 {
+        match stage {
+            PipelineStage::Received => self.received,
+            PipelineStage::Parsed => self.parsed,
+            PipelineStage::Emitted => self.emitted,
+            PipelineStage::Delivered => self.delivered,
+        }
+    }
+
+    /// The first stage that has seen fewer packets than the stage before it.
+    ///
+    /// A gap at [`PipelineStage::Parsed`] can also be caused by malformed
+    /// packets that were skipped.
+    pub fn first_gap(&self) -> Option<PipelineStage> {
+        let stages = [
+            PipelineStage::Received,
+            PipelineStage::Parsed,
+            PipelineStage::Emitted,
+            PipelineStage::Delivered,
+        ];
+        stages
+            .windows(2)
+            .find(|w| self.count(w[1]) < self.count(w[0]))
+            .map(|w| w[1])
+    }
+
+    pub(super) fn on_connected(&mut self, generation: u64) {
+        *self = Self {
+            generation: Some(generation),
+            ..Self::default()
+        };
+    }
+
+    fn is_current(&self, seq: Option<PacketSeq>) -> bool {
+        seq.is_some_and(|seq| Some(seq.generation) == self.generation)
+    }
+
+    pub(super) fn on_parsed(&mut self, seq: Option<PacketSeq>) {
+        if let Some(seq) = seq.filter(|_| self.is_current(seq)) {
+            self.received = self.received.max(seq.seq + 1);
+            self.parsed += 1;
+        }
+    }
+
+    pub(super) fn on_emitted(&mut self, seq: Option<PacketSeq>) {
+        if self.is_current(seq) {
+            self.emitted += 1;
+        }
+    }
+
+    pub(super) fn on_delivered(&mut self, seq: Option<PacketSeq>) {
+        if self.is_current(seq) {
+            self.delivered += 1;
+        }
+    }
+}
+
+impl fmt::Display for PipelineReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} received, {} parsed, {} emitted, {} delivered",
+            self.received, self.parsed, self.emitted, self.delivered
+        )
+    }
+}
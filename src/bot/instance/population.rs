@@ -0,0 +1,53 @@
+use std::collections::VecDeque;
+
+use jiff::Timestamp;
+
+use crate::conn::Joined;
+
+/// How many [`PopulationSample`]s an [`Instance`](super::Instance) remembers.
+pub(super) const POPULATION_HISTORY_LEN: usize = 1440;
+
+/// The number of sessions in a room at a specific point in time.
+///
+/// See [`InstanceConfig::population_sampling`](super::InstanceConfig::population_sampling)
+/// for more details.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PopulationSample {
+    pub time: Timestamp,
+    /// See [`Joined::count_sessions`].
+    pub total: usize,
+    /// See [`Joined::count_humans`].
+    pub humans: usize,
+    /// See [`Joined::count_bots`].
+    pub bots: usize,
+}
+
+impl PopulationSample {
+    /// A sample of the room's population at `time`.
+    pub fn at(joined: &Joined, time: Timestamp) -> Self {
+        let total = joined.count_sessions();
+        let humans = joined.count_humans();
+        Self {
+            time,
+            total,
+            humans,
+            bots: total - humans,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub(super) struct PopulationHistory(pub(super) VecDeque<PopulationSample>);
+
+impl PopulationHistory {
+    pub(super) fn push(&mut self, sample: PopulationSample) {
+        if self.0.len() >= POPULATION_HISTORY_LEN {
+            self.0.pop_front();
+        }
+        self.0.push_back(sample);
+    }
+
+    pub(super) fn last(&self) -> Option<&PopulationSample> {
+        self.0.back()
+    }
+}
@@ -0,0 +1,208 @@
+use std::mem;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use jiff::Timestamp;
+use tokio::sync::Notify;
+
+use crate::api;
+use crate::clock::Clock;
+
+/// What an [`Instance`](super::Instance) should do with scheduled messages that
+/// became due while it was not connected to its room.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LateSchedules {
+    /// Send them immediately after joining the room again.
+    #[default]
+    Send,
+    /// Don't send them at all.
+    Drop,
+}
+
+/// A message scheduled to be sent at a specific time.
+///
+/// See [`Instance::schedule`](super::Instance::schedule) for more details.
+#[derive(Debug, Clone)]
+pub struct Scheduled {
+    /// Unique (per instance) id of the scheduled message.
+    pub id: u64,
+    /// When to send the message.
+    pub at: Timestamp,
+    pub send: api::Send,
+}
+
+#[derive(Debug, Default)]
+struct Queue {
+    next_id: u64,
+    pending: Vec<Scheduled>,
+}
+
+/// Messages scheduled on an instance, shared between the instance and its
+/// task.
+#[derive(Debug, Default)]
+pub(super) struct Schedules {
+    queue: Mutex<Queue>,
+    changed: Notify,
+}
+
+impl Schedules {
+    pub(super) fn add(&self, at: Timestamp, send: api::Send) -> u64 {
+        let mut queue = self.queue.lock().unwrap();
+        let id = queue.next_id;
+        queue.next_id += 1;
+        queue.pending.push(Scheduled { id, at, send });
+        drop(queue);
+
+        self.changed.notify_one();
+        id
+    }
+
+    pub(super) fn cancel(&self, id: u64) -> bool {
+        let mut queue = self.queue.lock().unwrap();
+        let len = queue.pending.len();
+        queue.pending.retain(|s| s.id != id);
+        let removed = queue.pending.len() < len;
+        drop(queue);
+
+        self.changed.notify_one();
+        removed
+    }
+
+    /// All pending messages, ordered by when they are due.
+    pub(super) fn pending(&self) -> Vec<Scheduled> {
+        let mut pending = self.queue.lock().unwrap().pending.clone();
+        pending.sort_by_key(|s| (s.at, s.id));
+        pending
+    }
+
+    /// Remove and return all messages due at `now`, ordered by when they were
+    /// due.
+    pub(super) fn take_due(&self, now: Timestamp) -> Vec<Scheduled> {
+        let mut queue = self.queue.lock().unwrap();
+        let (mut due, pending) = mem::take(&mut queue.pending)
+            .into_iter()
+            .partition::<Vec<_>, _>(|s| s.at <= now);
+        queue.pending = pending;
+        due.sort_by_key(|s| (s.at, s.id));
+        due
+    }
+
+    /// Wait until the next message may be due according to the clock.
+    ///
+    /// Returns early whenever messages are added or cancelled, or when the
+    /// clock [jumped](Clock::jumped).
+    pub(super) async fn wait(&self, clock: &dyn Clock) {
+        let jumped = clock.jumped();
+        let next = self
+            .queue
+            .lock()
+            .unwrap()
+            .pending
+            .iter()
+            .map(|s| s.at)
+            .min();
+        match next {
+            Some(at) => {
+                let delay = Duration::try_from(at.duration_since(clock.now()));
+                tokio::select! {
+                    _ = tokio::time::sleep(delay.unwrap_or_default()) => {}
+                    _ = self.changed.notified() => {}
+                    _ = jumped => {}
+                }
+            }
+            None => self.changed.notified().await,
+        }
+    }
+}
+
+/// A handle to a message scheduled via
+/// [`Instance::schedule`](super::Instance::schedule).
+///
+/// Dropping the handle does not cancel the message.
+#[derive(Debug, Clone)]
+pub struct ScheduleHandle {
+    pub(super) id: u64,
+    pub(super) schedules: Arc<Schedules>,
+}
+
+impl ScheduleHandle {
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Cancel the message.
+    ///
+    /// Returns `false` if the message was already sent, dropped or cancelled.
+    pub fn cancel(&self) -> bool {
+        self.schedules.cancel(self.id)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use jiff::{Timestamp, ToSpan};
+
+    use crate::api;
+    use crate::clock::SystemClock;
+
+    use super::Schedules;
+
+    fn send(content: &str) -> api::Send {
+        api::Send {
+            content: content.to_string(),
+            parent: None,
+        }
+    }
+
+    fn contents(due: Vec<super::Scheduled>) -> Vec<String> {
+        due.into_iter().map(|s| s.send.content).collect()
+    }
+
+    #[test]
+    fn due_in_order() {
+        let schedules = Schedules::default();
+        let now = Timestamp::now();
+        schedules.add(now + 3.seconds(), send("c"));
+        schedules.add(now + 1.second(), send("a"));
+        schedules.add(now + 2.seconds(), send("b"));
+
+        assert!(schedules.take_due(now).is_empty());
+        assert_eq!(contents(schedules.take_due(now + 2.seconds())), ["a", "b"]);
+        assert_eq!(contents(schedules.pending()), ["c"]);
+        assert_eq!(contents(schedules.take_due(now + 1.hour())), ["c"]);
+        assert!(schedules.pending().is_empty());
+    }
+
+    #[test]
+    fn cancel() {
+        let schedules = Schedules::default();
+        let now = Timestamp::now();
+        let a = schedules.add(now + 1.second(), send("a"));
+        schedules.add(now + 1.second(), send("b"));
+
+        assert!(schedules.cancel(a));
+        assert!(!schedules.cancel(a));
+        assert_eq!(contents(schedules.take_due(now + 1.second())), ["b"]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn wait_is_interrupted_by_changes() {
+        let schedules = Arc::new(Schedules::default());
+
+        let waiting = tokio::spawn({
+            let schedules = schedules.clone();
+            async move { schedules.wait(&SystemClock).await }
+        });
+        tokio::task::yield_now().await;
+        assert!(!waiting.is_finished());
+
+        schedules.add(Timestamp::now() + 1.hour(), send("a"));
+        tokio::time::timeout(Duration::from_secs(1), waiting)
+            .await
+            .unwrap()
+            .unwrap();
+    }
+}
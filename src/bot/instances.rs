@@ -1,10 +1,297 @@
 //! A convenient way to keep a [`ServerConfig`] and some [`Instance`]s.
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::time::Duration;
+use std::{fmt, ptr};
 
-use super::instance::{self, Instance, ServerConfig};
+use crate::api::{self, PmId, PmInitiate, PmInitiateEvent, UserId};
+use crate::conn;
+
+use super::instance::{self, Instance, InstanceConfig, ServerConfig};
+
+/// A field of an [`InstanceConfig`], including the fields of its
+/// [`ServerConfig`].
+///
+/// See [`config_changes`] and [`Instances::reconcile`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConfigField {
+    Timeout,
+    ReconnectDelay,
+    ReconnectDelayMax,
+    ReconnectJitter,
+    Domain,
+    Tls,
+    OnMalformed,
+    SlowMode,
+    SendRate,
+    ParentCheck,
+    PermissionCheck,
+    MessageTimes,
+    MessageLog,
+    Room,
+    Human,
+    Username,
+    ForceUsername,
+    Password,
+    LateSchedules,
+    PopulationSampling,
+    PopulationSamplingDelta,
+    StopWhenUnobserved,
+    JoinAfterNick,
+    Outbox,
+    TagDurable,
+    NickRefreshInterval,
+    NickRefreshMode,
+    NickRefreshSuppression,
+    DuplicatePolicy,
+    DuplicateDeferTimeout,
+    GapReports,
+    MissedMessageChecks,
+}
+
+impl ConfigField {
+    /// Whether a running instance must reconnect for a change of this field to
+    /// take effect.
+    ///
+    /// These fields determine where the instance connects to and who it is
+    /// once it has joined the room. Changes to all other fields only affect
+    /// how the instance behaves, and are applied the next time it is created.
+    pub fn requires_reconnect(self) -> bool {
+        match self {
+            Self::Timeout
+            | Self::Domain
+            | Self::Tls
+            | Self::Room
+            | Self::Human
+            | Self::Username
+            | Self::ForceUsername
+            | Self::Password => true,
+            Self::ReconnectDelay
+            | Self::ReconnectDelayMax
+            | Self::ReconnectJitter
+            | Self::OnMalformed
+            | Self::SlowMode
+            | Self::SendRate
+            | Self::ParentCheck
+            | Self::PermissionCheck
+            | Self::MessageTimes
+            | Self::MessageLog
+            | Self::LateSchedules
+            | Self::PopulationSampling
+            | Self::PopulationSamplingDelta
+            | Self::StopWhenUnobserved
+            | Self::JoinAfterNick
+            | Self::Outbox
+            | Self::TagDurable
+            | Self::NickRefreshInterval
+            | Self::NickRefreshMode
+            | Self::NickRefreshSuppression
+            | Self::DuplicatePolicy
+            | Self::DuplicateDeferTimeout
+            | Self::GapReports
+            | Self::MissedMessageChecks => false,
+        }
+    }
+}
+
+/// The fields that differ between two configs.
+///
+/// The [`InstanceConfig::name`] is not compared since it identifies an
+/// instance. The [`ServerConfig::cookies`] are not compared either since they
+/// are updated by the instances themselves, and neither is the
+/// [`ServerConfig::connect_governor`] and [`ServerConfig::clock`] since they
+/// are shared between instances.
+/// Outboxes are compared by identity.
+pub fn config_changes(old: &InstanceConfig, new: &InstanceConfig) -> Vec<ConfigField> {
+    // Destructuring ensures that new fields aren't forgotten here.
+    let InstanceConfig {
+        server,
+        name: _,
+        room,
+        human,
+        username,
+        force_username,
+        password,
+        late_schedules,
+        population_sampling,
+        population_sampling_delta,
+        stop_when_unobserved,
+        join_after_nick,
+        outbox,
+        tag_durable,
+        nick_refresh_interval,
+        nick_refresh_mode,
+        nick_refresh_suppression,
+        duplicate_policy,
+        duplicate_defer_timeout,
+        gap_reports,
+        missed_message_checks,
+    } = new;
+    let ServerConfig {
+        timeout,
+        reconnect_delay,
+        reconnect_delay_max,
+        reconnect_jitter,
+        domain,
+        tls,
+        cookies: _,
+        connect_governor: _,
+        clock: _,
+        on_malformed,
+        slow_mode,
+        send_rate,
+        parent_check,
+        permission_check,
+        message_times,
+        message_log,
+    } = server;
+
+    let outboxes_eq = match (&old.outbox, outbox) {
+        (Some(a), Some(b)) => Arc::ptr_eq(a, b),
+        (a, b) => a.is_none() && b.is_none(),
+    };
+    let tag_durables_eq = match (old.tag_durable, *tag_durable) {
+        (Some(a), Some(b)) => ptr::fn_addr_eq(a, b),
+        (a, b) => a.is_none() && b.is_none(),
+    };
+
+    [
+        (old.server.timeout != *timeout, ConfigField::Timeout),
+        (
+            old.server.reconnect_delay != *reconnect_delay,
+            ConfigField::ReconnectDelay,
+        ),
+        (
+            old.server.reconnect_delay_max != *reconnect_delay_max,
+            ConfigField::ReconnectDelayMax,
+        ),
+        (
+            old.server.reconnect_jitter != *reconnect_jitter,
+            ConfigField::ReconnectJitter,
+        ),
+        (old.server.domain != *domain, ConfigField::Domain),
+        (old.server.tls != *tls, ConfigField::Tls),
+        (
+            old.server.on_malformed != *on_malformed,
+            ConfigField::OnMalformed,
+        ),
+        (old.server.slow_mode != *slow_mode, ConfigField::SlowMode),
+        (old.server.send_rate != *send_rate, ConfigField::SendRate),
+        (
+            old.server.parent_check != *parent_check,
+            ConfigField::ParentCheck,
+        ),
+        (
+            old.server.permission_check != *permission_check,
+            ConfigField::PermissionCheck,
+        ),
+        (
+            old.server.message_times != *message_times,
+            ConfigField::MessageTimes,
+        ),
+        (
+            old.server.message_log != *message_log,
+            ConfigField::MessageLog,
+        ),
+        (old.room != *room, ConfigField::Room),
+        (old.human != *human, ConfigField::Human),
+        (old.username != *username, ConfigField::Username),
+        (
+            old.force_username != *force_username,
+            ConfigField::ForceUsername,
+        ),
+        (old.password != *password, ConfigField::Password),
+        (
+            old.late_schedules != *late_schedules,
+            ConfigField::LateSchedules,
+        ),
+        (
+            old.population_sampling != *population_sampling,
+            ConfigField::PopulationSampling,
+        ),
+        (
+            old.population_sampling_delta != *population_sampling_delta,
+            ConfigField::PopulationSamplingDelta,
+        ),
+        (
+            old.stop_when_unobserved != *stop_when_unobserved,
+            ConfigField::StopWhenUnobserved,
+        ),
+        (
+            old.join_after_nick != *join_after_nick,
+            ConfigField::JoinAfterNick,
+        ),
+        (!outboxes_eq, ConfigField::Outbox),
+        (!tag_durables_eq, ConfigField::TagDurable),
+        (
+            old.nick_refresh_interval != *nick_refresh_interval,
+            ConfigField::NickRefreshInterval,
+        ),
+        (
+            old.nick_refresh_mode != *nick_refresh_mode,
+            ConfigField::NickRefreshMode,
+        ),
+        (
+            old.nick_refresh_suppression != *nick_refresh_suppression,
+            ConfigField::NickRefreshSuppression,
+        ),
+        (
+            old.duplicate_policy != *duplicate_policy,
+            ConfigField::DuplicatePolicy,
+        ),
+        (
+            old.duplicate_defer_timeout != *duplicate_defer_timeout,
+            ConfigField::DuplicateDeferTimeout,
+        ),
+        (old.gap_reports != *gap_reports, ConfigField::GapReports),
+        (
+            old.missed_message_checks != *missed_message_checks,
+            ConfigField::MissedMessageChecks,
+        ),
+    ]
+    .into_iter()
+    .filter(|(changed, _)| *changed)
+    .map(|(_, field)| field)
+    .collect()
+}
+
+/// What [`Instances::reconcile`] did, listing instances by name.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ReconcileReport {
+    /// Instances that were not running before.
+    pub added: Vec<String>,
+    /// Instances that were stopped because they are no longer desired.
+    pub removed: Vec<String>,
+    /// Instances that were replaced because their config changed in a way
+    /// that requires reconnecting.
+    pub restarted: Vec<String>,
+    /// Instances that were replaced because their config changed, but only in
+    /// ways that don't require reconnecting.
+    pub updated: Vec<String>,
+    /// Instances that were left running because their config didn't change.
+    pub unchanged: Vec<String>,
+}
+
+impl fmt::Display for ReconcileReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} added, {} removed, {} restarted, {} updated, {} unchanged",
+            self.added.len(),
+            self.removed.len(),
+            self.restarted.len(),
+            self.updated.len(),
+            self.unchanged.len()
+        )
+    }
+}
 
 /// A convenient way to keep a [`ServerConfig`] and some [`Instance`]s.
+///
+/// To limit how often the instances may try to connect in total, set a
+/// [`ServerConfig::connect_governor`] and create the instances using clones of
+/// the [`Self::server_config`].
 pub struct Instances {
     server_config: ServerConfig,
     instances: HashMap<String, Instance>,
@@ -35,7 +322,7 @@ impl Instances {
     ///
     /// The user is responsible for ensuring that instances' names are unique.
     pub fn is_from_known_instance(&self, event: &instance::Event) -> bool {
-        self.instances.contains_key(&event.config().name)
+        self.instances.contains_key(&*event.identity().name)
     }
 
     pub fn is_empty(&self) -> bool {
@@ -61,6 +348,163 @@ impl Instances {
         self.instances.remove(name)
     }
 
+    /// Remove an instance by its name and make it leave its room.
+    ///
+    /// See [`Instance::leave`] for details on the `goodbye`. The instance keeps
+    /// running until it has left, even if the returned instance is dropped.
+    pub fn leave(&mut self, name: &str, goodbye: Option<api::Send>) -> Option<Instance> {
+        let instance = self.instances.remove(name)?;
+        instance.leave(goodbye);
+        Some(instance)
+    }
+
+    /// Make the running instances match a desired set of configs.
+    ///
+    /// Instances are matched to configs by their [`InstanceConfig::name`].
+    /// Instances without a matching config are stopped and removed. For configs
+    /// without a matching instance, `make` is called to create a new instance.
+    /// Instances whose config changed in any way are stopped and replaced by a
+    /// new instance as well. They are reported as restarted if one of the
+    /// changes [requires reconnecting](ConfigField::requires_reconnect), and as
+    /// updated otherwise. Instances whose config didn't change are left
+    /// untouched.
+    ///
+    /// To avoid connecting to many rooms at once, the function waits for
+    /// `stagger` between creating instances. Stopping instances is not
+    /// staggered.
+    ///
+    /// This is intended for reloading a config file, for example when the
+    /// process receives a `SIGHUP`.
+    pub async fn reconcile<F>(
+        &mut self,
+        desired: Vec<InstanceConfig>,
+        stagger: Duration,
+        mut make: F,
+    ) -> ReconcileReport
+    where
+        F: FnMut(InstanceConfig) -> Instance,
+    {
+        let mut report = ReconcileReport::default();
+
+        let desired_names = desired.iter().map(|c| &c.name).collect::<HashSet<_>>();
+        let mut removed = self
+            .instances
+            .keys()
+            .filter(|name| !desired_names.contains(name))
+            .cloned()
+            .collect::<Vec<_>>();
+        removed.sort_unstable();
+        for name in removed {
+            if let Some(instance) = self.instances.remove(&name) {
+                instance.stop();
+            }
+            report.removed.push(name);
+        }
+
+        let mut created = false;
+        for config in desired {
+            let name = config.name.clone();
+            match self.instances.get(&name) {
+                Some(instance) => {
+                    let changes = config_changes(instance.config(), &config);
+                    if changes.is_empty() {
+                        report.unchanged.push(name);
+                        continue;
+                    }
+                    instance.stop();
+                    if changes.iter().any(|field| field.requires_reconnect()) {
+                        report.restarted.push(name.clone());
+                    } else {
+                        report.updated.push(name.clone());
+                    }
+                }
+                None => report.added.push(name.clone()),
+            }
+
+            if created {
+                tokio::time::sleep(stagger).await;
+            }
+            created = true;
+            self.instances.insert(name, make(config));
+        }
+
+        report
+    }
+
+    /// Invite a user to chat privately and join the private chat room.
+    ///
+    /// Sends a [`PmInitiate`] via the instance named `via`. Once the server
+    /// replies, `make` is called to create an instance for the private chat
+    /// room from `via`'s config, see [`InstanceConfig::pm`].
+    ///
+    /// If a running instance connected to the private chat room exists
+    /// already, it is returned instead. Fails with
+    /// [`conn::Error::ConnectionClosed`] if there is no instance named `via` or
+    /// it is not currently connected.
+    pub async fn initiate_pm<F>(
+        &mut self,
+        via: &str,
+        user_id: UserId,
+        make: F,
+    ) -> conn::Result<&Instance>
+    where
+        F: FnOnce(InstanceConfig) -> Instance,
+    {
+        let instance = self.get(via).ok_or(conn::Error::ConnectionClosed)?;
+        let config = instance.config().clone();
+        let conn_tx = instance
+            .conn_tx()
+            .await
+            .ok_or(conn::Error::ConnectionClosed)?;
+        let reply = conn_tx.send(PmInitiate { user_id }).await?;
+        Ok(self.join_pm(&config, reply.pm_id, make))
+    }
+
+    /// Accept an invitation to chat privately received by the instance named
+    /// `via`.
+    ///
+    /// Calls `make` to create an instance for the private chat room from
+    /// `via`'s config, see [`InstanceConfig::pm`]. If a running instance
+    /// connected to the private chat room exists already, it is returned
+    /// instead. Returns `None` if there is no instance named `via`.
+    ///
+    /// Invitations can be obtained via [`instance::Event::pm_invitation`]. To
+    /// decline private chats with some users, see
+    /// [`PmRegistry`](super::pm::PmRegistry).
+    pub fn accept_pm<F>(
+        &mut self,
+        via: &str,
+        invitation: &PmInitiateEvent,
+        make: F,
+    ) -> Option<&Instance>
+    where
+        F: FnOnce(InstanceConfig) -> Instance,
+    {
+        let config = self.get(via)?.config().clone();
+        Some(self.join_pm(&config, invitation.pm_id, make))
+    }
+
+    fn join_pm<F>(&mut self, base: &InstanceConfig, pm_id: PmId, make: F) -> &Instance
+    where
+        F: FnOnce(InstanceConfig) -> Instance,
+    {
+        let config = base.pm(pm_id);
+        let existing = self
+            .instances
+            .iter()
+            .find(|(_, i)| i.config().room == config.room && !i.stopped())
+            .map(|(name, _)| name.clone());
+        let name = match existing {
+            Some(name) => name,
+            None => {
+                let name = config.name.clone();
+                self.instances.insert(name.clone(), make(config));
+                name
+            }
+        };
+        &self.instances[&name]
+    }
+
     /// Remove all stopped instances.
     ///
     /// This function should be called regularly.
@@ -68,3 +512,308 @@ impl Instances {
         self.instances.retain(|_, i| !i.stopped());
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::sync::Mutex;
+    use std::time::Duration;
+
+    use tokio::net::TcpListener;
+
+    use crate::api::{PmId, PmInitiateEvent, Snowflake, UserId};
+
+    use crate::bot::instance::{
+        DuplicatePolicy, InstanceConfig, LateSchedules, NickRefreshMode, ServerConfig,
+    };
+    use crate::conn::test::{hello, Server};
+    use crate::conn::{self, MalformedPolicy, MessageTimesConfig, ParentCheck, PermissionCheck};
+
+    use super::{config_changes, ConfigField, Instances, ReconcileReport};
+
+    fn config(name: &str) -> InstanceConfig {
+        let server = ServerConfig::default().with_domain("localhost:0");
+        InstanceConfig::new(server, "test").with_name(name)
+    }
+
+    #[test]
+    fn changes_per_field() {
+        let old = config("a");
+        let changed = |f: fn(InstanceConfig) -> InstanceConfig| {
+            let changes = config_changes(&old, &f(old.clone()));
+            assert_eq!(changes.len(), 1);
+            changes[0]
+        };
+
+        assert!(config_changes(&old, &old.clone().with_name("b")).is_empty());
+        // A freshly loaded config has a new cookie jar
+        assert!(config_changes(&old, &config("a")).is_empty());
+
+        let reconnect = [
+            changed(|c| InstanceConfig {
+                server: c.server.clone().with_timeout(Duration::from_secs(1)),
+                ..c
+            }),
+            changed(|c| InstanceConfig {
+                server: c.server.clone().with_domain("example.com"),
+                ..c
+            }),
+            changed(|c| InstanceConfig {
+                server: c.server.clone().with_tls(false),
+                ..c
+            }),
+            changed(|c| InstanceConfig {
+                room: "other".to_string(),
+                ..c
+            }),
+            changed(|c| c.with_human(true)),
+            changed(|c| c.with_username("TestBot")),
+            changed(|c| c.with_force_username(true)),
+            changed(|c| c.with_password("hunter2")),
+        ];
+        assert_eq!(
+            reconnect,
+            [
+                ConfigField::Timeout,
+                ConfigField::Domain,
+                ConfigField::Tls,
+                ConfigField::Room,
+                ConfigField::Human,
+                ConfigField::Username,
+                ConfigField::ForceUsername,
+                ConfigField::Password,
+            ]
+        );
+        assert!(reconnect.iter().all(|f| f.requires_reconnect()));
+
+        let live = [
+            changed(|c| InstanceConfig {
+                server: c
+                    .server
+                    .clone()
+                    .with_reconnect_delay(Duration::from_secs(1)),
+                ..c
+            }),
+            changed(|c| InstanceConfig {
+                server: c
+                    .server
+                    .clone()
+                    .with_reconnect_delay_max(Duration::from_secs(1)),
+                ..c
+            }),
+            changed(|c| InstanceConfig {
+                server: c.server.clone().with_reconnect_jitter(0.0),
+                ..c
+            }),
+            changed(|c| InstanceConfig {
+                server: c.server.clone().with_on_malformed(MalformedPolicy::Skip),
+                ..c
+            }),
+            changed(|c| InstanceConfig {
+                server: c.server.clone().with_parent_check(ParentCheck::Enforce),
+                ..c
+            }),
+            changed(|c| InstanceConfig {
+                server: c
+                    .server
+                    .clone()
+                    .with_permission_check(PermissionCheck::Enforce),
+                ..c
+            }),
+            changed(|c| InstanceConfig {
+                server: c
+                    .server
+                    .clone()
+                    .with_message_times(MessageTimesConfig::new()),
+                ..c
+            }),
+            changed(|c| InstanceConfig {
+                server: c.server.clone().with_message_log(100),
+                ..c
+            }),
+            changed(|c| c.with_late_schedules(LateSchedules::Drop)),
+            changed(|c| c.with_population_sampling(Duration::from_secs(1))),
+            changed(|c| c.with_population_sampling_delta(1)),
+            changed(|c| c.with_stop_when_unobserved(false)),
+            changed(|c| c.with_join_after_nick(true)),
+            changed(|c| c.with_tag_durable(|_, _| {})),
+            changed(|c| c.with_nick_refresh_interval(Duration::from_secs(1))),
+            changed(|c| c.with_nick_refresh_mode(NickRefreshMode::IfEmpty)),
+            changed(|c| c.with_nick_refresh_suppression(Duration::ZERO)),
+            changed(|c| c.with_duplicate_policy(DuplicatePolicy::Defer)),
+            changed(|c| c.with_duplicate_defer_timeout(Duration::ZERO)),
+            changed(|c| c.with_gap_reports(true)),
+            changed(|c| c.with_missed_message_checks(Duration::from_secs(1))),
+        ];
+        assert_eq!(
+            live,
+            [
+                ConfigField::ReconnectDelay,
+                ConfigField::ReconnectDelayMax,
+                ConfigField::ReconnectJitter,
+                ConfigField::OnMalformed,
+                ConfigField::ParentCheck,
+                ConfigField::PermissionCheck,
+                ConfigField::MessageTimes,
+                ConfigField::MessageLog,
+                ConfigField::LateSchedules,
+                ConfigField::PopulationSampling,
+                ConfigField::PopulationSamplingDelta,
+                ConfigField::StopWhenUnobserved,
+                ConfigField::JoinAfterNick,
+                ConfigField::TagDurable,
+                ConfigField::NickRefreshInterval,
+                ConfigField::NickRefreshMode,
+                ConfigField::NickRefreshSuppression,
+                ConfigField::DuplicatePolicy,
+                ConfigField::DuplicateDeferTimeout,
+                ConfigField::GapReports,
+                ConfigField::MissedMessageChecks,
+            ]
+        );
+        assert!(live.iter().all(|f| !f.requires_reconnect()));
+    }
+
+    #[tokio::test]
+    async fn reconcile() {
+        let mut instances = Instances::new(ServerConfig::default());
+        let created = Mutex::new(vec![]);
+        let make = |config: InstanceConfig| {
+            created.lock().unwrap().push(config.name.clone());
+            config.build(|_| {})
+        };
+        let stagger = Duration::from_millis(1);
+
+        let desired = vec![config("a"), config("b"), config("c"), config("d")];
+        let report = instances.reconcile(desired, stagger, make).await;
+        assert_eq!(report.added, ["a", "b", "c", "d"]);
+        assert!(report.removed.is_empty());
+
+        let desired = vec![
+            config("a").with_late_schedules(LateSchedules::Drop),
+            config("b").with_password("hunter2"),
+            config("c"),
+            config("e"),
+        ];
+        let report = instances.reconcile(desired, stagger, make).await;
+        assert_eq!(
+            report,
+            ReconcileReport {
+                added: vec!["e".to_string()],
+                removed: vec!["d".to_string()],
+                restarted: vec!["b".to_string()],
+                updated: vec!["a".to_string()],
+                unchanged: vec!["c".to_string()],
+            }
+        );
+        assert_eq!(
+            report.to_string(),
+            "1 added, 1 removed, 1 restarted, 1 updated, 1 unchanged"
+        );
+        assert_eq!(
+            *created.lock().unwrap(),
+            ["a", "b", "c", "d", "a", "b", "e"]
+        );
+
+        // Changes that don't require reconnecting are applied as well
+        let a = instances.get("a").unwrap();
+        assert_eq!(a.config().late_schedules, LateSchedules::Drop);
+        let b = instances.get("b").unwrap();
+        assert_eq!(b.config().password.as_deref(), Some("hunter2"));
+        assert!(instances.get("d").is_none());
+    }
+
+    fn invitation(pm_id: u64) -> PmInitiateEvent {
+        PmInitiateEvent {
+            from: UserId("account:alice".to_string()),
+            from_nick: "alice".to_string(),
+            from_room: "test".to_string(),
+            pm_id: PmId(Snowflake(pm_id)),
+        }
+    }
+
+    #[tokio::test]
+    async fn accept_pm() {
+        let server = ServerConfig::default()
+            .with_domain("127.0.0.1:1")
+            .with_reconnect_delay(Duration::from_secs(60));
+        let mut instances = Instances::new(server.clone());
+        let config = server
+            .room("test")
+            .with_username("TestBot")
+            .with_password("pw");
+        instances.add(config.build(|_| {}));
+
+        let pm = instances
+            .accept_pm("test", &invitation(1), |c| c.build(|_| {}))
+            .unwrap();
+        let room = format!("pm:{}", Snowflake(1));
+        assert_eq!(pm.config().name, room);
+        assert_eq!(pm.config().room, room);
+        assert_eq!(pm.config().username.as_deref(), Some("TestBot"));
+        assert_eq!(pm.config().password, None);
+
+        // The existing instance is reused
+        let pm = instances
+            .accept_pm("test", &invitation(1), |_| panic!("duplicate instance"))
+            .unwrap();
+        assert_eq!(pm.config().room, room);
+        assert_eq!(instances.instances().count(), 2);
+
+        assert!(instances
+            .accept_pm("unknown", &invitation(2), |c| c.build(|_| {}))
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn initiate_pm() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let server = ServerConfig::default()
+            .with_domain(listener.local_addr().unwrap())
+            .with_tls(false)
+            .with_reconnect_delay(Duration::from_secs(60));
+        let mut instances = Instances::new(server.clone());
+        instances.add(server.room("test").build(|_| {}));
+
+        let result = instances
+            .initiate_pm("unknown", UserId("account:alice".to_string()), |_| {
+                panic!("no reply")
+            })
+            .await;
+        assert!(matches!(result, Err(conn::Error::ConnectionClosed)));
+
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut server = Server(tokio_tungstenite::accept_async(stream).await.unwrap());
+            server.join(hello(false, None)).await;
+            while let Some(packet) = server.recv().await {
+                if packet["type"] == "pm-initiate" {
+                    assert_eq!(packet["data"]["user_id"], "account:alice");
+                    let reply = serde_json::json!({
+                        "id": packet["id"],
+                        "type": "pm-initiate-reply",
+                        "data": { "pm_id": Snowflake(7).to_string(), "to_nick": "alice" },
+                    });
+                    server.send(reply).await;
+                }
+            }
+        });
+
+        let test = instances.get("test").unwrap();
+        tokio::time::timeout(Duration::from_secs(10), async {
+            while test.conn_tx().await.is_none() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+
+        let pm = instances
+            .initiate_pm("test", UserId("account:alice".to_string()), |c| {
+                c.build(|_| {})
+            })
+            .await
+            .unwrap();
+        assert_eq!(pm.config().room, format!("pm:{}", Snowflake(7)));
+        assert_eq!(instances.instances().count(), 2);
+    }
+}
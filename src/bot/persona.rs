@@ -0,0 +1,369 @@
+//! Sending messages under different nicks via a single connection.
+//!
+//! A bot can speak as multiple personas without opening a connection per
+//! persona by renaming itself around each message. [`Personas`] takes care of
+//! the sequencing: renaming, sending, and restoring the bot's resting nick
+//! afterwards, even if something fails halfway.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::{error, fmt};
+
+use tokio::sync::Mutex;
+
+use crate::api::{self, Message, MessageId, Nick};
+use crate::conn::{self, ConnTx};
+
+/// Why [`Personas::send_as`] failed.
+///
+/// Every variant that occurs before the message was sent also says whether
+/// restoring the resting nick afterwards failed.
+#[derive(Debug)]
+pub enum PersonaError {
+    /// Changing the nick to the persona failed, so nothing was sent.
+    Rename {
+        error: conn::Error,
+        /// Why restoring the resting nick failed, if it did.
+        restore: Option<conn::Error>,
+    },
+    /// Sending the message failed.
+    Send {
+        error: conn::Error,
+        /// Why restoring the resting nick failed, if it did.
+        restore: Option<conn::Error>,
+    },
+    /// The message was sent, but restoring the resting nick failed.
+    Restore {
+        message: Box<Message>,
+        error: conn::Error,
+    },
+}
+
+impl fmt::Display for PersonaError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let restore = match self {
+            Self::Rename { error, restore } => {
+                write!(f, "failed to change nick to persona: {error}")?;
+                restore
+            }
+            Self::Send { error, restore } => {
+                write!(f, "failed to send as persona: {error}")?;
+                restore
+            }
+            Self::Restore { error, .. } => {
+                return write!(f, "sent as persona, but failed to restore nick: {error}");
+            }
+        };
+        match restore {
+            Some(error) => write!(f, " (failed to restore nick: {error})"),
+            None => Ok(()),
+        }
+    }
+}
+
+impl error::Error for PersonaError {}
+
+#[derive(Debug, Default)]
+struct Shared {
+    /// Held for the entire rename-send-restore sequence if sends are
+    /// serialized.
+    lock: Mutex<()>,
+    /// How many sends are waiting for the lock.
+    waiting: AtomicUsize,
+}
+
+/// Sends messages under different nicks via a single connection.
+///
+/// Each [`Self::send_as`] changes the nick to the persona, sends the message
+/// and then changes the nick back to the resting nick. If the nick already is
+/// the persona's name, it isn't changed again.
+///
+/// By default, sends are serialized, i.e. only one send renames the bot at a
+/// time so concurrent sends don't end up under the wrong persona. While other
+/// sends are waiting, the resting nick isn't restored in between, so
+/// consecutive sends by the same persona don't bounce the nick. The last send
+/// restores it.
+///
+/// Messages sent via the connection without going through this type may end up
+/// under a persona's nick while a send is in progress.
+///
+/// Clones share their state. A single instance should be used per connection,
+/// and it can be reused when reconnecting.
+#[derive(Debug, Clone)]
+pub struct Personas {
+    resting: String,
+    serialize: bool,
+    shared: Arc<Shared>,
+}
+
+impl Personas {
+    /// Create a helper that restores the nick to `resting` after each send.
+    pub fn new<S: ToString>(resting: S) -> Self {
+        Self {
+            resting: resting.to_string(),
+            serialize: true,
+            shared: Arc::new(Shared::default()),
+        }
+    }
+
+    /// Whether sends wait for each other (default: `true`).
+    ///
+    /// If disabled, every send renames, sends and restores on its own.
+    /// Concurrent sends may then interleave their renames.
+    pub fn with_serialize(mut self, serialize: bool) -> Self {
+        self.serialize = serialize;
+        self
+    }
+
+    /// The nick restored after each send.
+    pub fn resting(&self) -> &str {
+        &self.resting
+    }
+
+    /// Send a message as a persona.
+    ///
+    /// If anything fails halfway, the resting nick is restored before
+    /// returning the error.
+    pub async fn send_as<S: ToString>(
+        &self,
+        conn_tx: &ConnTx,
+        persona: &str,
+        parent: Option<MessageId>,
+        content: S,
+    ) -> Result<Message, PersonaError> {
+        let content = content.to_string();
+        if !self.serialize {
+            return self
+                .rename_and_send(conn_tx, persona, parent, content)
+                .await;
+        }
+
+        self.shared.waiting.fetch_add(1, Ordering::SeqCst);
+        let _guard = self.shared.lock.lock().await;
+        self.shared.waiting.fetch_sub(1, Ordering::SeqCst);
+        self.rename_and_send(conn_tx, persona, parent, content)
+            .await
+    }
+
+    async fn rename_and_send(
+        &self,
+        conn_tx: &ConnTx,
+        persona: &str,
+        parent: Option<MessageId>,
+        content: String,
+    ) -> Result<Message, PersonaError> {
+        let current = match conn_tx.state().await {
+            Ok(state) => state.joined().map(|j| j.session.name.clone()),
+            Err(_) => None,
+        };
+        if current.as_deref() != Some(persona) {
+            if let Err(error) = Self::set_nick(conn_tx, persona).await {
+                let restore = self.restore(conn_tx).await.err();
+                return Err(PersonaError::Rename { error, restore });
+            }
+        }
+
+        let cmd = api::Send { content, parent };
+        let message = match conn_tx.send(cmd).await {
+            Ok(reply) => reply.0,
+            Err(error) => {
+                let restore = self.restore(conn_tx).await.err();
+                return Err(PersonaError::Send { error, restore });
+            }
+        };
+
+        // Whoever is next renames the bot anyways, and the last one restores.
+        let others_waiting = self.serialize && self.shared.waiting.load(Ordering::SeqCst) > 0;
+        if !others_waiting && persona != self.resting {
+            if let Err(error) = self.restore(conn_tx).await {
+                let message = Box::new(message);
+                return Err(PersonaError::Restore { message, error });
+            }
+        }
+
+        Ok(message)
+    }
+
+    async fn set_nick(conn_tx: &ConnTx, name: &str) -> conn::Result<()> {
+        let name = name.to_string();
+        conn_tx.send(Nick { name }).await.map(|_| ())
+    }
+
+    async fn restore(&self, conn_tx: &ConnTx) -> conn::Result<()> {
+        Self::set_nick(conn_tx, &self.resting).await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    use serde_json::{json, Value};
+
+    use crate::conn::test::{connect, hello};
+    use crate::conn::{self, ConnTx, State};
+
+    use super::{PersonaError, Personas};
+
+    type Log = Arc<Mutex<Vec<String>>>;
+
+    /// Connect to a server that logs every command as `type content` and
+    /// answers it, failing commands whose content is in `fail`.
+    async fn serve(fail: &'static [&'static str]) -> (ConnTx, Log) {
+        let (mut conn, mut server) = connect(Duration::from_secs(10)).await;
+        server.join(hello(false, None)).await;
+        while let State::Joining(_) = conn.state() {
+            conn.recv().await.unwrap();
+        }
+        let conn_tx = conn.tx().clone();
+        tokio::spawn(async move { while conn.recv().await.is_ok() {} });
+
+        let log = Log::default();
+        let server_log = log.clone();
+        tokio::spawn(async move {
+            while let Some(cmd) = server.recv().await {
+                let r#type = cmd["type"].as_str().unwrap().to_string();
+                let arg = match &r#type[..] {
+                    "nick" => cmd["data"]["name"].as_str().unwrap(),
+                    "send" => cmd["data"]["content"].as_str().unwrap(),
+                    _ => continue,
+                };
+                server_log.lock().unwrap().push(format!("{type} {arg}"));
+                let reply = match (&r#type[..], fail.contains(&arg)) {
+                    (_, true) => json!({ "type": format!("{type}-reply"), "error": "nope" }),
+                    ("nick", false) => json!({
+                        "type": "nick-reply",
+                        "data": {
+                            "session_id": "session",
+                            "id": "agent:abc",
+                            "from": "",
+                            "to": arg,
+                        },
+                    }),
+                    _ => json!({
+                        "type": "send-reply",
+                        "data": {
+                            "id": "0000000000001",
+                            "time": 0,
+                            "sender": hello(false, None).session,
+                            "content": arg,
+                        },
+                    }),
+                };
+                let mut reply: Value = reply;
+                reply["id"] = cmd["id"].clone();
+                server.send(reply).await;
+            }
+        });
+        (conn_tx, log)
+    }
+
+    fn take(log: &Log) -> Vec<String> {
+        std::mem::take(&mut *log.lock().unwrap())
+    }
+
+    #[tokio::test]
+    async fn single_send() {
+        let (conn_tx, log) = serve(&[]).await;
+        let personas = Personas::new("Bot");
+
+        let msg = personas
+            .send_as(&conn_tx, "Alice", None, "hi")
+            .await
+            .unwrap();
+        assert_eq!(msg.content, "hi");
+        assert_eq!(take(&log), ["nick Alice", "send hi", "nick Bot"]);
+
+        // The resting nick needs no renaming
+        personas.send_as(&conn_tx, "Bot", None, "ho").await.unwrap();
+        assert_eq!(take(&log), ["send ho"]);
+    }
+
+    #[tokio::test]
+    async fn interleaved_sends() {
+        let (conn_tx, log) = serve(&[]).await;
+        let personas = Personas::new("Bot");
+
+        let (a, b, c, d) = tokio::join!(
+            personas.send_as(&conn_tx, "Alice", None, "1"),
+            personas.send_as(&conn_tx, "Alice", None, "2"),
+            personas.send_as(&conn_tx, "Bob", None, "3"),
+            personas.send_as(&conn_tx, "Alice", None, "4"),
+        );
+        for result in [a, b, c, d] {
+            result.unwrap();
+        }
+        assert_eq!(
+            take(&log),
+            [
+                "nick Alice",
+                "send 1",
+                "send 2",
+                "nick Bob",
+                "send 3",
+                "nick Alice",
+                "send 4",
+                "nick Bot",
+            ]
+        );
+
+        // Without serialization, every send renames on its own
+        let personas = personas.with_serialize(false);
+        let (a, b) = tokio::join!(
+            personas.send_as(&conn_tx, "Alice", None, "5"),
+            personas.send_as(&conn_tx, "Bob", None, "6"),
+        );
+        a.unwrap();
+        b.unwrap();
+        let log = take(&log);
+        assert_eq!(log.len(), 6);
+        assert_eq!(log.iter().filter(|c| *c == "nick Bot").count(), 2);
+    }
+
+    #[tokio::test]
+    async fn failures_restore_nick() {
+        let (conn_tx, log) = serve(&["Mallory", "boom", "Broken"]).await;
+
+        let personas = Personas::new("Bot");
+        let err = personas
+            .send_as(&conn_tx, "Mallory", None, "hi")
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            PersonaError::Rename {
+                error: conn::Error::Euph { .. },
+                restore: None
+            }
+        ));
+        assert_eq!(take(&log), ["nick Mallory", "nick Bot"]);
+
+        let err = personas
+            .send_as(&conn_tx, "Alice", None, "boom")
+            .await
+            .unwrap_err();
+        assert!(matches!(err, PersonaError::Send { restore: None, .. }));
+        assert_eq!(take(&log), ["nick Alice", "send boom", "nick Bot"]);
+
+        let personas = Personas::new("Broken");
+        let err = personas
+            .send_as(&conn_tx, "Alice", None, "hi")
+            .await
+            .unwrap_err();
+        match err {
+            PersonaError::Restore { message, .. } => assert_eq!(message.content, "hi"),
+            err => panic!("unexpected error {err:?}"),
+        }
+        assert_eq!(take(&log), ["nick Alice", "send hi", "nick Broken"]);
+        assert_eq!(
+            PersonaError::Send {
+                error: conn::Error::CommandTimedOut,
+                restore: Some(conn::Error::ConnectionClosed),
+            }
+            .to_string(),
+            "failed to send as persona: server did not reply to command in time \
+            (failed to restore nick: connection closed)"
+        );
+    }
+}
@@ -0,0 +1,168 @@
+//! Keeping track of private chats with other users.
+
+use std::collections::{HashMap, HashSet};
+
+use crate::api::{PmInitiateEvent, UserId};
+
+use super::instance::Instance;
+
+/// Keeps track of [`Instance`]s connected to private chat rooms, and of users
+/// whose private chats were declined.
+///
+/// Each instance is identified by the user on the other end of the private
+/// chat, see [`Joined::pm_counterpart`](crate::conn::Joined::pm_counterpart).
+///
+/// The set of declined users can be persisted using [`Self::declined`] and
+/// restored using [`Self::with_declined`].
+#[derive(Default)]
+pub struct PmRegistry {
+    instances: HashMap<UserId, Instance>,
+    declined: HashSet<UserId>,
+}
+
+impl PmRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Create a registry that has already declined private chats with some
+    /// users.
+    pub fn with_declined<I: IntoIterator<Item = UserId>>(declined: I) -> Self {
+        Self {
+            instances: HashMap::new(),
+            declined: declined.into_iter().collect(),
+        }
+    }
+
+    pub fn instances(&self) -> impl Iterator<Item = (&UserId, &Instance)> {
+        self.instances.iter()
+    }
+
+    /// Get the instance of the private chat with a user.
+    pub fn get(&self, user_id: &UserId) -> Option<&Instance> {
+        self.instances.get(user_id)
+    }
+
+    /// Add the instance of the private chat with a user.
+    ///
+    /// If there is an instance for the same user already, it will be replaced
+    /// by the new instance and returned.
+    pub fn add(&mut self, user_id: UserId, instance: Instance) -> Option<Instance> {
+        self.instances.insert(user_id, instance)
+    }
+
+    /// Remove the instance of the private chat with a user without stopping
+    /// it.
+    pub fn remove(&mut self, user_id: &UserId) -> Option<Instance> {
+        self.instances.remove(user_id)
+    }
+
+    /// Remove all stopped instances.
+    ///
+    /// This function should be called regularly.
+    pub fn purge(&mut self) {
+        self.instances.retain(|_, i| !i.stopped());
+    }
+
+    /// Whether a private chat should be joined in response to an invitation.
+    ///
+    /// This is the case unless private chats with the inviting user were
+    /// declined or an instance for the user exists already.
+    pub fn should_join(&self, event: &PmInitiateEvent) -> bool {
+        !self.is_declined(&event.from) && !self.instances.contains_key(&event.from)
+    }
+
+    /// Decline private chats with a user.
+    ///
+    /// Stops and removes the user's instance, if any. Until [`Self::clear`] is
+    /// called for the user, [`Self::should_join`] returns `false` for their
+    /// invitations.
+    pub fn decline(&mut self, user_id: UserId) -> Option<Instance> {
+        let instance = self.instances.remove(&user_id);
+        if let Some(instance) = &instance {
+            instance.stop();
+        }
+        self.declined.insert(user_id);
+        instance
+    }
+
+    pub fn is_declined(&self, user_id: &UserId) -> bool {
+        self.declined.contains(user_id)
+    }
+
+    /// The users whose private chats were declined.
+    pub fn declined(&self) -> impl Iterator<Item = &UserId> {
+        self.declined.iter()
+    }
+
+    /// Accept private chats with a previously declined user again.
+    ///
+    /// Returns whether the user was declined.
+    pub fn clear(&mut self, user_id: &UserId) -> bool {
+        self.declined.remove(user_id)
+    }
+
+    /// Accept private chats with all previously declined users again.
+    pub fn clear_all(&mut self) {
+        self.declined.clear();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::api::{PmId, PmInitiateEvent, Snowflake, UserId};
+    use crate::bot::instance::{InstanceConfig, ServerConfig};
+
+    use super::PmRegistry;
+
+    fn user(name: &str) -> UserId {
+        UserId(format!("account:{name}"))
+    }
+
+    fn invitation(from: &str) -> PmInitiateEvent {
+        PmInitiateEvent {
+            from: user(from),
+            from_nick: from.to_string(),
+            from_room: "test".to_string(),
+            pm_id: PmId(Snowflake(1)),
+        }
+    }
+
+    #[tokio::test]
+    async fn decline() {
+        let mut registry = PmRegistry::new();
+        assert!(registry.should_join(&invitation("alice")));
+
+        let server = ServerConfig::default().with_domain("localhost:0");
+        let instance = InstanceConfig::new(server, "pm:0000000000001").build(|_| {});
+        registry.add(user("alice"), instance);
+        assert!(!registry.should_join(&invitation("alice")));
+        assert!(registry.should_join(&invitation("bob")));
+
+        assert!(registry.decline(user("alice")).is_some());
+        assert!(registry.get(&user("alice")).is_none());
+        assert!(!registry.should_join(&invitation("alice")));
+
+        // Declined users are remembered across registries
+        let registry = PmRegistry::with_declined(registry.declined().cloned());
+        assert!(!registry.should_join(&invitation("alice")));
+        assert!(registry.should_join(&invitation("bob")));
+    }
+
+    #[test]
+    fn clear() {
+        let mut registry = PmRegistry::new();
+        assert!(registry.decline(user("alice")).is_none());
+        registry.decline(user("bob"));
+        assert!(!registry.should_join(&invitation("alice")));
+
+        assert!(registry.clear(&user("alice")));
+        assert!(!registry.clear(&user("alice")));
+        assert!(registry.should_join(&invitation("alice")));
+        assert!(!registry.should_join(&invitation("bob")));
+
+        registry.clear_all();
+        assert!(registry.should_join(&invitation("bob")));
+        assert_eq!(registry.declined().count(), 0);
+    }
+}
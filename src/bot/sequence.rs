@@ -0,0 +1,489 @@
+//! Running a fixed sequence of commands, e.g. when setting up a room.
+//!
+//! Doing this by hand usually means handling timeouts, retries and partial
+//! failure for every single command. [`run_sequence`] does so uniformly based
+//! on each [`SequenceStep`]'s [`OnFailure`] policy and reports what happened
+//! in a [`SequenceReport`].
+
+use std::fmt;
+use std::time::Duration;
+
+use futures_util::future::BoxFuture;
+use futures_util::FutureExt;
+use tokio::time::Instant;
+
+use crate::api::packet::Command;
+use crate::api::{self, Auth, AuthOption, Data, Nick};
+use crate::conn::{self, ConnTx};
+
+/// What to do when a [`SequenceStep`] fails.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OnFailure {
+    /// Skip all remaining steps.
+    #[default]
+    Abort,
+    /// Continue with the next step.
+    Continue,
+    /// Try the step again up to this many times, then abort.
+    Retry(usize),
+}
+
+/// Why a [`SequenceStep`] failed.
+#[derive(Debug)]
+pub enum StepError {
+    Conn(conn::Error),
+    /// The server didn't reply within [`SequenceStep::with_timeout`].
+    TimedOut,
+    /// The server replied, but the reply didn't pass the step's check.
+    Rejected(String),
+}
+
+impl fmt::Display for StepError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Conn(err) => write!(f, "{err}"),
+            Self::TimedOut => write!(f, "step timed out"),
+            Self::Rejected(reason) => write!(f, "rejected: {reason}"),
+        }
+    }
+}
+
+type Run = Box<dyn Fn(ConnTx) -> BoxFuture<'static, Result<Data, StepError>> + Send + Sync>;
+
+/// A single command of a sequence, see [`run_sequence`].
+pub struct SequenceStep {
+    name: String,
+    run: Run,
+    timeout: Option<Duration>,
+    on_failure: OnFailure,
+}
+
+impl fmt::Debug for SequenceStep {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SequenceStep")
+            .field("name", &self.name)
+            .field("timeout", &self.timeout)
+            .field("on_failure", &self.on_failure)
+            .finish_non_exhaustive()
+    }
+}
+
+impl SequenceStep {
+    /// A step sending a command and waiting for its reply.
+    ///
+    /// The step succeeds if the server replies without an error.
+    pub fn new<S, C>(name: S, cmd: C) -> Self
+    where
+        S: ToString,
+        C: Command + Into<Data> + Clone + Send + Sync + 'static,
+        C::Reply: TryFrom<Data> + Into<Data> + Send,
+    {
+        Self::checked(name, cmd, |_| Ok(()))
+    }
+
+    /// Like [`Self::new`], but the step only succeeds if the reply also passes
+    /// `check`.
+    ///
+    /// This is useful for commands like [`Auth`] whose replies indicate failure
+    /// without being an error.
+    pub fn checked<S, C, F>(name: S, cmd: C, check: F) -> Self
+    where
+        S: ToString,
+        C: Command + Into<Data> + Clone + Send + Sync + 'static,
+        C::Reply: TryFrom<Data> + Into<Data> + Send,
+        F: Fn(&C::Reply) -> Result<(), String> + Clone + Send + Sync + 'static,
+    {
+        let run = move |conn_tx: ConnTx| {
+            let reply = conn_tx.send(cmd.clone());
+            let check = check.clone();
+            async move {
+                let reply = reply.await.map_err(StepError::Conn)?;
+                check(&reply).map_err(StepError::Rejected)?;
+                Ok(reply.into())
+            }
+            .boxed()
+        };
+        Self {
+            name: name.to_string(),
+            run: Box::new(run),
+            timeout: None,
+            on_failure: OnFailure::default(),
+        }
+    }
+
+    /// Authenticate with a passcode.
+    pub fn auth<S: ToString>(passcode: S) -> Self {
+        let cmd = Auth {
+            r#type: AuthOption::Passcode,
+            passcode: Some(passcode.to_string()),
+        };
+        Self::checked("auth", cmd, |reply| {
+            if reply.success {
+                return Ok(());
+            }
+            let reason = reply.reason.as_deref().unwrap_or("authentication failed");
+            Err(reason.to_string())
+        })
+    }
+
+    /// Set the nick.
+    pub fn nick<S: ToString>(name: S) -> Self {
+        let cmd = Nick {
+            name: name.to_string(),
+        };
+        Self::new("nick", cmd)
+    }
+
+    /// Send a top-level message.
+    pub fn send<S: ToString>(content: S) -> Self {
+        let cmd = api::Send {
+            content: content.to_string(),
+            parent: None,
+        };
+        Self::new("send", cmd)
+    }
+
+    /// Shown in the step's [`StepReport`].
+    pub fn with_name<S: ToString>(mut self, name: S) -> Self {
+        self.name = name.to_string();
+        self
+    }
+
+    /// How long to wait for each attempt's reply (default: the connection's
+    /// command timeout).
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// What to do if the step fails (default: [`OnFailure::Abort`]).
+    pub fn with_on_failure(mut self, on_failure: OnFailure) -> Self {
+        self.on_failure = on_failure;
+        self
+    }
+
+    async fn attempt(&self, conn_tx: &ConnTx) -> Result<Data, StepError> {
+        let run = (self.run)(conn_tx.clone());
+        match self.timeout {
+            Some(timeout) => match tokio::time::timeout(timeout, run).await {
+                Ok(result) => result,
+                Err(_) => Err(StepError::TimedOut),
+            },
+            None => run.await,
+        }
+    }
+}
+
+/// How a [`SequenceStep`] ended.
+#[derive(Debug)]
+pub enum StepOutcome {
+    /// The server's reply to the last attempt.
+    Succeeded(Data),
+    /// The error of the last attempt.
+    Failed(StepError),
+    /// The step was never attempted because an earlier step aborted the
+    /// sequence.
+    Skipped,
+}
+
+/// What happened to a single [`SequenceStep`].
+#[derive(Debug)]
+pub struct StepReport {
+    pub name: String,
+    pub outcome: StepOutcome,
+    /// How often the step was attempted, including retries.
+    pub attempts: usize,
+    /// How long all attempts took together.
+    pub elapsed: Duration,
+}
+
+/// What happened when running a sequence, see [`run_sequence`].
+#[derive(Debug)]
+pub struct SequenceReport {
+    /// The reports of all steps, in the order they were passed in.
+    pub steps: Vec<StepReport>,
+    /// How long the whole sequence took.
+    pub elapsed: Duration,
+}
+
+impl SequenceReport {
+    /// Whether every step succeeded.
+    pub fn succeeded(&self) -> bool {
+        self.steps
+            .iter()
+            .all(|s| matches!(s.outcome, StepOutcome::Succeeded(_)))
+    }
+
+    /// Whether a failed step caused the remaining steps to be skipped.
+    pub fn aborted(&self) -> bool {
+        self.steps
+            .iter()
+            .any(|s| matches!(s.outcome, StepOutcome::Skipped))
+    }
+
+    /// The first step that failed, if any.
+    pub fn first_failure(&self) -> Option<&StepReport> {
+        self.steps
+            .iter()
+            .find(|s| matches!(s.outcome, StepOutcome::Failed(_)))
+    }
+}
+
+/// Run steps one after another via a connection.
+///
+/// Each step's reply is awaited before the next step starts. If a step fails,
+/// its [`OnFailure`] policy decides whether it is retried, whether the
+/// sequence continues with the next step, or whether all remaining steps are
+/// skipped.
+pub async fn run_sequence(conn_tx: &ConnTx, steps: Vec<SequenceStep>) -> SequenceReport {
+    let start = Instant::now();
+    let mut reports = Vec::with_capacity(steps.len());
+    let mut aborted = false;
+
+    for step in steps {
+        if aborted {
+            reports.push(StepReport {
+                name: step.name,
+                outcome: StepOutcome::Skipped,
+                attempts: 0,
+                elapsed: Duration::ZERO,
+            });
+            continue;
+        }
+
+        let step_start = Instant::now();
+        let max_attempts = match step.on_failure {
+            OnFailure::Retry(retries) => retries + 1,
+            OnFailure::Abort | OnFailure::Continue => 1,
+        };
+        let mut attempts = 0;
+        let outcome = loop {
+            attempts += 1;
+            match step.attempt(conn_tx).await {
+                Ok(data) => break StepOutcome::Succeeded(data),
+                Err(err) if attempts >= max_attempts => break StepOutcome::Failed(err),
+                Err(_) => {}
+            }
+        };
+
+        if matches!(outcome, StepOutcome::Failed(_)) && step.on_failure != OnFailure::Continue {
+            aborted = true;
+        }
+        reports.push(StepReport {
+            name: step.name,
+            outcome,
+            attempts,
+            elapsed: step_start.elapsed(),
+        });
+    }
+
+    SequenceReport {
+        steps: reports,
+        elapsed: start.elapsed(),
+    }
+}
+
+/// The usual steps after joining a room: setting the nick, then sending an
+/// introductory message, if any.
+///
+/// Setting the nick is retried twice. If it still fails, the introduction is
+/// skipped so the bot doesn't post without a nick.
+pub fn standard_join_sequence(username: &str, intro: Option<&str>) -> Vec<SequenceStep> {
+    let mut steps = vec![SequenceStep::nick(username).with_on_failure(OnFailure::Retry(2))];
+    if let Some(intro) = intro {
+        steps.push(SequenceStep::send(intro).with_name("intro"));
+    }
+    steps
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use serde_json::{json, Value};
+
+    use crate::api::Data;
+    use crate::conn::test::{connect, hello};
+    use crate::conn::{self, ConnTx, State};
+
+    use super::{
+        run_sequence, standard_join_sequence, OnFailure, SequenceStep, StepError, StepOutcome,
+    };
+
+    /// Connect to a server that answers every command using `reply`.
+    ///
+    /// Commands for which `reply` returns `None` are left unanswered.
+    async fn serve<F>(reply: F) -> (ConnTx, tokio::sync::mpsc::UnboundedReceiver<Value>)
+    where
+        F: Fn(&Value) -> Option<Value> + Send + 'static,
+    {
+        let (mut conn, mut server) = connect(Duration::from_secs(10)).await;
+        server.join(hello(false, None)).await;
+        while let State::Joining(_) = conn.state() {
+            conn.recv().await.unwrap();
+        }
+        let conn_tx = conn.tx().clone();
+        tokio::spawn(async move { while conn.recv().await.is_ok() {} });
+
+        let (cmd_tx, cmd_rx) = tokio::sync::mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            while let Some(cmd) = server.recv().await {
+                let _ = cmd_tx.send(cmd.clone());
+                if let Some(mut packet) = reply(&cmd) {
+                    packet["id"] = cmd["id"].clone();
+                    server.send(packet).await;
+                }
+            }
+        });
+        (conn_tx, cmd_rx)
+    }
+
+    fn nick_reply(cmd: &Value) -> Value {
+        json!({
+            "type": "nick-reply",
+            "data": {
+                "session_id": "me",
+                "id": "bot:me",
+                "from": "",
+                "to": cmd["data"]["name"],
+            },
+        })
+    }
+
+    fn send_reply(cmd: &Value) -> Value {
+        json!({
+            "type": "send-reply",
+            "data": {
+                "id": "0000000000001",
+                "time": 0,
+                "sender": hello(false, None).session,
+                "content": cmd["data"]["content"],
+            },
+        })
+    }
+
+    fn error(cmd: &Value, error: &str) -> Value {
+        let r#type = format!("{}-reply", cmd["type"].as_str().unwrap());
+        json!({ "type": r#type, "error": error })
+    }
+
+    fn types(rx: &mut tokio::sync::mpsc::UnboundedReceiver<Value>) -> Vec<String> {
+        let mut types = vec![];
+        while let Ok(cmd) = rx.try_recv() {
+            types.push(cmd["type"].as_str().unwrap().to_string());
+        }
+        types
+    }
+
+    #[tokio::test]
+    async fn abort_on_failure() {
+        let (conn_tx, mut cmds) = serve(|cmd| match cmd["type"].as_str() {
+            Some("nick") => Some(error(cmd, "invalid nick")),
+            _ => Some(send_reply(cmd)),
+        })
+        .await;
+
+        let report = run_sequence(&conn_tx, standard_join_sequence("", Some("Hi!"))).await;
+        assert!(!report.succeeded());
+        assert!(report.aborted());
+        let nick = &report.steps[0];
+        assert_eq!(nick.attempts, 3);
+        assert!(matches!(
+            &nick.outcome,
+            StepOutcome::Failed(StepError::Conn(conn::Error::Euph { message, .. })) if message == "invalid nick"
+        ));
+        assert_eq!(report.steps[1].name, "intro");
+        assert_eq!(report.steps[1].attempts, 0);
+        assert!(matches!(report.steps[1].outcome, StepOutcome::Skipped));
+
+        // The bot never posted without a nick
+        assert_eq!(types(&mut cmds), ["nick", "nick", "nick"]);
+    }
+
+    #[tokio::test]
+    async fn retry_exhaustion() {
+        let (conn_tx, mut cmds) = serve(|cmd| match cmd["type"].as_str() {
+            Some("auth") => Some(json!({
+                "type": "auth-reply",
+                "data": { "success": false, "reason": "passcode incorrect" },
+            })),
+            Some("nick") => None,
+            _ => Some(send_reply(cmd)),
+        })
+        .await;
+
+        let steps = vec![
+            SequenceStep::auth("wrong").with_on_failure(OnFailure::Continue),
+            SequenceStep::nick("TestBot")
+                .with_timeout(Duration::from_millis(50))
+                .with_on_failure(OnFailure::Continue),
+            SequenceStep::send("Hi!"),
+        ];
+        let report = run_sequence(&conn_tx, steps).await;
+        assert!(!report.aborted());
+        assert!(matches!(
+            &report.steps[0].outcome,
+            StepOutcome::Failed(StepError::Rejected(r)) if r == "passcode incorrect"
+        ));
+        assert!(matches!(
+            report.steps[1].outcome,
+            StepOutcome::Failed(StepError::TimedOut)
+        ));
+        assert!(matches!(report.steps[2].outcome, StepOutcome::Succeeded(_)));
+        assert_eq!(report.first_failure().unwrap().name, "auth");
+        assert_eq!(types(&mut cmds), ["auth", "nick", "send"]);
+
+        // Retries are exhausted before giving up
+        let steps = vec![
+            SequenceStep::auth("wrong").with_on_failure(OnFailure::Retry(1)),
+            SequenceStep::nick("TestBot")
+                .with_timeout(Duration::from_millis(50))
+                .with_on_failure(OnFailure::Retry(2)),
+        ];
+        let report = run_sequence(&conn_tx, steps).await;
+        assert_eq!(report.steps[0].attempts, 2);
+        assert!(matches!(report.steps[1].outcome, StepOutcome::Skipped));
+
+        let steps = vec![SequenceStep::nick("TestBot")
+            .with_timeout(Duration::from_millis(50))
+            .with_on_failure(OnFailure::Retry(2))];
+        let report = run_sequence(&conn_tx, steps).await;
+        assert_eq!(report.steps[0].attempts, 3);
+        assert!(report.steps[0].elapsed >= Duration::from_millis(150));
+        assert_eq!(types(&mut cmds), ["auth", "auth", "nick", "nick", "nick"]);
+    }
+
+    #[tokio::test]
+    async fn report_contents() {
+        let (conn_tx, _cmds) = serve(|cmd| match cmd["type"].as_str() {
+            Some("nick") => Some(nick_reply(cmd)),
+            _ => Some(send_reply(cmd)),
+        })
+        .await;
+
+        let steps = standard_join_sequence("TestBot", Some("Hi!"));
+        let report = run_sequence(&conn_tx, steps).await;
+        assert!(report.succeeded());
+        assert!(!report.aborted());
+        assert!(report.first_failure().is_none());
+
+        let names = report.steps.iter().map(|s| &s.name[..]).collect::<Vec<_>>();
+        assert_eq!(names, ["nick", "intro"]);
+        for step in &report.steps {
+            assert_eq!(step.attempts, 1);
+            assert!(step.elapsed <= report.elapsed);
+        }
+        match &report.steps[0].outcome {
+            StepOutcome::Succeeded(Data::NickReply(reply)) => assert_eq!(reply.to, "TestBot"),
+            outcome => panic!("unexpected outcome {outcome:?}"),
+        }
+        match &report.steps[1].outcome {
+            StepOutcome::Succeeded(Data::SendReply(reply)) => assert_eq!(reply.0.content, "Hi!"),
+            outcome => panic!("unexpected outcome {outcome:?}"),
+        }
+
+        // Without an introduction, only the nick is set
+        let steps = standard_join_sequence("TestBot", None);
+        assert_eq!(run_sequence(&conn_tx, steps).await.steps.len(), 1);
+    }
+}
@@ -0,0 +1,638 @@
+//! Keeping per-room settings in the room itself.
+//!
+//! Small bots often want a few settings per room without needing a database.
+//! [`ChatSettings`] keeps them in a message the bot posts to the room. The
+//! message content consists of [`SETTINGS_PREFIX`] followed by a JSON object
+//! containing the [`SETTINGS_VERSION`], the name of the bot the settings belong
+//! to and the settings themselves. When the bot joins the room, it reads the
+//! settings back from the log.
+//!
+//! Whenever a setting changes, the bot writes the new settings. If
+//! [`ChatSettings::with_edit_in_place`] is enabled and the bot may edit
+//! messages, it edits its settings message. Otherwise, it posts a new settings
+//! message.
+//! Since there may be multiple settings messages in the room, for example
+//! because two instances of a bot changed settings at the same time, the one
+//! with the highest [`MessageId`] wins, and edits only count if they are newer
+//! than the last edit seen.
+
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+
+use jiff::Timestamp;
+use serde::{Deserialize, Serialize};
+
+use crate::api::packet::ParsedPacket;
+use crate::api::{
+    Data, EditMessage, EditMessageReply, GetMessage, GetMessageReply, Message, MessageId,
+    SendReply, SessionView, Snowflake, UserId,
+};
+use crate::conn::{self, ConnTx};
+use crate::search::{search_log, SearchLimits, TimeRange};
+
+/// The start of every settings message.
+pub const SETTINGS_PREFIX: &str = "[settings] ";
+
+/// The version of the settings format written by [`ChatSettings`].
+///
+/// Settings messages with a different version are ignored.
+pub const SETTINGS_VERSION: u32 = 1;
+
+#[derive(Deserialize)]
+struct Version {
+    version: u32,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Payload {
+    version: u32,
+    owner: String,
+    values: BTreeMap<String, String>,
+}
+
+fn format_settings(owner: &str, values: &BTreeMap<String, String>) -> String {
+    let payload = Payload {
+        version: SETTINGS_VERSION,
+        owner: owner.to_string(),
+        values: values.clone(),
+    };
+    let json = serde_json::to_string(&payload).expect("settings are serializable");
+    format!("{SETTINGS_PREFIX}{json}")
+}
+
+fn parse_settings(owner: &str, content: &str) -> Option<BTreeMap<String, String>> {
+    let json = content.strip_prefix(SETTINGS_PREFIX)?;
+    let version = serde_json::from_str::<Version>(json).ok()?.version;
+    if version != SETTINGS_VERSION {
+        return None;
+    }
+    let payload = serde_json::from_str::<Payload>(json).ok()?;
+    if payload.owner != owner {
+        return None;
+    }
+    Some(payload.values)
+}
+
+/// The settings message currently in effect.
+#[derive(Debug, Clone, Copy)]
+struct Current {
+    id: MessageId,
+    /// The id of the message's most recent edit, if any.
+    edit_id: Option<Snowflake>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    values: BTreeMap<String, String>,
+    current: Option<Current>,
+    /// The id of the agent or account the bot is logged in as.
+    identity: Option<UserId>,
+    /// Whether editing the settings message failed since the bot connected.
+    edit_failed: bool,
+}
+
+/// Key-value settings of a bot, stored in a message in the room.
+///
+/// The settings must be kept up to date by passing every packet the bot
+/// receives to [`Self::observe`], which also picks up the settings message in
+/// the snapshot's log when joining. If the settings message is older than the
+/// snapshot's log, [`Self::fetch`] searches the room's log for it.
+///
+/// Anyone can post a message that looks like a settings message. By default,
+/// only messages sent by the bot itself are considered, see [`Self::with_trust`].
+///
+/// Clones share their settings. For more details on how the settings are
+/// stored, see the [module documentation](self).
+#[derive(Debug, Clone)]
+pub struct ChatSettings {
+    owner: String,
+    thread: Option<MessageId>,
+    edit_in_place: bool,
+    trust: Option<fn(&SessionView) -> bool>,
+    state: Arc<Mutex<State>>,
+}
+
+impl ChatSettings {
+    /// Settings belonging to the bot with the name `owner`.
+    ///
+    /// Settings messages of other bots are ignored, so multiple bots can keep
+    /// their settings in the same room.
+    pub fn new<S: ToString>(owner: S) -> Self {
+        Self {
+            owner: owner.to_string(),
+            thread: None,
+            edit_in_place: false,
+            trust: None,
+            state: Arc::new(Mutex::new(State::default())),
+        }
+    }
+
+    /// The message new settings messages are posted as replies to (default:
+    /// none).
+    pub fn with_thread(self, thread: MessageId) -> Self {
+        self.with_thread_opt(Some(thread))
+    }
+
+    pub fn with_thread_opt(mut self, thread: Option<MessageId>) -> Self {
+        self.thread = thread;
+        self
+    }
+
+    /// Whether to edit the settings message instead of posting a new one
+    /// (default: `false`).
+    ///
+    /// Editing messages requires the bot to be a host of the room. If an edit
+    /// fails, for example because the bot isn't a host or because the message
+    /// was edited by someone else in the meantime, a new message is posted
+    /// instead. Until the bot reconnects, later changes are then posted
+    /// without trying to edit first.
+    pub fn with_edit_in_place(mut self, edit_in_place: bool) -> Self {
+        self.edit_in_place = edit_in_place;
+        self
+    }
+
+    /// Which senders of settings messages to trust (default: only the bot
+    /// itself).
+    ///
+    /// By default, a settings message is only trusted if it was sent by the
+    /// agent or account the bot is logged in as. This identity is learned
+    /// from the hello-event and snapshot-event passed to [`Self::observe`], or
+    /// can be set using [`Self::with_identity`]. Until it is known, no settings
+    /// message is trusted. Instances of a bot that should share their settings
+    /// must log in as the same agent or account, or use a custom `trust`.
+    pub fn with_trust(self, trust: fn(&SessionView) -> bool) -> Self {
+        self.with_trust_opt(Some(trust))
+    }
+
+    pub fn with_trust_opt(mut self, trust: Option<fn(&SessionView) -> bool>) -> Self {
+        self.trust = trust;
+        self
+    }
+
+    /// The id of the agent or account the bot is logged in as (default: none).
+    ///
+    /// See [`Self::with_trust`] for more details.
+    pub fn with_identity(self, identity: UserId) -> Self {
+        self.with_identity_opt(Some(identity))
+    }
+
+    pub fn with_identity_opt(self, identity: Option<UserId>) -> Self {
+        self.state.lock().unwrap().identity = identity;
+        self
+    }
+
+    fn trusts(&self, sender: &SessionView) -> bool {
+        match self.trust {
+            Some(trust) => trust(sender),
+            None => self.state.lock().unwrap().identity.as_ref() == Some(&sender.id),
+        }
+    }
+
+    pub fn get(&self, key: &str) -> Option<String> {
+        self.state.lock().unwrap().values.get(key).cloned()
+    }
+
+    pub fn values(&self) -> BTreeMap<String, String> {
+        self.state.lock().unwrap().values.clone()
+    }
+
+    /// The settings message currently in effect, if any.
+    pub fn message(&self) -> Option<MessageId> {
+        self.state.lock().unwrap().current.map(|c| c.id)
+    }
+
+    /// Take the settings from a message if it is a settings message newer
+    /// than the current one.
+    ///
+    /// Returns whether the settings were taken from the message.
+    pub fn observe_message(&self, msg: &Message) -> bool {
+        self.apply(msg, msg.previous_edit_id)
+    }
+
+    fn apply(&self, msg: &Message, edit_id: Option<Snowflake>) -> bool {
+        if msg.deleted.is_some() || msg.truncated || !self.trusts(&msg.sender) {
+            return false;
+        }
+        let values = match parse_settings(&self.owner, &msg.content) {
+            Some(values) => values,
+            None => return false,
+        };
+
+        let mut state = self.state.lock().unwrap();
+        if let Some(current) = state.current {
+            if (msg.id, edit_id) < (current.id, current.edit_id) {
+                return false;
+            }
+        }
+        state.values = values;
+        state.current = Some(Current {
+            id: msg.id,
+            edit_id,
+        });
+        true
+    }
+
+    /// Observe a packet received by the connection.
+    ///
+    /// Hello-events, snapshot-events, send-events, send-replies,
+    /// edit-message-events and edit-message-replies are taken into account.
+    pub fn observe(&self, packet: &ParsedPacket) {
+        match &packet.content {
+            Ok(Data::HelloEvent(event)) => {
+                let mut state = self.state.lock().unwrap();
+                state.identity = Some(event.id.clone());
+                // The bot may have become a host in the meantime
+                state.edit_failed = false;
+            }
+            Ok(Data::SnapshotEvent(event)) => {
+                self.state.lock().unwrap().identity = Some(event.identity.clone());
+                for msg in &event.log {
+                    self.observe_message(msg);
+                }
+            }
+            Ok(Data::SendEvent(event)) => {
+                self.observe_message(&event.0);
+            }
+            Ok(Data::SendReply(reply)) => {
+                self.observe_message(&reply.0);
+            }
+            Ok(Data::EditMessageEvent(event)) => {
+                self.apply(&event.message, Some(event.edit_id));
+            }
+            Ok(Data::EditMessageReply(reply)) => {
+                self.observe_message(&reply.0);
+            }
+            _ => {}
+        }
+    }
+
+    /// Search the room's log for the newest settings message.
+    ///
+    /// Returns whether a settings message was found. Settings messages the
+    /// server truncated in the log are retrieved in full.
+    ///
+    /// The [`ConnTx`] must belong to a [`Conn`](conn::Conn) whose
+    /// [`recv`](conn::Conn::recv) is being called while searching. See
+    /// [`search_log`] for more details.
+    pub async fn fetch(&self, conn_tx: &ConnTx, limits: SearchLimits) -> conn::Result<bool> {
+        let range = TimeRange::new(Timestamp::UNIX_EPOCH, Timestamp::MAX);
+        let predicate =
+            |msg: &Message| msg.content.starts_with(SETTINGS_PREFIX) && self.trusts(&msg.sender);
+        let result = search_log(conn_tx, range, predicate, limits).await?;
+
+        for msg in result.messages.into_iter().rev() {
+            let msg = if msg.truncated {
+                let GetMessageReply(msg) = conn_tx.send(GetMessage { id: msg.id }).await?;
+                msg
+            } else {
+                msg
+            };
+            if self.observe_message(&msg) {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    /// Change a setting and write the settings to the room.
+    ///
+    /// Returns whether the change is in effect. It is not if a newer settings
+    /// message was posted in the meantime, in which case the settings are
+    /// taken from that message instead.
+    pub async fn set<K: ToString, V: ToString>(
+        &self,
+        conn_tx: &ConnTx,
+        key: K,
+        value: V,
+    ) -> conn::Result<bool> {
+        let mut values = self.values();
+        values.insert(key.to_string(), value.to_string());
+        self.write(conn_tx, &values).await
+    }
+
+    /// Remove a setting and write the settings to the room.
+    ///
+    /// See [`Self::set`] for more details.
+    pub async fn remove(&self, conn_tx: &ConnTx, key: &str) -> conn::Result<bool> {
+        let mut values = self.values();
+        values.remove(key);
+        self.write(conn_tx, &values).await
+    }
+
+    async fn write(
+        &self,
+        conn_tx: &ConnTx,
+        values: &BTreeMap<String, String>,
+    ) -> conn::Result<bool> {
+        let content = format_settings(&self.owner, values);
+
+        let (current, edit_failed) = {
+            let state = self.state.lock().unwrap();
+            (state.current, state.edit_failed)
+        };
+        if let (true, false, Some(current)) = (self.edit_in_place, edit_failed, current) {
+            let edit = EditMessage {
+                id: current.id,
+                previous_edit_id: current.edit_id,
+                parent: None,
+                content: Some(content.clone()),
+                delete: false,
+                announce: true,
+            };
+            match conn_tx.send(edit).await {
+                Ok(EditMessageReply(msg)) => return Ok(self.observe_message(&msg)),
+                Err(conn::Error::Euph { .. }) => self.state.lock().unwrap().edit_failed = true,
+                Err(err) => return Err(err),
+            }
+        }
+
+        let send = crate::api::Send {
+            content,
+            parent: self.thread,
+        };
+        let SendReply(msg) = conn_tx.send(send).await?;
+        Ok(self.observe_message(&msg))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeMap;
+    use std::time::Duration;
+
+    use crate::api::{Message, MessageId, SessionId, Snowflake, Time, UserId};
+    use crate::conn::test::{connect, hello, Server};
+    use crate::search::SearchLimits;
+    use crate::test_util::{MessageBuilder, SessionViewBuilder};
+
+    use super::{format_settings, parse_settings, ChatSettings, SETTINGS_PREFIX};
+
+    /// The identity of the bot in [`hello`].
+    const ME: &str = "agent:abc";
+
+    fn own_settings() -> ChatSettings {
+        ChatSettings::new("TestBot").with_identity(UserId(ME.to_string()))
+    }
+
+    fn message(id: u64, sender: &str, content: &str) -> Message {
+        let sender = SessionViewBuilder::new("TestBot")
+            .with_id(UserId(sender.to_string()))
+            .with_session_id(SessionId(sender.to_string()))
+            .build();
+        MessageBuilder::new(content)
+            .with_id(MessageId(Snowflake(id)))
+            .with_time(Time(id as i64))
+            .with_sender(sender)
+            .build()
+    }
+
+    fn settings_content(greeting: &str) -> String {
+        let values = BTreeMap::from([("greeting".to_string(), greeting.to_string())]);
+        format_settings("TestBot", &values)
+    }
+
+    fn greeting(settings: &ChatSettings) -> Option<String> {
+        settings.get("greeting")
+    }
+
+    #[test]
+    fn format() {
+        let content = settings_content("off");
+        assert_eq!(
+            content,
+            r#"[settings] {"version":1,"owner":"TestBot","values":{"greeting":"off"}}"#
+        );
+        let values = parse_settings("TestBot", &content).unwrap();
+        assert_eq!(values["greeting"], "off");
+
+        assert!(parse_settings("OtherBot", &content).is_none());
+        assert!(parse_settings("TestBot", &content[1..]).is_none());
+        let future = format!(r#"{SETTINGS_PREFIX}{{"version":2,"owner":"TestBot"}}"#);
+        assert!(parse_settings("TestBot", &future).is_none());
+    }
+
+    #[test]
+    fn newest_message_wins() {
+        let older = message(10, ME, &settings_content("on"));
+        let newer = message(20, ME, &settings_content("off"));
+
+        // Regardless of the order the messages are seen in
+        for (first, second) in [(&older, &newer), (&newer, &older)] {
+            let settings = own_settings();
+            settings.observe_message(first);
+            settings.observe_message(second);
+            assert_eq!(greeting(&settings).as_deref(), Some("off"));
+            assert_eq!(settings.message(), Some(newer.id));
+        }
+
+        let settings = own_settings();
+        assert!(settings.observe_message(&newer));
+
+        // Edits of the current message count if they are newer
+        let mut edited = message(20, ME, &settings_content("on"));
+        edited.previous_edit_id = Some(Snowflake(5));
+        assert!(settings.observe_message(&edited));
+        edited.previous_edit_id = Some(Snowflake(4));
+        edited.content = settings_content("maybe");
+        assert!(!settings.observe_message(&edited));
+        assert_eq!(greeting(&settings).as_deref(), Some("on"));
+
+        // Edits of older messages don't
+        let mut edited = older.clone();
+        edited.previous_edit_id = Some(Snowflake(6));
+        assert!(!settings.observe_message(&edited));
+
+        // Neither do untrusted or deleted messages
+        let human = message(30, "agent:human", &settings_content("off"));
+        assert!(!settings.observe_message(&human));
+        let mut deleted = message(40, ME, &settings_content("off"));
+        deleted.deleted = Some(Time(40));
+        assert!(!settings.observe_message(&deleted));
+        assert_eq!(greeting(&settings).as_deref(), Some("on"));
+
+        let settings = settings.with_trust(|_| true);
+        assert!(settings.observe_message(&human));
+    }
+
+    #[test]
+    fn only_trusts_itself() {
+        // Other bots can post messages that look like our settings messages
+        let forged = message(10, "bot:other", &settings_content("forged"));
+        let own = message(20, ME, &settings_content("on"));
+
+        // Before the bot knows who it is, it trusts nobody
+        let settings = ChatSettings::new("TestBot");
+        assert!(!settings.observe_message(&own));
+
+        let settings = settings.with_identity(UserId(ME.to_string()));
+        assert!(!settings.observe_message(&forged));
+        assert!(settings.observe_message(&own));
+        let mut newer = forged.clone();
+        newer.id = MessageId(Snowflake(30));
+        assert!(!settings.observe_message(&newer));
+        assert_eq!(greeting(&settings).as_deref(), Some("on"));
+    }
+
+    /// A room whose log contains some settings messages and whose server
+    /// answers commands like heim would.
+    ///
+    /// Edits are rejected unless `can_edit` is set. Returns all commands other
+    /// than log and get-message commands.
+    async fn serve(mut server: Server, can_edit: bool) -> Vec<serde_json::Value> {
+        server.join(hello(false, None)).await;
+
+        let mut truncated = message(3000, ME, &settings_content("off"));
+        truncated.truncated = true;
+        truncated.content.truncate(20);
+        let mut log = vec![
+            message(1000, ME, &settings_content("on")),
+            message(2000, "agent:human", "hello"),
+            truncated,
+            message(4000, "bot:other", &settings_content("forged")),
+            message(5000, "agent:human", "bye"),
+        ];
+        let full = message(3000, ME, &settings_content("off"));
+
+        let mut commands = vec![];
+        let mut edits = 0;
+        while let Some(cmd) = server.recv().await {
+            let (r#type, data) = match cmd["type"].as_str().unwrap() {
+                "log" => {
+                    let n = cmd["data"]["n"].as_u64().unwrap() as usize;
+                    let before = match cmd["data"]["before"].as_str() {
+                        Some(before) => before.parse::<Snowflake>().unwrap().0,
+                        None => u64::MAX,
+                    };
+                    let older = log
+                        .iter()
+                        .filter(|m| m.id.0 .0 < before)
+                        .collect::<Vec<_>>();
+                    let page = &older[older.len().saturating_sub(n)..];
+                    let data = serde_json::json!({ "log": page, "before": cmd["data"]["before"] });
+                    ("log-reply", data)
+                }
+                "get-message" => {
+                    assert_eq!(cmd["data"]["id"], full.id.0.to_string());
+                    ("get-message-reply", serde_json::to_value(&full).unwrap())
+                }
+                "send" => {
+                    commands.push(cmd.clone());
+                    let id = (log.len() as u64 + 1) * 1000;
+                    let mut msg = message(id, ME, cmd["data"]["content"].as_str().unwrap());
+                    msg.parent = cmd["data"]["parent"]
+                        .as_str()
+                        .map(|p| MessageId(p.parse().unwrap()));
+                    log.push(msg.clone());
+                    ("send-reply", serde_json::to_value(&msg).unwrap())
+                }
+                "edit-message" => {
+                    commands.push(cmd.clone());
+                    if !can_edit {
+                        let reply = serde_json::json!({
+                            "id": cmd["id"],
+                            "type": "edit-message-reply",
+                            "error": "access denied",
+                        });
+                        server.send(reply).await;
+                        continue;
+                    }
+                    let id = cmd["data"]["id"].as_str().unwrap().parse().unwrap();
+                    let msg = log.iter_mut().find(|m| m.id.0 == id).unwrap();
+                    msg.content = cmd["data"]["content"].as_str().unwrap().to_string();
+                    msg.truncated = false;
+                    edits += 1;
+                    msg.previous_edit_id = Some(Snowflake(10_000 + edits));
+                    ("edit-message-reply", serde_json::to_value(&*msg).unwrap())
+                }
+                other => panic!("unexpected command {other}"),
+            };
+            server
+                .send(serde_json::json!({ "id": cmd["id"], "type": r#type, "data": data }))
+                .await;
+        }
+        commands
+    }
+
+    /// Fetch the settings, change them twice and return the commands the
+    /// server received.
+    async fn round_trip(settings: &ChatSettings, can_edit: bool) -> Vec<serde_json::Value> {
+        let (mut conn, server) = connect(Duration::from_secs(10)).await;
+        let server = tokio::spawn(serve(server, can_edit));
+        let conn_tx = conn.tx().clone();
+
+        let script = async {
+            let limits = SearchLimits::new().with_page_size(2);
+            assert!(settings.fetch(&conn_tx, limits).await.unwrap());
+            assert_eq!(greeting(settings).as_deref(), Some("off"));
+            assert_eq!(settings.message(), Some(MessageId(Snowflake(3000))));
+
+            assert!(settings.set(&conn_tx, "greeting", "on").await.unwrap());
+            assert!(settings.set(&conn_tx, "farewell", "off").await.unwrap());
+            assert!(settings.remove(&conn_tx, "greeting").await.unwrap());
+        };
+        tokio::pin!(script);
+        loop {
+            tokio::select! {
+                () = &mut script => break,
+                result = conn.recv() => settings.observe(&result.unwrap()),
+            }
+        }
+        drop(conn);
+        server.await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn posts_new_messages() {
+        let thread = MessageId(Snowflake(1000));
+        let settings = ChatSettings::new("TestBot").with_thread(thread);
+        let commands = round_trip(&settings, true).await;
+
+        let types = commands.iter().map(|c| &c["type"]).collect::<Vec<_>>();
+        assert_eq!(types, ["send", "send", "send"]);
+        let parent = thread.0.to_string();
+        assert!(commands.iter().all(|c| c["data"]["parent"] == parent));
+        assert_eq!(settings.message(), Some(MessageId(Snowflake(8000))));
+
+        // The settings survive a restart
+        let restarted = own_settings();
+        let content = commands[2]["data"]["content"].as_str().unwrap();
+        assert!(restarted.observe_message(&message(8000, ME, content)));
+        assert_eq!(restarted.values(), settings.values());
+        assert_eq!(greeting(&restarted), None);
+        assert_eq!(restarted.get("farewell").as_deref(), Some("off"));
+    }
+
+    #[tokio::test]
+    async fn edits_in_place() {
+        let settings = ChatSettings::new("TestBot").with_edit_in_place(true);
+        let commands = round_trip(&settings, true).await;
+
+        let types = commands.iter().map(|c| &c["type"]).collect::<Vec<_>>();
+        assert_eq!(types, ["edit-message", "edit-message", "edit-message"]);
+        // Every edit is based on the previous one
+        assert!(commands[0]["data"].get("previous_edit_id").is_none());
+        assert_eq!(
+            commands[1]["data"]["previous_edit_id"],
+            Snowflake(10_001).to_string()
+        );
+        assert_eq!(
+            commands[2]["data"]["previous_edit_id"],
+            Snowflake(10_002).to_string()
+        );
+        assert_eq!(settings.message(), Some(MessageId(Snowflake(3000))));
+        assert_eq!(settings.get("farewell").as_deref(), Some("off"));
+    }
+
+    #[tokio::test]
+    async fn falls_back_to_posting() {
+        let settings = ChatSettings::new("TestBot").with_edit_in_place(true);
+        let commands = round_trip(&settings, false).await;
+
+        // Without permission to edit, the bot stops trying after the first
+        // failed edit
+        let types = commands.iter().map(|c| &c["type"]).collect::<Vec<_>>();
+        assert_eq!(types, ["edit-message", "send", "send", "send"]);
+        assert_eq!(settings.message(), Some(MessageId(Snowflake(8000))));
+        assert_eq!(settings.get("farewell").as_deref(), Some("off"));
+    }
+}
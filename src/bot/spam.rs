@@ -0,0 +1,592 @@
+//! Simple heuristics for detecting spam.
+//!
+//! [`SpamHeuristics`] only reports suspicious behaviour. What to do about it,
+//! e.g. warning, kicking or banning the sender, is up to the bot.
+
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::time::Duration;
+
+use jiff::Timestamp;
+
+use crate::api::{Data, Message, MessageId, SessionId, UserId};
+
+/// A rule of [`SpamHeuristics`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum SpamRule {
+    /// The same content was posted too often within a short time.
+    Repeat,
+    /// Too many messages were posted within a short time.
+    Rate,
+    /// A session posted a link right after joining the room.
+    LinkFromNewSession,
+}
+
+impl fmt::Display for SpamRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Repeat => write!(f, "repeated content"),
+            Self::Rate => write!(f, "message rate"),
+            Self::LinkFromNewSession => write!(f, "link from new session"),
+        }
+    }
+}
+
+/// A message that triggered a [`SpamRule`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SpamVerdict {
+    pub rule: SpamRule,
+    pub user: UserId,
+    pub session: SessionId,
+    /// The messages that together triggered the rule, oldest first.
+    ///
+    /// The message that was just checked is always the last one.
+    pub evidence: Vec<MessageId>,
+}
+
+/// Thresholds and limits of [`SpamHeuristics`].
+///
+/// Rules can be disabled by setting their threshold to `None`.
+///
+/// # Example
+///
+/// ```
+/// use std::time::Duration;
+///
+/// use euphoxide::bot::spam::SpamConfig;
+///
+/// let config = SpamConfig::new()
+///     .with_rate(10, Duration::from_secs(60))
+///     .with_new_session_age_opt(None);
+/// assert_eq!(config.rate_threshold, Some(10));
+/// assert_eq!(config.new_session_age, None);
+/// ```
+#[derive(Debug, Clone)]
+pub struct SpamConfig {
+    /// How often the same content (ignoring whitespace differences) may be
+    /// posted by a user within [`Self::repeat_window`] before
+    /// [`SpamRule::Repeat`] triggers.
+    pub repeat_threshold: Option<usize>,
+    pub repeat_window: Duration,
+    /// How many messages a user may post within [`Self::rate_window`] before
+    /// [`SpamRule::Rate`] triggers.
+    pub rate_threshold: Option<usize>,
+    pub rate_window: Duration,
+    /// For how long after joining a session may not post links before
+    /// [`SpamRule::LinkFromNewSession`] triggers.
+    pub new_session_age: Option<Duration>,
+    /// How many messages to remember per user.
+    ///
+    /// Thresholds above this limit can never be reached.
+    pub max_messages_per_user: usize,
+    /// How many users to remember messages of.
+    ///
+    /// If more users are active, the ones that were inactive the longest are
+    /// forgotten early.
+    pub max_users: usize,
+    /// How many recently joined sessions to remember.
+    ///
+    /// If more sessions join, the ones that joined first are forgotten early.
+    pub max_new_sessions: usize,
+}
+
+impl SpamConfig {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_repeat(self, threshold: usize, window: Duration) -> Self {
+        self.with_repeat_opt(Some(threshold), window)
+    }
+
+    pub fn with_repeat_opt(mut self, threshold: Option<usize>, window: Duration) -> Self {
+        self.repeat_threshold = threshold;
+        self.repeat_window = window;
+        self
+    }
+
+    pub fn with_rate(self, threshold: usize, window: Duration) -> Self {
+        self.with_rate_opt(Some(threshold), window)
+    }
+
+    pub fn with_rate_opt(mut self, threshold: Option<usize>, window: Duration) -> Self {
+        self.rate_threshold = threshold;
+        self.rate_window = window;
+        self
+    }
+
+    pub fn with_new_session_age(self, age: Duration) -> Self {
+        self.with_new_session_age_opt(Some(age))
+    }
+
+    pub fn with_new_session_age_opt(mut self, age: Option<Duration>) -> Self {
+        self.new_session_age = age;
+        self
+    }
+
+    pub fn with_max_messages_per_user(mut self, max: usize) -> Self {
+        self.max_messages_per_user = max;
+        self
+    }
+
+    pub fn with_max_users(mut self, max: usize) -> Self {
+        self.max_users = max;
+        self
+    }
+
+    pub fn with_max_new_sessions(mut self, max: usize) -> Self {
+        self.max_new_sessions = max;
+        self
+    }
+
+    /// The longest time any message must be remembered for.
+    fn max_window(&self) -> Duration {
+        let repeat = self.repeat_threshold.map(|_| self.repeat_window);
+        let rate = self.rate_threshold.map(|_| self.rate_window);
+        repeat.max(rate).unwrap_or_default()
+    }
+}
+
+impl Default for SpamConfig {
+    fn default() -> Self {
+        Self {
+            repeat_threshold: Some(3),
+            repeat_window: Duration::from_secs(60),
+            rate_threshold: Some(10),
+            rate_window: Duration::from_secs(10),
+            new_session_age: Some(Duration::from_secs(5 * 60)),
+            max_messages_per_user: 32,
+            max_users: 1000,
+            max_new_sessions: 1000,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Seen {
+    time: Timestamp,
+    id: MessageId,
+    content_hash: u64,
+}
+
+fn content_hash(content: &str) -> u64 {
+    // Whitespace differences shouldn't make two messages distinct
+    let mut hasher = DefaultHasher::new();
+    for word in content.split_whitespace() {
+        word.hash(&mut hasher);
+    }
+    hasher.finish()
+}
+
+fn contains_link(content: &str) -> bool {
+    content.split_whitespace().any(|word| {
+        let word = word.to_lowercase();
+        word.contains("http://") || word.contains("https://") || word.starts_with("www.")
+    })
+}
+
+/// How much time passed between two timestamps, or zero if `then` is after
+/// `now`.
+fn age(then: Timestamp, now: Timestamp) -> Duration {
+    Duration::try_from(now.duration_since(then)).unwrap_or_default()
+}
+
+/// Detects spam in a stream of messages.
+///
+/// Messages are fed to [`Self::check`], which reports all rules the message
+/// triggered. A message triggers [`SpamRule::Repeat`] and [`SpamRule::Rate`]
+/// every time the respective threshold is reached, so a user posting the same
+/// content five times with a threshold of three produces three verdicts.
+///
+/// To detect [`SpamRule::LinkFromNewSession`], joining sessions must be fed to
+/// [`Self::on_join`]. Sessions that were already present when the bot joined
+/// are never considered new. [`Self::on_data`] does both.
+///
+/// Time is measured using the messages' own timestamps, so messages should be
+/// checked roughly in the order they were sent. Old data is forgotten as new
+/// messages arrive, and can also be forgotten explicitly using
+/// [`Self::decay`].
+#[derive(Debug, Clone, Default)]
+pub struct SpamHeuristics {
+    config: SpamConfig,
+    users: HashMap<UserId, VecDeque<Seen>>,
+    /// Recently joined sessions, ordered by join time.
+    joins: VecDeque<(SessionId, Timestamp)>,
+}
+
+impl SpamHeuristics {
+    pub fn new(config: SpamConfig) -> Self {
+        Self {
+            config,
+            users: HashMap::new(),
+            joins: VecDeque::new(),
+        }
+    }
+
+    pub fn config(&self) -> &SpamConfig {
+        &self.config
+    }
+
+    /// How many users messages are currently remembered of.
+    pub fn tracked_users(&self) -> usize {
+        self.users.len()
+    }
+
+    /// How many recently joined sessions are currently remembered.
+    pub fn tracked_sessions(&self) -> usize {
+        self.joins.len()
+    }
+
+    /// Remember that a session joined the room.
+    pub fn on_join(&mut self, session: &SessionId, time: Timestamp) {
+        if self.config.new_session_age.is_none() {
+            return;
+        }
+        self.joins.retain(|(id, _)| id != session);
+        while self.joins.len() >= self.config.max_new_sessions.max(1) {
+            self.joins.pop_front();
+        }
+        self.joins.push_back((session.clone(), time));
+    }
+
+    /// Forget that a session joined the room.
+    pub fn on_part(&mut self, session: &SessionId) {
+        self.joins.retain(|(id, _)| id != session);
+    }
+
+    /// Feed a packet, checking messages and remembering joins and parts.
+    ///
+    /// Joins are timestamped with `now`.
+    pub fn on_data(&mut self, data: &Data, now: Timestamp) -> Vec<SpamVerdict> {
+        match data {
+            Data::SendEvent(event) => return self.check(&event.0),
+            Data::JoinEvent(event) => self.on_join(&event.0.session_id, now),
+            Data::PartEvent(event) => self.on_part(&event.0.session_id),
+            _ => {}
+        }
+        vec![]
+    }
+
+    /// Forget everything that is too old to trigger any rule at `now`.
+    pub fn decay(&mut self, now: Timestamp) {
+        let max_window = self.config.max_window();
+        self.users.retain(|_, seen| {
+            seen.retain(|s| age(s.time, now) < max_window);
+            !seen.is_empty()
+        });
+
+        let max_age = self.config.new_session_age.unwrap_or_default();
+        self.joins.retain(|(_, time)| age(*time, now) < max_age);
+    }
+
+    fn joined_at(&self, session: &SessionId) -> Option<Timestamp> {
+        let (_, time) = self.joins.iter().find(|(id, _)| id == session)?;
+        Some(*time)
+    }
+
+    /// Forget the user that was inactive for the longest time.
+    fn evict_user(&mut self) {
+        let oldest = self
+            .users
+            .iter()
+            .min_by_key(|(_, seen)| seen.back().map(|s| s.time))
+            .map(|(id, _)| id.clone());
+        if let Some(oldest) = oldest {
+            self.users.remove(&oldest);
+        }
+    }
+
+    /// Check a message, returning the rules it triggered.
+    pub fn check(&mut self, msg: &Message) -> Vec<SpamVerdict> {
+        let now = msg.time.as_timestamp();
+        self.decay(now);
+
+        let mut verdicts = vec![];
+        let verdict = |rule, evidence| SpamVerdict {
+            rule,
+            user: msg.sender.id.clone(),
+            session: msg.sender.session_id.clone(),
+            evidence,
+        };
+
+        if let Some(max_age) = self.config.new_session_age {
+            if let Some(joined) = self.joined_at(&msg.sender.session_id) {
+                if age(joined, now) < max_age && contains_link(&msg.content) {
+                    verdicts.push(verdict(SpamRule::LinkFromNewSession, vec![msg.id]));
+                }
+            }
+        }
+
+        if self.config.max_window().is_zero() {
+            return verdicts;
+        }
+
+        if !self.users.contains_key(&msg.sender.id) && self.users.len() >= self.config.max_users {
+            self.evict_user();
+        }
+        let seen = self.users.entry(msg.sender.id.clone()).or_default();
+        while seen.len() >= self.config.max_messages_per_user.max(1) {
+            seen.pop_front();
+        }
+        seen.push_back(Seen {
+            time: now,
+            id: msg.id,
+            content_hash: content_hash(&msg.content),
+        });
+
+        if let Some(threshold) = self.config.repeat_threshold {
+            let hash = seen.back().unwrap().content_hash;
+            let evidence = seen
+                .iter()
+                .filter(|s| age(s.time, now) < self.config.repeat_window)
+                .filter(|s| s.content_hash == hash)
+                .map(|s| s.id)
+                .collect::<Vec<_>>();
+            if evidence.len() >= threshold {
+                verdicts.push(verdict(SpamRule::Repeat, evidence));
+            }
+        }
+
+        if let Some(threshold) = self.config.rate_threshold {
+            let evidence = seen
+                .iter()
+                .filter(|s| age(s.time, now) < self.config.rate_window)
+                .map(|s| s.id)
+                .collect::<Vec<_>>();
+            if evidence.len() >= threshold {
+                verdicts.push(verdict(SpamRule::Rate, evidence));
+            }
+        }
+
+        verdicts
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use jiff::{Timestamp, ToSpan};
+
+    use crate::api::{Message, MessageId, SessionId, Snowflake, Time};
+    use crate::test_util::{MessageBuilder, SessionViewBuilder};
+
+    use super::{SpamConfig, SpamHeuristics, SpamRule, SpamVerdict};
+
+    const START: i64 = 1_700_000_000;
+
+    fn message(id: u64, user: &str, second: i64, content: &str) -> Message {
+        MessageBuilder::new(content)
+            .with_id(MessageId(Snowflake(id)))
+            .with_time(Time(START + second))
+            .with_sender(SessionViewBuilder::new(user).build())
+            .build()
+    }
+
+    fn at(second: i64) -> Timestamp {
+        Timestamp::from_second(START).unwrap() + second.seconds()
+    }
+
+    fn secs(secs: u64) -> Duration {
+        Duration::from_secs(secs)
+    }
+
+    /// Check a stream of messages, returning the rules and evidence of all
+    /// verdicts by message id.
+    fn run(spam: &mut SpamHeuristics, stream: &[Message]) -> Vec<(u64, SpamRule, Vec<u64>)> {
+        let mut result = vec![];
+        for msg in stream {
+            for SpamVerdict { rule, evidence, .. } in spam.check(msg) {
+                let evidence = evidence.iter().map(|id| id.0 .0).collect();
+                result.push((msg.id.0 .0, rule, evidence));
+            }
+        }
+        result
+    }
+
+    fn only(rule: SpamRule) -> SpamConfig {
+        let config = SpamConfig::default()
+            .with_repeat_opt(None, secs(60))
+            .with_rate_opt(None, secs(10))
+            .with_new_session_age_opt(None);
+        match rule {
+            SpamRule::Repeat => config.with_repeat(3, secs(60)),
+            SpamRule::Rate => config.with_rate(4, secs(10)),
+            SpamRule::LinkFromNewSession => config.with_new_session_age(secs(300)),
+        }
+    }
+
+    #[test]
+    fn repeat() {
+        let mut spam = SpamHeuristics::new(only(SpamRule::Repeat));
+        let stream = [
+            message(1, "a", 0, "buy now"),
+            message(2, "a", 10, "something else"),
+            message(3, "a", 20, "buy   now"),
+            // Other users don't count
+            message(4, "b", 25, "buy now"),
+            message(5, "a", 30, "buy now"),
+            message(6, "a", 40, "buy now"),
+            // The first message left the window
+            message(7, "a", 85, "buy now"),
+            // All earlier messages left the window
+            message(8, "a", 150, "buy now"),
+        ];
+        assert_eq!(
+            run(&mut spam, &stream),
+            [
+                (5, SpamRule::Repeat, vec![1, 3, 5]),
+                (6, SpamRule::Repeat, vec![1, 3, 5, 6]),
+                (7, SpamRule::Repeat, vec![5, 6, 7]),
+            ]
+        );
+    }
+
+    #[test]
+    fn rate() {
+        let mut spam = SpamHeuristics::new(only(SpamRule::Rate));
+        let stream = [
+            message(1, "a", 0, "1"),
+            message(2, "a", 2, "2"),
+            message(3, "b", 3, "hi"),
+            message(4, "a", 4, "3"),
+            message(5, "a", 6, "4"),
+            message(6, "a", 11, "5"),
+            message(7, "a", 30, "6"),
+        ];
+        assert_eq!(
+            run(&mut spam, &stream),
+            [
+                (5, SpamRule::Rate, vec![1, 2, 4, 5]),
+                (6, SpamRule::Rate, vec![2, 4, 5, 6]),
+            ]
+        );
+    }
+
+    #[test]
+    fn link_from_new_session() {
+        let mut spam = SpamHeuristics::new(only(SpamRule::LinkFromNewSession));
+        spam.on_join(&SessionId("new".to_string()), at(0));
+        spam.on_join(&SessionId("parted".to_string()), at(0));
+        spam.on_join(&SessionId("slow".to_string()), at(0));
+        spam.on_part(&SessionId("parted".to_string()));
+
+        let stream = [
+            message(1, "new", 10, "hello"),
+            message(2, "new", 20, "see https://example.com"),
+            message(3, "new", 30, "or WWW.example.com"),
+            // Sessions not seen joining are not new
+            message(4, "old", 30, "https://example.com"),
+            message(5, "parted", 30, "https://example.com"),
+            message(6, "slow", 300, "https://example.com"),
+        ];
+        assert_eq!(
+            run(&mut spam, &stream),
+            [
+                (2, SpamRule::LinkFromNewSession, vec![2]),
+                (3, SpamRule::LinkFromNewSession, vec![3]),
+            ]
+        );
+    }
+
+    #[test]
+    fn multiple_rules() {
+        let config = SpamConfig::default()
+            .with_repeat(2, secs(60))
+            .with_rate(2, secs(10));
+        let mut spam = SpamHeuristics::new(config);
+        spam.on_join(&SessionId("a".to_string()), at(0));
+
+        let stream = [
+            message(1, "a", 0, "https://example.com"),
+            message(2, "a", 1, "https://example.com"),
+        ];
+        assert_eq!(
+            run(&mut spam, &stream),
+            [
+                (1, SpamRule::LinkFromNewSession, vec![1]),
+                (2, SpamRule::LinkFromNewSession, vec![2]),
+                (2, SpamRule::Repeat, vec![1, 2]),
+                (2, SpamRule::Rate, vec![1, 2]),
+            ]
+        );
+    }
+
+    #[test]
+    fn bounded_per_user() {
+        let config = only(SpamRule::Rate).with_max_messages_per_user(3);
+        let mut spam = SpamHeuristics::new(config);
+        let stream = (1..=10)
+            .map(|i| message(i, "a", 0, "spam"))
+            .collect::<Vec<_>>();
+        // With only three messages remembered, the threshold of four is never
+        // reached
+        assert_eq!(run(&mut spam, &stream), []);
+
+        let config = only(SpamRule::Rate).with_max_messages_per_user(5);
+        let mut spam = SpamHeuristics::new(config);
+        let result = run(&mut spam, &stream);
+        assert_eq!(result.len(), 7);
+        assert_eq!(result[6], (10, SpamRule::Rate, vec![6, 7, 8, 9, 10]));
+    }
+
+    #[test]
+    fn bounded_globally() {
+        let config = only(SpamRule::Repeat).with_max_users(2);
+        let mut spam = SpamHeuristics::new(config);
+        let stream = [
+            message(1, "a", 0, "spam"),
+            message(2, "a", 1, "spam"),
+            message(3, "b", 2, "spam"),
+            // Evicts a, the user inactive for the longest time
+            message(4, "c", 3, "spam"),
+            message(5, "a", 4, "spam"),
+            message(6, "b", 5, "spam"),
+            message(7, "b", 6, "spam"),
+        ];
+        assert_eq!(run(&mut spam, &stream), []);
+        assert_eq!(spam.tracked_users(), 2);
+
+        let mut spam =
+            SpamHeuristics::new(only(SpamRule::LinkFromNewSession).with_max_new_sessions(2));
+        for (i, name) in ["a", "b", "c"].into_iter().enumerate() {
+            spam.on_join(&SessionId(name.to_string()), at(i as i64));
+        }
+        assert_eq!(spam.tracked_sessions(), 2);
+        let stream = [
+            message(1, "a", 10, "https://example.com"),
+            message(2, "c", 10, "https://example.com"),
+        ];
+        assert_eq!(
+            run(&mut spam, &stream),
+            [(2, SpamRule::LinkFromNewSession, vec![2])]
+        );
+    }
+
+    #[test]
+    fn decay() {
+        let config = SpamConfig::default()
+            .with_repeat(3, secs(60))
+            .with_rate(10, secs(10));
+        let mut spam = SpamHeuristics::new(config);
+        spam.on_join(&SessionId("a".to_string()), at(0));
+        run(
+            &mut spam,
+            &[message(1, "a", 0, "hi"), message(2, "b", 30, "hi")],
+        );
+        assert_eq!((spam.tracked_users(), spam.tracked_sessions()), (2, 1));
+
+        spam.decay(at(59));
+        assert_eq!((spam.tracked_users(), spam.tracked_sessions()), (2, 1));
+        spam.decay(at(60));
+        assert_eq!((spam.tracked_users(), spam.tracked_sessions()), (1, 1));
+        spam.decay(at(300));
+        assert_eq!((spam.tracked_users(), spam.tracked_sessions()), (0, 0));
+
+        // Checking messages decays old data too
+        run(&mut spam, &[message(3, "c", 1000, "hi")]);
+        assert_eq!(spam.tracked_users(), 1);
+    }
+}
@@ -0,0 +1,512 @@
+//! Running multiple independent bots in a single process.
+//!
+//! See [`Supervisor`] for more details.
+
+use std::any::Any;
+use std::collections::HashMap;
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use log::{info, warn};
+use tokio::select;
+use tokio::sync::Notify;
+use tokio::task::AbortHandle;
+use tokio::time::Instant;
+
+type UnitFuture = Pin<Box<dyn Future<Output = ()> + Send>>;
+type MakeFn = dyn Fn() -> UnitFuture + Send + Sync;
+
+/// When a [`Supervisor`] should restart a unit whose task has ended.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum RestartPolicy {
+    /// Restart the unit whenever its task ends.
+    Always,
+    /// Restart the unit only if its task panicked.
+    #[default]
+    OnPanic,
+    /// Never restart the unit automatically.
+    Never,
+}
+
+/// How a [`Supervisor`] should treat a unit.
+///
+/// # Example
+///
+/// ```
+/// use std::time::Duration;
+///
+/// use euphoxide::bot::supervisor::{RestartPolicy, UnitConfig};
+///
+/// let config = UnitConfig::new()
+///     .with_policy(RestartPolicy::Always)
+///     .with_max_delay(Duration::from_secs(60));
+/// assert_eq!(config.max_delay, Duration::from_secs(60));
+/// ```
+#[derive(Debug, Clone, Copy)]
+pub struct UnitConfig {
+    pub policy: RestartPolicy,
+    /// Delay before the first restart after a unit's task ended.
+    ///
+    /// The delay is doubled for every consecutive restart until it reaches
+    /// [`Self::max_delay`]. Once a unit ran for at least [`Self::max_delay`], the
+    /// delay is reset.
+    pub min_delay: Duration,
+    /// Maximum delay between restarts.
+    pub max_delay: Duration,
+}
+
+impl UnitConfig {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_policy(mut self, policy: RestartPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    pub fn with_min_delay(mut self, min_delay: Duration) -> Self {
+        self.min_delay = min_delay;
+        self
+    }
+
+    pub fn with_max_delay(mut self, max_delay: Duration) -> Self {
+        self.max_delay = max_delay;
+        self
+    }
+
+    fn next_delay(&self, delay: Duration) -> Duration {
+        (delay * 2).min(self.max_delay).max(self.min_delay)
+    }
+}
+
+impl Default for UnitConfig {
+    fn default() -> Self {
+        Self {
+            policy: RestartPolicy::default(),
+            min_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(5 * 60),
+        }
+    }
+}
+
+/// What a unit is currently doing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnitState {
+    /// The unit's task is running.
+    Running,
+    /// The unit's task has ended and the unit is waiting to be restarted.
+    Restarting,
+    /// The unit's task has ended and the unit won't be restarted
+    /// automatically.
+    Stopped,
+}
+
+/// The status of a unit, as returned by [`Supervisor::status`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnitStatus {
+    pub name: String,
+    pub state: UnitState,
+    /// How often the unit was restarted, both automatically and using
+    /// [`Supervisor::restart`].
+    pub restarts: usize,
+    /// The message of the most recent panic, if any.
+    pub last_error: Option<String>,
+}
+
+impl fmt::Display for UnitStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let state = match self.state {
+            UnitState::Running => "running",
+            UnitState::Restarting => "restarting",
+            UnitState::Stopped => "stopped",
+        };
+        write!(
+            f,
+            "unit {} {state}, restarted {} times",
+            self.name, self.restarts
+        )?;
+        if let Some(error) = &self.last_error {
+            write!(f, ", last error: {error}")?;
+        }
+        Ok(())
+    }
+}
+
+struct Unit {
+    status: Arc<Mutex<UnitStatus>>,
+    restart: Arc<Notify>,
+    task: AbortHandle,
+}
+
+/// Aborts a task when dropped.
+struct AbortOnDrop(AbortHandle);
+
+impl Drop for AbortOnDrop {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+/// Runs multiple named units and restarts them when they end.
+///
+/// A unit is usually a single logical bot, e.g. a [`Commands`] with some
+/// [`Instances`] and the event loop handling their events. It is created by a
+/// function returning a future, which is called again every time the unit is
+/// (re-)started. The future is run in its own task so that panics don't affect
+/// other units.
+///
+/// The future should create all of the unit's [`Instance`]s and channels
+/// itself. When the unit's task ends or is aborted, they are dropped and the
+/// instances stop, so every restart begins with fresh instances.
+///
+/// Units are identified by their names. Cloning a supervisor is cheap and the
+/// clones refer to the same units, so a clone can e.g. be passed to an admin
+/// command that restarts units using [`Self::restart`]. The units keep running
+/// when the supervisor is dropped. Use [`Self::remove`] to stop them.
+///
+/// [`Commands`]: super::commands::Commands
+/// [`Instances`]: super::instances::Instances
+/// [`Instance`]: super::instance::Instance
+#[derive(Clone, Default)]
+pub struct Supervisor {
+    units: Arc<Mutex<HashMap<String, Unit>>>,
+}
+
+impl Supervisor {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add and start a new unit.
+    ///
+    /// If a unit with the same name exists already, it will be stopped and
+    /// replaced by the new unit.
+    pub fn add<S, F, Fut>(&self, name: S, config: UnitConfig, make: F)
+    where
+        S: ToString,
+        F: Fn() -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        let name = name.to_string();
+        let make: Arc<MakeFn> = Arc::new(move || Box::pin(make()));
+        let status = Arc::new(Mutex::new(UnitStatus {
+            name: name.clone(),
+            state: UnitState::Running,
+            restarts: 0,
+            last_error: None,
+        }));
+        let restart = Arc::new(Notify::new());
+
+        let task = tokio::spawn(supervise(make, config, status.clone(), restart.clone()));
+        let unit = Unit {
+            status,
+            restart,
+            task: task.abort_handle(),
+        };
+
+        if let Some(old) = self.units.lock().unwrap().insert(name, unit) {
+            old.task.abort();
+        }
+    }
+
+    /// Stop and remove a unit by its name.
+    ///
+    /// Returns `false` if no unit with this name exists.
+    pub fn remove(&self, name: &str) -> bool {
+        match self.units.lock().unwrap().remove(name) {
+            Some(unit) => {
+                unit.task.abort();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Restart a unit by its name.
+    ///
+    /// If the unit is running, its task is aborted. The unit is then restarted
+    /// immediately, regardless of its [`RestartPolicy`] or any delay. This also
+    /// restarts units that have stopped.
+    ///
+    /// Returns `false` if no unit with this name exists.
+    pub fn restart(&self, name: &str) -> bool {
+        match self.units.lock().unwrap().get(name) {
+            Some(unit) => {
+                unit.restart.notify_one();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Get the status of a unit by its name.
+    pub fn status(&self, name: &str) -> Option<UnitStatus> {
+        let units = self.units.lock().unwrap();
+        let status = units.get(name)?.status.lock().unwrap().clone();
+        Some(status)
+    }
+
+    /// Get the status of all units, ordered by their names.
+    pub fn statuses(&self) -> Vec<UnitStatus> {
+        let units = self.units.lock().unwrap();
+        let mut statuses = units
+            .values()
+            .map(|u| u.status.lock().unwrap().clone())
+            .collect::<Vec<_>>();
+        statuses.sort_unstable_by(|a, b| a.name.cmp(&b.name));
+        statuses
+    }
+}
+
+impl fmt::Debug for Supervisor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Supervisor")
+            .field("units", &self.statuses())
+            .finish()
+    }
+}
+
+fn panic_message(payload: Box<dyn Any + Send>) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "unknown panic".to_string()
+    }
+}
+
+async fn supervise(
+    make: Arc<MakeFn>,
+    config: UnitConfig,
+    status: Arc<Mutex<UnitStatus>>,
+    restart: Arc<Notify>,
+) {
+    let name = status.lock().unwrap().name.clone();
+    let mut delay = config.min_delay;
+
+    loop {
+        status.lock().unwrap().state = UnitState::Running;
+        let started = Instant::now();
+
+        // The function is called within the unit's task so that panics in the
+        // function itself are caught like panics in the future it returns.
+        let make = make.clone();
+        let mut task = tokio::spawn(async move { make().await });
+        let guard = AbortOnDrop(task.abort_handle());
+        let (result, requested) = select! {
+            result = &mut task => (result, false),
+            () = restart.notified() => {
+                task.abort();
+                (task.await, true)
+            }
+        };
+        drop(guard);
+
+        let panic = match result {
+            Err(err) if err.is_panic() => Some(panic_message(err.into_panic())),
+            _ => None,
+        };
+
+        let should_restart = requested
+            || match config.policy {
+                RestartPolicy::Always => true,
+                RestartPolicy::OnPanic => panic.is_some(),
+                RestartPolicy::Never => false,
+            };
+
+        {
+            let mut status = status.lock().unwrap();
+            if let Some(panic) = &panic {
+                warn!("Unit {name} panicked: {panic}");
+                status.last_error = Some(panic.clone());
+            }
+            status.state = if should_restart {
+                UnitState::Restarting
+            } else {
+                UnitState::Stopped
+            };
+        }
+
+        if requested {
+            info!("Restarting unit {name} on request");
+        } else if should_restart {
+            if started.elapsed() >= config.max_delay {
+                delay = config.min_delay;
+            }
+            info!("Restarting unit {name} in {delay:?}");
+            select! {
+                () = tokio::time::sleep(delay) => {}
+                () = restart.notified() => {}
+            }
+            delay = config.next_delay(delay);
+        } else {
+            info!("Unit {name} stopped");
+            restart.notified().await;
+        }
+
+        status.lock().unwrap().restarts += 1;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::future;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    use tokio::time::Instant;
+
+    use super::{RestartPolicy, Supervisor, UnitConfig, UnitState};
+
+    /// Add a unit that panics during its first `panics` starts and keeps
+    /// running afterwards. Returns the times at which the unit was started.
+    fn add_panicking(
+        supervisor: &Supervisor,
+        config: UnitConfig,
+        panics: usize,
+    ) -> Arc<Mutex<Vec<Instant>>> {
+        let starts = Arc::new(Mutex::new(vec![]));
+        let count = Arc::new(AtomicUsize::new(0));
+        supervisor.add("unit", config, {
+            let starts = starts.clone();
+            move || {
+                starts.lock().unwrap().push(Instant::now());
+                let n = count.fetch_add(1, Ordering::SeqCst);
+                async move {
+                    if n < panics {
+                        panic!("panic {n}");
+                    }
+                    future::pending::<()>().await;
+                }
+            }
+        });
+        starts
+    }
+
+    fn delays(starts: &Mutex<Vec<Instant>>) -> Vec<u64> {
+        let starts = starts.lock().unwrap();
+        starts.windows(2).map(|w| (w[1] - w[0]).as_secs()).collect()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn restarts_with_backoff() {
+        let supervisor = Supervisor::new();
+        let config = UnitConfig::new()
+            .with_min_delay(Duration::from_secs(1))
+            .with_max_delay(Duration::from_secs(4));
+        let starts = add_panicking(&supervisor, config, 4);
+
+        tokio::time::sleep(Duration::from_secs(60)).await;
+        assert_eq!(delays(&starts), [1, 2, 4, 4]);
+
+        let status = supervisor.status("unit").unwrap();
+        assert_eq!(status.state, UnitState::Running);
+        assert_eq!(status.restarts, 4);
+        assert_eq!(status.last_error.as_deref(), Some("panic 3"));
+        assert_eq!(
+            status.to_string(),
+            "unit unit running, restarted 4 times, last error: panic 3"
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn never_restarts() {
+        let supervisor = Supervisor::new();
+        let config = UnitConfig::new().with_policy(RestartPolicy::Never);
+        let starts = add_panicking(&supervisor, config, 1);
+
+        tokio::time::sleep(Duration::from_secs(60)).await;
+        assert_eq!(starts.lock().unwrap().len(), 1);
+        let status = supervisor.status("unit").unwrap();
+        assert_eq!(status.state, UnitState::Stopped);
+        assert_eq!(status.restarts, 0);
+
+        // Stopped units can still be restarted manually
+        assert!(supervisor.restart("unit"));
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        assert_eq!(starts.lock().unwrap().len(), 2);
+        let status = supervisor.status("unit").unwrap();
+        assert_eq!(status.state, UnitState::Running);
+        assert_eq!(status.restarts, 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn restart_on_request() {
+        let supervisor = Supervisor::new();
+        let starts = add_panicking(&supervisor, UnitConfig::new(), 0);
+        tokio::time::sleep(Duration::from_secs(1)).await;
+
+        assert!(supervisor.restart("unit"));
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        assert!(supervisor.restart("unit"));
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        assert!(!supervisor.restart("other"));
+
+        // Requested restarts happen immediately
+        assert_eq!(delays(&starts), [1, 1]);
+        let status = supervisor.status("unit").unwrap();
+        assert_eq!(status.state, UnitState::Running);
+        assert_eq!(status.restarts, 2);
+        assert_eq!(status.last_error, None);
+
+        assert!(supervisor.remove("unit"));
+        assert!(supervisor.status("unit").is_none());
+        assert!(supervisor.statuses().is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn make_panics() {
+        let supervisor = Supervisor::new();
+        let count = Arc::new(AtomicUsize::new(0));
+        for (name, policy) in [
+            ("never", RestartPolicy::Never),
+            ("on_panic", RestartPolicy::OnPanic),
+        ] {
+            let config = UnitConfig::new().with_policy(policy);
+            let count = count.clone();
+            supervisor.add(name, config, move || {
+                if count.fetch_add(1, Ordering::SeqCst) < 2 {
+                    panic!("make failed");
+                }
+                future::pending::<()>()
+            });
+        }
+
+        tokio::time::sleep(Duration::from_millis(1500)).await;
+        let statuses = supervisor.statuses();
+        assert_eq!(statuses[0].name, "never");
+        assert_eq!(statuses[0].state, UnitState::Stopped);
+        assert_eq!(statuses[0].last_error.as_deref(), Some("make failed"));
+        assert_eq!(statuses[1].name, "on_panic");
+        assert_eq!(statuses[1].state, UnitState::Running);
+        assert_eq!(statuses[1].restarts, 1);
+        assert_eq!(statuses[1].last_error.as_deref(), Some("make failed"));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn exits_depending_on_policy() {
+        let supervisor = Supervisor::new();
+        for (name, policy) in [
+            ("always", RestartPolicy::Always),
+            ("on_panic", RestartPolicy::OnPanic),
+        ] {
+            let config = UnitConfig::new().with_policy(policy);
+            supervisor.add(name, config, || async {});
+        }
+
+        tokio::time::sleep(Duration::from_millis(3500)).await;
+        let statuses = supervisor.statuses();
+        assert_eq!(statuses[0].name, "always");
+        assert_eq!(statuses[0].state, UnitState::Restarting);
+        assert_eq!(statuses[0].restarts, 2);
+        assert_eq!(statuses[1].name, "on_panic");
+        assert_eq!(statuses[1].state, UnitState::Stopped);
+        assert_eq!(statuses[1].restarts, 0);
+    }
+}
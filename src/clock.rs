@@ -0,0 +1,153 @@
+//! Sources of the current time.
+//!
+//! Components that need the current wall-clock time ask a [`Clock`] instead of
+//! calling [`Timestamp::now`] directly. This way, tests can use a [`MockClock`]
+//! and control the wall-clock time, including jumps.
+//!
+//! Monotonic time is not part of a [`Clock`]. Timeouts, delays, cooldowns and
+//! similar use [`tokio::time`], which tests can control by pausing it, e.g.
+//! via `#[tokio::test(start_paused = true)]`. Tests that need both should
+//! advance the [`MockClock`] along with tokio's time.
+
+use std::fmt;
+use std::future::{self, Future};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use jiff::Timestamp;
+use tokio::sync::Notify;
+
+pub trait Clock: fmt::Debug + Send + Sync {
+    /// The current wall-clock time.
+    fn now(&self) -> Timestamp;
+
+    /// Resolves once the time was moved by something other than its normal
+    /// passing, for example when a [`MockClock`] is advanced.
+    ///
+    /// Components sleeping until a wall-clock time use this to notice that
+    /// they should wake up earlier. The default implementation never resolves.
+    fn jumped(&self) -> Pin<Box<dyn Future<Output = ()> + Send + '_>> {
+        Box::pin(future::pending())
+    }
+}
+
+/// The real time, as reported by the operating system.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl SystemClock {
+    /// A shared [`SystemClock`], the default clock of all configs.
+    pub fn shared() -> Arc<dyn Clock> {
+        Arc::new(Self)
+    }
+}
+
+impl Clock for SystemClock {
+    fn now(&self) -> Timestamp {
+        Timestamp::now()
+    }
+}
+
+/// A clock that only moves when told to.
+///
+/// Clones share the same time.
+#[derive(Debug, Clone)]
+pub struct MockClock(Arc<Mutex<Timestamp>>, Arc<Notify>);
+
+impl MockClock {
+    /// A clock starting at the current time.
+    pub fn new() -> Self {
+        Self::at(Timestamp::now())
+    }
+
+    /// A clock starting at a specific wall-clock time.
+    pub fn at(now: Timestamp) -> Self {
+        Self(Arc::new(Mutex::new(now)), Arc::new(Notify::new()))
+    }
+
+    /// Move the wall-clock time forward.
+    ///
+    /// This doesn't affect tokio's time, see the [module docs](self).
+    pub fn advance(&self, duration: Duration) {
+        let mut guard = self.0.lock().unwrap();
+        // Timestamps can't exceed the year 9999, which no test should reach
+        *guard = guard.checked_add(duration).expect("timestamp in range");
+        drop(guard);
+        self.1.notify_waiters();
+    }
+
+    /// Set the wall-clock time.
+    ///
+    /// Unlike monotonic time, the wall-clock time can jump backwards, for
+    /// example when the system clock is adjusted.
+    pub fn set(&self, now: Timestamp) {
+        *self.0.lock().unwrap() = now;
+        self.1.notify_waiters();
+    }
+
+    /// This clock as a [`Clock`] to put into configs.
+    pub fn shared(&self) -> Arc<dyn Clock> {
+        Arc::new(self.clone())
+    }
+}
+
+impl Default for MockClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> Timestamp {
+        *self.0.lock().unwrap()
+    }
+
+    fn jumped(&self) -> Pin<Box<dyn Future<Output = ()> + Send + '_>> {
+        Box::pin(self.1.notified())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use jiff::{Timestamp, ToSpan};
+
+    use super::{MockClock, SystemClock};
+
+    #[test]
+    fn mock_clock() {
+        let start = Timestamp::from_second(1_000_000_000).unwrap();
+        let clock = MockClock::at(start);
+        let shared = clock.shared();
+
+        clock.advance(Duration::from_secs(90));
+        assert_eq!(shared.now(), start + 90.seconds());
+
+        clock.set(start);
+        assert_eq!(shared.now(), start);
+    }
+
+    #[tokio::test]
+    async fn mock_clock_jumps() {
+        let clock = MockClock::new();
+        let shared = clock.shared();
+
+        let jumped = shared.jumped();
+        clock.advance(Duration::from_secs(1));
+        tokio::time::timeout(Duration::from_secs(1), jumped)
+            .await
+            .unwrap();
+
+        let jumped = shared.jumped();
+        clock.set(Timestamp::now());
+        tokio::time::timeout(Duration::from_secs(1), jumped)
+            .await
+            .unwrap();
+
+        let system = SystemClock::shared();
+        let never = tokio::time::timeout(Duration::from_millis(10), system.jumped()).await;
+        assert!(never.is_err());
+    }
+}
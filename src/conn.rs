@@ -1,28 +1,37 @@
 //! Connection state modeling.
 
-use std::collections::HashMap;
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
 use std::convert::Infallible;
-use std::future::Future;
-use std::time::{Duration, Instant};
+use std::future::{self, Future};
+use std::ops::Bound;
+use std::sync::atomic::{AtomicU64, AtomicU8, AtomicUsize, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
 use std::{error, fmt, result};
 
 use futures_util::SinkExt;
 use jiff::Timestamp;
-use log::debug;
+use log::{debug, warn};
+use serde_json::Value;
 use tokio::net::TcpStream;
 use tokio::select;
 use tokio::sync::{mpsc, oneshot};
+use tokio::time::Instant;
 use tokio_stream::StreamExt;
 use tokio_tungstenite::tungstenite::client::IntoClientRequest;
 use tokio_tungstenite::tungstenite::http::{header, HeaderValue};
+use tokio_tungstenite::tungstenite::protocol::CloseFrame;
 use tokio_tungstenite::{tungstenite, MaybeTlsStream, WebSocketStream};
 
-use crate::api::packet::{Command, ParsedPacket};
+use crate::api::packet::{Command, ErrorReason, Packet, PacketSeq, ParsedPacket, ThrottleReason};
 use crate::api::{
-    BounceEvent, Data, HelloEvent, LoginReply, NickEvent, PersonalAccountView, Ping, PingReply,
-    SessionId, SessionView, SnapshotEvent, Time, UserId,
+    AccessErrorReason, BounceEvent, Data, HelloEvent, Log, LogCompleteness, LoginReply, Message,
+    MessageId, Nick, NickEvent, PacketType, PersonalAccountView, Ping, PingReply,
+    RegisterAccountReply, SendErrorReason, SessionId, SessionType, SessionView, SnapshotEvent,
+    Time, UserId,
 };
-use crate::replies::{self, PendingReply, Replies};
+use crate::clock::{Clock, SystemClock};
+use crate::replies::{self, Completion, PendingReply, Replies};
 
 pub type WsStream = WebSocketStream<MaybeTlsStream<TcpStream>>;
 
@@ -30,27 +39,99 @@ pub type WsStream = WebSocketStream<MaybeTlsStream<TcpStream>>;
 pub enum Error {
     /// The connection is now closed.
     ConnectionClosed,
+    /// The server closed the connection with a close frame.
+    ///
+    /// The code and reason are the ones the server put in its close frame.
+    ConnectionClosedWithReason {
+        code: u16,
+        reason: String,
+    },
     /// The connection was not opened in time.
     ConnectionTimedOut,
+    /// The operation was cancelled before it could finish.
+    Cancelled,
     /// The server didn't reply to one of our commands in time.
     CommandTimedOut,
     /// The server did something that violated the api specification.
     ProtocolViolation(&'static str),
     /// An error returned by the euphoria server.
-    Euph(String),
+    ///
+    /// The message is classified once when the error is created, see
+    /// [`Self::euph`].
+    Euph {
+        /// The message sent by the server.
+        message: String,
+        /// The kind of error, parsed from the message.
+        reason: ErrorReason,
+    },
+    /// A [`Send`](crate::api::Send) was not sent because its parent is not a
+    /// message of the connection's room.
+    ///
+    /// See [`ParentCheck`] for more details.
+    ParentNotInRoom(MessageId),
+    /// A command was not sent because the session is not permitted to send it.
+    ///
+    /// See [`PermissionCheck`] for more details.
+    NotPermitted(PacketType),
 
     Tungstenite(tungstenite::Error),
     SerdeJson(serde_json::Error),
 }
 
+impl Error {
+    /// An error returned by the euphoria server with the given message.
+    pub fn euph<S: ToString>(message: S) -> Self {
+        let message = message.to_string();
+        let reason = ErrorReason::parse(&message);
+        Self::Euph { message, reason }
+    }
+
+    /// If the server rejected a [`Send`](crate::api::Send), the reason why.
+    pub fn send_error_reason(&self) -> Option<SendErrorReason> {
+        match self {
+            Self::Euph { message, reason } => Some(SendErrorReason::from_reason(reason, message)),
+            _ => None,
+        }
+    }
+
+    /// If the server rejected a command, the kind of error.
+    ///
+    /// Unlike [`Self::send_error_reason`] and [`Self::access_error_reason`],
+    /// this applies to all commands.
+    pub fn error_reason(&self) -> Option<&ErrorReason> {
+        match self {
+            Self::Euph { reason, .. } => Some(reason),
+            _ => None,
+        }
+    }
+
+    /// If the server rejected a [`GrantAccess`](crate::api::GrantAccess) or
+    /// [`RevokeAccess`](crate::api::RevokeAccess), the reason why.
+    pub fn access_error_reason(&self) -> Option<AccessErrorReason> {
+        match self {
+            Self::Euph { message, reason } => Some(AccessErrorReason::from_reason(reason, message)),
+            _ => None,
+        }
+    }
+}
+
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             Self::ConnectionClosed => write!(f, "connection closed"),
+            Self::ConnectionClosedWithReason { code, reason } if reason.is_empty() => {
+                write!(f, "connection closed by server with code {code}")
+            }
+            Self::ConnectionClosedWithReason { code, reason } => {
+                write!(f, "connection closed by server with code {code}: {reason}")
+            }
             Self::ConnectionTimedOut => write!(f, "connection did not open in time"),
+            Self::Cancelled => write!(f, "operation was cancelled"),
             Self::CommandTimedOut => write!(f, "server did not reply to command in time"),
             Self::ProtocolViolation(msg) => write!(f, "{msg}"),
-            Self::Euph(msg) => write!(f, "{msg}"),
+            Self::Euph { message, .. } => write!(f, "{message}"),
+            Self::ParentNotInRoom(id) => write!(f, "parent {} is not in this room", id.0),
+            Self::NotPermitted(r#type) => write!(f, "not permitted to send {type}"),
             Self::Tungstenite(err) => write!(f, "{err}"),
             Self::SerdeJson(err) => write!(f, "{err}"),
         }
@@ -73,7 +154,419 @@ impl error::Error for Error {}
 
 pub type Result<T> = result::Result<T, Error>;
 
+/// What a [`Conn`] should do when it receives a packet it can't deserialize.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum MalformedPolicy {
+    /// Return an [`Error::SerdeJson`] and close the connection.
+    #[default]
+    Disconnect,
+    /// Log a warning and skip the packet.
+    ///
+    /// If only the packet's data is malformed, the packet is not skipped.
+    /// Instead, its data is replaced by [`Data::Unimplemented`] so that
+    /// replies to commands can still be resolved.
+    Skip,
+}
+
+/// Whether a [`Conn`] checks that the parents of outgoing
+/// [`Send`](crate::api::Send)s are messages of its room.
+///
+/// Replying to a message of a different room is usually a bug, for example in
+/// bots that mix up message ids between connections. The server rejects such
+/// messages with a generic error.
+///
+/// The connection remembers the ids of the messages in its room's snapshot and
+/// of all messages sent in the room since, up to a limit. From the oldest id
+/// it remembers onward, it knows every message of the room. A parent is
+/// conclusively not in the room if it is newer than the oldest remembered id,
+/// but not remembered itself. Parents older than that may be outside the
+/// remembered window and are always allowed, as are all parents before the
+/// connection has joined its room.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ParentCheck {
+    /// Don't check parents.
+    #[default]
+    Off,
+    /// Log a warning, but send the message anyway.
+    Warn,
+    /// Don't send the message. [`ConnTx::send`] fails with
+    /// [`Error::ParentNotInRoom`].
+    Enforce,
+}
+
+/// Whether and how a [`Conn`] checks outgoing commands against the session's
+/// [`Permissions`].
+///
+/// Commands the session is not known to be permitted to send, e.g. a
+/// [`Ban`](crate::api::Ban) by a session that isn't a manager, would only be
+/// rejected by the server. Commands are only checked once the connection has
+/// joined its room, and commands that don't require any permissions are never
+/// checked. See [`Permissions::allows`] for details.
+///
+/// The session's permissions are only known as far as the server told us. For
+/// example, staff that unlocked their staff capabilities may send manager
+/// commands without being managers, so staff sessions are never checked.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PermissionCheck {
+    /// Don't check commands.
+    #[default]
+    Off,
+    /// Log a warning, but send the command anyway.
+    Warn,
+    /// Don't send the command. [`ConnTx::send`] fails with
+    /// [`Error::NotPermitted`].
+    Enforce,
+}
+
+/// How many message ids [`RoomHistory`] remembers at most.
+const ROOM_HISTORY_LEN: usize = 4096;
+
+/// Whether a message id belongs to a room, see [`RoomHistory::contains`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Membership {
+    Known,
+    Inconclusive,
+    NotInRoom,
+}
+
+/// The ids of the most recent messages of a room, see [`ParentCheck`].
+#[derive(Debug, Default)]
+struct RoomHistory {
+    /// Every message of the room from the first id onward, if
+    /// [`Self::complete`].
+    ids: BTreeSet<MessageId>,
+    /// Whether a snapshot was received, i.e. whether the record is complete.
+    complete: bool,
+}
+
+impl RoomHistory {
+    fn on_data(&mut self, data: &Data) {
+        match data {
+            Data::SnapshotEvent(p) => {
+                self.ids = p.log.iter().map(|m| m.id).collect();
+                self.complete = true;
+            }
+            Data::SendEvent(p) => self.insert(p.0.id),
+            Data::SendReply(p) => self.insert(p.0.id),
+            _ => {}
+        }
+    }
+
+    fn insert(&mut self, id: MessageId) {
+        if !self.complete {
+            return;
+        }
+        self.ids.insert(id);
+        while self.ids.len() > ROOM_HISTORY_LEN {
+            // The record stays complete from the new first id onward
+            self.ids.pop_first();
+        }
+    }
+
+    fn contains(&self, id: MessageId) -> Membership {
+        if self.ids.contains(&id) {
+            return Membership::Known;
+        }
+        match self.ids.first() {
+            Some(first) if self.complete && id > *first => Membership::NotInRoom,
+            _ => Membership::Inconclusive,
+        }
+    }
+}
+
+/// How a [`Conn`] complies with the slow mode of its room, see
+/// [`Conn::set_slow_mode`].
+///
+/// # Example
+///
+/// ```
+/// use std::time::Duration;
+///
+/// use euphoxide::conn::SlowMode;
+///
+/// let slow_mode = SlowMode::new().with_decay(Duration::from_secs(60));
+/// assert_eq!(slow_mode.decay, Duration::from_secs(60));
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SlowMode {
+    /// Interval between messages to use when the server doesn't say how long
+    /// to wait.
+    pub fallback_interval: Duration,
+    /// How long the interval is kept after the last throttled reply.
+    pub decay: Duration,
+}
+
+impl SlowMode {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_fallback_interval(mut self, fallback_interval: Duration) -> Self {
+        self.fallback_interval = fallback_interval;
+        self
+    }
+
+    pub fn with_decay(mut self, decay: Duration) -> Self {
+        self.decay = decay;
+        self
+    }
+}
+
+impl Default for SlowMode {
+    fn default() -> Self {
+        Self {
+            fallback_interval: Duration::from_secs(5),
+            decay: Duration::from_secs(5 * 60),
+        }
+    }
+}
+
+/// Spaces out [`Send`](crate::api::Send) commands while the room is in slow
+/// mode.
+#[derive(Debug)]
+struct SendLimiter {
+    config: SlowMode,
+    /// The adopted interval and when the server last throttled us.
+    interval: Option<(Duration, Instant)>,
+    last_send: Option<Instant>,
+}
+
+impl SendLimiter {
+    fn new(config: SlowMode) -> Self {
+        Self {
+            config,
+            interval: None,
+            last_send: None,
+        }
+    }
+
+    fn on_throttled(&mut self, reason: ThrottleReason, now: Instant) {
+        match (reason, &mut self.interval) {
+            (ThrottleReason::SlowMode(interval), _) => {
+                let interval = interval.unwrap_or(self.config.fallback_interval);
+                debug!("Room is in slow mode, sending one message every {interval:?}");
+                self.interval = Some((interval, now));
+            }
+            // Other throttling only prolongs an interval that is still active
+            (ThrottleReason::Flood, Some((_, since))) if now < *since + self.config.decay => {
+                *since = now;
+            }
+            (ThrottleReason::Flood, _) => {}
+        }
+    }
+
+    fn on_send(&mut self, now: Instant) {
+        self.last_send = Some(now);
+    }
+
+    /// The interval currently in effect, if any.
+    fn interval(&self, now: Instant) -> Option<Duration> {
+        let (interval, since) = self.interval?;
+        (now < since + self.config.decay).then_some(interval)
+    }
+
+    /// When the next message may be sent.
+    fn next_send(&self, now: Instant) -> Instant {
+        match (self.interval, self.last_send) {
+            (Some((interval, since)), Some(last_send)) => (last_send + interval)
+                .min(since + self.config.decay)
+                .max(now),
+            _ => now,
+        }
+    }
+}
+
+/// A budget for the commands a [`Conn`] sends, see [`Conn::set_send_rate`].
+///
+/// # Example
+///
+/// ```
+/// use std::time::Duration;
+///
+/// use euphoxide::conn::SendRate;
+///
+/// let rate = SendRate::new()
+///     .with_burst(3)
+///     .with_interval(Duration::from_secs(2));
+/// assert_eq!(rate.burst, 3);
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SendRate {
+    /// How many commands may be sent in quick succession.
+    pub burst: u32,
+    /// How long it takes to regain the budget for a single command.
+    pub interval: Duration,
+    /// How long to stop sending commands after the server throttled a reply.
+    pub backoff: Duration,
+}
+
+impl SendRate {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_burst(mut self, burst: u32) -> Self {
+        self.burst = burst;
+        self
+    }
+
+    pub fn with_interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    pub fn with_backoff(mut self, backoff: Duration) -> Self {
+        self.backoff = backoff;
+        self
+    }
+}
+
+impl Default for SendRate {
+    fn default() -> Self {
+        Self {
+            burst: 5,
+            interval: Duration::from_secs(1),
+            backoff: Duration::from_secs(5),
+        }
+    }
+}
+
+/// Keeps commands within a [`SendRate`].
+///
+/// This is the generic cell rate algorithm, which behaves like a token bucket
+/// holding [`SendRate::burst`] tokens.
+#[derive(Debug)]
+struct RateLimiter {
+    config: SendRate,
+    /// When the budget would be full again if no more commands were sent,
+    /// plus one interval.
+    full_at: Instant,
+}
+
+impl RateLimiter {
+    fn new(config: SendRate, now: Instant) -> Self {
+        Self {
+            config,
+            full_at: now,
+        }
+    }
+
+    /// How far `full_at` may be in the future for a command to be sent.
+    fn tolerance(&self) -> Duration {
+        self.config.interval * self.config.burst.saturating_sub(1)
+    }
+
+    fn on_send(&mut self, now: Instant) {
+        self.full_at = self.full_at.max(now) + self.config.interval;
+    }
+
+    /// Use up the budget and wait for [`SendRate::backoff`].
+    fn on_throttled(&mut self, now: Instant) {
+        let full_at = now + self.config.backoff + self.tolerance();
+        self.full_at = self.full_at.max(full_at);
+    }
+
+    /// When the next command may be sent.
+    fn next_send(&self, now: Instant) -> Instant {
+        match self.full_at.checked_sub(self.tolerance()) {
+            Some(next_send) => next_send.max(now),
+            None => now,
+        }
+    }
+}
+
+/// How many log commands [`LogRequests`] remembers.
+const LOG_REQUESTS_LEN: usize = 16;
+
+/// Remembers the log commands we sent so their replies can be judged by
+/// [`LogReply::completeness`](crate::api::LogReply::completeness).
+#[derive(Debug, Default)]
+struct LogRequests {
+    /// Ids and commands, oldest first.
+    sent: VecDeque<(String, Log)>,
+}
+
+impl LogRequests {
+    fn on_send(&mut self, id: &str, log: &Log) {
+        if self.sent.len() >= LOG_REQUESTS_LEN {
+            self.sent.pop_front();
+        }
+        self.sent.push_back((id.to_string(), log.clone()));
+    }
+
+    fn take(&mut self, id: &str) -> Option<Log> {
+        let i = self.sent.iter().position(|(sent, _)| sent == id)?;
+        self.sent.remove(i).map(|(_, log)| log)
+    }
+}
+
+/// How many nick commands [`NickOrder`] remembers.
+const NICK_ORDER_LEN: usize = 16;
+
+/// Keeps renames of our own session from being applied out of order.
+///
+/// The server may tell us about a rename via a nick-reply, a nick-event about
+/// our own session, or both, in either order. If we rename ourselves multiple
+/// times in quick succession, a late packet about an earlier rename could
+/// otherwise revert a later one.
+#[derive(Debug, Default)]
+struct NickOrder {
+    /// Ids and names of the nick commands we sent, oldest first.
+    sent: VecDeque<(usize, String)>,
+    /// Id of the newest nick command whose rename has been applied.
+    applied: Option<usize>,
+}
+
+impl NickOrder {
+    fn on_send(&mut self, id: usize, nick: &Nick) {
+        if self.sent.len() >= NICK_ORDER_LEN {
+            self.sent.pop_front();
+        }
+        self.sent.push_back((id, nick.name.clone()));
+    }
+
+    /// Whether a packet about our own nick is older than what we already know.
+    ///
+    /// Packets that are not stale are recorded as applied.
+    fn is_stale(&mut self, id: Option<&str>, data: &Data, own: &SessionView) -> bool {
+        let is_older = |id: usize| self.applied.is_some_and(|applied| applied > id);
+        match data {
+            Data::NickReply(p) if p.session_id == own.session_id => {
+                match id.and_then(|id| id.parse().ok()) {
+                    Some(id) if is_older(id) => true,
+                    Some(id) => {
+                        self.applied = Some(id);
+                        false
+                    }
+                    None => false,
+                }
+            }
+            Data::NickEvent(p) if p.session_id == own.session_id => {
+                let sent = self.sent.iter().rev().find(|(_, name)| *name == p.to);
+                match sent {
+                    Some(&(id, _)) if is_older(id) => true,
+                    Some(&(id, _)) => {
+                        self.applied = Some(id);
+                        false
+                    }
+                    // Not caused by one of our commands, or the server changed
+                    // the name we asked for. Only apply it if it continues from
+                    // our current nick.
+                    None => p.from != own.name,
+                }
+            }
+            _ => false,
+        }
+    }
+}
+
+/// The state of a connection that has not yet joined its room.
+///
+/// With the `serde` feature enabled, this type can be serialized, e.g. to
+/// export snapshots of the connection state. Timestamps are serialized as
+/// RFC 3339 strings.
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Joining {
     pub since: Timestamp,
     pub hello: Option<HelloEvent>,
@@ -82,15 +575,16 @@ pub struct Joining {
 }
 
 impl Joining {
-    fn new() -> Self {
+    fn new(now: Timestamp) -> Self {
         Self {
-            since: Timestamp::now(),
+            since: now,
             hello: None,
             snapshot: None,
             bounce: None,
         }
     }
 
+    #[allow(clippy::result_large_err)]
     fn on_data(&mut self, data: &Data) -> Result<()> {
         match data {
             Data::BounceEvent(p) => self.bounce = Some(p.clone()),
@@ -109,7 +603,7 @@ impl Joining {
         Ok(())
     }
 
-    fn joined(&self) -> Option<Joined> {
+    fn joined(&self, now: Timestamp) -> Option<Joined> {
         if let (Some(hello), Some(snapshot)) = (&self.hello, &self.snapshot) {
             let mut session = hello.session.clone();
             if let Some(nick) = &snapshot.nick {
@@ -121,11 +615,21 @@ impl Joining {
                 .cloned()
                 .map(|s| (s.session_id.clone(), SessionInfo::Full(s)))
                 .collect::<HashMap<_, _>>();
+            let pm_counterpart = match (&snapshot.pm_with_user_id, &snapshot.pm_with_nick) {
+                (Some(id), Some(nick)) => Some((UserId(id.clone()), nick.clone())),
+                _ => None,
+            };
             Some(Joined {
-                since: Timestamp::now(),
+                since: now,
                 session,
                 account: hello.account.clone(),
-                listing,
+                account_email_verified: hello.account_email_verified,
+                last_who: None,
+                room_is_private: hello.room_is_private,
+                pm_counterpart,
+                listing: Arc::new(listing),
+                message_times: None,
+                message_log: None,
             })
         } else {
             None
@@ -133,7 +637,12 @@ impl Joining {
     }
 }
 
+/// With the `serde` feature enabled, this type is serialized as the wrapped
+/// value with an additional `kind` field that is either `"full"` or
+/// `"partial"`.
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(tag = "kind", rename_all = "snake_case"))]
 pub enum SessionInfo {
     Full(SessionView),
     Partial(NickEvent),
@@ -162,57 +671,326 @@ impl SessionInfo {
     }
 }
 
+/// The state of a connection that has joined its room.
+///
+/// With the `serde` feature enabled, this type can be serialized, e.g. to
+/// export snapshots of the connection state. Timestamps are serialized as
+/// RFC 3339 strings and [`Self::listing`] is serialized as an array of
+/// [`SessionInfo`]s ordered by session id.
+///
+/// ```
+/// # #[cfg(feature = "serde")] {
+/// use euphoxide::conn::Joined;
+/// use serde_json::json;
+///
+/// let session = json!({
+///     "id": "agent:abcd",
+///     "name": "TestBot",
+///     "server_id": "heim.1",
+///     "server_era": "era",
+///     "session_id": "abcd",
+/// });
+/// let joined: Joined = serde_json::from_value(json!({
+///     "since": "2024-01-01T12:00:00Z",
+///     "session": session,
+///     "account": null,
+///     "account_email_verified": null,
+///     "last_who": null,
+///     "room_is_private": false,
+///     "listing": [],
+/// }))
+/// .unwrap();
+///
+/// let snapshot = serde_json::to_value(&joined).unwrap();
+/// assert_eq!(snapshot["since"], "2024-01-01T12:00:00Z");
+/// assert_eq!(snapshot["session"], session);
+/// assert_eq!(snapshot["listing"], json!([]));
+/// # }
+/// ```
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Joined {
     pub since: Timestamp,
     pub session: SessionView,
     pub account: Option<PersonalAccountView>,
-    pub listing: HashMap<SessionId, SessionInfo>,
+    /// Whether the email address of [`Self::account`] has been verified.
+    ///
+    /// `None` if the session is not logged in or the server didn't say.
+    pub account_email_verified: Option<bool>,
+    /// When the last who-reply was received, if any.
+    ///
+    /// The who-reply's listing is not used to update [`Self::listing`].
+    pub last_who: Option<Timestamp>,
+    /// Whether the room is private, i.e. requires authentication to join.
+    pub room_is_private: bool,
+    /// If the room is for private chat, the id and nick of the other user.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub pm_counterpart: Option<(UserId, String)>,
+    /// The other sessions in the room.
+    ///
+    /// The listing is kept behind its own [`Arc`] so that updating any other
+    /// part of the state doesn't copy it. Use [`Arc::make_mut`] to modify it.
+    #[cfg_attr(feature = "serde", serde(with = "listing_serde"))]
+    pub listing: Arc<HashMap<SessionId, SessionInfo>>,
+    /// When other sessions recently sent messages, if tracked.
+    ///
+    /// Like [`Self::listing`], the times are kept behind their own [`Arc`].
+    /// See [`Conn::set_message_times`] for more details.
+    #[cfg_attr(
+        feature = "serde",
+        serde(default, skip_serializing_if = "Option::is_none")
+    )]
+    pub message_times: Option<Arc<MessageTimes>>,
+    /// The room's most recent messages, if tracked.
+    ///
+    /// Like [`Self::listing`], the log is kept behind its own [`Arc`]. See
+    /// [`Conn::set_message_log`] for more details.
+    #[cfg_attr(
+        feature = "serde",
+        serde(default, skip_serializing_if = "Option::is_none")
+    )]
+    pub message_log: Option<Arc<MessageLog>>,
+}
+
+#[cfg(feature = "serde")]
+mod listing_serde {
+    use std::collections::HashMap;
+    use std::sync::Arc;
+
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    use crate::api::SessionId;
+
+    use super::SessionInfo;
+
+    pub(super) fn serialize<S: Serializer>(
+        listing: &HashMap<SessionId, SessionInfo>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        let mut sessions = listing.values().collect::<Vec<_>>();
+        sessions.sort_by(|a, b| a.session_id().cmp(b.session_id()));
+        serializer.collect_seq(sessions)
+    }
+
+    pub(super) fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Arc<HashMap<SessionId, SessionInfo>>, D::Error> {
+        let sessions = Vec::<SessionInfo>::deserialize(deserializer)?;
+        Ok(Arc::new(
+            sessions
+                .into_iter()
+                .map(|s| (s.session_id().clone(), s))
+                .collect(),
+        ))
+    }
 }
 
 impl Joined {
-    fn on_data(&mut self, data: &Data) {
+    /// The number of sessions in the room, including our own.
+    pub fn count_sessions(&self) -> usize {
+        self.listing.len() + 1
+    }
+
+    /// The number of sessions in the room that don't belong to bots, including
+    /// our own session if it is not a bot.
+    ///
+    /// Sessions whose [`SessionType`] is unknown are counted as humans.
+    pub fn count_humans(&self) -> usize {
+        let is_human = |id: &UserId| id.session_type() != Some(SessionType::Bot);
+        let own = usize::from(is_human(&self.session.id));
+        let others = self.listing.values().filter(|s| is_human(s.id())).count();
+        own + others
+    }
+
+    /// The number of sessions in the room that belong to bots, including our
+    /// own session if it is a bot.
+    pub fn count_bots(&self) -> usize {
+        self.count_sessions() - self.count_humans()
+    }
+
+    /// Whether a session of the user is in the room, including our own.
+    fn contains_user(&self, id: &UserId) -> bool {
+        self.session.id == *id
+            || self.listing.values().any(|s| match s {
+                SessionInfo::Full(s) => s.id == *id,
+                SessionInfo::Partial(p) => p.id == *id,
+            })
+    }
+
+    /// Check whether our view of the room is fresh enough to act on at `now`.
+    ///
+    /// This is intended for commands performing destructive actions like
+    /// banning a user. Returns the first requirement that is not met. The
+    /// current time should come from the connection's [`Clock`], see
+    /// [`Conn::clock`].
+    pub fn ensure_fresh(
+        &self,
+        requirements: FreshnessRequirements<'_>,
+        now: Timestamp,
+    ) -> result::Result<(), StaleStateError> {
+        let age =
+            |time: Timestamp| Duration::try_from(now.duration_since(time)).unwrap_or_default();
+
+        let connected_for = age(self.since);
+        if let Some(min) = requirements.min_connected {
+            if connected_for < min {
+                return Err(StaleStateError::ReconnectedRecently { connected_for, min });
+            }
+        }
+
+        let who_age = self.last_who.map(age);
+        if let Some(max) = requirements.require_recent_who {
+            if who_age.is_none_or(|age| age > max) {
+                return Err(StaleStateError::NoRecentWho { age: who_age, max });
+            }
+        }
+
+        if let Some(max) = requirements.max_state_age {
+            // Joining the room and receiving a who-reply both tell us the full
+            // listing, so whichever happened last counts.
+            let age = who_age.map_or(connected_for, |who_age| who_age.min(connected_for));
+            if age > max {
+                return Err(StaleStateError::StateTooOld { age, max });
+            }
+        }
+
+        if let Some(target) = requirements.target {
+            if !self.contains_user(target) {
+                return Err(StaleStateError::TargetNotPresent(target.clone()));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// How many messages per second a session sent within the `window` ending
+    /// at `now`.
+    ///
+    /// Returns `0.0` if message times aren't tracked, see
+    /// [`Conn::set_message_times`].
+    pub fn recent_message_rate(
+        &self,
+        session: &SessionId,
+        window: Duration,
+        now: Timestamp,
+    ) -> f32 {
+        match &self.message_times {
+            Some(times) => times.rate(session, window, now),
+            None => 0.0,
+        }
+    }
+
+    /// When a session last sent a message, if message times are tracked.
+    pub fn last_message_at(&self, session: &SessionId) -> Option<Timestamp> {
+        self.message_times.as_ref()?.last(session)
+    }
+
+    /// A message from the room's log, if the log is tracked and the message is
+    /// in it.
+    pub fn message(&self, id: &MessageId) -> Option<&Message> {
+        self.message_log.as_ref()?.get(id)
+    }
+
+    /// The direct replies to a message that are in the room's log, oldest
+    /// first.
+    ///
+    /// Returns nothing if the log isn't tracked.
+    pub fn children_of(&self, id: &MessageId) -> Vec<&Message> {
+        match &self.message_log {
+            Some(log) => log.children_of(*id).collect(),
+            None => vec![],
+        }
+    }
+
+    /// The `n` newest messages in the room's log, oldest first.
+    ///
+    /// Returns nothing if the log isn't tracked.
+    pub fn latest(&self, n: usize) -> Vec<&Message> {
+        match &self.message_log {
+            Some(log) => log.latest(n).collect(),
+            None => vec![],
+        }
+    }
+
+    /// What our session can currently do in the room.
+    pub fn permissions(&self) -> Permissions {
+        Permissions {
+            has_nick: !self.session.name.is_empty(),
+            logged_in: self.account.is_some(),
+            is_manager: self.session.is_manager,
+            is_staff: self.session.is_staff,
+        }
+    }
+
+    /// Whether the listing already contains exactly this session.
+    fn is_listed(&self, session: &SessionView) -> bool {
+        matches!(
+            self.listing.get(&session.session_id),
+            Some(SessionInfo::Full(s)) if s == session
+        )
+    }
+
+    fn on_data(&mut self, data: &Data, now: Timestamp) {
+        if let Some(log) = &mut self.message_log {
+            if MessageLog::is_affected_by(data) {
+                Arc::make_mut(log).on_data(data);
+            }
+        }
+
         match data {
             Data::JoinEvent(p) => {
                 debug!("Updating listing after join-event");
-                self.listing
+                Arc::make_mut(&mut self.listing)
                     .insert(p.0.session_id.clone(), SessionInfo::Full(p.0.clone()));
             }
             Data::SendEvent(p) => {
-                debug!("Updating listing after send-event");
-                self.listing.insert(
-                    p.0.sender.session_id.clone(),
-                    SessionInfo::Full(p.0.sender.clone()),
-                );
+                if !self.is_listed(&p.0.sender) {
+                    debug!("Updating listing after send-event");
+                    Arc::make_mut(&mut self.listing).insert(
+                        p.0.sender.session_id.clone(),
+                        SessionInfo::Full(p.0.sender.clone()),
+                    );
+                }
+                if let Some(times) = &mut self.message_times {
+                    Arc::make_mut(times).record(&p.0.sender.session_id, now);
+                }
             }
             Data::PartEvent(p) => {
                 debug!("Updating listing after part-event");
-                self.listing.remove(&p.0.session_id);
-            }
-            Data::NetworkEvent(p) => {
-                if p.r#type == "partition" {
-                    debug!("Updating listing after network-event with type partition");
-                    self.listing.retain(|_, s| match s {
-                        SessionInfo::Full(s) => {
-                            s.server_id != p.server_id && s.server_era != p.server_era
-                        }
-                        // We can't know if the session was disconnected by the
-                        // partition or not, so we're erring on the side of
-                        // caution and assuming they were kicked. If we're
-                        // wrong, we'll re-add the session as soon as it
-                        // performs another visible action.
-                        //
-                        // If we always kept such sessions, we might keep
-                        // disconnected ones indefinitely, thereby keeping them
-                        // from moving on, instead forever tethering them to the
-                        // digital realm.
-                        SessionInfo::Partial(_) => false,
-                    });
+                Arc::make_mut(&mut self.listing).remove(&p.0.session_id);
+                if let Some(times) = &mut self.message_times {
+                    Arc::make_mut(times).forget(&p.0.session_id);
+                }
+            }
+            Data::NetworkEvent(p) if p.r#type == "partition" => {
+                debug!("Updating listing after network-event with type partition");
+                Arc::make_mut(&mut self.listing).retain(|_, s| match s {
+                    SessionInfo::Full(s) => {
+                        s.server_id != p.server_id || s.server_era != p.server_era
+                    }
+                    // We can't know if the session was disconnected by the
+                    // partition or not, so we're erring on the side of caution
+                    // and assuming they were kicked. If we're wrong, we'll
+                    // re-add the session as soon as it performs another visible
+                    // action.
+                    //
+                    // If we always kept such sessions, we might keep
+                    // disconnected ones indefinitely, thereby keeping them from
+                    // moving on, instead forever tethering them to the digital
+                    // realm.
+                    SessionInfo::Partial(_) => false,
+                });
+                if let Some(times) = &mut self.message_times {
+                    Arc::make_mut(times).retain(&self.listing);
                 }
             }
+            Data::NickEvent(p) if p.session_id == self.session.session_id => {
+                debug!("Updating own session after nick-event");
+                self.session.name = p.to.clone();
+            }
             Data::NickEvent(p) => {
                 debug!("Updating listing after nick-event");
-                self.listing
+                Arc::make_mut(&mut self.listing)
                     .entry(p.session_id.clone())
                     .and_modify(|s| match s {
                         SessionInfo::Full(session) => session.name = p.to.clone(),
@@ -221,63 +999,791 @@ impl Joined {
                     .or_insert_with(|| SessionInfo::Partial(p.clone()));
             }
             Data::NickReply(p) => {
+                // A misrouted reply shouldn't be able to crash the connection.
+                if p.session_id != self.session.session_id || p.id != self.session.id {
+                    warn!("Ignoring nick-reply for session {}", p.session_id.0);
+                    return;
+                }
                 debug!("Updating own session after nick-reply");
-                assert_eq!(self.session.id, p.id);
                 self.session.name = p.to.clone();
             }
             // The who reply is broken and can't be trusted right now, so we'll
-            // not even look at it.
+            // only remember when it arrived.
+            Data::WhoReply(_) => {
+                debug!("Updating last who-reply time");
+                self.last_who = Some(now);
+            }
             _ => {}
         }
     }
 }
 
-#[derive(Debug, Clone)]
-#[allow(clippy::large_enum_variant)]
-pub enum State {
-    Joining(Joining),
-    Joined(Joined),
+/// What a session can do in a room, as returned by [`Joined::permissions`].
+///
+/// The methods only return `true` if the server is known to allow the action.
+/// For example, staff can perform manager actions only after unlocking their
+/// staff capabilities, which a session can't know about, so being staff does
+/// not grant any additional permissions here.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Permissions {
+    /// Whether the session has a non-empty nick.
+    pub has_nick: bool,
+    /// Whether the session is logged in to an account.
+    pub logged_in: bool,
+    /// Whether the session is a manager of the room.
+    pub is_manager: bool,
+    /// Whether the session belongs to a member of the euphoria staff.
+    pub is_staff: bool,
 }
 
-impl State {
-    pub fn into_joining(self) -> Option<Joining> {
-        match self {
-            Self::Joining(joining) => Some(joining),
-            Self::Joined(_) => None,
-        }
+impl Permissions {
+    /// Whether the session can send messages.
+    ///
+    /// The server rejects messages from sessions without a nick.
+    pub fn can_send(&self) -> bool {
+        self.has_nick
     }
 
-    pub fn into_joined(self) -> Option<Joined> {
-        match self {
-            Self::Joining(_) => None,
-            Self::Joined(joined) => Some(joined),
-        }
+    /// Whether the session can edit and delete messages.
+    pub fn can_edit_messages(&self) -> bool {
+        self.is_manager
     }
 
-    pub fn joining(&self) -> Option<&Joining> {
-        match self {
-            Self::Joining(joining) => Some(joining),
-            Self::Joined(_) => None,
-        }
+    /// Whether the session can ban users and IPs.
+    pub fn can_ban(&self) -> bool {
+        self.is_manager
     }
 
-    pub fn joined(&self) -> Option<&Joined> {
-        match self {
-            Self::Joining(_) => None,
-            Self::Joined(joined) => Some(joined),
+    /// Whether the session can grant and revoke access to a private room.
+    pub fn can_grant_access(&self) -> bool {
+        self.is_manager
+    }
+
+    /// Whether the session can invite other users to a private chat.
+    ///
+    /// Only sessions logged in to an account can initiate private chats.
+    pub fn can_initiate_pm(&self) -> bool {
+        self.logged_in
+    }
+
+    /// Whether the session can make other accounts managers of the room or
+    /// revoke their manager status.
+    pub fn can_grant_manager(&self) -> bool {
+        self.is_manager
+    }
+
+    /// Whether the session can send a command of this type.
+    ///
+    /// Commands that don't require any of the permissions above are always
+    /// allowed.
+    pub fn allows(&self, r#type: PacketType) -> bool {
+        match r#type {
+            PacketType::Send => self.can_send(),
+            PacketType::EditMessage => self.can_edit_messages(),
+            PacketType::Ban | PacketType::Unban => self.can_ban(),
+            PacketType::GrantAccess | PacketType::RevokeAccess => self.can_grant_access(),
+            PacketType::GrantManager | PacketType::RevokeManager => self.can_grant_manager(),
+            PacketType::PmInitiate => self.can_initiate_pm(),
+            _ => true,
         }
     }
 }
 
+/// Limits on the message times remembered per room, see
+/// [`Conn::set_message_times`].
+///
+/// # Example
+///
+/// ```
+/// use euphoxide::conn::MessageTimesConfig;
+///
+/// let config = MessageTimesConfig::new().with_per_session(50);
+/// assert_eq!(config.per_session, 50);
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct MessageTimesConfig {
+    /// How many of its most recent messages are remembered per session
+    /// (default: 20).
+    ///
+    /// Rates over windows containing more messages than this are
+    /// underestimated.
+    pub per_session: usize,
+    /// How many sessions are tracked at most (default: 1000).
+    ///
+    /// If more sessions send messages, the ones that were quiet for the
+    /// longest time are forgotten.
+    pub max_sessions: usize,
+}
+
+impl MessageTimesConfig {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_per_session(mut self, per_session: usize) -> Self {
+        self.per_session = per_session;
+        self
+    }
+
+    pub fn with_max_sessions(mut self, max_sessions: usize) -> Self {
+        self.max_sessions = max_sessions;
+        self
+    }
+}
+
+impl Default for MessageTimesConfig {
+    fn default() -> Self {
+        Self {
+            per_session: 20,
+            max_sessions: 1000,
+        }
+    }
+}
+
+/// When sessions recently sent messages, see [`Joined::message_times`].
+///
+/// The times are those at which the connection received the messages, not the
+/// times reported by the server.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct MessageTimes {
+    config: MessageTimesConfig,
+    /// Oldest first.
+    sessions: HashMap<SessionId, VecDeque<Timestamp>>,
+}
+
+impl MessageTimes {
+    pub fn new(config: MessageTimesConfig) -> Self {
+        Self {
+            config,
+            sessions: HashMap::new(),
+        }
+    }
+
+    pub fn config(&self) -> MessageTimesConfig {
+        self.config
+    }
+
+    /// How many sessions are currently tracked.
+    pub fn tracked_sessions(&self) -> usize {
+        self.sessions.len()
+    }
+
+    fn record(&mut self, session: &SessionId, now: Timestamp) {
+        if self.config.per_session == 0 || self.config.max_sessions == 0 {
+            return;
+        }
+
+        if !self.sessions.contains_key(session) && self.sessions.len() >= self.config.max_sessions {
+            let quietest = self
+                .sessions
+                .iter()
+                .min_by_key(|(_, times)| times.back().copied())
+                .map(|(id, _)| id.clone());
+            if let Some(quietest) = quietest {
+                self.sessions.remove(&quietest);
+            }
+        }
+
+        let times = self.sessions.entry(session.clone()).or_default();
+        times.push_back(now);
+        while times.len() > self.config.per_session {
+            times.pop_front();
+        }
+    }
+
+    fn forget(&mut self, session: &SessionId) {
+        self.sessions.remove(session);
+    }
+
+    fn retain(&mut self, listing: &HashMap<SessionId, SessionInfo>) {
+        self.sessions.retain(|id, _| listing.contains_key(id));
+    }
+
+    fn last(&self, session: &SessionId) -> Option<Timestamp> {
+        self.sessions.get(session)?.back().copied()
+    }
+
+    fn rate(&self, session: &SessionId, window: Duration, now: Timestamp) -> f32 {
+        let times = match self.sessions.get(session) {
+            Some(times) if !window.is_zero() => times,
+            _ => return 0.0,
+        };
+        let start = now.checked_sub(window).unwrap_or(Timestamp::MIN);
+        let count = times.iter().filter(|t| **t > start && **t <= now).count();
+        count as f32 / window.as_secs_f32()
+    }
+}
+
+/// The room's most recent messages, see [`Joined::message_log`].
+///
+/// Messages are ordered by id, which is also the order in which they were sent.
+/// Once the log is full, the oldest messages are dropped, even if an older
+/// message is added later on, e.g. via a [`LogReply`](crate::api::LogReply).
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct MessageLog {
+    capacity: usize,
+    messages: BTreeMap<MessageId, Arc<Message>>,
+}
+
+impl MessageLog {
+    /// Create a log remembering at most `capacity` messages.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            messages: BTreeMap::new(),
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn len(&self) -> usize {
+        self.messages.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.messages.is_empty()
+    }
+
+    pub fn get(&self, id: &MessageId) -> Option<&Message> {
+        self.messages.get(id).map(|msg| &**msg)
+    }
+
+    /// All messages, oldest first.
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = &Message> {
+        self.messages.values().map(|msg| &**msg)
+    }
+
+    /// The direct replies to a message, oldest first.
+    pub fn children_of(&self, id: MessageId) -> impl Iterator<Item = &Message> {
+        // Replies are always newer than their parent.
+        self.messages
+            .range((Bound::Excluded(id), Bound::Unbounded))
+            .map(|(_, msg)| &**msg)
+            .filter(move |msg| msg.parent == Some(id))
+    }
+
+    /// The `n` newest messages, oldest first.
+    pub fn latest(&self, n: usize) -> impl Iterator<Item = &Message> {
+        self.iter().skip(self.len().saturating_sub(n))
+    }
+
+    /// Add a message, replacing any message with the same id.
+    pub fn insert(&mut self, msg: Arc<Message>) {
+        self.messages.insert(msg.id, msg);
+        while self.messages.len() > self.capacity {
+            self.messages.pop_first();
+        }
+    }
+
+    fn is_affected_by(data: &Data) -> bool {
+        matches!(
+            data,
+            Data::SnapshotEvent(_)
+                | Data::SendEvent(_)
+                | Data::SendReply(_)
+                | Data::EditMessageEvent(_)
+                | Data::LogReply(_)
+                | Data::GetMessageReply(_)
+        )
+    }
+
+    fn on_data(&mut self, data: &Data) {
+        match data {
+            Data::SnapshotEvent(p) => {
+                for msg in &p.log {
+                    self.insert(Arc::new(msg.clone()));
+                }
+            }
+            Data::SendEvent(p) => self.insert(p.0.clone()),
+            Data::SendReply(p) => self.insert(Arc::new(p.0.clone())),
+            Data::EditMessageEvent(p) => self.insert(Arc::new(p.message.clone())),
+            Data::LogReply(p) => {
+                for msg in &p.log {
+                    self.insert(Arc::new(msg.clone()));
+                }
+            }
+            Data::GetMessageReply(p) => self.insert(Arc::new(p.0.clone())),
+            _ => {}
+        }
+    }
+}
+
+/// What [`Joined::ensure_fresh`] requires of the room state.
+///
+/// Requirements that are `None` are not checked.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FreshnessRequirements<'a> {
+    /// Maximum time since the full listing was last received, either when
+    /// joining the room or via a who-reply.
+    pub max_state_age: Option<Duration>,
+    /// Maximum time since the last who-reply.
+    pub require_recent_who: Option<Duration>,
+    /// Minimum time since (re-)joining the room.
+    pub min_connected: Option<Duration>,
+    /// A user that must currently be in the room.
+    pub target: Option<&'a UserId>,
+}
+
+/// The reason why [`Joined::ensure_fresh`] considers the room state stale.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StaleStateError {
+    StateTooOld {
+        age: Duration,
+        max: Duration,
+    },
+    /// `age` is `None` if no who-reply was received yet.
+    NoRecentWho {
+        age: Option<Duration>,
+        max: Duration,
+    },
+    ReconnectedRecently {
+        connected_for: Duration,
+        min: Duration,
+    },
+    TargetNotPresent(UserId),
+}
+
+impl fmt::Display for StaleStateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::StateTooOld { age, max } => write!(
+                f,
+                "room state is {}s old, but must be at most {}s old",
+                age.as_secs(),
+                max.as_secs()
+            ),
+            Self::NoRecentWho { age: None, .. } => write!(f, "no who-reply received yet"),
+            Self::NoRecentWho {
+                age: Some(age),
+                max,
+            } => write!(
+                f,
+                "last who-reply is {}s old, but must be at most {}s old",
+                age.as_secs(),
+                max.as_secs()
+            ),
+            Self::ReconnectedRecently { connected_for, min } => write!(
+                f,
+                "reconnected {}s ago, but must be connected for at least {}s",
+                connected_for.as_secs(),
+                min.as_secs()
+            ),
+            Self::TargetNotPresent(id) => write!(f, "{} is not in the room", id.0),
+        }
+    }
+}
+
+impl error::Error for StaleStateError {}
+
+/// With the `serde` feature enabled, this type is serialized as the wrapped
+/// value with an additional `state` field that is either `"joining"` or
+/// `"joined"`.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(tag = "state", rename_all = "snake_case"))]
+#[allow(clippy::large_enum_variant)]
+pub enum State {
+    Joining(Joining),
+    Joined(Joined),
+}
+
+impl State {
+    /// Whether [`Self::on_data`] might modify the state.
+    ///
+    /// This is used to avoid cloning the state when it is shared and the
+    /// packet wouldn't change it anyways.
+    fn is_affected_by(&self, data: &Data) -> bool {
+        match self {
+            // Joining only lasts until the snapshot arrives and needs to see
+            // most packets to detect unexpected ones.
+            Self::Joining(_) => true,
+            Self::Joined(joined) => match data {
+                Data::JoinEvent(_)
+                | Data::PartEvent(_)
+                | Data::NickEvent(_)
+                | Data::NickReply(_)
+                | Data::WhoReply(_) => true,
+                Data::NetworkEvent(p) => p.r#type == "partition",
+                Data::SendEvent(_) if joined.message_times.is_some() => true,
+                _ if joined.message_log.is_some() && MessageLog::is_affected_by(data) => true,
+                Data::SendEvent(p) => !joined.is_listed(&p.0.sender),
+                _ => false,
+            },
+        }
+    }
+
+    #[allow(clippy::result_large_err)]
+    fn on_data(&mut self, data: &Data, now: Timestamp) -> Result<()> {
+        match self {
+            Self::Joining(joining) => {
+                joining.on_data(data)?;
+                if let Some(joined) = joining.joined(now) {
+                    *self = Self::Joined(joined);
+                }
+            }
+            Self::Joined(joined) => joined.on_data(data, now),
+        }
+        Ok(())
+    }
+
+    /// Update a potentially shared state, cloning it only if necessary.
+    #[allow(clippy::result_large_err)]
+    fn update(state: &mut Arc<Self>, data: &Data, now: Timestamp) -> Result<()> {
+        if state.is_affected_by(data) {
+            Arc::make_mut(state).on_data(data, now)?;
+        }
+        Ok(())
+    }
+
+    pub fn into_joining(self) -> Option<Joining> {
+        match self {
+            Self::Joining(joining) => Some(joining),
+            Self::Joined(_) => None,
+        }
+    }
+
+    pub fn into_joined(self) -> Option<Joined> {
+        match self {
+            Self::Joining(_) => None,
+            Self::Joined(joined) => Some(joined),
+        }
+    }
+
+    pub fn joining(&self) -> Option<&Joining> {
+        match self {
+            Self::Joining(joining) => Some(joining),
+            Self::Joined(_) => None,
+        }
+    }
+
+    pub fn joined(&self) -> Option<&Joined> {
+        match self {
+            Self::Joining(_) => None,
+            Self::Joined(joined) => Some(joined),
+        }
+    }
+}
+
+/// Internal details of a [`Conn`], mostly useful for debugging.
+///
+/// See [`Conn::debug_info`] and [`ConnTx::debug_info`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct DebugInfo {
+    /// How many commands are still waiting for their replies.
+    pub pending_replies: usize,
+    /// See [`Conn::malformed_packets`].
+    pub malformed_packets: usize,
+    /// How many replies to commands were throttled by the server.
+    pub throttled_replies: usize,
+    /// If the most recent reply to a command was throttled, the reason why.
+    pub throttled: Option<String>,
+    /// The interval between messages currently adopted because the room is in
+    /// slow mode, see [`Conn::set_slow_mode`].
+    pub slow_mode_interval: Option<Duration>,
+    /// See [`ConnTx::pending_sends`].
+    pub pending_sends: usize,
+    /// Whether the connection will be closed during the next call to
+    /// [`Conn::recv`].
+    pub disconnect_pending: bool,
+    /// See [`Conn::generation`].
+    pub generation: u64,
+    /// How many packets were received from the server, including malformed
+    /// packets that were skipped.
+    pub received_packets: u64,
+    /// How many packets were returned by [`Conn::recv`].
+    pub parsed_packets: u64,
+    /// How many diagnostics were recorded so far, see [`Conn::drain_diagnostics`].
+    pub diagnostics: DiagnosticCounts,
+    /// See [`Conn::connect_timings`].
+    pub connect_timings: ConnectTimings,
+}
+
+/// How long the phases of establishing a connection took, see
+/// [`Conn::connect_timings`].
+///
+/// Phases that haven't completed yet are `None`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ConnectTimings {
+    /// From starting to connect until the websocket upgrade completed.
+    ///
+    /// This includes resolving the domain as well as the TCP, TLS and
+    /// websocket handshakes, which can't be measured separately. Always `None`
+    /// if the connection was created via [`Conn::wrap`].
+    pub handshake: Option<Duration>,
+    /// From the websocket upgrade until the hello-event arrived.
+    pub hello: Option<Duration>,
+    /// From the hello-event until the snapshot-event arrived.
+    ///
+    /// In private rooms, this includes authenticating.
+    pub snapshot: Option<Duration>,
+}
+
+impl ConnectTimings {
+    /// How long establishing the connection took in total, if all phases
+    /// were measured.
+    pub fn total(&self) -> Option<Duration> {
+        Some(self.handshake? + self.hello? + self.snapshot?)
+    }
+}
+
+impl fmt::Display for ConnectTimings {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let phases = [
+            ("handshake", self.handshake),
+            ("hello", self.hello),
+            ("snapshot", self.snapshot),
+        ];
+        for (i, (name, duration)) in phases.into_iter().enumerate() {
+            if i > 0 {
+                write!(f, ", ")?;
+            }
+            match duration {
+                Some(duration) => write!(f, "{name} {duration:?}")?,
+                None => write!(f, "{name} -")?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// What a connection is currently doing, see [`ConnStatus::phase`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum ConnPhase {
+    Disconnected = 0,
+    Connecting = 1,
+    Joining = 2,
+    Joined = 3,
+}
+
+impl ConnPhase {
+    fn from_u8(phase: u8) -> Self {
+        match phase {
+            1 => Self::Connecting,
+            2 => Self::Joining,
+            3 => Self::Joined,
+            _ => Self::Disconnected,
+        }
+    }
+}
+
+/// Connection quality signals that can be read from any thread without
+/// awaiting, e.g. once per frame by an immediate-mode UI.
+///
+/// Obtained via [`Conn::status`] or
+/// [`Instance::status`](crate::bot::instance::Instance::status). For
+/// event-driven consumers, reacting to packets or instance events is usually
+/// the better choice.
+///
+/// The values are updated as follows:
+/// - The [`Self::phase`] is set to [`ConnPhase::Joining`] or
+///   [`ConnPhase::Joined`] whenever a [`Conn`] using the status changes its
+///   [`State`], and to [`ConnPhase::Disconnected`] once [`Conn::recv`] fails.
+///   Instances additionally set it to [`ConnPhase::Connecting`] while
+///   connecting and to [`ConnPhase::Disconnected`] once a connection ended.
+/// - [`Self::last_packet`] and [`Self::received_packets`] are updated for every
+///   packet a [`Conn`] receives, including malformed ones.
+/// - [`Self::last_pong`] is updated whenever the server replies to one of the
+///   pings a [`Conn`] sends periodically, either via websocket or via
+///   ping-reply.
+/// - [`Self::connections`] is incremented whenever a [`Conn`] starts using the
+///   status, see [`Conn::set_status`].
+/// - [`Self::last_error`] is set whenever [`Conn::recv`] fails or an instance's
+///   connection ends.
+///
+/// Except for the last error, all values are stored in atomics, so updating
+/// them costs a few relaxed stores per packet.
+#[derive(Debug)]
+pub struct ConnStatus {
+    /// Instants are stored as nanoseconds since this instant, plus one. Zero
+    /// means never.
+    base: Instant,
+    phase: AtomicU8,
+    last_packet: AtomicU64,
+    last_pong: AtomicU64,
+    received_packets: AtomicU64,
+    connections: AtomicU64,
+    last_error: RwLock<Option<String>>,
+}
+
+impl ConnStatus {
+    pub fn new() -> Self {
+        Self {
+            base: Instant::now(),
+            phase: AtomicU8::new(ConnPhase::Disconnected as u8),
+            last_packet: AtomicU64::new(0),
+            last_pong: AtomicU64::new(0),
+            received_packets: AtomicU64::new(0),
+            connections: AtomicU64::new(0),
+            last_error: RwLock::new(None),
+        }
+    }
+
+    pub fn phase(&self) -> ConnPhase {
+        ConnPhase::from_u8(self.phase.load(Ordering::Relaxed))
+    }
+
+    /// When the most recent packet was received.
+    pub fn last_packet(&self) -> Option<Instant> {
+        self.load_instant(&self.last_packet)
+    }
+
+    /// When the server most recently replied to a ping.
+    pub fn last_pong(&self) -> Option<Instant> {
+        self.load_instant(&self.last_pong)
+    }
+
+    /// How long ago the server most recently replied to a ping.
+    pub fn last_pong_age(&self) -> Option<Duration> {
+        self.last_pong().map(|pong| pong.elapsed())
+    }
+
+    /// How many packets were received over all connections.
+    ///
+    /// Sampling this regularly yields the packet rate.
+    pub fn received_packets(&self) -> u64 {
+        self.received_packets.load(Ordering::Relaxed)
+    }
+
+    /// How many connections used this status so far.
+    pub fn connections(&self) -> u64 {
+        self.connections.load(Ordering::Relaxed)
+    }
+
+    /// Why the most recent connection ended, if it did.
+    pub fn last_error(&self) -> Option<String> {
+        self.last_error.read().unwrap().clone()
+    }
+
+    pub(crate) fn set_phase(&self, phase: ConnPhase) {
+        self.phase.store(phase as u8, Ordering::Relaxed);
+    }
+
+    pub(crate) fn set_last_error(&self, error: String) {
+        *self.last_error.write().unwrap() = Some(error);
+    }
+
+    fn on_packet(&self, now: Instant) {
+        self.store_instant(&self.last_packet, now);
+        self.received_packets.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn on_pong(&self, now: Instant) {
+        self.store_instant(&self.last_pong, now);
+    }
+
+    fn load_instant(&self, atomic: &AtomicU64) -> Option<Instant> {
+        match atomic.load(Ordering::Relaxed) {
+            0 => None,
+            nanos => Some(self.base + Duration::from_nanos(nanos - 1)),
+        }
+    }
+
+    fn store_instant(&self, atomic: &AtomicU64, instant: Instant) {
+        let nanos = instant.saturating_duration_since(self.base).as_nanos();
+        let nanos = u64::try_from(nanos).unwrap_or(u64::MAX - 1);
+        atomic.store(nanos + 1, Ordering::Relaxed);
+    }
+}
+
+impl Default for ConnStatus {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// How many [`Diagnostic`]s to keep until the oldest are dropped.
+const DIAGNOSTICS_LEN: usize = 100;
+
+/// How serious a [`Diagnostic`] is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Severity {
+    /// The server sent something the library doesn't understand in detail, but
+    /// nothing was lost.
+    Info,
+    /// The server rejected or throttled a command, or sent a packet the
+    /// library couldn't interpret.
+    Warning,
+    /// The server sent a packet that had to be dropped or partially replaced.
+    Error,
+}
+
+/// Something the server told the connection that may indicate the two don't
+/// fully understand each other.
+///
+/// See [`Conn::drain_diagnostics`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Diagnostic {
+    /// When the diagnostic was recorded, according to the connection's
+    /// [`Clock`].
+    pub time: Timestamp,
+    pub severity: Severity,
+    /// The id of the packet the diagnostic is about, if it had one.
+    pub packet_id: Option<String>,
+    /// The type of the packet the diagnostic is about, if it could be parsed.
+    pub packet_type: Option<PacketType>,
+    pub message: String,
+}
+
+/// How many [`Diagnostic`]s of each [`Severity`] a connection recorded.
+///
+/// Diagnostics are counted when they are recorded, so the counts include
+/// diagnostics that were already drained or dropped.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct DiagnosticCounts {
+    pub info: u64,
+    pub warning: u64,
+    pub error: u64,
+}
+
+#[derive(Debug, Default)]
+struct Diagnostics {
+    queue: VecDeque<Diagnostic>,
+    counts: DiagnosticCounts,
+}
+
+impl Diagnostics {
+    fn push(&mut self, diagnostic: Diagnostic) {
+        match diagnostic.severity {
+            Severity::Info => self.counts.info += 1,
+            Severity::Warning => self.counts.warning += 1,
+            Severity::Error => self.counts.error += 1,
+        }
+        if self.queue.len() >= DIAGNOSTICS_LEN {
+            self.queue.pop_front();
+        }
+        self.queue.push_back(diagnostic);
+    }
+}
+
+/// Receives the id of the sent command packet and its pending reply.
+type ReplyTx = oneshot::Sender<Result<(String, PendingReply<ParsedPacket>)>>;
+
 #[allow(clippy::large_enum_variant)]
 enum ConnCommand {
-    SendCmd(Data, oneshot::Sender<PendingReply<ParsedPacket>>),
+    SendCmd(Data, ReplyTx),
+    SendOnly(Data),
+    SendRaw(String, Value),
     GetState(oneshot::Sender<State>),
+    GetDebugInfo(oneshot::Sender<DebugInfo>),
+    DrainDiagnostics(oneshot::Sender<Vec<Diagnostic>>),
 }
 
 #[derive(Debug, Clone)]
 pub struct ConnTx {
     cmd_tx: mpsc::UnboundedSender<ConnCommand>,
+    pending_sends: Arc<AtomicUsize>,
+    room: Option<Arc<str>>,
 }
 
 impl ConnTx {
@@ -286,18 +1792,42 @@ impl ConnTx {
     /// This is split into a separate function so that [`Self::send`] can be
     /// fully synchronous (you can safely throw away the returned future) while
     /// still guaranteeing that the packet was sent.
-    async fn finish_send<C>(rx: oneshot::Receiver<PendingReply<ParsedPacket>>) -> Result<C::Reply>
+    async fn finish_send<C>(
+        rx: oneshot::Receiver<Result<(String, PendingReply<ParsedPacket>)>>,
+    ) -> Result<C::Reply>
     where
         C: Command,
         C::Reply: TryFrom<Data>,
     {
-        let pending_reply = rx
-            .await
+        Self::finish_send_with_id::<C>(rx).await.1
+    }
+
+    /// Like [`Self::finish_send`], but also returning the id of the command
+    /// packet if it was sent.
+    async fn finish_send_with_id<C>(
+        rx: oneshot::Receiver<Result<(String, PendingReply<ParsedPacket>)>>,
+    ) -> (Option<String>, Result<C::Reply>)
+    where
+        C: Command,
+        C::Reply: TryFrom<Data>,
+    {
+        let (id, pending_reply) = match rx.await {
+            Ok(Ok(sent)) => sent,
+            Ok(Err(err)) => return (None, Err(err)),
             // This should only happen if something goes wrong during encoding
             // of the packet or while sending it through the websocket. Assuming
             // the first doesn't happen, the connection is probably closed.
-            .map_err(|_| Error::ConnectionClosed)?;
+            Err(_) => return (None, Err(Error::ConnectionClosed)),
+        };
+        let result = Self::finish_reply::<C>(pending_reply).await;
+        (Some(id), result)
+    }
 
+    async fn finish_reply<C>(pending_reply: PendingReply<ParsedPacket>) -> Result<C::Reply>
+    where
+        C: Command,
+        C::Reply: TryFrom<Data>,
+    {
         let data = pending_reply
             .get()
             .await
@@ -306,7 +1836,7 @@ impl ConnTx {
                 replies::Error::Canceled => Error::ConnectionClosed,
             })?
             .content
-            .map_err(Error::Euph)?;
+            .map_err(Error::euph)?;
 
         data.try_into()
             .map_err(|_| Error::ProtocolViolation("incorrect command reply type"))
@@ -334,309 +1864,3673 @@ impl ConnTx {
     }
 
     /// Like [`Self::send`] but ignoring the server's reply.
-    pub fn send_only<C: Into<Data>>(&self, cmd: C) {
-        let (tx, _) = oneshot::channel();
-        let _ = self.cmd_tx.send(ConnCommand::SendCmd(cmd.into(), tx));
+    ///
+    /// This is cheaper than [`Self::send`] since the connection doesn't need
+    /// to keep track of the command's reply.
+    ///
+    /// Returns [`Error::ConnectionClosed`] if the connection is already closed,
+    /// just like the future returned by [`Self::send`] would.
+    #[allow(clippy::result_large_err)]
+    pub fn send_only<C: Into<Data>>(&self, cmd: C) -> Result<()> {
+        self.cmd_tx
+            .send(ConnCommand::SendOnly(cmd.into()))
+            .map_err(|_| Error::ConnectionClosed)
     }
 
-    pub async fn state(&self) -> Result<State> {
-        let (tx, rx) = oneshot::channel();
+    /// Send a packet of an arbitrary type with arbitrary data.
+    ///
+    /// This is an escape hatch for packet types not modeled by [`Data`] and for
+    /// deliberately malformed packets. The packet is neither checked nor
+    /// delayed by slow mode or the rate limit, and its reply is not tracked.
+    ///
+    /// Returns [`Error::ConnectionClosed`] if the connection is already closed.
+    #[allow(clippy::result_large_err)]
+    pub fn send_raw_value<S: ToString>(&self, r#type: S, data: Value) -> Result<()> {
         self.cmd_tx
-            .send(ConnCommand::GetState(tx))
-            .map_err(|_| Error::ConnectionClosed)?;
-        rx.await.map_err(|_| Error::ConnectionClosed)
+            .send(ConnCommand::SendRaw(r#type.to_string(), data))
+            .map_err(|_| Error::ConnectionClosed)
+    }
+
+    /// Like [`Self::send_only`], but logging failures instead of returning
+    /// them.
+    ///
+    /// Unlike [`Self::send_only`], the server's reply is tracked. A task is
+    /// spawned that waits for the reply and, if the command failed for any
+    /// reason, logs a warning with the packet type, packet id, room and error.
+    /// This function never blocks and must be called from within a tokio
+    /// runtime.
+    pub fn send_logged<C>(&self, cmd: C)
+    where
+        C: Command + Into<Data> + 'static,
+        C::Reply: TryFrom<Data>,
+    {
+        let data = cmd.into();
+        let packet_type = data.packet_type();
+        let (tx, rx) = oneshot::channel();
+        let _ = self.cmd_tx.send(ConnCommand::SendCmd(data, tx));
+        let room = self.room.clone();
+        tokio::spawn(async move {
+            let (id, result) = Self::finish_send_with_id::<C>(rx).await;
+            if let Err(err) = result {
+                log_send_failure(packet_type, id.as_deref(), room.as_deref(), &err);
+            }
+        });
+    }
+
+    /// The room the connection was opened to.
+    ///
+    /// Returns `None` for connections created via [`Conn::wrap`].
+    pub fn room(&self) -> Option<&str> {
+        self.room.as_deref()
+    }
+
+    pub async fn state(&self) -> Result<State> {
+        let (tx, rx) = oneshot::channel();
+        self.cmd_tx
+            .send(ConnCommand::GetState(tx))
+            .map_err(|_| Error::ConnectionClosed)?;
+        rx.await.map_err(|_| Error::ConnectionClosed)
+    }
+
+    /// Retrieve internal details of the connection.
+    ///
+    /// See [`Conn::debug_info`] for more details.
+    pub async fn debug_info(&self) -> Result<DebugInfo> {
+        let (tx, rx) = oneshot::channel();
+        self.cmd_tx
+            .send(ConnCommand::GetDebugInfo(tx))
+            .map_err(|_| Error::ConnectionClosed)?;
+        rx.await.map_err(|_| Error::ConnectionClosed)
+    }
+
+    /// Remove and return all diagnostics recorded so far.
+    ///
+    /// See [`Conn::drain_diagnostics`] for more details.
+    pub async fn drain_diagnostics(&self) -> Result<Vec<Diagnostic>> {
+        let (tx, rx) = oneshot::channel();
+        self.cmd_tx
+            .send(ConnCommand::DrainDiagnostics(tx))
+            .map_err(|_| Error::ConnectionClosed)?;
+        rx.await.map_err(|_| Error::ConnectionClosed)
+    }
+
+    /// How many commands the connection is currently holding back.
+    ///
+    /// Commands are held back while the room is in slow mode (see
+    /// [`Conn::set_slow_mode`]) or while they would exceed the connection's
+    /// [`SendRate`] (see [`Conn::set_send_rate`]). A steadily growing number
+    /// means commands are submitted faster than they may be sent.
+    pub fn pending_sends(&self) -> usize {
+        self.pending_sends.load(Ordering::Relaxed)
+    }
+
+    /// Whether the connection is closed.
+    ///
+    /// Once this returns `true`, all further commands fail with
+    /// [`Error::ConnectionClosed`].
+    pub fn is_closed(&self) -> bool {
+        self.cmd_tx.is_closed()
+    }
+
+    /// Wait until the connection is closed.
+    pub async fn closed(&self) {
+        self.cmd_tx.closed().await;
+    }
+}
+
+/// Log a command sent via [`ConnTx::send_logged`] that failed.
+///
+/// Besides the message, the record contains the packet type, packet id, room
+/// and error as the key-values `packet_type`, `id`, `room` and `error`.
+fn log_send_failure(packet_type: PacketType, id: Option<&str>, room: Option<&str>, err: &Error) {
+    warn!(
+        target: "euphoxide::conn::send",
+        packet_type:% = packet_type,
+        id = id,
+        room = room,
+        error:% = err;
+        "{}",
+        format_send_failure(packet_type, id, room, err)
+    );
+}
+
+fn format_send_failure(
+    packet_type: PacketType,
+    id: Option<&str>,
+    room: Option<&str>,
+    err: &Error,
+) -> String {
+    let id = id.unwrap_or("-");
+    let room = match room {
+        Some(room) => format!("&{room}"),
+        None => "unknown room".to_string(),
+    };
+    format!("Sending {packet_type} (id {id}) in {room} failed: {err}")
+}
+
+/// Makes ping payloads unique across all connections of this process.
+static PING_NONCE: AtomicU64 = AtomicU64::new(0);
+
+static GENERATION: AtomicU64 = AtomicU64::new(0);
+
+/// Create the payloads for the next websocket ping and euph ping.
+///
+/// Both payloads contain the current time and a nonce that is unique within
+/// this process, so replies to pings of previous connections can't be mistaken
+/// for replies to the current ones. The nonce is placed in the low 16 bits of
+/// the euph ping's time, which the server echoes verbatim.
+fn ping_payloads(now: Timestamp) -> (Vec<u8>, Time) {
+    let nonce = PING_NONCE.fetch_add(1, Ordering::Relaxed);
+
+    let mut ws_payload = now.as_millisecond().to_be_bytes().to_vec();
+    ws_payload.extend_from_slice(&nonce.to_be_bytes());
+
+    let euph_payload = Time((now.as_second() << 16) | (nonce & 0xffff) as i64);
+
+    (ws_payload, euph_payload)
+}
+
+#[derive(Debug)]
+pub struct Conn {
+    ws: WsStream,
+    last_id: usize,
+    replies: Replies<String, PacketType, ParsedPacket>,
+
+    conn_tx: ConnTx,
+    cmd_rx: mpsc::UnboundedReceiver<ConnCommand>,
+
+    // The websocket server may send a pong frame with arbitrary payload
+    // unprompted at any time (see RFC 6455 5.5.3). Because of this, we can't
+    // just remember the last pong payload.
+    last_ping: Instant,
+    last_ws_ping_payload: Option<Vec<u8>>,
+    last_ws_ping_replied_to: bool,
+    last_euph_ping_payload: Option<Time>,
+    last_euph_ping_replied_to: bool,
+
+    /// Whether the connection should be closed during the next call to
+    /// [`Self::recv`], after the packet causing the disconnect was returned.
+    disconnect_pending: bool,
+
+    // Frames are queued here and only removed once the websocket accepted
+    // them, so dropping a future half way through sending loses nothing.
+    outbox: VecDeque<tungstenite::Message>,
+    unflushed: bool,
+    /// A packet that was received and processed, but not yet returned because
+    /// the future returning it was dropped.
+    received: Option<ParsedPacket>,
+    /// The close frame sent by the server, if any.
+    close_frame: Option<CloseFrame<'static>>,
+
+    on_malformed: MalformedPolicy,
+    malformed_packets: usize,
+
+    throttled_replies: usize,
+    throttled: Option<String>,
+
+    diagnostics: Diagnostics,
+
+    // Shared with other threads, which may read it at any time.
+    status: Arc<ConnStatus>,
+
+    limiter: Option<SendLimiter>,
+    rate_limiter: Option<RateLimiter>,
+    delayed: VecDeque<(Data, Option<ReplyTx>)>,
+    /// The length of [`Self::delayed`], shared with the [`ConnTx`]s.
+    pending_sends: Arc<AtomicUsize>,
+    parent_check: ParentCheck,
+    permission_check: PermissionCheck,
+    history: RoomHistory,
+    message_times: Option<MessageTimesConfig>,
+    message_log: Option<usize>,
+
+    nick_order: NickOrder,
+    log_requests: LogRequests,
+
+    generation: u64,
+    received_packets: u64,
+    parsed_packets: u64,
+
+    /// When the websocket upgrade completed.
+    upgraded_at: Instant,
+    /// When the hello-event arrived, if it has.
+    hello_at: Option<Instant>,
+    connect_timings: ConnectTimings,
+
+    // Shared with snapshots of the state, so it is only cloned when the state
+    // changes while a snapshot is still around.
+    state: Arc<State>,
+
+    clock: Arc<dyn Clock>,
+}
+
+enum ConnEvent {
+    Ws(Option<tungstenite::Result<tungstenite::Message>>),
+    Cmd(Option<ConnCommand>),
+    Ping,
+    SendDelayed,
+}
+
+impl Conn {
+    pub fn tx(&self) -> &ConnTx {
+        &self.conn_tx
+    }
+
+    pub fn state(&self) -> &State {
+        &self.state
+    }
+
+    /// The timeout specified when connecting.
+    pub fn timeout(&self) -> Duration {
+        self.replies.timeout()
+    }
+
+    /// The clock the connection uses for timestamps like [`Joined::since`].
+    pub fn clock(&self) -> &Arc<dyn Clock> {
+        &self.clock
+    }
+
+    /// Set the clock the connection uses (default: [`SystemClock`]).
+    ///
+    /// If the connection hasn't joined its room yet, [`Joining::since`] is
+    /// reset to the new clock's current time.
+    pub fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+        if let State::Joining(joining) = Arc::make_mut(&mut self.state) {
+            joining.since = clock.now();
+        }
+        self.clock = clock;
+    }
+
+    /// What the connection does when it receives a packet it can't
+    /// deserialize.
+    pub fn on_malformed(&self) -> MalformedPolicy {
+        self.on_malformed
+    }
+
+    /// Set what the connection does when it receives a packet it can't
+    /// deserialize (default: [`MalformedPolicy::Disconnect`]).
+    pub fn set_on_malformed(&mut self, policy: MalformedPolicy) {
+        self.on_malformed = policy;
+    }
+
+    /// How the connection complies with the slow mode of its room, if at all.
+    pub fn slow_mode(&self) -> Option<SlowMode> {
+        self.limiter.as_ref().map(|l| l.config)
+    }
+
+    /// Set how the connection complies with the slow mode of its room
+    /// (default: `None`).
+    ///
+    /// When a reply is throttled because the room is in
+    /// [`ThrottleReason::SlowMode`], the connection adopts the interval
+    /// mentioned by the server and spaces out [`Send`](crate::api::Send)
+    /// commands accordingly, delaying them instead of sending them right away.
+    /// The interval is kept until [`SlowMode::decay`] passes without the server
+    /// throttling any replies.
+    ///
+    /// Other commands don't count towards the interval. However, commands are
+    /// always sent in the order they were submitted via [`ConnTx`], so a
+    /// command submitted after a delayed `Send` is delayed along with it.
+    /// Packets the connection sends on its own, like replies to pings, are
+    /// never delayed.
+    ///
+    /// With `None`, commands are always sent right away.
+    pub fn set_slow_mode(&mut self, slow_mode: Option<SlowMode>) {
+        self.limiter = slow_mode.map(SendLimiter::new);
+    }
+
+    /// The budget for commands sent via the connection, if any.
+    pub fn send_rate(&self) -> Option<SendRate> {
+        self.rate_limiter.as_ref().map(|l| l.config)
+    }
+
+    /// Set the budget for commands sent via the connection (default: `None`).
+    ///
+    /// Commands submitted via [`ConnTx`] are delayed instead of sent right away
+    /// once the budget is exhausted, and sent in order once it allows. When the
+    /// server throttles a reply, the budget is used up and no commands are
+    /// sent for [`SendRate::backoff`]. Packets the connection sends on its own,
+    /// like replies to pings, are never delayed.
+    ///
+    /// With `None`, commands are only delayed due to slow mode, see
+    /// [`Self::set_slow_mode`].
+    pub fn set_send_rate(&mut self, send_rate: Option<SendRate>) {
+        let now = Instant::now();
+        self.rate_limiter = send_rate.map(|r| RateLimiter::new(r, now));
+    }
+
+    /// Whether the connection checks the parents of outgoing messages.
+    pub fn parent_check(&self) -> ParentCheck {
+        self.parent_check
+    }
+
+    /// Set whether the connection checks the parents of outgoing messages
+    /// (default: [`ParentCheck::Off`]).
+    ///
+    /// See [`ParentCheck`] for more details.
+    pub fn set_parent_check(&mut self, parent_check: ParentCheck) {
+        self.parent_check = parent_check;
+    }
+
+    /// Whether the connection checks outgoing commands against the session's
+    /// permissions.
+    pub fn permission_check(&self) -> PermissionCheck {
+        self.permission_check
+    }
+
+    /// Set whether the connection checks outgoing commands against the
+    /// session's permissions (default: [`PermissionCheck::Off`]).
+    ///
+    /// See [`PermissionCheck`] for more details.
+    pub fn set_permission_check(&mut self, permission_check: PermissionCheck) {
+        self.permission_check = permission_check;
+    }
+
+    /// Limits on the message times tracked in [`Joined::message_times`], if
+    /// they are tracked at all.
+    pub fn message_times(&self) -> Option<MessageTimesConfig> {
+        self.message_times
+    }
+
+    /// Set whether and how [`Joined::message_times`] are tracked (default:
+    /// `None`).
+    ///
+    /// If set, the connection remembers when other sessions sent their most
+    /// recent messages, e.g. to check whether a user is currently flooding the
+    /// room via [`Joined::recent_message_rate`]. Sessions are forgotten when
+    /// they leave the room. Changing the config discards all times tracked so
+    /// far.
+    pub fn set_message_times(&mut self, config: Option<MessageTimesConfig>) {
+        self.message_times = config;
+        self.apply_message_times();
+    }
+
+    /// Ensure the tracking of message times in the state matches the config.
+    fn apply_message_times(&mut self) {
+        let current = self
+            .state
+            .joined()
+            .map(|j| j.message_times.as_ref().map(|t| t.config()));
+        if current.is_none_or(|c| c == self.message_times) {
+            return;
+        }
+        if let State::Joined(joined) = Arc::make_mut(&mut self.state) {
+            joined.message_times = self
+                .message_times
+                .map(|config| Arc::new(MessageTimes::new(config)));
+        }
+    }
+
+    /// The capacity of [`Joined::message_log`], if the log is tracked at all.
+    pub fn message_log(&self) -> Option<usize> {
+        self.message_log
+    }
+
+    /// Set whether and how many messages are tracked in
+    /// [`Joined::message_log`] (default: `None`).
+    ///
+    /// If set, the connection remembers the room's most recent messages, e.g.
+    /// to look up the parent of a message via [`Joined::message`]. The log
+    /// contains the messages from the [`SnapshotEvent`] and all messages the
+    /// connection receives afterwards, including those in replies to
+    /// [`Log`] and [`GetMessage`](crate::api::GetMessage) commands. Edited
+    /// messages are replaced. Changing the capacity discards all messages
+    /// tracked so far.
+    pub fn set_message_log(&mut self, capacity: Option<usize>) {
+        self.message_log = capacity;
+        self.apply_message_log(None);
+    }
+
+    /// Ensure the tracking of the message log in the state matches the config.
+    ///
+    /// A newly created log starts out with the messages in `data`, so the log
+    /// of the snapshot that made us join the room isn't lost.
+    fn apply_message_log(&mut self, data: Option<&Data>) {
+        let current = self
+            .state
+            .joined()
+            .map(|j| j.message_log.as_ref().map(|l| l.capacity()));
+        if current.is_none_or(|c| c == self.message_log) {
+            return;
+        }
+        if let State::Joined(joined) = Arc::make_mut(&mut self.state) {
+            joined.message_log = self.message_log.map(|capacity| {
+                let mut log = MessageLog::new(capacity);
+                if let Some(data) = data {
+                    log.on_data(data);
+                }
+                Arc::new(log)
+            });
+        }
+    }
+
+    /// How many malformed packets were skipped or partially replaced so far.
+    ///
+    /// See [`MalformedPolicy::Skip`] for more details.
+    pub fn malformed_packets(&self) -> usize {
+        self.malformed_packets
+    }
+
+    /// Connection quality signals that can be read from any thread.
+    ///
+    /// See [`ConnStatus`] for more details.
+    pub fn status(&self) -> &Arc<ConnStatus> {
+        &self.status
+    }
+
+    /// Update a different [`ConnStatus`] from now on.
+    ///
+    /// This allows sharing a single status between successive connections,
+    /// e.g. when reconnecting. The status is updated immediately to reflect
+    /// this connection.
+    pub fn set_status(&mut self, status: Arc<ConnStatus>) {
+        self.status = status;
+        self.attach_status();
+    }
+
+    fn attach_status(&self) {
+        self.status.connections.fetch_add(1, Ordering::Relaxed);
+        self.update_status_phase();
+    }
+
+    fn update_status_phase(&self) {
+        let phase = match *self.state {
+            State::Joining(_) => ConnPhase::Joining,
+            State::Joined(_) => ConnPhase::Joined,
+        };
+        self.status.set_phase(phase);
+    }
+
+    /// Identifies this connection among all connections created in this
+    /// process.
+    ///
+    /// Used in the [`ParsedPacket::seq`] of every packet received on this
+    /// connection.
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    /// Internal details of the connection, mostly useful for debugging.
+    ///
+    /// While the connection is being used in a different task,
+    /// [`ConnTx::debug_info`] can be used instead.
+    pub fn debug_info(&self) -> DebugInfo {
+        DebugInfo {
+            pending_replies: self.replies.count_pending(),
+            malformed_packets: self.malformed_packets,
+            throttled_replies: self.throttled_replies,
+            throttled: self.throttled.clone(),
+            slow_mode_interval: self
+                .limiter
+                .as_ref()
+                .and_then(|l| l.interval(Instant::now())),
+            pending_sends: self.delayed.len(),
+            disconnect_pending: self.disconnect_pending,
+            generation: self.generation,
+            received_packets: self.received_packets,
+            parsed_packets: self.parsed_packets,
+            diagnostics: self.diagnostics.counts,
+            connect_timings: self.connect_timings,
+        }
+    }
+
+    /// How long the phases of establishing this connection took so far.
+    ///
+    /// The connection measures the hello and snapshot phases itself as the
+    /// packets arrive, so they are only filled in once [`Self::recv`] has
+    /// received them.
+    pub fn connect_timings(&self) -> ConnectTimings {
+        self.connect_timings
+    }
+
+    fn stamp_connect_timings(&mut self, data: &Data) {
+        let now = Instant::now();
+        match data {
+            Data::HelloEvent(_) if self.hello_at.is_none() => {
+                self.hello_at = Some(now);
+                self.connect_timings.hello = Some(now - self.upgraded_at);
+            }
+            Data::SnapshotEvent(_) if self.connect_timings.snapshot.is_none() => {
+                let since = self.hello_at.unwrap_or(self.upgraded_at);
+                self.connect_timings.snapshot = Some(now - since);
+            }
+            _ => {}
+        }
+    }
+
+    /// Remove and return all diagnostics recorded so far, oldest first.
+    ///
+    /// Diagnostics are recorded when
+    /// - a reply to a command is throttled,
+    /// - a reply to a command is an error,
+    /// - a packet has a type whose data isn't modeled by this library,
+    /// - a reply to a log command is possibly truncated, see
+    ///   [`LogReply::completeness`](crate::api::LogReply::completeness),
+    /// - a who-reply is implausibly small, see
+    ///   [`WhoReply::is_implausibly_small`](crate::api::WhoReply::is_implausibly_small),
+    /// - a packet has an unknown type or is otherwise malformed, and is skipped
+    ///   according to [`MalformedPolicy::Skip`].
+    ///
+    /// Only the most recent diagnostics are kept. While the connection is being
+    /// used in a different task, [`ConnTx::drain_diagnostics`] can be used
+    /// instead.
+    pub fn drain_diagnostics(&mut self) -> Vec<Diagnostic> {
+        self.diagnostics.queue.drain(..).collect()
+    }
+
+    fn diagnose(
+        &mut self,
+        severity: Severity,
+        packet_id: Option<String>,
+        packet_type: Option<PacketType>,
+        message: String,
+    ) {
+        self.diagnostics.push(Diagnostic {
+            time: self.clock.now(),
+            severity,
+            packet_id,
+            packet_type,
+            message,
+        });
+    }
+
+    /// A cheap snapshot of the connection's current state.
+    ///
+    /// The state is only cloned once it changes while the snapshot still
+    /// exists.
+    pub fn shared_state(&self) -> Arc<State> {
+        self.state.clone()
+    }
+
+    /// Receive the next packet from the server.
+    ///
+    /// Packets that make the connection close (like a
+    /// [`DisconnectEvent`](crate::api::DisconnectEvent)) are still returned.
+    /// The connection is then closed during the next call to this function.
+    ///
+    /// # Cancel safety
+    ///
+    /// This function is cancel-safe, so it can be used in `select!`. If the
+    /// returned future is dropped before completing, no packet is lost. A
+    /// packet that was already received is returned by the next call instead.
+    /// Frames that weren't sent yet, like replies to ping events or commands
+    /// sent via [`ConnTx`], are sent during the next call. Pings are sent at
+    /// most once per interval, no matter when the future is dropped.
+    pub async fn recv(&mut self) -> Result<ParsedPacket> {
+        let result = self.recv_packet().await;
+        if let Err(err) = &result {
+            self.status.set_phase(ConnPhase::Disconnected);
+            self.status.set_last_error(err.to_string());
+        }
+        result
+    }
+
+    async fn recv_packet(&mut self) -> Result<ParsedPacket> {
+        // Everything that happens between awaits only queues frames, and the
+        // awaits themselves are cancel-safe. This keeps the whole function
+        // cancel-safe.
+        loop {
+            self.flush_outbox().await?;
+
+            // Only returned once the frames it caused were sent
+            if let Some(packet) = self.received.take() {
+                self.parsed_packets += 1;
+                break Ok(packet);
+            }
+
+            if self.disconnect_pending {
+                self.disconnect().await?;
+            }
+
+            self.replies.purge();
+            let timeout = self.replies.timeout();
+            let next_send = self.next_delayed_send(Instant::now());
+
+            // All of these functions are cancel-safe.
+            let event = select! {
+                msg = self.ws.next() => ConnEvent::Ws(msg),
+                cmd = self.cmd_rx.recv() => ConnEvent::Cmd(cmd),
+                _ = Self::await_next_ping(self.last_ping, timeout) => ConnEvent::Ping,
+                _ = Self::await_next_send(next_send) => ConnEvent::SendDelayed,
+            };
+
+            match event {
+                ConnEvent::Ws(msg) => self.received = self.on_ws(msg)?,
+                ConnEvent::Cmd(Some(cmd)) => self.on_cmd(cmd)?,
+                ConnEvent::Cmd(None) => unreachable!("self contains a ConnTx"),
+                ConnEvent::Ping => self.on_ping()?,
+                ConnEvent::SendDelayed => self.send_delayed(false)?,
+            }
+        }
+    }
+
+    /// Gracefully close the connection.
+    ///
+    /// For the duration of `grace`, commands sent via [`ConnTx`] are still sent
+    /// to the server and incoming packets are still read so that pending
+    /// replies can resolve. These packets are handled like in [`Self::recv`],
+    /// but not returned. No new pings are sent while draining.
+    ///
+    /// Afterwards, any commands still queued are sent, followed by the given
+    /// close frame. The connection is closed once the server acknowledges the
+    /// close frame or the timeout specified when connecting has elapsed.
+    pub async fn drain(self, grace: Duration, close: CloseFrame<'static>) -> Result<()> {
+        self.shut_down(grace, false, close).await
+    }
+
+    /// Close the connection once all commands sent so far are done.
+    ///
+    /// This works like [`Self::drain`], except that the connection is closed as
+    /// soon as no commands are queued or held back and no replies are pending
+    /// anymore. If that takes longer than `timeout`, the connection is closed
+    /// anyways.
+    ///
+    /// Commands sent via [`ConnTx`] before this function is called are still
+    /// sent. Commands sent afterwards fail with [`Error::ConnectionClosed`] and
+    /// [`ConnTx::is_closed`] returns `true` immediately.
+    pub async fn close(mut self, timeout: Duration, close: CloseFrame<'static>) -> Result<()> {
+        // Commands already in the channel can still be received
+        self.cmd_rx.close();
+        self.shut_down(timeout, true, close).await
+    }
+
+    /// Whether there are no more commands or replies to wait for.
+    fn is_idle(&self) -> bool {
+        self.cmd_rx.is_empty() && self.delayed.is_empty() && self.replies.count_pending() == 0
+    }
+
+    async fn shut_down(
+        mut self,
+        grace: Duration,
+        until_idle: bool,
+        close: CloseFrame<'static>,
+    ) -> Result<()> {
+        let deadline = Instant::now() + grace;
+        while !self.disconnect_pending {
+            self.flush_outbox().await?;
+            if until_idle && self.is_idle() {
+                break;
+            }
+            let next_send = self.next_delayed_send(Instant::now());
+            // All of these functions are cancel-safe.
+            select! {
+                msg = self.ws.next() => {
+                    self.on_ws(msg)?;
+                }
+                Some(cmd) = self.cmd_rx.recv() => self.on_cmd(cmd)?,
+                _ = Self::await_next_send(next_send) => self.send_delayed(false)?,
+                _ = tokio::time::sleep_until(deadline) => break,
+            }
+        }
+
+        while let Ok(cmd) = self.cmd_rx.try_recv() {
+            self.on_cmd(cmd)?;
+        }
+        self.send_delayed(true)?;
+        self.flush_outbox().await?;
+
+        let timeout = self.replies.timeout();
+        let close = async {
+            self.ws.close(Some(close)).await?;
+            // Wait for the server to complete the closing handshake
+            while let Some(Ok(_)) = self.ws.next().await {}
+            Ok::<_, Error>(())
+        };
+        let _ = tokio::time::timeout(timeout, close).await;
+        debug!("Drained and closed connection");
+        Ok(())
+    }
+
+    #[allow(clippy::result_large_err)]
+    fn on_ws(
+        &mut self,
+        msg: Option<tungstenite::Result<tungstenite::Message>>,
+    ) -> Result<Option<ParsedPacket>> {
+        let msg = match msg {
+            Some(Ok(msg)) => msg,
+            Some(Err(tungstenite::Error::ConnectionClosed | tungstenite::Error::AlreadyClosed))
+            | None
+                if self.close_frame.is_some() =>
+            {
+                let frame = self.close_frame.take().unwrap();
+                return Err(Error::ConnectionClosedWithReason {
+                    code: frame.code.into(),
+                    reason: frame.reason.into_owned(),
+                });
+            }
+            Some(Err(err)) => return Err(err.into()),
+            None => return Err(Error::ConnectionClosed),
+        };
+        match msg {
+            tungstenite::Message::Text(text) => {
+                let seq = PacketSeq {
+                    generation: self.generation,
+                    seq: self.received_packets,
+                };
+                self.received_packets += 1;
+                self.status.on_packet(Instant::now());
+                let mut packet = match self.parse(&text)? {
+                    Some(packet) => packet,
+                    None => return Ok(None),
+                };
+                packet.seq = Some(seq);
+                self.on_packet(&packet)?;
+                return Ok(Some(packet));
+            }
+            tungstenite::Message::Binary(_) => {
+                return Err(Error::ProtocolViolation("unexpected binary ws message"));
+            }
+            tungstenite::Message::Ping(_) => {}
+            tungstenite::Message::Pong(payload) => {
+                if self.last_ws_ping_payload == Some(payload) {
+                    self.last_ws_ping_replied_to = true;
+                    self.status.on_pong(Instant::now());
+                }
+            }
+            tungstenite::Message::Close(frame) => {
+                // The connection ends once tungstenite has replied to the frame
+                self.close_frame = frame.map(|f| f.into_owned());
+            }
+            tungstenite::Message::Frame(_) => {}
+        }
+        Ok(None)
+    }
+
+    #[allow(clippy::result_large_err)]
+    fn parse(&mut self, text: &str) -> Result<Option<ParsedPacket>> {
+        let packet = match serde_json::from_str::<Packet>(text) {
+            Ok(packet) => packet,
+            Err(err) if self.on_malformed == MalformedPolicy::Skip => {
+                warn!("Skipping malformed packet ({err}): {text}");
+                self.malformed_packets += 1;
+                self.diagnose_malformed(text, err);
+                return Ok(None);
+            }
+            Err(err) => return Err(err.into()),
+        };
+        debug!(target: "euphoxide::conn::full", "Received {packet:?}");
+
+        match ParsedPacket::from_packet_lenient(packet) {
+            (packet, None) => Ok(Some(packet)),
+            (packet, Some(err)) if self.on_malformed == MalformedPolicy::Skip => {
+                warn!(
+                    "Ignoring malformed {:?} packet data ({err}): {text}",
+                    packet.r#type
+                );
+                self.malformed_packets += 1;
+                let message = format!("Ignored malformed packet data: {err}");
+                self.diagnose(
+                    Severity::Error,
+                    packet.id.clone(),
+                    Some(packet.r#type),
+                    message,
+                );
+                Ok(Some(packet))
+            }
+            (_, Some(err)) => Err(err.into()),
+        }
+    }
+
+    fn diagnose_malformed(&mut self, text: &str, err: serde_json::Error) {
+        let value = serde_json::from_str::<Value>(text).ok();
+        let packet_id = value
+            .as_ref()
+            .and_then(|v| v.get("id")?.as_str())
+            .map(|id| id.to_string());
+        let unknown_type = value.as_ref().and_then(|v| {
+            let r#type = v.get("type")?;
+            match serde_json::from_value::<PacketType>(r#type.clone()) {
+                Ok(_) => None,
+                Err(_) => Some(r#type.to_string()),
+            }
+        });
+        match unknown_type {
+            Some(r#type) => {
+                let message = format!("Skipped packet of unknown type {type}");
+                self.diagnose(Severity::Warning, packet_id, None, message);
+            }
+            None => {
+                let message = format!("Skipped malformed packet: {err}");
+                self.diagnose(Severity::Error, packet_id, None, message);
+            }
+        }
+    }
+
+    #[allow(clippy::result_large_err)]
+    fn on_packet(&mut self, packet: &ParsedPacket) -> Result<()> {
+        // Complete pending replies if the packet has an id
+        if let Some(id) = &packet.id {
+            debug!("Resolving pending reply for id {id}");
+            let completion = self.replies.complete(id, &packet.r#type, packet.clone());
+            if let Completion::Mismatch { expected } = completion {
+                // Handing this packet to the waiter would be worse than letting
+                // it time out, e.g. if this is a stale duplicate reply.
+                warn!(
+                    "Dropping {} with id {id}, expected {expected} instead",
+                    packet.r#type
+                );
+            }
+
+            if completion == Completion::Completed {
+                if let Err(error) = &packet.content {
+                    let message = format!("Command failed: {error}");
+                    self.diagnose(
+                        Severity::Warning,
+                        Some(id.clone()),
+                        Some(packet.r#type),
+                        message,
+                    );
+                }
+            }
+
+            if let Some(reason) = &packet.throttled {
+                self.throttled_replies += 1;
+                let message = format!("Throttled: {reason}");
+                self.diagnose(
+                    Severity::Warning,
+                    Some(id.clone()),
+                    Some(packet.r#type),
+                    message,
+                );
+            }
+            self.throttled = packet.throttled.clone();
+
+            if let (Some(limiter), Some(reason)) = (&mut self.limiter, packet.throttle_reason()) {
+                limiter.on_throttled(reason, Instant::now());
+            }
+            if let (Some(limiter), Some(_)) = (&mut self.rate_limiter, &packet.throttled) {
+                limiter.on_throttled(Instant::now());
+            }
+        }
+
+        if let Ok(data) = &packet.content {
+            self.diagnose_incomplete(&packet.id, data);
+            if matches!(data, Data::Unimplemented) && !Data::MODELED_TYPES.contains(&packet.r#type)
+            {
+                let message = format!("No data model for {} packets", packet.r#type);
+                self.diagnose(
+                    Severity::Info,
+                    packet.id.clone(),
+                    Some(packet.r#type),
+                    message,
+                );
+            }
+            self.on_data(&packet.id, data)?;
+        }
+
+        Ok(())
+    }
+
+    /// Flag replies that look like the server truncated them.
+    ///
+    /// The replies are still used as they are, but whoever inspects the
+    /// diagnostics learns that data may be missing.
+    fn diagnose_incomplete(&mut self, id: &Option<String>, data: &Data) {
+        match data {
+            Data::LogReply(reply) => {
+                let request = match id.as_deref().and_then(|id| self.log_requests.take(id)) {
+                    Some(request) => request,
+                    None => return,
+                };
+                let (severity, message) = match reply.completeness(&request) {
+                    LogCompleteness::Complete | LogCompleteness::StartOfLog => return,
+                    LogCompleteness::Short => (
+                        Severity::Info,
+                        format!(
+                            "Log reply contains {} of {} requested messages, \
+                             it reached the start of the log or was truncated",
+                            reply.log.len(),
+                            request.n
+                        ),
+                    ),
+                    LogCompleteness::Mismatched => (
+                        Severity::Warning,
+                        "Log reply doesn't match its command".to_string(),
+                    ),
+                };
+                self.diagnose(severity, id.clone(), Some(PacketType::LogReply), message);
+            }
+            Data::WhoReply(reply) => {
+                let previous = match self.state.joined() {
+                    Some(joined) => joined.count_sessions(),
+                    None => return,
+                };
+                if reply.is_implausibly_small(previous) {
+                    let message = format!(
+                        "Who reply lists {} sessions, but {previous} were known, \
+                         it was probably truncated",
+                        reply.listing.len()
+                    );
+                    self.diagnose(
+                        Severity::Warning,
+                        id.clone(),
+                        Some(PacketType::WhoReply),
+                        message,
+                    );
+                }
+            }
+            _ => {}
+        }
+    }
+
+    #[allow(clippy::result_large_err)]
+    fn on_data(&mut self, id: &Option<String>, data: &Data) -> Result<()> {
+        // Play a game of table tennis
+        match data {
+            Data::PingReply(p)
+                if self.last_euph_ping_payload.is_some()
+                    && self.last_euph_ping_payload == p.time =>
+            {
+                self.last_euph_ping_replied_to = true;
+                self.status.on_pong(Instant::now());
+            }
+            Data::PingEvent(p) => {
+                let reply = PingReply { time: Some(p.time) };
+                self.send_packet(id.clone(), reply.into())?;
+            }
+            _ => {}
+        }
+
+        // Update internal state
+        let stale = match self.state.joined() {
+            Some(joined) => self
+                .nick_order
+                .is_stale(id.as_deref(), data, &joined.session),
+            None => false,
+        };
+        if stale {
+            debug!("Ignoring outdated {} about own nick", data.packet_type());
+        } else {
+            State::update(&mut self.state, data, self.clock.now())?;
+            self.update_status_phase();
+        }
+        self.apply_message_times();
+        self.apply_message_log(Some(data));
+        self.stamp_connect_timings(data);
+        self.history.on_data(data);
+
+        // The euphoria server doesn't always disconnect the client when it
+        // would make sense to do so or when the API specifies it should. This
+        // ensures we always disconnect when it makes sense to do so.
+        //
+        // The disconnect is delayed until the next call to recv so users still
+        // get to see the packet that caused it.
+        if matches!(
+            data,
+            Data::DisconnectEvent(_)
+                | Data::LoginEvent(_)
+                | Data::LogoutEvent(_)
+                | Data::LoginReply(LoginReply { success: true, .. })
+                | Data::LogoutReply(_)
+                | Data::RegisterAccountReply(RegisterAccountReply { success: true, .. })
+        ) {
+            self.disconnect_pending = true;
+        }
+
+        Ok(())
+    }
+
+    #[allow(clippy::result_large_err)]
+    fn on_cmd(&mut self, cmd: ConnCommand) -> Result<()> {
+        match cmd {
+            ConnCommand::SendCmd(data, reply_tx) => match self.check_cmd(&data) {
+                Ok(()) => {
+                    self.delayed.push_back((data, Some(reply_tx)));
+                    self.send_delayed(false)?;
+                }
+                Err(err) => {
+                    let _ = reply_tx.send(Err(err));
+                }
+            },
+            ConnCommand::SendOnly(data) => {
+                if self.check_cmd(&data).is_ok() {
+                    self.delayed.push_back((data, None));
+                    self.send_delayed(false)?;
+                }
+            }
+            ConnCommand::SendRaw(r#type, data) => self.send_raw(r#type, data)?,
+            ConnCommand::GetState(reply_tx) => {
+                let _ = reply_tx.send((*self.state).clone());
+            }
+            ConnCommand::GetDebugInfo(reply_tx) => {
+                let _ = reply_tx.send(self.debug_info());
+            }
+            ConnCommand::DrainDiagnostics(reply_tx) => {
+                let _ = reply_tx.send(self.drain_diagnostics());
+            }
+        }
+        Ok(())
+    }
+
+    async fn await_next_ping(last_ping: Instant, timeout: Duration) {
+        let next_ping = last_ping + timeout;
+        tokio::time::sleep_until(next_ping).await;
+    }
+
+    /// When the first delayed command may be sent, if there is one.
+    fn next_delayed_send(&self, now: Instant) -> Option<Instant> {
+        let (data, _) = self.delayed.front()?;
+        let mut next_send = now;
+        if let (Some(limiter), Data::Send(_)) = (&self.limiter, data) {
+            next_send = next_send.max(limiter.next_send(now));
+        }
+        if let Some(limiter) = &self.rate_limiter {
+            next_send = next_send.max(limiter.next_send(now));
+        }
+        Some(next_send)
+    }
+
+    async fn await_next_send(next_send: Option<Instant>) {
+        match next_send {
+            Some(next_send) => tokio::time::sleep_until(next_send).await,
+            None => future::pending().await,
+        }
+    }
+
+    /// Send delayed commands in order until one may not be sent yet.
+    ///
+    /// With `all`, every delayed command is sent regardless of slow mode and
+    /// send rate.
+    #[allow(clippy::result_large_err)]
+    fn send_delayed(&mut self, all: bool) -> Result<()> {
+        loop {
+            let now = Instant::now();
+            match self.next_delayed_send(now) {
+                Some(next_send) if all || next_send <= now => {}
+                _ => break,
+            }
+            let (data, reply_tx) = self.delayed.pop_front().expect("command is delayed");
+            let is_message = matches!(data, Data::Send(_));
+            self.send_cmd(data, reply_tx)?;
+            if let (Some(limiter), true) = (&mut self.limiter, is_message) {
+                limiter.on_send(now);
+            }
+            if let Some(limiter) = &mut self.rate_limiter {
+                limiter.on_send(now);
+            }
+        }
+        self.pending_sends
+            .store(self.delayed.len(), Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Check the previous pings and queue new ones.
+    ///
+    /// Since this doesn't await anything, pings can't be skipped or sent twice
+    /// if a future calling this is dropped.
+    #[allow(clippy::result_large_err)]
+    fn on_ping(&mut self) -> Result<()> {
+        debug!("Checking ping replies and sending new pings");
+
+        // Check previous pings
+        if self.last_ws_ping_payload.is_some() && !self.last_ws_ping_replied_to {
+            debug!("Server did not respond to websocket ping, disconnecting");
+            self.disconnect_pending = true;
+            return Ok(());
+        }
+        if self.last_euph_ping_payload.is_some() && !self.last_euph_ping_replied_to {
+            debug!("Server did not respond to euph ping, disconnecting");
+            self.disconnect_pending = true;
+            return Ok(());
+        }
+
+        let (ws_payload, euph_payload) = ping_payloads(Timestamp::now());
+
+        // Send new ws ping
+        self.last_ws_ping_payload = Some(ws_payload.clone());
+        self.last_ws_ping_replied_to = false;
+        self.outbox
+            .push_back(tungstenite::Message::Ping(ws_payload));
+
+        // Send new euph ping
+        self.last_euph_ping_payload = Some(euph_payload);
+        self.last_euph_ping_replied_to = false;
+        self.send_cmd(Ping { time: euph_payload }.into(), None)?;
+
+        self.last_ping = Instant::now();
+
+        Ok(())
+    }
+
+    /// Check an outgoing command according to the [`ParentCheck`] and the
+    /// [`PermissionCheck`].
+    #[allow(clippy::result_large_err)]
+    fn check_cmd(&self, data: &Data) -> Result<()> {
+        self.check_permissions(data)?;
+        self.check_parent(data)
+    }
+
+    /// Check an outgoing command according to the [`PermissionCheck`].
+    #[allow(clippy::result_large_err)]
+    fn check_permissions(&self, data: &Data) -> Result<()> {
+        if self.permission_check == PermissionCheck::Off {
+            return Ok(());
+        }
+        let permissions = match self.state.joined() {
+            Some(joined) => joined.permissions(),
+            None => return Ok(()),
+        };
+        let r#type = data.packet_type();
+        if permissions.is_staff || permissions.allows(r#type) {
+            return Ok(());
+        }
+        match self.permission_check {
+            PermissionCheck::Off => Ok(()),
+            PermissionCheck::Warn => {
+                warn!("Sending {type} without being permitted to");
+                Ok(())
+            }
+            PermissionCheck::Enforce => {
+                warn!("Not sending {type} without being permitted to");
+                Err(Error::NotPermitted(r#type))
+            }
+        }
+    }
+
+    /// Check the parent of an outgoing message according to the
+    /// [`ParentCheck`].
+    #[allow(clippy::result_large_err)]
+    fn check_parent(&self, data: &Data) -> Result<()> {
+        let parent = match data {
+            Data::Send(crate::api::Send {
+                parent: Some(parent),
+                ..
+            }) => *parent,
+            _ => return Ok(()),
+        };
+        if self.parent_check == ParentCheck::Off {
+            return Ok(());
+        }
+        if self.history.contains(parent) != Membership::NotInRoom {
+            return Ok(());
+        }
+        match self.parent_check {
+            ParentCheck::Off => Ok(()),
+            ParentCheck::Warn => {
+                warn!(
+                    "Sending message with parent {} that is not in this room",
+                    parent.0
+                );
+                Ok(())
+            }
+            ParentCheck::Enforce => {
+                warn!(
+                    "Not sending message with parent {} that is not in this room",
+                    parent.0
+                );
+                Err(Error::ParentNotInRoom(parent))
+            }
+        }
+    }
+
+    /// Queue a command to be sent to the server.
+    ///
+    /// Only if a `reply_tx` is given is the command's reply tracked.
+    #[allow(clippy::result_large_err)]
+    fn send_cmd(&mut self, data: Data, reply_tx: Option<ReplyTx>) -> Result<()> {
+        // Overkill of universe-heat-death-like proportions
+        self.last_id = self.last_id.wrapping_add(1);
+        let id = format!("{}", self.last_id);
+
+        let expected = data.packet_type().reply_type();
+        if let Data::Nick(nick) = &data {
+            self.nick_order.on_send(self.last_id, nick);
+        }
+        if let Data::Log(log) = &data {
+            self.log_requests.on_send(&id, log);
+        }
+        self.send_packet(Some(id.clone()), data)?;
+
+        if let Some(reply_tx) = reply_tx {
+            let pending_reply = self.replies.wait_for(id.clone(), expected);
+            let _ = reply_tx.send(Ok((id, pending_reply)));
+        }
+
+        Ok(())
+    }
+
+    /// Queue a packet of an arbitrary type to be sent to the server.
+    #[allow(clippy::result_large_err)]
+    fn send_raw(&mut self, r#type: String, data: Value) -> Result<()> {
+        self.last_id = self.last_id.wrapping_add(1);
+        let packet = serde_json::json!({
+            "id": format!("{}", self.last_id),
+            "type": r#type,
+            "data": data,
+        });
+        debug!(target: "euphoxide::conn::full", "Sending raw {packet}");
+
+        let msg = tungstenite::Message::Text(serde_json::to_string(&packet)?);
+        self.outbox.push_back(msg);
+
+        Ok(())
+    }
+
+    /// Queue a packet to be sent to the server.
+    #[allow(clippy::result_large_err)]
+    fn send_packet(&mut self, id: Option<String>, data: Data) -> Result<()> {
+        let packet = ParsedPacket {
+            id,
+            r#type: data.packet_type(),
+            content: Ok(data),
+            throttled: None,
+            seq: None,
+        }
+        .into_packet()?;
+        debug!(target: "euphoxide::conn::full", "Sending {packet:?}");
+
+        let msg = tungstenite::Message::Text(serde_json::to_string(&packet)?);
+        self.outbox.push_back(msg);
+
+        Ok(())
+    }
+
+    /// Send all queued frames to the server.
+    ///
+    /// Frames are only removed from the outbox once the websocket accepted
+    /// them, so this is cancel-safe.
+    async fn flush_outbox(&mut self) -> Result<()> {
+        while !self.outbox.is_empty() {
+            future::poll_fn(|cx| self.ws.poll_ready_unpin(cx)).await?;
+            let msg = self.outbox.pop_front().expect("outbox is not empty");
+            self.ws.start_send_unpin(msg)?;
+            self.unflushed = true;
+        }
+        if self.unflushed {
+            self.ws.flush().await?;
+            self.unflushed = false;
+        }
+        Ok(())
+    }
+
+    async fn disconnect(&mut self) -> Result<Infallible> {
+        let _ = tokio::time::timeout(self.replies.timeout(), self.ws.close(None)).await;
+        debug!("Closed connection");
+        Err(Error::ConnectionClosed)
+    }
+
+    pub fn wrap(ws: WsStream, timeout: Duration) -> Self {
+        let (cmd_tx, cmd_rx) = mpsc::unbounded_channel();
+        let pending_sends = Arc::new(AtomicUsize::new(0));
+        let conn = Self {
+            ws,
+            last_id: 0,
+            replies: Replies::new(timeout),
+
+            conn_tx: ConnTx {
+                cmd_tx,
+                pending_sends: pending_sends.clone(),
+                room: None,
+            },
+            cmd_rx,
+
+            last_ping: Instant::now(), // Wait a bit before first pings
+            last_ws_ping_payload: None,
+            last_ws_ping_replied_to: false,
+            last_euph_ping_payload: None,
+            last_euph_ping_replied_to: false,
+
+            disconnect_pending: false,
+
+            outbox: VecDeque::new(),
+            unflushed: false,
+            received: None,
+            close_frame: None,
+
+            on_malformed: MalformedPolicy::default(),
+            malformed_packets: 0,
+
+            throttled_replies: 0,
+            throttled: None,
+
+            diagnostics: Diagnostics::default(),
+
+            status: Arc::new(ConnStatus::new()),
+
+            limiter: None,
+            rate_limiter: None,
+            delayed: VecDeque::new(),
+            pending_sends,
+            parent_check: ParentCheck::default(),
+            permission_check: PermissionCheck::default(),
+            history: RoomHistory::default(),
+            message_times: None,
+            message_log: None,
+
+            nick_order: NickOrder::default(),
+            log_requests: LogRequests::default(),
+
+            generation: GENERATION.fetch_add(1, Ordering::Relaxed),
+            received_packets: 0,
+            parsed_packets: 0,
+
+            upgraded_at: Instant::now(),
+            hello_at: None,
+            connect_timings: ConnectTimings::default(),
+
+            state: Arc::new(State::Joining(Joining::new(Timestamp::now()))),
+            clock: SystemClock::shared(),
+        };
+        conn.attach_status();
+        conn
+    }
+
+    /// Connect to a room via `wss://`.
+    ///
+    /// Only available with the `tls` feature. Without it, open a [`WsStream`]
+    /// some other way and use [`Self::wrap`] instead.
+    ///
+    /// Installs the crypto provider selected via cargo features unless a
+    /// default provider has been installed already, see [`crate::tls`].
+    #[cfg(feature = "tls")]
+    pub async fn connect(
+        domain: &str,
+        room: &str,
+        human: bool,
+        cookies: Option<HeaderValue>,
+        timeout: Duration,
+    ) -> Result<(Self, Vec<HeaderValue>)> {
+        crate::tls::install_default_provider();
+        let uri = Self::room_uri("wss", domain, room, human);
+        Self::connect_to(uri, room, cookies, timeout).await
+    }
+
+    /// Connect to a room via unencrypted `ws://`.
+    ///
+    /// Euphoria servers only accept TLS connections, so this is mostly useful
+    /// for testing against local servers.
+    pub async fn connect_insecure(
+        domain: &str,
+        room: &str,
+        human: bool,
+        cookies: Option<HeaderValue>,
+        timeout: Duration,
+    ) -> Result<(Self, Vec<HeaderValue>)> {
+        let uri = Self::room_uri("ws", domain, room, human);
+        Self::connect_to(uri, room, cookies, timeout).await
+    }
+
+    fn room_uri(scheme: &str, domain: &str, room: &str, human: bool) -> String {
+        let human = if human { "?h=1" } else { "" };
+        format!("{scheme}://{domain}/room/{room}/ws{human}")
+    }
+
+    async fn connect_to(
+        uri: String,
+        room: &str,
+        cookies: Option<HeaderValue>,
+        timeout: Duration,
+    ) -> Result<(Self, Vec<HeaderValue>)> {
+        debug!("Connecting to {uri} with cookies: {cookies:?}");
+        let mut request = uri.into_client_request().expect("valid request");
+        if let Some(cookies) = cookies {
+            request.headers_mut().append(header::COOKIE, cookies);
+        }
+
+        let start = Instant::now();
+        let (ws, response) =
+            tokio::time::timeout(timeout, tokio_tungstenite::connect_async(request))
+                .await
+                .map_err(|_| Error::ConnectionTimedOut)??;
+        let handshake = start.elapsed();
+        let (mut parts, _) = response.into_parts();
+        let cookies_set = match parts.headers.entry(header::SET_COOKIE) {
+            header::Entry::Occupied(entry) => entry.remove_entry_mult().1.collect(),
+            header::Entry::Vacant(_) => vec![],
+        };
+        debug!("Received cookies {cookies_set:?}");
+        let mut rx = Self::wrap(ws, timeout);
+        rx.connect_timings.handshake = Some(handshake);
+        rx.conn_tx.room = Some(room.into());
+        Ok((rx, cookies_set))
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use std::collections::{BTreeMap, HashMap};
+    use std::future::Future;
+    use std::sync::{Arc, Mutex};
+    use std::task::Poll;
+    use std::time::{Duration, Instant};
+
+    use futures_util::SinkExt;
+    use jiff::{Timestamp, ToSpan};
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio_stream::StreamExt;
+    use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+    use tokio_tungstenite::tungstenite::protocol::CloseFrame;
+    use tokio_tungstenite::tungstenite::Message;
+    use tokio_tungstenite::WebSocketStream;
+
+    use crate::api::packet::{ErrorReason, ParsedPacket, ThrottleReason};
+    use crate::api::{
+        AccessErrorReason, Data, EditMessageEvent, GetMessageReply, HelloEvent, JoinEvent, Log,
+        LogReply, Message as EuphMessage, MessageId, NetworkEvent, Nick, NickEvent, NickReply,
+        PacketType, PartEvent, Ping, SendErrorReason, SendEvent, SendReply, SessionId, SessionView,
+        SnapshotEvent, Snowflake, Time, UserId, Who, WhoReply,
+    };
+
+    use crate::clock::{Clock, MockClock};
+    use crate::test_util::{JoinedBuilder, MessageBuilder, SessionViewBuilder};
+
+    use super::format_send_failure;
+    use super::{
+        Conn, ConnPhase, ConnTx, ConnectTimings, DebugInfo, DiagnosticCounts, Error,
+        FreshnessRequirements, Joined, Joining, MalformedPolicy, Membership, MessageLog,
+        MessageTimes, MessageTimesConfig, ParentCheck, PermissionCheck, RateLimiter, RoomHistory,
+        SendLimiter, SendRate, SessionInfo, Severity, SlowMode, StaleStateError, State,
+        DIAGNOSTICS_LEN, ROOM_HISTORY_LEN,
+    };
+
+    /// A record logged by [`super::log_send_failure`].
+    #[derive(Debug, Clone)]
+    struct SendFailure {
+        level: log::Level,
+        message: String,
+        fields: BTreeMap<String, String>,
+    }
+
+    struct Fields(BTreeMap<String, String>);
+
+    impl<'kvs> log::kv::VisitSource<'kvs> for Fields {
+        fn visit_pair(
+            &mut self,
+            key: log::kv::Key<'kvs>,
+            value: log::kv::Value<'kvs>,
+        ) -> Result<(), log::kv::Error> {
+            self.0.insert(key.to_string(), value.to_string());
+            Ok(())
+        }
+    }
+
+    /// Records the send failures logged once [`capture_logs`] was called.
+    struct SendFailureLogger(Mutex<Vec<SendFailure>>);
+
+    impl log::Log for SendFailureLogger {
+        fn enabled(&self, metadata: &log::Metadata<'_>) -> bool {
+            metadata.target() == "euphoxide::conn::send"
+        }
+
+        fn log(&self, record: &log::Record<'_>) {
+            if !self.enabled(record.metadata()) {
+                return;
+            }
+            let mut fields = Fields(BTreeMap::new());
+            record.key_values().visit(&mut fields).unwrap();
+            self.0.lock().unwrap().push(SendFailure {
+                level: record.level(),
+                message: record.args().to_string(),
+                fields: fields.0,
+            });
+        }
+
+        fn flush(&self) {}
+    }
+
+    static SEND_FAILURES: SendFailureLogger = SendFailureLogger(Mutex::new(Vec::new()));
+
+    /// Install [`SEND_FAILURES`] as the global logger.
+    ///
+    /// Tests may run in any order and there can only be one global logger, so
+    /// this may be called multiple times.
+    fn capture_logs() -> &'static SendFailureLogger {
+        if log::set_logger(&SEND_FAILURES).is_err() {
+            let installed = log::logger() as *const dyn log::Log;
+            assert!(
+                std::ptr::addr_eq(installed, &SEND_FAILURES),
+                "a different logger is already installed"
+            );
+        }
+        if log::max_level() < log::LevelFilter::Warn {
+            log::set_max_level(log::LevelFilter::Warn);
+        }
+        &SEND_FAILURES
+    }
+
+    /// A [`ConnTx`] whose connection is already closed.
+    #[cfg(feature = "bot")]
+    pub(crate) fn closed_tx() -> ConnTx {
+        let (cmd_tx, _) = super::mpsc::unbounded_channel();
+        ConnTx {
+            cmd_tx,
+            pending_sends: Arc::default(),
+            room: None,
+        }
+    }
+
+    /// The server side of a websocket connection to a [`Conn`].
+    pub(crate) struct Server(pub(crate) WebSocketStream<TcpStream>);
+
+    impl Server {
+        /// Send a packet given as JSON to the client.
+        pub(crate) async fn send(&mut self, packet: serde_json::Value) {
+            let text = serde_json::to_string(&packet).unwrap();
+            self.0.send(Message::Text(text)).await.unwrap();
+        }
+
+        /// Receive the next packet from the client as JSON.
+        ///
+        /// Returns `None` once the connection is closed.
+        pub(crate) async fn recv(&mut self) -> Option<serde_json::Value> {
+            while let Some(Ok(msg)) = self.0.next().await {
+                if let Message::Text(text) = msg {
+                    return Some(serde_json::from_str(&text).unwrap());
+                }
+            }
+            None
+        }
+
+        /// Send a hello-event and snapshot-event so the client joins the room.
+        pub(crate) async fn join(&mut self, hello: HelloEvent) {
+            self.send(serde_json::json!({ "type": "hello-event", "data": hello }))
+                .await;
+            self.send(serde_json::json!({ "type": "snapshot-event", "data": snapshot() }))
+                .await;
+        }
+    }
+
+    /// Connect a [`Conn`] to a local websocket server.
+    ///
+    /// The connection doesn't use TLS, so this also works without the `tls`
+    /// feature.
+    pub(crate) async fn connect(timeout: Duration) -> (Conn, Server) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = async {
+            let (stream, _) = listener.accept().await.unwrap();
+            tokio_tungstenite::accept_async(stream).await.unwrap()
+        };
+        let client = tokio_tungstenite::connect_async(format!("ws://{addr}"));
+        let (server, client) = tokio::join!(server, client);
+        let (client, _) = client.unwrap();
+        (Conn::wrap(client, timeout), Server(server))
+    }
+
+    pub(crate) fn hello(room_is_private: bool, account: Option<bool>) -> HelloEvent {
+        let mut hello = serde_json::json!({
+            "id": "agent:abc",
+            "session": {
+                "id": "agent:abc",
+                "name": "",
+                "server_id": "heim.1",
+                "server_era": "era",
+                "session_id": "session",
+            },
+            "room_is_private": room_is_private,
+            "version": "version",
+        });
+        if let Some(verified) = account {
+            hello["account"] = serde_json::json!({
+                "id": "0000000000000",
+                "name": "TestBot",
+                "email": "testbot@example.com",
+            });
+            hello["account_has_access"] = serde_json::json!(true);
+            hello["account_email_verified"] = serde_json::json!(verified);
+        }
+        serde_json::from_value(hello).unwrap()
+    }
+
+    fn snapshot() -> SnapshotEvent {
+        serde_json::from_value(serde_json::json!({
+            "identity": "agent:abc",
+            "session_id": "session",
+            "version": "version",
+            "listing": [],
+            "log": [],
+        }))
+        .unwrap()
+    }
+
+    fn join(hello: HelloEvent) -> Joined {
+        let mut joining = Joining::new(Timestamp::now());
+        joining.on_data(&Data::HelloEvent(hello)).unwrap();
+        joining.on_data(&Data::SnapshotEvent(snapshot())).unwrap();
+        joining.joined(Timestamp::now()).unwrap()
+    }
+
+    #[test]
+    fn hello_without_optional_fields() {
+        let hello = serde_json::from_value::<HelloEvent>(serde_json::json!({
+            "id": "agent:abc",
+            "session": {
+                "id": "agent:abc",
+                "name": "",
+                "server_id": "heim.1",
+                "server_era": "era",
+                "session_id": "session",
+            },
+            "version": "version",
+        }))
+        .unwrap();
+        assert!(!hello.room_is_private);
+        assert_eq!(hello.account_has_access, None);
+        assert_eq!(hello.account_email_verified, None);
+    }
+
+    #[test]
+    fn room_privacy() {
+        assert!(!join(hello(false, None)).room_is_private);
+        assert!(join(hello(true, None)).room_is_private);
+    }
+
+    #[test]
+    fn permissions() {
+        let permissions = |joined: &Joined| {
+            let p = joined.permissions();
+            [
+                p.can_send(),
+                p.can_edit_messages(),
+                p.can_ban(),
+                p.can_grant_access(),
+                p.can_initiate_pm(),
+            ]
+        };
+
+        // Anonymous session without nick
+        let mut joined = join(hello(false, None));
+        assert_eq!(permissions(&joined), [false, false, false, false, false]);
+
+        // Agent with nick
+        joined.session.name = "TestBot".to_string();
+        assert_eq!(permissions(&joined), [true, false, false, false, false]);
+
+        // Logged in to an account
+        let mut joined = join(hello(false, Some(true)));
+        assert_eq!(permissions(&joined), [false, false, false, false, true]);
+        joined.session.name = "TestBot".to_string();
+        assert_eq!(permissions(&joined), [true, false, false, false, true]);
+
+        // Manager
+        joined.session.is_manager = true;
+        assert_eq!(permissions(&joined), [true, true, true, true, true]);
+
+        // Staff without being a manager
+        joined.session.is_manager = false;
+        joined.session.is_staff = true;
+        assert_eq!(permissions(&joined), [true, false, false, false, true]);
+    }
+
+    #[test]
+    fn permissions_allow_packet_types() {
+        let mut joined = join(hello(false, Some(true)));
+        joined.session.name = "TestBot".to_string();
+        let p = joined.permissions();
+        assert!(p.allows(PacketType::Send));
+        assert!(p.allows(PacketType::PmInitiate));
+        assert!(p.allows(PacketType::Who));
+        assert!(!p.allows(PacketType::EditMessage));
+        assert!(!p.allows(PacketType::Ban));
+        assert!(!p.allows(PacketType::RevokeAccess));
+        assert!(!p.allows(PacketType::GrantManager));
+        assert!(!p.can_grant_manager());
+
+        joined.session.is_manager = true;
+        let p = joined.permissions();
+        assert!(p.allows(PacketType::Ban));
+        assert!(p.allows(PacketType::GrantManager));
+        assert!(p.can_grant_manager());
+    }
+
+    #[test]
+    fn pm_counterpart() {
+        assert_eq!(join(hello(false, None)).pm_counterpart, None);
+
+        let snapshot = serde_json::from_value::<SnapshotEvent>(serde_json::json!({
+            "identity": "agent:abc",
+            "session_id": "session",
+            "version": "version",
+            "listing": [],
+            "log": [],
+            "pm_with_nick": "alice",
+            "pm_with_user_id": "account:alice",
+        }))
+        .unwrap();
+        let mut joining = Joining::new(Timestamp::now());
+        joining
+            .on_data(&Data::HelloEvent(hello(false, None)))
+            .unwrap();
+        joining.on_data(&Data::SnapshotEvent(snapshot)).unwrap();
+        assert_eq!(
+            joining.joined(Timestamp::now()).unwrap().pm_counterpart,
+            Some((UserId("account:alice".to_string()), "alice".to_string()))
+        );
+    }
+
+    #[test]
+    fn account_email_verification() {
+        let joined = join(hello(false, None));
+        assert!(joined.account.is_none());
+        assert_eq!(joined.account_email_verified, None);
+
+        let joined = join(hello(false, Some(false)));
+        assert!(joined.account.is_some());
+        assert_eq!(joined.account_email_verified, Some(false));
+
+        let joined = join(hello(true, Some(true)));
+        assert!(joined.account.is_some());
+        assert_eq!(joined.account_email_verified, Some(true));
+    }
+
+    #[tokio::test]
+    async fn disconnect_event_is_returned_before_closing() {
+        let (mut conn, mut server) = connect(Duration::from_secs(10)).await;
+        server
+            .send(serde_json::json!({
+                "type": "disconnect-event",
+                "data": { "reason": "authentication changed" },
+            }))
+            .await;
+
+        let packet = conn.recv().await.unwrap();
+        assert!(matches!(packet.content, Ok(Data::DisconnectEvent(_))));
+        assert!(matches!(conn.recv().await, Err(Error::ConnectionClosed)));
+    }
+
+    #[tokio::test]
+    async fn close_frame_is_surfaced() {
+        let (mut conn, mut server) = connect(Duration::from_secs(10)).await;
+        let close = CloseFrame {
+            code: CloseCode::Away,
+            reason: "going away".into(),
+        };
+        let server = tokio::spawn(async move {
+            server.0.close(Some(close)).await.unwrap();
+            while server.0.next().await.is_some() {}
+        });
+
+        let err = conn.recv().await.unwrap_err();
+        match &err {
+            Error::ConnectionClosedWithReason { code, reason } => {
+                assert_eq!(*code, 1001);
+                assert_eq!(reason, "going away");
+            }
+            err => panic!("expected close reason, got {err:?}"),
+        }
+        assert_eq!(
+            err.to_string(),
+            "connection closed by server with code 1001: going away"
+        );
+        server.await.unwrap();
+    }
+
+    /// Poll a future at most `polls` times, yielding to the runtime in
+    /// between, and drop it if it hasn't completed by then.
+    async fn poll_then_drop<F: Future>(fut: F, polls: usize) -> Option<F::Output> {
+        let mut fut = std::pin::pin!(fut);
+        for _ in 0..polls {
+            let poll = std::future::poll_fn(|cx| Poll::Ready(fut.as_mut().poll(cx))).await;
+            if let Poll::Ready(output) = poll {
+                return Some(output);
+            }
+            tokio::task::yield_now().await;
+        }
+        None
+    }
+
+    #[tokio::test]
+    async fn recv_is_cancel_safe() {
+        let (mut conn, mut server) = connect(Duration::from_secs(10)).await;
+        server.join(hello(false, None)).await;
+        for time in 0..20 {
+            let ping = serde_json::json!({ "time": time, "next": time + 1 });
+            server
+                .send(serde_json::json!({ "type": "ping-event", "data": ping }))
+                .await;
+        }
+
+        let mut packets = vec![];
+        let mut polls = 0;
+        let receive = async {
+            while packets.len() < 22 {
+                // Drop the future at varying points
+                polls = polls % 4 + 1;
+                if let Some(packet) = poll_then_drop(conn.recv(), polls).await {
+                    packets.push(packet.unwrap());
+                }
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(5), receive)
+            .await
+            .unwrap();
+
+        // Every packet is returned exactly once and in order
+        let seqs = packets
+            .iter()
+            .map(|p| p.seq.unwrap().seq)
+            .collect::<Vec<_>>();
+        assert_eq!(seqs, (0..22).collect::<Vec<_>>());
+        assert_eq!(packets[0].r#type, PacketType::HelloEvent);
+        assert_eq!(packets[1].r#type, PacketType::SnapshotEvent);
+        for (time, packet) in packets[2..].iter().enumerate() {
+            match &packet.content {
+                Ok(Data::PingEvent(p)) => assert_eq!(p.time, Time(time as i64)),
+                content => panic!("unexpected packet {content:?}"),
+            }
+        }
+
+        // Every ping event is replied to exactly once
+        drop(conn);
+        let mut replies = vec![];
+        while let Some(packet) = server.recv().await {
+            assert_eq!(packet["type"], "ping-reply");
+            replies.push(packet["data"]["time"].as_i64().unwrap());
+        }
+        assert_eq!(replies, (0..20).collect::<Vec<_>>());
+    }
+
+    #[tokio::test]
+    async fn pings_survive_cancelled_recv() {
+        let interval = Duration::from_millis(100);
+        let (mut conn, mut server) = connect(interval).await;
+        server.join(hello(false, None)).await;
+        let server = tokio::spawn(async move {
+            let mut pings = vec![];
+            while let Some(packet) = server.recv().await {
+                if packet["type"] == "ping" {
+                    pings.push((Instant::now(), packet["data"]["time"].clone()));
+                    let reply = serde_json::json!({
+                        "id": packet["id"],
+                        "type": "ping-reply",
+                        "data": { "time": packet["data"]["time"] },
+                    });
+                    server.send(reply).await;
+                }
+            }
+            pings
+        });
+
+        let start = Instant::now();
+        let mut polls = 0;
+        while start.elapsed() < interval * 6 + interval / 2 {
+            polls = polls % 4 + 1;
+            if let Some(packet) = poll_then_drop(conn.recv(), polls).await {
+                packet.unwrap();
+            }
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        drop(conn);
+
+        // Pings are neither skipped nor sent twice per interval
+        let pings = server.await.unwrap();
+        assert!(pings.len() >= 4, "only {} pings were sent", pings.len());
+        for pair in pings.windows(2) {
+            assert_ne!(pair[0].1, pair[1].1);
+            assert!(pair[1].0 - pair[0].0 >= interval / 2);
+        }
+    }
+
+    #[tokio::test]
+    async fn drain_flushes_commands_before_closing() {
+        let (conn, mut server) = connect(Duration::from_secs(10)).await;
+        let tx = conn.tx().clone();
+        tx.send_only(Ping { time: Time(1) }).unwrap();
+        let reply = tx.send(Ping { time: Time(2) });
+
+        let close = CloseFrame {
+            code: CloseCode::Normal,
+            reason: "bye".into(),
+        };
+        let drain = conn.drain(Duration::from_millis(100), close);
+
+        // The server must close the tcp connection after the closing handshake,
+        // so it is moved into and dropped at the end of this block.
+        let server = async move {
+            let mut received = vec![];
+            while let Some(Ok(msg)) = server.0.next().await {
+                if let Message::Text(text) = &msg {
+                    let packet: serde_json::Value = serde_json::from_str(text).unwrap();
+                    if packet["data"]["time"] == 2 {
+                        server
+                            .send(serde_json::json!({
+                                "id": packet["id"],
+                                "type": "ping-reply",
+                                "data": { "time": 2 },
+                            }))
+                            .await;
+                    }
+                }
+                received.push(msg);
+            }
+            received
+        };
+
+        let (drained, received, reply) = tokio::join!(drain, server, reply);
+        drained.unwrap();
+        assert_eq!(reply.unwrap().time, Some(Time(2)));
+
+        assert_eq!(received.len(), 3);
+        assert!(matches!(&received[0], Message::Text(t) if t.contains("\"time\":1")));
+        assert!(matches!(&received[1], Message::Text(t) if t.contains("\"time\":2")));
+        match &received[2] {
+            Message::Close(Some(frame)) => {
+                assert_eq!(frame.code, CloseCode::Normal);
+                assert_eq!(frame.reason, "bye");
+            }
+            msg => panic!("expected close frame, got {msg:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn close_waits_for_replies() {
+        let (conn, mut server) = connect(Duration::from_secs(10)).await;
+        let tx = conn.tx().clone();
+        let reply = tx.send(Ping { time: Time(1) });
+
+        let close = CloseFrame {
+            code: CloseCode::Normal,
+            reason: "bye".into(),
+        };
+        // If the connection wasn't closed as soon as the reply arrives, this
+        // test would hang instead of finishing immediately.
+        let closed = conn.close(Duration::from_secs(24 * 60 * 60), close);
+
+        let server = async move {
+            let mut received = vec![];
+            while let Some(Ok(msg)) = server.0.next().await {
+                if let Message::Text(text) = &msg {
+                    let packet: serde_json::Value = serde_json::from_str(text).unwrap();
+                    tokio::time::sleep(Duration::from_millis(50)).await;
+                    server
+                        .send(serde_json::json!({
+                            "id": packet["id"],
+                            "type": "ping-reply",
+                            "data": { "time": 1 },
+                        }))
+                        .await;
+                }
+                received.push(msg);
+            }
+            received
+        };
+
+        let (closed, received, reply) = tokio::join!(closed, server, reply);
+        closed.unwrap();
+        assert_eq!(reply.unwrap().time, Some(Time(1)));
+
+        assert_eq!(received.len(), 2);
+        assert!(matches!(&received[0], Message::Text(t) if t.contains("\"time\":1")));
+        assert!(matches!(&received[1], Message::Close(Some(_))));
+    }
+
+    fn ping_event(time: i64) -> serde_json::Value {
+        serde_json::json!({
+            "type": "ping-event",
+            "data": { "time": time, "next": time + 30 },
+        })
+    }
+
+    /// Send valid packets with some malformed packets in between.
+    async fn send_poisoned(server: &mut Server) {
+        server.send(ping_event(1)).await;
+        server
+            .0
+            .send(Message::Text("not json".to_string()))
+            .await
+            .unwrap();
+        server
+            .send(serde_json::json!({ "type": "no-such-type", "data": {} }))
+            .await;
+        server
+            .send(serde_json::json!({
+                "type": "ping-event",
+                "data": { "time": "soon", "next": "later" },
+            }))
+            .await;
+        server.send(ping_event(2)).await;
+    }
+
+    fn ping_time(packet: &ParsedPacket) -> Option<i64> {
+        match &packet.content {
+            Ok(Data::PingEvent(p)) => Some(p.time.0),
+            _ => None,
+        }
+    }
+
+    #[tokio::test]
+    async fn malformed_packets_are_skipped() {
+        let (mut conn, mut server) = connect(Duration::from_secs(10)).await;
+        conn.set_on_malformed(MalformedPolicy::Skip);
+        send_poisoned(&mut server).await;
+
+        assert_eq!(ping_time(&conn.recv().await.unwrap()), Some(1));
+
+        let packet = conn.recv().await.unwrap();
+        assert_eq!(packet.r#type, PacketType::PingEvent);
+        assert!(matches!(packet.content, Ok(Data::Unimplemented)));
+
+        assert_eq!(ping_time(&conn.recv().await.unwrap()), Some(2));
+        assert_eq!(conn.malformed_packets(), 3);
+    }
+
+    #[tokio::test]
+    async fn clock() {
+        let start = Timestamp::from_second(1_000_000_000).unwrap();
+        let clock = MockClock::at(start);
+        let (mut conn, mut server) = connect(Duration::from_secs(10)).await;
+        conn.set_clock(clock.shared());
+        assert_eq!(conn.state().joining().unwrap().since, start);
+
+        clock.advance(Duration::from_secs(5));
+        server.join(hello(false, None)).await;
+        while let State::Joining(_) = conn.state() {
+            conn.recv().await.unwrap();
+        }
+        assert_eq!(conn.state().joined().unwrap().since, start + 5.seconds());
+    }
+
+    #[tokio::test]
+    async fn packet_sequence_numbers() {
+        let (mut conn, mut server) = connect(Duration::from_secs(10)).await;
+        conn.set_on_malformed(MalformedPolicy::Skip);
+        server.join(hello(false, None)).await;
+        send_poisoned(&mut server).await;
+
+        let mut seqs = vec![];
+        for _ in 0..5 {
+            let seq = conn.recv().await.unwrap().seq.unwrap();
+            assert_eq!(seq.generation, conn.generation());
+            seqs.push(seq.seq);
+        }
+        // The skipped packets leave gaps
+        assert_eq!(seqs, [0, 1, 2, 5, 6]);
+
+        let info = conn.debug_info();
+        assert_eq!((info.received_packets, info.parsed_packets), (7, 5));
+
+        // Sequence numbers start over on the next connection
+        let (mut conn2, mut server2) = connect(Duration::from_secs(10)).await;
+        assert!(conn2.generation() > conn.generation());
+        server2.send(ping_event(1)).await;
+        let seq = conn2.recv().await.unwrap().seq.unwrap();
+        assert_eq!((seq.generation, seq.seq), (conn2.generation(), 0));
+    }
+
+    #[tokio::test]
+    async fn malformed_packets_disconnect() {
+        let (mut conn, mut server) = connect(Duration::from_secs(10)).await;
+        send_poisoned(&mut server).await;
+
+        assert_eq!(ping_time(&conn.recv().await.unwrap()), Some(1));
+        assert!(matches!(conn.recv().await, Err(Error::SerdeJson(_))));
+    }
+
+    #[tokio::test]
+    async fn malformed_packet_data_disconnects() {
+        let (mut conn, mut server) = connect(Duration::from_secs(10)).await;
+        server
+            .send(serde_json::json!({
+                "type": "ping-event",
+                "data": { "time": "soon", "next": "later" },
+            }))
+            .await;
+
+        assert!(matches!(conn.recv().await, Err(Error::SerdeJson(_))));
+        assert_eq!(conn.malformed_packets(), 0);
+    }
+
+    #[test]
+    fn ping_payloads_are_unique() {
+        let now = Timestamp::now();
+        let (ws1, euph1) = super::ping_payloads(now);
+        let (ws2, euph2) = super::ping_payloads(now);
+        assert_ne!(ws1, ws2);
+        assert_ne!(euph1, euph2);
+    }
+
+    /// Whether a connection stays alive for a few ping intervals if the server
+    /// answers its first euph ping with the payload of a previous connection's
+    /// ping, if `stale` is set, or with the correct payload.
+    async fn survives_ping_reply(stale: bool) -> bool {
+        let timeout = Duration::from_millis(100);
+
+        let (mut old_conn, mut old_server) = connect(timeout).await;
+        let old_conn = tokio::spawn(async move { while old_conn.recv().await.is_ok() {} });
+        let old_ping = old_server.recv().await.unwrap();
+        assert_eq!(old_ping["type"], "ping");
+        old_conn.abort();
+
+        let (mut conn, mut server) = connect(timeout).await;
+        let conn = tokio::spawn(async move { while conn.recv().await.is_ok() {} });
+        let ping = server.recv().await.unwrap();
+        assert_eq!(ping["type"], "ping");
+        assert_ne!(ping["data"]["time"], old_ping["data"]["time"]);
+
+        let time = if stale { &old_ping } else { &ping }["data"]["time"].clone();
+        server
+            .send(serde_json::json!({
+                "id": ping["id"],
+                "type": "ping-reply",
+                "data": { "time": time },
+            }))
+            .await;
+
+        // Answer all further pings correctly
+        tokio::spawn(async move {
+            while let Some(ping) = server.recv().await {
+                server
+                    .send(serde_json::json!({
+                        "id": ping["id"],
+                        "type": "ping-reply",
+                        "data": { "time": ping["data"]["time"] },
+                    }))
+                    .await;
+            }
+        });
+        tokio::time::timeout(timeout * 3, conn).await.is_err()
+    }
+
+    #[tokio::test]
+    async fn stale_ping_replies_are_rejected() {
+        assert!(survives_ping_reply(false).await);
+        assert!(!survives_ping_reply(true).await);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn slow_mode_interval_adoption_and_decay() {
+        let secs = Duration::from_secs;
+        let config = SlowMode::new()
+            .with_fallback_interval(secs(3))
+            .with_decay(secs(60));
+        let mut limiter = SendLimiter::new(config);
+        let start = tokio::time::Instant::now();
+        limiter.on_send(start);
+        assert_eq!(limiter.next_send(start), start);
+
+        // Flood protection alone doesn't make the limiter adopt an interval
+        limiter.on_throttled(ThrottleReason::Flood, start);
+        assert_eq!(limiter.interval(start), None);
+
+        limiter.on_throttled(ThrottleReason::SlowMode(Some(secs(10))), start);
+        assert_eq!(limiter.interval(start), Some(secs(10)));
+        assert_eq!(limiter.next_send(start), start + secs(10));
+
+        tokio::time::advance(secs(10)).await;
+        let now = tokio::time::Instant::now();
+        assert_eq!(limiter.next_send(now), now);
+        limiter.on_send(now);
+        assert_eq!(limiter.next_send(now), now + secs(10));
+
+        // Further throttling prolongs the interval
+        tokio::time::advance(secs(40)).await;
+        let now = tokio::time::Instant::now();
+        limiter.on_throttled(ThrottleReason::Flood, now);
+        tokio::time::advance(secs(59)).await;
+        let now = tokio::time::Instant::now();
+        assert_eq!(limiter.interval(now), Some(secs(10)));
+
+        // The interval decays without further throttling
+        limiter.on_send(now);
+        assert_eq!(limiter.next_send(now), now + secs(1));
+        tokio::time::advance(secs(1)).await;
+        let now = tokio::time::Instant::now();
+        assert_eq!(limiter.interval(now), None);
+        assert_eq!(limiter.next_send(now), now);
+
+        // Flood protection doesn't revive a decayed interval
+        limiter.on_throttled(ThrottleReason::Flood, now);
+        assert_eq!(limiter.interval(now), None);
+
+        limiter.on_throttled(ThrottleReason::SlowMode(None), now);
+        assert_eq!(limiter.interval(now), Some(secs(3)));
+    }
+
+    fn message_json(id: &str) -> serde_json::Value {
+        serde_json::json!({
+            "id": id,
+            "time": 0,
+            "sender": hello(false, None).session,
+            "content": "content",
+        })
+    }
+
+    fn history(ids: &[u64]) -> RoomHistory {
+        let log = ids
+            .iter()
+            .map(|id| message_json(&Snowflake(*id).to_string()))
+            .collect::<Vec<_>>();
+        let mut snapshot = serde_json::to_value(snapshot()).unwrap();
+        snapshot["log"] = serde_json::json!(log);
+        let snapshot = serde_json::from_value(snapshot).unwrap();
+        let mut history = RoomHistory::default();
+        history.on_data(&Data::SnapshotEvent(snapshot));
+        history
+    }
+
+    #[test]
+    fn room_history() {
+        let id = |id| MessageId(Snowflake(id));
+
+        // Before a snapshot, nothing is known
+        let mut unjoined = RoomHistory::default();
+        unjoined.insert(id(10));
+        assert_eq!(unjoined.contains(id(10)), Membership::Inconclusive);
+
+        // Two rooms whose messages interleave
+        let a = history(&[10, 12, 14]);
+        let b = history(&[11, 13, 15]);
+        assert_eq!(a.contains(id(12)), Membership::Known);
+        assert_eq!(b.contains(id(12)), Membership::NotInRoom);
+        assert_eq!(a.contains(id(15)), Membership::NotInRoom);
+        // Messages older than the snapshot may be in either room
+        assert_eq!(a.contains(id(5)), Membership::Inconclusive);
+        assert_eq!(b.contains(id(10)), Membership::Inconclusive);
+
+        // Only the most recent messages are remembered
+        let mut a = a;
+        for i in 0..ROOM_HISTORY_LEN as u64 {
+            a.insert(id(100 + i));
+        }
+        assert_eq!(a.contains(id(14)), Membership::Inconclusive);
+        assert_eq!(a.contains(id(100)), Membership::Known);
+        assert_eq!(a.contains(id(99)), Membership::Inconclusive);
+        assert_eq!(a.contains(id(101)), Membership::Known);
+
+        // An empty room knows no messages either
+        let empty = history(&[]);
+        assert_eq!(empty.contains(id(10)), Membership::Inconclusive);
+    }
+
+    /// Join a room whose log contains the messages 10 and 12.
+    async fn connect_with_log(parent_check: ParentCheck) -> (ConnTx, Server) {
+        let (mut conn, mut server) = connect(Duration::from_secs(10)).await;
+        assert_eq!(conn.parent_check(), ParentCheck::Off);
+        conn.set_parent_check(parent_check);
+        server
+            .send(serde_json::json!({ "type": "hello-event", "data": hello(false, None) }))
+            .await;
+        let mut snapshot = serde_json::to_value(snapshot()).unwrap();
+        snapshot["log"] = serde_json::json!([message_json("0000000000010")]);
+        server
+            .send(serde_json::json!({ "type": "snapshot-event", "data": snapshot }))
+            .await;
+        server
+            .send(serde_json::json!({
+                "type": "send-event",
+                "data": message_json("0000000000012"),
+            }))
+            .await;
+        while let State::Joining(_) = conn.state() {
+            conn.recv().await.unwrap();
+        }
+        conn.recv().await.unwrap();
+        let tx = conn.tx().clone();
+        tokio::spawn(async move { while conn.recv().await.is_ok() {} });
+        (tx, server)
+    }
+
+    fn reply_to(tx: &ConnTx, parent: &str) -> impl Future<Output = super::Result<SendReply>> {
+        let parent = serde_json::from_value(serde_json::json!(parent)).unwrap();
+        tx.send(crate::api::Send {
+            content: "reply".to_string(),
+            parent: Some(parent),
+        })
+    }
+
+    #[tokio::test]
+    async fn parent_check() {
+        let (tx, mut server) = connect_with_log(ParentCheck::Enforce).await;
+
+        // Known parents and parents from before the snapshot are always sent
+        for parent in ["0000000000012", "0000000000001"] {
+            drop(reply_to(&tx, parent));
+            let cmd = server.recv().await.unwrap();
+            assert_eq!(cmd["data"]["parent"], parent);
+        }
+
+        let result = reply_to(&tx, "0000000000011").await;
+        assert!(matches!(result, Err(Error::ParentNotInRoom(_))));
+
+        for check in [ParentCheck::Warn, ParentCheck::Off] {
+            let (tx, mut server) = connect_with_log(check).await;
+            drop(reply_to(&tx, "0000000000011"));
+            let cmd = server.recv().await.unwrap();
+            assert_eq!(cmd["data"]["parent"], "0000000000011");
+        }
+    }
+
+    #[tokio::test]
+    async fn permission_check() {
+        let connect_with = |check: PermissionCheck| async move {
+            let (mut conn, mut server) = connect(Duration::from_secs(10)).await;
+            assert_eq!(conn.permission_check(), PermissionCheck::Off);
+            conn.set_permission_check(check);
+            server.join(hello(false, None)).await;
+            while let State::Joining(_) = conn.state() {
+                conn.recv().await.unwrap();
+            }
+            let tx = conn.tx().clone();
+            tokio::spawn(async move { while conn.recv().await.is_ok() {} });
+            (tx, server)
+        };
+        let ban = || crate::api::Ban {
+            id: Some(UserId("agent:spammer".to_string())),
+            ip: None,
+            seconds: None,
+        };
+
+        let (tx, mut server) = connect_with(PermissionCheck::Enforce).await;
+        let result = tx.send(ban()).await;
+        assert!(matches!(result, Err(Error::NotPermitted(PacketType::Ban))));
+        let result = tx
+            .send(crate::api::Send {
+                content: "hi".to_string(),
+                parent: None,
+            })
+            .await;
+        assert!(matches!(result, Err(Error::NotPermitted(PacketType::Send))));
+
+        // Commands that need no special permissions are always sent
+        drop(tx.send(Who {}));
+        let cmd = server.recv().await.unwrap();
+        assert_eq!(cmd["type"], "who");
+
+        for check in [PermissionCheck::Warn, PermissionCheck::Off] {
+            let (tx, mut server) = connect_with(check).await;
+            drop(tx.send(ban()));
+            let cmd = server.recv().await.unwrap();
+            assert_eq!(cmd["type"], "ban");
+        }
+    }
+
+    #[tokio::test]
+    async fn slow_mode_delays_messages() {
+        let (mut conn, mut server) = connect(Duration::from_secs(10)).await;
+        conn.set_slow_mode(Some(SlowMode::new()));
+        let tx = conn.tx().clone();
+        tokio::spawn(async move { while conn.recv().await.is_ok() {} });
+
+        let send = |content: &str| {
+            tx.send_only(crate::api::Send {
+                content: content.to_string(),
+                parent: None,
+            })
+            .unwrap();
+        };
+
+        let first = tx.send(crate::api::Send {
+            content: "first".to_string(),
+            parent: None,
+        });
+        let cmd = server.recv().await.unwrap();
+        server
+            .send(serde_json::json!({
+                "id": cmd["id"],
+                "type": "send-reply",
+                "error": "throttled",
+                "throttled": true,
+                "throttled_reason": "slow mode: 200ms",
+            }))
+            .await;
+        assert!(first.await.is_err());
+        let info = tx.debug_info().await.unwrap();
+        assert_eq!(info.slow_mode_interval, Some(Duration::from_millis(200)));
+
+        let start = tokio::time::Instant::now();
+        send("second");
+        send("third");
+        tx.send_only(Ping { time: Time(1) }).unwrap();
+        let cmds = [
+            server.recv().await.unwrap(),
+            server.recv().await.unwrap(),
+            server.recv().await.unwrap(),
+        ];
+        let elapsed = start.elapsed();
+        // The ping doesn't overtake the delayed message
+        assert_eq!(cmds.map(|c| c["type"].clone()), ["send", "send", "ping"]);
+        assert!(elapsed >= Duration::from_millis(200), "{elapsed:?}");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn rate_limiter() {
+        let secs = Duration::from_secs;
+        let config = SendRate::new()
+            .with_burst(3)
+            .with_interval(secs(1))
+            .with_backoff(secs(10));
+        let start = tokio::time::Instant::now();
+        let mut limiter = RateLimiter::new(config, start);
+
+        // The burst may be sent right away
+        for _ in 0..3 {
+            assert_eq!(limiter.next_send(start), start);
+            limiter.on_send(start);
+        }
+        assert_eq!(limiter.next_send(start), start + secs(1));
+
+        // Afterwards, the budget is regained one command per interval
+        let now = start + secs(1);
+        limiter.on_send(now);
+        assert_eq!(limiter.next_send(now), now + secs(1));
+
+        // The full budget is back after idling for long enough
+        let now = now + secs(3);
+        assert_eq!(limiter.next_send(now), now);
+        limiter.on_send(now);
+        limiter.on_send(now);
+        assert_eq!(limiter.next_send(now), now);
+
+        // Throttling empties the budget and backs off
+        limiter.on_throttled(now);
+        assert_eq!(limiter.next_send(now), now + secs(10));
+        let now = now + secs(10);
+        limiter.on_send(now);
+        assert_eq!(limiter.next_send(now), now + secs(1));
+    }
+
+    #[tokio::test]
+    async fn send_rate_delays_commands_but_not_ping_replies() {
+        let (mut conn, mut server) = connect(Duration::from_secs(10)).await;
+        let rate = SendRate::new()
+            .with_burst(2)
+            .with_interval(Duration::from_millis(100));
+        conn.set_send_rate(Some(rate));
+        assert_eq!(conn.send_rate(), Some(rate));
+        let tx = conn.tx().clone();
+        tokio::spawn(async move { while conn.recv().await.is_ok() {} });
+
+        let start = tokio::time::Instant::now();
+        for time in 1..=4 {
+            tx.send_only(Ping { time: Time(time) }).unwrap();
+        }
+        let first = [server.recv().await.unwrap(), server.recv().await.unwrap()];
+        assert_eq!(first.map(|c| c["data"]["time"].clone()), [1, 2]);
+        assert_eq!(tx.debug_info().await.unwrap().pending_sends, 2);
+        assert_eq!(tx.pending_sends(), 2);
+
+        // Replies to the server's pings skip the queue
+        server.send(ping_event(10)).await;
+        let reply = server.recv().await.unwrap();
+        assert_eq!(reply["type"], "ping-reply");
+        assert_eq!(reply["data"]["time"], 10);
+
+        let rest = [server.recv().await.unwrap(), server.recv().await.unwrap()];
+        assert_eq!(rest.map(|c| c["data"]["time"].clone()), [3, 4]);
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(200), "{elapsed:?}");
+        assert_eq!(tx.debug_info().await.unwrap().pending_sends, 0);
+        assert_eq!(tx.pending_sends(), 0);
+    }
+
+    #[tokio::test]
+    async fn debug_info() {
+        let (mut conn, mut server) = connect(Duration::from_secs(10)).await;
+        conn.set_on_malformed(MalformedPolicy::Skip);
+        let tx = conn.tx().clone();
+        tokio::spawn(async move { while conn.recv().await.is_ok() {} });
+
+        let reply1 = tx.send(Ping { time: Time(1) });
+        let reply2 = tx.send(Ping { time: Time(2) });
+        let cmd1 = server.recv().await.unwrap();
+        let cmd2 = server.recv().await.unwrap();
+
+        server.0.send(Message::Text("{".to_string())).await.unwrap();
+        server
+            .send(serde_json::json!({
+                "id": cmd1["id"],
+                "type": "ping-reply",
+                "data": { "time": 1 },
+                "throttled": true,
+                "throttled_reason": "slow down",
+            }))
+            .await;
+        reply1.await.unwrap();
+
+        let info = tx.debug_info().await.unwrap();
+        assert_eq!(
+            info,
+            DebugInfo {
+                pending_replies: 1,
+                malformed_packets: 1,
+                throttled_replies: 1,
+                throttled: Some("slow down".to_string()),
+                slow_mode_interval: None,
+                pending_sends: 0,
+                disconnect_pending: false,
+                generation: info.generation,
+                received_packets: 2,
+                parsed_packets: 1,
+                diagnostics: DiagnosticCounts {
+                    info: 0,
+                    warning: 1,
+                    error: 1,
+                },
+                connect_timings: ConnectTimings::default(),
+            }
+        );
+
+        server
+            .send(serde_json::json!({
+                "id": cmd2["id"],
+                "type": "ping-reply",
+                "data": { "time": 2 },
+            }))
+            .await;
+        reply2.await.unwrap();
+
+        let info = tx.debug_info().await.unwrap();
+        assert_eq!(info.pending_replies, 0);
+        assert_eq!(info.throttled_replies, 1);
+        assert_eq!(info.throttled, None);
+    }
+
+    /// Wait until the connection processed all packets sent so far.
+    async fn sync(tx: &ConnTx, server: &mut Server) {
+        let reply = tx.send(Ping { time: Time(0) });
+        let cmd = server.recv().await.unwrap();
+        server
+            .send(serde_json::json!({
+                "id": cmd["id"],
+                "type": "ping-reply",
+                "data": { "time": 0 },
+            }))
+            .await;
+        reply.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn diagnostics() {
+        let (mut conn, mut server) = connect(Duration::from_secs(10)).await;
+        conn.set_on_malformed(MalformedPolicy::Skip);
+        let tx = conn.tx().clone();
+        tokio::spawn(async move { while conn.recv().await.is_ok() {} });
+
+        let reply1 = tx.send(Ping { time: Time(1) });
+        let reply2 = tx.send(Ping { time: Time(2) });
+        let cmd1 = server.recv().await.unwrap();
+        let cmd2 = server.recv().await.unwrap();
+        server
+            .send(serde_json::json!({
+                "id": cmd1["id"],
+                "type": "ping-reply",
+                "data": { "time": 1 },
+                "throttled": true,
+                "throttled_reason": "slow down",
+            }))
+            .await;
+        server
+            .send(serde_json::json!({
+                "id": cmd2["id"],
+                "type": "ping-reply",
+                "error": "nope",
+            }))
+            .await;
+        reply1.await.unwrap();
+        reply2.await.unwrap_err();
+
+        let packets = [
+            serde_json::json!({ "id": "1", "type": "staff-invade-reply", "data": {} }),
+            serde_json::json!({ "id": "2", "type": "frobnicate-event", "data": {} }),
+            serde_json::json!({ "type": "nick-event", "data": { "to": 42 } }),
+        ];
+        for packet in packets {
+            server.send(packet).await;
+        }
+        server.0.send(Message::Text("{".to_string())).await.unwrap();
+        sync(&tx, &mut server).await;
+
+        let diagnostics = tx.drain_diagnostics().await.unwrap();
+        let summary = diagnostics
+            .iter()
+            .map(|d| (d.severity, d.packet_id.as_deref(), d.packet_type))
+            .collect::<Vec<_>>();
+        let id1 = cmd1["id"].as_str();
+        let id2 = cmd2["id"].as_str();
+        assert_eq!(
+            summary,
+            [
+                (Severity::Warning, id1, Some(PacketType::PingReply)),
+                (Severity::Warning, id2, Some(PacketType::PingReply)),
+                (
+                    Severity::Info,
+                    Some("1"),
+                    Some(PacketType::StaffInvadeReply)
+                ),
+                (Severity::Warning, Some("2"), None),
+                (Severity::Error, None, Some(PacketType::NickEvent)),
+                (Severity::Error, None, None),
+            ]
+        );
+        assert_eq!(diagnostics[0].message, "Throttled: slow down");
+        assert_eq!(diagnostics[1].message, "Command failed: nope");
+        assert!(diagnostics[3].message.contains("frobnicate-event"));
+
+        // Drained diagnostics are gone, but still counted
+        assert!(tx.drain_diagnostics().await.unwrap().is_empty());
+        let counts = tx.debug_info().await.unwrap().diagnostics;
+        assert_eq!(
+            counts,
+            DiagnosticCounts {
+                info: 1,
+                warning: 3,
+                error: 2,
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn diagnostics_drop_oldest() {
+        let (mut conn, mut server) = connect(Duration::from_secs(10)).await;
+        conn.set_on_malformed(MalformedPolicy::Skip);
+        let tx = conn.tx().clone();
+        tokio::spawn(async move { while conn.recv().await.is_ok() {} });
+
+        let total = DIAGNOSTICS_LEN + 5;
+        for i in 0..total {
+            let packet = serde_json::json!({ "id": i.to_string(), "type": "unknown" });
+            server.send(packet).await;
+        }
+        sync(&tx, &mut server).await;
+
+        let diagnostics = tx.drain_diagnostics().await.unwrap();
+        let ids = diagnostics
+            .iter()
+            .map(|d| d.packet_id.clone().unwrap())
+            .collect::<Vec<_>>();
+        let expected = (5..total).map(|i| i.to_string()).collect::<Vec<_>>();
+        assert_eq!(ids, expected);
+        let counts = tx.debug_info().await.unwrap().diagnostics;
+        assert_eq!(counts.warning, total as u64);
+    }
+
+    #[tokio::test]
+    async fn truncated_replies_are_diagnosed() {
+        let (mut conn, mut server) = connect(Duration::from_secs(10)).await;
+        let tx = conn.tx().clone();
+        tokio::spawn(async move { while conn.recv().await.is_ok() {} });
+
+        server.join(hello(false, None)).await;
+        for n in 1..30 {
+            let data = serde_json::to_value(session(n)).unwrap();
+            server
+                .send(serde_json::json!({ "type": "join-event", "data": data }))
+                .await;
+        }
+
+        let log = |ids: &[u64]| {
+            let log = ids
+                .iter()
+                .map(|id| message_json(&Snowflake(*id).to_string()))
+                .collect::<Vec<_>>();
+            serde_json::json!(log)
+        };
+        let before = Some(MessageId(Snowflake(10)));
+        let replies = [
+            // Complete and start of log, not diagnosed
+            (Log { n: 2, before }, log(&[8, 9]), before),
+            (Log { n: 2, before }, log(&[]), before),
+            // Short
+            (Log { n: 3, before }, log(&[8, 9]), before),
+            // Mismatched
+            (Log { n: 2, before }, log(&[8, 9]), None),
+        ];
+        let mut ids = vec![];
+        for (cmd, log, before) in replies {
+            let reply = tx.send(cmd);
+            let cmd = server.recv().await.unwrap();
+            let data = serde_json::json!({ "log": log, "before": before });
+            server
+                .send(serde_json::json!({ "id": cmd["id"], "type": "log-reply", "data": data }))
+                .await;
+            reply.await.unwrap();
+            ids.push(cmd["id"].as_str().unwrap().to_string());
+        }
+
+        // 30 sessions are known, so 10 are implausibly few
+        let listing = (0..10).map(session).collect::<Vec<_>>();
+        let reply = tx.send(Who {});
+        let cmd = server.recv().await.unwrap();
+        let data = serde_json::json!({ "listing": listing });
+        server
+            .send(serde_json::json!({ "id": cmd["id"], "type": "who-reply", "data": data }))
+            .await;
+        reply.await.unwrap();
+        sync(&tx, &mut server).await;
+
+        let diagnostics = tx.drain_diagnostics().await.unwrap();
+        let summary = diagnostics
+            .iter()
+            .map(|d| (d.severity, d.packet_id.as_deref(), d.packet_type))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            summary,
+            [
+                (Severity::Info, Some(&*ids[2]), Some(PacketType::LogReply)),
+                (
+                    Severity::Warning,
+                    Some(&*ids[3]),
+                    Some(PacketType::LogReply)
+                ),
+                (
+                    Severity::Warning,
+                    cmd["id"].as_str(),
+                    Some(PacketType::WhoReply)
+                ),
+            ]
+        );
+        assert_eq!(
+            diagnostics[0].message,
+            "Log reply contains 2 of 3 requested messages, \
+             it reached the start of the log or was truncated"
+        );
+        assert_eq!(
+            diagnostics[2].message,
+            "Who reply lists 10 sessions, but 30 were known, it was probably truncated"
+        );
+    }
+
+    #[tokio::test]
+    async fn status() {
+        let (mut conn, mut server) = connect(Duration::from_millis(100)).await;
+        let status = conn.status().clone();
+        assert_eq!(status.phase(), ConnPhase::Joining);
+        assert_eq!(status.connections(), 1);
+        assert_eq!(status.received_packets(), 0);
+        assert_eq!(status.last_packet(), None);
+        assert_eq!(status.last_pong(), None);
+        assert_eq!(status.last_error(), None);
+
+        server.join(hello(false, None)).await;
+        while let State::Joining(_) = conn.state() {
+            conn.recv().await.unwrap();
+        }
+        assert_eq!(status.phase(), ConnPhase::Joined);
+        assert_eq!(status.received_packets(), 2);
+        let joined_at = status.last_packet().unwrap();
+
+        // The connection pings the server once the timeout has passed
+        let pong = async {
+            while status.last_pong().is_none() {
+                conn.recv().await.unwrap();
+            }
+        };
+        let reply = async {
+            loop {
+                let packet = server.recv().await.unwrap();
+                if packet["type"] == "ping" {
+                    let reply = serde_json::json!({
+                        "id": packet["id"],
+                        "type": "ping-reply",
+                        "data": { "time": packet["data"]["time"] },
+                    });
+                    server.send(reply).await;
+                    break;
+                }
+            }
+        };
+        tokio::join!(pong, reply);
+        assert!(status.last_pong_age().unwrap() < Duration::from_secs(10));
+        assert!(status.last_packet().unwrap() >= joined_at);
+        assert!(status.received_packets() >= 2);
+
+        drop(server);
+        conn.recv().await.unwrap_err();
+        assert_eq!(status.phase(), ConnPhase::Disconnected);
+        assert!(status.last_error().is_some());
+
+        // A new connection continues where the old one left off
+        let (mut conn, _server) = connect(Duration::from_secs(10)).await;
+        let received = status.received_packets();
+        conn.set_status(status.clone());
+        assert_eq!(status.connections(), 2);
+        assert_eq!(status.phase(), ConnPhase::Joining);
+        assert_eq!(status.received_packets(), received);
+    }
+
+    #[tokio::test]
+    async fn send_only_does_not_track_replies() {
+        let (mut conn, _server) = connect(Duration::from_secs(10)).await;
+        let tx = conn.tx().clone();
+        for i in 0..100 {
+            tx.send_only(Ping { time: Time(i) }).unwrap();
+        }
+        let reply = tx.send(Ping { time: Time(100) });
+
+        let _ = tokio::time::timeout(Duration::from_millis(100), conn.recv()).await;
+        assert_eq!(conn.replies.len(), 1);
+
+        drop(conn);
+        assert!(matches!(
+            tx.send_only(Ping { time: Time(0) }),
+            Err(Error::ConnectionClosed)
+        ));
+        assert!(matches!(reply.await, Err(Error::ConnectionClosed)));
+        assert!(matches!(
+            tx.send(Ping { time: Time(0) }).await,
+            Err(Error::ConnectionClosed)
+        ));
+    }
+
+    #[tokio::test]
+    async fn send_raw_value_sends_unmodeled_types() {
+        let (mut conn, mut server) = connect(Duration::from_secs(10)).await;
+        let tx = conn.tx().clone();
+        tokio::spawn(async move { while conn.recv().await.is_ok() {} });
+
+        let data = serde_json::json!({ "anything": [1, 2] });
+        tx.send_raw_value("frobnicate", data.clone()).unwrap();
+        let packet = loop {
+            let packet = server.recv().await.unwrap();
+            if packet["type"] == "frobnicate" {
+                break packet;
+            }
+        };
+        assert_eq!(packet["data"], data);
+        assert!(packet["id"].is_string());
+    }
+
+    #[tokio::test]
+    async fn mismatched_reply_type_times_out() {
+        let (mut conn, mut server) = connect(Duration::from_millis(200)).await;
+        let tx = conn.tx().clone();
+        tokio::spawn(async move { while conn.recv().await.is_ok() {} });
+
+        let reply = tx.send(Nick {
+            name: "TestBot".to_string(),
+        });
+        let cmd = server.recv().await.unwrap();
+        assert_eq!(cmd["type"], "nick");
+
+        // A stale reply to some other command that happens to share the id
+        server
+            .send(serde_json::json!({
+                "id": cmd["id"],
+                "type": "who-reply",
+                "data": { "listing": [] },
+            }))
+            .await;
+
+        assert!(matches!(reply.await, Err(Error::CommandTimedOut)));
+    }
+
+    /// Join, rename ourselves to each of `names` and have the server answer
+    /// with the packets returned by `script`, which is given the ids of the
+    /// nick commands. Returns our nick afterwards.
+    async fn rename(
+        names: &[&str],
+        script: impl FnOnce(&[serde_json::Value]) -> Vec<serde_json::Value>,
+    ) -> String {
+        let (mut conn, mut server) = connect(Duration::from_secs(10)).await;
+        server.join(hello(false, None)).await;
+        while let State::Joining(_) = conn.state() {
+            conn.recv().await.unwrap();
+        }
+
+        let tx = conn.tx().clone();
+        for name in names {
+            let name = name.to_string();
+            tx.send_only(Nick { name }).unwrap();
+        }
+        let mut ids = vec![];
+        while ids.len() < names.len() {
+            tokio::select! {
+                _ = conn.recv() => panic!("unexpected packet"),
+                cmd = server.recv() => ids.push(cmd.unwrap()["id"].clone()),
+            }
+        }
+
+        for packet in script(&ids) {
+            server.send(packet).await;
+            conn.recv().await.unwrap();
+        }
+        conn.state().joined().unwrap().session.name.clone()
     }
-}
 
-#[derive(Debug)]
-pub struct Conn {
-    ws: WsStream,
-    last_id: usize,
-    replies: Replies<String, ParsedPacket>,
+    fn own_nick_reply(id: &serde_json::Value, from: &str, to: &str) -> serde_json::Value {
+        serde_json::json!({
+            "id": id,
+            "type": "nick-reply",
+            "data": { "session_id": "session", "id": "agent:abc", "from": from, "to": to },
+        })
+    }
 
-    conn_tx: ConnTx,
-    cmd_rx: mpsc::UnboundedReceiver<ConnCommand>,
+    fn own_nick_event(from: &str, to: &str) -> serde_json::Value {
+        serde_json::json!({
+            "type": "nick-event",
+            "data": { "session_id": "session", "id": "agent:abc", "from": from, "to": to },
+        })
+    }
 
-    // The websocket server may send a pong frame with arbitrary payload
-    // unprompted at any time (see RFC 6455 5.5.3). Because of this, we can't
-    // just remember the last pong payload.
-    last_ping: Instant,
-    last_ws_ping_payload: Option<Vec<u8>>,
-    last_ws_ping_replied_to: bool,
-    last_euph_ping_payload: Option<Time>,
-    last_euph_ping_replied_to: bool,
+    #[tokio::test]
+    async fn own_nick_order() {
+        // A single rename, reported twice
+        let nick = rename(&["A"], |ids| {
+            vec![own_nick_reply(&ids[0], "", "A"), own_nick_event("", "A")]
+        });
+        assert_eq!(nick.await, "A");
+        let nick = rename(&["A"], |ids| {
+            vec![own_nick_event("", "A"), own_nick_reply(&ids[0], "", "A")]
+        });
+        assert_eq!(nick.await, "A");
 
-    state: State,
-}
+        // Two renames whose packets are interleaved, with the last packet
+        // always being about the first rename
+        let nick = rename(&["A", "B"], |ids| {
+            vec![own_nick_reply(&ids[1], "A", "B"), own_nick_event("", "A")]
+        });
+        assert_eq!(nick.await, "B");
+        let nick = rename(&["A", "B"], |ids| {
+            vec![
+                own_nick_event("", "A"),
+                own_nick_event("A", "B"),
+                own_nick_reply(&ids[0], "", "A"),
+            ]
+        });
+        assert_eq!(nick.await, "B");
+        let nick = rename(&["A", "B"], |ids| {
+            vec![
+                own_nick_reply(&ids[0], "", "A"),
+                own_nick_event("A", "B"),
+                own_nick_reply(&ids[1], "A", "B"),
+                own_nick_event("", "A"),
+            ]
+        });
+        assert_eq!(nick.await, "B");
 
-enum ConnEvent {
-    Ws(Option<tungstenite::Result<tungstenite::Message>>),
-    Cmd(Option<ConnCommand>),
-    Ping,
-}
+        // Renames we didn't ask for only apply if they continue from our nick
+        let nick = rename(&["A"], |ids| {
+            vec![
+                own_nick_reply(&ids[0], "", "A"),
+                own_nick_event("", "unrelated"),
+                own_nick_event("A", "renamed"),
+            ]
+        });
+        assert_eq!(nick.await, "renamed");
+    }
 
-impl Conn {
-    pub fn tx(&self) -> &ConnTx {
-        &self.conn_tx
+    fn session(n: usize) -> SessionView {
+        session_on(n, "heim.1", "era")
     }
 
-    pub fn state(&self) -> &State {
-        &self.state
+    fn session_on(n: usize, server_id: &str, server_era: &str) -> SessionView {
+        SessionViewBuilder::new(format!("user{n}"))
+            .with_id(UserId(format!("agent:{n}")))
+            .with_session_id(SessionId(format!("session{n}")))
+            .with_server(server_id, server_era)
+            .build()
     }
 
-    pub async fn recv(&mut self) -> Result<ParsedPacket> {
-        loop {
-            self.replies.purge();
-            let timeout = self.replies.timeout();
+    fn send_event(sender: SessionView) -> Data {
+        let message = MessageBuilder::new("hello")
+            .with_id(MessageId(Snowflake(0)))
+            .with_sender(sender)
+            .build();
+        Data::SendEvent(SendEvent(Arc::new(message)))
+    }
 
-            // All of these functions are cancel-safe.
-            let event = select! {
-                msg = self.ws.next() => ConnEvent::Ws(msg),
-                cmd = self.cmd_rx.recv() => ConnEvent::Cmd(cmd),
-                _ = Self::await_next_ping(self.last_ping, timeout) => ConnEvent::Ping,
-            };
+    #[test]
+    fn shared_state_is_only_cloned_on_change() {
+        let mut state = Arc::new(State::Joined(joined((1..=1000).map(session))));
 
-            match event {
-                ConnEvent::Ws(msg) => {
-                    if let Some(packet) = self.on_ws(msg).await? {
-                        break Ok(packet);
-                    }
-                }
-                ConnEvent::Cmd(Some(cmd)) => self.on_cmd(cmd).await?,
-                ConnEvent::Cmd(None) => unreachable!("self contains a ConnTx"),
-                ConnEvent::Ping => self.on_ping().await?,
+        // Consumers hold on to the previous snapshot while new packets arrive
+        let mut clones = 0;
+        for n in 1..=1000 {
+            let snapshot = state.clone();
+            State::update(&mut state, &send_event(session(n)), Timestamp::now()).unwrap();
+            if !Arc::ptr_eq(&snapshot, &state) {
+                clones += 1;
             }
         }
+        assert_eq!(clones, 0);
+
+        // Packets that change the state still clone it exactly once
+        let snapshot = state.clone();
+        State::update(
+            &mut state,
+            &Data::JoinEvent(JoinEvent(session(1001))),
+            Timestamp::now(),
+        )
+        .unwrap();
+        assert!(!Arc::ptr_eq(&snapshot, &state));
+        assert_eq!(snapshot.joined().unwrap().listing.len(), 1000);
+        assert_eq!(state.joined().unwrap().listing.len(), 1001);
+
+        // Senders that aren't in the listing yet are added
+        let snapshot = state.clone();
+        State::update(&mut state, &send_event(session(1002)), Timestamp::now()).unwrap();
+        assert!(!Arc::ptr_eq(&snapshot, &state));
+        assert_eq!(state.joined().unwrap().listing.len(), 1002);
     }
 
-    async fn on_ws(
-        &mut self,
-        msg: Option<tungstenite::Result<tungstenite::Message>>,
-    ) -> Result<Option<ParsedPacket>> {
-        let msg = msg.ok_or(Error::ConnectionClosed)??;
-        match msg {
-            tungstenite::Message::Text(text) => {
-                let packet = serde_json::from_str(&text)?;
-                debug!(target: "euphoxide::conn::full", "Received {packet:?}");
-                let packet = ParsedPacket::from_packet(packet)?;
-                self.on_packet(&packet).await?;
-                return Ok(Some(packet));
-            }
-            tungstenite::Message::Binary(_) => {
-                return Err(Error::ProtocolViolation("unexpected binary ws message"));
-            }
-            tungstenite::Message::Ping(_) => {}
-            tungstenite::Message::Pong(payload) => {
-                if self.last_ws_ping_payload == Some(payload) {
-                    self.last_ws_ping_replied_to = true;
-                }
-            }
-            tungstenite::Message::Close(_) => {}
-            tungstenite::Message::Frame(_) => {}
-        }
-        Ok(None)
+    fn listing(state: &State) -> &Arc<HashMap<SessionId, SessionInfo>> {
+        &state.joined().unwrap().listing
     }
 
-    async fn on_packet(&mut self, packet: &ParsedPacket) -> Result<()> {
-        // Complete pending replies if the packet has an id
-        if let Some(id) = &packet.id {
-            debug!("Resolving pending reply for id {id}");
-            self.replies.complete(id, packet.clone());
+    #[test]
+    fn message_log_updates_share_listing() {
+        let mut joined = joined((1..=1000).map(session));
+        joined.message_log = Some(Arc::new(MessageLog::new(100)));
+        let mut state = Arc::new(State::Joined(joined));
+
+        for n in 1..=1000 {
+            let snapshot = state.clone();
+            State::update(&mut state, &send_event(session(n)), Timestamp::now()).unwrap();
+            assert!(Arc::ptr_eq(listing(&snapshot), listing(&state)));
         }
+        let joined = state.joined().unwrap();
+        assert!(joined.message(&MessageId(Snowflake(0))).is_some());
+    }
 
-        if let Ok(data) = &packet.content {
-            self.on_data(&packet.id, data).await?;
+    #[test]
+    fn message_times_updates_share_listing() {
+        let mut joined = joined((1..=1000).map(session));
+        joined.message_times = Some(Arc::new(MessageTimes::new(MessageTimesConfig::new())));
+        let mut state = Arc::new(State::Joined(joined));
+
+        for n in 1..=1000 {
+            let snapshot = state.clone();
+            State::update(&mut state, &send_event(session(n)), Timestamp::now()).unwrap();
+            assert!(Arc::ptr_eq(listing(&snapshot), listing(&state)));
         }
+        let joined = state.joined().unwrap();
+        assert!(joined.last_message_at(&session(1000).session_id).is_some());
+    }
 
-        Ok(())
+    /// Our own session is `session(0)`.
+    fn joined(listing: impl IntoIterator<Item = SessionView>) -> Joined {
+        listing
+            .into_iter()
+            .fold(
+                JoinedBuilder::new().with_session(session(0)),
+                |joined, s| joined.with_listing_entry(SessionInfo::Full(s)),
+            )
+            .build()
     }
 
-    async fn on_data(&mut self, id: &Option<String>, data: &Data) -> Result<()> {
-        // Play a game of table tennis
-        match data {
-            Data::PingReply(p) => {
-                if self.last_euph_ping_payload.is_some() && self.last_euph_ping_payload == p.time {
-                    self.last_euph_ping_replied_to = true;
-                }
-            }
-            Data::PingEvent(p) => {
-                let reply = PingReply { time: Some(p.time) };
-                self.send_rpl(id.clone(), reply.into()).await?;
-            }
-            _ => {}
-        }
+    fn nick_event(n: usize, to: &str) -> Data {
+        Data::NickEvent(NickEvent {
+            session_id: session(n).session_id,
+            id: session(n).id,
+            from: session(n).name,
+            to: to.to_string(),
+        })
+    }
 
-        // Update internal state
-        match &mut self.state {
-            State::Joining(joining) => {
-                joining.on_data(data)?;
-                if let Some(joined) = joining.joined() {
-                    self.state = State::Joined(joined);
-                }
-            }
-            State::Joined(joined) => joined.on_data(data),
-        }
+    fn partition(server_id: &str, server_era: &str) -> Data {
+        Data::NetworkEvent(NetworkEvent {
+            r#type: "partition".to_string(),
+            server_id: server_id.to_string(),
+            server_era: server_era.to_string(),
+        })
+    }
 
-        // The euphoria server doesn't always disconnect the client when it
-        // would make sense to do so or when the API specifies it should. This
-        // ensures we always disconnect when it makes sense to do so.
-        if matches!(
-            data,
-            Data::DisconnectEvent(_)
-                | Data::LoginEvent(_)
-                | Data::LogoutEvent(_)
-                | Data::LoginReply(LoginReply { success: true, .. })
-                | Data::LogoutReply(_)
-        ) {
-            self.disconnect().await?;
-        }
+    fn names(joined: &Joined) -> Vec<&str> {
+        let mut names = joined
+            .listing
+            .values()
+            .map(|s| s.name())
+            .collect::<Vec<_>>();
+        names.sort_unstable();
+        names
+    }
 
-        Ok(())
+    #[test]
+    fn message_times() {
+        let clock = MockClock::new();
+        let config = MessageTimesConfig::new()
+            .with_per_session(3)
+            .with_max_sessions(2);
+        let mut joined = joined([session(1), session(2)]);
+        joined.message_times = Some(Arc::new(MessageTimes::new(config)));
+        let mut state = Arc::new(State::Joined(joined));
+        let id = |n| session(n).session_id;
+        let window = Duration::from_secs(10);
+
+        let mut send = |n| {
+            let snapshot = state.clone();
+            State::update(&mut state, &send_event(session(n)), clock.now()).unwrap();
+            // Tracking message times requires updating the state every time
+            assert!(!Arc::ptr_eq(&snapshot, &state));
+            clock.advance(Duration::from_secs(2));
+        };
+        send(1);
+        send(1);
+        send(2);
+        send(1);
+        send(1);
+        let now = clock.now();
+        let joined = state.joined().unwrap();
+
+        // Only the last three messages are remembered
+        assert_eq!(joined.recent_message_rate(&id(1), window, now), 0.3);
+        assert_eq!(joined.recent_message_rate(&id(2), window, now), 0.1);
+        assert_eq!(
+            joined.recent_message_rate(&id(1), Duration::from_secs(3), now),
+            1.0 / 3.0
+        );
+        assert_eq!(joined.recent_message_rate(&id(3), window, now), 0.0);
+        assert_eq!(joined.last_message_at(&id(1)), Some(now - 2.seconds()));
+        assert_eq!(joined.last_message_at(&id(2)), Some(now - 6.seconds()));
+
+        // The session that was quiet the longest is evicted
+        State::update(&mut state, &send_event(session(3)), now).unwrap();
+        let joined = state.joined().unwrap();
+        let times = joined.message_times.as_ref().unwrap();
+        assert_eq!(times.tracked_sessions(), 2);
+        assert_eq!(joined.last_message_at(&id(2)), None);
+        assert_eq!(joined.last_message_at(&id(3)), Some(now));
+
+        // Sessions are forgotten when they leave
+        let part = Data::PartEvent(PartEvent(session(1)));
+        State::update(&mut state, &part, now).unwrap();
+        let joined = state.joined().unwrap();
+        assert_eq!(joined.last_message_at(&id(1)), None);
+        assert_eq!(joined.recent_message_rate(&id(1), window, now), 0.0);
+        assert_eq!(joined.message_times.as_ref().unwrap().tracked_sessions(), 1);
+
+        // Without tracking, nothing is known
+        let joined = self::joined([session(1)]);
+        assert_eq!(joined.recent_message_rate(&id(1), window, now), 0.0);
+        assert_eq!(joined.last_message_at(&id(1)), None);
     }
 
-    async fn on_cmd(&mut self, cmd: ConnCommand) -> Result<()> {
-        match cmd {
-            ConnCommand::SendCmd(data, reply_tx) => self.send_cmd(data, reply_tx).await?,
-            ConnCommand::GetState(reply_tx) => {
-                let _ = reply_tx.send(self.state.clone());
-            }
+    #[tokio::test]
+    async fn message_times_follow_config() {
+        let (mut conn, mut server) = connect(Duration::from_secs(10)).await;
+        conn.set_message_times(Some(MessageTimesConfig::default()));
+        server.join(hello(false, None)).await;
+        while let State::Joining(_) = conn.state() {
+            conn.recv().await.unwrap();
         }
-        Ok(())
+        let times = conn.state().joined().unwrap().message_times.as_ref();
+        assert_eq!(times.unwrap().config(), MessageTimesConfig::default());
+
+        let config = MessageTimesConfig::new().with_per_session(1);
+        conn.set_message_times(Some(config));
+        let times = conn.state().joined().unwrap().message_times.as_ref();
+        assert_eq!(times.unwrap().config(), config);
+
+        conn.set_message_times(None);
+        assert!(conn.state().joined().unwrap().message_times.is_none());
     }
 
-    async fn await_next_ping(last_ping: Instant, timeout: Duration) {
-        let next_ping = last_ping + timeout;
-        tokio::time::sleep_until(next_ping.into()).await;
+    #[test]
+    fn message_log() {
+        let id = |id| MessageId(Snowflake(id));
+        let msg = |n: u64, parent: Option<u64>, content: &str| {
+            let mut msg = message_json(&Snowflake(n).to_string());
+            msg["parent"] = serde_json::json!(parent.map(|p| Snowflake(p).to_string()));
+            msg["content"] = serde_json::json!(content);
+            serde_json::from_value::<EuphMessage>(msg).unwrap()
+        };
+        let ids = |msgs: Vec<&EuphMessage>| msgs.iter().map(|m| m.id.0 .0).collect::<Vec<_>>();
+        let now = Timestamp::now();
+
+        // Without tracking, nothing is known
+        let mut joined = joined([]);
+        joined.on_data(
+            &Data::SendEvent(SendEvent(Arc::new(msg(1, None, "a")))),
+            now,
+        );
+        assert!(joined.message(&id(1)).is_none());
+        assert!(joined.children_of(&id(1)).is_empty());
+        assert!(joined.latest(5).is_empty());
+
+        joined.message_log = Some(Arc::new(MessageLog::new(3)));
+        let packets = [
+            Data::SendEvent(SendEvent(Arc::new(msg(1, None, "a")))),
+            Data::SendReply(SendReply(msg(2, Some(1), "b"))),
+            Data::GetMessageReply(GetMessageReply(msg(3, Some(1), "c"))),
+            Data::EditMessageEvent(EditMessageEvent {
+                edit_id: Snowflake(10),
+                message: msg(2, Some(1), "edited"),
+            }),
+        ];
+        for data in &packets {
+            joined.on_data(data, now);
+        }
+        assert_eq!(joined.message(&id(2)).unwrap().content, "edited");
+        assert_eq!(ids(joined.children_of(&id(1))), [2, 3]);
+        assert!(joined.children_of(&id(2)).is_empty());
+        assert_eq!(ids(joined.latest(2)), [2, 3]);
+        assert_eq!(ids(joined.latest(5)), [1, 2, 3]);
+
+        // The oldest messages are dropped, even if they were just added
+        let reply = LogReply {
+            log: vec![msg(0, None, "z"), msg(4, None, "d")],
+            before: None,
+        };
+        joined.on_data(&Data::LogReply(reply), now);
+        assert_eq!(ids(joined.latest(5)), [2, 3, 4]);
+        assert!(joined.message(&id(0)).is_none());
+        assert!(joined.message(&id(1)).is_none());
+        assert_eq!(joined.message_log.as_ref().unwrap().len(), 3);
+
+        // Packets that don't affect the log share it with older snapshots
+        let snapshot = joined.clone();
+        joined.on_data(&nick_event(7, "someone"), now);
+        let (old, new) = (&snapshot.message_log, &joined.message_log);
+        assert!(Arc::ptr_eq(old.as_ref().unwrap(), new.as_ref().unwrap()));
+
+        // Messages only copy the log, not the messages within it
+        let msg5 = Arc::new(msg(5, None, "e"));
+        joined.on_data(&Data::SendEvent(SendEvent(msg5.clone())), now);
+        let (old, new) = (&snapshot.message_log, &joined.message_log);
+        assert!(!Arc::ptr_eq(old.as_ref().unwrap(), new.as_ref().unwrap()));
+        assert_eq!(ids(snapshot.latest(5)), [2, 3, 4]);
+        assert_eq!(ids(joined.latest(5)), [3, 4, 5]);
+        assert!(std::ptr::eq(joined.message(&id(5)).unwrap(), &*msg5));
     }
 
-    async fn on_ping(&mut self) -> Result<()> {
-        debug!("Checking ping replies and sending new pings");
+    #[tokio::test]
+    async fn connect_timings() {
+        let ms = Duration::from_millis;
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
 
-        // Check previous pings
-        if self.last_ws_ping_payload.is_some() && !self.last_ws_ping_replied_to {
-            debug!("Server did not respond to websocket ping, disconnecting");
-            self.disconnect().await?;
+        // Each phase is delayed by a different amount, so we can tell whether
+        // the delays are attributed to the right phases. Since the test uses
+        // real time, the phases may take arbitrarily longer under load, so
+        // only lower bounds are checked.
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            tokio::time::sleep(ms(100)).await;
+            let mut server = Server(tokio_tungstenite::accept_async(stream).await.unwrap());
+            tokio::time::sleep(ms(400)).await;
+            server
+                .send(serde_json::json!({ "type": "hello-event", "data": hello(false, None) }))
+                .await;
+            tokio::time::sleep(ms(200)).await;
+            server
+                .send(serde_json::json!({ "type": "snapshot-event", "data": snapshot() }))
+                .await;
+            while server.recv().await.is_some() {}
+        });
+
+        let domain = addr.to_string();
+        let start = Instant::now();
+        let connected = Conn::connect_insecure(&domain, "test", false, None, ms(5000));
+        let (mut conn, _) = connected.await.unwrap();
+        let timings = conn.connect_timings();
+        assert!(timings.hello.is_none() && timings.snapshot.is_none());
+        while let State::Joining(_) = conn.state() {
+            conn.recv().await.unwrap();
         }
-        if self.last_euph_ping_payload.is_some() && !self.last_euph_ping_replied_to {
-            debug!("Server did not respond to euph ping, disconnecting");
-            self.disconnect().await?;
+        let elapsed = start.elapsed();
+
+        let timings = conn.connect_timings();
+        let handshake = timings.handshake.unwrap();
+        let to_hello = timings.hello.unwrap();
+        let to_snapshot = timings.snapshot.unwrap();
+        assert!(handshake >= ms(100), "{timings}");
+        assert!(to_hello >= ms(400), "{timings}");
+        assert!(to_snapshot >= ms(200), "{timings}");
+        assert_eq!(timings.total(), Some(handshake + to_hello + to_snapshot));
+        // No time is attributed to more than one phase
+        assert!(timings.total().unwrap() <= elapsed, "{timings}");
+        assert_eq!(conn.debug_info().connect_timings, timings);
+
+        // Wrapped connections don't know about their handshake
+        let (mut conn, mut server) = connect(ms(5000)).await;
+        server.join(hello(false, None)).await;
+        while let State::Joining(_) = conn.state() {
+            conn.recv().await.unwrap();
         }
+        let timings = conn.connect_timings();
+        assert!(timings.handshake.is_none() && timings.total().is_none());
+        assert!(timings.hello.is_some() && timings.snapshot.is_some());
+        assert!(timings.to_string().starts_with("handshake -, hello "));
+    }
 
-        let now = Timestamp::now();
+    #[tokio::test]
+    async fn message_log_follows_config() {
+        let (mut conn, mut server) = connect(Duration::from_secs(10)).await;
+        conn.set_message_log(Some(10));
+        let mut snapshot = serde_json::to_value(snapshot()).unwrap();
+        snapshot["log"] = serde_json::json!([message_json(&Snowflake(1).to_string())]);
+        server
+            .send(serde_json::json!({ "type": "hello-event", "data": hello(false, None) }))
+            .await;
+        server
+            .send(serde_json::json!({ "type": "snapshot-event", "data": snapshot }))
+            .await;
+        while let State::Joining(_) = conn.state() {
+            conn.recv().await.unwrap();
+        }
 
-        // Send new ws ping
-        let ws_payload = now.as_millisecond().to_be_bytes().to_vec();
-        self.last_ws_ping_payload = Some(ws_payload.clone());
-        self.last_ws_ping_replied_to = false;
-        self.ws.send(tungstenite::Message::Ping(ws_payload)).await?;
+        // The snapshot's log is tracked
+        let joined = conn.state().joined().unwrap();
+        assert_eq!(joined.message_log.as_ref().unwrap().capacity(), 10);
+        assert!(joined.message(&MessageId(Snowflake(1))).is_some());
 
-        // Send new euph ping
-        let euph_payload = Time::from_timestamp(now);
-        self.last_euph_ping_payload = Some(euph_payload);
-        self.last_euph_ping_replied_to = false;
-        let (tx, _) = oneshot::channel();
-        self.send_cmd(Ping { time: euph_payload }.into(), tx)
-            .await?;
+        // Messages received afterwards are tracked too
+        let msg = message_json(&Snowflake(2).to_string());
+        server
+            .send(serde_json::json!({ "type": "send-event", "data": msg }))
+            .await;
+        conn.recv().await.unwrap();
+        assert_eq!(conn.state().joined().unwrap().latest(5).len(), 2);
 
-        self.last_ping = Instant::now();
+        conn.set_message_log(Some(1));
+        let log = conn.state().joined().unwrap().message_log.as_ref();
+        assert!(log.unwrap().is_empty());
 
-        Ok(())
+        conn.set_message_log(None);
+        assert!(conn.state().joined().unwrap().message_log.is_none());
     }
 
-    async fn send_cmd(
-        &mut self,
-        data: Data,
-        reply_tx: oneshot::Sender<PendingReply<ParsedPacket>>,
-    ) -> Result<()> {
-        // Overkill of universe-heat-death-like proportions
-        self.last_id = self.last_id.wrapping_add(1);
-        let id = format!("{}", self.last_id);
+    #[test]
+    fn partition_removes_affected_sessions() {
+        let mut joined = joined([
+            session_on(1, "heim.1", "era1"),
+            session_on(2, "heim.1", "era1"),
+            session_on(3, "heim.1", "era2"),
+            session_on(4, "heim.2", "era1"),
+            session_on(5, "heim.2", "era2"),
+        ]);
+        // A session we only know from its nick-event
+        joined.on_data(&nick_event(6, "partial"), Timestamp::now());
+        assert_eq!(joined.listing.len(), 6);
 
-        let packet = ParsedPacket {
-            id: Some(id.clone()),
-            r#type: data.packet_type(),
-            content: Ok(data),
-            throttled: None,
-        }
-        .into_packet()?;
-        debug!(target: "euphoxide::conn::full", "Sending {packet:?}");
+        joined.on_data(&partition("heim.1", "era1"), Timestamp::now());
+        assert_eq!(names(&joined), ["user3", "user4", "user5"]);
 
-        let msg = tungstenite::Message::Text(serde_json::to_string(&packet)?);
-        self.ws.send(msg).await?;
+        // Other types of network-event are ignored
+        joined.on_data(
+            &Data::NetworkEvent(NetworkEvent {
+                r#type: "unknown".to_string(),
+                server_id: "heim.2".to_string(),
+                server_era: "era1".to_string(),
+            }),
+            Timestamp::now(),
+        );
+        assert_eq!(joined.listing.len(), 3);
 
-        let _ = reply_tx.send(self.replies.wait_for(id));
+        // Unaffected servers don't cause any changes
+        joined.on_data(&partition("heim.3", "era1"), Timestamp::now());
+        assert_eq!(joined.listing.len(), 3);
+    }
 
-        Ok(())
+    #[test]
+    fn who_reply_is_ignored() {
+        let mut joined = joined([session(1), session(2)]);
+        let now = Timestamp::now() - 1.hour();
+        let who_reply = WhoReply {
+            listing: vec![session(3)],
+        };
+        joined.on_data(&Data::WhoReply(who_reply), now);
+        assert_eq!(names(&joined), ["user1", "user2"]);
+        assert_eq!(joined.last_who, Some(now));
     }
 
-    async fn send_rpl(&mut self, id: Option<String>, data: Data) -> Result<()> {
-        let packet = ParsedPacket {
-            id,
-            r#type: data.packet_type(),
-            content: Ok(data),
-            throttled: None,
+    #[test]
+    fn freshness() {
+        let now = Timestamp::now();
+        let secs = Duration::from_secs;
+        let present = session(1).id;
+        let absent = session(2).id;
+        let own = session(0).id;
+
+        let fresh = |since: i64, last_who: Option<i64>, requirements| {
+            let mut joined = joined([session(1)]);
+            joined.since = now - since.seconds();
+            joined.last_who = last_who.map(|s| now - s.seconds());
+            match joined.ensure_fresh(requirements, now) {
+                Ok(()) => "fresh",
+                Err(StaleStateError::StateTooOld { .. }) => "too old",
+                Err(StaleStateError::NoRecentWho { .. }) => "no recent who",
+                Err(StaleStateError::ReconnectedRecently { .. }) => "reconnected",
+                Err(StaleStateError::TargetNotPresent(_)) => "not present",
+            }
+        };
+
+        let none = FreshnessRequirements::default();
+        let connected = FreshnessRequirements {
+            min_connected: Some(secs(60)),
+            ..none
+        };
+        let who = FreshnessRequirements {
+            require_recent_who: Some(secs(60)),
+            ..none
+        };
+        let state = FreshnessRequirements {
+            max_state_age: Some(secs(60)),
+            ..none
+        };
+        let target = |id| FreshnessRequirements {
+            target: Some(id),
+            ..none
+        };
+        let all = FreshnessRequirements {
+            max_state_age: Some(secs(60)),
+            require_recent_who: Some(secs(60)),
+            min_connected: Some(secs(60)),
+            target: Some(&present),
+        };
+
+        let cases = [
+            (0, None, none, "fresh"),
+            (3600, None, none, "fresh"),
+            (30, None, connected, "reconnected"),
+            (120, None, connected, "fresh"),
+            (120, None, who, "no recent who"),
+            (120, Some(90), who, "no recent who"),
+            (120, Some(30), who, "fresh"),
+            (30, None, state, "fresh"),
+            (120, None, state, "too old"),
+            (120, Some(90), state, "too old"),
+            (120, Some(30), state, "fresh"),
+            (0, None, target(&present), "fresh"),
+            (0, None, target(&own), "fresh"),
+            (0, None, target(&absent), "not present"),
+            (120, Some(30), all, "fresh"),
+            (30, Some(10), all, "reconnected"),
+            (120, None, all, "no recent who"),
+        ];
+
+        for (i, (since, last_who, requirements, expected)) in cases.into_iter().enumerate() {
+            assert_eq!(fresh(since, last_who, requirements), expected, "case {i}");
         }
-        .into_packet()?;
-        debug!(target: "euphoxide::conn::full", "Sending {packet:?}");
 
-        let msg = tungstenite::Message::Text(serde_json::to_string(&packet)?);
-        self.ws.send(msg).await?;
+        let mut joined = joined([session(1)]);
+        joined.since = now - 120.seconds();
+        joined.on_data(&Data::WhoReply(WhoReply { listing: vec![] }), now);
+        assert_eq!(joined.ensure_fresh(all, now), Ok(()));
+    }
 
-        Ok(())
+    #[test]
+    fn partial_and_full_sessions() {
+        let mut joined = joined([session(1)]);
+
+        // Known sessions stay full
+        joined.on_data(&nick_event(1, "renamed"), Timestamp::now());
+        let info = &joined.listing[&session(1).session_id];
+        assert!(matches!(info, SessionInfo::Full(s) if s.name == "renamed"));
+
+        // Unknown sessions become partial
+        joined.on_data(&nick_event(2, "partial"), Timestamp::now());
+        joined.on_data(&nick_event(2, "still partial"), Timestamp::now());
+        let info = &joined.listing[&session(2).session_id];
+        assert!(matches!(info, SessionInfo::Partial(p) if p.to == "still partial"));
+
+        // Visible actions upgrade partial sessions to full sessions
+        joined.on_data(&send_event(session(2)), Timestamp::now());
+        let info = &joined.listing[&session(2).session_id];
+        assert!(matches!(info, SessionInfo::Full(s) if s.name == "user2"));
+
+        joined.on_data(&Data::PartEvent(PartEvent(session(1))), Timestamp::now());
+        joined.on_data(&Data::PartEvent(PartEvent(session(2))), Timestamp::now());
+        assert!(joined.listing.is_empty());
     }
 
-    async fn disconnect(&mut self) -> Result<Infallible> {
-        let _ = tokio::time::timeout(self.replies.timeout(), self.ws.close(None)).await;
-        debug!("Closed connection");
-        Err(Error::ConnectionClosed)
+    #[test]
+    fn nick_reply() {
+        let mut joined = joined([session(1)]);
+        let reply = |n: usize, to: &str| {
+            Data::NickReply(NickReply {
+                session_id: session(n).session_id,
+                id: session(n).id,
+                from: session(n).name,
+                to: to.to_string(),
+            })
+        };
+
+        joined.on_data(&reply(0, "TestBot"), Timestamp::now());
+        assert_eq!(joined.session.name, "TestBot");
+
+        // Misrouted replies must not panic or modify any sessions
+        joined.on_data(&reply(1, "Hijacked"), Timestamp::now());
+        assert_eq!(joined.session.name, "TestBot");
+        assert_eq!(names(&joined), ["user1"]);
     }
 
-    pub fn wrap(ws: WsStream, timeout: Duration) -> Self {
-        let (cmd_tx, cmd_rx) = mpsc::unbounded_channel();
-        Self {
-            ws,
-            last_id: 0,
-            replies: Replies::new(timeout),
+    #[cfg(feature = "serde")]
+    fn assert_golden(state: &State, golden: &str) {
+        let expected = serde_json::from_str::<serde_json::Value>(golden).unwrap();
+        let actual = serde_json::to_value(state).unwrap();
+        assert_eq!(actual, expected);
 
-            conn_tx: ConnTx { cmd_tx },
-            cmd_rx,
+        // Deserializing and serializing again must not change anything
+        let state = serde_json::from_value::<State>(actual).unwrap();
+        assert_eq!(serde_json::to_value(&state).unwrap(), expected);
+    }
 
-            last_ping: Instant::now(), // Wait a bit before first pings
-            last_ws_ping_payload: None,
-            last_ws_ping_replied_to: false,
-            last_euph_ping_payload: None,
-            last_euph_ping_replied_to: false,
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serialize_joining() {
+        let state = State::Joining(Joining {
+            since: "2024-01-01T12:00:00Z".parse().unwrap(),
+            hello: Some(HelloEvent {
+                id: session(0).id,
+                account: None,
+                session: session(0),
+                account_has_access: None,
+                account_email_verified: None,
+                room_is_private: false,
+                version: "version".to_string(),
+            }),
+            snapshot: None,
+            bounce: None,
+        });
+        assert_golden(&state, include_str!("../tests/golden/joining.json"));
+    }
 
-            state: State::Joining(Joining::new()),
-        }
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serialize_joined() {
+        let mut joined = joined([session(3), session(1)]);
+        joined.since = "2024-01-01T12:00:00Z".parse().unwrap();
+        joined.last_who = Some("2024-01-01T12:05:30.5Z".parse().unwrap());
+        joined.on_data(&nick_event(2, "partial"), Timestamp::now());
+        let state = State::Joined(joined);
+        assert_golden(&state, include_str!("../tests/golden/joined.json"));
     }
 
-    pub async fn connect(
-        domain: &str,
-        room: &str,
-        human: bool,
-        cookies: Option<HeaderValue>,
-        timeout: Duration,
-    ) -> Result<(Self, Vec<HeaderValue>)> {
-        let human = if human { "?h=1" } else { "" };
-        let uri = format!("wss://{domain}/room/{room}/ws{human}");
-        debug!("Connecting to {uri} with cookies: {cookies:?}");
-        let mut request = uri.into_client_request().expect("valid request");
-        if let Some(cookies) = cookies {
-            request.headers_mut().append(header::COOKIE, cookies);
-        }
+    #[test]
+    fn euph_error_reasons() {
+        let err = Error::euph("thread too deep");
+        assert_eq!(err.to_string(), "thread too deep");
+        assert_eq!(err.error_reason(), Some(&ErrorReason::ThreadTooDeep));
+        assert_eq!(
+            err.send_error_reason(),
+            Some(SendErrorReason::ThreadTooDeep)
+        );
+        assert_eq!(
+            err.access_error_reason(),
+            Some(AccessErrorReason::Other("thread too deep".to_string()))
+        );
 
-        let (ws, response) =
-            tokio::time::timeout(timeout, tokio_tungstenite::connect_async(request))
-                .await
-                .map_err(|_| Error::ConnectionTimedOut)??;
-        let (mut parts, _) = response.into_parts();
-        let cookies_set = match parts.headers.entry(header::SET_COOKIE) {
-            header::Entry::Occupied(entry) => entry.remove_entry_mult().1.collect(),
-            header::Entry::Vacant(_) => vec![],
+        let err = Error::euph("already granted");
+        assert_eq!(err.error_reason(), Some(&ErrorReason::Conflict));
+        assert_eq!(
+            err.access_error_reason(),
+            Some(AccessErrorReason::AlreadyGranted)
+        );
+
+        let err = Error::euph("message not found");
+        assert_eq!(
+            err.send_error_reason(),
+            Some(SendErrorReason::InvalidParent)
+        );
+        assert_eq!(
+            err.access_error_reason(),
+            Some(AccessErrorReason::NotGranted)
+        );
+
+        assert_eq!(Error::ConnectionClosed.error_reason(), None);
+    }
+
+    #[test]
+    fn send_failure_format() {
+        let err = Error::euph("access denied");
+        assert_eq!(
+            format_send_failure(PacketType::Send, Some("3"), Some("test"), &err),
+            format!("Sending send (id 3) in &test failed: {err}")
+        );
+        assert_eq!(
+            format_send_failure(PacketType::Nick, None, None, &Error::ConnectionClosed),
+            format!(
+                "Sending nick (id -) in unknown room failed: {}",
+                Error::ConnectionClosed
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn send_logged() {
+        let logger = capture_logs();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut server = Server(tokio_tungstenite::accept_async(stream).await.unwrap());
+            server.join(hello(false, None)).await;
+            while let Some(packet) = server.recv().await {
+                if packet["type"] == "send" {
+                    let reply = serde_json::json!({
+                        "id": packet["id"],
+                        "type": "send-reply",
+                        "error": "access denied",
+                    });
+                    server.send(reply).await;
+                }
+            }
+        });
+
+        let domain = addr.to_string();
+        let timeout = Duration::from_secs(10);
+        let connected = Conn::connect_insecure(&domain, "send-logged", false, None, timeout);
+        let (mut conn, _) = connected.await.unwrap();
+        let conn_tx = conn.tx().clone();
+        assert_eq!(conn_tx.room(), Some("send-logged"));
+        tokio::spawn(async move { while conn.recv().await.is_ok() {} });
+
+        conn_tx.send_logged(crate::api::Send {
+            content: "hello".to_string(),
+            parent: None,
+        });
+
+        let ours = || {
+            logger
+                .0
+                .lock()
+                .unwrap()
+                .iter()
+                .filter(|f| f.fields.get("room").map(|r| &r[..]) == Some("send-logged"))
+                .cloned()
+                .collect::<Vec<_>>()
         };
-        debug!("Received cookies {cookies_set:?}");
-        let rx = Self::wrap(ws, timeout);
-        Ok((rx, cookies_set))
+        tokio::time::timeout(timeout, async {
+            while ours().is_empty() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let logs = ours();
+        assert_eq!(logs.len(), 1);
+        let log = &logs[0];
+        assert_eq!(log.level, log::Level::Warn);
+        assert_eq!(log.fields["packet_type"], "send");
+        assert_eq!(log.fields["error"], "access denied");
+        let id = &log.fields["id"];
+        assert!(!id.is_empty());
+        assert_eq!(
+            log.message,
+            format!("Sending send (id {id}) in &send-logged failed: access denied")
+        );
     }
 }
@@ -0,0 +1,437 @@
+//! Conventions for message content.
+//!
+//! The euphoria client renders some message content specially: messages
+//! starting with `/me ` are actions, lines starting with `>` are quotes, and
+//! URLs are turned into links. [`MessageContent`] splits content along these
+//! conventions.
+
+use std::borrow::Cow;
+use std::ops::Range;
+
+const ACTION_PREFIX: &str = "/me";
+
+/// Characters that end a URL if they appear at its end.
+///
+/// Closing brackets are handled separately since they may be part of a URL.
+const TRAILING_PUNCTUATION: &[char] = &['.', ',', ':', ';', '!', '?', '\'', '"'];
+
+/// A part of a [`Block::Text`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Segment<'a> {
+    Plain(Cow<'a, str>),
+    Url {
+        url: Cow<'a, str>,
+        /// The byte range of the URL in the content it was parsed from.
+        ///
+        /// This range is not updated if the content is modified.
+        range: Range<usize>,
+    },
+}
+
+impl Segment<'_> {
+    pub fn as_str(&self) -> &str {
+        match self {
+            Self::Plain(text) => text,
+            Self::Url { url, .. } => url,
+        }
+    }
+}
+
+/// A group of consecutive lines of a [`MessageContent`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Block<'a> {
+    /// Lines starting with `>`.
+    ///
+    /// The `>` and a single space following it are removed from each line.
+    Quote(Vec<Cow<'a, str>>),
+    /// Lines not starting with `>`, split into plain text and URLs.
+    ///
+    /// Newlines between the lines are part of the plain text.
+    Text(Vec<Segment<'a>>),
+}
+
+/// Message content split along the conventions of the euphoria client.
+///
+/// Parsing borrows from the original content, so it is cheap. The parts can
+/// be modified and turned back into content using [`Self::to_content_string`],
+/// which is useful e.g. for bridges rewriting messages.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MessageContent<'a> {
+    /// Whether the content starts with `/me` followed by whitespace.
+    ///
+    /// The `/me` and the whitespace character following it are not part of
+    /// [`Self::blocks`].
+    pub action: bool,
+    /// The content's lines, grouped into blocks.
+    ///
+    /// Blocks are separated by newlines.
+    pub blocks: Vec<Block<'a>>,
+}
+
+impl<'a> MessageContent<'a> {
+    pub fn parse(content: &'a str) -> Self {
+        let (action, offset) = match action_text(content) {
+            Some(text) => (true, content.len() - text.len()),
+            None => (false, 0),
+        };
+
+        let mut blocks = vec![];
+        let mut text_start = None;
+        let mut quote = vec![];
+        let mut line_start = offset;
+        for line in content[offset..].split('\n') {
+            let line_end = line_start + line.len();
+            match line.strip_prefix('>') {
+                Some(quoted) => {
+                    if let Some(start) = text_start.take() {
+                        // Exclude the newline before this line
+                        blocks.push(text_block(content, start..line_start - 1));
+                    }
+                    let quoted = quoted.strip_prefix(' ').unwrap_or(quoted);
+                    quote.push(Cow::Borrowed(quoted));
+                }
+                None => {
+                    if !quote.is_empty() {
+                        blocks.push(Block::Quote(std::mem::take(&mut quote)));
+                    }
+                    text_start.get_or_insert(line_start);
+                }
+            }
+            line_start = line_end + 1;
+        }
+        if let Some(start) = text_start {
+            blocks.push(text_block(content, start..content.len()));
+        }
+        if !quote.is_empty() {
+            blocks.push(Block::Quote(quote));
+        }
+
+        Self { action, blocks }
+    }
+
+    pub fn is_action(&self) -> bool {
+        self.action
+    }
+
+    /// The content without its leading `/me`, if it is an action.
+    pub fn action_text(&self) -> Option<String> {
+        self.action.then(|| self.body())
+    }
+
+    /// The quoted blocks, with their lines joined by newlines.
+    pub fn quotes(&self) -> Vec<String> {
+        self.blocks
+            .iter()
+            .filter_map(|block| match block {
+                Block::Quote(lines) => Some(lines.join("\n")),
+                Block::Text(_) => None,
+            })
+            .collect()
+    }
+
+    /// The URLs along with their byte ranges in the content they were parsed
+    /// from.
+    ///
+    /// URLs in quotes are not detected.
+    pub fn urls(&self) -> Vec<(Range<usize>, &str)> {
+        self.segments()
+            .filter_map(|segment| match segment {
+                Segment::Plain(_) => None,
+                Segment::Url { url, range } => Some((range.clone(), &**url)),
+            })
+            .collect()
+    }
+
+    /// The plain text segments, i.e. the text that is neither quoted nor a URL.
+    pub fn plain(&self) -> Vec<&str> {
+        self.segments()
+            .filter_map(|segment| match segment {
+                Segment::Plain(text) => Some(&**text),
+                Segment::Url { .. } => None,
+            })
+            .collect()
+    }
+
+    fn segments(&self) -> impl Iterator<Item = &Segment<'a>> {
+        self.blocks.iter().flat_map(|block| match block {
+            Block::Quote(_) => &[][..],
+            Block::Text(segments) => &segments[..],
+        })
+    }
+
+    /// The content without the `/me` prefix.
+    fn body(&self) -> String {
+        let blocks = self
+            .blocks
+            .iter()
+            .map(|block| match block {
+                Block::Quote(lines) => lines
+                    .iter()
+                    .map(|line| match &**line {
+                        "" => ">".to_string(),
+                        line => format!("> {line}"),
+                    })
+                    .collect::<Vec<_>>()
+                    .join("\n"),
+                Block::Text(segments) => segments.iter().map(|s| s.as_str()).collect(),
+            })
+            .collect::<Vec<_>>();
+        blocks.join("\n")
+    }
+
+    /// Turn the parts back into message content.
+    ///
+    /// For unmodified parts, the result is equivalent to the content they were
+    /// parsed from. It may differ in the whitespace after quote markers and
+    /// after the `/me` of actions.
+    pub fn to_content_string(&self) -> String {
+        let body = self.body();
+        if self.action {
+            format!("{ACTION_PREFIX} {body}")
+        } else {
+            body
+        }
+    }
+}
+
+/// The content without its leading `/me` and the whitespace character
+/// following it, if it is an action.
+fn action_text(content: &str) -> Option<&str> {
+    let rest = content.strip_prefix(ACTION_PREFIX)?;
+    let mut chars = rest.chars();
+    match chars.next() {
+        Some(c) if c.is_whitespace() => Some(chars.as_str()),
+        _ => None,
+    }
+}
+
+fn text_block(content: &str, range: Range<usize>) -> Block<'_> {
+    let mut segments = vec![];
+    let mut plain_start = range.start;
+    for url in find_urls(&content[range.clone()]) {
+        let url = url.start + range.start..url.end + range.start;
+        if plain_start < url.start {
+            segments.push(Segment::Plain(Cow::Borrowed(
+                &content[plain_start..url.start],
+            )));
+        }
+        segments.push(Segment::Url {
+            url: Cow::Borrowed(&content[url.clone()]),
+            range: url.clone(),
+        });
+        plain_start = url.end;
+    }
+    if plain_start < range.end {
+        segments.push(Segment::Plain(Cow::Borrowed(
+            &content[plain_start..range.end],
+        )));
+    }
+    Block::Text(segments)
+}
+
+fn is_scheme_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || matches!(c, '+' | '-' | '.')
+}
+
+/// Where the URL in a whitespace-delimited token starts, if it contains one.
+///
+/// URLs either have a scheme like `https://` or start with `www.`. They must
+/// not be preceded by an alphanumeric character.
+fn url_start(token: &str) -> Option<usize> {
+    let start = match token.find("://") {
+        Some(sep) => {
+            let scheme_start = token[..sep]
+                .char_indices()
+                .rev()
+                .find(|(_, c)| !is_scheme_char(*c))
+                .map_or(0, |(i, c)| i + c.len_utf8());
+            // Schemes start with a letter
+            let scheme_start =
+                scheme_start + token[scheme_start..sep].find(|c: char| c.is_ascii_alphabetic())?;
+            if sep + "://".len() == token.len() {
+                return None;
+            }
+            scheme_start
+        }
+        None => token.find("www.")?,
+    };
+    let preceded_by_alnum = token[..start]
+        .chars()
+        .next_back()
+        .is_some_and(|c| c.is_alphanumeric());
+    (!preceded_by_alnum).then_some(start)
+}
+
+/// Remove trailing punctuation that is probably not part of the URL.
+///
+/// Closing brackets are only removed if the URL doesn't contain the matching
+/// opening bracket.
+fn trim_url(url: &str) -> &str {
+    let mut url = url;
+    loop {
+        let trimmed = url.trim_end_matches(TRAILING_PUNCTUATION);
+        let trimmed = match trimmed.chars().next_back() {
+            Some(c @ (')' | ']' | '>' | '}')) => {
+                let open = match c {
+                    ')' => '(',
+                    ']' => '[',
+                    '>' => '<',
+                    _ => '{',
+                };
+                let opened = trimmed.matches(open).count();
+                let closed = trimmed.matches(c).count();
+                if closed > opened {
+                    &trimmed[..trimmed.len() - c.len_utf8()]
+                } else {
+                    trimmed
+                }
+            }
+            _ => trimmed,
+        };
+        if trimmed.len() == url.len() {
+            return url;
+        }
+        url = trimmed;
+    }
+}
+
+/// Byte ranges of the URLs in some text.
+fn find_urls(text: &str) -> Vec<Range<usize>> {
+    let mut urls = vec![];
+    let mut offset = 0;
+    for token in text.split_whitespace() {
+        let token_start = offset + text[offset..].find(token).unwrap_or(0);
+        offset = token_start + token.len();
+        let start = match url_start(token) {
+            Some(start) => start,
+            None => continue,
+        };
+        let url = trim_url(&token[start..]);
+        if url.len() > "www.".len() {
+            let start = token_start + start;
+            urls.push(start..start + url.len());
+        }
+    }
+    urls
+}
+
+#[cfg(test)]
+mod test {
+    use std::borrow::Cow;
+
+    use super::{Block, MessageContent, Segment};
+
+    fn urls(content: &str) -> Vec<&str> {
+        MessageContent::parse(content)
+            .urls()
+            .into_iter()
+            .map(|(range, url)| {
+                assert_eq!(&content[range.clone()], url);
+                &content[range]
+            })
+            .collect()
+    }
+
+    #[test]
+    fn actions() {
+        let cases = [
+            ("/me waves", Some("waves")),
+            ("/me  waves", Some(" waves")),
+            ("/me\nwaves", Some("waves")),
+            ("/me ", Some("")),
+            ("/me", None),
+            ("/mercy", None),
+            ("/meow at /me", None),
+            (" /me waves", None),
+            ("/ME waves", None),
+            ("waves /me", None),
+        ];
+        for (content, expected) in cases {
+            let parsed = MessageContent::parse(content);
+            assert_eq!(parsed.is_action(), expected.is_some(), "{content:?}");
+            assert_eq!(parsed.action_text().as_deref(), expected, "{content:?}");
+        }
+    }
+
+    #[test]
+    fn quotes() {
+        let content = "> first\n>second\n>\nreply\nmore\n> another";
+        let parsed = MessageContent::parse(content);
+        assert_eq!(
+            parsed.blocks,
+            [
+                Block::Quote(vec!["first".into(), "second".into(), "".into()]),
+                Block::Text(vec![Segment::Plain("reply\nmore".into())]),
+                Block::Quote(vec!["another".into()]),
+            ]
+        );
+        assert_eq!(parsed.quotes(), ["first\nsecond\n", "another"]);
+        assert_eq!(parsed.plain(), ["reply\nmore"]);
+
+        // Quotes borrow from the content
+        match &parsed.blocks[0] {
+            Block::Quote(lines) => assert!(matches!(lines[0], Cow::Borrowed(_))),
+            Block::Text(_) => unreachable!(),
+        }
+
+        // Quote markers must be at the start of the line
+        let parsed = MessageContent::parse(" > not a quote\n/me > neither");
+        assert!(parsed.quotes().is_empty());
+        let parsed = MessageContent::parse("/me waves\n> quoted");
+        assert_eq!(parsed.quotes(), ["quoted"]);
+    }
+
+    #[test]
+    fn url_boundaries() {
+        let cases = [
+            ("see https://example.com.", vec!["https://example.com"]),
+            ("(https://example.com)", vec!["https://example.com"]),
+            (
+                "https://en.wikipedia.org/wiki/Rust_(programming_language)),",
+                vec!["https://en.wikipedia.org/wiki/Rust_(programming_language)"],
+            ),
+            (
+                "<https://example.com/a?b=c>",
+                vec!["https://example.com/a?b=c"],
+            ),
+            ("\"www.example.com\"!", vec!["www.example.com"]),
+            ("http://a.b and ftp://c.d?", vec!["http://a.b", "ftp://c.d"]),
+            ("https://example.com/ü…", vec!["https://example.com/ü…"]),
+            ("https:// www.", vec![]),
+            ("(x)https://example.com", vec!["https://example.com"]),
+            ("awww.example.com", vec![]),
+            ("euphoria.leet.nu", vec![]),
+        ];
+        for (content, expected) in cases {
+            assert_eq!(urls(content), expected, "{content:?}");
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let cases = [
+            "",
+            "hello",
+            "/me waves at https://example.com",
+            "> quote\n\nreply\n>\n> https://example.com\ntext ",
+            "\n\n",
+        ];
+        for content in cases {
+            assert_eq!(MessageContent::parse(content).to_content_string(), content);
+        }
+
+        let parsed = MessageContent::parse("/me\tquotes\n>quote");
+        assert_eq!(parsed.to_content_string(), "/me quotes\n> quote");
+
+        // Modified content
+        let mut parsed = MessageContent::parse("look: http://example.com");
+        if let Block::Text(segments) = &mut parsed.blocks[0] {
+            segments.push(Segment::Plain(" (via bridge)".into()));
+        }
+        parsed.action = true;
+        assert_eq!(
+            parsed.to_content_string(),
+            "/me look: http://example.com (via bridge)"
+        );
+    }
+}
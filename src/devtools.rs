@@ -0,0 +1,3 @@
+//! Tools for developing against and debugging euphoria servers.
+
+pub mod repl;
@@ -0,0 +1,447 @@
+//! A minimal interactive client for manual protocol testing.
+//!
+//! [`run_repl`] connects to a room, prints every packet it receives and sends
+//! the lines typed into stdin. Lines are interpreted as follows:
+//!
+//! - `/nick <name>` sends a [`Nick`] command.
+//! - `/who` sends a [`Who`] command.
+//! - `/log <n>` sends a [`Log`] command.
+//! - `/<type> [json]` sends a packet of the given type with the given data,
+//!   e.g. `/send {"content": "hi"}`. The data defaults to an empty object.
+//!   Types not modeled by [`Data`] are sent as-is via
+//!   [`ConnTx::send_raw_value`](conn::ConnTx::send_raw_value).
+//! - `//<text>` sends `/<text>` as a message.
+//! - Any other non-empty line is sent as a message.
+//!
+//! The REPL stops when stdin is closed (usually via Ctrl+D) or when the
+//! connection is closed.
+
+use std::time::Duration;
+use std::{error, fmt};
+
+use serde_json::Value;
+use tokio::io::{AsyncBufReadExt, BufReader};
+
+use crate::api::packet::ParsedPacket;
+use crate::api::{Data, Log, Message, Nick, PacketType, Send, Who};
+use crate::conn::{self, Conn};
+use crate::nick::{self, Rgb, Theme};
+use crate::Emoji;
+
+const RESET: &str = "\x1b[0m";
+const RED: &str = "\x1b[31m";
+const GREEN: &str = "\x1b[32m";
+const YELLOW: &str = "\x1b[33m";
+const CYAN: &str = "\x1b[36m";
+
+/// Where and how [`run_repl`] connects, and how it prints packets.
+///
+/// # Example
+///
+/// ```
+/// use euphoxide::devtools::repl::ReplConfig;
+///
+/// let config = ReplConfig::new()
+///     .with_domain("euphoria.example.com")
+///     .with_color(false);
+/// assert_eq!(config.domain, "euphoria.example.com");
+/// ```
+#[derive(Debug, Clone)]
+pub struct ReplConfig {
+    pub domain: String,
+    pub human: bool,
+    pub timeout: Duration,
+    /// Whether to colorize the output using ANSI escape sequences.
+    pub color: bool,
+}
+
+impl ReplConfig {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_domain<S: ToString>(mut self, domain: S) -> Self {
+        self.domain = domain.to_string();
+        self
+    }
+
+    pub fn with_human(mut self, human: bool) -> Self {
+        self.human = human;
+        self
+    }
+
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn with_color(mut self, color: bool) -> Self {
+        self.color = color;
+        self
+    }
+}
+
+impl Default for ReplConfig {
+    fn default() -> Self {
+        Self {
+            domain: "euphoria.leet.nu".to_string(),
+            human: false,
+            timeout: Duration::from_secs(30),
+            color: true,
+        }
+    }
+}
+
+/// A packet to send, parsed from a line of input.
+#[derive(Debug)]
+#[allow(clippy::large_enum_variant)]
+pub enum Input {
+    /// A packet whose type is modeled by [`Data`].
+    Data(Data),
+    /// A packet of any other type, with its type name and data.
+    Raw(String, Value),
+}
+
+/// A line of input that couldn't be turned into a packet.
+#[derive(Debug)]
+pub enum InputError {
+    /// A command was given without its required argument.
+    MissingArgument(&'static str),
+    /// The argument of `/log` is not a number.
+    InvalidCount(String),
+    /// The packet data doesn't match the packet type.
+    Json(serde_json::Error),
+}
+
+impl fmt::Display for InputError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::MissingArgument(usage) => write!(f, "usage: {usage}"),
+            Self::InvalidCount(n) => write!(f, "invalid message count {n:?}"),
+            Self::Json(err) => write!(f, "invalid packet data: {err}"),
+        }
+    }
+}
+
+impl error::Error for InputError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            Self::Json(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+/// Turn a line of input into a packet to send.
+///
+/// Returns `None` for empty lines. See the [module documentation](self) for
+/// the accepted syntax.
+pub fn parse_input(line: &str) -> Result<Option<Input>, InputError> {
+    let line = line.trim_end_matches(['\r', '\n']);
+    if line.trim().is_empty() {
+        return Ok(None);
+    }
+
+    let command = match line.strip_prefix('/') {
+        Some(command) if !command.starts_with('/') => command,
+        _ => {
+            let content = line.strip_prefix('/').unwrap_or(line).to_string();
+            return Ok(Some(Input::Data(
+                Send {
+                    content,
+                    parent: None,
+                }
+                .into(),
+            )));
+        }
+    };
+
+    let (name, arg) = match command.split_once(char::is_whitespace) {
+        Some((name, arg)) => (name, arg.trim()),
+        None => (command, ""),
+    };
+
+    let data = match name {
+        "nick" if arg.is_empty() => return Err(InputError::MissingArgument("/nick <name>")),
+        "nick" => Nick {
+            name: arg.to_string(),
+        }
+        .into(),
+        "who" => Who {}.into(),
+        "log" if arg.is_empty() => return Err(InputError::MissingArgument("/log <n>")),
+        "log" => Log {
+            n: arg
+                .parse()
+                .map_err(|_| InputError::InvalidCount(arg.to_string()))?,
+            before: None,
+        }
+        .into(),
+        _ => {
+            let value = if arg.is_empty() {
+                Value::Object(Default::default())
+            } else {
+                serde_json::from_str(arg).map_err(InputError::Json)?
+            };
+            let ptype = match serde_json::from_value::<PacketType>(Value::String(name.into())) {
+                Ok(ptype) => ptype,
+                Err(_) => return Ok(Some(Input::Raw(name.to_string(), value))),
+            };
+            match Data::from_value(ptype, value.clone()).map_err(InputError::Json)? {
+                Data::Unimplemented => return Ok(Some(Input::Raw(name.to_string(), value))),
+                data => data,
+            }
+        }
+    };
+    Ok(Some(Input::Data(data)))
+}
+
+/// The color of a nick with the given hue, as RGB.
+///
+/// The hue can be calculated via [`nick::hue`]. This is [`nick::color`] with
+/// [`Theme::Dark`].
+pub fn nick_rgb(hue: u8) -> (u8, u8, u8) {
+    let Rgb { r, g, b } = nick::color(hue, Theme::Dark);
+    (r, g, b)
+}
+
+fn paint(color: bool, code: &str, text: &str) -> String {
+    if color {
+        format!("{code}{text}{RESET}")
+    } else {
+        text.to_string()
+    }
+}
+
+fn format_nick(emoji: &Emoji, color: bool, name: &str) -> String {
+    let text = format!("[{}]", emoji.replace(name));
+    if !color {
+        return text;
+    }
+    let rgb = nick::color(nick::hue(emoji, name), Theme::Dark);
+    paint(true, &rgb.to_ansi_truecolor(), &text)
+}
+
+fn format_message(emoji: &Emoji, color: bool, msg: &Message) -> String {
+    let nick = format_nick(emoji, color, &msg.sender.name);
+    let content = emoji.replace(&msg.content);
+    let content = content.lines().collect::<Vec<_>>().join("\n    ");
+    match msg.parent {
+        Some(parent) => format!("{} (reply to {}) {nick} {content}", msg.id.0, parent.0),
+        None => format!("{} {nick} {content}", msg.id.0),
+    }
+}
+
+/// Format a received packet as a single entry of human-readable text.
+///
+/// Messages are shown with their sender's nick, and emoji in nicks and message
+/// contents are replaced by their unicode equivalent. All other packets are
+/// shown as their type followed by their data as JSON. If `color` is set, the
+/// text contains ANSI escape sequences for colors.
+pub fn format_packet(packet: &ParsedPacket, emoji: &Emoji, color: bool) -> String {
+    let name = packet.r#type.to_string();
+    let type_color = match (&packet.content, packet.throttled.is_some()) {
+        (Err(_), _) => RED,
+        (_, true) => YELLOW,
+        _ if name.ends_with("-event") => CYAN,
+        _ => GREEN,
+    };
+    let mut result = paint(color, type_color, &name);
+
+    match &packet.content {
+        Err(err) => result.push_str(&format!(" error: {err}")),
+        Ok(Data::SendEvent(event)) => {
+            result.push(' ');
+            result.push_str(&format_message(emoji, color, &event.0));
+        }
+        Ok(Data::SendReply(reply)) => {
+            result.push(' ');
+            result.push_str(&format_message(emoji, color, &reply.0));
+        }
+        Ok(Data::NickEvent(event)) => {
+            let from = format_nick(emoji, color, &event.from);
+            let to = format_nick(emoji, color, &event.to);
+            result.push_str(&format!(" {from} is now {to}"));
+        }
+        Ok(Data::Unimplemented) => result.push_str(" (unimplemented)"),
+        Ok(data) => match data.clone().into_value() {
+            Ok(value) => result.push_str(&format!(" {value}")),
+            Err(err) => result.push_str(&format!(" (unserializable: {err})")),
+        },
+    }
+
+    if let Some(reason) = &packet.throttled {
+        result.push_str(&format!(" (throttled: {reason})"));
+    }
+
+    result
+}
+
+/// Connect to a room and interact with it via stdin and stdout.
+///
+/// See the [module documentation](self) for more details.
+pub async fn run_repl(config: ReplConfig, room: &str) -> conn::Result<()> {
+    let (mut conn, _) =
+        Conn::connect(&config.domain, room, config.human, None, config.timeout).await?;
+    let emoji = Emoji::global();
+    let mut lines = BufReader::new(tokio::io::stdin()).lines();
+
+    loop {
+        tokio::select! {
+            packet = conn.recv() => {
+                println!("{}", format_packet(&packet?, emoji, config.color));
+            }
+            line = lines.next_line() => {
+                let line = match line {
+                    Ok(Some(line)) => line,
+                    Ok(None) | Err(_) => break,
+                };
+                match parse_input(&line) {
+                    Ok(Some(Input::Data(data))) => conn.tx().send_only(data)?,
+                    Ok(Some(Input::Raw(ptype, data))) => conn.tx().send_raw_value(ptype, data)?,
+                    Ok(None) => {}
+                    Err(err) => eprintln!("{}", paint(config.color, RED, &err.to_string())),
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use crate::api::packet::ParsedPacket;
+    use crate::api::{Data, MessageId, PacketType, SendEvent, Snowflake, UserId, WhoReply};
+    use crate::test_util::{MessageBuilder, SessionViewBuilder};
+    use crate::Emoji;
+
+    use super::{format_packet, nick_rgb, parse_input, Input, InputError};
+
+    fn data(line: &str) -> Data {
+        match parse_input(line).unwrap() {
+            Some(Input::Data(data)) => data,
+            other => panic!("not modeled data: {other:?}"),
+        }
+    }
+
+    fn send_content(line: &str) -> String {
+        match data(line) {
+            Data::Send(send) => send.content,
+            other => panic!("not a send: {other:?}"),
+        }
+    }
+
+    #[test]
+    fn input() {
+        assert!(parse_input("").unwrap().is_none());
+        assert!(parse_input("  \n").unwrap().is_none());
+        assert_eq!(send_content("hello world\n"), "hello world");
+        assert_eq!(send_content("//nick"), "/nick");
+
+        match data("/nick  Test Bot ") {
+            Data::Nick(nick) => assert_eq!(nick.name, "Test Bot"),
+            other => panic!("not a nick: {other:?}"),
+        }
+        assert!(matches!(data("/who"), Data::Who(_)));
+        match data("/log 50") {
+            Data::Log(log) => assert_eq!(log.n, 50),
+            other => panic!("not a log: {other:?}"),
+        }
+
+        match data(r#"/send {"content": "hi", "parent": "0000000000001"}"#) {
+            Data::Send(send) => {
+                assert_eq!(send.content, "hi");
+                assert_eq!(send.parent.unwrap().0 .0, 1);
+            }
+            other => panic!("not a send: {other:?}"),
+        }
+        assert!(matches!(data("/ping {\"time\": 0}"), Data::Ping(_)));
+
+        match parse_input("/frobnicate {\"a\": 1}").unwrap() {
+            Some(Input::Raw(ptype, value)) => {
+                assert_eq!(ptype, "frobnicate");
+                assert_eq!(value, serde_json::json!({ "a": 1 }));
+            }
+            other => panic!("not raw: {other:?}"),
+        }
+        match parse_input("/frobnicate").unwrap() {
+            Some(Input::Raw(_, value)) => assert_eq!(value, serde_json::json!({})),
+            other => panic!("not raw: {other:?}"),
+        }
+
+        assert!(matches!(
+            parse_input("/nick"),
+            Err(InputError::MissingArgument(_))
+        ));
+        assert!(matches!(
+            parse_input("/log many"),
+            Err(InputError::InvalidCount(_))
+        ));
+        assert!(matches!(
+            parse_input("/frobnicate {"),
+            Err(InputError::Json(_))
+        ));
+        assert!(matches!(
+            parse_input("/send {\"content\": 1}"),
+            Err(InputError::Json(_))
+        ));
+        assert!(matches!(parse_input("/send {"), Err(InputError::Json(_))));
+    }
+
+    #[test]
+    fn nick_colors() {
+        assert_eq!(nick_rgb(0), (255, 112, 112));
+        assert_eq!(nick_rgb(120), (112, 255, 112));
+        assert_eq!(nick_rgb(240), (112, 112, 255));
+    }
+
+    fn packet(data: Data) -> ParsedPacket {
+        ParsedPacket {
+            id: None,
+            r#type: data.packet_type(),
+            content: Ok(data),
+            throttled: None,
+            seq: None,
+        }
+    }
+
+    #[test]
+    fn packets() {
+        let emoji = Emoji::global();
+        let sender = SessionViewBuilder::new("greenie :thumbsup:")
+            .with_id(UserId("agent:abc".to_string()))
+            .build();
+        let message = MessageBuilder::new("first :thumbsup:\nsecond")
+            .with_id(MessageId(Snowflake(2)))
+            .with_parent(MessageId(Snowflake(1)))
+            .with_sender(sender)
+            .build();
+        let event = packet(SendEvent(Arc::new(message)).into());
+        assert_eq!(
+            format_packet(&event, emoji, false),
+            "send-event 0000000000002 (reply to 0000000000001) [greenie 👍] first 👍\n    second"
+        );
+        assert!(format_packet(&event, emoji, true).contains("\x1b[36msend-event\x1b[0m"));
+
+        let who = packet(WhoReply { listing: vec![] }.into());
+        assert_eq!(
+            format_packet(&who, emoji, false),
+            r#"who-reply {"listing":[]}"#
+        );
+
+        let error = ParsedPacket {
+            id: Some("1".to_string()),
+            r#type: PacketType::SendReply,
+            content: Err("denied".to_string()),
+            throttled: Some("slow down".to_string()),
+            seq: None,
+        };
+        assert_eq!(
+            format_packet(&error, emoji, false),
+            "send-reply error: denied (throttled: slow down)"
+        );
+        assert!(format_packet(&error, emoji, true).starts_with("\x1b[31m"));
+    }
+}
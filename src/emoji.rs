@@ -2,7 +2,11 @@
 
 use std::borrow::Cow;
 use std::collections::HashMap;
-use std::ops::RangeInclusive;
+use std::ops::{Range, RangeInclusive};
+use std::sync::{Arc, OnceLock};
+use std::{error, fmt};
+
+use log::{error, warn};
 
 /// Euphoria.leet.nu emoji list, obtainable via shell command:
 ///
@@ -15,7 +19,25 @@ const EMOJI_JSON: &str = include_str!("emoji.json");
 
 /// A map from emoji names to their unicode representation. Not all emojis have
 /// such a representation.
-pub struct Emoji(pub HashMap<String, Option<String>>);
+///
+/// The map is shared between clones, so cloning is cheap.
+#[derive(Clone)]
+pub struct Emoji(pub Arc<HashMap<String, Option<String>>>);
+
+const VS16: char = '\u{fe0f}';
+
+/// The five Fitzpatrick skin tone modifiers, from light to dark.
+const SKIN_TONES: [char; 5] = [
+    '\u{1f3fb}',
+    '\u{1f3fc}',
+    '\u{1f3fd}',
+    '\u{1f3fe}',
+    '\u{1f3ff}',
+];
+
+fn is_skin_tone(c: char) -> bool {
+    SKIN_TONES.contains(&c)
+}
 
 fn parse_hex_to_char(hex: &str) -> Option<char> {
     u32::from_str_radix(hex, 16).ok()?.try_into().ok()
@@ -28,27 +50,152 @@ fn parse_code_points(code_points: &str) -> Option<String> {
         .collect::<Option<String>>()
 }
 
+/// An entry of an emoji list whose value could not be interpreted.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InvalidEmoji {
+    pub name: String,
+    /// The entry's value as JSON.
+    pub value: String,
+}
+
+/// The reason why [`Emoji::load_from_json`] failed.
+pub enum EmojiLoadError {
+    /// The document is not a JSON object.
+    Document(serde_json::Error),
+    /// Some entries are invalid.
+    ///
+    /// All other entries were loaded successfully. The invalid entries are
+    /// included as emoji without unicode representation.
+    Entries {
+        emoji: Emoji,
+        /// The invalid entries, sorted by name.
+        invalid: Vec<InvalidEmoji>,
+    },
+}
+
+impl fmt::Debug for EmojiLoadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Document(err) => f.debug_tuple("Document").field(err).finish(),
+            Self::Entries { emoji, invalid } => f
+                .debug_struct("Entries")
+                .field("emoji", &format_args!("<{} emoji>", emoji.0.len()))
+                .field("invalid", invalid)
+                .finish(),
+        }
+    }
+}
+
+impl fmt::Display for EmojiLoadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Document(err) => write!(f, "invalid emoji list: {err}"),
+            Self::Entries { invalid, .. } => write!(f, "{} invalid emoji", invalid.len()),
+        }
+    }
+}
+
+impl error::Error for EmojiLoadError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            Self::Document(err) => Some(err),
+            Self::Entries { .. } => None,
+        }
+    }
+}
+
 impl Emoji {
     /// Load a list of emoji compiled into the library.
+    ///
+    /// This parses a JSON object with a few thousand entries and allocates
+    /// multiple strings per entry every time it is called. Prefer
+    /// [`Self::global`] unless you need an instance you own.
+    ///
+    /// Problems with the list are logged. Invalid entries are loaded as emoji
+    /// without unicode representation, and if the list can't be parsed at all,
+    /// it is treated as empty. Use [`Self::try_load`] to handle problems
+    /// yourself.
     pub fn load() -> Self {
-        Self::load_from_json(EMOJI_JSON).unwrap()
+        match Self::try_load() {
+            Ok(emoji) => emoji,
+            Err(EmojiLoadError::Entries { emoji, invalid }) => {
+                for entry in invalid {
+                    warn!("Invalid emoji {:?}: {}", entry.name, entry.value);
+                }
+                emoji
+            }
+            Err(err @ EmojiLoadError::Document(_)) => {
+                error!("Failed to load emoji: {err}");
+                Self(Arc::new(HashMap::new()))
+            }
+        }
+    }
+
+    /// Load a list of emoji compiled into the library.
+    ///
+    /// See [`Self::load_from_json`] for more details.
+    pub fn try_load() -> Result<Self, EmojiLoadError> {
+        Self::load_from_json(EMOJI_JSON)
+    }
+
+    /// The list of emoji compiled into the library.
+    ///
+    /// The list is loaded once during the first call and shared afterwards.
+    pub fn global() -> &'static Self {
+        static GLOBAL: OnceLock<Emoji> = OnceLock::new();
+        GLOBAL.get_or_init(Self::load)
     }
 
     /// Load a list of emoji from a string containing a JSON object.
     ///
     /// The object keys are the emoji names (without colons `:`). The object
     /// values are the emoji code points encoded as hexadecimal numbers and
-    /// separated by a dash `-` (e.g. `"34-fe0f-20e3"`). Emojis whose values
-    /// don't match this schema are interpreted as emojis without unicode
+    /// separated by a dash `-` (e.g. `"34-fe0f-20e3"`). Custom emojis without
+    /// unicode representation have values starting with a tilde `~` (e.g.
+    /// `"~bot"`).
+    ///
+    /// Values matching neither schema are reported via
+    /// [`EmojiLoadError::Entries`] and interpreted as emojis without unicode
     /// representation.
-    pub fn load_from_json(json: &str) -> Option<Self> {
-        let map = serde_json::from_str::<HashMap<String, String>>(json)
-            .ok()?
-            .into_iter()
-            .map(|(k, v)| (k, parse_code_points(&v)))
-            .collect::<HashMap<_, _>>();
+    pub fn load_from_json(json: &str) -> Result<Self, EmojiLoadError> {
+        let entries = serde_json::from_str::<HashMap<String, serde_json::Value>>(json)
+            .map_err(EmojiLoadError::Document)?;
 
-        Some(Self(map))
+        let mut map = HashMap::new();
+        let mut invalid = vec![];
+        for (name, value) in entries {
+            let unicode = match &value {
+                serde_json::Value::String(custom) if custom.starts_with('~') => None,
+                serde_json::Value::String(code_points) => match parse_code_points(code_points) {
+                    Some(unicode) => Some(unicode),
+                    None => {
+                        let value = value.to_string();
+                        invalid.push(InvalidEmoji {
+                            name: name.clone(),
+                            value,
+                        });
+                        None
+                    }
+                },
+                _ => {
+                    let value = value.to_string();
+                    invalid.push(InvalidEmoji {
+                        name: name.clone(),
+                        value,
+                    });
+                    None
+                }
+            };
+            map.insert(name, unicode);
+        }
+
+        let emoji = Self(Arc::new(map));
+        if invalid.is_empty() {
+            Ok(emoji)
+        } else {
+            invalid.sort_by(|a, b| a.name.cmp(&b.name));
+            Err(EmojiLoadError::Entries { emoji, invalid })
+        }
     }
 
     pub fn get(&self, name: &str) -> Option<Option<&str>> {
@@ -59,21 +206,44 @@ impl Emoji {
         }
     }
 
+    /// Find all colon-delimited emoji in a text.
+    ///
+    /// Returns the byte range of each emoji (including its colons) along with
+    /// its unicode representation, if any, in the order they appear in.
+    ///
+    /// The text is scanned from left to right. Every colon may open an emoji
+    /// unless it already closed one. If the text between an opening colon and
+    /// the next colon is a known emoji name, the emoji is found and scanning
+    /// resumes after its closing colon. Otherwise, the next colon opens the
+    /// next candidate. This means that the leftmost emoji always wins, e.g.
+    /// `:x:o:` only contains `:x:`, while `:bad:x:o:` contains `:x:` as well.
+    ///
+    /// Since emoji names don't contain colons, an opening colon can only be
+    /// closed by the next colon and there is never more than one candidate per
+    /// opening colon. Names containing colons are never found. Each character
+    /// of the text is looked at a bounded number of times, so this function
+    /// runs in linear time.
     pub fn find(&self, text: &str) -> Vec<(RangeInclusive<usize>, Option<&str>)> {
         let mut result = vec![];
 
-        let mut prev_colon_idx = None;
+        let mut open_idx = None;
         for (colon_idx, _) in text.match_indices(':') {
-            if let Some(prev_idx) = prev_colon_idx {
-                let name = &text[prev_idx + 1..colon_idx];
-                if let Some(replace) = self.get(name) {
-                    let range = prev_idx..=colon_idx;
-                    result.push((range, replace));
-                    prev_colon_idx = None;
+            let prev_idx = match open_idx {
+                Some(idx) => idx,
+                None => {
+                    open_idx = Some(colon_idx);
                     continue;
                 }
+            };
+
+            let name = &text[prev_idx + 1..colon_idx];
+            match self.get(name) {
+                Some(replace) => {
+                    result.push((prev_idx..=colon_idx, replace));
+                    open_idx = None;
+                }
+                None => open_idx = Some(colon_idx),
             }
-            prev_colon_idx = Some(colon_idx);
         }
 
         result
@@ -134,18 +304,285 @@ impl Emoji {
     }
 }
 
+/// What [`Demojifier`] does with skin tone modifiers.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SkinTones {
+    /// Replace emoji with skin tones by the name of the emoji without skin
+    /// tone, e.g. `👍🏽` by `:thumbsup:`.
+    #[default]
+    Strip,
+    /// Keep skin tones.
+    ///
+    /// If the list contains the emoji with its skin tone, its name is used,
+    /// e.g. `:thumbs_up_medium_skin_tone:`. Otherwise, the name of the emoji
+    /// without skin tone is followed by the name of each skin tone modifier if
+    /// the list contains one (e.g. `:thumbsup::skin-tone-4:`), or by the
+    /// modifier itself.
+    Preserve,
+}
+
+/// Turns unicode emoji back into colon-delimited emoji.
+///
+/// This is the reverse of [`Emoji::replace`]. When matching, the optional
+/// variation selector 16 (U+FE0F) is ignored and skin tone modifiers (U+1F3FB
+/// to U+1F3FF) are handled according to [`SkinTones`], so that e.g. `❤` and
+/// `❤️` as well as `👍` and `👍🏽` are recognized as the same emoji.
+///
+/// If multiple names share the same unicode representation, the shortest name
+/// is used, and of those the alphabetically first one.
+///
+/// Building the lookup tables takes some time, so a demojifier should be
+/// reused where possible.
+#[derive(Debug, Clone)]
+pub struct Demojifier {
+    /// Names by unicode representation without variation selectors.
+    exact: HashMap<String, String>,
+    /// Names of emoji without skin tone by unicode representation without
+    /// variation selectors.
+    base: HashMap<String, String>,
+    /// Names of the lone skin tone modifiers.
+    tones: [Option<String>; 5],
+    /// The longest representation in characters.
+    max_chars: usize,
+    skin_tones: SkinTones,
+}
+
+impl Demojifier {
+    pub fn new(emoji: &Emoji) -> Self {
+        fn insert(map: &mut HashMap<String, String>, key: String, name: &str) {
+            let better = |old: &String| (name.len(), name) < (old.len(), old.as_str());
+            match map.get(&key) {
+                Some(old) if !better(old) => {}
+                _ => {
+                    map.insert(key, name.to_string());
+                }
+            }
+        }
+
+        let mut exact = HashMap::new();
+        let mut base = HashMap::new();
+        let mut tones = [None, None, None, None, None];
+        let mut max_chars = 0;
+        for (name, unicode) in emoji.0.iter() {
+            let unicode = match unicode {
+                Some(unicode) => unicode.replace(VS16, ""),
+                None => continue,
+            };
+            // Plain text shouldn't be demojified
+            if unicode.is_ascii() {
+                continue;
+            }
+
+            max_chars = max_chars.max(unicode.chars().count());
+            let mut chars = unicode.chars();
+            if let (Some(c), None) = (chars.next(), chars.next()) {
+                if let Some(i) = SKIN_TONES.iter().position(|t| *t == c) {
+                    let better = tones[i]
+                        .as_ref()
+                        .is_none_or(|old: &String| (name.len(), name) < (old.len(), old));
+                    if better {
+                        tones[i] = Some(name.clone());
+                    }
+                    continue;
+                }
+            }
+
+            if !unicode.chars().any(is_skin_tone) {
+                insert(&mut base, unicode.clone(), name);
+            }
+            insert(&mut exact, unicode, name);
+        }
+
+        Self {
+            exact,
+            base,
+            tones,
+            max_chars,
+            skin_tones: SkinTones::default(),
+        }
+    }
+
+    pub fn with_skin_tones(mut self, skin_tones: SkinTones) -> Self {
+        self.skin_tones = skin_tones;
+        self
+    }
+
+    /// The longest emoji starting at the beginning of the text, along with its
+    /// length in bytes and its colon-delimited replacement.
+    fn find_at(&self, text: &str) -> Option<(usize, String)> {
+        let first = text.chars().next()?;
+        if first == VS16 || is_skin_tone(first) {
+            return None;
+        }
+
+        // Variation selectors and skin tones don't count towards the length
+        let mut ends = vec![];
+        let mut counted = 0;
+        for (i, c) in text.char_indices() {
+            if c != VS16 && !is_skin_tone(c) {
+                if counted == self.max_chars {
+                    break;
+                }
+                counted += 1;
+            }
+            ends.push(i + c.len_utf8());
+        }
+
+        for end in ends.into_iter().rev() {
+            let candidate = &text[..end];
+            let key = candidate.replace(VS16, "");
+            let base_key = key.replace(is_skin_tone, "");
+            let found = match self.skin_tones {
+                SkinTones::Strip => self
+                    .base
+                    .get(&base_key)
+                    .or_else(|| self.exact.get(&key))
+                    .map(|name| format!(":{name}:")),
+                SkinTones::Preserve => match self.exact.get(&key) {
+                    Some(name) => Some(format!(":{name}:")),
+                    None => self.base.get(&base_key).map(|name| {
+                        let mut result = format!(":{name}:");
+                        for tone in key.chars().filter(|c| is_skin_tone(*c)) {
+                            let i = SKIN_TONES.iter().position(|t| *t == tone).unwrap();
+                            match &self.tones[i] {
+                                Some(tone_name) => result.push_str(&format!(":{tone_name}:")),
+                                None => result.push(tone),
+                            }
+                        }
+                        result
+                    }),
+                },
+            };
+            if let Some(found) = found {
+                return Some((end, found));
+            }
+        }
+        None
+    }
+
+    /// Find all unicode emoji in a text.
+    ///
+    /// Returns the byte range of each emoji along with its colon-delimited
+    /// replacement, in the order they appear in. At each position, the
+    /// longest emoji wins.
+    pub fn find(&self, text: &str) -> Vec<(Range<usize>, String)> {
+        let mut result = vec![];
+        let mut start = 0;
+        while start < text.len() {
+            match self.find_at(&text[start..]) {
+                Some((len, replacement)) => {
+                    result.push((start..start + len, replacement));
+                    start += len;
+                }
+                None => {
+                    // There is always a next character since start < text.len()
+                    start += text[start..].chars().next().unwrap().len_utf8();
+                }
+            }
+        }
+        result
+    }
+
+    /// Replace all unicode emoji by their colon-delimited names.
+    pub fn demojify<'a>(&self, text: &'a str) -> Cow<'a, str> {
+        let emoji = self.find(text);
+        if emoji.is_empty() {
+            return Cow::Borrowed(text);
+        }
+
+        let mut result = String::new();
+        let mut after_last_emoji = 0;
+        for (range, replacement) in emoji {
+            result.push_str(&text[after_last_emoji..range.start]);
+            result.push_str(&replacement);
+            after_last_emoji = range.end;
+        }
+        result.push_str(&text[after_last_emoji..]);
+
+        Cow::Owned(result)
+    }
+}
+
 #[cfg(test)]
 mod test {
-    use super::Emoji;
+    use std::ptr;
+    use std::sync::Arc;
+
+    use super::{Demojifier, Emoji, EmojiLoadError, SkinTones};
 
     #[test]
     fn load_without_panic() {
         Emoji::load();
     }
 
+    #[test]
+    fn embedded_list_is_valid() {
+        assert!(Emoji::try_load().is_ok());
+    }
+
+    #[test]
+    fn load_from_json() {
+        let json = r#"{
+            "wave": "1f44b",
+            "keycap": "34-fe0f-20e3",
+            "bot": "~bot",
+            "not_hex": "zz",
+            "too_large": "110000",
+            "empty": "",
+            "number": 42
+        }"#;
+        let (emoji, invalid) = match Emoji::load_from_json(json) {
+            Err(EmojiLoadError::Entries { emoji, invalid }) => (emoji, invalid),
+            other => panic!("unexpected result {:?}", other.err()),
+        };
+
+        assert_eq!(emoji.get("wave"), Some(Some("👋")));
+        assert_eq!(emoji.get("keycap"), Some(Some("4\u{fe0f}\u{20e3}")));
+        assert_eq!(emoji.get("bot"), Some(None));
+        // Invalid entries are still known, just without unicode
+        assert_eq!(emoji.get("not_hex"), Some(None));
+        assert_eq!(emoji.get("number"), Some(None));
+        assert_eq!(emoji.0.len(), 7);
+
+        let invalid = invalid
+            .iter()
+            .map(|e| (e.name.as_str(), e.value.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            invalid,
+            [
+                ("empty", r#""""#),
+                ("not_hex", r#""zz""#),
+                ("number", "42"),
+                ("too_large", r#""110000""#),
+            ]
+        );
+
+        let json = r#"{"wave": "1f44b", "bot": "~bot"}"#;
+        assert_eq!(Emoji::load_from_json(json).unwrap().0.len(), 2);
+
+        for json in [r#"["1f44b"]"#, "{", ""] {
+            let result = Emoji::load_from_json(json);
+            assert!(matches!(result, Err(EmojiLoadError::Document(_))));
+        }
+    }
+
+    #[test]
+    fn global_is_loaded_once() {
+        assert!(ptr::eq(Emoji::global(), Emoji::global()));
+        assert!(Arc::ptr_eq(&Emoji::global().0, &Emoji::global().0));
+        assert_eq!(Emoji::global().0.len(), Emoji::load().0.len());
+    }
+
+    #[test]
+    fn clones_share_map() {
+        let emoji = Emoji::global().clone();
+        assert!(Arc::ptr_eq(&emoji.0, &Emoji::global().0));
+    }
+
     #[test]
     fn find() {
-        let emoji = Emoji::load();
+        let emoji = Emoji::global();
 
         // :bad: does not exist, while :x: and :o: do.
 
@@ -161,9 +598,41 @@ mod test {
         );
     }
 
+    #[test]
+    fn find_adjacent() {
+        let emoji = Emoji::global();
+
+        assert_eq!(
+            emoji.find(":x::o:"),
+            vec![(0..=2, Some("❌")), (3..=5, Some("⭕"))]
+        );
+        assert_eq!(emoji.find(":x:o:"), vec![(0..=2, Some("❌"))]);
+        assert_eq!(emoji.find(":notreal::x:o:"), vec![(9..=11, Some("❌"))]);
+        assert_eq!(emoji.find(":notreal:x:o:"), vec![(8..=10, Some("❌"))]);
+        assert_eq!(emoji.find("::x:"), vec![(1..=3, Some("❌"))]);
+        assert_eq!(
+            emoji.find(":x:::o:"),
+            vec![(0..=2, Some("❌")), (4..=6, Some("⭕"))]
+        );
+    }
+
+    #[test]
+    fn find_only_colons() {
+        let emoji = Emoji::global();
+
+        assert_eq!(emoji.find(""), vec![]);
+        assert_eq!(emoji.find(":"), vec![]);
+        assert_eq!(emoji.find("::"), vec![]);
+        assert_eq!(emoji.find(&":".repeat(100_000)), vec![]);
+        assert_eq!(
+            emoji.find(&format!("{}x:", ":".repeat(1000))),
+            vec![(999..=1001, Some("❌"))]
+        );
+    }
+
     #[test]
     fn replace() {
-        let emoji = Emoji::load();
+        let emoji = Emoji::global();
         assert_eq!(emoji.replace("no:emo:ji:here"), "no:emo:ji:here");
         assert_eq!(emoji.replace(":bad:x:o:"), ":bad❌o:");
         assert_eq!(emoji.replace(":x:bad:o:"), "❌bad⭕");
@@ -179,7 +648,7 @@ mod test {
 
     #[test]
     fn remove() {
-        let emoji = Emoji::load();
+        let emoji = Emoji::global();
         assert_eq!(emoji.remove("no:emo:ji:here"), "no:emo:ji:here");
         assert_eq!(emoji.remove(":bad:x:o:"), ":bado:");
         assert_eq!(emoji.remove(":x:bad:o:"), "bad");
@@ -192,4 +661,62 @@ mod test {
         );
         assert_eq!(emoji.remove("Jan-20 17:58 Z"), "Jan-20 17:58 Z");
     }
+
+    #[test]
+    fn demojify() {
+        let demojifier = Demojifier::new(Emoji::global());
+        assert_eq!(
+            demojifier.demojify("no emoji here: 1 # *"),
+            "no emoji here: 1 # *"
+        );
+        assert_eq!(demojifier.demojify("👍 ok"), ":thumbsup: ok");
+        assert_eq!(demojifier.demojify("❌⭕"), ":x::o:");
+        // Aliases resolve to the shortest name
+        assert_eq!(demojifier.demojify("🐝"), ":bee:");
+        // Round trip
+        let text = ":crown: chᴜm :ant: :waning_crescent_moon:";
+        assert_eq!(demojifier.demojify(&Emoji::global().replace(text)), text);
+    }
+
+    #[test]
+    fn demojify_variation_selectors() {
+        let demojifier = Demojifier::new(Emoji::global());
+        assert_eq!(Emoji::global().get("heart"), Some(Some("❤\u{fe0f}")));
+        assert_eq!(demojifier.demojify("❤\u{fe0f}"), ":heart:");
+        assert_eq!(demojifier.demojify("❤"), ":heart:");
+        assert_eq!(demojifier.demojify("👍\u{fe0f}"), ":thumbsup:");
+        // Keycaps need their keycap character
+        assert_eq!(demojifier.demojify("#\u{fe0f}\u{20e3}"), ":hash:");
+        assert_eq!(demojifier.demojify("#\u{fe0f}"), "#\u{fe0f}");
+        // Lone variation selectors and modifiers are not emoji
+        assert_eq!(
+            demojifier.demojify("\u{fe0f}\u{1f3fd}"),
+            "\u{fe0f}\u{1f3fd}"
+        );
+    }
+
+    #[test]
+    fn demojify_skin_tones() {
+        let strip = Demojifier::new(Emoji::global());
+        assert_eq!(strip.demojify("👍🏽!"), ":thumbsup:!");
+        assert_eq!(strip.demojify("👍\u{fe0f}🏽"), ":thumbsup:");
+        // ZWJ sequences with skin tones
+        assert_eq!(strip.demojify("🧑🏿\u{200d}🎨"), ":artist:");
+
+        let preserve = Demojifier::new(Emoji::global()).with_skin_tones(SkinTones::Preserve);
+        assert_eq!(preserve.demojify("👍🏽!"), ":thumbs_up_medium_skin_tone:!");
+        assert_eq!(preserve.demojify("🧑🏿\u{200d}🎨"), ":artist_dark_skin_tone:");
+        assert_eq!(preserve.demojify("👍"), ":thumbsup:");
+
+        // Emoji without an entry for their skin tone
+        let json = r#"{"wave": "1f44b", "skin-tone-4": "1f3fd"}"#;
+        let emoji = Emoji::load_from_json(json).unwrap();
+        let preserve = Demojifier::new(&emoji).with_skin_tones(SkinTones::Preserve);
+        assert_eq!(preserve.demojify("👋🏽"), ":wave::skin-tone-4:");
+        assert_eq!(preserve.demojify("👋🏿"), ":wave:🏿");
+        let strip = Demojifier::new(&emoji);
+        assert_eq!(strip.demojify("👋🏽"), ":wave:");
+        // Lone skin tones are never demojified
+        assert_eq!(strip.demojify("🏽"), "🏽");
+    }
 }
@@ -1,9 +1,20 @@
+pub mod account;
 pub mod api;
 #[cfg(feature = "bot")]
 pub mod bot;
+pub mod clock;
 pub mod conn;
+pub mod content;
+#[cfg(feature = "devtools")]
+pub mod devtools;
 mod emoji;
 pub mod nick;
 mod replies;
+pub mod search;
+#[cfg(any(test, feature = "test-util"))]
+pub mod test_util;
+pub mod text;
+#[cfg(feature = "tls")]
+pub mod tls;
 
-pub use emoji::Emoji;
+pub use emoji::{Demojifier, Emoji, EmojiLoadError, InvalidEmoji, SkinTones};
@@ -1,5 +1,7 @@
 //! Nick-related utility functions.
 
+use std::fmt;
+
 use caseless::Caseless;
 use unicode_normalization::UnicodeNormalization;
 
@@ -53,6 +55,136 @@ pub fn hue(emoji: &Emoji, nick: &str) -> u8 {
     hue_without_removing_emoji(&emoji.remove(nick))
 }
 
+/// A color as red, green and blue components.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Rgb {
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+}
+
+impl Rgb {
+    pub fn new(r: u8, g: u8, b: u8) -> Self {
+        Self { r, g, b }
+    }
+
+    /// Convert a CSS-style HSL color to RGB.
+    ///
+    /// The hue is in degrees, the saturation and lightness range from 0 to 1.
+    /// Components are rounded to the nearest integer like browsers do.
+    pub fn from_hsl(hue: f64, saturation: f64, lightness: f64) -> Self {
+        let h = hue.rem_euclid(360.0) / 60.0;
+        let c = (1.0 - (2.0 * lightness - 1.0).abs()) * saturation;
+        let x = c * (1.0 - (h % 2.0 - 1.0).abs());
+        let (r, g, b) = match h as u8 {
+            0 => (c, x, 0.0),
+            1 => (x, c, 0.0),
+            2 => (0.0, c, x),
+            3 => (0.0, x, c),
+            4 => (x, 0.0, c),
+            _ => (c, 0.0, x),
+        };
+        let m = lightness - c / 2.0;
+        let channel = |v: f64| ((v + m) * 255.0).round() as u8;
+        Self::new(channel(r), channel(g), channel(b))
+    }
+
+    /// Format the color like `#rrggbb`.
+    pub fn to_hex(self) -> String {
+        format!("#{:02x}{:02x}{:02x}", self.r, self.g, self.b)
+    }
+
+    /// The closest color in the 256-color palette supported by most terminals.
+    ///
+    /// Only the 6×6×6 color cube and the grayscale ramp are considered, since
+    /// the first 16 colors vary between terminals.
+    pub fn to_ansi256(self) -> u8 {
+        const CUBE: [u8; 6] = [0, 95, 135, 175, 215, 255];
+
+        let nearest = |v: u8| {
+            (0..CUBE.len())
+                .min_by_key(|&i| CUBE[i].abs_diff(v))
+                .expect("cube is not empty")
+        };
+        let (ri, gi, bi) = (nearest(self.r), nearest(self.g), nearest(self.b));
+        let cube = Self::new(CUBE[ri], CUBE[gi], CUBE[bi]);
+        let cube_index = 16 + 36 * ri + 6 * gi + bi;
+
+        let avg = (self.r as u32 + self.g as u32 + self.b as u32) / 3;
+        let gray_i = (avg.saturating_sub(3) / 10).min(23);
+        let gray_v = (8 + 10 * gray_i) as u8;
+        let gray = Self::new(gray_v, gray_v, gray_v);
+        let gray_index = 232 + gray_i as usize;
+
+        if self.distance(gray) < self.distance(cube) {
+            gray_index as u8
+        } else {
+            cube_index as u8
+        }
+    }
+
+    /// The escape sequence setting a terminal's foreground to this color.
+    pub fn to_ansi_truecolor(self) -> String {
+        format!("\x1b[38;2;{};{};{}m", self.r, self.g, self.b)
+    }
+
+    fn distance(self, other: Self) -> u32 {
+        let d = |a: u8, b: u8| (a.abs_diff(b) as u32).pow(2);
+        d(self.r, other.r) + d(self.g, other.g) + d(self.b, other.b)
+    }
+}
+
+impl fmt::Display for Rgb {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.to_hex())
+    }
+}
+
+/// The background a nick is displayed on, see [`color`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Theme {
+    /// The official client's theme, where nicks are shown on a pastel
+    /// background.
+    Light,
+    /// A dark background like that of most terminals, where nicks are shown as
+    /// text of a bright color.
+    ///
+    /// The official client has no dark theme, so these are the colors used by
+    /// [cove][0], the terminal client.
+    ///
+    /// [0]: https://github.com/Garmelon/cove
+    Dark,
+}
+
+impl Theme {
+    /// Saturation and lightness of nick colors in this theme.
+    fn saturation_lightness(self) -> (f64, f64) {
+        match self {
+            // `hsl(hue, 65%, 85%)`, see
+            // https://github.com/CylonicRaider/heim/blob/978c921063e6b06012fc8d16d9fbf1b3a0be1191/client/lib/ui/Nick.js
+            Self::Light => (0.65, 0.85),
+            // `hsl_to_rgb(hue, 1.0, 0.72)` in cove's `euph::util::nick_color`
+            Self::Dark => (1.0, 0.72),
+        }
+    }
+}
+
+/// Calculate the color of a nick with the given hue.
+///
+/// The hue can be calculated via [`hue`]. The official client uses it as the
+/// hue of an HSL color whose saturation and lightness depend on the theme.
+pub fn color(hue: u8, theme: Theme) -> Rgb {
+    let (saturation, lightness) = theme.saturation_lightness();
+    Rgb::from_hsl(hue as f64, saturation, lightness)
+}
+
+/// Calculate how a nick is displayed: with colon-delimited emoji replaced by
+/// their unicode equivalent, and in its [`color`].
+pub fn styled(emoji: &Emoji, nick: &str, theme: Theme) -> (String, Rgb) {
+    let name = emoji.replace(nick).into_owned();
+    (name, color(hue(emoji, nick), theme))
+}
+
 /// Normalize a nick to a form that can be compared against other nicks.
 ///
 /// This normalization is less aggressive than the nick hue normalization. It is
@@ -113,3 +245,77 @@ pub fn mention(nick: &str) -> String {
     }
     result
 }
+
+#[cfg(test)]
+mod test {
+    use crate::emoji::Emoji;
+
+    use super::{color, hue, hue_without_removing_emoji, styled, Rgb, Theme};
+
+    #[test]
+    fn hue_of_toned_emoji() {
+        let emoji = Emoji::global();
+
+        // Like the web client, hue normalization drops all characters except
+        // ASCII alphanumerics, '_' and '-'. Unicode emoji are dropped along with
+        // their skin tones and variation selectors, so they need no special
+        // treatment.
+        let plain = hue(emoji, "Bob");
+        for nick in ["Bob👍", "Bob👍🏽", "👍\u{fe0f}🏿Bob", "Bob:thumbsup:"] {
+            assert_eq!(hue(emoji, nick), plain, "{nick:?}");
+        }
+
+        // If nothing is left after normalization, the web client hashes the
+        // nick as is, including skin tones. Toned emoji don't have the same
+        // hue as their base emoji there, so they mustn't here either.
+        assert_ne!(hue(emoji, "👍🏽"), hue(emoji, "👍"));
+        assert_ne!(hue(emoji, "👍🏽"), hue(emoji, "👍🏿"));
+        assert_eq!(hue(emoji, "👍🏽"), hue_without_removing_emoji("👍🏽"));
+    }
+
+    #[test]
+    fn nick_colors() {
+        let emoji = Emoji::global();
+
+        // Pins the colors of some nicks, so that changes to the hue hashing or
+        // the theme constants are noticed. The expected colors weren't computed
+        // by this module but by Python's `colorsys.hls_to_rgb` from the
+        // constants cited in `Theme::saturation_lightness`, rounding like
+        // browsers do.
+        let nicks = [
+            ("greenie", 148, "#c0f2d7", "#70ffb3"),
+            ("Garmy", 40, "#f2e1c0", "#ffcf70"),
+            ("TellBot", 240, "#c0c0f2", "#7070ff"),
+            (":thumbsup:", 84, "#def2c0", "#c6ff70"),
+        ];
+        for (nick, expected_hue, light, dark) in nicks {
+            let hue = hue(emoji, nick);
+            assert_eq!(hue, expected_hue, "{nick:?}");
+            assert_eq!(color(hue, Theme::Light).to_hex(), light, "{nick:?}");
+            assert_eq!(color(hue, Theme::Dark).to_hex(), dark, "{nick:?}");
+        }
+
+        let (name, rgb) = styled(emoji, "Bob:thumbsup:", Theme::Dark);
+        assert_eq!(name, "Bob👍");
+        assert_eq!(rgb, color(hue(emoji, "Bob"), Theme::Dark));
+    }
+
+    #[test]
+    fn rgb_conversions() {
+        assert_eq!(Rgb::from_hsl(0.0, 1.0, 0.5), Rgb::new(255, 0, 0));
+        assert_eq!(Rgb::from_hsl(120.0, 1.0, 0.7), Rgb::new(102, 255, 102));
+        assert_eq!(Rgb::from_hsl(480.0, 1.0, 0.7), Rgb::new(102, 255, 102));
+        assert_eq!(Rgb::from_hsl(42.0, 0.0, 1.0), Rgb::new(255, 255, 255));
+
+        let rgb = Rgb::new(255, 102, 10);
+        assert_eq!(rgb.to_hex(), "#ff660a");
+        assert_eq!(rgb.to_string(), "#ff660a");
+        assert_eq!(rgb.to_ansi_truecolor(), "\x1b[38;2;255;102;10m");
+
+        assert_eq!(Rgb::new(0, 0, 0).to_ansi256(), 16);
+        assert_eq!(Rgb::new(255, 255, 255).to_ansi256(), 231);
+        assert_eq!(Rgb::new(255, 0, 0).to_ansi256(), 196);
+        assert_eq!(Rgb::new(128, 128, 128).to_ansi256(), 244);
+        assert_eq!(Rgb::new(95, 135, 175).to_ansi256(), 67);
+    }
+}
@@ -5,6 +5,7 @@ use std::time::Duration;
 use std::{error, result};
 
 use tokio::sync::oneshot::{self, Receiver, Sender};
+use tokio::time::Instant;
 
 #[derive(Debug)]
 pub enum Error {
@@ -28,7 +29,7 @@ pub type Result<T> = result::Result<T, Error>;
 #[derive(Debug)]
 pub struct PendingReply<R> {
     timeout: Duration,
-    result: Receiver<R>,
+    result: Receiver<Result<R>>,
 }
 
 impl<R> PendingReply<R> {
@@ -36,18 +37,39 @@ impl<R> PendingReply<R> {
         match tokio::time::timeout(self.timeout, self.result).await {
             Err(_) => Err(Error::TimedOut),
             Ok(Err(_)) => Err(Error::Canceled),
-            Ok(Ok(value)) => Ok(value),
+            Ok(Ok(result)) => result,
         }
     }
 }
 
+/// What happened when trying to complete a pending reply.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Completion<K> {
+    /// The waiter received the reply.
+    Completed,
+    /// Nobody was waiting for a reply with this id.
+    NotPending,
+    /// Somebody was waiting for a reply with this id, but of a different kind.
+    /// The waiter was left untouched and will eventually time out.
+    Mismatch { expected: K },
+}
+
+#[derive(Debug)]
+struct Waiter<K, R> {
+    /// The kind of reply the waiter expects, or [`None`] if any kind will do.
+    expected: Option<K>,
+    /// When the waiter times out.
+    deadline: Instant,
+    tx: Sender<Result<R>>,
+}
+
 #[derive(Debug)]
-pub struct Replies<I, R> {
+pub struct Replies<I, K, R> {
     timeout: Duration,
-    pending: HashMap<I, Sender<R>>,
+    pending: HashMap<I, Waiter<K, R>>,
 }
 
-impl<I, R> Replies<I, R> {
+impl<I, K, R> Replies<I, K, R> {
     pub fn new(timeout: Duration) -> Self {
         Self {
             timeout,
@@ -59,28 +81,145 @@ impl<I, R> Replies<I, R> {
         self.timeout
     }
 
-    pub fn wait_for(&mut self, id: I) -> PendingReply<R>
+    pub fn wait_for(&mut self, id: I, expected: Option<K>) -> PendingReply<R>
     where
         I: Eq + Hash,
     {
         let (tx, rx) = oneshot::channel();
-        self.pending.insert(id, tx);
+        let deadline = Instant::now() + self.timeout;
+        let waiter = Waiter {
+            expected,
+            deadline,
+            tx,
+        };
+        self.pending.insert(id, waiter);
         PendingReply {
             timeout: self.timeout,
             result: rx,
         }
     }
 
-    pub fn complete(&mut self, id: &I, result: R)
+    /// Hand a reply of kind `kind` to whoever is waiting for `id`.
+    ///
+    /// Replies of an unexpected kind are not handed out.
+    pub fn complete(&mut self, id: &I, kind: &K, result: R) -> Completion<K>
+    where
+        I: Eq + Hash,
+        K: PartialEq + Clone,
+    {
+        let waiter = match self.pending.get(id) {
+            Some(waiter) => waiter,
+            None => return Completion::NotPending,
+        };
+
+        if let Some(expected) = &waiter.expected {
+            if expected != kind {
+                return Completion::Mismatch {
+                    expected: expected.clone(),
+                };
+            }
+        }
+
+        if let Some(waiter) = self.pending.remove(id) {
+            let _ = waiter.tx.send(Ok(result));
+        }
+        Completion::Completed
+    }
+
+    #[cfg(test)]
+    pub fn len(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// The number of replies that are still being waited for.
+    pub fn count_pending(&self) -> usize {
+        self.pending.values().filter(|w| !w.tx.is_closed()).count()
+    }
+
+    /// Forget waiters that are no longer waiting or have timed out.
+    ///
+    /// Timed out waiters receive [`Error::TimedOut`], even if their own timer
+    /// hasn't run out yet.
+    pub fn purge(&mut self)
     where
         I: Eq + Hash,
     {
-        if let Some(tx) = self.pending.remove(id) {
-            let _ = tx.send(result);
+        let now = Instant::now();
+        for (id, waiter) in std::mem::take(&mut self.pending) {
+            if waiter.tx.is_closed() {
+                continue;
+            }
+            if waiter.deadline <= now {
+                let _ = waiter.tx.send(Err(Error::TimedOut));
+                continue;
+            }
+            self.pending.insert(id, waiter);
         }
     }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::{Completion, Error, Replies};
+
+    #[tokio::test(start_paused = true)]
+    async fn mismatched_kind_is_not_completed() {
+        let mut replies = Replies::<u32, &str, u32>::new(Duration::from_secs(60));
+        let pending = replies.wait_for(1, Some("nick-reply"));
+
+        assert_eq!(
+            replies.complete(&1, &"get-message-reply", 42),
+            Completion::Mismatch {
+                expected: "nick-reply"
+            }
+        );
+        assert_eq!(replies.len(), 1);
+
+        tokio::time::advance(Duration::from_secs(60)).await;
+        replies.purge();
+        assert_eq!(replies.len(), 0);
+        assert!(matches!(pending.get().await, Err(Error::TimedOut)));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn purge_forgets_timed_out_and_dropped_waiters() {
+        let mut replies = Replies::<u32, &str, u32>::new(Duration::from_secs(60));
+
+        let first = replies.wait_for(1, None);
+        tokio::time::advance(Duration::from_secs(30)).await;
+        let second = replies.wait_for(2, None);
+        drop(replies.wait_for(3, None));
+
+        replies.purge();
+        assert_eq!(replies.len(), 2);
+
+        tokio::time::advance(Duration::from_secs(30)).await;
+        replies.purge();
+        assert_eq!(replies.len(), 1);
+        assert!(matches!(first.get().await, Err(Error::TimedOut)));
+
+        assert_eq!(replies.complete(&2, &"reply", 2), Completion::Completed);
+        assert_eq!(second.get().await.unwrap(), 2);
+    }
+
+    #[tokio::test]
+    async fn matching_kind_is_completed_once() {
+        let mut replies = Replies::<u32, &str, u32>::new(Duration::from_millis(50));
+        let pending = replies.wait_for(1, Some("nick-reply"));
+        let any = replies.wait_for(2, None);
 
-    pub fn purge(&mut self) {
-        self.pending.retain(|_, tx| !tx.is_closed());
+        assert_eq!(
+            replies.complete(&1, &"nick-reply", 1),
+            Completion::Completed
+        );
+        assert_eq!(
+            replies.complete(&1, &"nick-reply", 2),
+            Completion::NotPending
+        );
+        assert_eq!(replies.complete(&2, &"whatever", 3), Completion::Completed);
+        assert_eq!(pending.get().await.unwrap(), 1);
+        assert_eq!(any.get().await.unwrap(), 3);
     }
 }
@@ -0,0 +1,468 @@
+//! Searching a room's message log.
+//!
+//! The server only offers a [`Log`] command that returns messages before a
+//! given id. [`search_log`] uses it to find messages posted within a
+//! [`TimeRange`] that match a predicate, without fetching the entire log.
+
+use std::time::Duration;
+
+use caseless::Caseless;
+use jiff::Timestamp;
+use unicode_normalization::UnicodeNormalization;
+
+use crate::api::{Log, LogCompleteness, LogReply, Message, MessageId, Snowflake, Time};
+use crate::conn::{self, ConnTx};
+use crate::emoji::Emoji;
+
+/// How often [`search_log`] retries a command whose reply doesn't match it.
+const MISMATCH_RETRIES: usize = 3;
+
+/// A range of time, including both its start and its end.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimeRange {
+    pub start: Timestamp,
+    pub end: Timestamp,
+}
+
+impl TimeRange {
+    pub fn new(start: Timestamp, end: Timestamp) -> Self {
+        Self { start, end }
+    }
+
+    /// Whether a message's [`Time`] lies within the range.
+    ///
+    /// Since [`Time`] only has a resolution of one second, the start and end
+    /// of the range are rounded down to the second.
+    pub fn contains(&self, time: Time) -> bool {
+        !self.is_before(time) && !self.is_after(time)
+    }
+
+    fn is_before(&self, time: Time) -> bool {
+        time.0 < Time::from_timestamp(self.start).0
+    }
+
+    fn is_after(&self, time: Time) -> bool {
+        time.0 > Time::from_timestamp(self.end).0
+    }
+}
+
+/// Limits on how much work [`search_log`] may do.
+///
+/// # Example
+///
+/// ```
+/// use euphoxide::search::SearchLimits;
+///
+/// let limits = SearchLimits::new().with_max_scanned(500).with_page_size(100);
+/// assert_eq!(limits.max_scanned, 500);
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SearchLimits {
+    /// Maximum number of messages to request from the server.
+    ///
+    /// This includes the single messages requested while seeking to the end
+    /// of the range.
+    pub max_scanned: usize,
+    /// Number of messages to request per [`Log`] command (at most 1000).
+    pub page_size: usize,
+    /// How long to wait before the next command if the server throttled the
+    /// reply to the previous one.
+    pub throttle_delay: Duration,
+}
+
+impl SearchLimits {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_max_scanned(mut self, max_scanned: usize) -> Self {
+        self.max_scanned = max_scanned;
+        self
+    }
+
+    pub fn with_page_size(mut self, page_size: usize) -> Self {
+        self.page_size = page_size;
+        self
+    }
+
+    pub fn with_throttle_delay(mut self, throttle_delay: Duration) -> Self {
+        self.throttle_delay = throttle_delay;
+        self
+    }
+}
+
+impl Default for SearchLimits {
+    fn default() -> Self {
+        Self {
+            max_scanned: 10_000,
+            page_size: 1000,
+            throttle_delay: Duration::from_secs(1),
+        }
+    }
+}
+
+/// The result of a [`search_log`].
+#[derive(Debug, Clone)]
+pub struct SearchResult {
+    /// The matching messages, in chronological order.
+    pub messages: Vec<Message>,
+    /// How many messages were requested from the server.
+    pub scanned: usize,
+    /// Whether the search stopped before the whole range was searched, either
+    /// because [`SearchLimits::max_scanned`] was reached or because the server
+    /// kept sending replies that don't match their commands.
+    ///
+    /// If this is `true`, older messages within the range may be missing.
+    pub truncated: bool,
+}
+
+struct Search<'a> {
+    conn_tx: &'a ConnTx,
+    limits: SearchLimits,
+    scanned: usize,
+}
+
+impl Search<'_> {
+    fn remaining(&self) -> usize {
+        self.limits.max_scanned.saturating_sub(self.scanned)
+    }
+
+    /// Request up to `n` messages before `before`, oldest first.
+    async fn log(&mut self, n: usize, before: Option<MessageId>) -> conn::Result<LogReply> {
+        let reply = self.conn_tx.send(Log { n, before }).await?;
+        self.scanned += n;
+
+        if self.conn_tx.debug_info().await?.throttled.is_some() {
+            tokio::time::sleep(self.limits.throttle_delay).await;
+        }
+
+        Ok(reply)
+    }
+
+    /// Find an id such that all messages before it were sent no later than
+    /// the end of `range`.
+    ///
+    /// Message ids increase over time, so the newest message before an id is a
+    /// monotonic function of the id and can be binary searched. This makes no
+    /// assumptions about how the server generates its ids. Returns `None` if
+    /// the newest message in the room is within the range.
+    async fn seek(&mut self, range: &TimeRange) -> conn::Result<Option<MessageId>> {
+        let newest = match self.log(1, None).await?.log.pop() {
+            Some(msg) if range.is_after(msg.time) => msg,
+            _ => return Ok(None),
+        };
+
+        // The newest message before `lo` is within or before the range, the
+        // one before `hi` is after the range.
+        let mut lo = 0;
+        let mut hi = newest.id.0 .0 + 1;
+        while hi - lo > 1 && self.remaining() > 0 {
+            let mid = lo + (hi - lo) / 2;
+            match self
+                .log(1, Some(MessageId(Snowflake(mid))))
+                .await?
+                .log
+                .pop()
+            {
+                Some(msg) if range.is_after(msg.time) => hi = mid,
+                _ => lo = mid,
+            }
+        }
+
+        Ok(Some(MessageId(Snowflake(lo))))
+    }
+}
+
+/// Find messages within a time range that match a predicate.
+///
+/// The log is searched backwards in pages of [`SearchLimits::page_size`]
+/// messages, starting at the end of the range. The predicate is only called
+/// for messages within the range. If the server throttles a reply, the search
+/// waits for [`SearchLimits::throttle_delay`] before sending the next command.
+///
+/// The server may truncate replies without saying so, see
+/// [`LogReply::completeness`]. If a reply contains fewer messages than
+/// requested, the command is retried with as many messages as the reply
+/// contained, and the search continues with pages of that size. If a reply
+/// doesn't match its command, the command is retried with half as many
+/// messages, up to three times in a row. This way, the end of the log is only
+/// assumed to be reached once the server sends an empty reply.
+///
+/// The search stops once it reaches the start of the range, the start of the
+/// log, [`SearchLimits::max_scanned`] or the retry limit for mismatched
+/// replies. In the latter two cases, the result is marked as
+/// [`truncated`](SearchResult::truncated).
+///
+/// The [`ConnTx`] must belong to a [`Conn`](conn::Conn) whose
+/// [`recv`](conn::Conn::recv) is being called while searching.
+pub async fn search_log<P>(
+    conn_tx: &ConnTx,
+    range: TimeRange,
+    predicate: P,
+    limits: SearchLimits,
+) -> conn::Result<SearchResult>
+where
+    P: Fn(&Message) -> bool,
+{
+    let mut search = Search {
+        conn_tx,
+        limits,
+        scanned: 0,
+    };
+    let mut messages = vec![];
+
+    let mut before = search.seek(&range).await?;
+    let mut page_size = search.limits.page_size;
+    let mut mismatches = 0;
+    let truncated = loop {
+        let n = page_size.min(search.remaining());
+        if n == 0 {
+            break true;
+        }
+
+        let reply = search.log(n, before).await?;
+        match reply.completeness(&Log { n, before }) {
+            LogCompleteness::Complete => mismatches = 0,
+            LogCompleteness::StartOfLog => break false,
+            LogCompleteness::Short => {
+                // Either this is the start of the log or the server truncated
+                // the reply, in which case we don't know which messages it
+                // left out. Asking again for only as many messages as it sent
+                // settles it either way.
+                page_size = reply.log.len();
+                continue;
+            }
+            LogCompleteness::Mismatched if mismatches < MISMATCH_RETRIES => {
+                mismatches += 1;
+                page_size = (n / 2).max(1);
+                continue;
+            }
+            LogCompleteness::Mismatched => break true,
+        }
+
+        let page = reply.log;
+        let reached_start = page.first().is_none_or(|m| range.is_before(m.time));
+
+        messages.extend(
+            page.iter()
+                .rev()
+                .filter(|m| range.contains(m.time) && predicate(m))
+                .cloned(),
+        );
+
+        if reached_start {
+            break false;
+        }
+        before = page.first().map(|m| m.id);
+    };
+
+    messages.reverse();
+    Ok(SearchResult {
+        messages,
+        scanned: search.scanned,
+        truncated,
+    })
+}
+
+fn normalize(text: &str) -> String {
+    Emoji::global()
+        .replace(text)
+        .nfkc()
+        .default_case_fold()
+        .collect()
+}
+
+/// A predicate for [`search_log`] that matches messages containing some text.
+///
+/// The comparison is case-insensitive. Colon-delimited emoji like
+/// `:thumbsup:` are replaced by their unicode equivalent first, so `:thumbsup:`
+/// and `👍` match each other.
+pub fn contains_text(text: &str) -> impl Fn(&Message) -> bool {
+    let text = normalize(text);
+    move |msg| normalize(&msg.content).contains(&text)
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use jiff::Timestamp;
+
+    use crate::api::{Message, MessageId, Snowflake, Time};
+    use crate::conn::test::{connect, hello, Server};
+    use crate::test_util::MessageBuilder;
+
+    use super::{contains_text, search_log, SearchLimits, SearchResult, TimeRange};
+
+    const TIMEOUT: Duration = Duration::from_secs(10);
+
+    /// Message `i` has the id `1000 * i` and was sent at second `10 * i`.
+    fn message(i: u64, content: &str) -> Message {
+        MessageBuilder::new(content)
+            .with_id(MessageId(Snowflake(1000 * i)))
+            .with_time(Time(10 * i as i64))
+            .build()
+    }
+
+    fn log() -> Vec<Message> {
+        (1..=100)
+            .map(|i| match i {
+                25 => message(i, "look at https://example.com"),
+                75 => message(i, "HTTPS://EXAMPLE.COM :thumbsup:"),
+                _ => message(i, &format!("message {i}")),
+            })
+            .collect()
+    }
+
+    /// How the server answers log commands asking for more messages than it is
+    /// willing to send.
+    #[derive(Clone, Copy)]
+    enum Cap {
+        None,
+        /// Send only the oldest messages of the page.
+        Truncate(usize),
+        /// Send a reply that doesn't match the command.
+        Mismatch(usize),
+    }
+
+    /// Answer log commands using the synthetic log.
+    async fn serve(mut server: Server, cap: Cap) {
+        server.join(hello(false, None)).await;
+        let log = log();
+        while let Some(cmd) = server.recv().await {
+            let n = cmd["data"]["n"].as_u64().unwrap() as usize;
+            let before = match cmd["data"]["before"].as_str() {
+                Some(before) => before.parse::<Snowflake>().unwrap().0,
+                None => u64::MAX,
+            };
+            let older = log
+                .iter()
+                .filter(|m| m.id.0 .0 < before)
+                .collect::<Vec<_>>();
+            let mut page = &older[older.len().saturating_sub(n)..];
+            let data = match cap {
+                Cap::Truncate(cap) if n > cap => {
+                    page = &page[..cap.min(page.len())];
+                    serde_json::json!({ "log": page, "before": cmd["data"]["before"] })
+                }
+                Cap::Mismatch(cap) if n > cap => {
+                    serde_json::json!({ "log": [], "before": Snowflake(1).to_string() })
+                }
+                _ => serde_json::json!({ "log": page, "before": cmd["data"]["before"] }),
+            };
+            server
+                .send(serde_json::json!({ "id": cmd["id"], "type": "log-reply", "data": data }))
+                .await;
+        }
+    }
+
+    async fn search<P>(range: (i64, i64), predicate: P, limits: SearchLimits) -> SearchResult
+    where
+        P: Fn(&Message) -> bool,
+    {
+        search_capped(range, predicate, limits, Cap::None).await
+    }
+
+    async fn search_capped<P>(
+        range: (i64, i64),
+        predicate: P,
+        limits: SearchLimits,
+        cap: Cap,
+    ) -> SearchResult
+    where
+        P: Fn(&Message) -> bool,
+    {
+        let (mut conn, server) = connect(TIMEOUT).await;
+        tokio::spawn(serve(server, cap));
+        let conn_tx = conn.tx().clone();
+        let range = TimeRange::new(
+            Timestamp::from_second(range.0).unwrap(),
+            Timestamp::from_second(range.1).unwrap(),
+        );
+
+        let search = search_log(&conn_tx, range, predicate, limits);
+        tokio::pin!(search);
+        loop {
+            tokio::select! {
+                result = &mut search => break result.unwrap(),
+                result = conn.recv() => { result.unwrap(); }
+            }
+        }
+    }
+
+    fn ids(result: &SearchResult) -> Vec<u64> {
+        result.messages.iter().map(|m| m.id.0 .0 / 1000).collect()
+    }
+
+    #[tokio::test]
+    async fn range_edges() {
+        let limits = SearchLimits::new().with_page_size(7);
+
+        let result = search((200, 300), |_| true, limits).await;
+        assert_eq!(ids(&result), (20..=30).collect::<Vec<_>>());
+        assert!(!result.truncated);
+        // Seeking avoids scanning all messages after the range
+        assert!(result.scanned < 50);
+
+        let result = search((995, 2000), |_| true, limits).await;
+        assert_eq!(ids(&result), vec![100]);
+        assert!(!result.truncated);
+
+        let result = search((0, 15), |_| true, limits).await;
+        assert_eq!(ids(&result), vec![1]);
+        assert!(!result.truncated);
+
+        let result = search((1001, 2000), |_| true, limits).await;
+        assert_eq!(ids(&result), Vec::<u64>::new());
+        assert!(!result.truncated);
+    }
+
+    #[tokio::test]
+    async fn budget_truncation() {
+        let limits = SearchLimits::new().with_page_size(10).with_max_scanned(21);
+        let result = search((0, 1000), |_| true, limits).await;
+        // One message while seeking, then two pages of ten
+        assert_eq!(ids(&result), (81..=100).collect::<Vec<_>>());
+        assert_eq!(result.scanned, 21);
+        assert!(result.truncated);
+    }
+
+    #[tokio::test]
+    async fn text_predicate() {
+        let limits = SearchLimits::new();
+        let result = search((0, 1000), contains_text("https://example.com"), limits).await;
+        assert_eq!(ids(&result), vec![25, 75]);
+
+        let result = search((0, 500), contains_text("Example.com"), limits).await;
+        assert_eq!(ids(&result), vec![25]);
+
+        let result = search((0, 1000), contains_text("👍"), limits).await;
+        assert_eq!(ids(&result), vec![75]);
+    }
+
+    #[tokio::test]
+    async fn truncated_replies_are_retried() {
+        let limits = SearchLimits::new().with_page_size(10);
+        let all = (1..=100).collect::<Vec<_>>();
+
+        // Short replies are retried with fewer messages, so nothing the server
+        // left out is skipped
+        let result = search_capped((0, 1000), |_| true, limits, Cap::Truncate(4)).await;
+        assert_eq!(ids(&result), all);
+        assert!(!result.truncated);
+
+        let result = search_capped((200, 300), |_| true, limits, Cap::Truncate(4)).await;
+        assert_eq!(ids(&result), (20..=30).collect::<Vec<_>>());
+        assert!(!result.truncated);
+
+        // Mismatched replies are retried with half as many messages
+        let result = search_capped((0, 1000), |_| true, limits, Cap::Mismatch(3)).await;
+        assert_eq!(ids(&result), all);
+        assert!(!result.truncated);
+
+        // Until the server has been given enough chances
+        let result = search_capped((0, 1000), |_| true, limits, Cap::Mismatch(0)).await;
+        assert!(result.messages.is_empty());
+        assert!(result.truncated);
+    }
+}
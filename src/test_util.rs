@@ -0,0 +1,541 @@
+//! Builders for connection states and messages, for testing code that uses
+//! them.
+//!
+//! All fields of [`State`], [`Joined`], [`SessionView`] and [`Message`] are
+//! public, but building realistic values by hand is verbose and breaks whenever
+//! a field is added. These builders fill in sensible defaults instead.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use jiff::Timestamp;
+
+use crate::api::{
+    Message, MessageId, PersonalAccountView, SessionId, SessionType, SessionView, Snowflake, Time,
+    UserId,
+};
+use crate::conn::{Joined, Joining, MessageLog, MessageTimes, SessionInfo, State};
+
+fn prefix(kind: &SessionType) -> &'static str {
+    match kind {
+        SessionType::Agent => "agent",
+        SessionType::Account => "account",
+        SessionType::Bot => "bot",
+    }
+}
+
+/// Builds a [`SessionView`].
+///
+/// By default, the session is an agent whose user and session ids are derived
+/// from its name.
+#[derive(Debug, Clone)]
+pub struct SessionViewBuilder {
+    view: SessionView,
+    /// Whether the user id was set explicitly and must not be derived anymore.
+    custom_id: bool,
+}
+
+impl SessionViewBuilder {
+    pub fn new<S: ToString>(name: S) -> Self {
+        let name = name.to_string();
+        Self {
+            view: SessionView {
+                id: UserId(format!("agent:{name}")),
+                session_id: SessionId(name.clone()),
+                name,
+                server_id: "heim.1".to_string(),
+                server_era: "era".to_string(),
+                is_staff: false,
+                is_manager: false,
+                client_address: None,
+                real_client_address: None,
+            },
+            custom_id: false,
+        }
+    }
+
+    /// Derive the user id from the name using this type's prefix.
+    ///
+    /// Has no effect once [`Self::with_id`] was called.
+    pub fn with_kind(mut self, kind: SessionType) -> Self {
+        if !self.custom_id {
+            self.view.id = UserId(format!("{}:{}", prefix(&kind), self.view.name));
+        }
+        self
+    }
+
+    pub fn with_id(mut self, id: UserId) -> Self {
+        self.view.id = id;
+        self.custom_id = true;
+        self
+    }
+
+    pub fn with_session_id(mut self, session_id: SessionId) -> Self {
+        self.view.session_id = session_id;
+        self
+    }
+
+    pub fn with_server<S1: ToString, S2: ToString>(
+        mut self,
+        server_id: S1,
+        server_era: S2,
+    ) -> Self {
+        self.view.server_id = server_id.to_string();
+        self.view.server_era = server_era.to_string();
+        self
+    }
+
+    pub fn with_staff(mut self, is_staff: bool) -> Self {
+        self.view.is_staff = is_staff;
+        self
+    }
+
+    pub fn with_manager(mut self, is_manager: bool) -> Self {
+        self.view.is_manager = is_manager;
+        self
+    }
+
+    pub fn with_client_address<S: ToString>(self, client_address: S) -> Self {
+        self.with_client_address_opt(Some(client_address))
+    }
+
+    pub fn with_client_address_opt<S: ToString>(mut self, client_address: Option<S>) -> Self {
+        self.view.client_address = client_address.map(|a| a.to_string());
+        self
+    }
+
+    pub fn with_real_client_address<S: ToString>(self, real_client_address: S) -> Self {
+        self.with_real_client_address_opt(Some(real_client_address))
+    }
+
+    pub fn with_real_client_address_opt<S: ToString>(
+        mut self,
+        real_client_address: Option<S>,
+    ) -> Self {
+        self.view.real_client_address = real_client_address.map(|a| a.to_string());
+        self
+    }
+
+    pub fn build(self) -> SessionView {
+        self.view
+    }
+}
+
+/// Builds a [`Message`].
+///
+/// By default, the message has the id 1, isn't a reply and was sent at the
+/// unix epoch by an agent named `someone`.
+#[derive(Debug, Clone)]
+pub struct MessageBuilder {
+    message: Message,
+}
+
+impl MessageBuilder {
+    pub fn new<S: ToString>(content: S) -> Self {
+        Self {
+            message: Message {
+                id: MessageId(Snowflake(1)),
+                parent: None,
+                previous_edit_id: None,
+                time: Time(0),
+                sender: SessionViewBuilder::new("someone").build(),
+                content: content.to_string(),
+                encryption_key_id: None,
+                edited: None,
+                deleted: None,
+                truncated: false,
+            },
+        }
+    }
+
+    pub fn with_id(mut self, id: MessageId) -> Self {
+        self.message.id = id;
+        self
+    }
+
+    pub fn with_parent(self, parent: MessageId) -> Self {
+        self.with_parent_opt(Some(parent))
+    }
+
+    pub fn with_parent_opt(mut self, parent: Option<MessageId>) -> Self {
+        self.message.parent = parent;
+        self
+    }
+
+    pub fn with_time(mut self, time: Time) -> Self {
+        self.message.time = time;
+        self
+    }
+
+    pub fn with_sender(mut self, sender: SessionView) -> Self {
+        self.message.sender = sender;
+        self
+    }
+
+    pub fn with_edited(self, edited: Time) -> Self {
+        self.with_edited_opt(Some(edited))
+    }
+
+    pub fn with_edited_opt(mut self, edited: Option<Time>) -> Self {
+        self.message.edited = edited;
+        self
+    }
+
+    pub fn with_deleted(self, deleted: Time) -> Self {
+        self.with_deleted_opt(Some(deleted))
+    }
+
+    pub fn with_deleted_opt(mut self, deleted: Option<Time>) -> Self {
+        self.message.deleted = deleted;
+        self
+    }
+
+    pub fn with_truncated(mut self, truncated: bool) -> Self {
+        self.message.truncated = truncated;
+        self
+    }
+
+    pub fn build(self) -> Message {
+        self.message
+    }
+}
+
+/// Builds a [`Joined`] state.
+///
+/// By default, the own session is a bot named `TestBot` that joined just now,
+/// isn't logged in and is alone in a public room.
+#[derive(Debug, Clone)]
+pub struct JoinedBuilder {
+    joined: Joined,
+}
+
+impl JoinedBuilder {
+    pub fn new() -> Self {
+        let session = SessionViewBuilder::new("TestBot")
+            .with_kind(SessionType::Bot)
+            .with_session_id(SessionId("me".to_string()))
+            .build();
+        Self {
+            joined: Joined {
+                since: Timestamp::now(),
+                session,
+                account: None,
+                account_email_verified: None,
+                last_who: None,
+                room_is_private: false,
+                pm_counterpart: None,
+                listing: Arc::new(HashMap::new()),
+                message_times: None,
+                message_log: None,
+            },
+        }
+    }
+
+    /// The own session.
+    pub fn with_session(mut self, session: SessionView) -> Self {
+        self.joined.session = session;
+        self
+    }
+
+    pub fn with_since(mut self, since: Timestamp) -> Self {
+        self.joined.since = since;
+        self
+    }
+
+    pub fn with_account(self, account: PersonalAccountView) -> Self {
+        self.with_account_opt(Some(account))
+    }
+
+    pub fn with_account_opt(mut self, account: Option<PersonalAccountView>) -> Self {
+        self.joined.account = account;
+        self
+    }
+
+    pub fn with_account_email_verified(self, account_email_verified: bool) -> Self {
+        self.with_account_email_verified_opt(Some(account_email_verified))
+    }
+
+    pub fn with_account_email_verified_opt(mut self, account_email_verified: Option<bool>) -> Self {
+        self.joined.account_email_verified = account_email_verified;
+        self
+    }
+
+    pub fn with_last_who(self, last_who: Timestamp) -> Self {
+        self.with_last_who_opt(Some(last_who))
+    }
+
+    pub fn with_last_who_opt(mut self, last_who: Option<Timestamp>) -> Self {
+        self.joined.last_who = last_who;
+        self
+    }
+
+    pub fn with_room_is_private(mut self, room_is_private: bool) -> Self {
+        self.joined.room_is_private = room_is_private;
+        self
+    }
+
+    pub fn with_pm_counterpart<S: ToString>(self, pm_counterpart: (UserId, S)) -> Self {
+        self.with_pm_counterpart_opt(Some(pm_counterpart))
+    }
+
+    pub fn with_pm_counterpart_opt<S: ToString>(
+        mut self,
+        pm_counterpart: Option<(UserId, S)>,
+    ) -> Self {
+        self.joined.pm_counterpart = pm_counterpart.map(|(id, nick)| (id, nick.to_string()));
+        self
+    }
+
+    pub fn with_message_times(self, message_times: MessageTimes) -> Self {
+        self.with_message_times_opt(Some(message_times))
+    }
+
+    pub fn with_message_times_opt(mut self, message_times: Option<MessageTimes>) -> Self {
+        self.joined.message_times = message_times.map(Arc::new);
+        self
+    }
+
+    pub fn with_message_log(self, message_log: MessageLog) -> Self {
+        self.with_message_log_opt(Some(message_log))
+    }
+
+    pub fn with_message_log_opt(mut self, message_log: Option<MessageLog>) -> Self {
+        self.joined.message_log = message_log.map(Arc::new);
+        self
+    }
+
+    /// Add a session to the listing, replacing any session with the same id.
+    pub fn with_listing_entry(mut self, info: SessionInfo) -> Self {
+        let session_id = info.session_id().clone();
+        Arc::make_mut(&mut self.joined.listing).insert(session_id, info);
+        self
+    }
+
+    /// Add a session with this name and type to the listing.
+    ///
+    /// The session gets a session id that is unique within the listing, so the
+    /// same name can be added multiple times.
+    pub fn with_member<S: ToString>(self, name: S, kind: SessionType) -> Self {
+        let name = name.to_string();
+        let mut session_id = SessionId(name.clone());
+        let mut n = 1;
+        while self.joined.listing.contains_key(&session_id)
+            || session_id == self.joined.session.session_id
+        {
+            n += 1;
+            session_id = SessionId(format!("{name}-{n}"));
+        }
+        let view = SessionViewBuilder::new(name)
+            .with_kind(kind)
+            .with_session_id(session_id)
+            .build();
+        self.with_listing_entry(SessionInfo::Full(view))
+    }
+
+    pub fn build(self) -> Joined {
+        self.joined
+    }
+}
+
+impl Default for JoinedBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Builds a [`State`].
+///
+/// By default, the state is the default [`JoinedBuilder`]'s state.
+#[derive(Debug, Clone)]
+pub struct StateBuilder {
+    state: State,
+}
+
+impl StateBuilder {
+    pub fn new() -> Self {
+        Self::joined(JoinedBuilder::new())
+    }
+
+    /// A session that has joined its room.
+    pub fn joined(joined: JoinedBuilder) -> Self {
+        Self {
+            state: State::Joined(joined.build()),
+        }
+    }
+
+    /// A session that has connected just now but hasn't received any packets.
+    pub fn joining() -> Self {
+        Self {
+            state: State::Joining(Joining {
+                since: Timestamp::now(),
+                hello: None,
+                snapshot: None,
+                bounce: None,
+            }),
+        }
+    }
+
+    /// Modify the [`Joining`] state.
+    ///
+    /// Has no effect if the state is [`State::Joined`].
+    pub fn with_joining<F: FnOnce(&mut Joining)>(mut self, f: F) -> Self {
+        if let State::Joining(joining) = &mut self.state {
+            f(joining);
+        }
+        self
+    }
+
+    pub fn build(self) -> State {
+        self.state
+    }
+}
+
+impl Default for StateBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use jiff::Timestamp;
+
+    use crate::api::{
+        AccountId, MessageId, PersonalAccountView, SessionId, SessionType, Snowflake, Time, UserId,
+    };
+    use crate::conn::{MessageLog, MessageTimes, MessageTimesConfig, SessionInfo, State};
+
+    use super::{JoinedBuilder, MessageBuilder, SessionViewBuilder, StateBuilder};
+
+    #[test]
+    fn session_view() {
+        let view = SessionViewBuilder::new("alice").build();
+        assert_eq!(view.id, UserId("agent:alice".to_string()));
+        assert_eq!(view.session_id, SessionId("alice".to_string()));
+        assert_eq!(view.name, "alice");
+
+        let view = SessionViewBuilder::new("bob")
+            .with_kind(SessionType::Account)
+            .with_session_id(SessionId("s".to_string()))
+            .with_server("heim.2", "era2")
+            .with_staff(true)
+            .with_manager(true)
+            .with_client_address("1.2.3.4")
+            .with_real_client_address("5.6.7.8")
+            .build();
+        assert_eq!(view.id, UserId("account:bob".to_string()));
+        assert_eq!(view.session_id, SessionId("s".to_string()));
+        assert_eq!((&*view.server_id, &*view.server_era), ("heim.2", "era2"));
+        assert!(view.is_staff && view.is_manager);
+        assert_eq!(view.client_address.as_deref(), Some("1.2.3.4"));
+        assert_eq!(view.real_client_address.as_deref(), Some("5.6.7.8"));
+
+        // An explicit id isn't overwritten by the kind
+        let id = UserId("bot:custom".to_string());
+        let view = SessionViewBuilder::new("carol")
+            .with_id(id.clone())
+            .with_kind(SessionType::Agent)
+            .build();
+        assert_eq!(view.id, id);
+    }
+
+    #[test]
+    fn message() {
+        let message = MessageBuilder::new("hello").build();
+        assert_eq!(message.id, MessageId(Snowflake(1)));
+        assert_eq!(message.parent, None);
+        assert_eq!(message.sender.name, "someone");
+        assert_eq!(message.content, "hello");
+
+        let sender = SessionViewBuilder::new("alice").build();
+        let message = MessageBuilder::new("edited")
+            .with_id(MessageId(Snowflake(3)))
+            .with_parent(MessageId(Snowflake(2)))
+            .with_time(Time(10))
+            .with_sender(sender.clone())
+            .with_edited(Time(20))
+            .with_deleted(Time(30))
+            .with_truncated(true)
+            .build();
+        assert_eq!(message.id, MessageId(Snowflake(3)));
+        assert_eq!(message.parent, Some(MessageId(Snowflake(2))));
+        assert_eq!(message.time, Time(10));
+        assert_eq!(message.sender, sender);
+        assert_eq!(message.edited, Some(Time(20)));
+        assert_eq!(message.deleted, Some(Time(30)));
+        assert!(message.truncated);
+    }
+
+    #[test]
+    fn joined() {
+        let joined = JoinedBuilder::new().build();
+        assert_eq!(joined.session.name, "TestBot");
+        assert_eq!(joined.session.id.session_type(), Some(SessionType::Bot));
+        assert!(joined.listing.is_empty());
+        assert!(joined.account.is_none());
+
+        let since = Timestamp::UNIX_EPOCH;
+        let account = PersonalAccountView {
+            id: AccountId(Snowflake(1)),
+            name: "Bot".to_string(),
+            email: "bot@example.com".to_string(),
+        };
+        let counterpart = (UserId("account:other".to_string()), "other");
+        let message_times = MessageTimes::new(MessageTimesConfig::new());
+        let joined = JoinedBuilder::new()
+            .with_session(SessionViewBuilder::new("Me").build())
+            .with_since(since)
+            .with_account(account)
+            .with_account_email_verified(true)
+            .with_last_who(since)
+            .with_room_is_private(true)
+            .with_pm_counterpart(counterpart)
+            .with_message_times(message_times)
+            .with_message_log(MessageLog::new(10))
+            .with_member("alice", SessionType::Agent)
+            .with_member("alice", SessionType::Account)
+            .with_member("Me", SessionType::Bot)
+            .with_listing_entry(SessionInfo::Full(SessionViewBuilder::new("dave").build()))
+            .build();
+        assert_eq!(joined.session.name, "Me");
+        assert_eq!(joined.since, since);
+        assert_eq!(joined.account.as_ref().unwrap().name, "Bot");
+        assert_eq!(joined.account_email_verified, Some(true));
+        assert_eq!(joined.last_who, Some(since));
+        assert!(joined.room_is_private);
+        assert_eq!(joined.pm_counterpart.as_ref().unwrap().1, "other");
+        assert!(joined.message_times.is_some());
+        assert_eq!(joined.message_log.as_ref().unwrap().capacity(), 10);
+
+        // Members with the same name get different session ids
+        let mut ids = joined
+            .listing
+            .keys()
+            .map(|id| id.0.clone())
+            .collect::<Vec<_>>();
+        ids.sort();
+        assert_eq!(ids, ["Me-2", "alice", "alice-2", "dave"]);
+        assert_eq!(joined.count_sessions(), 5);
+    }
+
+    #[test]
+    fn state() {
+        assert!(matches!(StateBuilder::new().build(), State::Joined(_)));
+        assert!(matches!(StateBuilder::default().build(), State::Joined(_)));
+
+        let joined = JoinedBuilder::new().with_member("alice", SessionType::Agent);
+        let state = StateBuilder::joined(joined).build();
+        assert_eq!(state.joined().unwrap().listing.len(), 1);
+
+        let state = StateBuilder::joining()
+            .with_joining(|j| j.since = Timestamp::UNIX_EPOCH)
+            .build();
+        match state {
+            State::Joining(joining) => {
+                assert_eq!(joining.since, Timestamp::UNIX_EPOCH);
+                assert!(joining.hello.is_none());
+            }
+            State::Joined(_) => panic!("expected joining state"),
+        }
+    }
+}
@@ -0,0 +1,625 @@
+//! Helpers for formatting message content.
+//!
+//! The euphoria web client collapses long multi-line messages and only expands
+//! them on request. Depending on the use case, sending a chain of shorter
+//! messages instead may be preferable. [`MessagePlan`] helps decide between the
+//! two. [`format_line`] renders messages as single lines of text, for example
+//! for logs or bridges. [`Template`] builds content from untrusted parts
+//! without accidentally pinging anyone.
+
+mod template;
+
+use std::mem;
+
+use jiff::fmt::strtime;
+use unicode_normalization::char::is_combining_mark;
+
+use crate::api::Message;
+use crate::emoji::Emoji;
+
+pub use self::template::*;
+
+/// Normalize line endings to `\n` and remove trailing newlines.
+///
+/// Both `\r\n` and lone `\r` are treated as line breaks.
+pub fn normalize_newlines(content: &str) -> String {
+    content
+        .replace("\r\n", "\n")
+        .replace('\r', "\n")
+        .trim_end_matches('\n')
+        .to_string()
+}
+
+fn wrapped_lines(line: &str, wrap_width: Option<usize>) -> usize {
+    match wrap_width {
+        Some(width) if width > 0 => line.chars().count().div_ceil(width).max(1),
+        _ => 1,
+    }
+}
+
+/// The number of lines a message is displayed as.
+///
+/// Line endings are normalized using [`normalize_newlines`], so trailing
+/// newlines don't count. If `wrap_width` is set, lines longer than `wrap_width`
+/// characters count as multiple lines, like a client would soft-wrap them.
+pub fn count_lines(content: &str, wrap_width: Option<usize>) -> usize {
+    normalize_newlines(content)
+        .split('\n')
+        .map(|line| wrapped_lines(line, wrap_width))
+        .sum()
+}
+
+/// How to send some content, as decided by [`MessagePlan::plan`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Plan {
+    /// The content is blank, so there is nothing to send.
+    Empty,
+    /// Send the content as a single message.
+    Single(String),
+    /// Send the content as multiple messages, in this order.
+    Chain(Vec<String>),
+}
+
+impl Plan {
+    /// The contents of the messages to send, in order.
+    pub fn into_messages(self) -> Vec<String> {
+        match self {
+            Self::Empty => vec![],
+            Self::Single(content) => vec![content],
+            Self::Chain(contents) => contents,
+        }
+    }
+}
+
+/// How the messages of a [`Plan::Chain`] relate to each other.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Chaining {
+    /// All messages have the same parent.
+    #[default]
+    Siblings,
+    /// Each message is a reply to the previous one.
+    Nested,
+}
+
+/// Decides whether content should be sent as a single message or a chain of
+/// messages.
+///
+/// Content is sent as a single message if it is within all limits. Otherwise,
+/// it is split into as few messages as possible that are each within all
+/// limits. Lines are only split if they are longer than [`Self::max_len`] by
+/// themselves.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MessagePlan {
+    /// Maximum number of lines per message, if any.
+    ///
+    /// See [`count_lines`] for how lines are counted.
+    pub max_lines: Option<usize>,
+    /// Maximum number of characters per message.
+    pub max_len: usize,
+    /// Width at which long lines are assumed to be soft-wrapped, if at all.
+    pub wrap_width: Option<usize>,
+}
+
+impl MessagePlan {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_max_lines(self, max_lines: usize) -> Self {
+        self.with_max_lines_opt(Some(max_lines))
+    }
+
+    pub fn with_max_lines_opt(mut self, max_lines: Option<usize>) -> Self {
+        self.max_lines = max_lines;
+        self
+    }
+
+    pub fn with_max_len(mut self, max_len: usize) -> Self {
+        self.max_len = max_len;
+        self
+    }
+
+    pub fn with_wrap_width(self, wrap_width: usize) -> Self {
+        self.with_wrap_width_opt(Some(wrap_width))
+    }
+
+    pub fn with_wrap_width_opt(mut self, wrap_width: Option<usize>) -> Self {
+        self.wrap_width = wrap_width;
+        self
+    }
+
+    fn fits(&self, content: &str) -> bool {
+        content.chars().count() <= self.max_len
+            && self
+                .max_lines
+                .is_none_or(|max| count_lines(content, self.wrap_width) <= max)
+    }
+
+    /// Split a line into pieces of at most [`Self::max_len`] characters.
+    fn split_line<'a>(&self, line: &'a str) -> Vec<&'a str> {
+        let max_len = self.max_len.max(1);
+        let mut pieces = vec![];
+        let mut rest = line;
+        while let Some((i, _)) = rest.char_indices().nth(max_len) {
+            let (piece, new_rest) = rest.split_at(i);
+            pieces.push(piece);
+            rest = new_rest;
+        }
+        pieces.push(rest);
+        pieces
+    }
+
+    /// Decide how to send some content.
+    ///
+    /// Blank lines at the start or end of a message are removed. Content
+    /// without any non-whitespace characters results in [`Plan::Empty`].
+    pub fn plan(&self, content: &str) -> Plan {
+        let content = normalize_newlines(content);
+        if content.trim().is_empty() {
+            return Plan::Empty;
+        }
+        if self.fits(&content) {
+            return Plan::Single(content);
+        }
+
+        let mut chain = vec![];
+        let mut current = String::new();
+        for line in content.split('\n') {
+            for piece in self.split_line(line) {
+                let candidate = format!("{current}\n{piece}");
+                if !current.is_empty() && !self.fits(candidate.trim_start_matches('\n')) {
+                    chain.push(mem::take(&mut current));
+                    current = piece.to_string();
+                } else {
+                    current = candidate;
+                }
+            }
+        }
+        chain.push(current);
+
+        // Blank lines at the start or end of a message are not shown, and
+        // messages must not be empty.
+        let mut chain = chain
+            .into_iter()
+            .map(|c| c.trim_matches('\n').to_string())
+            .filter(|c| !c.trim().is_empty())
+            .collect::<Vec<_>>();
+
+        match chain.len() {
+            0 => Plan::Empty,
+            1 => Plan::Single(chain.remove(0)),
+            _ => Plan::Chain(chain),
+        }
+    }
+}
+
+impl Default for MessagePlan {
+    /// No line limit and a length limit of 4096 characters, the maximum
+    /// length of a euphoria message.
+    fn default() -> Self {
+        Self {
+            max_lines: None,
+            max_len: 4096,
+            wrap_width: None,
+        }
+    }
+}
+
+fn is_regional_indicator(c: char) -> bool {
+    ('\u{1F1E6}'..='\u{1F1FF}').contains(&c)
+}
+
+/// Whether a char attaches to the char before it instead of starting a new
+/// grapheme cluster.
+fn extends_cluster(prev: char, c: char) -> bool {
+    is_combining_mark(c)
+        || prev == '\u{200D}' // Zero width joiner
+        || c == '\u{200D}'
+        || ('\u{FE00}'..='\u{FE0F}').contains(&c) // Variation selectors
+        || ('\u{1F3FB}'..='\u{1F3FF}').contains(&c) // Skin tone modifiers
+        || ('\u{E0020}'..='\u{E007F}').contains(&c) // Tags
+}
+
+/// Split text into user-perceived characters.
+///
+/// This approximates Unicode's extended grapheme clusters closely enough that
+/// emoji sequences and combining marks are never split apart.
+fn graphemes(text: &str) -> Vec<&str> {
+    let mut graphemes = vec![];
+    let mut start = 0;
+    let mut prev = None::<char>;
+    let mut regional_indicators = 0;
+    for (i, c) in text.char_indices() {
+        let extends = match prev {
+            Some(prev) if is_regional_indicator(c) && is_regional_indicator(prev) => {
+                // Flags are pairs of regional indicators
+                regional_indicators % 2 == 1
+            }
+            Some(prev) => extends_cluster(prev, c),
+            None => true,
+        };
+        if !extends {
+            graphemes.push(&text[start..i]);
+            start = i;
+        }
+        if is_regional_indicator(c) {
+            regional_indicators += 1;
+        } else {
+            regional_indicators = 0;
+        }
+        prev = Some(c);
+    }
+    if start < text.len() {
+        graphemes.push(&text[start..]);
+    }
+    graphemes
+}
+
+/// Pad or truncate text to exactly `width` user-perceived characters.
+///
+/// Truncated text ends in `…`.
+fn fit_width(text: &str, width: usize) -> String {
+    let graphemes = graphemes(text);
+    if graphemes.len() <= width {
+        let padding = " ".repeat(width - graphemes.len());
+        return format!("{text}{padding}");
+    }
+    if width == 0 {
+        return String::new();
+    }
+    let mut result = graphemes[..width - 1].concat();
+    result.push('…');
+    result
+}
+
+/// How [`format_line`] handles content spanning multiple lines.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Newlines {
+    /// Replace line breaks by `\n` and backslashes by `\\`.
+    #[default]
+    Escape,
+    /// Only show the first line, followed by ` […]` if there are more lines.
+    FirstLine,
+}
+
+/// Options for [`format_line`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LineFormatOptions {
+    /// How to format the message's time, see [`jiff::fmt::strtime`].
+    ///
+    /// The time is formatted in UTC. If the format can't be used, the time is
+    /// shown in RFC 3339 format instead.
+    pub time_format: String,
+    /// Pad or truncate nicks to this many characters, if set.
+    ///
+    /// Nicks are never truncated in the middle of an emoji or before a
+    /// combining character.
+    pub nick_width: Option<usize>,
+    pub newlines: Newlines,
+    /// Whether to replace colon-delimited emoji in the nick and content by
+    /// their unicode equivalent, see [`Emoji::replace`].
+    pub replace_emoji: bool,
+    /// Shown before the content of messages that have a parent.
+    pub reply_marker: Option<String>,
+    pub edited_marker: Option<String>,
+    /// Shown instead of the content of deleted messages.
+    pub deleted_marker: Option<String>,
+    /// Shown after the content of messages whose content was truncated by the
+    /// server.
+    pub truncated_marker: Option<String>,
+}
+
+impl LineFormatOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_time_format<S: ToString>(mut self, time_format: S) -> Self {
+        self.time_format = time_format.to_string();
+        self
+    }
+
+    pub fn with_nick_width(self, nick_width: usize) -> Self {
+        self.with_nick_width_opt(Some(nick_width))
+    }
+
+    pub fn with_nick_width_opt(mut self, nick_width: Option<usize>) -> Self {
+        self.nick_width = nick_width;
+        self
+    }
+
+    pub fn with_newlines(mut self, newlines: Newlines) -> Self {
+        self.newlines = newlines;
+        self
+    }
+
+    pub fn with_replace_emoji(mut self, replace_emoji: bool) -> Self {
+        self.replace_emoji = replace_emoji;
+        self
+    }
+
+    pub fn with_reply_marker<S: ToString>(self, reply_marker: S) -> Self {
+        self.with_reply_marker_opt(Some(reply_marker))
+    }
+
+    pub fn with_reply_marker_opt<S: ToString>(mut self, reply_marker: Option<S>) -> Self {
+        self.reply_marker = reply_marker.map(|s| s.to_string());
+        self
+    }
+
+    pub fn with_edited_marker<S: ToString>(self, edited_marker: S) -> Self {
+        self.with_edited_marker_opt(Some(edited_marker))
+    }
+
+    pub fn with_edited_marker_opt<S: ToString>(mut self, edited_marker: Option<S>) -> Self {
+        self.edited_marker = edited_marker.map(|s| s.to_string());
+        self
+    }
+
+    pub fn with_deleted_marker<S: ToString>(self, deleted_marker: S) -> Self {
+        self.with_deleted_marker_opt(Some(deleted_marker))
+    }
+
+    pub fn with_deleted_marker_opt<S: ToString>(mut self, deleted_marker: Option<S>) -> Self {
+        self.deleted_marker = deleted_marker.map(|s| s.to_string());
+        self
+    }
+
+    pub fn with_truncated_marker<S: ToString>(self, truncated_marker: S) -> Self {
+        self.with_truncated_marker_opt(Some(truncated_marker))
+    }
+
+    pub fn with_truncated_marker_opt<S: ToString>(mut self, truncated_marker: Option<S>) -> Self {
+        self.truncated_marker = truncated_marker.map(|s| s.to_string());
+        self
+    }
+}
+
+impl Default for LineFormatOptions {
+    fn default() -> Self {
+        Self {
+            time_format: "%Y-%m-%d %H:%M:%S".to_string(),
+            nick_width: None,
+            newlines: Newlines::default(),
+            replace_emoji: false,
+            reply_marker: Some("↳".to_string()),
+            edited_marker: Some("(edited)".to_string()),
+            deleted_marker: Some("(deleted)".to_string()),
+            truncated_marker: Some("(truncated)".to_string()),
+        }
+    }
+}
+
+/// Render a message as a single line of text.
+///
+/// The line has the form `<time> [<nick>] <content>`, with the markers from
+/// the [`LineFormatOptions`] added around the content. The output only depends
+/// on the message and the options.
+pub fn format_line(msg: &Message, opts: &LineFormatOptions) -> String {
+    let (mut nick, mut content) = (msg.sender.name.clone(), msg.content.clone());
+    if opts.replace_emoji {
+        nick = Emoji::global().replace(&nick).into_owned();
+        content = Emoji::global().replace(&content).into_owned();
+    }
+    if let Some(width) = opts.nick_width {
+        nick = fit_width(&nick, width);
+    }
+
+    let content = normalize_newlines(&content);
+    let content = match opts.newlines {
+        Newlines::Escape => content.replace('\\', "\\\\").replace('\n', "\\n"),
+        Newlines::FirstLine => match content.split_once('\n') {
+            Some((first, _)) => format!("{first} […]"),
+            None => content,
+        },
+    };
+
+    let time = msg.time.as_timestamp();
+    let time = strtime::format(&opts.time_format, time).unwrap_or_else(|_| time.to_string());
+    let mut parts = vec![format!("{time} [{nick}]")];
+    if msg.parent.is_some() {
+        parts.extend(opts.reply_marker.clone());
+    }
+    match (&msg.deleted, &opts.deleted_marker) {
+        (Some(_), Some(marker)) => parts.push(marker.clone()),
+        _ => {
+            parts.push(content);
+            if msg.truncated {
+                parts.extend(opts.truncated_marker.clone());
+            }
+        }
+    }
+    if msg.edited.is_some() {
+        parts.extend(opts.edited_marker.clone());
+    }
+    parts.join(" ")
+}
+
+/// The prefix drawing a message's position in a thread, for a message at the
+/// given depth.
+///
+/// Top-level messages have a depth of 0 and no prefix. Each level of nesting
+/// adds two columns.
+pub fn format_thread_prefix(depth: usize) -> String {
+    match depth {
+        0 => String::new(),
+        _ => format!("{}└ ", "│ ".repeat(depth - 1)),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::api::{Message, MessageId, Snowflake, Time};
+    use crate::test_util::{MessageBuilder, SessionViewBuilder};
+
+    use super::{
+        count_lines, format_line, format_thread_prefix, graphemes, normalize_newlines,
+        LineFormatOptions, MessagePlan, Newlines, Plan,
+    };
+
+    fn chain(messages: &[&str]) -> Plan {
+        Plan::Chain(messages.iter().map(|m| m.to_string()).collect())
+    }
+
+    #[test]
+    fn newlines() {
+        assert_eq!(normalize_newlines("a\r\nb\rc\nd"), "a\nb\nc\nd");
+        assert_eq!(normalize_newlines("a\n\n"), "a");
+        assert_eq!(normalize_newlines("a\r\n\r\n"), "a");
+        assert_eq!(normalize_newlines("\na"), "\na");
+        assert_eq!(normalize_newlines(""), "");
+    }
+
+    #[test]
+    fn lines() {
+        assert_eq!(count_lines("a", None), 1);
+        assert_eq!(count_lines("a\nb", None), 2);
+        assert_eq!(count_lines("a\r\nb\r\n", None), 2);
+        assert_eq!(count_lines("a\n\nb", None), 3);
+        assert_eq!(count_lines("aaaaa", Some(5)), 1);
+        assert_eq!(count_lines("aaaaaa", Some(5)), 2);
+        assert_eq!(count_lines("äääääääääää\n", Some(5)), 3);
+        assert_eq!(count_lines("\nb", Some(5)), 2);
+    }
+
+    #[test]
+    fn line_limit() {
+        let plan = MessagePlan::new().with_max_lines(3);
+        assert_eq!(plan.plan("a\nb\nc"), Plan::Single("a\nb\nc".to_string()));
+        assert_eq!(plan.plan("a\nb\nc\n"), Plan::Single("a\nb\nc".to_string()));
+        assert_eq!(
+            plan.plan("a\r\nb\r\nc\r\n"),
+            Plan::Single("a\nb\nc".to_string())
+        );
+        assert_eq!(plan.plan("a\nb\nc\nd"), chain(&["a\nb\nc", "d"]));
+        assert_eq!(
+            plan.plan("a\nb\nc\nd\ne\nf\ng"),
+            chain(&["a\nb\nc", "d\ne\nf", "g"])
+        );
+
+        let plan = MessagePlan::new().with_max_lines(1);
+        assert_eq!(plan.plan("a\nb\nc"), chain(&["a", "b", "c"]));
+    }
+
+    #[test]
+    fn length_limit() {
+        let plan = MessagePlan::new().with_max_len(5);
+        assert_eq!(plan.plan("aaaaa"), Plan::Single("aaaaa".to_string()));
+        assert_eq!(plan.plan("aa\nbb"), Plan::Single("aa\nbb".to_string()));
+        assert_eq!(plan.plan("aa\nbbb"), chain(&["aa", "bbb"]));
+        assert_eq!(plan.plan("aaaaaa"), chain(&["aaaaa", "a"]));
+        assert_eq!(plan.plan("ääääääääääää"), chain(&["äääää", "äääää", "ää"]));
+        assert_eq!(plan.plan("a\naaaaaaa"), chain(&["a", "aaaaa", "aa"]));
+    }
+
+    #[test]
+    fn soft_wrapped_lines() {
+        let plan = MessagePlan::new().with_max_lines(2).with_wrap_width(5);
+        assert_eq!(
+            plan.plan("aaaaaaaaaa"),
+            Plan::Single("aaaaaaaaaa".to_string())
+        );
+        assert_eq!(plan.plan("aaaaaaaaaa\nb"), chain(&["aaaaaaaaaa", "b"]));
+        assert_eq!(plan.plan("aaaaa\nbbbbbb"), chain(&["aaaaa", "bbbbbb"]));
+    }
+
+    #[test]
+    fn no_empty_messages() {
+        let plan = MessagePlan::new().with_max_lines(1);
+        assert_eq!(plan.plan("a\n\n\nb\n \n"), chain(&["a", "b"]));
+        assert_eq!(plan.plan("\n\na\n\n"), Plan::Single("a".to_string()));
+
+        assert_eq!(plan.plan(""), Plan::Empty);
+        assert_eq!(plan.plan("\n \n\n"), Plan::Empty);
+        assert_eq!(MessagePlan::new().plan(" "), Plan::Empty);
+        assert_eq!(Plan::Empty.into_messages(), Vec::<String>::new());
+    }
+
+    fn message(nick: &str, content: &str) -> Message {
+        MessageBuilder::new(content)
+            // 2024-05-20 12:34:56 UTC
+            .with_time(Time(1716208496))
+            .with_sender(SessionViewBuilder::new(nick).build())
+            .build()
+    }
+
+    #[test]
+    fn grapheme_clusters() {
+        assert_eq!(graphemes(""), Vec::<&str>::new());
+        assert_eq!(graphemes("abc"), ["a", "b", "c"]);
+        assert_eq!(graphemes("e\u{301}x"), ["e\u{301}", "x"]);
+        assert_eq!(graphemes("👩‍👩‍👧!"), ["👩‍👩‍👧", "!"]);
+        assert_eq!(graphemes("👍🏽❤️"), ["👍🏽", "❤️"]);
+        assert_eq!(graphemes("🇩🇪🇫🇷🇮"), ["🇩🇪", "🇫🇷", "🇮"]);
+    }
+
+    #[test]
+    fn format_lines() {
+        let width = LineFormatOptions::new().with_nick_width(6);
+        let mut reply = message("bob", "sure");
+        reply.parent = Some(MessageId(Snowflake(0)));
+        reply.edited = Some(Time(1716208500));
+        let mut deleted = reply.clone();
+        deleted.deleted = Some(Time(1716208600));
+        let mut truncated = message("bob", "a very long message");
+        truncated.truncated = true;
+        let long_word = "a".repeat(100);
+
+        let cases = [
+            (message("alice", "hello world"), LineFormatOptions::new()),
+            (message("alice", "hello world"), width.clone()),
+            (
+                message("alice", "one\\ntwo\r\nthree\n"),
+                LineFormatOptions::new(),
+            ),
+            (
+                message("alice", "one\ntwo\nthree"),
+                LineFormatOptions::new().with_newlines(Newlines::FirstLine),
+            ),
+            (message("👩‍👩‍👧 family", "hi"), width.clone()),
+            (message("🇩🇪🇫🇷🇮🇹🇪🇸🇳🇱🇵🇱🇸🇪", "flags"), width.clone()),
+            (message(&"ne\u{301}".repeat(4), "accents"), width.clone()),
+            (message("דני", "שלום עולם, hello!"), width.clone()),
+            (message("alice", &long_word), width.clone()),
+            (reply.clone(), LineFormatOptions::new()),
+            (
+                reply,
+                LineFormatOptions::new()
+                    .with_reply_marker("re:")
+                    .with_edited_marker_opt(None::<&str>),
+            ),
+            (deleted.clone(), LineFormatOptions::new()),
+            (
+                deleted,
+                LineFormatOptions::new().with_deleted_marker_opt(None::<&str>),
+            ),
+            (truncated, LineFormatOptions::new()),
+            (
+                message(":bear:", "nice :thumbsup:"),
+                LineFormatOptions::new().with_replace_emoji(true),
+            ),
+            (
+                message("alice", "short time"),
+                LineFormatOptions::new().with_time_format("%H:%M"),
+            ),
+            (
+                message("alice", "invalid time format"),
+                LineFormatOptions::new().with_time_format("%Z"),
+            ),
+        ];
+
+        let lines = cases
+            .iter()
+            .map(|(msg, opts)| format_line(msg, opts))
+            .collect::<Vec<_>>();
+        let golden = include_str!("../tests/golden/message_lines.txt");
+        assert_eq!(lines, golden.lines().collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn thread_prefixes() {
+        assert_eq!(format_thread_prefix(0), "");
+        assert_eq!(format_thread_prefix(1), "└ ");
+        assert_eq!(format_thread_prefix(3), "│ │ └ ");
+    }
+}
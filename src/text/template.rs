@@ -0,0 +1,450 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::mem;
+use std::{error, fmt};
+
+/// Inserted after an `@` to prevent it from starting a mention.
+///
+/// See [`escape_mentions`].
+pub const MENTION_ESCAPE: char = '\u{200b}';
+
+/// Inserted between an `@` and a nick filled in via [`Filler::fill_nick`].
+///
+/// A zero width space doesn't end a mention, so `@` followed by
+/// [`MENTION_ESCAPE`] and a nick is still a mention, just one of a nick starting
+/// with a zero width space. Clients that ignore invisible characters when
+/// comparing nicks would ping the nick anyways. This hair space is whitespace,
+/// so no mention starts at the `@` at all.
+pub const NICK_ESCAPE: char = '\u{200a}';
+
+/// Prevent a text from pinging anyone.
+///
+/// A zero width space is inserted after every `@`, so the euphoria client
+/// doesn't recognize what follows as a mention. An `@` that is already
+/// followed by a zero width space or [`NICK_ESCAPE`] is left alone, so escaping
+/// a text multiple times has the same effect as escaping it once.
+pub fn escape_mentions(text: &str) -> Cow<'_, str> {
+    if !text.contains('@') {
+        return Cow::Borrowed(text);
+    }
+
+    let mut result = String::with_capacity(text.len() + 3);
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        result.push(c);
+        if c == '@' && !matches!(chars.peek(), Some(&(MENTION_ESCAPE | NICK_ESCAPE))) {
+            result.push(MENTION_ESCAPE);
+        }
+    }
+    Cow::Owned(result)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TemplateError {
+    /// A `{` at this byte offset is not closed by a `}`.
+    UnclosedPlaceholder(usize),
+    /// A `}` at this byte offset doesn't close a placeholder. Literal braces
+    /// must be written as `{{` and `}}`.
+    UnmatchedBrace(usize),
+    /// A placeholder name is empty or contains characters other than ASCII
+    /// alphanumerics and `_`.
+    InvalidName(String),
+    /// A placeholder was not filled.
+    Unfilled(String),
+    /// A value was filled in for a placeholder the template doesn't contain.
+    UnknownPlaceholder(String),
+}
+
+impl fmt::Display for TemplateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UnclosedPlaceholder(at) => write!(f, "unclosed placeholder at byte {at}"),
+            Self::UnmatchedBrace(at) => write!(f, "unmatched '}}' at byte {at}"),
+            Self::InvalidName(name) => write!(f, "invalid placeholder name {name:?}"),
+            Self::Unfilled(name) => write!(f, "placeholder {name:?} was not filled"),
+            Self::UnknownPlaceholder(name) => write!(f, "unknown placeholder {name:?}"),
+        }
+    }
+}
+
+impl error::Error for TemplateError {}
+
+#[derive(Debug, Clone)]
+enum Value {
+    /// A nick whose mentions are already escaped. It is separated from a
+    /// preceding `@` by [`NICK_ESCAPE`] when rendering.
+    Nick(String),
+    Text(String),
+}
+
+enum Segment<'a> {
+    Literal(String),
+    Placeholder(&'a str),
+}
+
+fn is_valid_name(name: &str) -> bool {
+    !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+fn parse_segments(source: &str) -> Result<Vec<Segment<'_>>, TemplateError> {
+    let mut segments = vec![];
+    let mut literal = String::new();
+    let mut rest = source;
+    while let Some(i) = rest.find(['{', '}']) {
+        let offset = source.len() - rest.len();
+        literal.push_str(&rest[..i]);
+        let brace = &rest[i..i + 1];
+        let after = &rest[i + 1..];
+
+        if after.starts_with(brace) {
+            literal.push_str(brace);
+            rest = &after[1..];
+            continue;
+        }
+        if brace == "}" {
+            return Err(TemplateError::UnmatchedBrace(offset + i));
+        }
+
+        let end = after
+            .find('}')
+            .ok_or(TemplateError::UnclosedPlaceholder(offset + i))?;
+        let name = &after[..end];
+        if !is_valid_name(name) {
+            return Err(TemplateError::InvalidName(name.to_string()));
+        }
+        if !literal.is_empty() {
+            segments.push(Segment::Literal(mem::take(&mut literal)));
+        }
+        segments.push(Segment::Placeholder(name));
+        rest = &after[end + 1..];
+    }
+    literal.push_str(rest);
+    if !literal.is_empty() {
+        segments.push(Segment::Literal(literal));
+    }
+    Ok(segments)
+}
+
+/// Message content with named placeholders.
+///
+/// Placeholders are written as `{name}`, where the name consists of ASCII
+/// alphanumerics and `_`. Literal braces are written as `{{` and `}}`.
+/// Everything else, including emoji shortcodes like `:tada:`, is kept as is.
+///
+/// Templates are filled via [`Self::fill`]. Every placeholder must be filled
+/// with exactly one value, which can be escaped in different ways:
+///
+/// - [`Filler::fill_nick`] for nicks, which can't ping anyone. If the
+///   placeholder directly follows an `@`, the nick is separated from it by
+///   [`NICK_ESCAPE`].
+/// - [`Filler::fill_text`] for arbitrary text, where mentions are escaped using
+///   [`escape_mentions`].
+/// - [`Filler::fill_raw`] for text that is inserted unchanged.
+///
+/// Escaping is idempotent, so content produced by a template can safely be
+/// escaped again, e.g. by an output transform.
+///
+/// ```
+/// use euphoxide::text::Template;
+///
+/// const ROLLED: Template = Template::new("Hello {nick}, you rolled {roll} :tada:");
+/// const PING: Template = Template::new("@{nick}, you rolled {roll}");
+///
+/// let content = ROLLED
+///     .fill()
+///     .fill_nick("nick", "@someone")
+///     .fill_raw("roll", 6)
+///     .render()
+///     .unwrap();
+/// assert_eq!(content, "Hello @\u{200b}someone, you rolled 6 :tada:");
+///
+/// let content = PING
+///     .fill()
+///     .fill_nick("nick", "someone")
+///     .fill_raw("roll", 6)
+///     .render()
+///     .unwrap();
+/// assert_eq!(content, "@\u{200a}someone, you rolled 6");
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Template {
+    source: Cow<'static, str>,
+}
+
+impl Template {
+    /// Create a template without checking its syntax.
+    ///
+    /// This function is meant for templates in constants. Syntax errors are
+    /// only reported once the template is rendered, so templates created this
+    /// way should be checked using [`Self::validate`] in a test.
+    pub const fn new(source: &'static str) -> Self {
+        Self {
+            source: Cow::Borrowed(source),
+        }
+    }
+
+    /// Create a template, checking its syntax.
+    pub fn parse<S: ToString>(source: S) -> Result<Self, TemplateError> {
+        let template = Self {
+            source: Cow::Owned(source.to_string()),
+        };
+        template.validate()?;
+        Ok(template)
+    }
+
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+
+    /// Check the template's syntax.
+    pub fn validate(&self) -> Result<(), TemplateError> {
+        parse_segments(&self.source).map(|_| ())
+    }
+
+    /// The names of the template's placeholders in the order they appear in.
+    pub fn placeholders(&self) -> Result<Vec<&str>, TemplateError> {
+        let segments = parse_segments(&self.source)?;
+        let names = segments
+            .into_iter()
+            .filter_map(|s| match s {
+                Segment::Placeholder(name) => Some(name),
+                Segment::Literal(_) => None,
+            })
+            .collect();
+        Ok(names)
+    }
+
+    /// Start filling the template's placeholders.
+    pub fn fill(&self) -> Filler<'_> {
+        Filler {
+            template: self,
+            values: HashMap::new(),
+        }
+    }
+}
+
+/// Fills the placeholders of a [`Template`].
+///
+/// Filling the same placeholder multiple times replaces its previous value.
+#[derive(Debug, Clone)]
+pub struct Filler<'a> {
+    template: &'a Template,
+    values: HashMap<String, Value>,
+}
+
+impl Filler<'_> {
+    /// Fill a placeholder with a nick that can't ping anyone.
+    ///
+    /// Mentions in the nick are escaped like in [`Self::fill_text`]. If the
+    /// placeholder directly follows an `@`, [`NICK_ESCAPE`] is inserted
+    /// between the two.
+    pub fn fill_nick<S: ToString>(mut self, name: &str, nick: S) -> Self {
+        let nick = escape_mentions(&nick.to_string()).into_owned();
+        self.values.insert(name.to_string(), Value::Nick(nick));
+        self
+    }
+
+    /// Fill a placeholder with text whose mentions are escaped.
+    pub fn fill_text<S: ToString>(mut self, name: &str, text: S) -> Self {
+        let text = escape_mentions(&text.to_string()).into_owned();
+        self.values.insert(name.to_string(), Value::Text(text));
+        self
+    }
+
+    /// Fill a placeholder with text that is inserted unchanged.
+    pub fn fill_raw<S: ToString>(mut self, name: &str, text: S) -> Self {
+        self.values
+            .insert(name.to_string(), Value::Text(text.to_string()));
+        self
+    }
+
+    /// Render the template.
+    ///
+    /// Fails if the template is invalid, if a placeholder was not filled, or if
+    /// a value was filled in for a placeholder the template doesn't contain.
+    pub fn render(self) -> Result<String, TemplateError> {
+        let segments = parse_segments(&self.template.source)?;
+
+        let mut unknown = self
+            .values
+            .keys()
+            .filter(|name| {
+                !segments
+                    .iter()
+                    .any(|s| matches!(s, Segment::Placeholder(p) if p == name))
+            })
+            .collect::<Vec<_>>();
+        unknown.sort_unstable();
+        if let Some(name) = unknown.first() {
+            return Err(TemplateError::UnknownPlaceholder(name.to_string()));
+        }
+
+        let mut result = String::new();
+        for segment in &segments {
+            match segment {
+                Segment::Literal(literal) => result.push_str(literal),
+                Segment::Placeholder(name) => match self.values.get(*name) {
+                    Some(Value::Nick(nick)) => {
+                        if result.ends_with('@') {
+                            result.push(NICK_ESCAPE);
+                        }
+                        result.push_str(nick);
+                    }
+                    Some(Value::Text(text)) => result.push_str(text),
+                    None => return Err(TemplateError::Unfilled(name.to_string())),
+                },
+            }
+        }
+        Ok(result)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::nick;
+
+    use super::{escape_mentions, Template, TemplateError};
+
+    const GREETING: Template = Template::new("Hello {nick}, you rolled {roll} :tada:");
+    const PING: Template = Template::new("@{nick}: {{{message}}}");
+    const MENTION: Template = Template::new("@{nick} {message}");
+
+    #[test]
+    fn constant_templates_are_valid() {
+        assert_eq!(GREETING.validate(), Ok(()));
+        assert_eq!(GREETING.placeholders().unwrap(), ["nick", "roll"]);
+        assert_eq!(PING.validate(), Ok(()));
+        assert_eq!(PING.placeholders().unwrap(), ["nick", "message"]);
+    }
+
+    #[test]
+    fn escaping() {
+        assert_eq!(escape_mentions("no mentions"), "no mentions");
+        assert_eq!(escape_mentions("hi @bob"), "hi @\u{200b}bob");
+        assert_eq!(escape_mentions("@@"), "@\u{200b}@\u{200b}");
+
+        // Escaping is idempotent
+        let once = escape_mentions("@a @\u{200b}b @");
+        assert_eq!(once, "@\u{200b}a @\u{200b}b @\u{200b}");
+        assert_eq!(escape_mentions(&once), once);
+        assert_eq!(escape_mentions("@\u{200a}bob"), "@\u{200a}bob");
+    }
+
+    #[test]
+    fn filling() {
+        let content = GREETING
+            .fill()
+            .fill_nick("nick", "bob")
+            .fill_raw("roll", 4)
+            .render()
+            .unwrap();
+        assert_eq!(content, "Hello bob, you rolled 4 :tada:");
+
+        // Nicks can't ping, even right after an @
+        let content = PING
+            .fill()
+            .fill_nick("nick", "bob")
+            .fill_text("message", "ask @alice")
+            .render()
+            .unwrap();
+        assert_eq!(content, "@\u{200a}bob: {ask @\u{200b}alice}");
+
+        // Raw text is inserted unchanged
+        let content = PING
+            .fill()
+            .fill_raw("nick", "bob")
+            .fill_raw("message", "@alice")
+            .render()
+            .unwrap();
+        assert_eq!(content, "@bob: {@alice}");
+
+        // Templates are reusable and later values replace earlier ones
+        let content = PING
+            .fill()
+            .fill_raw("nick", "x")
+            .fill_nick("nick", "carol")
+            .fill_text("message", "")
+            .render()
+            .unwrap();
+        assert_eq!(content, "@\u{200a}carol: {}");
+    }
+
+    /// The normalized nicks mentioned in a text.
+    fn mentioned(content: &str) -> Vec<String> {
+        content
+            .match_indices('@')
+            .map(|(i, _)| {
+                let token = content[i + 1..].split(char::is_whitespace).next().unwrap();
+                nick::normalize(token)
+            })
+            .filter(|mention| !mention.is_empty())
+            .collect()
+    }
+
+    #[test]
+    fn nicks_are_not_mentioned() {
+        for name in ["bob", "@bob", "\u{200b}bob", "b.o.b", "🙂"] {
+            for template in [PING, MENTION, Template::new("{nick}: {message}")] {
+                let content = template
+                    .fill()
+                    .fill_nick("nick", name)
+                    .fill_text("message", "")
+                    .render()
+                    .unwrap();
+                let mentioned = mentioned(&content);
+                assert!(
+                    !mentioned.contains(&nick::normalize(name)),
+                    "{content:?} mentions {name:?}"
+                );
+            }
+        }
+
+        // Unescaped, the nick would be mentioned
+        let content = MENTION
+            .fill()
+            .fill_raw("nick", "b.o.b")
+            .fill_text("message", "")
+            .render()
+            .unwrap();
+        let mentioned = mentioned(&content);
+        assert_eq!(mentioned, [nick::normalize("bob")]);
+    }
+
+    #[test]
+    fn errors() {
+        let parse = |s: &str| Template::parse(s).unwrap_err();
+        assert_eq!(parse("a {b"), TemplateError::UnclosedPlaceholder(2));
+        assert_eq!(parse("a } b"), TemplateError::UnmatchedBrace(2));
+        assert_eq!(parse("{{a}"), TemplateError::UnmatchedBrace(3));
+        assert_eq!(parse("{}"), TemplateError::InvalidName(String::new()));
+        assert_eq!(
+            parse("{a b}"),
+            TemplateError::InvalidName("a b".to_string())
+        );
+        assert_eq!(
+            Template::parse("{{a}}")
+                .unwrap()
+                .placeholders()
+                .unwrap()
+                .len(),
+            0
+        );
+
+        let err = GREETING.fill().fill_nick("nick", "bob").render();
+        assert_eq!(err, Err(TemplateError::Unfilled("roll".to_string())));
+        let err = GREETING
+            .fill()
+            .fill_nick("nick", "bob")
+            .fill_raw("roll", 1)
+            .fill_raw("rol", 1)
+            .render();
+        assert_eq!(
+            err,
+            Err(TemplateError::UnknownPlaceholder("rol".to_string()))
+        );
+
+        // Invalid constant templates fail when rendering
+        let err = Template::new("{oops").fill().render();
+        assert_eq!(err, Err(TemplateError::UnclosedPlaceholder(0)));
+    }
+}
@@ -0,0 +1,45 @@
+//! Selecting the crypto provider used for TLS connections.
+//!
+//! The provider is selected via cargo features. At least one of them must be
+//! enabled whenever the `tls` feature is:
+//!
+//! - `tls-aws-lc` (default) uses [aws-lc-rs](https://crates.io/crates/aws-lc-rs).
+//! - `tls-ring` uses [ring](https://crates.io/crates/ring), which is written
+//!   mostly in Rust and builds on targets aws-lc-rs doesn't support.
+//!
+//! If both are enabled, for example because different crates in the
+//! dependency tree enable different ones, `tls-aws-lc` is used. The `bot`,
+//! `devtools` and `webhook` features enable `tls`, so a provider must be
+//! enabled alongside them when default features are disabled.
+//!
+//! Connections install the selected provider as rustls' process-wide default
+//! automatically, so usually nothing needs to be done. Call
+//! [`install_default_provider`] to use the provider for other rustls clients
+//! before the first connection is opened.
+
+#[cfg(not(any(feature = "tls-aws-lc", feature = "tls-ring")))]
+compile_error!("the feature `tls` requires a crypto provider, enable `tls-aws-lc` or `tls-ring`");
+
+use rustls::crypto::CryptoProvider;
+
+#[cfg(feature = "tls-aws-lc")]
+use rustls::crypto::aws_lc_rs as selected;
+#[cfg(all(feature = "tls-ring", not(feature = "tls-aws-lc")))]
+use rustls::crypto::ring as selected;
+
+/// The crypto provider selected via cargo features.
+pub fn provider() -> CryptoProvider {
+    selected::default_provider()
+}
+
+/// Install the [selected provider](provider) as rustls' process-wide default.
+///
+/// Does nothing if a default provider has already been installed, even if it
+/// is a different one.
+pub fn install_default_provider() {
+    if CryptoProvider::get_default().is_none() {
+        // Another thread may have installed a provider in the meantime, which
+        // is fine as well.
+        let _ = provider().install_default();
+    }
+}
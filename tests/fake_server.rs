@@ -0,0 +1,688 @@
+//! End-to-end tests running bots against a scripted fake euphoria server.
+//!
+//! The server speaks the websocket protocol over localhost, so these tests
+//! exercise the real composition of [`Conn`](euphoxide::conn::Conn),
+//! [`Instance`] and [`Commands`] instead of their parts in isolation.
+
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use euphoxide::api::{self, Send, UserId};
+use euphoxide::bot::botrulez::Ping;
+use euphoxide::bot::command::{Command, Context, General, HasInstance};
+use euphoxide::bot::commands::Commands;
+use euphoxide::bot::handoff::HandoffCommand;
+use euphoxide::bot::instance::{Event, Instance, InstanceConfig, ServerConfig};
+use euphoxide::conn::{self, ConnPhase, MalformedPolicy};
+use futures_util::SinkExt;
+use serde_json::{json, Value};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tokio_stream::StreamExt;
+use tokio_tungstenite::tungstenite::handshake::server::{Request, Response};
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::WebSocketStream;
+
+/// How long to wait for anything to happen before failing a test.
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+async fn within<F: Future>(future: F) -> F::Output {
+    tokio::time::timeout(TIMEOUT, future)
+        .await
+        .expect("timed out")
+}
+
+/// Accepts connections from instances.
+struct FakeServer {
+    listener: TcpListener,
+}
+
+impl FakeServer {
+    async fn new() -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        Self { listener }
+    }
+
+    fn config(&self) -> ServerConfig {
+        let addr = self.listener.local_addr().unwrap();
+        ServerConfig::default()
+            .with_domain(addr)
+            .with_tls(false)
+            .with_timeout(TIMEOUT)
+            .with_reconnect_delay(Duration::from_millis(10))
+    }
+
+    async fn accept(&self) -> Client {
+        within(async {
+            let (stream, _) = self.listener.accept().await.unwrap();
+            let mut path = String::new();
+            #[allow(clippy::result_large_err)]
+            let callback = |request: &Request, response: Response| {
+                path = request.uri().to_string();
+                Ok(response)
+            };
+            let ws = tokio_tungstenite::accept_hdr_async(stream, callback)
+                .await
+                .unwrap();
+            Client { ws, path }
+        })
+        .await
+    }
+}
+
+/// The server side of a single connection.
+struct Client {
+    ws: WebSocketStream<TcpStream>,
+    /// The path the client connected to.
+    path: String,
+}
+
+impl Client {
+    async fn send(&mut self, packet: Value) {
+        self.send_text(&packet.to_string()).await;
+    }
+
+    async fn send_text(&mut self, text: &str) {
+        let msg = Message::Text(text.to_string());
+        self.ws.send(msg).await.unwrap();
+    }
+
+    /// Receive the next packet, or `None` once the connection is closed.
+    async fn recv(&mut self) -> Option<Value> {
+        within(async {
+            while let Some(Ok(msg)) = self.ws.next().await {
+                if let Message::Text(text) = msg {
+                    return Some(serde_json::from_str(&text).unwrap());
+                }
+            }
+            None
+        })
+        .await
+    }
+
+    /// Receive packets until one of the given type arrives.
+    async fn expect(&mut self, r#type: &str) -> Value {
+        loop {
+            let packet = self.recv().await.expect("connection closed");
+            if packet["type"] == r#type {
+                return packet;
+            }
+        }
+    }
+
+    /// Reply to a command with the given data.
+    async fn reply(&mut self, cmd: &Value, data: Value) {
+        let r#type = format!("{}-reply", cmd["type"].as_str().unwrap());
+        self.send(json!({ "id": cmd["id"], "type": r#type, "data": data }))
+            .await;
+    }
+
+    async fn hello(&mut self, room_is_private: bool) {
+        self.hello_as(session("me", ""), room_is_private).await;
+    }
+
+    /// Greet the client, attaching it to the server of the given session.
+    async fn hello_as(&mut self, session: Value, room_is_private: bool) {
+        let data = json!({
+            "id": "bot:me",
+            "session": session,
+            "room_is_private": room_is_private,
+            "version": "version",
+        });
+        self.send(json!({ "type": "hello-event", "data": data }))
+            .await;
+    }
+
+    async fn snapshot(&mut self) {
+        let data = json!({
+            "identity": "bot:me",
+            "session_id": "me",
+            "version": "version",
+            "listing": [session("other", "Someone")],
+            "log": [],
+        });
+        self.send(json!({ "type": "snapshot-event", "data": data }))
+            .await;
+    }
+
+    async fn join(&mut self) {
+        self.hello(false).await;
+        self.snapshot().await;
+    }
+
+    async fn send_event(&mut self, id: &str, content: &str) {
+        let data = json!({
+            "id": id,
+            "time": 0,
+            "sender": session("other", "Someone"),
+            "content": content,
+        });
+        self.send(json!({ "type": "send-event", "data": data }))
+            .await;
+    }
+}
+
+fn session(id: &str, name: &str) -> Value {
+    json!({
+        "id": format!("agent:{id}"),
+        "name": name,
+        "server_id": "heim.1",
+        "server_era": "era",
+        "session_id": id,
+    })
+}
+
+/// Receive events until one matches.
+async fn wait_for<T>(
+    rx: &mut mpsc::UnboundedReceiver<Event>,
+    mut f: impl FnMut(Event) -> Option<T>,
+) -> T {
+    within(async {
+        loop {
+            let event = rx.recv().await.expect("instance stopped");
+            if let Some(result) = f(event) {
+                return result;
+            }
+        }
+    })
+    .await
+}
+
+async fn wait_for_joined(rx: &mut mpsc::UnboundedReceiver<Event>) {
+    wait_for(rx, |e| matches!(e, Event::Joined(..)).then_some(())).await;
+}
+
+fn start(config: InstanceConfig) -> (Instance, mpsc::UnboundedReceiver<Event>) {
+    let (tx, rx) = mpsc::unbounded_channel();
+    (config.build_with_sender(tx), rx)
+}
+
+#[tokio::test]
+async fn join_and_ping() {
+    let server = FakeServer::new().await;
+    let (_instance, mut rx) = start(server.config().room("test").with_human(true));
+
+    let mut client = server.accept().await;
+    assert_eq!(client.path, "/room/test/ws?h=1");
+    client.join().await;
+    wait_for_joined(&mut rx).await;
+
+    client
+        .send(json!({ "type": "ping-event", "data": { "time": 123, "next": 153 } }))
+        .await;
+    let reply = client.expect("ping-reply").await;
+    assert_eq!(reply["data"]["time"], 123);
+}
+
+#[tokio::test]
+async fn passcode_auth() {
+    let server = FakeServer::new().await;
+    let config = server.config().room("private").with_password("hunter2");
+    let (_instance, mut rx) = start(config);
+
+    let mut client = server.accept().await;
+    client.hello(true).await;
+    let bounce = json!({ "reason": "authentication required" });
+    client
+        .send(json!({ "type": "bounce-event", "data": bounce }))
+        .await;
+    let auth = client.expect("auth").await;
+    assert_eq!(auth["data"]["type"], "passcode");
+    assert_eq!(auth["data"]["passcode"], "hunter2");
+    client.reply(&auth, json!({ "success": true })).await;
+    client.snapshot().await;
+
+    let private = wait_for(&mut rx, |e| match e {
+        Event::Joined(_, snapshot, _) => Some(snapshot.state.joined().unwrap().room_is_private),
+        _ => None,
+    })
+    .await;
+    assert!(private);
+}
+
+#[tokio::test]
+async fn passcode_auth_failure() {
+    let server = FakeServer::new().await;
+    let config = server.config().room("private").with_password("wrong");
+    let (instance, mut rx) = start(config);
+
+    let mut client = server.accept().await;
+    client.hello(true).await;
+    let bounce = json!({ "reason": "authentication required" });
+    client
+        .send(json!({ "type": "bounce-event", "data": bounce }))
+        .await;
+    let auth = client.expect("auth").await;
+    let failure = json!({ "success": false, "reason": "passcode incorrect" });
+    client.reply(&auth, failure).await;
+
+    // The instance stays connected, but never joins the room
+    let reply = wait_for(&mut rx, |e| match e {
+        Event::Packet(_, packet, snapshot) if packet.r#type.to_string() == "auth-reply" => {
+            Some((packet, snapshot))
+        }
+        _ => None,
+    })
+    .await;
+    assert!(reply.1.state.joined().is_none());
+    let state = within(instance.conn_tx())
+        .await
+        .unwrap()
+        .state()
+        .await
+        .unwrap();
+    assert!(state.joined().is_none());
+}
+
+#[tokio::test]
+async fn password_not_in_debug_output() {
+    let server = FakeServer::new().await;
+    let config = server.config().room("private").with_password("hunter2");
+    assert!(!format!("{config:?}").contains("hunter2"));
+    let (instance, mut rx) = start(config);
+
+    let mut client = server.accept().await;
+    client.hello(true).await;
+    let bounce = json!({ "reason": "authentication required" });
+    client
+        .send(json!({ "type": "bounce-event", "data": bounce }))
+        .await;
+    let auth = client.expect("auth").await;
+    assert_eq!(auth["data"]["passcode"], "hunter2");
+    client.reply(&auth, json!({ "success": true })).await;
+    client.snapshot().await;
+
+    let mut events = vec![];
+    wait_for(&mut rx, |e| {
+        let joined = matches!(e, Event::Joined(..));
+        events.push(format!("{e:?}"));
+        joined.then_some(())
+    })
+    .await;
+    instance.stop();
+    wait_for(&mut rx, |e| {
+        let stopped = matches!(e, Event::Stopped(_));
+        events.push(format!("{e:?}"));
+        stopped.then_some(())
+    })
+    .await;
+
+    // Connecting, Connected, 4 packets, Joined, Disconnected and Stopped
+    assert_eq!(events.len(), 9, "{events:#?}");
+    for event in events {
+        assert!(!event.contains("hunter2"), "{event}");
+    }
+}
+
+#[tokio::test]
+async fn reconnect_after_close() {
+    let server = FakeServer::new().await;
+    let (_instance, mut rx) = start(server.config().room("test"));
+
+    let mut client = server.accept().await;
+    client.join().await;
+    wait_for_joined(&mut rx).await;
+    client.ws.close(None).await.unwrap();
+    drop(client);
+
+    wait_for(&mut rx, |e| {
+        matches!(e, Event::Disconnected(_)).then_some(())
+    })
+    .await;
+    let mut client = server.accept().await;
+    assert_eq!(client.path, "/room/test/ws");
+    client.join().await;
+    wait_for_joined(&mut rx).await;
+}
+
+#[tokio::test]
+async fn status_across_reconnect() {
+    let server = FakeServer::new().await;
+    let (instance, mut rx) = start(server.config().room("test"));
+    let status = instance.status().clone();
+
+    let mut client = server.accept().await;
+    client.join().await;
+    wait_for_joined(&mut rx).await;
+    assert_eq!(status.phase(), ConnPhase::Joined);
+    assert_eq!(status.connections(), 1);
+    assert_eq!(status.received_packets(), 2);
+    assert!(status.last_packet().is_some());
+
+    client.ws.close(None).await.unwrap();
+    drop(client);
+    wait_for(&mut rx, |e| {
+        matches!(e, Event::Disconnected(_)).then_some(())
+    })
+    .await;
+    assert!(status.last_error().is_some());
+
+    let mut client = server.accept().await;
+    client.join().await;
+    wait_for_joined(&mut rx).await;
+    assert_eq!(status.phase(), ConnPhase::Joined);
+    assert_eq!(status.connections(), 2);
+    assert_eq!(status.received_packets(), 4);
+}
+
+#[tokio::test]
+async fn placement_history_across_reconnect() {
+    let server = FakeServer::new().await;
+    let (instance, mut rx) = start(server.config().room("test"));
+
+    let mut client = server.accept().await;
+    client.join().await;
+    wait_for_joined(&mut rx).await;
+    let data = json!({ "reason": "authentication changed" });
+    client
+        .send(json!({ "type": "disconnect-event", "data": data }))
+        .await;
+    client.ws.close(None).await.unwrap();
+    drop(client);
+
+    let mut me = session("me", "");
+    me["server_id"] = json!("heim.2");
+    me["server_era"] = json!("era2");
+    let mut client = server.accept().await;
+    client.hello_as(me, false).await;
+    client.snapshot().await;
+    wait_for_joined(&mut rx).await;
+
+    let placements = instance.placement_history();
+    assert_eq!(placements.len(), 2);
+    assert_eq!(instance.connections(), 2);
+
+    assert_eq!(placements[0].server_id, "heim.1");
+    assert_eq!(placements[0].server_era, "era");
+    assert!(placements[0].disconnected.is_some());
+    assert_eq!(
+        placements[0].disconnect_cause.as_deref(),
+        Some("disconnected because authentication changed")
+    );
+
+    assert_eq!(placements[1].server_id, "heim.2");
+    assert_eq!(placements[1].server_era, "era2");
+    assert!(placements[1].disconnected.is_none());
+    assert!(placements[1].disconnect_cause.is_none());
+}
+
+#[tokio::test]
+async fn disconnect_imminent_before_disconnect_event() {
+    let server = FakeServer::new().await;
+    let (_instance, mut rx) = start(server.config().room("test"));
+
+    let mut client = server.accept().await;
+    client.join().await;
+    wait_for_joined(&mut rx).await;
+    let data = json!({ "reason": "shutting down" });
+    client
+        .send(json!({ "type": "disconnect-event", "data": data }))
+        .await;
+    client.ws.close(None).await.unwrap();
+    drop(client);
+
+    let mut events = vec![];
+    wait_for(&mut rx, |e| {
+        let disconnected = matches!(e, Event::Disconnected(_));
+        match e {
+            Event::DisconnectImminent(_, reason) => {
+                events.push(format!("DisconnectImminent({reason})"));
+            }
+            Event::Packet(_, packet, _) => events.push(format!("Packet({})", packet.r#type)),
+            Event::Disconnected(_) => events.push("Disconnected".to_string()),
+            _ => {}
+        }
+        disconnected.then_some(())
+    })
+    .await;
+    assert_eq!(
+        events,
+        [
+            "DisconnectImminent(shutting down)",
+            "Packet(disconnect-event)",
+            "Disconnected",
+        ]
+    );
+}
+
+#[tokio::test]
+async fn malformed_packets_are_skipped() {
+    let server = FakeServer::new().await;
+    let config = ServerConfig {
+        on_malformed: MalformedPolicy::Skip,
+        ..server.config()
+    };
+    let (_instance, mut rx) = start(config.room("test"));
+
+    let mut client = server.accept().await;
+    client.join().await;
+    wait_for_joined(&mut rx).await;
+
+    client.send_text("this is not json").await;
+    client
+        .send(json!({ "type": "send-event", "data": { "content": 42 } }))
+        .await;
+    client.send_event("0000000000001", "still here").await;
+
+    let content = wait_for(&mut rx, |e| match e {
+        Event::Packet(_, packet, _) => match packet.content {
+            Ok(api::Data::SendEvent(event)) => Some(event.0.content.clone()),
+            _ => None,
+        },
+        Event::Disconnected(_) => panic!("disconnected"),
+        _ => None,
+    })
+    .await;
+    assert_eq!(content, "still here");
+}
+
+#[tokio::test]
+async fn command_dispatch() {
+    let server = FakeServer::new().await;
+    let config = server.config().room("test").with_username("TestBot");
+    let (_instance, mut rx) = start(config.clone());
+
+    let mut commands = Commands::<(), conn::Error>::new();
+    commands.add(General::new("ping", Ping::default()));
+    let commands = Arc::new(commands);
+    tokio::spawn(async move {
+        while let Some(event) = rx.recv().await {
+            if let Event::Packet(_, packet, snapshot) = event {
+                let commands = commands.clone();
+                let config = config.clone();
+                tokio::spawn(async move {
+                    let _ = commands
+                        .handle_packet(&config, &packet, &snapshot, &mut ())
+                        .await;
+                });
+            }
+        }
+    });
+
+    let mut client = server.accept().await;
+    client.join().await;
+    let nick = client.expect("nick").await;
+    assert_eq!(nick["data"]["name"], "TestBot");
+    let nick_reply = json!({
+        "session_id": "me",
+        "id": "bot:me",
+        "from": "",
+        "to": "TestBot",
+    });
+    client.reply(&nick, nick_reply).await;
+
+    client.send_event("0000000000001", "!ping").await;
+    let send = client.expect("send").await;
+    assert_eq!(send["data"]["content"], "Pong!");
+    assert_eq!(send["data"]["parent"], "0000000000001");
+}
+
+/// Asks for a retry the first time, then replies with the attempt.
+struct Busy;
+
+#[async_trait]
+impl Command<(), conn::Error> for Busy {
+    async fn execute(
+        &self,
+        _arg: &str,
+        msg: &api::Message,
+        ctx: &Context,
+        _bot: &mut (),
+    ) -> Result<bool, conn::Error> {
+        if ctx.attempt == 0 {
+            ctx.retry_after(Duration::from_millis(50));
+            return Ok(false);
+        }
+        ctx.reply_only(msg.id, format!("attempt {}", ctx.attempt))?;
+        Ok(true)
+    }
+}
+
+#[tokio::test]
+async fn command_retry() {
+    let server = FakeServer::new().await;
+    let config = server.config().room("test");
+    let (_instance, mut rx) = start(config.clone());
+
+    let mut commands = Commands::<(), conn::Error>::new();
+    commands.add(General::new("busy", Busy));
+    tokio::spawn(async move {
+        loop {
+            let event = tokio::select! {
+                biased;
+                () = commands.retries_due() => {
+                    commands.handle_retries(&mut ()).await.unwrap();
+                    continue;
+                }
+                event = rx.recv() => match event {
+                    Some(event) => event,
+                    None => break,
+                },
+            };
+            if let Event::Packet(_, packet, snapshot) = event {
+                commands
+                    .handle_packet(&config, &packet, &snapshot, &mut ())
+                    .await
+                    .unwrap();
+            }
+        }
+    });
+
+    let mut client = server.accept().await;
+    client.join().await;
+    client.send_event("0000000000001", "!busy").await;
+    let send = client.expect("send").await;
+    assert_eq!(send["data"]["content"], "attempt 1");
+    assert_eq!(send["data"]["parent"], "0000000000001");
+}
+
+struct HandoffBot(Instance);
+
+impl HasInstance for HandoffBot {
+    fn instance(&self, _config: &InstanceConfig) -> Option<&Instance> {
+        Some(&self.0)
+    }
+}
+
+/// Expect a message, reply to it as sent, and return its content.
+async fn expect_send(client: &mut Client) -> String {
+    let send = client.expect("send").await;
+    let content = send["data"]["content"].as_str().unwrap().to_string();
+    let data = json!({
+        "id": "0000000000002",
+        "parent": send["data"]["parent"],
+        "time": 0,
+        "sender": session("me", "Bot"),
+        "content": content,
+    });
+    client.reply(&send, data).await;
+    content
+}
+
+#[tokio::test]
+async fn handoff_park_survives_reconnect() {
+    let server = FakeServer::new().await;
+    let config = server.config().room("test").with_username("Bot");
+    let (instance, mut rx) = start(config.clone());
+
+    let mut commands = Commands::<HandoffBot, conn::Error>::new();
+    commands.add(HandoffCommand::new([UserId("agent:other".to_string())]));
+    let mut bot = HandoffBot(instance.clone());
+    tokio::spawn(async move {
+        while let Some(event) = rx.recv().await {
+            if let Event::Packet(_, packet, snapshot) = event {
+                let _ = commands
+                    .handle_packet(&config, &packet, &snapshot, &mut bot)
+                    .await;
+            }
+        }
+    });
+
+    let mut client = server.accept().await;
+    client.join().await;
+    let nick = client.expect("nick").await;
+    let nick_reply = json!({ "session_id": "me", "id": "agent:me", "from": "", "to": "Bot" });
+    client.reply(&nick, nick_reply).await;
+
+    client
+        .send_event("0000000000001", "!handoff request Bot")
+        .await;
+    assert_eq!(expect_send(&mut client).await, "!handoff ok");
+
+    client
+        .send_event("0000000000003", "!handoff rename Bot (leaving)")
+        .await;
+    let nick = client.expect("nick").await;
+    assert_eq!(nick["data"]["name"], "Bot (leaving)");
+    let nick_reply = json!({
+        "session_id": "me",
+        "id": "agent:me",
+        "from": "Bot",
+        "to": "Bot (leaving)",
+    });
+    client.reply(&nick, nick_reply).await;
+    assert_eq!(expect_send(&mut client).await, "!handoff ok");
+    assert_eq!(instance.username().as_deref(), Some("Bot (leaving)"));
+
+    // The old bot must not reclaim the real nick after reconnecting
+    client.ws.close(None).await.unwrap();
+    drop(client);
+    let mut client = server.accept().await;
+    client.join().await;
+    let nick = client.expect("nick").await;
+    assert_eq!(nick["data"]["name"], "Bot (leaving)");
+}
+
+#[tokio::test]
+async fn graceful_stop() {
+    let server = FakeServer::new().await;
+    let (instance, mut rx) = start(server.config().room("test"));
+
+    let mut client = server.accept().await;
+    client.join().await;
+    wait_for_joined(&mut rx).await;
+
+    instance.leave(Some(Send {
+        content: "/me leaves".to_string(),
+        parent: None,
+    }));
+    let send = client.expect("send").await;
+    assert_eq!(send["data"]["content"], "/me leaves");
+    let message = json!({
+        "id": "0000000000002",
+        "time": 0,
+        "sender": session("me", ""),
+        "content": "/me leaves",
+    });
+    client.reply(&send, message).await;
+
+    // The connection is closed and the instance doesn't reconnect
+    while client.recv().await.is_some() {}
+    drop(client);
+    wait_for(&mut rx, |e| matches!(e, Event::Stopped(_)).then_some(())).await;
+    assert!(instance.stopped());
+    let reconnect = tokio::time::timeout(Duration::from_millis(100), server.listener.accept());
+    assert!(reconnect.await.is_err());
+}